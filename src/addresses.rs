@@ -0,0 +1,240 @@
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, Problem};
+use crate::models::{Address, Addresses};
+use crate::negotiate::Body;
+use crate::observe;
+use crate::repository::ColumnWrite;
+use crate::state::AppState;
+use crate::users;
+use crate::validation;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use scylla::frame::response::result::CqlValue;
+use uuid::Uuid;
+
+// A user's postal addresses live on the user row, as a list of frozen
+// `address` values, and are served as a sub-resource of the user: GET
+// /users/{id}/addresses lists them, POST adds one at the end and DELETE
+// /users/{id}/addresses/{index} removes one. Both writes are collection
+// mutations (`addresses + ?` and `addresses - ?`), so neither rewrites the
+// rest of the row or the list, and concurrent ones don't undo each other.
+//
+// Removal is by value: the address at `index` is read, then removed wherever
+// it has moved to, so a concurrent removal that shifts the positions can't
+// make it take the wrong one. That is why adding an address the user already
+// has is refused: removing one copy would remove both. Like the avatar, the
+// addresses are not part of the user's representation, and changing them
+// leaves `updated_at`, the user's version and its events alone.
+
+// Most addresses one user may have. Checked against the list as read, so
+// two concurrent additions may both get in at the limit.
+const MAX_ADDRESSES: usize = 10;
+
+// `address` as a value of the `address` user-defined type in `keyspace`.
+// Fields it lacks are bound as null, as reading them back gives them.
+pub fn address_value(keyspace: &str, address: &Address) -> CqlValue {
+    let field = |name: &str, value: Option<&String>| {
+        (name.to_string(), value.cloned().map(CqlValue::Text))
+    };
+    CqlValue::UserDefinedType {
+        keyspace: keyspace.to_string(),
+        type_name: String::from("address"),
+        fields: vec![
+            field("label", address.label.as_ref()),
+            field("line1", Some(&address.line1)),
+            field("line2", address.line2.as_ref()),
+            field("city", Some(&address.city)),
+            field("region", address.region.as_ref()),
+            field("postal_code", address.postal_code.as_ref()),
+            field("country", Some(&address.country)),
+        ],
+    }
+}
+
+// `addresses` as a value of the `addresses` column.
+pub fn list_value(keyspace: &str, addresses: &[Address]) -> CqlValue {
+    CqlValue::List(
+        addresses
+            .iter()
+            .map(|address| address_value(keyspace, address))
+            .collect(),
+    )
+}
+
+fn authorize_for(subject: Option<&Subject>, user_id: Uuid) -> Result<&Subject, AuthError> {
+    auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only manage their own addresses unless they have the admin role",
+    )
+}
+
+// The addresses of the live user with `user_id` and when the user expires,
+// or 404.
+async fn stored(
+    data: &AppState,
+    user_id: Uuid,
+) -> Result<(Vec<Address>, Option<DateTime<Utc>>), ApiError> {
+    let user = users::stored_user(data, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User with ID {} not found", user_id)))?;
+    let result = observe::query(data, "select_user_addresses", || {
+        data.session
+            .execute_unpaged(&data.statements.select_user_addresses, (user_id,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read addresses", e))?
+        .maybe_first_row::<(Option<Vec<Address>>,)>()
+        .map_err(|e| ApiError::internal("Failed to read addresses", e))?;
+    let addresses = row.and_then(|(addresses,)| addresses).unwrap_or_default();
+    Ok((addresses, user.expires_at))
+}
+
+// Makes `write`, `AddAddress` or `RemoveAddress`, failing with 404 when the
+// row has gone since it was read.
+async fn change(
+    data: &AppState,
+    user_id: Uuid,
+    write: ColumnWrite<'_>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let (applied, _) = data.users.write_column(user_id, expires_at, write).await?;
+    if !applied {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)));
+    }
+    Ok(())
+}
+
+/// The user's addresses, in the order they were added.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/addresses",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The addresses", body = Addresses),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn list_addresses(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let (addresses, _) = stored(&data, user_id).await?;
+    Ok(HttpResponse::Ok().json(Addresses { addresses }))
+}
+
+/// Adds an address at the end of the user's list.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/addresses",
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body = Address,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 201, description = "Address added; the user's addresses", body = Addresses),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "The user already has this address, or 10 addresses", body = Problem),
+        (status = 422, description = "Invalid address", body = Problem),
+    )
+)]
+pub async fn add_address(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    Body(address): Body<Address>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let address = validation::address(address)?;
+    let (mut addresses, expires_at) = stored(&data, user_id).await?;
+    if addresses.contains(&address) {
+        return Err(ApiError::Conflict(format!("User {} already has this address", user_id)).into());
+    }
+    if addresses.len() >= MAX_ADDRESSES {
+        return Err(ApiError::Conflict(format!(
+            "User {} already has {} addresses",
+            user_id, MAX_ADDRESSES
+        ))
+        .into());
+    }
+    change(&data, user_id, ColumnWrite::AddAddress(&address), expires_at).await?;
+    tracing::info!(%user_id, "address added");
+    addresses.push(address);
+    Ok(HttpResponse::Created().json(Addresses { addresses }))
+}
+
+/// Removes the address at `index` in the user's list.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/addresses/{index}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("index" = usize, Path, description = "Position of the address, from 0"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Address removed; the user's addresses", body = Addresses),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user, or no address at this position", body = Problem),
+    )
+)]
+pub async fn delete_address(
+    subject: Option<web::ReqData<Subject>>,
+    path: web::Path<(Uuid, usize)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, index) = path.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let (mut addresses, expires_at) = stored(&data, user_id).await?;
+    if index >= addresses.len() {
+        let message = format!("User {} has no address at position {}", user_id, index);
+        return Err(ApiError::NotFound(message).into());
+    }
+    let address = addresses.remove(index);
+    change(&data, user_id, ColumnWrite::RemoveAddress(&address), expires_at).await?;
+    tracing::info!(%user_id, index, "address removed");
+    Ok(HttpResponse::Ok().json(Addresses { addresses }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_bind_every_field_of_the_type_in_order() {
+        let address = Address {
+            label: None,
+            line1: String::from("12 Baker Street"),
+            line2: None,
+            city: String::from("London"),
+            region: None,
+            postal_code: Some(String::from("NW1 6XE")),
+            country: String::from("GB"),
+        };
+        let CqlValue::List(values) = list_value("app", &[address]) else {
+            panic!("not a list");
+        };
+        let [CqlValue::UserDefinedType { keyspace, type_name, fields }] = values.as_slice() else {
+            panic!("not one address");
+        };
+        assert_eq!((keyspace.as_str(), type_name.as_str()), ("app", "address"));
+        let names: Vec<&str> = fields.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["label", "line1", "line2", "city", "region", "postal_code", "country"]
+        );
+        assert_eq!(fields[0].1, None);
+        assert_eq!(fields[6].1, Some(CqlValue::Text(String::from("GB"))));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::auth::{AuthError, Subject};
+use crate::error::{ApiError, Problem};
+use crate::observe;
+use crate::state::AppState;
+use crate::statements;
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use scylla::frame::value::CqlTimestamp;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// Keys are handed out as `<id>.<secret>`. Only the SHA-256 of the secret is
+// stored, so a leaked api_keys table does not leak usable keys.
+
+// Scope an API key needs to call the mutating user routes.
+pub const WRITE_SCOPE: &str = "users:write";
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewApiKey {
+    pub owner: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    pub id: Uuid,
+    /// Shown only once; store it safely.
+    pub key: String,
+    pub owner: String,
+    pub scopes: Vec<String>,
+}
+
+// 32 random bytes, URL-safe so they can sit in a path or a link.
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Compares without short-circuiting so timing doesn't reveal how much of a
+// guessed hash matched.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+// Looks up the key presented in `X-API-Key` and checks it is live and carries
+// `scope`. The subject is the key's owner, with the key's scopes as roles.
+pub async fn verify(state: &AppState, presented: &str, scope: &str) -> Result<Subject, AuthError> {
+    let (_, subject) = live_key(state, presented).await?;
+    if !subject.has_role(scope) {
+        return Err(AuthError::MissingScope(scope.to_string()));
+    }
+    Ok(subject)
+}
+
+// The id and subject of a presented key that exists, matches and is not
+// revoked, whatever its scopes.
+pub async fn live_key(state: &AppState, presented: &str) -> Result<(Uuid, Subject), AuthError> {
+    let (id, secret) = presented
+        .split_once('.')
+        .and_then(|(id, secret)| Uuid::parse_str(id).ok().map(|id| (id, secret)))
+        .ok_or(AuthError::InvalidApiKey)?;
+
+    let result = observe::query(
+        state,
+        "select_api_key",
+        || state.session.execute_unpaged(&state.statements.select_api_key, (id,)),
+    )
+    .await
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?
+        .maybe_first_row::<(String, String, Option<Vec<String>>, Option<bool>)>()
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+
+    let Some((key_hash, owner, scopes, revoked)) = row else {
+        return Err(AuthError::InvalidApiKey);
+    };
+    if revoked.unwrap_or(false) || !constant_time_eq(&key_hash, &hash_secret(secret)) {
+        return Err(AuthError::InvalidApiKey);
+    }
+    let subject = Subject {
+        id: owner,
+        roles: scopes.unwrap_or_default(),
+        session: None,
+    };
+    Ok((id, subject))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys",
+    request_body = NewApiKey,
+    security(("bearer" = [])),
+    responses(
+        (status = 201, description = "Key created", body = CreatedApiKey),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 403, description = "Caller lacks the admin role"),
+    )
+)]
+pub async fn create_api_key(
+    new_key: web::Json<NewApiKey>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let new_key = new_key.into_inner();
+    let id = Uuid::new_v4();
+    let secret = new_secret();
+    let created_at = CqlTimestamp(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64),
+    );
+
+    let key_hash = hash_secret(&secret);
+    observe::query(&data, "insert_api_key", || {
+        data.session.execute_unpaged(
+            &data.statements.insert_api_key,
+            (id, &key_hash, &new_key.owner, &new_key.scopes, created_at),
+        )
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(CreatedApiKey {
+        id,
+        key: format!("{}.{}", id, secret),
+        owner: new_key.owner,
+        scopes: new_key.scopes,
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/api-keys/{id}",
+    params(("id" = Uuid, Path, description = "API key id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Key revoked", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such key", body = Problem),
+    )
+)]
+pub async fn revoke_api_key(
+    key_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let key_id = key_id.into_inner();
+
+    let result = observe::conditional(
+        &data,
+        "revoke_api_key",
+        || data.session.execute_unpaged(&data.statements.revoke_api_key, (key_id,)),
+    )
+    .await?;
+    match statements::applied(result) {
+        Ok(true) => Ok(HttpResponse::Ok().json(format!("API key {} revoked", key_id))),
+        Ok(false) => Err(ApiError::NotFound(format!("API key {} not found", key_id))),
+        Err(e) => Err(ApiError::internal("Failed to revoke API key", e)),
+    }
+}
@@ -0,0 +1,521 @@
+use crate::error::{ApiError, Problem};
+use crate::models::User;
+use crate::observe;
+use crate::paging::{self, CursorKind, CursorScope};
+use crate::state::AppState;
+use crate::users;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use scylla::frame::response::result::CqlValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+// Every create, update, replace, delete, restore and anonymization of a user
+// is recorded in `audit_log`, in the partition of the user it changed, by the
+// same function that made the change (`users::register` and its siblings,
+// and the batch, whose entries ride along in the CQL batch). An entry names
+// the action, the subject that made it, the id of the request and each field
+// that changed with its value before and after; an anonymization records
+// only the values after, so the entry doesn't keep what it erased. The
+// subject and request id are held in a task-local for the rest of the
+// request: the request id from `request_id::assign`, the subject from the
+// auth middleware (or from GraphQL and gRPC, which authorize calls
+// themselves) once it is known.
+//
+// The entry is written once the change has been, and a failure to write it
+// is logged rather than failing a change that has already been made.
+//
+// GET /users/{id}/audit pages through a user's entries newest first, which is
+// the table's clustering order, so every page is a forward read of one slice
+// of the partition. Its cursors hold the clustering key (`at`, `id`) of the
+// last entry served, and the next page is the slice below it; `?since=`
+// bounds the slice from below.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Create,
+    Update,
+    Replace,
+    Delete,
+    Purge,
+    Restore,
+    Anonymize,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Replace => "replace",
+            Action::Delete => "delete",
+            Action::Purge => "purge",
+            Action::Restore => "restore",
+            Action::Anonymize => "anonymize",
+        }
+    }
+}
+
+/// A field's value before and after a change; `null` where it had none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Change {
+    pub before: Value,
+    pub after: Value,
+}
+
+/// One change to a user, as recorded when it was made.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// `create`, `update`, `replace`, `delete` (soft), `purge` (hard delete),
+    /// `restore` or `anonymize`.
+    pub action: String,
+    /// The subject that made the change; absent for registrations by
+    /// unauthenticated callers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Each field that changed, by name; profile fields are named
+    /// `profile.bio` and so on.
+    pub changes: BTreeMap<String, Change>,
+}
+
+/// A page of a user's recorded changes, newest first.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditLog {
+    pub entries: Vec<AuditEntry>,
+    /// Absent on the last page. Usable for `http.cursor_max_age_secs`.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Page size; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page; one issued with another `since`
+    /// is refused.
+    pub cursor: Option<String>,
+    /// Only entries recorded at or after this RFC 3339 time.
+    pub since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Context {
+    actor: Option<String>,
+    request_id: Option<String>,
+}
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+fn current() -> Context {
+    CONTEXT.try_with(Context::clone).unwrap_or_default()
+}
+
+// Runs `future`, the rest of the request with id `request_id`.
+pub async fn in_request<F: Future>(request_id: String, future: F) -> F::Output {
+    let context = Context {
+        request_id: Some(request_id),
+        ..current()
+    };
+    CONTEXT.scope(context, future).await
+}
+
+// Runs `future` on behalf of `actor`, a subject id.
+pub async fn acting_as<F: Future>(actor: &str, future: F) -> F::Output {
+    let context = Context {
+        actor: Some(actor.to_string()),
+        ..current()
+    };
+    CONTEXT.scope(context, future).await
+}
+
+// The fields of `user` as sent, profile fields flattened to `profile.bio`
+// and so on. The id and timestamps are left out: the entry has its own.
+fn fields(user: Option<&User>) -> Map<String, Value> {
+    let mut fields = Map::new();
+    let Some(Value::Object(object)) = user.and_then(|user| serde_json::to_value(user).ok()) else {
+        return fields;
+    };
+    for (name, value) in object {
+        match (name.as_str(), value) {
+            ("id" | "created_at" | "updated_at" | "expires_at", _) => {}
+            (_, Value::Object(nested)) => {
+                for (field, value) in nested {
+                    fields.insert(format!("{}.{}", name, field), value);
+                }
+            }
+            (_, value) => {
+                fields.insert(name, value);
+            }
+        }
+    }
+    fields
+}
+
+// The fields that differ between `before` and `after`, either of which is
+// `None` for a user that doesn't exist (or is deleted) on that side.
+pub fn diff(before: Option<&User>, after: Option<&User>) -> BTreeMap<String, Change> {
+    let before = fields(before);
+    let after = fields(after);
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let change = Change {
+                before: before.get(name).cloned().unwrap_or(Value::Null),
+                after: after.get(name).cloned().unwrap_or(Value::Null),
+            };
+            (change.before != change.after).then(|| (name.clone(), change))
+        })
+        .collect()
+}
+
+// Bind values for `insert_audit_entry`, recording `action` on `user_id` at
+// `at` by the current request's subject.
+pub fn entry_values(
+    user_id: Uuid,
+    action: Action,
+    before: Option<&User>,
+    after: Option<&User>,
+    at: DateTime<Utc>,
+) -> Vec<Option<CqlValue>> {
+    let context = current();
+    let changes = serde_json::to_string(&diff(before, after)).unwrap_or_default();
+    vec![
+        Some(CqlValue::Uuid(user_id)),
+        Some(CqlValue::Timestamp(at.into())),
+        Some(CqlValue::Uuid(Uuid::new_v4())),
+        Some(CqlValue::Text(action.as_str().to_string())),
+        context.actor.map(CqlValue::Text),
+        context.request_id.map(CqlValue::Text),
+        Some(CqlValue::Text(changes)),
+    ]
+}
+
+// Records `action` on `user_id`, which took it from `before` to `after`.
+pub async fn record(
+    state: &AppState,
+    user_id: Uuid,
+    action: Action,
+    before: Option<&User>,
+    after: Option<&User>,
+) {
+    let values = entry_values(user_id, action, before, after, Utc::now());
+    let result = observe::query(state, "insert_audit_entry", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.insert_audit_entry, &values)
+    })
+    .await;
+    if let Err(e) = result {
+        tracing::warn!(%user_id, action = action.as_str(), error = %e, "failed to record audit entry");
+    }
+}
+
+// An entry as read, with the clustering key it is stored under.
+struct Stored {
+    key: (DateTime<Utc>, Uuid),
+    entry: AuditEntry,
+}
+
+// The cursor continuing after the entry stored under `key`: its time in
+// milliseconds, as stored, then its id.
+fn encode_key(scope: &CursorScope, (at, id): (DateTime<Utc>, Uuid)) -> String {
+    let mut key = at.timestamp_millis().to_be_bytes().to_vec();
+    key.extend_from_slice(id.as_bytes());
+    paging::encode_key(scope, &key)
+}
+
+fn decode_key(key: &[u8]) -> Option<(DateTime<Utc>, Uuid)> {
+    let (millis, id) = key.split_first_chunk::<8>()?;
+    let at = DateTime::from_timestamp_millis(i64::from_be_bytes(*millis))?;
+    Some((at, Uuid::from_slice(id).ok()?))
+}
+
+// The page of `stored`, the entries read for it with one more than `limit`
+// to tell whether another page follows.
+fn page(mut stored: Vec<Stored>, limit: usize, scope: &CursorScope) -> AuditLog {
+    let next_cursor = if stored.len() > limit {
+        stored.truncate(limit);
+        stored.last().map(|last| encode_key(scope, last.key))
+    } else {
+        None
+    };
+    AuditLog {
+        entries: stored.into_iter().map(|stored| stored.entry).collect(),
+        next_cursor,
+    }
+}
+
+// Up to `limit` entries of `user_id` at or after `since`, newest first,
+// starting below `before` when given.
+async fn read(
+    data: &AppState,
+    user_id: Uuid,
+    since: DateTime<Utc>,
+    before: Option<(DateTime<Utc>, Uuid)>,
+    limit: usize,
+) -> Result<Vec<Stored>, ApiError> {
+    let limit = i32::try_from(limit).unwrap_or(i32::MAX);
+    let result = match before {
+        None => {
+            observe::query(data, "select_audit_entries", || {
+                data.session
+                    .execute_unpaged(&data.statements.select_audit_entries, (user_id, since, limit))
+            })
+            .await?
+        }
+        Some((at, id)) => {
+            observe::query(data, "select_audit_entries_before", || {
+                data.session.execute_unpaged(
+                    &data.statements.select_audit_entries_before,
+                    (user_id, since, Uuid::nil(), at, id, limit),
+                )
+            })
+            .await?
+        }
+    };
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading audit log", e))?
+        .rows::<(DateTime<Utc>, Uuid, String, Option<String>, Option<String>, Option<String>)>()
+        .map_err(|e| ApiError::internal("Error reading audit log", e))?
+        .map(|row| {
+            row.map(|(at, id, action, actor, request_id, changes)| Stored {
+                key: (at, id),
+                entry: AuditEntry {
+                    at,
+                    action,
+                    actor,
+                    request_id,
+                    changes: changes
+                        .and_then(|changes| serde_json::from_str(&changes).ok())
+                        .unwrap_or_default(),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Error reading audit log", e))
+}
+
+/// The user's recorded changes, newest first, including those from before a
+/// delete, a page at a time.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/audit",
+    params(("id" = Uuid, Path, description = "User id"), AuditQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "A page of the user's changes, newest first", body = AuditLog),
+        (status = 400, description = "Invalid limit or cursor", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn get_audit_log(
+    user_id: web::Path<Uuid>,
+    params: web::Query<AuditQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user_id.into_inner();
+    let (limit, truncated) = users::page_limit(&data, params.limit)?;
+    let scope = CursorScope::new(CursorKind::AuditLog, &(user_id, params.since));
+    let before = paging::decode_key(params.cursor.as_deref(), &scope, data.cursor_max_age)?
+        .map(|key| decode_key(&key).ok_or_else(|| ApiError::BadRequest(String::from("Invalid cursor"))))
+        .transpose()?;
+    let since = params.since.unwrap_or(DateTime::UNIX_EPOCH);
+    let stored = read(&data, user_id, since, before, limit + 1).await?;
+    let response = HttpResponse::Ok().json(page(stored, limit, &scope));
+    Ok(users::with_truncated(response, truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Profile;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn ada() -> User {
+        User {
+            id: Uuid::from_u128(1),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: Some(Profile {
+                bio: Some(String::from("Analyst")),
+                ..Profile::default()
+            }),
+            created_at: Some(Utc::now()),
+            updated_at: Some(Utc::now()),
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    fn change(before: Value, after: Value) -> Change {
+        Change { before, after }
+    }
+
+    #[test]
+    fn updates_record_only_the_fields_that_changed() {
+        let before = ada();
+        let mut after = before.clone();
+        after.name = String::from("Ada Lovelace");
+        after.profile = Some(Profile {
+            locale: Some(String::from("en-GB")),
+            ..before.profile.clone().unwrap()
+        });
+        after.updated_at = Some(Utc::now() + chrono::Duration::seconds(1));
+        let changes = diff(Some(&before), Some(&after));
+        assert_eq!(
+            changes,
+            BTreeMap::from([
+                (String::from("name"), change(json!("Ada"), json!("Ada Lovelace"))),
+                (String::from("profile.locale"), change(Value::Null, json!("en-GB"))),
+            ])
+        );
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn creates_and_deletes_record_every_field() {
+        let ada = ada();
+        let created = diff(None, Some(&ada));
+        assert_eq!(
+            created.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["email", "name", "profile.bio"]
+        );
+        assert_eq!(created["email"], change(Value::Null, json!("ada@example.com")));
+        let deleted = diff(Some(&ada), None);
+        assert_eq!(deleted["profile.bio"], change(json!("Analyst"), Value::Null));
+    }
+
+    // What `read` selects from `log`, stored newest first: the entries at or
+    // after `since` and below `before`, up to `limit`.
+    fn select(
+        log: &[(DateTime<Utc>, Uuid)],
+        since: DateTime<Utc>,
+        before: Option<(DateTime<Utc>, Uuid)>,
+        limit: usize,
+    ) -> Vec<Stored> {
+        log.iter()
+            .filter(|key| key.0 >= since && before.is_none_or(|before| **key < before))
+            .take(limit)
+            .map(|&(at, id)| Stored {
+                key: (at, id),
+                entry: AuditEntry {
+                    at,
+                    action: id.to_string(),
+                    actor: None,
+                    request_id: None,
+                    changes: BTreeMap::new(),
+                },
+            })
+            .collect()
+    }
+
+    // `count` entries a second apart, newest first, in milliseconds as stored.
+    fn log(count: usize) -> Vec<(DateTime<Utc>, Uuid)> {
+        let start = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let mut log: Vec<_> = (0..count)
+            .map(|n| (start + chrono::Duration::seconds(n as i64), Uuid::new_v4()))
+            .collect();
+        log.reverse();
+        log
+    }
+
+    // Every page of `log` from `since`, following the cursors.
+    fn follow(log: &[(DateTime<Utc>, Uuid)], since: DateTime<Utc>, limit: usize) -> Vec<AuditLog> {
+        let scope = CursorScope::new(CursorKind::AuditLog, &(Uuid::nil(), Some(since)));
+        let mut pages = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let key = paging::decode_key(cursor.as_deref(), &scope, Duration::from_secs(60)).unwrap();
+            let before = key.map(|key| decode_key(&key).unwrap());
+            let served = page(select(log, since, before, limit + 1), limit, &scope);
+            cursor = served.next_cursor.clone();
+            pages.push(served);
+            if cursor.is_none() {
+                return pages;
+            }
+        }
+    }
+
+    #[test]
+    fn pages_follow_each_other_newest_first() {
+        let log = log(25);
+        let pages = follow(&log, DateTime::UNIX_EPOCH, 10);
+        assert_eq!(pages.iter().map(|page| page.entries.len()).collect::<Vec<_>>(), [10, 10, 5]);
+        let served: Vec<String> = pages
+            .iter()
+            .flat_map(|page| page.entries.iter().map(|entry| entry.action.clone()))
+            .collect();
+        let expected: Vec<String> = log.iter().map(|(_, id)| id.to_string()).collect();
+        assert_eq!(served, expected);
+        assert!(pages.iter().flat_map(|page| &page.entries).is_sorted_by(|a, b| a.at >= b.at));
+
+        // A page that ends the log exactly has no next page.
+        let exact = follow(&log[..20], DateTime::UNIX_EPOCH, 10);
+        assert_eq!(exact.len(), 2);
+        assert!(exact[1].next_cursor.is_none());
+    }
+
+    #[test]
+    fn since_excludes_older_entries() {
+        let log = log(25);
+        // The entries are newest first, so the seventh is the seventh newest.
+        let since = log[6].0;
+        let pages = follow(&log, since, 3);
+        let served: Vec<DateTime<Utc>> = pages
+            .iter()
+            .flat_map(|page| page.entries.iter().map(|entry| entry.at))
+            .collect();
+        assert_eq!(served, log[..7].iter().map(|(at, _)| *at).collect::<Vec<_>>());
+        assert!(served.iter().all(|at| *at >= since));
+    }
+
+    #[test]
+    fn cursors_hold_the_clustering_key() {
+        let scope = CursorScope::new(CursorKind::AuditLog, &(Uuid::nil(), None::<DateTime<Utc>>));
+        let key = log(1)[0];
+        let cursor = encode_key(&scope, key);
+        let sealed = paging::decode_key(Some(&cursor), &scope, Duration::from_secs(60)).unwrap();
+        assert_eq!(sealed.as_deref().and_then(decode_key), Some(key));
+        assert_eq!(decode_key(b"short"), None);
+
+        let other = CursorScope::new(CursorKind::AuditLog, &(Uuid::nil(), Some(key.0)));
+        assert!(paging::decode_key(Some(&cursor), &other, Duration::from_secs(60)).is_err());
+    }
+
+    #[actix_web::test]
+    async fn entries_name_the_request_and_its_subject() {
+        let ada = ada();
+        let outside = entry_values(ada.id, Action::Create, None, Some(&ada), Utc::now());
+        assert_eq!(outside[4], None);
+        assert_eq!(outside[5], None);
+
+        let values = in_request(String::from("req-1"), async {
+            acting_as("admin-1", async {
+                entry_values(ada.id, Action::Purge, Some(&ada), None, Utc::now())
+            })
+            .await
+        })
+        .await;
+        assert_eq!(values[3], Some(CqlValue::Text(String::from("purge"))));
+        assert_eq!(values[4], Some(CqlValue::Text(String::from("admin-1"))));
+        assert_eq!(values[5], Some(CqlValue::Text(String::from("req-1"))));
+    }
+}
@@ -0,0 +1,393 @@
+use crate::api_keys;
+use crate::audit;
+use crate::error;
+use crate::observe;
+use crate::sessions;
+use crate::state::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use scylla::frame::value::CqlTimestamp;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// HS256 bearer token validation for the mutating routes, and issuing of the
+// same tokens from POST /login and POST /token/refresh. Tokens we issue name
+// their session in `sid`, and are refused once it has been revoked; see
+// `sessions`.
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+    encoding_key: EncodingKey,
+    issuer: Option<String>,
+    token_ttl: Duration,
+    refresh_ttl: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    // Absent from tokens issued elsewhere, which have no session to revoke.
+    #[serde(default)]
+    sid: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct IssuedClaims<'a> {
+    sub: &'a str,
+    iat: u64,
+    exp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<&'a str>,
+    sid: Uuid,
+}
+
+pub const ADMIN_ROLE: &str = "admin";
+
+// Caller identity stored in the request extensions. For bearer tokens `id` is
+// the token subject (a user id) and `roles` come from that user's row; for API
+// keys `id` is the key owner and the key's scopes double as its roles.
+// `session` is the session of a bearer token that names one.
+#[derive(Debug, Clone)]
+pub struct Subject {
+    pub id: String,
+    pub roles: Vec<String>,
+    pub session: Option<Uuid>,
+}
+
+impl Subject {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|granted| granted == role)
+    }
+
+    pub fn is_user(&self, user_id: Uuid) -> bool {
+        Uuid::parse_str(&self.id).is_ok_and(|id| id == user_id)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthenticated,
+    MissingToken,
+    InvalidToken(jsonwebtoken::errors::Error),
+    InvalidApiKey,
+    InvalidCredentials,
+    RevokedSession,
+    // The user refused, or the sign-in with a provider could not be matched
+    // to one this service started.
+    SignInFailed(String),
+    // A provider failed or gave an answer that could not be used.
+    ProviderFailed(String),
+    MissingScope(String),
+    Forbidden(String),
+    Unavailable(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => {
+                write!(f, "this endpoint requires a bearer token or an API key")
+            }
+            AuthError::MissingToken => write!(f, "missing bearer token"),
+            AuthError::InvalidToken(e) => write!(f, "invalid bearer token: {}", e),
+            AuthError::InvalidApiKey => write!(f, "invalid or revoked API key"),
+            AuthError::InvalidCredentials => write!(f, "invalid email or password"),
+            AuthError::RevokedSession => write!(f, "the session has ended or been revoked"),
+            AuthError::SignInFailed(reason) => write!(f, "sign-in failed: {}", reason),
+            AuthError::ProviderFailed(e) => write!(f, "sign-in provider failed: {}", e),
+            AuthError::MissingScope(scope) => write!(f, "API key lacks the {} scope", scope),
+            AuthError::Forbidden(reason) => write!(f, "{}", reason),
+            AuthError::Unavailable(e) => write!(f, "cannot verify credentials: {}", e),
+        }
+    }
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::Unauthenticated => "unauthenticated",
+            AuthError::MissingToken => "missing_token",
+            AuthError::InvalidToken(_) => "invalid_token",
+            AuthError::InvalidApiKey => "invalid_api_key",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::RevokedSession => "revoked_session",
+            AuthError::SignInFailed(_) => "sign_in_failed",
+            AuthError::ProviderFailed(_) => "provider_failed",
+            AuthError::MissingScope(_) => "missing_scope",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::Unavailable(_) => "auth_unavailable",
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingScope(_) | AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AuthError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::ProviderFailed(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = error::problem(self.status_code(), self.code(), self.to_string());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+impl JwtAuth {
+    pub fn new(
+        secret: &str,
+        issuer: Option<&str>,
+        token_ttl: Duration,
+        refresh_ttl: Duration,
+    ) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        if let Some(issuer) = issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        JwtAuth {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            issuer: issuer.map(String::from),
+            token_ttl,
+            refresh_ttl,
+        }
+    }
+
+    pub fn token_ttl(&self) -> Duration {
+        self.token_ttl
+    }
+
+    // How long a session lasts.
+    pub fn refresh_ttl(&self) -> Duration {
+        self.refresh_ttl
+    }
+
+    // Signs a token for `subject` in `session` that `validate` will accept
+    // until it expires.
+    pub fn issue(&self, subject: &str, session: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let claims = IssuedClaims {
+            sub: subject,
+            iat: now,
+            exp: now + self.token_ttl.as_secs(),
+            iss: self.issuer.as_deref(),
+            sid: session,
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+    }
+
+    fn validate(&self, token: &str) -> Result<Claims, AuthError> {
+        decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(AuthError::InvalidToken)
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// Roles of the user a token was issued to. Subjects that are not user ids
+// (e.g. service accounts) and soft-deleted users have none.
+async fn user_roles(state: &AppState, subject: &str) -> Result<Vec<String>, AuthError> {
+    let Ok(user_id) = Uuid::parse_str(subject) else {
+        return Ok(Vec::new());
+    };
+    let result = observe::query(
+        state,
+        "select_user_roles",
+        || state.session.execute_unpaged(&state.statements.select_user_roles, (user_id,)),
+    )
+    .await
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?
+        .maybe_first_row::<(Option<Vec<String>>, Option<CqlTimestamp>)>()
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+    Ok(match row {
+        Some((roles, None)) => roles.unwrap_or_default(),
+        _ => Vec::new(),
+    })
+}
+
+fn app_state(req: &HttpRequest) -> web::Data<AppState> {
+    req.app_data::<web::Data<AppState>>()
+        .expect("AppState is registered")
+        .clone()
+}
+
+// The subject of `bearer_token`, or `None` when `jwt` is `None` (no secret
+// configured). With a secret configured, a missing token is `MissingToken`.
+async fn jwt_subject(
+    state: &AppState,
+    jwt: Option<&JwtAuth>,
+    bearer_token: Option<&str>,
+) -> Result<Option<Subject>, AuthError> {
+    let Some(jwt) = jwt else {
+        return Ok(None);
+    };
+    let token = bearer_token.ok_or(AuthError::MissingToken)?;
+    let Claims { sub: id, sid } = jwt.validate(token)?;
+    if let Some(session) = sid
+        && !sessions::is_live(state, session, &id).await?
+    {
+        return Err(AuthError::RevokedSession);
+    }
+    let roles = user_roles(state, &id).await?;
+    Ok(Some(Subject {
+        id,
+        roles,
+        session: sid,
+    }))
+}
+
+// The caller behind `api_key` (which must have the write scope), or else
+// behind `bearer_token`. Transport-neutral, for listeners outside actix such
+// as the gRPC one.
+pub async fn identify(
+    state: &AppState,
+    jwt: Option<&JwtAuth>,
+    api_key: Option<&str>,
+    bearer_token: Option<&str>,
+) -> Result<Option<Subject>, AuthError> {
+    match api_key {
+        Some(key) => Ok(Some(api_keys::verify(state, key, api_keys::WRITE_SCOPE).await?)),
+        None => jwt_subject(state, jwt, bearer_token).await,
+    }
+}
+
+fn jwt_auth(req: &HttpRequest) -> Option<&JwtAuth> {
+    req.app_data::<web::Data<JwtAuth>>().map(|auth| auth.get_ref())
+}
+
+// The caller behind an `X-API-Key` with the write scope, or else behind a
+// bearer token.
+pub async fn authenticate(req: &HttpRequest) -> Result<Option<Subject>, AuthError> {
+    let api_key = match req.headers().get("X-API-Key") {
+        Some(key) => Some(key.to_str().map_err(|_| AuthError::InvalidApiKey)?),
+        None => None,
+    };
+    identify(&app_state(req), jwt_auth(req), api_key, bearer_token(req)).await
+}
+
+// Rejects the request with 401 if it carries no bearer token or an invalid
+// one, and records the subject of a valid one. Only when the app has no
+// `web::Data<JwtAuth>` (no secret configured) do requests pass through, then
+// without a subject, which `authorize` and `require_admin` refuse.
+pub async fn require_jwt(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request = req.request();
+    let subject = jwt_subject(&app_state(request), jwt_auth(request), bearer_token(request)).await?;
+    serve_as(req, next, subject).await
+}
+
+// Like `require_jwt`, but machine callers may present an `X-API-Key` with the
+// write scope instead of a bearer token.
+pub async fn require_jwt_or_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let subject = authenticate(req.request()).await?;
+    serve_as(req, next, subject).await
+}
+
+// Serves the rest of the request with `subject` recorded, as the actor of
+// any change it makes (see `audit`).
+async fn serve_as<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+    subject: Option<Subject>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    match subject {
+        Some(subject) => {
+            let actor = subject.id.clone();
+            req.extensions_mut().insert(subject);
+            audit::acting_as(&actor, next.call(req)).await
+        }
+        None => next.call(req).await,
+    }
+}
+
+// Checks the caller against `allowed`. Callers without a verified subject are
+// refused with 401: a deployment without `auth.jwt_secret` only admits API
+// keys, never anonymous writes.
+pub fn authorize<'a>(
+    subject: Option<&'a Subject>,
+    allowed: impl FnOnce(&Subject) -> bool,
+    reason: &str,
+) -> Result<&'a Subject, AuthError> {
+    match subject {
+        None => Err(AuthError::Unauthenticated),
+        Some(subject) if !allowed(subject) => Err(AuthError::Forbidden(reason.to_string())),
+        Some(subject) => Ok(subject),
+    }
+}
+
+// Route guard for admin-only endpoints; must run inside an authenticating
+// middleware so the subject is already resolved.
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    authorize(
+        req.extensions().get::<Subject>(),
+        |subject| subject.has_role(ADMIN_ROLE),
+        "this endpoint requires the admin role",
+    )?;
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject(roles: &[&str]) -> Subject {
+        Subject {
+            id: Uuid::nil().to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            session: None,
+        }
+    }
+
+    #[test]
+    fn authorize_refuses_callers_without_a_subject() {
+        let result = authorize(None, |_| true, "admins only");
+        assert!(matches!(result, Err(AuthError::Unauthenticated)));
+        assert_eq!(AuthError::Unauthenticated.status_code(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn authorize_checks_the_subject() {
+        let admin = subject(&[ADMIN_ROLE]);
+        let user = subject(&[]);
+        assert!(authorize(Some(&admin), |subject| subject.has_role(ADMIN_ROLE), "admins only").is_ok());
+        let refused = authorize(Some(&user), |subject| subject.has_role(ADMIN_ROLE), "admins only");
+        assert!(matches!(refused, Err(AuthError::Forbidden(_))));
+        assert!(authorize(Some(&user), |subject| subject.is_user(Uuid::nil()), "own record").is_ok());
+    }
+}
@@ -0,0 +1,366 @@
+use crate::auth::{self, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, Problem};
+use crate::multipart;
+use crate::observe;
+use crate::state::AppState;
+use crate::users;
+use actix_web::http::header::{self, CacheControl, CacheDirective, EntityTag, IfNoneMatch};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::rt::time::sleep;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+// Avatars are stored in `avatar_chunks` as CHUNK_SIZE blobs under a fresh
+// upload id, and `user_avatars` points at the current upload. Chunks are
+// written first and the pointer last, so readers see either the old image or
+// the new one. The old upload's chunks are dropped `RETIRED_GRACE` after the
+// pointer moved, so downloads that already started can still finish.
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// Chunk inserts in flight at once for one upload.
+const CHUNK_CONCURRENCY: usize = 4;
+// Room for the multipart boundaries and part headers around the image.
+const MULTIPART_OVERHEAD: usize = 16 * 1024;
+// Form field carrying the image.
+const FIELD: &str = "avatar";
+// How long chunks of a replaced or removed upload stay readable.
+const RETIRED_GRACE: Duration = Duration::from_secs(300);
+// Browsers may reuse an avatar for a few minutes, then revalidate it with
+// If-None-Match.
+const MAX_AGE_SECS: u32 = 300;
+
+// The image type from its magic bytes. The Content-Type the client declared
+// is ignored, so nothing but these formats is ever served back.
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn etag(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .take(16)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Reads the body up to `limit` bytes, refusing anything larger with 413.
+async fn read_body(mut payload: web::Payload, limit: usize) -> Result<Bytes, actix_web::Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(ApiError::PayloadTooLarge(String::from("avatar upload is too large")).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+struct Avatar {
+    upload_id: Uuid,
+    content_type: String,
+    size: i32,
+    etag: String,
+    updated_at: Option<DateTime<Utc>>,
+}
+
+async fn current(data: &AppState, user_id: Uuid) -> Result<Option<Avatar>, ApiError> {
+    let result = observe::query(data, "select_avatar", || {
+        data.session
+            .execute_unpaged(&data.statements.select_avatar, (user_id,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read avatar", e))?
+        .maybe_first_row::<(Uuid, String, i32, String, Option<DateTime<Utc>>)>()
+        .map_err(|e| ApiError::internal("Failed to read avatar", e))?;
+    Ok(row.map(|(upload_id, content_type, size, etag, updated_at)| Avatar {
+        upload_id,
+        content_type,
+        size,
+        etag,
+        updated_at,
+    }))
+}
+
+// Deletes the chunks of an upload. Failures are only logged: orphaned chunks
+// waste space but are never read.
+async fn drop_chunks(data: &AppState, upload_id: Uuid) {
+    if let Err(e) = observe::query(data, "delete_avatar_chunks", || {
+        data.session
+            .execute_unpaged(&data.statements.delete_avatar_chunks, (upload_id,))
+    })
+    .await
+    {
+        tracing::warn!(%upload_id, error = %e, "failed to delete avatar chunks");
+    }
+}
+
+// Drops an upload's chunks in the background once `RETIRED_GRACE` has
+// passed. A restart in between leaves them orphaned, like a failed delete.
+fn retire_chunks(data: &AppState, upload_id: Uuid) {
+    let data = data.clone();
+    actix_web::rt::spawn(async move {
+        sleep(RETIRED_GRACE).await;
+        drop_chunks(&data, upload_id).await;
+    });
+}
+
+// Removes a user's avatar, for hard deletes. Best effort, like `drop_chunks`.
+pub async fn remove(data: &AppState, user_id: Uuid) {
+    let avatar = match current(data, user_id).await {
+        Ok(Some(avatar)) => avatar,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!(%user_id, error = %e, "failed to look up avatar");
+            return;
+        }
+    };
+    if let Err(e) = observe::query(data, "delete_avatar", || {
+        data.session
+            .execute_unpaged(&data.statements.delete_avatar, (user_id,))
+    })
+    .await
+    {
+        tracing::warn!(%user_id, error = %e, "failed to delete avatar");
+        return;
+    }
+    retire_chunks(data, avatar.upload_id);
+}
+
+fn caching(builder: &mut actix_web::HttpResponseBuilder, avatar: &Avatar) {
+    builder
+        .insert_header(header::ETag(EntityTag::new_strong(avatar.etag.clone())))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(MAX_AGE_SECS),
+        ]));
+    if let Some(updated_at) = avatar.updated_at {
+        builder.insert_header(header::LastModified(SystemTime::from(updated_at).into()));
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{id}/avatar",
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body(content = String, content_type = "multipart/form-data", description = "PNG, JPEG, GIF or WebP image in the `avatar` field"),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Avatar stored", body = String),
+        (status = 400, description = "Malformed multipart body or no `avatar` field", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 413, description = "Image larger than http.avatar_max_bytes", body = Problem),
+        (status = 415, description = "Not multipart/form-data, or not a supported image", body = Problem),
+    )
+)]
+pub async fn upload_avatar(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    auth::authorize(
+        subject.as_deref(),
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only change their own avatar unless they have the admin role",
+    )?;
+
+    let boundary = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(multipart::boundary)
+        .ok_or_else(|| ApiError::UnsupportedMediaType(String::from("expected multipart/form-data")))?;
+    if users::stored_user(&data, user_id).await?.is_none() {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)).into());
+    }
+
+    let body = read_body(payload, data.avatar_max_bytes + MULTIPART_OVERHEAD).await?;
+    let image = multipart::parse(&body, &boundary)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid multipart body: {}", e)))?
+        .into_iter()
+        .find(|part| part.name.as_deref() == Some(FIELD))
+        .ok_or_else(|| ApiError::BadRequest(format!("missing `{}` field", FIELD)))?
+        .data;
+    if image.len() > data.avatar_max_bytes {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "avatar exceeds {} bytes",
+            data.avatar_max_bytes
+        ))
+        .into());
+    }
+    let content_type = sniff(&image).ok_or_else(|| {
+        ApiError::UnsupportedMediaType(String::from(
+            "avatar must be a PNG, JPEG, GIF or WebP image",
+        ))
+    })?;
+
+    let upload_id = Uuid::new_v4();
+    let written = stream::iter(image.chunks(CHUNK_SIZE).enumerate())
+        .map(|(index, chunk)| {
+            let data = &data;
+            async move {
+                observe::query(data, "insert_avatar_chunk", || {
+                    data.session.execute_unpaged(
+                        &data.statements.insert_avatar_chunk,
+                        (upload_id, index as i32, chunk),
+                    )
+                })
+                .await
+            }
+        })
+        .buffer_unordered(CHUNK_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await;
+    if let Err(e) = written {
+        drop_chunks(&data, upload_id).await;
+        return Err(ApiError::from(e).into());
+    }
+
+    let previous = current(&data, user_id).await?;
+    let avatar = Avatar {
+        upload_id,
+        content_type: content_type.to_string(),
+        size: image.len() as i32,
+        etag: etag(&image),
+        updated_at: Some(Utc::now()),
+    };
+    let stored = observe::query(&data, "insert_avatar", || {
+        data.session.execute_unpaged(
+            &data.statements.insert_avatar,
+            (
+                user_id,
+                avatar.upload_id,
+                &avatar.content_type,
+                avatar.size,
+                &avatar.etag,
+                avatar.updated_at,
+            ),
+        )
+    })
+    .await;
+    if let Err(e) = stored {
+        drop_chunks(&data, upload_id).await;
+        return Err(ApiError::from(e).into());
+    }
+    if let Some(previous) = previous {
+        retire_chunks(&data, previous.upload_id);
+    }
+
+    tracing::info!(%user_id, size = avatar.size, content_type, "avatar uploaded");
+    let mut response = HttpResponse::Ok();
+    caching(&mut response, &avatar);
+    Ok(response.json(format!("Avatar of user {} updated", user_id)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}/avatar",
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The image, with ETag and Cache-Control", content_type = "image/*", body = Vec<u8>),
+        (status = 304, description = "If-None-Match matched the current ETag"),
+        (status = 404, description = "No such user, or no avatar", body = Problem),
+    )
+)]
+pub async fn get_avatar(
+    req: HttpRequest,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user_id.into_inner();
+    if users::stored_user(&data, user_id).await?.is_none() {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)));
+    }
+    let avatar = current(&data, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} has no avatar", user_id)))?;
+
+    let tag = EntityTag::new_strong(avatar.etag.clone());
+    let unchanged = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|candidate| candidate.weak_eq(&tag)),
+        None => false,
+    };
+    if unchanged {
+        let mut response = HttpResponse::NotModified();
+        caching(&mut response, &avatar);
+        return Ok(response.finish());
+    }
+
+    // One small page at a time, so the image is streamed to the client
+    // rather than buffered whole.
+    let mut query = data.statements.select_avatar_chunks.clone();
+    query.set_page_size(4);
+    let pager = observe::query(&data, "select_avatar_chunks", || {
+        data.session.execute_iter(query.clone(), (avatar.upload_id,))
+    })
+    .await?;
+    let chunks = pager
+        .rows_stream::<(Vec<u8>,)>()
+        .map_err(|e| ApiError::internal("Failed to read avatar", e))?
+        .map_ok(|(chunk,)| Bytes::from(chunk));
+
+    let mut response = HttpResponse::Ok();
+    caching(&mut response, &avatar);
+    Ok(response
+        .content_type(avatar.content_type.as_str())
+        .no_chunking(avatar.size as u64)
+        .streaming(chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn images_are_recognised_by_their_magic_bytes() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF89a..."), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"<svg xmlns=..."), None);
+        assert_eq!(sniff(b"RIFF"), None);
+    }
+
+    #[actix_web::test]
+    async fn bodies_over_the_limit_are_refused() {
+        let app = init_service(App::new().route(
+            "/",
+            web::put().to(|payload: web::Payload| async move {
+                read_body(payload, 8)
+                    .await
+                    .map(|body| HttpResponse::Ok().body(body))
+            }),
+        ))
+        .await;
+
+        let fits = TestRequest::put().uri("/").set_payload("12345678").to_request();
+        assert_eq!(call_service(&app, fits).await.status(), 200);
+
+        let too_large = TestRequest::put().uri("/").set_payload("123456789").to_request();
+        assert_eq!(call_service(&app, too_large).await.status(), 413);
+    }
+}
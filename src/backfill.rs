@@ -0,0 +1,324 @@
+use crate::days;
+use crate::emails::{self, Adopted};
+use crate::error::ApiError;
+use crate::observe;
+use crate::search;
+use crate::state::AppState;
+use crate::models::User;
+use crate::users::UserRow;
+use futures::future::BoxFuture;
+#[cfg(test)]
+use futures::future;
+use futures::{FutureExt, Stream, TryStreamExt};
+#[cfg(test)]
+use std::collections::{HashMap, HashSet};
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use uuid::Uuid;
+
+// Fills the tables derived from `users` for rows written before those tables
+// existed: `users_by_email` (migration 0002), `users_by_name` (0003) and
+// `users_by_day` (0027). It scans the whole `users` table, claims each email
+// for its user, indexes each live user's name and each user's creation day.
+// Every write is idempotent, so it is safe to re-run and to run while the
+// server is serving; `backfill` runs it and exits.
+
+// Users between two progress lines.
+const PROGRESS_EVERY: u64 = 1_000;
+
+// Counts of what a run did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub scanned: u64,
+    pub emails_claimed: u64,
+    pub emails_present: u64,
+    // Addresses already claimed by another user, e.g. two legacy users that
+    // differ only in case. Logged for an operator to resolve.
+    pub email_conflicts: u64,
+    pub names_indexed: u64,
+    pub days_indexed: u64,
+    pub failed: u64,
+}
+
+// What was done for one user.
+#[derive(Debug, Default)]
+struct Outcome {
+    email: Option<Adopted>,
+    name_indexed: bool,
+    day_indexed: bool,
+    failed: bool,
+}
+
+impl Report {
+    fn add(&mut self, outcome: Outcome) {
+        self.scanned += 1;
+        match outcome.email {
+            Some(Adopted::Claimed) => self.emails_claimed += 1,
+            Some(Adopted::AlreadyHeld) => self.emails_present += 1,
+            Some(Adopted::HeldBy(_)) => self.email_conflicts += 1,
+            None => {}
+        }
+        if outcome.name_indexed {
+            self.names_indexed += 1;
+        }
+        if outcome.day_indexed {
+            self.days_indexed += 1;
+        }
+        if outcome.failed {
+            self.failed += 1;
+        }
+    }
+
+    // Whether every user was processed; a re-run picks up the rest.
+    pub fn complete(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+// The tables the backfill fills: `AppState` writes them on the cluster, and
+// `MemoryIndexes` keeps them in maps for tests.
+pub trait IndexStore {
+    fn adopt_email<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<Adopted, ApiError>>;
+
+    fn index_name<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), ApiError>>;
+
+    fn index_day<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), ApiError>>;
+}
+
+impl IndexStore for AppState {
+    fn adopt_email<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<Adopted, ApiError>> {
+        emails::adopt(self, &user.email, user.id, user.expires_at).boxed()
+    }
+
+    fn index_name<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), ApiError>> {
+        search::try_index(self, user).boxed()
+    }
+
+    fn index_day<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), ApiError>> {
+        days::try_index(self, user).boxed()
+    }
+}
+
+async fn backfill_user<S: IndexStore>(store: &S, user: User, deleted: bool) -> Outcome {
+    let mut outcome = Outcome::default();
+
+    match store.adopt_email(&user).await {
+        Ok(adopted) => {
+            if let Adopted::HeldBy(holder) = adopted {
+                tracing::warn!(
+                    user_id = %user.id,
+                    %holder,
+                    "email is already claimed by another user"
+                );
+            }
+            outcome.email = Some(adopted);
+        }
+        Err(e) => {
+            tracing::warn!(user_id = %user.id, error = %e, "failed to claim email");
+            outcome.failed = true;
+        }
+    }
+
+    // Soft-deleted users keep their email claim but are left out of search.
+    if !deleted {
+        match store.index_name(&user).await {
+            Ok(()) => outcome.name_indexed = true,
+            Err(e) => {
+                tracing::warn!(user_id = %user.id, error = %e, "failed to index user name");
+                outcome.failed = true;
+            }
+        }
+    }
+    // Soft-deleted users keep their day, as they do when deleted.
+    match store.index_day(&user).await {
+        Ok(()) => outcome.day_indexed = true,
+        Err(e) => {
+            tracing::warn!(user_id = %user.id, error = %e, "failed to index user creation day");
+            outcome.failed = true;
+        }
+    }
+    outcome
+}
+
+// Runs the backfill over every user, `concurrency` users at a time. Failures
+// for single users are counted and logged; an error reading `users` ends the
+// run.
+pub async fn run(state: &AppState, concurrency: usize) -> Result<Report, ApiError> {
+    let pager = observe::query(state, "select_all_users", || {
+        state
+            .session
+            .execute_iter(state.statements.select_all_users.clone(), ())
+    })
+    .await?;
+    let users = pager
+        .rows_stream::<UserRow>()
+        .map_err(|e| ApiError::internal("Error streaming users", e))?
+        .map_err(|e| ApiError::internal("Error fetching users", e))
+        .map_ok(|row| {
+            let deleted = row.is_deleted();
+            (row.into_user(), deleted)
+        });
+    fill(state, users, concurrency).await
+}
+
+// Backfills `users`, each with whether it is soft-deleted, into `store`.
+async fn fill<S: IndexStore>(
+    store: &S,
+    users: impl Stream<Item = Result<(User, bool), ApiError>> + Unpin,
+    concurrency: usize,
+) -> Result<Report, ApiError> {
+    let mut outcomes = users
+        .map_ok(|(user, deleted)| backfill_user(store, user, deleted).map(Ok))
+        .try_buffer_unordered(concurrency);
+
+    let mut report = Report::default();
+    while let Some(outcome) = outcomes.try_next().await? {
+        report.add(outcome);
+        if report.scanned % PROGRESS_EVERY == 0 {
+            tracing::info!(scanned = report.scanned, failed = report.failed, "backfill progress");
+        }
+    }
+    tracing::info!(
+        scanned = report.scanned,
+        emails_claimed = report.emails_claimed,
+        emails_present = report.emails_present,
+        email_conflicts = report.email_conflicts,
+        names_indexed = report.names_indexed,
+        days_indexed = report.days_indexed,
+        failed = report.failed,
+        "backfill finished"
+    );
+    Ok(report)
+}
+
+// The derived tables kept in maps: claimed addresses, by lowercased address,
+// and the users with their name and day indexed.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryIndexes {
+    emails: Mutex<HashMap<String, Uuid>>,
+    names: Mutex<HashSet<Uuid>>,
+    days: Mutex<HashSet<Uuid>>,
+}
+
+#[cfg(test)]
+impl IndexStore for MemoryIndexes {
+    fn adopt_email<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<Adopted, ApiError>> {
+        let mut emails = self.emails.lock().unwrap();
+        let adopted = match emails.get(&user.email.to_lowercase()) {
+            Some(&holder) if holder == user.id => Adopted::AlreadyHeld,
+            Some(&holder) => Adopted::HeldBy(holder),
+            None => {
+                emails.insert(user.email.to_lowercase(), user.id);
+                Adopted::Claimed
+            }
+        };
+        future::ready(Ok(adopted)).boxed()
+    }
+
+    fn index_name<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), ApiError>> {
+        self.names.lock().unwrap().insert(user.id);
+        future::ready(Ok(())).boxed()
+    }
+
+    fn index_day<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), ApiError>> {
+        self.days.lock().unwrap().insert(user.id);
+        future::ready(Ok(())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use futures::stream;
+
+    fn user(email: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: email.to_string(),
+            phone: None,
+            profile: None,
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn fills_every_index_for_the_seeded_users() {
+        let seeded = vec![
+            (user("ada@example.com"), false),
+            (user("grace@example.com"), false),
+            (user("edsger@example.com"), true),
+        ];
+        let store = MemoryIndexes::default();
+        let rows = || stream::iter(seeded.clone().into_iter().map(Ok));
+        let report = fill(&store, rows(), 2).await.unwrap();
+        assert!(report.complete());
+        assert_eq!(report.scanned, 3);
+        assert_eq!(report.emails_claimed, 3);
+
+        let emails = store.emails.lock().unwrap().clone();
+        let names = store.names.lock().unwrap().clone();
+        let days = store.days.lock().unwrap().clone();
+        for (user, deleted) in &seeded {
+            assert_eq!(emails.get(&user.email), Some(&user.id));
+            assert_eq!(names.contains(&user.id), !deleted);
+            assert!(days.contains(&user.id));
+        }
+
+        let rerun = fill(&store, rows(), 2).await.unwrap();
+        assert_eq!(rerun.emails_present, 3);
+        assert_eq!(store.emails.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn report_counts_each_outcome() {
+        let mut report = Report::default();
+        report.add(Outcome {
+            email: Some(Adopted::Claimed),
+            name_indexed: true,
+            day_indexed: true,
+            failed: false,
+        });
+        report.add(Outcome {
+            email: Some(Adopted::AlreadyHeld),
+            name_indexed: false,
+            day_indexed: true,
+            failed: false,
+        });
+        report.add(Outcome {
+            email: Some(Adopted::HeldBy(Uuid::new_v4())),
+            name_indexed: true,
+            day_indexed: false,
+            failed: false,
+        });
+        assert!(report.complete());
+        report.add(Outcome {
+            email: None,
+            name_indexed: false,
+            day_indexed: false,
+            failed: true,
+        });
+        assert_eq!(
+            report,
+            Report {
+                scanned: 4,
+                emails_claimed: 1,
+                emails_present: 1,
+                email_conflicts: 1,
+                names_indexed: 2,
+                days_indexed: 2,
+                failed: 1,
+            }
+        );
+        assert!(!report.complete());
+    }
+}
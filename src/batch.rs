@@ -0,0 +1,544 @@
+use crate::audit::{self, Action};
+use crate::config::BatchMode;
+use crate::cql::Condition;
+use crate::days;
+use crate::emails;
+use crate::error::{ApiError, FieldError, Problem};
+use crate::events::EventKind;
+use crate::history;
+use crate::login;
+use crate::models::{
+    BatchOperation, BatchQuery, BatchRequest, BatchResponse, NewUser, UpdateUser, User,
+    UserStatus,
+};
+use crate::negotiate::Body;
+use crate::observe;
+use crate::phones;
+use crate::repository::BatchWrite;
+use crate::search;
+use crate::state::AppState;
+use crate::users::{self, Registration};
+use crate::validation;
+use crate::verification;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use scylla::batch::{Batch, BatchType};
+use scylla::frame::response::result::CqlValue;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+// POST /batch applies a list of user mutations as one CQL batch. Conditional
+// statements can't span partitions in a batch, so unlike the single-item
+// routes the existence of updated and deleted users is checked by a read
+// beforehand, and email and phone claims are taken before the batch and
+// rolled back if it fails. The name search rows ride along in the same batch. Deletes are
+// soft, as on DELETE /delete/{id}. So do the audit log entries. With
+// `shadow.dual_write`, the `users` rows are written to the shadow cluster
+// once the batch has gone through (see `shadow`).
+
+enum Planned {
+    Insert {
+        id: Uuid,
+        user: NewUser,
+        password_hash: Option<String>,
+        expires_at: Option<DateTime<Utc>>,
+    },
+    Update {
+        before: User,
+        changes: UpdateUser,
+    },
+    Delete {
+        before: User,
+    },
+}
+
+// Validates every operation up front, so a batch is rejected as a whole with
+// all of its field errors rather than failing half-way.
+fn validate(
+    operations: Vec<BatchOperation>,
+    policy: &validation::Policy,
+) -> Result<Vec<(usize, BatchOperation)>, ApiError> {
+    let mut field_errors = Vec::new();
+    let mut valid = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.into_iter().enumerate() {
+        let checked = match operation {
+            BatchOperation::Insert { user } => validation::new_user(user).and_then(|user| {
+                policy.enforce(&user.email)?;
+                Ok(BatchOperation::Insert { user })
+            }),
+            BatchOperation::Update { id, changes } => {
+                validation::update_user(changes).and_then(|changes| {
+                    if let Some(email) = &changes.email {
+                        policy.enforce(email)?;
+                    }
+                    Ok(BatchOperation::Update { id, changes })
+                })
+            }
+            delete @ BatchOperation::Delete { .. } => Ok(delete),
+        };
+        match checked {
+            Ok(operation) => valid.push((index, operation)),
+            Err(ApiError::Validation(errors)) => {
+                field_errors.extend(errors.into_iter().map(|e| FieldError {
+                    field: format!("operations[{}].{}", index, e.field),
+                    message: e.message,
+                }))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if field_errors.is_empty() {
+        Ok(valid)
+    } else {
+        Err(ApiError::Validation(field_errors))
+    }
+}
+
+// What `?validate_first=true` finds wrong with validated operations, given the
+// stored email of each updated or deleted user that exists and the emails,
+// as `emails::key`, already taken: missing users, taken emails and emails
+// used twice in the batch.
+fn precheck_problems(
+    operations: &[(usize, BatchOperation)],
+    stored: &HashMap<Uuid, String>,
+    taken: &HashSet<String>,
+) -> Vec<FieldError> {
+    let mut problems = Vec::new();
+    let mut first_use: HashMap<String, usize> = HashMap::new();
+    let mut problem = |index: usize, field: &str, message: String| {
+        problems.push(FieldError {
+            field: format!("operations[{}].{}", index, field),
+            message,
+        })
+    };
+    for (index, operation) in operations {
+        let (email, field) = match operation {
+            BatchOperation::Insert { user } => (Some(&user.email), "email"),
+            BatchOperation::Update { id, changes } => match stored.get(id) {
+                None => {
+                    problem(*index, "id", format!("user {} not found", id));
+                    continue;
+                }
+                Some(current) => (
+                    changes
+                        .email
+                        .as_ref()
+                        .filter(|email| emails::key(email) != emails::key(current)),
+                    "changes.email",
+                ),
+            },
+            BatchOperation::Delete { id } => {
+                if !stored.contains_key(id) {
+                    problem(*index, "id", format!("user {} not found", id));
+                }
+                continue;
+            }
+        };
+        let Some(email) = email else {
+            continue;
+        };
+        let key = emails::key(email);
+        if taken.contains(&key) {
+            problem(*index, field, String::from("is already registered"));
+        } else if let Some(first) = first_use.get(&key) {
+            problem(*index, field, format!("is also used by operations[{}]", first));
+        } else {
+            first_use.insert(key, *index);
+        }
+    }
+    problems
+}
+
+// Runs the existence and email-availability checks of `plan` for the whole
+// batch without claiming anything, so every problem is reported at once. The
+// checks are reads: an email registered between them and its claim still
+// fails the claim with 409, as without the option.
+async fn precheck(data: &AppState, operations: &[(usize, BatchOperation)]) -> Result<(), ApiError> {
+    let mut stored = HashMap::new();
+    for (_, operation) in operations {
+        if let BatchOperation::Update { id, .. } | BatchOperation::Delete { id } = operation
+            && !stored.contains_key(id)
+            && let Some(user) = users::stored_user(data, *id).await?
+        {
+            stored.insert(*id, user.email);
+        }
+    }
+    let mut taken = HashSet::new();
+    for (_, operation) in operations {
+        let email = match operation {
+            BatchOperation::Insert { user } => Some(&user.email),
+            BatchOperation::Update { id, changes } => changes
+                .email
+                .as_ref()
+                .filter(|email| {
+                    stored
+                        .get(id)
+                        .is_some_and(|current| emails::key(current) != emails::key(email))
+                }),
+            BatchOperation::Delete { .. } => None,
+        };
+        if let Some(email) = email
+            && !taken.contains(&emails::key(email))
+            && emails::taken(data, email).await?
+        {
+            taken.insert(emails::key(email));
+        }
+    }
+
+    let problems = precheck_problems(operations, &stored, &taken);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::Validation(problems))
+    }
+}
+
+// An email or phone number claimed for a user.
+enum Claim {
+    Email(String, Uuid),
+    Phone(String, Uuid),
+}
+
+// Claims taken, released again if the batch doesn't go through.
+async fn release_all(data: &AppState, claims: &[Claim]) {
+    for claim in claims {
+        match claim {
+            Claim::Email(email, user_id) => emails::release(data, email, *user_id).await,
+            Claim::Phone(phone, user_id) => phones::release(data, phone, *user_id).await,
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/batch",
+    params(BatchQuery),
+    request_body = BatchRequest,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Every operation was applied", body = BatchResponse),
+        (status = 400, description = "No operations, or more than http.batch_max_operations", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "An updated or deleted user does not exist", body = Problem),
+        (status = 409, description = "An email or phone number is already registered", body = Problem),
+        (status = 422, description = "Invalid operations, or with validate_first every missing user and taken email; nothing was applied", body = Problem),
+    )
+)]
+pub async fn apply_batch(
+    Body(request): Body<BatchRequest>,
+    query: web::Query<BatchQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let operations = request.operations;
+    if operations.is_empty() || operations.len() > data.batch_max_operations {
+        return Err(ApiError::BadRequest(format!(
+            "expected between 1 and {} operations",
+            data.batch_max_operations
+        )));
+    }
+
+    let mut planned = Vec::with_capacity(operations.len());
+    // Claims that must be dropped after (`stale`) or instead of (`claimed`) a
+    // successful batch.
+    let mut claimed = Vec::new();
+    let mut stale = Vec::new();
+    let operations = validate(operations, &data.validation)?;
+    if query.validate_first.unwrap_or(false) {
+        precheck(&data, &operations).await?;
+    }
+    for (index, operation) in operations {
+        let step = plan(&data, index, operation, &mut claimed, &mut stale).await;
+        match step {
+            Ok(step) => planned.push(step),
+            Err(e) => {
+                release_all(&data, &claimed).await;
+                return Err(e);
+            }
+        }
+    }
+
+    let mut batch = Batch::new(match data.batch_type {
+        BatchMode::Logged => BatchType::Logged,
+        BatchMode::Unlogged => BatchType::Unlogged,
+    });
+    let mut values: Vec<Vec<Option<CqlValue>>> = Vec::with_capacity(planned.len());
+    let mut created = Vec::new();
+    // Published, and mirrored to the shadow cluster, once the batch has gone
+    // through.
+    let mut events = Vec::with_capacity(planned.len());
+    let mut writes = Vec::with_capacity(planned.len());
+    let now = Utc::now();
+    for step in &planned {
+        match step {
+            Planned::Insert {
+                id,
+                user,
+                password_hash,
+                expires_at,
+            } => {
+                batch.append_statement(data.statements.insert_user.clone());
+                values.push(vec![
+                    Some(CqlValue::Uuid(*id)),
+                    Some(CqlValue::Text(user.name.clone())),
+                    Some(CqlValue::Text(user.email.clone())),
+                    user.phone.clone().map(CqlValue::Text),
+                    password_hash.clone().map(CqlValue::Text),
+                    user.profile
+                        .as_ref()
+                        .map(|profile| users::profile_value(&data.keyspace, profile)),
+                    Some(CqlValue::Timestamp(now.into())),
+                    Some(CqlValue::Timestamp(now.into())),
+                    Some(CqlValue::Int(users::ttl(*expires_at))),
+                ]);
+                let indexed = User {
+                    id: *id,
+                    name: user.name.clone(),
+                    email: user.email.clone(),
+                    phone: user.phone.clone(),
+                    profile: user.profile.clone(),
+                    created_at: Some(now),
+                    updated_at: Some(now),
+                    expires_at: *expires_at,
+                    verified: Some(false),
+                    version: Some(1),
+                    status: Some(UserStatus::Active),
+                    anonymized_at: None,
+                };
+                batch.append_statement(data.statements.index_user_name.clone());
+                values.push(search::index_values(&indexed));
+                if let Some(day_values) = days::index_values(&indexed) {
+                    batch.append_statement(data.statements.index_user_day.clone());
+                    values.push(day_values);
+                }
+                batch.append_statement(data.statements.insert_audit_entry.clone());
+                values.push(audit::entry_values(*id, Action::Create, None, Some(&indexed), now));
+                created.push(*id);
+                writes.push(BatchWrite::Insert(Registration {
+                    user: indexed.clone(),
+                    password_hash: password_hash.clone(),
+                }));
+                events.push((EventKind::Created, *id, Some(indexed)));
+            }
+            Planned::Update { before, changes } => {
+                let (query, params) =
+                    users::update_statement(&data.keyspace, changes, before, now, Condition::None);
+                let prepared = match data.statements.get_or_prepare(&data.session, query).await {
+                    Ok(prepared) => prepared,
+                    Err(e) => {
+                        release_all(&data, &claimed).await;
+                        return Err(e.into());
+                    }
+                };
+                batch.append_statement(prepared);
+                values.push(params);
+
+                let after = users::apply_update(before, changes, now);
+                if search::normalize(&before.name) != search::normalize(&after.name) {
+                    batch.append_statement(data.statements.unindex_user_name.clone());
+                    values.push(search::unindex_values(before).into_iter().map(Some).collect());
+                }
+                batch.append_statement(data.statements.index_user_name.clone());
+                values.push(search::index_values(&after));
+                batch.append_statement(data.statements.insert_audit_entry.clone());
+                values.push(audit::entry_values(after.id, Action::Update, Some(before), Some(&after), now));
+                writes.push(BatchWrite::Update {
+                    before: before.clone(),
+                    update: changes.clone(),
+                });
+                events.push((EventKind::Updated, after.id, Some(after)));
+            }
+            Planned::Delete { before } => {
+                batch.append_statement(data.statements.soft_delete_user_in_batch.clone());
+                values.push(vec![
+                    Some(CqlValue::Int(users::ttl(before.expires_at))),
+                    Some(CqlValue::Timestamp(now.into())),
+                    Some(CqlValue::Uuid(before.id)),
+                ]);
+                batch.append_statement(data.statements.unindex_user_name.clone());
+                values.push(search::unindex_values(before).into_iter().map(Some).collect());
+                batch.append_statement(data.statements.insert_audit_entry.clone());
+                values.push(audit::entry_values(before.id, Action::Delete, Some(before), None, now));
+                writes.push(BatchWrite::SoftDelete(before.clone()));
+                events.push((EventKind::Deleted, before.id, None));
+            }
+        }
+    }
+
+    if let Err(e) = observe::query(&data, "batch", || data.session.batch(&batch, &values)).await {
+        release_all(&data, &claimed).await;
+        return Err(e.into());
+    }
+    release_all(&data, &stale).await;
+    data.users.mirror_batch(&writes, now).await;
+    for (kind, user_id, user) in events {
+        users::forget_cached(&data, user_id).await;
+        if let (EventKind::Created, Some(user)) = (kind, &user) {
+            verification::issue(&data, user).await;
+        }
+        history::append(&data, kind, user_id, user).await;
+    }
+
+    tracing::info!(applied = planned.len(), created = created.len(), "batch applied");
+    Ok(HttpResponse::Ok().json(BatchResponse {
+        applied: planned.len(),
+        created,
+    }))
+}
+
+// Turns one validated operation into a batch step: hashes passwords, checks
+// that updated and deleted users exist, and claims new emails and phone
+// numbers.
+async fn plan(
+    data: &AppState,
+    index: usize,
+    operation: BatchOperation,
+    claimed: &mut Vec<Claim>,
+    stale: &mut Vec<Claim>,
+) -> Result<Planned, ApiError> {
+    let not_found = |id: Uuid| ApiError::NotFound(format!("operation {}: user {} not found", index, id));
+    match operation {
+        BatchOperation::Insert { user } => {
+            let id = Uuid::new_v4();
+            let password_hash = match &user.password {
+                Some(password) => Some(
+                    login::hash_password_blocking(password.clone())
+                        .await
+                        .map_err(|e| ApiError::internal("Failed to hash password", e))?,
+                ),
+                None => None,
+            };
+            let expires_at = users::expiry(data, user.expires_in_seconds, Utc::now());
+            emails::claim(data, &user.email, id, expires_at).await?;
+            claimed.push(Claim::Email(user.email.clone(), id));
+            if let Some(phone) = &user.phone {
+                phones::claim(data, phone, id, expires_at).await?;
+                claimed.push(Claim::Phone(phone.clone(), id));
+            }
+            Ok(Planned::Insert {
+                id,
+                user,
+                password_hash,
+                expires_at,
+            })
+        }
+        BatchOperation::Update { id, changes } => {
+            let before = users::stored_user(data, id).await?.ok_or_else(|| not_found(id))?;
+            users::check_expected(&before, changes.version)?;
+            if let Some(email) = &changes.email
+                && !before.email.eq_ignore_ascii_case(email)
+            {
+                emails::claim(data, email, id, before.expires_at).await?;
+                claimed.push(Claim::Email(email.clone(), id));
+                stale.push(Claim::Email(before.email.clone(), id));
+            }
+            if let Some(phone) = &changes.phone
+                && before.phone.as_ref() != Some(phone)
+            {
+                phones::claim(data, phone, id, before.expires_at).await?;
+                claimed.push(Claim::Phone(phone.clone(), id));
+                if let Some(stale_phone) = &before.phone {
+                    stale.push(Claim::Phone(stale_phone.clone(), id));
+                }
+            }
+            Ok(Planned::Update { before, changes })
+        }
+        BatchOperation::Delete { id } => {
+            let before = users::stored_user(data, id).await?.ok_or_else(|| not_found(id))?;
+            Ok(Planned::Delete { before })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(email: &str) -> BatchOperation {
+        BatchOperation::Insert {
+            user: NewUser {
+                name: String::from("Ada"),
+                email: email.to_string(),
+                phone: None,
+                password: None,
+                profile: None,
+                expires_in_seconds: None,
+            },
+        }
+    }
+
+    fn change_email(id: Uuid, email: &str) -> BatchOperation {
+        BatchOperation::Update {
+            id,
+            changes: UpdateUser {
+                name: None,
+                email: Some(email.to_string()),
+                phone: None,
+                profile: None,
+                version: None,
+            },
+        }
+    }
+
+    fn fields(problems: &[FieldError]) -> Vec<(&str, &str)> {
+        problems
+            .iter()
+            .map(|problem| (problem.field.as_str(), problem.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn a_valid_batch_has_no_problems() {
+        let existing = Uuid::new_v4();
+        let operations: Vec<_> = [
+            insert("ada@example.com"),
+            change_email(existing, "Bob@Example.com"),
+            BatchOperation::Delete { id: existing },
+        ]
+        .into_iter()
+        .enumerate()
+        .collect();
+        let stored = HashMap::from([(existing, String::from("bob@example.com"))]);
+        assert!(precheck_problems(&operations, &stored, &HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn every_problem_is_reported_by_operation() {
+        let existing = Uuid::new_v4();
+        let missing = Uuid::new_v4();
+        let operations: Vec<_> = [
+            insert("ada@example.com"),
+            insert("taken@example.com"),
+            change_email(existing, "ADA@example.com"),
+            change_email(missing, "new@example.com"),
+            BatchOperation::Delete { id: missing },
+        ]
+        .into_iter()
+        .enumerate()
+        .collect();
+        let stored = HashMap::from([(existing, String::from("bob@example.com"))]);
+        let taken = HashSet::from([String::from("taken@example.com")]);
+        assert_eq!(
+            fields(&precheck_problems(&operations, &stored, &taken)),
+            [
+                ("operations[1].email", "is already registered"),
+                ("operations[2].changes.email", "is also used by operations[0]"),
+                ("operations[3].id", &*format!("user {} not found", missing)),
+                ("operations[4].id", &*format!("user {} not found", missing)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_single_failing_row_fails_the_precheck() {
+        let operations: Vec<_> = [insert("ada@example.com"), insert("taken@example.com")]
+            .into_iter()
+            .enumerate()
+            .collect();
+        let taken = HashSet::from([String::from("taken@example.com")]);
+        assert_eq!(
+            fields(&precheck_problems(&operations, &HashMap::new(), &taken)),
+            [("operations[1].email", "is already registered")]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::config::ScyllaConfig;
+use crate::models::BreakerState;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Circuit breaker in front of every CQL request (`observe`). After
+// `scylla.breaker_failures` requests in a row fail with a transient error (the
+// cluster timing out, overloaded or unreachable, after any retries) it opens:
+// for `scylla.breaker_open_secs` requests fail at once with 503 and
+// `Retry-After` instead of each waiting out its own timeouts on a worker.
+// Then it lets a single request through; its success closes the breaker and
+// its failure opens it again. A trial that never reports back (its handler was
+// dropped) is replaced after another open period. Errors in the request itself
+// (bad queries, failed conditions) say nothing about the cluster's health and
+// leave the breaker alone.
+pub struct Breaker {
+    threshold: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { trial_since: Instant },
+}
+
+impl Breaker {
+    pub fn new(config: &ScyllaConfig) -> Self {
+        Breaker {
+            threshold: config.breaker_failures,
+            open_for: Duration::from_secs(config.breaker_open_secs),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // Whether a request may be sent now; if not, how long until one may.
+    pub fn admit(&self) -> Result<(), Duration> {
+        self.admit_at(Instant::now())
+    }
+
+    fn admit_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(until - now),
+            State::HalfOpen { trial_since } if now < trial_since + self.open_for => {
+                Err(trial_since + self.open_for - now)
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                tracing::info!("circuit breaker half-open, trying one request");
+                *state = State::HalfOpen { trial_since: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    // Records how a request sent to the cluster went: `healthy` unless it
+    // failed in a way that says the cluster is in trouble.
+    pub fn record(&self, healthy: bool) {
+        self.record_at(healthy, Instant::now());
+    }
+
+    fn record_at(&self, healthy: bool, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, healthy) {
+            (State::Closed { failures: 0 }, true) => return,
+            (State::Closed { .. }, true) => State::Closed { failures: 0 },
+            (_, true) => {
+                tracing::info!("circuit breaker closed, the cluster is answering again");
+                State::Closed { failures: 0 }
+            }
+            (State::Closed { failures }, false) if failures + 1 < self.threshold => {
+                State::Closed { failures: failures + 1 }
+            }
+            // Requests sent before the breaker opened may still be failing.
+            (State::Open { until }, false) => State::Open { until },
+            (_, false) => {
+                tracing::warn!(open_for = ?self.open_for, "circuit breaker opened, failing fast");
+                State::Open { until: now + self.open_for }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32) -> Breaker {
+        Breaker::new(&ScyllaConfig {
+            breaker_failures: threshold,
+            breaker_open_secs: 10,
+            ..ScyllaConfig::default()
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(3);
+        let now = Instant::now();
+        breaker.record_at(false, now);
+        breaker.record_at(false, now);
+        breaker.record_at(true, now);
+        breaker.record_at(false, now);
+        breaker.record_at(false, now);
+        assert_eq!(breaker.admit_at(now), Ok(()));
+
+        breaker.record_at(false, now);
+        let later = now + Duration::from_secs(4);
+        assert_eq!(breaker.admit_at(later), Err(Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn one_trial_decides_whether_to_close() {
+        let breaker = breaker(1);
+        let now = Instant::now();
+        breaker.record_at(false, now);
+
+        let reopen = now + Duration::from_secs(10);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.admit_at(reopen), Ok(()));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.admit_at(reopen).is_err());
+        breaker.record_at(false, reopen);
+        assert_eq!(breaker.admit_at(reopen + Duration::from_secs(1)), Err(Duration::from_secs(9)));
+
+        let retrial = reopen + Duration::from_secs(10);
+        assert_eq!(breaker.admit_at(retrial), Ok(()));
+        breaker.record_at(true, retrial);
+        assert_eq!(breaker.admit_at(retrial), Ok(()));
+        assert_eq!(breaker.admit_at(retrial), Ok(()));
+    }
+
+    #[test]
+    fn abandoned_trials_are_replaced() {
+        let breaker = breaker(1);
+        let now = Instant::now();
+        breaker.record_at(false, now);
+        let trial = now + Duration::from_secs(10);
+        assert_eq!(breaker.admit_at(trial), Ok(()));
+        assert!(breaker.admit_at(trial + Duration::from_secs(9)).is_err());
+        assert_eq!(breaker.admit_at(trial + Duration::from_secs(10)), Ok(()));
+    }
+
+    #[test]
+    fn a_zero_threshold_never_opens() {
+        let breaker = breaker(0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            breaker.record_at(false, now);
+        }
+        assert_eq!(breaker.admit_at(now), Ok(()));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::auth::Subject;
+use crate::error::{ApiError, Problem};
+use crate::models::{BulkDeleteRequest, BulkDeleteResponse, BulkDeleteResult, User, UserStatus};
+use crate::observe;
+use crate::state::AppState;
+use crate::users::{self, UserRow};
+use actix_web::{web, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use uuid::Uuid;
+
+// POST /users/delete deletes many users in one request, for cleanups that
+// would otherwise take a DELETE per user: the users named by `ids`, or those
+// a filter on status and age matches. Each user is deleted as DELETE
+// /delete/{id} would, with its claims, index rows, event and audit entry,
+// `http.bulk_concurrency` at a time, and one failing doesn't stop the rest.
+//
+// A filter scans `users` for up to `http.bulk_max_items` matches and says
+// whether there were more; deleted users no longer match, so sending the
+// same filter again deletes the next ones.
+
+// Whether `user` matches the filter: `status`, when given, and last changed
+// before `older_than`, when given.
+fn matches(user: &User, status: Option<UserStatus>, older_than: Option<DateTime<Utc>>) -> bool {
+    let status_matches = status.is_none_or(|status| user.status.unwrap_or_default() == status);
+    let changed = user.updated_at.or(user.created_at);
+    let old_enough = match (older_than, changed) {
+        (Some(older_than), Some(changed)) => changed < older_than,
+        // Users with no timestamps predate them, so are older than any time.
+        (Some(_), None) => true,
+        (None, _) => true,
+    };
+    status_matches && old_enough
+}
+
+// Up to `limit` live users matching the filter, in storage order, and
+// whether there were more.
+async fn matching(
+    data: &AppState,
+    status: Option<UserStatus>,
+    older_than: Option<DateTime<Utc>>,
+    limit: usize,
+) -> Result<(Vec<Uuid>, bool), ApiError> {
+    let pager = observe::query(data, "select_all_users", || {
+        data.session
+            .execute_iter(data.statements.select_all_users.clone(), ())
+    })
+    .await?;
+    let mut ids: Vec<Uuid> = pager
+        .rows_stream::<UserRow>()
+        .map_err(|e| ApiError::internal("Error streaming users", e))?
+        .map_err(|e| ApiError::internal("Error fetching users", e))
+        .try_filter(|row| future::ready(!row.is_deleted()))
+        .map_ok(UserRow::into_user)
+        .try_filter(|user| future::ready(matches(user, status, older_than)))
+        .map_ok(|user| user.id)
+        .take(limit + 1)
+        .try_collect()
+        .await?;
+    let more = ids.len() > limit;
+    ids.truncate(limit);
+    Ok((ids, more))
+}
+
+/// Deletes the users named by `ids`, or up to `http.bulk_max_items` users
+/// matching `status` and `older_than`, each as DELETE /delete/{id} would.
+#[utoipa::path(
+    post,
+    path = "/users/delete",
+    request_body = BulkDeleteRequest,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Per-user outcomes", body = BulkDeleteResponse),
+        (status = 400, description = "Neither or both of ids and a filter, or too many ids", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+    )
+)]
+pub async fn delete_users(
+    subject: Option<web::ReqData<Subject>>,
+    request: web::Json<BulkDeleteRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let BulkDeleteRequest {
+        ids,
+        status,
+        older_than,
+        hard,
+    } = request.into_inner();
+    let hard = hard.unwrap_or(false);
+    let filtered = status.is_some() || older_than.is_some();
+    let (ids, more) = match ids {
+        Some(_) if filtered => {
+            return Err(ApiError::BadRequest(String::from(
+                "give either ids or status and older_than, not both",
+            )));
+        }
+        Some(ids) if ids.is_empty() || ids.len() > data.bulk_max_items => {
+            return Err(ApiError::BadRequest(format!(
+                "expected between 1 and {} ids",
+                data.bulk_max_items
+            )));
+        }
+        Some(ids) => (ids, false),
+        None if filtered => matching(&data, status, older_than, data.bulk_max_items).await?,
+        None => {
+            return Err(ApiError::BadRequest(String::from(
+                "give ids, or status or older_than to choose the users",
+            )));
+        }
+    };
+
+    let results: Vec<BulkDeleteResult> = stream::iter(ids)
+        .map(|id| {
+            let data = &data;
+            async move {
+                match users::delete(data, id, hard, None, false).await {
+                    Ok(_) => BulkDeleteResult {
+                        id,
+                        status: 200,
+                        error: None,
+                    },
+                    Err(e) => BulkDeleteResult {
+                        id,
+                        status: e.status_code().as_u16(),
+                        error: Some(e.to_problem()),
+                    },
+                }
+            }
+        })
+        .buffered(data.bulk_concurrency)
+        .collect()
+        .await;
+
+    let deleted = results.iter().filter(|result| result.error.is_none()).count();
+    let failed = results.len() - deleted;
+    let actor = subject.as_ref().map_or("anonymous", |subject| subject.id.as_str());
+    tracing::info!(deleted, failed, hard, more, actor, "users deleted in bulk");
+    Ok(HttpResponse::Ok().json(BulkDeleteResponse {
+        deleted,
+        failed,
+        results,
+        more,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(status: Option<UserStatus>, updated_at: Option<&str>) -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: Some("2025-01-01T00:00:00Z".parse().unwrap()),
+            updated_at: updated_at.map(|at| at.parse().unwrap()),
+            expires_at: None,
+            verified: None,
+            version: None,
+            status,
+            anonymized_at: None,
+        }
+    }
+
+    #[test]
+    fn filters_match_the_status_and_the_last_change() {
+        let cutoff = Some("2026-01-01T00:00:00Z".parse().unwrap());
+        let deactivated = Some(UserStatus::Deactivated);
+        let stale = user(deactivated, Some("2025-06-01T00:00:00Z"));
+        let recent = user(deactivated, Some("2026-03-01T00:00:00Z"));
+        assert!(matches(&stale, deactivated, cutoff));
+        assert!(!matches(&recent, deactivated, cutoff));
+        assert!(matches(&recent, deactivated, None));
+        // Never changed: the creation time counts, and no status is active.
+        let untouched = user(None, None);
+        assert!(matches(&untouched, Some(UserStatus::Active), cutoff));
+        assert!(!matches(&untouched, deactivated, cutoff));
+    }
+}
@@ -0,0 +1,250 @@
+use crate::models::User;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Live users by id for point lookups, bounded to `capacity` entries (the
+// least recently used goes first) and kept for at most `ttl`. Writes through
+// this instance, and changes the CDC consumer reads, invalidate their user at
+// once; other writes are picked up when the entry expires. A capacity of 0
+// turns the cache off.
+//
+// Lookups that miss read the row and then `insert` it with the version taken
+// before the read. Every invalidation bumps the version, so a row read before
+// a concurrent write can't be cached after that write invalidated it.
+
+struct Entry {
+    user: User,
+    expires: Instant,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Uuid, Entry>,
+    // Entries by last use, oldest first; keys are ticks of `clock`.
+    recency: BTreeMap<u64, Uuid>,
+    clock: u64,
+    version: u64,
+    ttl: Duration,
+}
+
+pub struct UserCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl UserCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        UserCache {
+            capacity,
+            inner: Mutex::new(Inner {
+                ttl,
+                ..Inner::default()
+            }),
+        }
+    }
+
+    // Applies to entries inserted from now on.
+    pub fn set_ttl(&self, ttl: Duration) {
+        self.lock().ttl = ttl;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("user cache lock poisoned")
+    }
+
+    pub fn get(&self, user_id: Uuid) -> Option<User> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let entry = inner.entries.get_mut(&user_id)?;
+        inner.recency.remove(&entry.used);
+        if entry.expires <= Instant::now() {
+            inner.entries.remove(&user_id);
+            return None;
+        }
+        inner.clock += 1;
+        entry.used = inner.clock;
+        inner.recency.insert(entry.used, user_id);
+        Some(entry.user.clone())
+    }
+
+    pub fn version(&self) -> u64 {
+        self.lock().version
+    }
+
+    pub fn insert(&self, version: u64, user: User) {
+        if !self.enabled() {
+            return;
+        }
+        let mut inner = self.lock();
+        if inner.version != version {
+            return;
+        }
+        inner.clock += 1;
+        let used = inner.clock;
+        let user_id = user.id;
+        let entry = Entry {
+            user,
+            expires: Instant::now() + inner.ttl,
+            used,
+        };
+        if let Some(replaced) = inner.entries.insert(user_id, entry) {
+            inner.recency.remove(&replaced.used);
+        } else if inner.entries.len() > self.capacity
+            && let Some((_, oldest)) = inner.recency.pop_first()
+        {
+            inner.entries.remove(&oldest);
+        }
+        inner.recency.insert(used, user_id);
+    }
+
+    // Drops the expired entries, returning how many there were. They would
+    // otherwise linger until looked up or evicted.
+    pub fn sweep(&self) -> usize {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let now = Instant::now();
+        let before = inner.entries.len();
+        inner.entries.retain(|_, entry| {
+            let live = entry.expires > now;
+            if !live {
+                inner.recency.remove(&entry.used);
+            }
+            live
+        });
+        before - inner.entries.len()
+    }
+
+    pub fn invalidate(&self, user_id: Uuid) {
+        let mut inner = self.lock();
+        inner.version += 1;
+        if let Some(entry) = inner.entries.remove(&user_id) {
+            inner.recency.remove(&entry.used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            email: format!("{}@example.com", name.to_lowercase()),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    fn cache(capacity: usize) -> UserCache {
+        UserCache::new(capacity, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn hits_return_the_inserted_user() {
+        let cache = cache(2);
+        let ada = user("Ada");
+        assert!(cache.get(ada.id).is_none());
+        cache.insert(cache.version(), ada.clone());
+        assert_eq!(cache.get(ada.id).map(|user| user.name), Some(String::from("Ada")));
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted() {
+        let cache = cache(2);
+        let (ada, bob, cyd) = (user("Ada"), user("Bob"), user("Cyd"));
+        cache.insert(cache.version(), ada.clone());
+        cache.insert(cache.version(), bob.clone());
+        // Reading Ada makes Bob the oldest.
+        assert!(cache.get(ada.id).is_some());
+        cache.insert(cache.version(), cyd.clone());
+        assert!(cache.get(bob.id).is_none());
+        assert!(cache.get(ada.id).is_some());
+        assert!(cache.get(cyd.id).is_some());
+    }
+
+    #[test]
+    fn replacing_an_entry_does_not_evict_another() {
+        let cache = cache(2);
+        let (ada, bob) = (user("Ada"), user("Bob"));
+        cache.insert(cache.version(), ada.clone());
+        cache.insert(cache.version(), bob.clone());
+        cache.insert(cache.version(), User { name: String::from("Ada L"), ..ada.clone() });
+        assert_eq!(cache.get(ada.id).map(|user| user.name), Some(String::from("Ada L")));
+        assert!(cache.get(bob.id).is_some());
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = UserCache::new(2, Duration::ZERO);
+        let ada = user("Ada");
+        cache.insert(cache.version(), ada.clone());
+        assert!(cache.get(ada.id).is_none());
+        // The expired entry is gone, so it doesn't hold a slot.
+        assert!(cache.lock().entries.is_empty());
+        assert!(cache.lock().recency.is_empty());
+    }
+
+    #[test]
+    fn a_new_ttl_applies_to_later_entries() {
+        let cache = UserCache::new(2, Duration::from_secs(60));
+        cache.set_ttl(Duration::ZERO);
+        let ada = user("Ada");
+        cache.insert(cache.version(), ada.clone());
+        assert!(cache.get(ada.id).is_none());
+    }
+
+    #[test]
+    fn sweeps_drop_only_expired_entries() {
+        let expired = UserCache::new(2, Duration::ZERO);
+        expired.insert(expired.version(), user("Ada"));
+        expired.insert(expired.version(), user("Bob"));
+        assert_eq!(expired.sweep(), 2);
+        assert!(expired.lock().recency.is_empty());
+
+        let cache = cache(2);
+        let ada = user("Ada");
+        cache.insert(cache.version(), ada.clone());
+        assert_eq!(cache.sweep(), 0);
+        assert!(cache.get(ada.id).is_some());
+    }
+
+    #[test]
+    fn invalidation_removes_the_user_and_refuses_stale_reads() {
+        let cache = cache(2);
+        let ada = user("Ada");
+        let version = cache.version();
+        cache.insert(version, ada.clone());
+        cache.invalidate(ada.id);
+        assert!(cache.get(ada.id).is_none());
+        // A read that started before the invalidation is not cached.
+        cache.insert(version, ada.clone());
+        assert!(cache.get(ada.id).is_none());
+        cache.insert(cache.version(), ada.clone());
+        assert!(cache.get(ada.id).is_some());
+    }
+
+    #[test]
+    fn a_capacity_of_zero_disables_the_cache() {
+        let cache = cache(0);
+        let ada = user("Ada");
+        assert!(!cache.enabled());
+        cache.insert(cache.version(), ada.clone());
+        assert!(cache.get(ada.id).is_none());
+    }
+}
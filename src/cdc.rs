@@ -0,0 +1,223 @@
+use crate::config::CdcConfig;
+use crate::error::ApiError;
+use crate::events::EventKind;
+use crate::observe;
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use crate::users;
+use chrono::Utc;
+use futures::TryStreamExt;
+use scylla::frame::value::{CqlTimestamp, CqlTimeuuid};
+use scylla::prepared_statement::PreparedStatement;
+use scylla::transport::errors::QueryError;
+use scylla::DeserializeRow;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Polls the `users` CDC log (`users_scylla_cdc_log`) and publishes every
+// change it finds as a user event, wherever the write came from. The log is
+// partitioned by stream; the streams in use change with each CDC generation
+// (on topology changes), so the consumer reads them from
+// `system_distributed` and moves to the next generation once its window
+// reaches it.
+//
+// Reading starts when the consumer does; there is no checkpoint, in line
+// with the in-memory event feeds. Changed rows are reread from `users` for
+// the event body, so an event carries the user as it is when the change is
+// read rather than right after it.
+
+// Log partitions per query; a generation has a stream per vnode and shard.
+const STREAMS_PER_QUERY: usize = 100;
+
+// Values of `cdc$operation`; pre- and post-images and range deletes don't
+// apply to `users`, which has no clustering key and no images enabled.
+const ROW_UPDATE: i8 = 1;
+const ROW_INSERT: i8 = 2;
+const ROW_DELETE: i8 = 3;
+const PARTITION_DELETE: i8 = 4;
+
+const SELECT_GENERATIONS: &str =
+    "SELECT time FROM system_distributed.cdc_generation_timestamps WHERE key = 'timestamps'";
+const SELECT_STREAMS: &str =
+    "SELECT streams FROM system_distributed.cdc_streams_descriptions_v2 WHERE time = ?";
+
+#[derive(DeserializeRow)]
+struct LogRow {
+    #[scylla(rename = "cdc$time")]
+    time: CqlTimeuuid,
+    #[scylla(rename = "cdc$batch_seq_no")]
+    batch_seq_no: i32,
+    #[scylla(rename = "cdc$operation")]
+    operation: i8,
+    id: Uuid,
+    deleted_at: Option<CqlTimestamp>,
+    #[scylla(rename = "cdc$deleted_deleted_at")]
+    deleted_deleted_at: Option<bool>,
+}
+
+struct Generation {
+    start: i64,
+    streams: Vec<Vec<u8>>,
+}
+
+pub struct Consumer {
+    state: AppState,
+    poll_interval: Duration,
+    confidence_window_ms: i64,
+    select_generations: PreparedStatement,
+    select_streams: PreparedStatement,
+    select_changes: PreparedStatement,
+}
+
+fn now_ms() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn read_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal("Error reading the CDC log", e)
+}
+
+impl Consumer {
+    // Fails when CDC is not enabled on `users`, as its log table is missing.
+    pub async fn new(state: AppState, config: &CdcConfig) -> Result<Self, QueryError> {
+        let select_changes = format!(
+            "SELECT \"cdc$time\", \"cdc$batch_seq_no\", \"cdc$operation\", id, deleted_at, \
+             \"cdc$deleted_deleted_at\" FROM {}.users_scylla_cdc_log \
+             WHERE \"cdc$stream_id\" IN ? \
+             AND \"cdc$time\" >= minTimeuuid(?) AND \"cdc$time\" < minTimeuuid(?)",
+            state.keyspace
+        );
+        Ok(Consumer {
+            select_generations: state.session.prepare(SELECT_GENERATIONS).await?,
+            select_streams: state.session.prepare(SELECT_STREAMS).await?,
+            select_changes: state.session.prepare(select_changes).await?,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            confidence_window_ms: i64::try_from(config.confidence_window_ms).unwrap_or(i64::MAX),
+            state,
+        })
+    }
+
+    pub fn start(self, tasks: &mut Tasks) {
+        tasks.spawn("cdc consumer", |stopping| self.run(stopping));
+    }
+
+    async fn run(self, mut stopping: Stopping) {
+        let mut from = now_ms();
+        let mut generation = None;
+        while stopping.pause(self.poll_interval).await {
+            // A failed poll is retried from the same point on the next one.
+            match self.poll(&mut generation, from).await {
+                Ok(next) => from = next,
+                Err(e) => tracing::warn!(error = %e, "failed to read the CDC log"),
+            }
+        }
+    }
+
+    // Publishes the changes logged from `from` until the confidence window,
+    // one generation at a time, and returns where the next poll starts.
+    async fn poll(&self, current: &mut Option<Generation>, mut from: i64) -> Result<i64, ApiError> {
+        let until = now_ms().saturating_sub(self.confidence_window_ms);
+        if from >= until {
+            return Ok(from);
+        }
+        let starts = self.generation_starts().await?;
+        while from < until {
+            let Some(&start) = starts.iter().rev().find(|&&start| start <= from) else {
+                // Nothing can be logged before the first generation.
+                from = starts.first().map_or(until, |&first| first.min(until));
+                continue;
+            };
+            let end = starts
+                .iter()
+                .find(|&&next| next > from)
+                .map_or(until, |&next| next.min(until));
+            if current.as_ref().is_none_or(|generation| generation.start != start) {
+                *current = Some(Generation {
+                    start,
+                    streams: self.streams(start).await?,
+                });
+            }
+            if let Some(generation) = current.as_ref() {
+                self.publish(generation, from, end).await?;
+            }
+            from = end;
+        }
+        Ok(from)
+    }
+
+    async fn generation_starts(&self) -> Result<Vec<i64>, ApiError> {
+        let result = observe::query(&self.state, "select_cdc_generations", || {
+            self.state.session.execute_unpaged(&self.select_generations, ())
+        })
+        .await?;
+        let mut starts = result
+            .into_rows_result()
+            .map_err(read_error)?
+            .rows::<(CqlTimestamp,)>()
+            .map_err(read_error)?
+            .map(|row| row.map(|(time,)| time.0))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(read_error)?;
+        starts.sort_unstable();
+        Ok(starts)
+    }
+
+    async fn streams(&self, start: i64) -> Result<Vec<Vec<u8>>, ApiError> {
+        let time = CqlTimestamp(start);
+        let mut rows = observe::query(&self.state, "select_cdc_streams", || {
+            self.state.session.execute_iter(self.select_streams.clone(), (time,))
+        })
+        .await?
+        .rows_stream::<(Vec<Vec<u8>>,)>()
+        .map_err(read_error)?;
+        let mut streams = Vec::new();
+        while let Some((range_streams,)) = rows.try_next().await.map_err(read_error)? {
+            streams.extend(range_streams);
+        }
+        Ok(streams)
+    }
+
+    async fn publish(&self, generation: &Generation, from: i64, until: i64) -> Result<(), ApiError> {
+        let window = (CqlTimestamp(from), CqlTimestamp(until));
+        let mut changes = Vec::new();
+        for streams in generation.streams.chunks(STREAMS_PER_QUERY) {
+            let mut rows = observe::query(&self.state, "select_cdc_changes", || {
+                self.state
+                    .session
+                    .execute_iter(self.select_changes.clone(), (streams, window.0, window.1))
+            })
+            .await?
+            .rows_stream::<LogRow>()
+            .map_err(read_error)?;
+            while let Some(row) = rows.try_next().await.map_err(read_error)? {
+                changes.push(row);
+            }
+        }
+        // Streams are read one after another; put their changes back in the
+        // order they were made.
+        changes.sort_by(|a, b| a.time.cmp(&b.time).then(a.batch_seq_no.cmp(&b.batch_seq_no)));
+
+        for change in changes {
+            let kind = match change.operation {
+                ROW_INSERT => EventKind::Created,
+                ROW_UPDATE if change.deleted_at.is_some() => EventKind::Deleted,
+                ROW_UPDATE if change.deleted_deleted_at == Some(true) => EventKind::Restored,
+                ROW_UPDATE => EventKind::Updated,
+                ROW_DELETE | PARTITION_DELETE => EventKind::Deleted,
+                _ => continue,
+            };
+            users::forget_cached(&self.state, change.id).await;
+            let user = match kind {
+                EventKind::Deleted => None,
+                _ => match users::stored_row(&self.state, change.id).await? {
+                    Some((user, false)) => Some(user),
+                    // Gone or soft-deleted again since; a later change in the
+                    // log says so.
+                    _ => continue,
+                },
+            };
+            self.state.events.publish_captured(kind, change.id, user);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use crate::indexes;
+use crate::migrations;
+use crate::models::IndexState;
+use crate::statements::Statements;
+use scylla::Session;
+
+// `check-db` verifies, without changing anything, that the server could start
+// against the configured cluster: the nodes agree on one schema version, the
+// keyspace and `users` table are queryable, every migration is applied
+// unmodified, the indexes the API queries through exist and every statement
+// the server runs prepares. Each check is
+// logged, and the command fails if any of them did.
+
+type CheckResult = Result<(), String>;
+
+async fn schema_agreement(session: &Session) -> CheckResult {
+    match session.check_schema_agreement().await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(String::from("the nodes report different schema versions")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn users_table(session: &Session, keyspace: &str) -> CheckResult {
+    session
+        .query_unpaged(format!("SELECT id FROM {}.users LIMIT 1", keyspace), &[])
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("{}.users is not queryable: {}", keyspace, e))
+}
+
+async fn applied_migrations(session: &Session, keyspace: &str) -> CheckResult {
+    let pending = migrations::pending(session, keyspace).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<String> = pending
+        .iter()
+        .map(|migration| format!("{:04}_{}", migration.version, migration.name))
+        .collect();
+    Err(format!("{} pending: {}", pending.len(), names.join(", ")))
+}
+
+async fn required_indexes(session: &Session, keyspace: &str) -> CheckResult {
+    let report = indexes::report(session, keyspace).await?;
+    let absent: Vec<String> = report
+        .indexes
+        .iter()
+        .filter(|index| matches!(index.state, IndexState::Missing | IndexState::Mismatched))
+        .map(|index| format!("{} ({:?})", index.name, index.state).to_lowercase())
+        .collect();
+    if absent.is_empty() {
+        return Ok(());
+    }
+    Err(format!("not usable: {}", absent.join(", ")))
+}
+
+async fn statements(session: &Session, keyspace: &str) -> CheckResult {
+    Statements::prepare(session, keyspace)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("cannot prepare statements: {}", e))
+}
+
+pub async fn run(session: &Session, keyspace: &str) -> bool {
+    let results = [
+        ("schema agreement", schema_agreement(session).await),
+        ("users table", users_table(session, keyspace).await),
+        ("migrations", applied_migrations(session, keyspace).await),
+        ("indexes", required_indexes(session, keyspace).await),
+        ("statements", statements(session, keyspace).await),
+    ];
+
+    let mut failures = 0;
+    for (check, result) in &results {
+        match result {
+            Ok(()) => tracing::info!(check, "database check passed"),
+            Err(e) => {
+                failures += 1;
+                tracing::error!(check, error = %e, "database check failed");
+            }
+        }
+    }
+    tracing::info!(
+        keyspace,
+        passed = results.len() - failures,
+        total = results.len(),
+        "database check finished"
+    );
+
+    failures == 0
+}
@@ -0,0 +1,157 @@
+use crate::restore::Conflict;
+use std::path::PathBuf;
+
+// The command line: one subcommand naming what the process does, `serve` when
+// there is none. The flags earlier releases took (`--migrate`, `--self-test`,
+// `--backfill`) still work as aliases of their subcommands, so existing
+// deploy jobs keep running. Everything else is configured as before, through
+// the config file and environment.
+
+pub const USAGE: &str = "\
+Usage: singlepg_hireme_rust_server [COMMAND]
+
+Commands:
+  serve          Serve the HTTP API (the default)
+  migrate        Apply pending schema migrations and exit
+  check-db       Check connectivity, schema and migrations, and exit
+  seed <FILE>    Register the users in a CSV or JSON Lines fixture and exit
+  seed --count N Register N fake users and exit
+  snapshot <FILE>
+                 Copy the users table to a JSON Lines file and its manifest
+  restore <FILE> [--overwrite]
+                 Write a snapshot's users back, replacing existing ones with
+                 --overwrite, and exit
+  self-test      Run a CRUD smoke test in a temporary keyspace and exit
+  backfill       Fill the email and name index tables and exit
+  help           Print this message
+
+Configuration is read from the file named by CONFIG_FILE (config.toml by
+default) and from environment variables; see config.example.toml.";
+
+// Where `seed` takes its users from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Seed {
+    File(PathBuf),
+    Fake { count: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    Migrate,
+    CheckDb,
+    Seed(Seed),
+    Snapshot { path: PathBuf },
+    Restore { path: PathBuf, conflict: Conflict },
+    SelfTest,
+    Backfill,
+    Help,
+}
+
+// The command named by `args`, the process arguments after the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    let Some(first) = args.next() else {
+        return Ok(Command::Serve);
+    };
+    let command = match first.as_str() {
+        "serve" => Command::Serve,
+        "migrate" | "--migrate" => Command::Migrate,
+        "check-db" => Command::CheckDb,
+        "seed" => match args.next() {
+            Some(flag) if flag == "--count" => {
+                let count = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| String::from("--count needs a positive number of users"))?;
+                Command::Seed(Seed::Fake { count })
+            }
+            Some(path) if !path.starts_with('-') => Command::Seed(Seed::File(PathBuf::from(path))),
+            Some(flag) => return Err(format!("unexpected option {} for seed", flag)),
+            None => return Err(String::from("seed needs a fixture file or --count")),
+        },
+        "snapshot" => match args.next() {
+            Some(path) if !path.starts_with('-') => Command::Snapshot { path: PathBuf::from(path) },
+            Some(flag) => return Err(format!("unexpected option {} for snapshot", flag)),
+            None => return Err(String::from("snapshot needs the file to write")),
+        },
+        "restore" => match args.next() {
+            Some(path) if !path.starts_with('-') => Command::Restore {
+                path: PathBuf::from(path),
+                conflict: match args.next_if(|flag| flag == "--overwrite") {
+                    Some(_) => Conflict::Overwrite,
+                    None => Conflict::Skip,
+                },
+            },
+            Some(flag) => return Err(format!("unexpected option {} for restore", flag)),
+            None => return Err(String::from("restore needs the snapshot file")),
+        },
+        "self-test" | "--self-test" => Command::SelfTest,
+        "backfill" | "--backfill" => Command::Backfill,
+        "help" | "--help" | "-h" => Command::Help,
+        other => return Err(format!("unknown command {}", other)),
+    };
+    match args.next() {
+        Some(extra) => Err(format!("unexpected argument {}", extra)),
+        None => Ok(command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn subcommands_and_legacy_flags_parse() {
+        assert_eq!(parse_str(&[]), Ok(Command::Serve));
+        assert_eq!(parse_str(&["serve"]), Ok(Command::Serve));
+        assert_eq!(parse_str(&["migrate"]), Ok(Command::Migrate));
+        assert_eq!(parse_str(&["--migrate"]), Ok(Command::Migrate));
+        assert_eq!(parse_str(&["check-db"]), Ok(Command::CheckDb));
+        assert_eq!(parse_str(&["--self-test"]), Ok(Command::SelfTest));
+        assert_eq!(parse_str(&["backfill"]), Ok(Command::Backfill));
+        assert_eq!(parse_str(&["-h"]), Ok(Command::Help));
+        assert_eq!(
+            parse_str(&["seed", "fixtures/users.csv"]),
+            Ok(Command::Seed(Seed::File(PathBuf::from("fixtures/users.csv"))))
+        );
+        assert_eq!(
+            parse_str(&["snapshot", "users.jsonl"]),
+            Ok(Command::Snapshot { path: PathBuf::from("users.jsonl") })
+        );
+        assert_eq!(
+            parse_str(&["restore", "users.jsonl"]),
+            Ok(Command::Restore { path: PathBuf::from("users.jsonl"), conflict: Conflict::Skip })
+        );
+        assert_eq!(
+            parse_str(&["restore", "users.jsonl", "--overwrite"]),
+            Ok(Command::Restore {
+                path: PathBuf::from("users.jsonl"),
+                conflict: Conflict::Overwrite
+            })
+        );
+        assert_eq!(
+            parse_str(&["seed", "--count", "5000"]),
+            Ok(Command::Seed(Seed::Fake { count: 5000 }))
+        );
+    }
+
+    #[test]
+    fn unknown_or_incomplete_commands_are_refused() {
+        assert!(parse_str(&["serv"]).is_err());
+        assert!(parse_str(&["seed"]).is_err());
+        assert!(parse_str(&["seed", "--force"]).is_err());
+        assert!(parse_str(&["seed", "--count"]).is_err());
+        assert!(parse_str(&["seed", "--count", "0"]).is_err());
+        assert!(parse_str(&["seed", "--count", "many"]).is_err());
+        assert!(parse_str(&["snapshot"]).is_err());
+        assert!(parse_str(&["restore", "--overwrite"]).is_err());
+        assert!(parse_str(&["restore", "users.jsonl", "--force"]).is_err());
+        assert!(parse_str(&["migrate", "now"]).is_err());
+    }
+}
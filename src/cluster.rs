@@ -0,0 +1,424 @@
+use crate::error::{ApiError, Problem};
+use crate::models::{
+    ClusterMetadata, ColumnMetadata, DatacenterMetadata, FieldMetadata, KeyspaceMetadata,
+    NodeMetadata, SchemaMetadata, SchemaQuery, TableMetadata, TypeMetadata, ViewMetadata,
+};
+use crate::state::AppState;
+use actix_web::{web, HttpResponse};
+use scylla::routing::Token;
+use scylla::transport::topology::{
+    CollectionType, ColumnKind, CqlType, Keyspace, NativeType, Strategy, Table, UserDefinedType,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+// GET /admin/cluster reports the cluster as the driver sees it, which is what
+// it routes by: the nodes and the share of the token ring each owns, the
+// datacenters and racks, every keyspace's replication, and the `users`
+// table's schema. GET /admin/schema reports every table, materialized view
+// and user-defined type of the serving keyspace, for tooling that discovers
+// the data model. Both read the metadata the driver keeps and refreshes on
+// its own; nothing is queried, unless GET /admin/schema is asked to refresh
+// that metadata first.
+
+// CQL name of a column type, as `DESCRIBE` writes it.
+fn type_name(cql_type: &CqlType) -> String {
+    let frozen = |frozen: bool, name: String| {
+        if frozen { format!("frozen<{}>", name) } else { name }
+    };
+    match cql_type {
+        CqlType::Native(native) => String::from(match native {
+            NativeType::Ascii => "ascii",
+            NativeType::Boolean => "boolean",
+            NativeType::Blob => "blob",
+            NativeType::Counter => "counter",
+            NativeType::Date => "date",
+            NativeType::Decimal => "decimal",
+            NativeType::Double => "double",
+            NativeType::Duration => "duration",
+            NativeType::Float => "float",
+            NativeType::Int => "int",
+            NativeType::BigInt => "bigint",
+            NativeType::Text => "text",
+            NativeType::Timestamp => "timestamp",
+            NativeType::Inet => "inet",
+            NativeType::SmallInt => "smallint",
+            NativeType::TinyInt => "tinyint",
+            NativeType::Time => "time",
+            NativeType::Timeuuid => "timeuuid",
+            NativeType::Uuid => "uuid",
+            NativeType::Varint => "varint",
+        }),
+        CqlType::Collection { frozen: is_frozen, type_ } => {
+            let name = match type_ {
+                CollectionType::List(element) => format!("list<{}>", type_name(element)),
+                CollectionType::Set(element) => format!("set<{}>", type_name(element)),
+                CollectionType::Map(key, value) => {
+                    format!("map<{}, {}>", type_name(key), type_name(value))
+                }
+            };
+            frozen(*is_frozen, name)
+        }
+        CqlType::Tuple(elements) => {
+            let elements: Vec<String> = elements.iter().map(type_name).collect();
+            format!("tuple<{}>", elements.join(", "))
+        }
+        CqlType::UserDefinedType { frozen: is_frozen, definition } => {
+            let name = match definition {
+                Ok(udt) => udt.name.clone(),
+                Err(missing) => missing.name.clone(),
+            };
+            frozen(*is_frozen, name)
+        }
+    }
+}
+
+fn kind_name(kind: &ColumnKind) -> &'static str {
+    match kind {
+        ColumnKind::PartitionKey => "partition_key",
+        ColumnKind::Clustering => "clustering",
+        ColumnKind::Static => "static",
+        ColumnKind::Regular => "regular",
+    }
+}
+
+// The strategy's name and its replication options.
+fn replication(strategy: &Strategy) -> (String, BTreeMap<String, String>) {
+    match strategy {
+        Strategy::SimpleStrategy { replication_factor } => (
+            String::from("SimpleStrategy"),
+            BTreeMap::from([(String::from("replication_factor"), replication_factor.to_string())]),
+        ),
+        Strategy::NetworkTopologyStrategy { datacenter_repfactors } => (
+            String::from("NetworkTopologyStrategy"),
+            datacenter_repfactors
+                .iter()
+                .map(|(datacenter, factor)| (datacenter.clone(), factor.to_string()))
+                .collect(),
+        ),
+        Strategy::LocalStrategy => (String::from("LocalStrategy"), BTreeMap::new()),
+        Strategy::Other { name, data } => (
+            name.clone(),
+            data.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+        ),
+    }
+}
+
+fn keyspace_metadata(name: &str, keyspace: &Keyspace) -> KeyspaceMetadata {
+    let (strategy, replication) = replication(&keyspace.strategy);
+    let sorted = |names: Vec<&String>| {
+        let mut names: Vec<String> = names.into_iter().cloned().collect();
+        names.sort();
+        names
+    };
+    KeyspaceMetadata {
+        name: name.to_string(),
+        strategy,
+        replication,
+        tables: sorted(keyspace.tables.keys().collect()),
+        views: sorted(keyspace.views.keys().collect()),
+    }
+}
+
+// `table`'s key columns in key order, then the rest by name.
+fn table_metadata(keyspace: &str, name: &str, table: &Table) -> TableMetadata {
+    let position = |column: &str| {
+        table
+            .partition_key
+            .iter()
+            .chain(&table.clustering_key)
+            .position(|key| key == column)
+            .unwrap_or(usize::MAX)
+    };
+    let mut columns: Vec<ColumnMetadata> = table
+        .columns
+        .iter()
+        .map(|(name, column)| ColumnMetadata {
+            name: name.clone(),
+            column_type: type_name(&column.type_),
+            kind: kind_name(&column.kind),
+        })
+        .collect();
+    columns.sort_by(|a, b| (position(&a.name), &a.name).cmp(&(position(&b.name), &b.name)));
+    TableMetadata {
+        keyspace: keyspace.to_string(),
+        name: name.to_string(),
+        partition_key: table.partition_key.clone(),
+        clustering_key: table.clustering_key.clone(),
+        columns,
+    }
+}
+
+fn type_metadata(udt: &UserDefinedType) -> TypeMetadata {
+    TypeMetadata {
+        name: udt.name.clone(),
+        fields: udt
+            .field_types
+            .iter()
+            .map(|(name, field_type)| FieldMetadata {
+                name: name.clone(),
+                field_type: type_name(field_type),
+            })
+            .collect(),
+    }
+}
+
+fn schema_metadata(name: &str, keyspace: &Keyspace) -> SchemaMetadata {
+    let mut tables: Vec<TableMetadata> = keyspace
+        .tables
+        .iter()
+        .map(|(table_name, table)| table_metadata(name, table_name, table))
+        .collect();
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut views: Vec<ViewMetadata> = keyspace
+        .views
+        .iter()
+        .map(|(view_name, view)| ViewMetadata {
+            base_table: view.base_table_name.clone(),
+            view: table_metadata(name, view_name, &view.view_metadata),
+        })
+        .collect();
+    views.sort_by(|a, b| a.view.name.cmp(&b.view.name));
+    let mut types: Vec<TypeMetadata> = keyspace
+        .user_defined_types
+        .values()
+        .map(|udt| type_metadata(udt))
+        .collect();
+    types.sort_by(|a, b| a.name.cmp(&b.name));
+    SchemaMetadata {
+        keyspace: name.to_string(),
+        tables,
+        views,
+        types,
+    }
+}
+
+// Tokens per node and the share of the ring each owns. A token owns the range
+// from the token before it, exclusive, up to itself; the first token owns the
+// range wrapping round from the last.
+fn ownership(ring: &[(Token, Uuid)]) -> HashMap<Uuid, (usize, f64)> {
+    const RING_SIZE: f64 = 18_446_744_073_709_551_616.0; // 2^64
+    let mut owned: HashMap<Uuid, (usize, f64)> = HashMap::new();
+    for (i, (token, node)) in ring.iter().enumerate() {
+        let previous = if i == 0 { ring[ring.len() - 1].0 } else { ring[i - 1].0 };
+        let width = (token.value() as i128 - previous.value() as i128).rem_euclid(1 << 64);
+        // A ring of one token owns all of it.
+        let width = if width == 0 { RING_SIZE } else { width as f64 };
+        let entry = owned.entry(*node).or_insert((0, 0.0));
+        entry.0 += 1;
+        entry.1 += width / RING_SIZE;
+    }
+    owned
+}
+
+pub fn metadata(data: &AppState) -> ClusterMetadata {
+    let cluster = data.session.get_cluster_data();
+    let ring: Vec<(Token, Uuid)> = cluster
+        .replica_locator()
+        .ring()
+        .iter()
+        .map(|(token, node)| (*token, node.host_id))
+        .collect();
+    let owned = ownership(&ring);
+
+    let mut nodes = Vec::new();
+    let mut datacenters: BTreeMap<String, (usize, BTreeSet<String>)> = BTreeMap::new();
+    for node in cluster.get_nodes_info() {
+        let (tokens, primary_ownership) = owned.get(&node.host_id).copied().unwrap_or((0, 0.0));
+        nodes.push(NodeMetadata {
+            host_id: node.host_id,
+            address: SocketAddr::new(node.address.ip(), node.address.port()).to_string(),
+            datacenter: node.datacenter.clone(),
+            rack: node.rack.clone(),
+            connected: node.is_enabled() && !node.is_down(),
+            tokens,
+            primary_ownership,
+        });
+        if let Some(datacenter) = &node.datacenter {
+            let (count, racks) = datacenters.entry(datacenter.clone()).or_default();
+            *count += 1;
+            racks.extend(node.rack.clone());
+        }
+    }
+    nodes.sort_by(|a, b| (&a.datacenter, &a.address).cmp(&(&b.datacenter, &b.address)));
+
+    let keyspaces = cluster.get_keyspace_info();
+    let mut keyspace_list: Vec<KeyspaceMetadata> = keyspaces
+        .iter()
+        .map(|(name, keyspace)| keyspace_metadata(name, keyspace))
+        .collect();
+    keyspace_list.sort_by(|a, b| a.name.cmp(&b.name));
+    let users_table = keyspaces
+        .get(&data.keyspace)
+        .and_then(|keyspace| keyspace.tables.get("users"))
+        .map(|table| table_metadata(&data.keyspace, "users", table));
+
+    ClusterMetadata {
+        nodes,
+        datacenters: datacenters
+            .into_iter()
+            .map(|(name, (nodes, racks))| DatacenterMetadata {
+                name,
+                nodes,
+                racks: racks.into_iter().collect(),
+            })
+            .collect(),
+        keyspaces: keyspace_list,
+        users_table,
+    }
+}
+
+/// The driver's view of the cluster: nodes and their share of the token
+/// ring, datacenters, keyspaces with their replication, and the schema of
+/// the `users` table.
+#[utoipa::path(
+    get,
+    path = "/admin/cluster",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The driver's cluster metadata", body = ClusterMetadata),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+    )
+)]
+pub async fn get_cluster(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(metadata(&data)))
+}
+
+/// The serving keyspace's tables, with their columns, types and keys, its
+/// materialized views and its user-defined types.
+#[utoipa::path(
+    get,
+    path = "/admin/schema",
+    params(SchemaQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The keyspace's schema", body = SchemaMetadata),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+        (status = 503, description = "The driver has no schema for the keyspace", body = Problem),
+    )
+)]
+pub async fn get_schema(
+    query: web::Query<SchemaQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if query.refresh.unwrap_or(false) {
+        data.session.refresh_metadata().await?;
+    }
+    let cluster = data.session.get_cluster_data();
+    let keyspace = cluster.get_keyspace_info().get(&data.keyspace).ok_or_else(|| {
+        ApiError::DbUnavailable(format!("The driver has no schema for keyspace {}", data.keyspace))
+    })?;
+    Ok(HttpResponse::Ok().json(schema_metadata(&data.keyspace, keyspace)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scylla::transport::topology::{Column, MaterializedView};
+    use std::sync::Arc;
+
+    #[test]
+    fn types_are_named_as_in_cql() {
+        let text = || Box::new(CqlType::Native(NativeType::Text));
+        assert_eq!(type_name(&CqlType::Native(NativeType::BigInt)), "bigint");
+        assert_eq!(
+            type_name(&CqlType::Collection {
+                frozen: false,
+                type_: CollectionType::Map(text(), Box::new(CqlType::Native(NativeType::Int))),
+            }),
+            "map<text, int>"
+        );
+        let profile = UserDefinedType {
+            name: String::from("profile"),
+            keyspace: String::from("app"),
+            field_types: Vec::new(),
+        };
+        assert_eq!(
+            type_name(&CqlType::UserDefinedType { frozen: true, definition: Ok(Arc::new(profile)) }),
+            "frozen<profile>"
+        );
+    }
+
+    #[test]
+    fn key_columns_come_first() {
+        let column = |type_, kind| Column { type_: CqlType::Native(type_), kind };
+        let table = Table {
+            columns: HashMap::from([
+                (String::from("name"), column(NativeType::Text, ColumnKind::Regular)),
+                (String::from("email"), column(NativeType::Text, ColumnKind::Regular)),
+                (String::from("id"), column(NativeType::Uuid, ColumnKind::PartitionKey)),
+            ]),
+            partition_key: vec![String::from("id")],
+            clustering_key: Vec::new(),
+            partitioner: None,
+        };
+        let metadata = table_metadata("app", "users", &table);
+        let names: Vec<&str> = metadata.columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["id", "email", "name"]);
+        assert_eq!(metadata.columns[0].kind, "partition_key");
+    }
+
+    #[test]
+    fn the_schema_lists_tables_views_and_types_by_name() {
+        let column = |type_, kind| Column { type_: CqlType::Native(type_), kind };
+        let key_column = || column(NativeType::Uuid, ColumnKind::PartitionKey);
+        let table = |key: &str| Table {
+            columns: HashMap::from([(key.to_string(), key_column())]),
+            partition_key: vec![key.to_string()],
+            clustering_key: Vec::new(),
+            partitioner: None,
+        };
+        let profile = UserDefinedType {
+            name: String::from("profile"),
+            keyspace: String::from("app"),
+            field_types: vec![
+                (String::from("bio"), CqlType::Native(NativeType::Text)),
+                (String::from("age"), CqlType::Native(NativeType::Int)),
+            ],
+        };
+        let keyspace = Keyspace {
+            strategy: Strategy::LocalStrategy,
+            tables: HashMap::from([
+                (String::from("users"), table("id")),
+                (String::from("api_keys"), table("id")),
+            ]),
+            views: HashMap::from([(
+                String::from("users_by_email_view"),
+                MaterializedView {
+                    view_metadata: table("email"),
+                    base_table_name: String::from("users"),
+                },
+            )]),
+            user_defined_types: HashMap::from([(String::from("profile"), Arc::new(profile))]),
+        };
+        let schema = schema_metadata("app", &keyspace);
+        let tables: Vec<&str> = schema.tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(tables, ["api_keys", "users"]);
+        assert_eq!(schema.views[0].base_table, "users");
+        assert_eq!(schema.views[0].view.partition_key, ["email"]);
+        let fields: Vec<(&str, &str)> = schema.types[0]
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.field_type.as_str()))
+            .collect();
+        assert_eq!(fields, [("bio", "text"), ("age", "int")]);
+    }
+
+    #[test]
+    fn ownership_adds_up_to_the_whole_ring() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let ring = [
+            (Token::new(i64::MIN / 2), a),
+            (Token::new(0), b),
+            (Token::new(i64::MAX / 2), a),
+        ];
+        let owned = ownership(&ring);
+        assert_eq!(owned[&a].0, 2);
+        assert!((owned[&a].1 - 0.75).abs() < 1e-9);
+        assert!((owned[&b].1 - 0.25).abs() < 1e-9);
+        assert!((ownership(&[(Token::new(42), a)])[&a].1 - 1.0).abs() < 1e-9);
+    }
+}
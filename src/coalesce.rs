@@ -0,0 +1,142 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Request coalescing ("singleflight"): concurrent reads of the same key share
+// one read instead of each sending its own, so a spike of requests for one
+// popular user is one query on its partition rather than hundreds. The first
+// caller starts the read; callers arriving while it is in flight wait for it
+// and get a clone of its result, errors included. The read runs for as long
+// as anyone waits on it; if every caller gives up, the next one to ask picks
+// it up where it was. It is forgotten once it finishes, so only reads that
+// overlap are shared and nothing is cached here.
+//
+// A write must `forget` its key before anything else, so that readers
+// arriving after it start a read of their own rather than join one that may
+// have read the row before the write.
+
+type Flight<V> = Shared<BoxFuture<'static, V>>;
+
+// Reads in flight by key, each with its id.
+type Flights<K, V> = Arc<Mutex<HashMap<K, (u64, Flight<V>)>>>;
+
+pub struct Coalescer<K, V: Clone> {
+    in_flight: Flights<K, V>,
+    // Tells a flight apart from a later one for the same key.
+    next_id: AtomicU64,
+}
+
+impl<K, V> Default for Coalescer<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Coalescer::new()
+    }
+}
+
+impl<K, V> Coalescer<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Coalescer {
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    // The result of the read of `key` in flight, or of `read` started now;
+    // and whether an earlier read was joined.
+    pub async fn run<F>(&self, key: K, read: impl FnOnce() -> F) -> (V, bool)
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let (flight, joined) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some((_, flight)) => (flight.clone(), true),
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let flights = self.in_flight.clone();
+                    let finished = key.clone();
+                    let read = read();
+                    let flight = async move {
+                        let value = read.await;
+                        let mut flights = flights.lock().unwrap();
+                        // Unless a write forgot it and a new read took its place.
+                        if flights.get(&finished).is_some_and(|(current, _)| *current == id) {
+                            flights.remove(&finished);
+                        }
+                        value
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.insert(key, (id, flight.clone()));
+                    (flight, false)
+                }
+            }
+        };
+        (flight.await, joined)
+    }
+
+    // Keeps later reads of `key` from joining the one in flight.
+    pub fn forget(&self, key: &K) {
+        self.in_flight.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::time::sleep;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn counted_read(reads: &Arc<AtomicUsize>) -> impl FnOnce() -> BoxFuture<'static, usize> {
+        let reads = reads.clone();
+        move || {
+            async move {
+                sleep(Duration::from_millis(20)).await;
+                reads.fetch_add(1, Ordering::SeqCst) + 1
+            }
+            .boxed()
+        }
+    }
+
+    #[actix_web::test]
+    async fn overlapping_reads_are_shared() {
+        let coalescer: Coalescer<u32, usize> = Coalescer::new();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let results = futures::future::join_all(
+            (0..10).map(|_| coalescer.run(7, counted_read(&reads))),
+        )
+        .await;
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|(value, _)| *value == 1));
+        assert_eq!(results.iter().filter(|(_, joined)| *joined).count(), 9);
+
+        // Finished reads aren't reused.
+        assert_eq!(coalescer.run(7, counted_read(&reads)).await, (2, false));
+    }
+
+    #[actix_web::test]
+    async fn reads_after_a_forget_start_afresh() {
+        let coalescer: Coalescer<u32, usize> = Coalescer::new();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let before = coalescer.run(7, counted_read(&reads));
+        let after = async {
+            sleep(Duration::from_millis(5)).await;
+            coalescer.forget(&7);
+            coalescer.run(7, counted_read(&reads)).await
+        };
+        let ((_, _), (_, joined)) = futures::future::join(before, after).await;
+        assert!(!joined);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,93 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web;
+
+// Responses are compressed with gzip, brotli or zstd, whichever the client
+// ranks highest in Accept-Encoding, by actix's `Compress` middleware. This
+// middleware runs inside it and marks the responses not worth compressing as
+// `Content-Encoding: identity`, which `Compress` leaves alone: bodies shorter
+// than `http.compression_min_bytes`, where the encoding costs more than it
+// saves, and server-sent events, which a compressor would hold back until
+// it had enough of them to fill a block. Bodies streamed without a known
+// length (exports, NDJSON listings) are compressed.
+
+// The smallest body compressed, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct MinSize(pub usize);
+
+fn worth_compressing<B: MessageBody>(res: &ServiceResponse<B>, min: usize) -> bool {
+    let event_stream = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    match res.response().body().size() {
+        _ if event_stream => false,
+        BodySize::Sized(length) => length >= min as u64,
+        BodySize::Stream => true,
+        BodySize::None => false,
+    }
+}
+
+pub async fn skip_small(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let min = req
+        .app_data::<web::Data<MinSize>>()
+        .map_or(0, |min| min.0);
+    let mut res = next.call(req).await?;
+    if !worth_compressing(&res, min) && !res.headers().contains_key(header::CONTENT_ENCODING) {
+        res.headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::{from_fn, Compress};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    #[actix_web::test]
+    async fn only_large_enough_bodies_are_compressed() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(MinSize(1024)))
+                .wrap(from_fn(skip_small))
+                .wrap(Compress::default())
+                .route("/small", web::get().to(|| async { HttpResponse::Ok().body("{}") }))
+                .route("/large", web::get().to(|| async { HttpResponse::Ok().body("x".repeat(4096)) }))
+                .route(
+                    "/events",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .content_type("text/event-stream")
+                            .body("data: x\n\n".repeat(512))
+                    }),
+                ),
+        )
+        .await;
+        let encoding = |path: &'static str, accept: &'static str| {
+            let app = &app;
+            async move {
+                let req = TestRequest::get()
+                    .uri(path)
+                    .insert_header((header::ACCEPT_ENCODING, accept))
+                    .to_request();
+                let res = call_service(app, req).await;
+                res.headers()
+                    .get(header::CONTENT_ENCODING)
+                    .map(|value| value.to_str().unwrap().to_string())
+            }
+        };
+        assert_eq!(encoding("/large", "gzip").await.as_deref(), Some("gzip"));
+        assert_eq!(encoding("/large", "gzip;q=0.5, br").await.as_deref(), Some("br"));
+        assert_eq!(encoding("/small", "gzip").await.as_deref(), Some("identity"));
+        assert_eq!(encoding("/events", "gzip").await.as_deref(), Some("identity"));
+    }
+}
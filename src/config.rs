@@ -0,0 +1,2193 @@
+use crate::flags::Flag;
+use crate::http_client;
+use crate::mailer;
+use crate::redis::RedisUrl;
+use crate::request_id::RequestIdFormat;
+use crate::validation;
+use scylla::statement::Consistency;
+use serde::Deserialize;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Settings are read from a TOML file (CONFIG_FILE, or ./config.toml when it
+// exists) and then overridden field by field from environment variables, so
+// containers can tweak a baked-in file without rewriting it.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub scylla: ScyllaConfig,
+    pub http: HttpConfig,
+    pub auth: AuthConfig,
+    pub self_test: SelfTestConfig,
+    pub log: LogConfig,
+    pub rate_limit: RateLimitConfig,
+    pub load_shedding: LoadSheddingConfig,
+    pub cors: CorsConfig,
+    pub grpc: GrpcConfig,
+    pub cdc: CdcConfig,
+    pub cache: CacheConfig,
+    pub validation: ValidationConfig,
+    pub tenants: TenantsConfig,
+    pub outbox: OutboxConfig,
+    pub kafka: KafkaConfig,
+    pub nats: NatsConfig,
+    pub search_index: SearchIndexConfig,
+    pub maintenance: MaintenanceConfig,
+    pub oauth: OauthConfig,
+    pub write_behind: WriteBehindConfig,
+    pub flags: FlagsConfig,
+    pub maintenance_mode: MaintenanceModeConfig,
+    pub webhooks: WebhooksConfig,
+    pub smtp: SmtpConfig,
+    pub shadow: ShadowConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScyllaConfig {
+    pub nodes: Vec<String>,
+    pub cloud_bundle: Option<PathBuf>,
+    pub local_datacenter: Option<String>,
+    pub dc_failover: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+    pub tls_ca_path: Option<PathBuf>,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub keyspace: String,
+    pub allow_tracing: bool,
+    pub allow_consistency_override: bool,
+    pub allow_raw_cql: bool,
+    pub migrate_on_startup: bool,
+    pub ensure_indexes: bool,
+    pub backfill_concurrency: usize,
+    pub seed_concurrency: usize,
+    pub snapshot_rows_per_sec: u32,
+    pub restore_concurrency: usize,
+    pub bootstrap: bool,
+    pub replication_factor: u32,
+    pub replication_datacenters: Vec<String>,
+    pub schema_wait_timeout_secs: u64,
+    pub schema_wait_initial_backoff_ms: u64,
+    pub schema_wait_max_backoff_ms: u64,
+    pub schema_consistency: SchemaConsistency,
+    pub schema_agreement_timeout_secs: u64,
+    pub request_timeout_ms: u64,
+    pub retry_max_attempts: u32,
+    pub retry_initial_backoff_ms: u64,
+    pub retry_max_backoff_ms: u64,
+    pub retry_on: Vec<RetryKind>,
+    pub breaker_failures: u32,
+    pub breaker_open_secs: u64,
+    pub max_in_flight_queries: usize,
+    pub query_queue_timeout_ms: u64,
+    pub monitor_interval_secs: u64,
+    pub slow_query_threshold_ms: u64,
+    pub speculative_execution: SpeculativeExecution,
+    pub speculative_max_executions: usize,
+    pub speculative_delay_ms: u64,
+    pub speculative_percentile: f64,
+    pub idempotent_statements: Vec<String>,
+    pub pool_size: usize,
+    pub pool_size_per: PoolSizePer,
+    pub token_aware: bool,
+    pub shard_aware_port: bool,
+    pub latency_awareness: bool,
+}
+
+/// Classes of transient query failure that may be retried. `read_timeout`,
+/// `write_timeout`, `overloaded` and `unavailable` are errors reported by the
+/// coordinator; `client_timeout` is the driver giving up waiting, and
+/// `connection` a broken or exhausted connection pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryKind {
+    ReadTimeout,
+    WriteTimeout,
+    ClientTimeout,
+    Overloaded,
+    Unavailable,
+    Connection,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub bind_addr: String,
+    pub unix_socket_path: Option<PathBuf>,
+    pub workers: usize,
+    pub backlog: u32,
+    pub max_connections: usize,
+    pub max_connection_rate: usize,
+    pub keep_alive_secs: u64,
+    pub client_request_timeout_ms: u64,
+    pub client_disconnect_timeout_ms: u64,
+    pub trailing_slash: TrailingSlashPolicy,
+    pub max_rows_per_request: usize,
+    pub row_cap_mode: RowCapMode,
+    pub latency_window: usize,
+    pub default_page_size: usize,
+    pub max_page_size: usize,
+    pub default_user_ttl_secs: u32,
+    pub cursor_max_age_secs: u64,
+    pub count_cache_secs: u64,
+    pub readiness_timeout_ms: u64,
+    pub handler_timeout_ms: u64,
+    pub panic_threshold: usize,
+    pub panic_window_secs: u64,
+    pub panic_marks_unready: bool,
+    pub bulk_max_items: usize,
+    pub bulk_concurrency: usize,
+    pub batch_max_operations: usize,
+    pub batch_type: BatchMode,
+    pub max_json_body_bytes: usize,
+    pub avatar_max_bytes: usize,
+    pub export_workers: usize,
+    pub event_buffer: usize,
+    pub idempotency_ttl_secs: u64,
+    pub verification_token_ttl_secs: u64,
+    pub password_reset_ttl_secs: u64,
+    pub shutdown_grace_secs: u64,
+    pub background_grace_secs: u64,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub redirect_bind_addr: Option<String>,
+    pub email_check_public: bool,
+    pub put_creates: bool,
+    pub concurrency_mode: ConcurrencyMode,
+    pub cancel_on_disconnect: bool,
+    pub compression: bool,
+    pub compression_min_bytes: usize,
+    pub request_id_format: RequestIdFormat,
+    pub verbose_errors: bool,
+}
+
+// Without a `jwt_secret` no bearer token is accepted, so the protected routes
+// only admit API keys; anonymous callers get 401 either way.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub jwt_secret: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub token_ttl_secs: u64,
+    pub refresh_token_ttl_secs: u64,
+}
+
+// Checks that can warn instead of reject. At `warn` the write goes through
+// and the problem is reported in `X-Validation-Warnings`; at `reject` it
+// fails with 422 like any invalid field.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    pub disposable_email: Severity,
+    pub disposable_domains: Vec<String>,
+}
+
+/// What a soft check does with a value that fails it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Off,
+    Warn,
+    Reject,
+}
+
+// `keyspace` prefixes the temporary keyspace `self-test` runs in.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfTestConfig {
+    pub keyspace: String,
+}
+
+// Token bucket per client: `burst` requests at once, refilled at
+// `requests_per_second`. Clients are keyed by API key id when a valid one is
+// presented, otherwise by IP; `trust_forwarded_for` takes the IP from
+// `Forwarded`/`X-Forwarded-For`, which is only safe behind a proxy that sets it.
+// Buckets are per instance; with `distributed` on, each client is also held
+// to `requests_per_second` times `window_secs` requests per window of that
+// many seconds across all instances, counted in Scylla.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub trust_forwarded_for: bool,
+    pub distributed: bool,
+    pub window_secs: u64,
+}
+
+// With `enabled` on, requests are turned away with a 503 while the service is
+// overloaded: more than `max_in_flight` requests at once, or recent requests
+// taking `max_latency_ms` on average. Low-priority requests go first, then
+// normal ones once the load reaches `shed_normal_at` times a threshold; see
+// `shedding`. `priority_header` lets a caller pick its request's priority.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    pub enabled: bool,
+    pub max_in_flight: usize,
+    pub max_latency_ms: u64,
+    pub shed_normal_at: f64,
+    pub priority_header: String,
+    pub retry_after_secs: u64,
+}
+
+// Cross-origin access for browser frontends. The allow-lists only apply in
+// `strict` mode; `permissive` accepts any origin, method and header.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub mode: CorsMode,
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_secs: usize,
+}
+
+/// `off` (default) sends no CORS headers, so browsers block cross-origin
+/// calls. `permissive` allows everything and is meant for local development.
+/// `strict` allows only the configured origins, methods and headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CorsMode {
+    Off,
+    Permissive,
+    Strict,
+}
+
+// The gRPC listener (proto/users.proto) is off unless `bind_addr` is set. It
+// speaks plaintext HTTP/2, so keep it on an internal network. Calls are rate
+// limited and drained on shutdown like HTTP requests. Only with the `grpc`
+// feature.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub bind_addr: Option<String>,
+}
+
+// In-process cache of users for lookups by id; `capacity` 0 turns it off.
+// Writes through other replicas (or straight to the database) show up here
+// only once an entry is `ttl_secs` old, unless the CDC consumer is on.
+//
+// With `redis_url` set, users and listing pages are also cached in Redis,
+// shared by every replica, for `redis_ttl_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub redis_url: Option<String>,
+    pub redis_ttl_secs: u64,
+    pub redis_timeout_ms: u64,
+    pub redis_key_prefix: String,
+}
+
+// The CDC consumer reads the `users` table's CDC log, which has to be turned
+// on with `ALTER TABLE users WITH cdc = {'enabled': true}`, and publishes the
+// changes it finds to the event feeds, so writes made outside this service
+// show up there too. Log entries can land a little late, so a change is read
+// only once it is `confidence_window_ms` old.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CdcConfig {
+    pub enabled: bool,
+    pub poll_interval_ms: u64,
+    pub confidence_window_ms: u64,
+}
+
+// With `enabled` on, user events go out through the `outbox` table: each is
+// written there after its change, and a background relay publishes what it
+// finds and deletes it, so an event written before a crash is still
+// published once the service is back. The relay looks every
+// `relay_interval_ms` and takes up to `batch_size` events from each shard.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxConfig {
+    pub enabled: bool,
+    pub relay_interval_ms: u64,
+    pub batch_size: usize,
+}
+
+// With `enabled` on, the outbox relay also produces every user event to
+// `topic` on the Kafka cluster reached through `brokers`, keyed by user id;
+// see `kafka`. `acks` is -1 to wait for every in-sync replica or 1 for the
+// leader alone; a produce not acknowledged within `timeout_ms` fails, and
+// the relay tries it again. Partition leaders are looked up again every
+// `metadata_max_age_secs`, and after any failure. Only with the `kafka`
+// feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    pub enabled: bool,
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub client_id: String,
+    pub acks: i16,
+    pub timeout_ms: u64,
+    pub metadata_max_age_secs: u64,
+}
+
+// With `enabled` on, instead of Kafka, the outbox relay publishes every user
+// event to NATS JetStream, on `subject_prefix` followed by the event kind and
+// the user id (`users.events.created.<id>`), for a stream capturing
+// `<subject_prefix>.>`; see `nats`. The first of `servers` that answers is
+// used, signed in to with `token` or `username` and `password` if set. A
+// publish the stream hasn't acknowledged within `timeout_ms` fails, and the
+// relay tries it again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatsConfig {
+    pub enabled: bool,
+    pub servers: Vec<String>,
+    pub subject_prefix: String,
+    pub client_name: String,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timeout_ms: u64,
+}
+
+// With `enabled` on, the outbox relay mirrors every user write into the
+// `index` index of a Meilisearch or Elasticsearch server at `url`, and GET
+// /users/search?q= runs full-text queries against it; see `search_index`.
+// `api_key` is sent as a bearer token to Meilisearch and as an `ApiKey` to
+// Elasticsearch. A request the server hasn't answered within `timeout_ms`
+// fails: a write is then retried by the relay, a search returns an error.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchIndexConfig {
+    pub enabled: bool,
+    pub backend: SearchBackend,
+    pub url: String,
+    pub index: String,
+    pub api_key: Option<String>,
+    pub timeout_ms: u64,
+}
+
+/// The search server `search_index` writes to and queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchBackend {
+    Meilisearch,
+    Elasticsearch,
+}
+
+// Periodic maintenance jobs, each run every `*_interval_secs`; 0 leaves a
+// job to POST /admin/jobs/{name}. `purge_deleted` hard-deletes users
+// soft-deleted more than `purge_deleted_after_days` ago, `recount_users`
+// refreshes the user count, and `sweep_user_cache` drops expired entries
+// from the in-memory user cache.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub purge_deleted_interval_secs: u64,
+    pub purge_deleted_after_days: u32,
+    pub recount_users_interval_secs: u64,
+    pub sweep_user_cache_interval_secs: u64,
+}
+
+// Sign-in with Google or GitHub through the authorization-code flow, for
+// each provider with both a client id and secret. Providers send users back
+// to `callback_base_url` followed by /oauth/{provider}/callback, which has to
+// be registered with them as the redirect URI. `request_timeout_ms` bounds
+// each call this service makes to a provider.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OauthConfig {
+    pub callback_base_url: Option<String>,
+    pub google_client_id: Option<String>,
+    pub google_client_secret: Option<String>,
+    pub github_client_id: Option<String>,
+    pub github_client_secret: Option<String>,
+    pub request_timeout_ms: u64,
+}
+
+// With `enabled` on, POST /register queues the new user's insert and answers
+// 202 Accepted; a background writer stores queued users in unlogged batches
+// of up to `batch_size`, waiting up to `flush_interval_ms` for one to fill.
+// Registrations beyond `queue_capacity` waiting are refused with a 503.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteBehindConfig {
+    pub enabled: bool,
+    pub queue_capacity: usize,
+    pub batch_size: usize,
+    pub flush_interval_ms: u64,
+}
+
+// The maintenance mode the service starts in: with `enabled` on, writes get
+// a 503 with `message`, and reads too with `block_reads`. PUT
+// /admin/maintenance switches it at runtime.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceModeConfig {
+    pub enabled: bool,
+    pub block_reads: bool,
+    pub message: String,
+}
+
+// With `enabled` on, user events are POSTed to the subscriptions stored in
+// the `webhooks` table; see `webhooks`. Up to `queue_capacity` events wait to
+// go out and `concurrency` deliveries run at once, each tried up to
+// `max_attempts` times with `timeout_ms` per attempt, backing off from
+// `initial_backoff_ms` to `max_backoff_ms`. Subscriptions are read again
+// every `refresh_interval_secs`, and attempts are logged for
+// `delivery_log_ttl_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub enabled: bool,
+    pub queue_capacity: usize,
+    pub concurrency: usize,
+    pub timeout_ms: u64,
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub refresh_interval_secs: u64,
+    pub delivery_log_ttl_secs: u32,
+}
+
+// With `enabled` on, every user created through the API is sent a welcome
+// email with a link to verify its address, `verification_url` with the token
+// in place of `{token}`, through the SMTP relay at `host`:`port`. Emails wait
+// in a queue of `queue_capacity` and `concurrency` are sent at once; see
+// `mailer`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub timeout_ms: u64,
+    pub queue_capacity: usize,
+    pub concurrency: usize,
+    pub verification_url: Option<String>,
+    pub welcome_subject: String,
+    pub welcome_body: String,
+}
+
+// With `enabled` on, a `sample_rate` share of the reads of a user by id are
+// repeated against a second cluster, at `nodes`, in the background and
+// compared with what the serving cluster returned, to check that the two hold
+// the same data while moving to it; with `dual_write`, writes to users are
+// applied to it as well. See `shadow`. Its keyspace is `scylla.keyspace`
+// unless `keyspace` is set, and with no `nodes` it is another keyspace of the
+// serving cluster. Its other settings, such as timeouts, are those of
+// `[scylla]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowConfig {
+    pub enabled: bool,
+    pub nodes: Vec<String>,
+    pub local_datacenter: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+    pub tls_ca_path: Option<PathBuf>,
+    pub keyspace: Option<String>,
+    pub sample_rate: f64,
+    pub max_in_flight: usize,
+    pub dual_write: bool,
+}
+
+/// How the connection to the SMTP relay is secured: `starttls` upgrades a
+/// plain connection (port 587), `tls` starts with TLS (port 465) and `none`
+/// sends in the clear, for a relay on the same host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    None,
+    StartTls,
+    Tls,
+}
+
+// Feature flags, each on unless named in `disabled`. With `table` on, rows of
+// the `feature_flags` table override them, read every
+// `refresh_interval_secs`; see `flags`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagsConfig {
+    pub disabled: Vec<String>,
+    pub table: bool,
+    pub refresh_interval_secs: u64,
+}
+
+// With `enabled` on, a request names its tenant with `X-Tenant-Id` or, when
+// `base_domain` is set, as the subdomain of it it was sent to, and is served
+// from that tenant's keyspace, `keyspace_prefix` followed by the tenant id.
+// Requests naming no tenant are served from `scylla.keyspace`, which also
+// holds the registry of tenants.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantsConfig {
+    pub enabled: bool,
+    pub base_domain: Option<String>,
+    pub keyspace_prefix: String,
+}
+
+// `level` is an `EnvFilter` directive such as `info` or
+// `info,singlepg_hireme_rust_server=debug`; `RUST_LOG` takes precedence.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub level: String,
+    pub otlp_endpoint: Option<String>,
+}
+
+/// `pretty` is human-readable output for terminals; `json` emits one object
+/// per line for log shippers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+/// How `/users/` relates to `/users`: `trim` (default) strips the trailing
+/// slash, `merge` only collapses repeated slashes, and `strict` leaves paths
+/// untouched so the two stay distinct routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlashPolicy {
+    Trim,
+    Merge,
+    Strict,
+}
+
+/// When the driver sends an idempotent statement again to another replica
+/// while the first attempt is still running: never, `speculative_delay_ms`
+/// after each attempt, or once an attempt takes longer than the
+/// `speculative_percentile` of the node's recent latencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeculativeExecution {
+    Off,
+    Simple,
+    Percentile,
+}
+
+/// Whether `pool_size` connections are opened to each node or to each shard
+/// of each node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolSizePer {
+    Host,
+    Shard,
+}
+
+/// Consistency of the DDL run by bootstrap and migrations. `all` makes every
+/// replica acknowledge a schema change, at the cost of failing while any node
+/// is down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaConsistency {
+    One,
+    LocalQuorum,
+    Quorum,
+    All,
+}
+
+impl SchemaConsistency {
+    pub fn cql(self) -> Consistency {
+        match self {
+            SchemaConsistency::One => Consistency::One,
+            SchemaConsistency::LocalQuorum => Consistency::LocalQuorum,
+            SchemaConsistency::Quorum => Consistency::Quorum,
+            SchemaConsistency::All => Consistency::All,
+        }
+    }
+}
+
+/// CQL batch type used by POST /batch. `logged` (default) guarantees that
+/// either every statement is eventually applied or none is, at the cost of a
+/// batch-log write; `unlogged` skips it and is only atomic within a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchMode {
+    Logged,
+    Unlogged,
+}
+
+/// What a read does once it has produced `max_rows_per_request` rows.
+///
+/// `Truncate` returns the rows gathered so far with `X-Truncated: true`, which
+/// keeps clients working but silently drops data they may not notice is
+/// missing. `Error` fails the request instead, which is louder but breaks
+/// clients that never expected a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RowCapMode {
+    Truncate,
+    Error,
+}
+
+/// Whether PUT and PATCH must say which state of the user they expect.
+///
+/// `Lenient` applies a write without `If-Match` or `version` to whatever is
+/// stored, which is convenient but lets two editors overwrite each other
+/// unawares. `Strict` refuses such writes with 428, so every client has to
+/// read before it writes and deal with 409 and 412 when it loses a race.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyMode {
+    Lenient,
+    Strict,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env { name: &'static str, value: String },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Env { name, value } => write!(f, "invalid {} value: {}", name, value),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for ScyllaConfig {
+    fn default() -> Self {
+        ScyllaConfig {
+            nodes: vec![String::from("127.0.0.1:9042")],
+            cloud_bundle: None,
+            local_datacenter: None,
+            dc_failover: false,
+            username: None,
+            password: None,
+            tls: false,
+            tls_ca_path: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            keyspace: String::from("my_keyspace"),
+            allow_tracing: false,
+            allow_consistency_override: false,
+            allow_raw_cql: false,
+            migrate_on_startup: false,
+            ensure_indexes: false,
+            backfill_concurrency: 8,
+            seed_concurrency: 16,
+            snapshot_rows_per_sec: 5_000,
+            restore_concurrency: 16,
+            bootstrap: false,
+            replication_factor: 1,
+            replication_datacenters: Vec::new(),
+            schema_wait_timeout_secs: 30,
+            schema_wait_initial_backoff_ms: 500,
+            schema_wait_max_backoff_ms: 5_000,
+            schema_consistency: SchemaConsistency::LocalQuorum,
+            schema_agreement_timeout_secs: 60,
+            request_timeout_ms: 5_000,
+            retry_max_attempts: 3,
+            retry_initial_backoff_ms: 50,
+            retry_max_backoff_ms: 1_000,
+            retry_on: vec![
+                RetryKind::ReadTimeout,
+                RetryKind::WriteTimeout,
+                RetryKind::ClientTimeout,
+                RetryKind::Overloaded,
+            ],
+            breaker_failures: 5,
+            breaker_open_secs: 10,
+            max_in_flight_queries: 1_024,
+            query_queue_timeout_ms: 1_000,
+            monitor_interval_secs: 15,
+            slow_query_threshold_ms: 500,
+            speculative_execution: SpeculativeExecution::Off,
+            speculative_max_executions: 1,
+            speculative_delay_ms: 50,
+            speculative_percentile: 99.0,
+            idempotent_statements: Vec::new(),
+            pool_size: 1,
+            pool_size_per: PoolSizePer::Shard,
+            token_aware: true,
+            shard_aware_port: true,
+            latency_awareness: false,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            bind_addr: String::from("127.0.0.1:8080"),
+            unix_socket_path: None,
+            workers: 0,
+            backlog: 2_048,
+            max_connections: 25_000,
+            max_connection_rate: 256,
+            keep_alive_secs: 5,
+            client_request_timeout_ms: 5_000,
+            client_disconnect_timeout_ms: 1_000,
+            trailing_slash: TrailingSlashPolicy::Trim,
+            max_rows_per_request: 10_000,
+            row_cap_mode: RowCapMode::Truncate,
+            latency_window: 1024,
+            default_page_size: 100,
+            max_page_size: 1_000,
+            default_user_ttl_secs: 0,
+            cursor_max_age_secs: 3_600,
+            count_cache_secs: 60,
+            readiness_timeout_ms: 2_000,
+            handler_timeout_ms: 30_000,
+            panic_threshold: 5,
+            panic_window_secs: 60,
+            panic_marks_unready: true,
+            bulk_max_items: 1_000,
+            bulk_concurrency: 16,
+            batch_max_operations: 100,
+            batch_type: BatchMode::Logged,
+            max_json_body_bytes: 1_048_576,
+            avatar_max_bytes: 1_048_576,
+            export_workers: 2,
+            event_buffer: 1024,
+            idempotency_ttl_secs: 86_400,
+            verification_token_ttl_secs: 86_400,
+            password_reset_ttl_secs: 3_600,
+            shutdown_grace_secs: 30,
+            background_grace_secs: 10,
+            tls_cert_path: None,
+            tls_key_path: None,
+            redirect_bind_addr: None,
+            email_check_public: true,
+            put_creates: false,
+            concurrency_mode: ConcurrencyMode::Lenient,
+            cancel_on_disconnect: true,
+            compression: true,
+            compression_min_bytes: 1_024,
+            request_id_format: RequestIdFormat::Uuid,
+            verbose_errors: false,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            jwt_secret: None,
+            jwt_issuer: None,
+            token_ttl_secs: 3_600,
+            refresh_token_ttl_secs: 2_592_000,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Pretty,
+            level: String::from("info"),
+            otlp_endpoint: None,
+        }
+    }
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        LoadSheddingConfig {
+            enabled: false,
+            max_in_flight: 512,
+            max_latency_ms: 1_000,
+            shed_normal_at: 2.0,
+            priority_header: String::from("X-Priority"),
+            retry_after_secs: 1,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            requests_per_second: 50.0,
+            burst: 100,
+            trust_forwarded_for: false,
+            distributed: false,
+            window_secs: 60,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            mode: CorsMode::Off,
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: [
+                "Authorization",
+                "Content-Type",
+                "Idempotency-Key",
+                "If-Match",
+                "If-None-Match",
+                "X-API-Key",
+                "X-Dry-Run",
+                "X-Request-Id",
+            ]
+            .map(String::from)
+            .to_vec(),
+            max_age_secs: 3_600,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 10_000,
+            ttl_secs: 30,
+            redis_url: None,
+            redis_ttl_secs: 60,
+            redis_timeout_ms: 100,
+            redis_key_prefix: String::from("hireme:"),
+        }
+    }
+}
+
+impl Default for CdcConfig {
+    fn default() -> Self {
+        CdcConfig {
+            enabled: false,
+            poll_interval_ms: 1_000,
+            confidence_window_ms: 5_000,
+        }
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            enabled: false,
+            relay_interval_ms: 1_000,
+            batch_size: 100,
+        }
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            purge_deleted_interval_secs: 0,
+            purge_deleted_after_days: 30,
+            recount_users_interval_secs: 0,
+            sweep_user_cache_interval_secs: 0,
+        }
+    }
+}
+
+impl Default for OauthConfig {
+    fn default() -> Self {
+        OauthConfig {
+            callback_base_url: None,
+            google_client_id: None,
+            google_client_secret: None,
+            github_client_id: None,
+            github_client_secret: None,
+            request_timeout_ms: 10_000,
+        }
+    }
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> Self {
+        WriteBehindConfig {
+            enabled: false,
+            queue_capacity: 10_000,
+            batch_size: 50,
+            flush_interval_ms: 20,
+        }
+    }
+}
+
+impl Default for MaintenanceModeConfig {
+    fn default() -> Self {
+        MaintenanceModeConfig {
+            enabled: false,
+            block_reads: false,
+            message: String::from("The service is down for maintenance, please retry later"),
+        }
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            enabled: false,
+            brokers: vec![String::from("localhost:9092")],
+            topic: String::from("user-events"),
+            client_id: String::from("hireme"),
+            acks: -1,
+            timeout_ms: 10_000,
+            metadata_max_age_secs: 300,
+        }
+    }
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        NatsConfig {
+            enabled: false,
+            servers: vec![String::from("localhost:4222")],
+            subject_prefix: String::from("users.events"),
+            client_name: String::from("hireme"),
+            token: None,
+            username: None,
+            password: None,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+impl Default for SearchIndexConfig {
+    fn default() -> Self {
+        SearchIndexConfig {
+            enabled: false,
+            backend: SearchBackend::Meilisearch,
+            url: String::from("http://localhost:7700"),
+            index: String::from("users"),
+            api_key: None,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            enabled: false,
+            queue_capacity: 10_000,
+            concurrency: 16,
+            timeout_ms: 5_000,
+            max_attempts: 5,
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 60_000,
+            refresh_interval_secs: 30,
+            delivery_log_ttl_secs: 604_800,
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            enabled: false,
+            host: String::from("localhost"),
+            port: 587,
+            tls: SmtpTls::StartTls,
+            username: None,
+            password: None,
+            from: String::from("HireMe <no-reply@example.com>"),
+            timeout_ms: 10_000,
+            queue_capacity: 1_000,
+            concurrency: 4,
+            verification_url: None,
+            welcome_subject: String::from("Welcome, {name}"),
+            welcome_body: String::from(
+                "Hi {name},\n\n\
+                 Welcome aboard! Please confirm that {email} is your address by opening\n\n\
+                 {verification_url}\n\n\
+                 If you didn't sign up, you can ignore this email.\n",
+            ),
+        }
+    }
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        ShadowConfig {
+            enabled: false,
+            nodes: Vec::new(),
+            local_datacenter: None,
+            username: None,
+            password: None,
+            tls: false,
+            tls_ca_path: None,
+            keyspace: None,
+            sample_rate: 1.0,
+            max_in_flight: 64,
+            dual_write: false,
+        }
+    }
+}
+
+impl Default for FlagsConfig {
+    fn default() -> Self {
+        FlagsConfig {
+            disabled: Vec::new(),
+            table: false,
+            refresh_interval_secs: 30,
+        }
+    }
+}
+
+impl Default for TenantsConfig {
+    fn default() -> Self {
+        TenantsConfig {
+            enabled: false,
+            base_domain: None,
+            keyspace_prefix: String::from("tenant_"),
+        }
+    }
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            disposable_email: Severity::Warn,
+            disposable_domains: [
+                "mailinator.com",
+                "guerrillamail.com",
+                "10minutemail.com",
+                "temp-mail.org",
+                "yopmail.com",
+                "trashmail.com",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        SelfTestConfig {
+            keyspace: String::from("self_test"),
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Severity::Off),
+            "warn" => Ok(Severity::Warn),
+            "reject" => Ok(Severity::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SchemaConsistency {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "one" => Ok(SchemaConsistency::One),
+            "local_quorum" => Ok(SchemaConsistency::LocalQuorum),
+            "quorum" => Ok(SchemaConsistency::Quorum),
+            "all" => Ok(SchemaConsistency::All),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for TrailingSlashPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "trim" => Ok(TrailingSlashPolicy::Trim),
+            "merge" => Ok(TrailingSlashPolicy::Merge),
+            "strict" => Ok(TrailingSlashPolicy::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for CorsMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(CorsMode::Off),
+            "permissive" => Ok(CorsMode::Permissive),
+            "strict" => Ok(CorsMode::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for RetryKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "read_timeout" => Ok(RetryKind::ReadTimeout),
+            "write_timeout" => Ok(RetryKind::WriteTimeout),
+            "client_timeout" => Ok(RetryKind::ClientTimeout),
+            "overloaded" => Ok(RetryKind::Overloaded),
+            "unavailable" => Ok(RetryKind::Unavailable),
+            "connection" => Ok(RetryKind::Connection),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for BatchMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "logged" => Ok(BatchMode::Logged),
+            "unlogged" => Ok(BatchMode::Unlogged),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SmtpTls {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(SmtpTls::None),
+            "starttls" => Ok(SmtpTls::StartTls),
+            "tls" => Ok(SmtpTls::Tls),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SearchBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "meilisearch" => Ok(SearchBackend::Meilisearch),
+            "elasticsearch" => Ok(SearchBackend::Elasticsearch),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for ConcurrencyMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lenient" => Ok(ConcurrencyMode::Lenient),
+            "strict" => Ok(ConcurrencyMode::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SpeculativeExecution {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(SpeculativeExecution::Off),
+            "simple" => Ok(SpeculativeExecution::Simple),
+            "percentile" => Ok(SpeculativeExecution::Percentile),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for PoolSizePer {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "host" => Ok(PoolSizePer::Host),
+            "shard" => Ok(PoolSizePer::Shard),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for RowCapMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "truncate" => Ok(RowCapMode::Truncate),
+            "error" => Ok(RowCapMode::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+// Overwrites `target` with the parsed value of `name` when it is set.
+fn env_override<T: FromStr>(name: &'static str, target: &mut T) -> Result<(), ConfigError> {
+    if let Ok(value) = std::env::var(name) {
+        *target = value.parse().map_err(|_| ConfigError::Env { name, value })?;
+    }
+    Ok(())
+}
+
+fn env_string(name: &'static str, target: &mut Option<String>) {
+    if let Ok(value) = std::env::var(name) {
+        *target = Some(value);
+    }
+}
+
+// Comma-separated list, blank entries dropped.
+fn env_list(name: &'static str, target: &mut Vec<String>) {
+    if let Ok(value) = std::env::var(name) {
+        *target = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+// Comma-separated list of values parsed with `FromStr`.
+fn env_parsed_list<T: FromStr>(name: &'static str, target: &mut Vec<T>) -> Result<(), ConfigError> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(());
+    };
+    *target = value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse().map_err(|_| ConfigError::Env {
+                name,
+                value: item.to_string(),
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+fn env_path(name: &'static str, target: &mut Option<PathBuf>) {
+    if let Some(value) = std::env::var_os(name) {
+        *target = Some(PathBuf::from(value));
+    }
+}
+
+fn env_flag(name: &'static str, target: &mut bool) {
+    if let Ok(value) = std::env::var(name) {
+        *target = value.eq_ignore_ascii_case("true");
+    }
+}
+
+// Keyspace names end up interpolated into CQL text, so only plain identifiers
+// are accepted.
+pub fn is_valid_keyspace(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 48
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Datacenter names are quoted into the replication map of CREATE KEYSPACE.
+fn is_valid_datacenter(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match std::env::var_os("CONFIG_FILE") {
+            Some(path) => Config::from_file(PathBuf::from(path))?,
+            None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(PathBuf::from(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: PathBuf) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_list("SCYLLA_NODES", &mut self.scylla.nodes);
+        env_path("SCYLLA_CLOUD_BUNDLE", &mut self.scylla.cloud_bundle);
+        env_string("SCYLLA_LOCAL_DC", &mut self.scylla.local_datacenter);
+        env_flag("SCYLLA_DC_FAILOVER", &mut self.scylla.dc_failover);
+        env_string("SCYLLA_USERNAME", &mut self.scylla.username);
+        env_string("SCYLLA_PASSWORD", &mut self.scylla.password);
+        env_flag("SCYLLA_TLS", &mut self.scylla.tls);
+        env_path("SCYLLA_TLS_CA", &mut self.scylla.tls_ca_path);
+        env_path("SCYLLA_TLS_CERT", &mut self.scylla.tls_cert_path);
+        env_path("SCYLLA_TLS_KEY", &mut self.scylla.tls_key_path);
+        env_override("KEYSPACE", &mut self.scylla.keyspace)?;
+        env_flag("ALLOW_SCYLLA_TRACING", &mut self.scylla.allow_tracing);
+        env_flag("ALLOW_CONSISTENCY_OVERRIDE", &mut self.scylla.allow_consistency_override);
+        env_flag("ALLOW_RAW_CQL", &mut self.scylla.allow_raw_cql);
+        env_flag("MIGRATE_ON_STARTUP", &mut self.scylla.migrate_on_startup);
+        env_flag("ENSURE_INDEXES", &mut self.scylla.ensure_indexes);
+        env_override("BACKFILL_CONCURRENCY", &mut self.scylla.backfill_concurrency)?;
+        env_override("SEED_CONCURRENCY", &mut self.scylla.seed_concurrency)?;
+        env_override("SNAPSHOT_ROWS_PER_SEC", &mut self.scylla.snapshot_rows_per_sec)?;
+        env_override("RESTORE_CONCURRENCY", &mut self.scylla.restore_concurrency)?;
+        env_flag("BOOTSTRAP_SCHEMA", &mut self.scylla.bootstrap);
+        env_override("REPLICATION_FACTOR", &mut self.scylla.replication_factor)?;
+        env_list("REPLICATION_DATACENTERS", &mut self.scylla.replication_datacenters);
+        env_override("SCHEMA_WAIT_TIMEOUT_SECS", &mut self.scylla.schema_wait_timeout_secs)?;
+        env_override(
+            "SCHEMA_WAIT_INITIAL_BACKOFF_MS",
+            &mut self.scylla.schema_wait_initial_backoff_ms,
+        )?;
+        env_override("SCHEMA_WAIT_MAX_BACKOFF_MS", &mut self.scylla.schema_wait_max_backoff_ms)?;
+        env_override("SCHEMA_CONSISTENCY", &mut self.scylla.schema_consistency)?;
+        env_override(
+            "SCHEMA_AGREEMENT_TIMEOUT_SECS",
+            &mut self.scylla.schema_agreement_timeout_secs,
+        )?;
+        env_override("SCYLLA_REQUEST_TIMEOUT_MS", &mut self.scylla.request_timeout_ms)?;
+        env_override("SCYLLA_RETRY_MAX_ATTEMPTS", &mut self.scylla.retry_max_attempts)?;
+        env_override(
+            "SCYLLA_RETRY_INITIAL_BACKOFF_MS",
+            &mut self.scylla.retry_initial_backoff_ms,
+        )?;
+        env_override("SCYLLA_RETRY_MAX_BACKOFF_MS", &mut self.scylla.retry_max_backoff_ms)?;
+        env_parsed_list("SCYLLA_RETRY_ON", &mut self.scylla.retry_on)?;
+        env_override("SCYLLA_BREAKER_FAILURES", &mut self.scylla.breaker_failures)?;
+        env_override("SCYLLA_BREAKER_OPEN_SECS", &mut self.scylla.breaker_open_secs)?;
+        env_override("SCYLLA_MAX_IN_FLIGHT_QUERIES", &mut self.scylla.max_in_flight_queries)?;
+        env_override("SCYLLA_QUERY_QUEUE_TIMEOUT_MS", &mut self.scylla.query_queue_timeout_ms)?;
+        env_override("SCYLLA_MONITOR_INTERVAL_SECS", &mut self.scylla.monitor_interval_secs)?;
+        env_override("SLOW_QUERY_THRESHOLD_MS", &mut self.scylla.slow_query_threshold_ms)?;
+        env_override("SCYLLA_SPECULATIVE_EXECUTION", &mut self.scylla.speculative_execution)?;
+        env_override(
+            "SCYLLA_SPECULATIVE_MAX_EXECUTIONS",
+            &mut self.scylla.speculative_max_executions,
+        )?;
+        env_override("SCYLLA_SPECULATIVE_DELAY_MS", &mut self.scylla.speculative_delay_ms)?;
+        env_override("SCYLLA_SPECULATIVE_PERCENTILE", &mut self.scylla.speculative_percentile)?;
+        env_list("SCYLLA_IDEMPOTENT_STATEMENTS", &mut self.scylla.idempotent_statements);
+        env_override("SCYLLA_POOL_SIZE", &mut self.scylla.pool_size)?;
+        env_override("SCYLLA_POOL_SIZE_PER", &mut self.scylla.pool_size_per)?;
+        env_flag("SCYLLA_TOKEN_AWARE", &mut self.scylla.token_aware);
+        env_flag("SCYLLA_SHARD_AWARE_PORT", &mut self.scylla.shard_aware_port);
+        env_flag("SCYLLA_LATENCY_AWARENESS", &mut self.scylla.latency_awareness);
+
+        env_override("BIND_ADDR", &mut self.http.bind_addr)?;
+        env_path("HTTP_UNIX_SOCKET_PATH", &mut self.http.unix_socket_path);
+        env_override("HTTP_WORKERS", &mut self.http.workers)?;
+        env_override("HTTP_BACKLOG", &mut self.http.backlog)?;
+        env_override("HTTP_MAX_CONNECTIONS", &mut self.http.max_connections)?;
+        env_override("HTTP_MAX_CONNECTION_RATE", &mut self.http.max_connection_rate)?;
+        env_override("HTTP_KEEP_ALIVE_SECS", &mut self.http.keep_alive_secs)?;
+        env_override("HTTP_CLIENT_REQUEST_TIMEOUT_MS", &mut self.http.client_request_timeout_ms)?;
+        env_override(
+            "HTTP_CLIENT_DISCONNECT_TIMEOUT_MS",
+            &mut self.http.client_disconnect_timeout_ms,
+        )?;
+        env_override("TRAILING_SLASH", &mut self.http.trailing_slash)?;
+        env_override("MAX_ROWS_PER_REQUEST", &mut self.http.max_rows_per_request)?;
+        env_override("ROW_CAP_MODE", &mut self.http.row_cap_mode)?;
+        env_override("LATENCY_WINDOW", &mut self.http.latency_window)?;
+        env_override("DEFAULT_PAGE_SIZE", &mut self.http.default_page_size)?;
+        env_override("MAX_PAGE_SIZE", &mut self.http.max_page_size)?;
+        env_override("DEFAULT_USER_TTL_SECS", &mut self.http.default_user_ttl_secs)?;
+        env_override("CURSOR_MAX_AGE_SECS", &mut self.http.cursor_max_age_secs)?;
+        env_override("COUNT_CACHE_SECS", &mut self.http.count_cache_secs)?;
+        env_override("READINESS_TIMEOUT_MS", &mut self.http.readiness_timeout_ms)?;
+        env_override("HANDLER_TIMEOUT_MS", &mut self.http.handler_timeout_ms)?;
+        env_override("PANIC_THRESHOLD", &mut self.http.panic_threshold)?;
+        env_override("PANIC_WINDOW_SECS", &mut self.http.panic_window_secs)?;
+        env_flag("PANIC_MARKS_UNREADY", &mut self.http.panic_marks_unready);
+        env_override("BULK_MAX_ITEMS", &mut self.http.bulk_max_items)?;
+        env_override("BULK_CONCURRENCY", &mut self.http.bulk_concurrency)?;
+        env_override("BATCH_MAX_OPERATIONS", &mut self.http.batch_max_operations)?;
+        env_override("BATCH_TYPE", &mut self.http.batch_type)?;
+        env_override("MAX_JSON_BODY_BYTES", &mut self.http.max_json_body_bytes)?;
+        env_override("AVATAR_MAX_BYTES", &mut self.http.avatar_max_bytes)?;
+        env_override("EXPORT_WORKERS", &mut self.http.export_workers)?;
+        env_override("EVENT_BUFFER", &mut self.http.event_buffer)?;
+        env_override("IDEMPOTENCY_TTL_SECS", &mut self.http.idempotency_ttl_secs)?;
+        env_override(
+            "VERIFICATION_TOKEN_TTL_SECS",
+            &mut self.http.verification_token_ttl_secs,
+        )?;
+        env_override("PASSWORD_RESET_TTL_SECS", &mut self.http.password_reset_ttl_secs)?;
+        env_override("SHUTDOWN_GRACE_SECS", &mut self.http.shutdown_grace_secs)?;
+        env_override("BACKGROUND_GRACE_SECS", &mut self.http.background_grace_secs)?;
+        env_path("TLS_CERT_PATH", &mut self.http.tls_cert_path);
+        env_path("TLS_KEY_PATH", &mut self.http.tls_key_path);
+        env_string("HTTP_REDIRECT_BIND_ADDR", &mut self.http.redirect_bind_addr);
+        env_flag("EMAIL_CHECK_PUBLIC", &mut self.http.email_check_public);
+        env_flag("PUT_CREATES", &mut self.http.put_creates);
+        env_override("CONCURRENCY_MODE", &mut self.http.concurrency_mode)?;
+        env_flag("CANCEL_ON_DISCONNECT", &mut self.http.cancel_on_disconnect);
+        env_flag("COMPRESSION", &mut self.http.compression);
+        env_override("COMPRESSION_MIN_BYTES", &mut self.http.compression_min_bytes)?;
+        env_override("REQUEST_ID_FORMAT", &mut self.http.request_id_format)?;
+        env_flag("VERBOSE_ERRORS", &mut self.http.verbose_errors);
+
+        env_string("JWT_SECRET", &mut self.auth.jwt_secret);
+        env_string("JWT_ISSUER", &mut self.auth.jwt_issuer);
+        env_override("TOKEN_TTL_SECS", &mut self.auth.token_ttl_secs)?;
+        env_override("REFRESH_TOKEN_TTL_SECS", &mut self.auth.refresh_token_ttl_secs)?;
+
+        env_override("SELF_TEST_KEYSPACE", &mut self.self_test.keyspace)?;
+
+        env_flag("RATE_LIMIT_ENABLED", &mut self.rate_limit.enabled);
+        env_override("RATE_LIMIT_RPS", &mut self.rate_limit.requests_per_second)?;
+        env_override("RATE_LIMIT_BURST", &mut self.rate_limit.burst)?;
+        env_flag("RATE_LIMIT_TRUST_FORWARDED_FOR", &mut self.rate_limit.trust_forwarded_for);
+        env_flag("RATE_LIMIT_DISTRIBUTED", &mut self.rate_limit.distributed);
+        env_override("RATE_LIMIT_WINDOW_SECS", &mut self.rate_limit.window_secs)?;
+
+        env_flag("LOAD_SHEDDING_ENABLED", &mut self.load_shedding.enabled);
+        env_override("LOAD_SHEDDING_MAX_IN_FLIGHT", &mut self.load_shedding.max_in_flight)?;
+        env_override("LOAD_SHEDDING_MAX_LATENCY_MS", &mut self.load_shedding.max_latency_ms)?;
+        env_override("LOAD_SHEDDING_SHED_NORMAL_AT", &mut self.load_shedding.shed_normal_at)?;
+        env_override("LOAD_SHEDDING_PRIORITY_HEADER", &mut self.load_shedding.priority_header)?;
+        env_override("LOAD_SHEDDING_RETRY_AFTER_SECS", &mut self.load_shedding.retry_after_secs)?;
+
+        env_override("CORS_MODE", &mut self.cors.mode)?;
+        env_list("CORS_ALLOWED_ORIGINS", &mut self.cors.allowed_origins);
+        env_list("CORS_ALLOWED_METHODS", &mut self.cors.allowed_methods);
+        env_list("CORS_ALLOWED_HEADERS", &mut self.cors.allowed_headers);
+        env_override("CORS_MAX_AGE_SECS", &mut self.cors.max_age_secs)?;
+
+        env_override("LOG_FORMAT", &mut self.log.format)?;
+        env_override("LOG_LEVEL", &mut self.log.level)?;
+        env_string("OTEL_EXPORTER_OTLP_ENDPOINT", &mut self.log.otlp_endpoint);
+
+        env_string("GRPC_BIND_ADDR", &mut self.grpc.bind_addr);
+
+        env_flag("CDC_ENABLED", &mut self.cdc.enabled);
+        env_override("CDC_POLL_INTERVAL_MS", &mut self.cdc.poll_interval_ms)?;
+        env_override("CDC_CONFIDENCE_WINDOW_MS", &mut self.cdc.confidence_window_ms)?;
+
+        env_override("CACHE_CAPACITY", &mut self.cache.capacity)?;
+        env_override("CACHE_TTL_SECS", &mut self.cache.ttl_secs)?;
+        env_string("CACHE_REDIS_URL", &mut self.cache.redis_url);
+        env_override("CACHE_REDIS_TTL_SECS", &mut self.cache.redis_ttl_secs)?;
+        env_override("CACHE_REDIS_TIMEOUT_MS", &mut self.cache.redis_timeout_ms)?;
+        env_override("CACHE_REDIS_KEY_PREFIX", &mut self.cache.redis_key_prefix)?;
+
+        env_override("VALIDATION_DISPOSABLE_EMAIL", &mut self.validation.disposable_email)?;
+        env_list("VALIDATION_DISPOSABLE_DOMAINS", &mut self.validation.disposable_domains);
+
+        env_flag("TENANTS_ENABLED", &mut self.tenants.enabled);
+        env_string("TENANTS_BASE_DOMAIN", &mut self.tenants.base_domain);
+        env_override("TENANTS_KEYSPACE_PREFIX", &mut self.tenants.keyspace_prefix)?;
+        env_flag("OUTBOX_ENABLED", &mut self.outbox.enabled);
+        env_override("OUTBOX_RELAY_INTERVAL_MS", &mut self.outbox.relay_interval_ms)?;
+        env_override("OUTBOX_BATCH_SIZE", &mut self.outbox.batch_size)?;
+        env_flag("KAFKA_ENABLED", &mut self.kafka.enabled);
+        env_list("KAFKA_BROKERS", &mut self.kafka.brokers);
+        env_override("KAFKA_TOPIC", &mut self.kafka.topic)?;
+        env_override("KAFKA_CLIENT_ID", &mut self.kafka.client_id)?;
+        env_override("KAFKA_ACKS", &mut self.kafka.acks)?;
+        env_override("KAFKA_TIMEOUT_MS", &mut self.kafka.timeout_ms)?;
+        env_override("KAFKA_METADATA_MAX_AGE_SECS", &mut self.kafka.metadata_max_age_secs)?;
+        env_flag("NATS_ENABLED", &mut self.nats.enabled);
+        env_list("NATS_SERVERS", &mut self.nats.servers);
+        env_override("NATS_SUBJECT_PREFIX", &mut self.nats.subject_prefix)?;
+        env_override("NATS_CLIENT_NAME", &mut self.nats.client_name)?;
+        env_string("NATS_TOKEN", &mut self.nats.token);
+        env_string("NATS_USERNAME", &mut self.nats.username);
+        env_string("NATS_PASSWORD", &mut self.nats.password);
+        env_override("NATS_TIMEOUT_MS", &mut self.nats.timeout_ms)?;
+        env_flag("SEARCH_INDEX_ENABLED", &mut self.search_index.enabled);
+        env_override("SEARCH_INDEX_BACKEND", &mut self.search_index.backend)?;
+        env_override("SEARCH_INDEX_URL", &mut self.search_index.url)?;
+        env_override("SEARCH_INDEX_INDEX", &mut self.search_index.index)?;
+        env_string("SEARCH_INDEX_API_KEY", &mut self.search_index.api_key);
+        env_override("SEARCH_INDEX_TIMEOUT_MS", &mut self.search_index.timeout_ms)?;
+        env_override(
+            "MAINTENANCE_PURGE_DELETED_INTERVAL_SECS",
+            &mut self.maintenance.purge_deleted_interval_secs,
+        )?;
+        env_override(
+            "MAINTENANCE_PURGE_DELETED_AFTER_DAYS",
+            &mut self.maintenance.purge_deleted_after_days,
+        )?;
+        env_override(
+            "MAINTENANCE_RECOUNT_USERS_INTERVAL_SECS",
+            &mut self.maintenance.recount_users_interval_secs,
+        )?;
+        env_override(
+            "MAINTENANCE_SWEEP_USER_CACHE_INTERVAL_SECS",
+            &mut self.maintenance.sweep_user_cache_interval_secs,
+        )?;
+        env_string("OAUTH_CALLBACK_BASE_URL", &mut self.oauth.callback_base_url);
+        env_string("OAUTH_GOOGLE_CLIENT_ID", &mut self.oauth.google_client_id);
+        env_string("OAUTH_GOOGLE_CLIENT_SECRET", &mut self.oauth.google_client_secret);
+        env_string("OAUTH_GITHUB_CLIENT_ID", &mut self.oauth.github_client_id);
+        env_string("OAUTH_GITHUB_CLIENT_SECRET", &mut self.oauth.github_client_secret);
+        env_override("OAUTH_REQUEST_TIMEOUT_MS", &mut self.oauth.request_timeout_ms)?;
+        env_flag("WRITE_BEHIND_ENABLED", &mut self.write_behind.enabled);
+        env_override("WRITE_BEHIND_QUEUE_CAPACITY", &mut self.write_behind.queue_capacity)?;
+        env_override("WRITE_BEHIND_BATCH_SIZE", &mut self.write_behind.batch_size)?;
+        env_override("WRITE_BEHIND_FLUSH_INTERVAL_MS", &mut self.write_behind.flush_interval_ms)?;
+        env_list("FLAGS_DISABLED", &mut self.flags.disabled);
+        env_flag("FLAGS_TABLE", &mut self.flags.table);
+        env_override("FLAGS_REFRESH_INTERVAL_SECS", &mut self.flags.refresh_interval_secs)?;
+        env_flag("MAINTENANCE_MODE", &mut self.maintenance_mode.enabled);
+        env_flag("MAINTENANCE_MODE_BLOCK_READS", &mut self.maintenance_mode.block_reads);
+        env_override("MAINTENANCE_MODE_MESSAGE", &mut self.maintenance_mode.message)?;
+        env_flag("WEBHOOKS_ENABLED", &mut self.webhooks.enabled);
+        env_override("WEBHOOKS_QUEUE_CAPACITY", &mut self.webhooks.queue_capacity)?;
+        env_override("WEBHOOKS_CONCURRENCY", &mut self.webhooks.concurrency)?;
+        env_override("WEBHOOKS_TIMEOUT_MS", &mut self.webhooks.timeout_ms)?;
+        env_override("WEBHOOKS_MAX_ATTEMPTS", &mut self.webhooks.max_attempts)?;
+        env_override("WEBHOOKS_INITIAL_BACKOFF_MS", &mut self.webhooks.initial_backoff_ms)?;
+        env_override("WEBHOOKS_MAX_BACKOFF_MS", &mut self.webhooks.max_backoff_ms)?;
+        env_override("WEBHOOKS_REFRESH_INTERVAL_SECS", &mut self.webhooks.refresh_interval_secs)?;
+        env_override("WEBHOOKS_DELIVERY_LOG_TTL_SECS", &mut self.webhooks.delivery_log_ttl_secs)?;
+        env_flag("SMTP_ENABLED", &mut self.smtp.enabled);
+        env_override("SMTP_HOST", &mut self.smtp.host)?;
+        env_override("SMTP_PORT", &mut self.smtp.port)?;
+        env_override("SMTP_TLS", &mut self.smtp.tls)?;
+        env_string("SMTP_USERNAME", &mut self.smtp.username);
+        env_string("SMTP_PASSWORD", &mut self.smtp.password);
+        env_override("SMTP_FROM", &mut self.smtp.from)?;
+        env_override("SMTP_TIMEOUT_MS", &mut self.smtp.timeout_ms)?;
+        env_override("SMTP_QUEUE_CAPACITY", &mut self.smtp.queue_capacity)?;
+        env_override("SMTP_CONCURRENCY", &mut self.smtp.concurrency)?;
+        env_string("SMTP_VERIFICATION_URL", &mut self.smtp.verification_url);
+        env_override("SMTP_WELCOME_SUBJECT", &mut self.smtp.welcome_subject)?;
+        env_flag("SHADOW_ENABLED", &mut self.shadow.enabled);
+        env_list("SHADOW_NODES", &mut self.shadow.nodes);
+        env_string("SHADOW_LOCAL_DC", &mut self.shadow.local_datacenter);
+        env_string("SHADOW_USERNAME", &mut self.shadow.username);
+        env_string("SHADOW_PASSWORD", &mut self.shadow.password);
+        env_flag("SHADOW_TLS", &mut self.shadow.tls);
+        env_path("SHADOW_TLS_CA", &mut self.shadow.tls_ca_path);
+        env_string("SHADOW_KEYSPACE", &mut self.shadow.keyspace);
+        env_override("SHADOW_SAMPLE_RATE", &mut self.shadow.sample_rate)?;
+        env_override("SHADOW_MAX_IN_FLIGHT", &mut self.shadow.max_in_flight)?;
+        env_flag("SHADOW_DUAL_WRITE", &mut self.shadow.dual_write);
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.scylla.nodes.is_empty() && self.scylla.cloud_bundle.is_none() {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.nodes must list at least one node",
+            )));
+        }
+        for keyspace in [&self.scylla.keyspace, &self.self_test.keyspace] {
+            if !is_valid_keyspace(keyspace) {
+                return Err(ConfigError::Invalid(format!("invalid keyspace name: {}", keyspace)));
+            }
+        }
+        if self.scylla.replication_factor == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.replication_factor must be positive",
+            )));
+        }
+        if self.scylla.backfill_concurrency == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.backfill_concurrency must be positive",
+            )));
+        }
+        if self.scylla.seed_concurrency == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.seed_concurrency must be positive",
+            )));
+        }
+        if self.scylla.restore_concurrency == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.restore_concurrency must be positive",
+            )));
+        }
+        if let Some(dc) = self
+            .scylla
+            .replication_datacenters
+            .iter()
+            .find(|dc| !is_valid_datacenter(dc))
+        {
+            return Err(ConfigError::Invalid(format!("invalid datacenter name: {}", dc)));
+        }
+        if self.http.unix_socket_path.is_some() && !cfg!(unix) {
+            return Err(ConfigError::Invalid(String::from(
+                "http.unix_socket_path is only supported on Unix",
+            )));
+        }
+        if self.http.bind_addr.is_empty() {
+            if self.http.unix_socket_path.is_none() {
+                return Err(ConfigError::Invalid(String::from(
+                    "http.bind_addr may only be empty with http.unix_socket_path set",
+                )));
+            }
+            if self.http.tls_cert_path.is_some() {
+                return Err(ConfigError::Invalid(String::from(
+                    "http.tls_cert_path needs a TCP listener; set http.bind_addr",
+                )));
+            }
+        } else if self.http.bind_addr.to_socket_addrs().is_err() {
+            return Err(ConfigError::Invalid(format!(
+                "http.bind_addr is not a host:port address: {}",
+                self.http.bind_addr
+            )));
+        }
+        if self.scylla.local_datacenter.as_deref().is_some_and(str::is_empty) {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.local_datacenter must not be empty",
+            )));
+        }
+        if self.scylla.username.is_some() != self.scylla.password.is_some() {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.username and scylla.password must be set together",
+            )));
+        }
+        if self.scylla.cloud_bundle.is_some() && self.scylla.username.is_some() {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.username is ignored with a cloud bundle; put credentials in the bundle",
+            )));
+        }
+        if self.scylla.tls_cert_path.is_some() != self.scylla.tls_key_path.is_some() {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.tls_cert_path and scylla.tls_key_path must be set together",
+            )));
+        }
+        if self.http.tls_cert_path.is_some() != self.http.tls_key_path.is_some() {
+            return Err(ConfigError::Invalid(String::from(
+                "http.tls_cert_path and http.tls_key_path must be set together",
+            )));
+        }
+        if let Some(redirect) = &self.http.redirect_bind_addr {
+            if self.http.tls_cert_path.is_none() {
+                return Err(ConfigError::Invalid(String::from(
+                    "http.redirect_bind_addr only makes sense with TLS enabled",
+                )));
+            }
+            if redirect.to_socket_addrs().is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "http.redirect_bind_addr is not a host:port address: {}",
+                    redirect
+                )));
+            }
+        }
+        if let Some(grpc) = &self.grpc.bind_addr
+            && grpc.to_socket_addrs().is_err()
+        {
+            return Err(ConfigError::Invalid(format!(
+                "grpc.bind_addr is not a host:port address: {}",
+                grpc
+            )));
+        }
+        if self.http.max_rows_per_request == 0 || self.http.latency_window == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.max_rows_per_request and http.latency_window must be positive",
+            )));
+        }
+        if self.http.default_page_size == 0 || self.http.default_page_size > self.http.max_page_size {
+            return Err(ConfigError::Invalid(String::from(
+                "http.default_page_size must be between 1 and http.max_page_size",
+            )));
+        }
+        if self.http.default_user_ttl_secs > validation::MAX_TTL_SECS {
+            return Err(ConfigError::Invalid(format!(
+                "http.default_user_ttl_secs must be at most {}",
+                validation::MAX_TTL_SECS
+            )));
+        }
+        if self.http.bulk_max_items == 0 || self.http.bulk_concurrency == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.bulk_max_items and http.bulk_concurrency must be positive",
+            )));
+        }
+        if self.http.batch_max_operations == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.batch_max_operations must be positive",
+            )));
+        }
+        if self.http.cursor_max_age_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.cursor_max_age_secs must be positive",
+            )));
+        }
+        if self.http.count_cache_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.count_cache_secs must be positive",
+            )));
+        }
+        if self.http.backlog == 0
+            || self.http.max_connections == 0
+            || self.http.max_connection_rate == 0
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "http.backlog, http.max_connections and http.max_connection_rate must be positive",
+            )));
+        }
+        if self.http.max_json_body_bytes == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.max_json_body_bytes must be positive",
+            )));
+        }
+        if self.http.avatar_max_bytes == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.avatar_max_bytes must be positive",
+            )));
+        }
+        if self.http.export_workers == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.export_workers must be positive",
+            )));
+        }
+        if self.http.event_buffer == 0 {
+            return Err(ConfigError::Invalid(String::from("http.event_buffer must be positive")));
+        }
+        // Scylla refuses TTLs over 20 years.
+        if self.http.idempotency_ttl_secs == 0 || self.http.idempotency_ttl_secs > 630_720_000 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.idempotency_ttl_secs must be between 1 and 630720000",
+            )));
+        }
+        if self.http.verification_token_ttl_secs == 0
+            || self.http.verification_token_ttl_secs > u64::from(validation::MAX_TTL_SECS)
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "http.verification_token_ttl_secs must be between 1 and 630720000",
+            )));
+        }
+        if self.http.password_reset_ttl_secs == 0
+            || self.http.password_reset_ttl_secs > u64::from(validation::MAX_TTL_SECS)
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "http.password_reset_ttl_secs must be between 1 and 630720000",
+            )));
+        }
+        if self.cache.capacity > 0 && self.cache.ttl_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "cache.ttl_secs must be positive (set cache.capacity = 0 to turn the cache off)",
+            )));
+        }
+        if let Some(url) = &self.cache.redis_url {
+            RedisUrl::parse(url)
+                .map_err(|e| ConfigError::Invalid(format!("cache.redis_url: {}", e)))?;
+            if self.cache.redis_ttl_secs == 0 || self.cache.redis_timeout_ms == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "cache.redis_ttl_secs and cache.redis_timeout_ms must be positive",
+                )));
+            }
+        }
+        if !is_valid_keyspace(&self.tenants.keyspace_prefix) {
+            return Err(ConfigError::Invalid(format!(
+                "invalid tenants.keyspace_prefix: {}",
+                self.tenants.keyspace_prefix
+            )));
+        }
+        if self.tenants.base_domain.as_deref().is_some_and(str::is_empty) {
+            return Err(ConfigError::Invalid(String::from("tenants.base_domain must not be empty")));
+        }
+        if self.cdc.poll_interval_ms == 0 {
+            return Err(ConfigError::Invalid(String::from("cdc.poll_interval_ms must be positive")));
+        }
+        if self.outbox.relay_interval_ms == 0 || self.outbox.batch_size == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "outbox.relay_interval_ms and outbox.batch_size must be positive",
+            )));
+        }
+        let kafka = &self.kafka;
+        if kafka.enabled {
+            if !self.outbox.enabled {
+                return Err(ConfigError::Invalid(String::from(
+                    "kafka.enabled needs outbox.enabled: events reach Kafka through the outbox",
+                )));
+            }
+            if kafka.brokers.is_empty() || kafka.topic.is_empty() {
+                return Err(ConfigError::Invalid(String::from(
+                    "kafka.brokers and kafka.topic must be set",
+                )));
+            }
+            if kafka.acks != -1 && kafka.acks != 1 {
+                return Err(ConfigError::Invalid(String::from("kafka.acks must be -1 or 1")));
+            }
+            if kafka.timeout_ms == 0 || kafka.timeout_ms > i32::MAX as u64 {
+                return Err(ConfigError::Invalid(String::from(
+                    "kafka.timeout_ms must be positive",
+                )));
+            }
+        }
+        let nats = &self.nats;
+        if nats.enabled {
+            if !self.outbox.enabled {
+                return Err(ConfigError::Invalid(String::from(
+                    "nats.enabled needs outbox.enabled: events reach NATS through the outbox",
+                )));
+            }
+            if kafka.enabled {
+                return Err(ConfigError::Invalid(String::from(
+                    "kafka.enabled and nats.enabled are alternatives; turn on one",
+                )));
+            }
+            let wildcard = |c: char| c.is_whitespace() || c == '*' || c == '>';
+            let prefix_valid = nats
+                .subject_prefix
+                .split('.')
+                .all(|token| !token.is_empty() && !token.contains(wildcard));
+            if nats.servers.is_empty() || !prefix_valid {
+                return Err(ConfigError::Invalid(String::from(
+                    "nats.servers must be set, and nats.subject_prefix be dot-separated tokens \
+                     without wildcards or spaces",
+                )));
+            }
+            if nats.username.is_some() != nats.password.is_some() {
+                return Err(ConfigError::Invalid(String::from(
+                    "nats.username and nats.password must be set together",
+                )));
+            }
+            if nats.timeout_ms == 0 {
+                return Err(ConfigError::Invalid(String::from("nats.timeout_ms must be positive")));
+            }
+        }
+        let search_index = &self.search_index;
+        if search_index.enabled {
+            if !self.outbox.enabled {
+                return Err(ConfigError::Invalid(String::from(
+                    "search_index.enabled needs outbox.enabled: writes reach the index through \
+                     the outbox",
+                )));
+            }
+            if http_client::check_url(&search_index.url).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "search_index.url is not an http(s) URL: {}",
+                    search_index.url
+                )));
+            }
+            let index_valid = !search_index.index.is_empty()
+                && search_index
+                    .index
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_".contains(c));
+            if !index_valid {
+                return Err(ConfigError::Invalid(String::from(
+                    "search_index.index must be lower-case letters, digits, '-' and '_'",
+                )));
+            }
+            if search_index.timeout_ms == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "search_index.timeout_ms must be positive",
+                )));
+            }
+        }
+        let write_behind = &self.write_behind;
+        if write_behind.batch_size == 0 || write_behind.flush_interval_ms == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "write_behind.batch_size and write_behind.flush_interval_ms must be positive",
+            )));
+        }
+        if write_behind.queue_capacity < write_behind.batch_size {
+            return Err(ConfigError::Invalid(String::from(
+                "write_behind.queue_capacity must be at least write_behind.batch_size",
+            )));
+        }
+        let unknown = self.flags.disabled.iter().find(|name| Flag::from_name(name).is_none());
+        if let Some(name) = unknown {
+            return Err(ConfigError::Invalid(format!(
+                "unknown feature flag in flags.disabled: {}",
+                name
+            )));
+        }
+        if self.maintenance_mode.message.trim().is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "maintenance_mode.message must not be blank",
+            )));
+        }
+        if self.flags.table && self.flags.refresh_interval_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "flags.refresh_interval_secs must be positive",
+            )));
+        }
+        let webhooks = &self.webhooks;
+        if webhooks.enabled {
+            if webhooks.queue_capacity == 0
+                || webhooks.concurrency == 0
+                || webhooks.timeout_ms == 0
+                || webhooks.max_attempts == 0
+                || webhooks.refresh_interval_secs == 0
+                || webhooks.delivery_log_ttl_secs == 0
+            {
+                return Err(ConfigError::Invalid(String::from(
+                    "webhooks.queue_capacity, concurrency, timeout_ms, max_attempts, \
+                     refresh_interval_secs and delivery_log_ttl_secs must be positive",
+                )));
+            }
+            if webhooks.delivery_log_ttl_secs > validation::MAX_TTL_SECS {
+                return Err(ConfigError::Invalid(String::from(
+                    "webhooks.delivery_log_ttl_secs must be at most 630720000",
+                )));
+            }
+            if webhooks.initial_backoff_ms > webhooks.max_backoff_ms {
+                return Err(ConfigError::Invalid(String::from(
+                    "webhooks.initial_backoff_ms must not exceed webhooks.max_backoff_ms",
+                )));
+            }
+        }
+        let smtp = &self.smtp;
+        if smtp.enabled {
+            if smtp.host.is_empty() || smtp.port == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "smtp.host and smtp.port must be set",
+                )));
+            }
+            if smtp.timeout_ms == 0 || smtp.queue_capacity == 0 || smtp.concurrency == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "smtp.timeout_ms, smtp.queue_capacity and smtp.concurrency must be positive",
+                )));
+            }
+            if smtp.username.is_some() != smtp.password.is_some() {
+                return Err(ConfigError::Invalid(String::from(
+                    "smtp.username and smtp.password must be set together",
+                )));
+            }
+            if mailer::address(&smtp.from).is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "smtp.from is not an email address: {}",
+                    smtp.from
+                )));
+            }
+            if !smtp.verification_url.as_deref().is_some_and(|url| url.contains("{token}")) {
+                return Err(ConfigError::Invalid(String::from(
+                    "smtp.verification_url must be set, with {token} where the token goes",
+                )));
+            }
+        }
+        let shadow = &self.shadow;
+        if shadow.enabled {
+            let same_keyspace = shadow
+                .keyspace
+                .as_ref()
+                .is_none_or(|keyspace| *keyspace == self.scylla.keyspace);
+            if shadow.nodes.is_empty() && same_keyspace {
+                return Err(ConfigError::Invalid(String::from(
+                    "shadow.nodes or another shadow.keyspace must be set",
+                )));
+            }
+            if let Some(keyspace) = &shadow.keyspace
+                && !is_valid_keyspace(keyspace)
+            {
+                return Err(ConfigError::Invalid(format!("invalid keyspace name: {}", keyspace)));
+            }
+            if !(0.0..=1.0).contains(&shadow.sample_rate) {
+                return Err(ConfigError::Invalid(String::from(
+                    "shadow.sample_rate must be between 0 and 1",
+                )));
+            }
+            if shadow.max_in_flight == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "shadow.max_in_flight must be positive",
+                )));
+            }
+            if shadow.username.is_some() != shadow.password.is_some() {
+                return Err(ConfigError::Invalid(String::from(
+                    "shadow.username and shadow.password must be set together",
+                )));
+            }
+        }
+        let oauth = &self.oauth;
+        let providers = [
+            ("google", &oauth.google_client_id, &oauth.google_client_secret),
+            ("github", &oauth.github_client_id, &oauth.github_client_secret),
+        ];
+        for (provider, id, secret) in &providers {
+            if id.is_some() != secret.is_some() {
+                return Err(ConfigError::Invalid(format!(
+                    "oauth.{0}_client_id and oauth.{0}_client_secret must be set together",
+                    provider
+                )));
+            }
+        }
+        if providers.iter().any(|(_, id, _)| id.is_some()) {
+            let base = oauth.callback_base_url.as_deref().unwrap_or_default();
+            if !base.starts_with("https://") && !base.starts_with("http://") {
+                return Err(ConfigError::Invalid(String::from(
+                    "oauth.callback_base_url must be an http(s) URL when a provider is configured",
+                )));
+            }
+            if self.auth.jwt_secret.is_none() {
+                return Err(ConfigError::Invalid(String::from(
+                    "oauth providers need auth.jwt_secret to issue tokens",
+                )));
+            }
+            if oauth.request_timeout_ms == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "oauth.request_timeout_ms must be positive",
+                )));
+            }
+        }
+        if self.maintenance.purge_deleted_after_days == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "maintenance.purge_deleted_after_days must be positive",
+            )));
+        }
+        if self.http.max_page_size > i32::MAX as usize {
+            return Err(ConfigError::Invalid(String::from("http.max_page_size is too large")));
+        }
+        if self.auth.jwt_secret.as_deref().is_some_and(str::is_empty) {
+            return Err(ConfigError::Invalid(String::from("auth.jwt_secret must not be empty")));
+        }
+        if self.auth.token_ttl_secs == 0 {
+            return Err(ConfigError::Invalid(String::from("auth.token_ttl_secs must be positive")));
+        }
+        if self.auth.refresh_token_ttl_secs == 0
+            || self.auth.refresh_token_ttl_secs > u64::from(validation::MAX_TTL_SECS)
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "auth.refresh_token_ttl_secs must be between 1 and 630720000",
+            )));
+        }
+        if !self.rate_limit.requests_per_second.is_finite()
+            || self.rate_limit.requests_per_second <= 0.0
+            || self.rate_limit.burst == 0
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "rate_limit.requests_per_second and rate_limit.burst must be positive",
+            )));
+        }
+        if self.rate_limit.window_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "rate_limit.window_secs must be positive",
+            )));
+        }
+        let shedding = &self.load_shedding;
+        if shedding.max_in_flight == 0
+            || shedding.max_latency_ms == 0
+            || shedding.retry_after_secs == 0
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "load_shedding.max_in_flight, max_latency_ms and retry_after_secs must be positive",
+            )));
+        }
+        if !shedding.shed_normal_at.is_finite() || shedding.shed_normal_at < 1.0 {
+            return Err(ConfigError::Invalid(String::from(
+                "load_shedding.shed_normal_at must be at least 1",
+            )));
+        }
+        let header = shedding.priority_header.as_bytes();
+        if actix_web::http::header::HeaderName::from_bytes(header).is_err() {
+            return Err(ConfigError::Invalid(String::from(
+                "load_shedding.priority_header must be a header name",
+            )));
+        }
+        if self.cors.mode == CorsMode::Strict && self.cors.allowed_origins.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "cors.mode = \"strict\" needs at least one entry in cors.allowed_origins",
+            )));
+        }
+        if let Some(origin) = self.cors.allowed_origins.iter().find(|origin| {
+            *origin == "*" || origin.parse::<actix_web::http::Uri>().is_err()
+        }) {
+            return Err(ConfigError::Invalid(format!(
+                "invalid CORS origin (use mode = \"permissive\" to allow any): {}",
+                origin
+            )));
+        }
+        if let Some(method) = self
+            .cors
+            .allowed_methods
+            .iter()
+            .find(|method| actix_web::http::Method::from_bytes(method.as_bytes()).is_err())
+        {
+            return Err(ConfigError::Invalid(format!("invalid CORS method: {}", method)));
+        }
+        if self.grpc.bind_addr.is_some() && !cfg!(feature = "grpc") {
+            return Err(ConfigError::Invalid(String::from(
+                "grpc.bind_addr is set but the server was built without the grpc feature",
+            )));
+        }
+        if self.kafka.enabled && !cfg!(feature = "kafka") {
+            return Err(ConfigError::Invalid(String::from(
+                "kafka.enabled is set but the server was built without the kafka feature",
+            )));
+        }
+        if self.log.otlp_endpoint.is_some() && !cfg!(feature = "otel") {
+            return Err(ConfigError::Invalid(String::from(
+                "log.otlp_endpoint is set but the server was built without the otel feature",
+            )));
+        }
+        if self.scylla.schema_agreement_timeout_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.schema_agreement_timeout_secs must be positive",
+            )));
+        }
+        if self.scylla.request_timeout_ms == 0 || self.http.handler_timeout_ms == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.request_timeout_ms and http.handler_timeout_ms must be positive",
+            )));
+        }
+        if self.http.panic_threshold == 0 || self.http.panic_window_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "http.panic_threshold and http.panic_window_secs must be positive",
+            )));
+        }
+        if self.scylla.schema_wait_initial_backoff_ms > self.scylla.schema_wait_max_backoff_ms {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.schema_wait_initial_backoff_ms exceeds schema_wait_max_backoff_ms",
+            )));
+        }
+        if self.scylla.retry_max_attempts == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.retry_max_attempts must be at least 1",
+            )));
+        }
+        if self.scylla.speculative_execution != SpeculativeExecution::Off {
+            if self.scylla.speculative_max_executions == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "scylla.speculative_max_executions must be at least 1",
+                )));
+            }
+            let percentile = self.scylla.speculative_percentile;
+            if !(percentile > 0.0 && percentile < 100.0) {
+                return Err(ConfigError::Invalid(String::from(
+                    "scylla.speculative_percentile must be above 0 and below 100",
+                )));
+            }
+        }
+        if self.scylla.pool_size == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.pool_size must be at least 1",
+            )));
+        }
+        if self.scylla.retry_initial_backoff_ms > self.scylla.retry_max_backoff_ms {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.retry_initial_backoff_ms exceeds retry_max_backoff_ms",
+            )));
+        }
+        if self.scylla.breaker_open_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scylla.breaker_open_secs must be positive",
+            )));
+        }
+        Ok(())
+    }
+}
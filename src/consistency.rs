@@ -0,0 +1,197 @@
+use crate::error::ApiError;
+use crate::state::AppState;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderName;
+use actix_web::middleware::Next;
+use actix_web::web;
+use scylla::prepared_statement::PreparedStatement;
+use scylla::statement::{Consistency, SerialConsistency};
+
+// Clients pick the consistency of a request's statements with `X-Consistency`
+// (one, local_one, quorum, local_quorum or all), and that of the Paxos round
+// of its conditional writes with `X-Serial-Consistency` (serial or
+// local_serial), so a latency-sensitive read can run at ONE while a write that
+// must be seen stays at QUORUM. The choice is held in a task-local for the
+// rest of the request and set on the user statements as they are cloned for
+// it (`users::for_request`); other statements keep the session defaults.
+// Overrides are only honoured when the deployment sets
+// `scylla.allow_consistency_override`, and refused with 400 otherwise, so a
+// client never silently gets weaker guarantees than it asked for.
+
+pub const CONSISTENCY_HEADER: HeaderName = HeaderName::from_static("x-consistency");
+pub const SERIAL_CONSISTENCY_HEADER: HeaderName = HeaderName::from_static("x-serial-consistency");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Requested {
+    consistency: Option<Consistency>,
+    serial: Option<SerialConsistency>,
+}
+
+tokio::task_local! {
+    static REQUESTED: Requested;
+}
+
+fn parse_consistency(value: &str) -> Option<Consistency> {
+    match value.to_ascii_lowercase().as_str() {
+        "one" => Some(Consistency::One),
+        "local_one" => Some(Consistency::LocalOne),
+        "quorum" => Some(Consistency::Quorum),
+        "local_quorum" => Some(Consistency::LocalQuorum),
+        "all" => Some(Consistency::All),
+        _ => None,
+    }
+}
+
+fn parse_serial(value: &str) -> Option<SerialConsistency> {
+    match value.to_ascii_lowercase().as_str() {
+        "serial" => Some(SerialConsistency::Serial),
+        "local_serial" => Some(SerialConsistency::LocalSerial),
+        _ => None,
+    }
+}
+
+// The level `req` asks for in header `name`, one of `levels`.
+fn level<T>(
+    req: &ServiceRequest,
+    name: &HeaderName,
+    allowed: bool,
+    parse: fn(&str) -> Option<T>,
+    levels: &str,
+) -> Result<Option<T>, ApiError> {
+    let Some(value) = req.headers().get(name) else {
+        return Ok(None);
+    };
+    if !allowed {
+        return Err(ApiError::BadRequest(format!("{} is not enabled on this server", name)));
+    }
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| parse(value.trim()))
+        .map(Some)
+        .ok_or_else(|| ApiError::BadRequest(format!("{} must be one of {}", name, levels)))
+}
+
+// The levels `req` asks for, if `allowed` to ask for any.
+fn requested(req: &ServiceRequest, allowed: bool) -> Result<Requested, ApiError> {
+    Ok(Requested {
+        consistency: level(
+            req,
+            &CONSISTENCY_HEADER,
+            allowed,
+            parse_consistency,
+            "one, local_one, quorum, local_quorum, all",
+        )?,
+        serial: level(
+            req,
+            &SERIAL_CONSISTENCY_HEADER,
+            allowed,
+            parse_serial,
+            "serial, local_serial",
+        )?,
+    })
+}
+
+// Runs the rest of the request with the levels it asked for.
+pub async fn scope(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let allowed = req
+        .app_data::<web::Data<AppState>>()
+        .is_some_and(|state| state.allow_consistency_override);
+    match requested(&req, allowed) {
+        Ok(levels) => REQUESTED
+            .scope(levels, next.call(req))
+            .await
+            .map(ServiceResponse::map_into_boxed_body),
+        Err(e) => Ok(req.error_response(e).map_into_boxed_body()),
+    }
+}
+
+// The levels of the request being served; none outside of one.
+fn current() -> Requested {
+    REQUESTED.try_with(|levels| *levels).unwrap_or_default()
+}
+
+// Sets the current request's levels on `statement`, a clone made for it.
+pub fn apply(statement: &mut PreparedStatement) {
+    let levels = current();
+    if let Some(consistency) = levels.consistency {
+        statement.set_consistency(consistency);
+    }
+    if let Some(serial) = levels.serial {
+        statement.set_serial_consistency(Some(serial));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    #[test]
+    fn levels_parse_case_insensitively() {
+        assert_eq!(parse_consistency("LOCAL_QUORUM"), Some(Consistency::LocalQuorum));
+        assert_eq!(parse_consistency("one"), Some(Consistency::One));
+        assert_eq!(parse_consistency("each_quorum"), None);
+        assert_eq!(parse_serial("Local_Serial"), Some(SerialConsistency::LocalSerial));
+        assert_eq!(parse_serial("quorum"), None);
+    }
+
+    #[test]
+    fn requests_name_their_levels() {
+        let req = TestRequest::default()
+            .insert_header((CONSISTENCY_HEADER, "quorum"))
+            .insert_header((SERIAL_CONSISTENCY_HEADER, " local_serial "))
+            .to_srv_request();
+        assert_eq!(
+            requested(&req, true).unwrap(),
+            Requested {
+                consistency: Some(Consistency::Quorum),
+                serial: Some(SerialConsistency::LocalSerial),
+            }
+        );
+        assert_eq!(requested(&TestRequest::default().to_srv_request(), false).unwrap(), Requested::default());
+
+        let unknown = TestRequest::default()
+            .insert_header((CONSISTENCY_HEADER, "three"))
+            .to_srv_request();
+        assert!(requested(&unknown, true).is_err());
+        assert!(requested(&req, false).is_err());
+    }
+
+    #[actix_web::test]
+    async fn handlers_see_the_requested_levels() {
+        assert_eq!(current(), Requested::default());
+        let levels = Requested {
+            consistency: Some(Consistency::One),
+            serial: None,
+        };
+        let seen = REQUESTED.scope(levels, async { current() }).await;
+        assert_eq!(seen, levels);
+    }
+
+    #[actix_web::test]
+    async fn overrides_are_refused_unless_enabled() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(scope))
+                .route("/", actix_web::web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let plain = test::call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(plain.status(), StatusCode::OK);
+
+        let overridden = TestRequest::get()
+            .uri("/")
+            .insert_header((CONSISTENCY_HEADER, "one"))
+            .to_request();
+        let refused = test::call_service(&app, overridden).await;
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+    }
+}
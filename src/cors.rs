@@ -0,0 +1,50 @@
+use crate::config::{CorsConfig, CorsMode};
+use crate::count::TOTAL_COUNT_HEADER;
+use crate::dry_run::DRY_RUN_HEADER;
+use crate::idempotency::REPLAYED_HEADER;
+use crate::rate_limit::{LIMIT_HEADER, REMAINING_HEADER, RESET_HEADER};
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::validation::WARNINGS_HEADER;
+use actix_cors::Cors;
+use actix_web::http::header::{HeaderName, ETAG};
+use actix_web::http::Method;
+
+// Builds the CORS middleware for one worker. Config validation has already
+// checked the origins and methods, so nothing here can fail.
+pub fn middleware(config: &CorsConfig) -> Cors {
+    match config.mode {
+        CorsMode::Off => Cors::default(),
+        CorsMode::Permissive => Cors::permissive(),
+        CorsMode::Strict => {
+            let mut cors = Cors::default()
+                .allowed_methods(
+                    config
+                        .allowed_methods
+                        .iter()
+                        .filter_map(|method| Method::from_bytes(method.as_bytes()).ok()),
+                )
+                .allowed_headers(
+                    config
+                        .allowed_headers
+                        .iter()
+                        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok()),
+                )
+                .expose_headers([
+                    REQUEST_ID_HEADER,
+                    ETAG,
+                    DRY_RUN_HEADER,
+                    REPLAYED_HEADER,
+                    WARNINGS_HEADER,
+                    TOTAL_COUNT_HEADER,
+                    LIMIT_HEADER,
+                    REMAINING_HEADER,
+                    RESET_HEADER,
+                ])
+                .max_age(config.max_age_secs);
+            for origin in &config.allowed_origins {
+                cors = cors.allowed_origin(origin);
+            }
+            cors
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::error::{ApiError, Problem};
+use crate::models::UserCount;
+use crate::observe;
+use crate::state::AppState;
+use actix_web::http::header::HeaderName;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// The number of live users, for GET /users/count and `X-Total-Count`. Scylla
+// keeps no row count, so the users are counted: the token ring is split into
+// `RANGES` ranges, scanned `CONCURRENCY` at a time, reading only `deleted_at`
+// so that soft-deleted users are left out. A count is reused for
+// `http.count_cache_secs`; requests arriving while it is stale wait for one
+// recount instead of each scanning the table. The `recount_users`
+// maintenance job recounts ahead of them.
+
+pub const TOTAL_COUNT_HEADER: HeaderName = HeaderName::from_static("x-total-count");
+
+pub const RANGES: i64 = 64;
+const CONCURRENCY: usize = 8;
+
+pub struct Counter {
+    ttl: Duration,
+    last: Mutex<Option<(Instant, UserCount)>>,
+}
+
+// `count` (start, end] token ranges covering the whole ring. The Murmur3
+// partitioner never assigns `i64::MIN`, so the first range can exclude it.
+pub fn token_ranges(count: i64) -> Vec<(i64, i64)> {
+    let (min, max) = (i128::from(i64::MIN), i128::from(i64::MAX));
+    let width = (max - min) / i128::from(count);
+    (0..count)
+        .map(|range| {
+            let start = min + width * i128::from(range);
+            let end = if range == count - 1 { max } else { start + width };
+            (start as i64, end as i64)
+        })
+        .collect()
+}
+
+async fn count_range(state: &AppState, (start, end): (i64, i64)) -> Result<u64, ApiError> {
+    let pager = observe::query(state, "select_deleted_in_token_range", || {
+        state
+            .session
+            .execute_iter(state.statements.select_deleted_in_token_range.clone(), (start, end))
+    })
+    .await?;
+    pager
+        .rows_stream::<(Option<DateTime<Utc>>,)>()
+        .map_err(|e| ApiError::internal("Error counting users", e))?
+        .map_err(|e| ApiError::internal("Error counting users", e))
+        .try_fold(0, |live, (deleted_at,)| future::ok(live + u64::from(deleted_at.is_none())))
+        .await
+}
+
+async fn count_users(state: &AppState) -> Result<u64, ApiError> {
+    stream::iter(token_ranges(RANGES))
+        .map(|range| count_range(state, range))
+        .buffer_unordered(CONCURRENCY)
+        .try_fold(0, |total, live| future::ok(total + live))
+        .await
+}
+
+async fn recount(state: &AppState, last: &mut Option<(Instant, UserCount)>) -> Result<UserCount, ApiError> {
+    let count = UserCount {
+        count: count_users(state).await?,
+        counted_at: Utc::now(),
+    };
+    tracing::debug!(count = count.count, "counted users");
+    *last = Some((Instant::now(), count.clone()));
+    Ok(count)
+}
+
+impl Counter {
+    pub fn new(ttl: Duration) -> Self {
+        Counter {
+            ttl,
+            last: Mutex::new(None),
+        }
+    }
+
+    // The latest count, recounting once it is older than the TTL. A failed
+    // recount leaves the previous count to be tried again by the next call.
+    pub async fn get(&self, state: &AppState) -> Result<UserCount, ApiError> {
+        let mut last = self.last.lock().await;
+        if let Some((counted, count)) = &*last
+            && counted.elapsed() < self.ttl
+        {
+            return Ok(count.clone());
+        }
+        recount(state, &mut last).await
+    }
+
+    // Counts the users now, whatever the age of the last count.
+    pub async fn refresh(&self, state: &AppState) -> Result<UserCount, ApiError> {
+        recount(state, &mut *self.last.lock().await).await
+    }
+}
+
+/// Counted by scanning the table, so it can be up to
+/// `http.count_cache_secs` old; `counted_at` says when it was taken.
+#[utoipa::path(
+    get,
+    path = "/users/count",
+    responses(
+        (status = 200, description = "The number of live users", body = UserCount),
+        (status = 503, description = "The database is unavailable", body = Problem),
+    )
+)]
+pub async fn get_user_count(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(data.user_count.get(&data).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_ranges_cover_the_ring_without_gaps() {
+        let ranges = token_ranges(RANGES);
+        assert_eq!(ranges.len(), RANGES as usize);
+        assert_eq!(ranges[0].0, i64::MIN);
+        assert_eq!(ranges[ranges.len() - 1].1, i64::MAX);
+        for pair in ranges.windows(2) {
+            assert!(pair[0].0 < pair[0].1);
+            assert_eq!(pair[0].1, pair[1].0);
+        }
+        assert_eq!(token_ranges(1), [(i64::MIN, i64::MAX)]);
+    }
+}
@@ -0,0 +1,250 @@
+use scylla::frame::response::result::CqlValue;
+
+// Builds the CQL statements whose text depends on the request: which columns
+// an update sets, which filters a listing applies. Column names are
+// `&'static str`, so only names written in the code can reach the statement
+// text, and every value is bound with a `?`; a request can change which of
+// the known fragments a statement is made of, never its text beyond them.
+// Values are `Option`s so a condition can compare with null. The text is
+// prepared and cached by `Statements::get_or_prepare`, which relies on there
+// being a bounded number of distinct texts.
+
+// Comparison of a column with a bound value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Lt,
+    Gt,
+    // A set or list column holding the value.
+    Contains,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Lt => "<",
+            Op::Gt => ">",
+            Op::Contains => "CONTAINS",
+        }
+    }
+}
+
+// Condition of a lightweight transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // No condition: a plain write, as in a batch.
+    None,
+    Exists,
+    // Each column equal to its value.
+    Equal(Vec<(&'static str, Option<CqlValue>)>),
+}
+
+// A column name or a field of a UDT column (`profile.bio`).
+fn checked(column: &'static str) -> &'static str {
+    assert!(
+        !column.is_empty()
+            && column
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.'),
+        "invalid CQL column name {:?}",
+        column
+    );
+    column
+}
+
+fn relations(columns: &[(&'static str, Op)]) -> String {
+    columns
+        .iter()
+        .map(|(column, op)| format!("{} {} ?", column, op.as_str()))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+// `UPDATE <keyspace>.<table> [USING TTL ?] SET ... WHERE ... [IF ...]`.
+#[derive(Debug)]
+pub struct Update {
+    table: String,
+    ttl: Option<i32>,
+    set: Vec<(&'static str, Option<CqlValue>)>,
+    key: Vec<(&'static str, Option<CqlValue>)>,
+    condition: Condition,
+}
+
+impl Update {
+    pub fn new(keyspace: &str, table: &'static str) -> Self {
+        Update {
+            table: format!("{}.{}", keyspace, checked(table)),
+            ttl: None,
+            set: Vec::new(),
+            key: Vec::new(),
+            condition: Condition::None,
+        }
+    }
+
+    // `USING TTL`, where 0 means the cells don't expire.
+    pub fn ttl(&mut self, seconds: i32) -> &mut Self {
+        self.ttl = Some(seconds);
+        self
+    }
+
+    pub fn set(&mut self, column: &'static str, value: CqlValue) -> &mut Self {
+        self.set.push((checked(column), Some(value)));
+        self
+    }
+
+    // Restricts the update to the row whose key `column` is `value`.
+    pub fn key(&mut self, column: &'static str, value: CqlValue) -> &mut Self {
+        self.key.push((checked(column), Some(value)));
+        self
+    }
+
+    pub fn condition(&mut self, condition: Condition) -> &mut Self {
+        if let Condition::Equal(columns) = &condition {
+            for (column, _) in columns {
+                checked(column);
+            }
+        }
+        self.condition = condition;
+        self
+    }
+
+    // The statement's text and its values, in the order they bind.
+    pub fn build(self) -> (String, Vec<Option<CqlValue>>) {
+        assert!(!self.set.is_empty() && !self.key.is_empty(), "an update needs SET and WHERE");
+        let mut query = format!("UPDATE {}", self.table);
+        let mut values = Vec::new();
+        if let Some(ttl) = self.ttl {
+            query.push_str(" USING TTL ?");
+            values.push(Some(CqlValue::Int(ttl)));
+        }
+        let assignments: Vec<String> =
+            self.set.iter().map(|(column, _)| format!("{} = ?", column)).collect();
+        query.push_str(&format!(" SET {}", assignments.join(", ")));
+        values.extend(self.set.into_iter().map(|(_, value)| value));
+
+        let key: Vec<_> = self.key.iter().map(|(column, _)| (*column, Op::Eq)).collect();
+        query.push_str(&format!(" WHERE {}", relations(&key)));
+        values.extend(self.key.into_iter().map(|(_, value)| value));
+
+        match self.condition {
+            Condition::None => {}
+            Condition::Exists => query.push_str(" IF EXISTS"),
+            Condition::Equal(columns) => {
+                let compared: Vec<_> =
+                    columns.iter().map(|(column, _)| (*column, Op::Eq)).collect();
+                query.push_str(&format!(" IF {}", relations(&compared)));
+                values.extend(columns.into_iter().map(|(_, value)| value));
+            }
+        }
+        (query, values)
+    }
+}
+
+// `SELECT <columns> FROM <keyspace>.<table> [WHERE ...] [ALLOW FILTERING]`.
+#[derive(Debug)]
+pub struct Select {
+    columns: String,
+    table: String,
+    filters: Vec<(&'static str, Op, Option<CqlValue>)>,
+    allow_filtering: bool,
+}
+
+impl Select {
+    // `columns` is the selection as written, such as `statements::USER_COLUMNS`.
+    pub fn new(columns: &'static str, keyspace: &str, table: &'static str) -> Self {
+        Select {
+            columns: columns.to_string(),
+            table: format!("{}.{}", keyspace, checked(table)),
+            filters: Vec::new(),
+            allow_filtering: false,
+        }
+    }
+
+    // Selects `columns` alone instead.
+    pub fn columns(&mut self, columns: &[&'static str]) -> &mut Self {
+        let columns: Vec<&str> = columns.iter().map(|column| checked(column)).collect();
+        self.columns = columns.join(", ");
+        self
+    }
+
+    pub fn filter(&mut self, column: &'static str, op: Op, value: CqlValue) -> &mut Self {
+        self.filters.push((checked(column), op, Some(value)));
+        self
+    }
+
+    pub fn allow_filtering(&mut self) -> &mut Self {
+        self.allow_filtering = true;
+        self
+    }
+
+    pub fn is_filtered(&self) -> bool {
+        !self.filters.is_empty()
+    }
+
+    // The statement's text and its values, in the order they bind.
+    pub fn build(self) -> (String, Vec<Option<CqlValue>>) {
+        let mut query = format!("SELECT {} FROM {}", self.columns, self.table);
+        if !self.filters.is_empty() {
+            let filters: Vec<_> =
+                self.filters.iter().map(|(column, op, _)| (*column, *op)).collect();
+            query.push_str(&format!(" WHERE {}", relations(&filters)));
+        }
+        if self.allow_filtering {
+            query.push_str(" ALLOW FILTERING");
+        }
+        (query, self.filters.into_iter().map(|(_, _, value)| value).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_bind_every_value_in_order() {
+        let mut update = Update::new("app", "users");
+        update
+            .ttl(0)
+            .set("name", CqlValue::Text(String::from("Robert'); DROP TABLE users;--")))
+            .set("profile.locale", CqlValue::Text(String::from("en-GB")))
+            .key("id", CqlValue::Int(7))
+            .condition(Condition::Equal(vec![("email", None)]));
+        let (query, values) = update.build();
+        assert_eq!(
+            query,
+            "UPDATE app.users USING TTL ? SET name = ?, profile.locale = ? \
+             WHERE id = ? IF email = ?"
+        );
+        assert_eq!(values.len(), 5);
+        assert_eq!(values[0], Some(CqlValue::Int(0)));
+        assert_eq!(values[3], Some(CqlValue::Int(7)));
+        assert_eq!(values[4], None);
+    }
+
+    #[test]
+    fn selects_join_their_filters() {
+        let mut select = Select::new("id", "app", "users");
+        assert!(!select.is_filtered());
+        select
+            .filter("email", Op::Eq, CqlValue::Text(String::from("ada@example.com")))
+            .filter("created_at", Op::Gt, CqlValue::Int(1))
+            .filter("tags", Op::Contains, CqlValue::Text(String::from("beta")))
+            .allow_filtering();
+        let (query, values) = select.build();
+        assert_eq!(
+            query,
+            "SELECT id FROM app.users WHERE email = ? AND created_at > ? AND tags CONTAINS ? \
+             ALLOW FILTERING"
+        );
+        assert_eq!(values.len(), 3);
+        assert_eq!(Select::new("id", "app", "users").build().0, "SELECT id FROM app.users");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid CQL column name")]
+    fn column_names_must_be_identifiers() {
+        let mut update = Update::new("app", "users");
+        update.set("name = 'x', email", CqlValue::Int(1));
+    }
+}
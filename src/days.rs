@@ -0,0 +1,380 @@
+use crate::error::ApiError;
+use crate::models::{ListUsersQuery, User, UsersPage};
+use crate::observe;
+use crate::paging::{self, CursorKind, CursorScope};
+use crate::state::AppState;
+use crate::users::{self, Listing};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use futures::future::try_join_all;
+use scylla::frame::response::result::CqlValue;
+use std::future::Future;
+use uuid::Uuid;
+
+// Maintenance of `users_by_day`, which lists users by when they were created:
+// one partition per UTC day, clustered by `created_at` and id. GET /users
+// with `created_after`, and no filter but `created_before`, walks the days of
+// the range in order instead of scanning `users` with ALLOW FILTERING, and
+// reads the users it finds. Like `users_by_name`, it is derived data: a
+// failed write is logged, not failed, and users stored before the table
+// existed are missing from it until `backfill` indexes them.
+//
+// A soft-deleted user keeps their row, so a restore needs nothing, and is
+// left out of listings when read; a hard delete removes it.
+
+// Most days one page walks. A sparse range ends the page early, with a cursor
+// for the next day, rather than holding the request for months of empty
+// partitions.
+const MAX_DAYS_PER_PAGE: usize = 31;
+
+// A row of `users_by_day`: when the user was created, and its id.
+type Row = (DateTime<Utc>, Uuid);
+
+// The partition of a user created at `at`.
+fn day(at: DateTime<Utc>) -> NaiveDate {
+    at.date_naive()
+}
+
+fn start_of(day: NaiveDate) -> DateTime<Utc> {
+    day.and_time(Default::default()).and_utc()
+}
+
+// Bind values for `index_user_day`, or `None` for a user without a
+// creation time.
+pub fn index_values(user: &User) -> Option<Vec<Option<CqlValue>>> {
+    let created_at = user.created_at?;
+    Some(vec![
+        Some(CqlValue::Date(day(created_at).into())),
+        Some(CqlValue::Timestamp(created_at.into())),
+        Some(CqlValue::Uuid(user.id)),
+        Some(CqlValue::Int(users::ttl(user.expires_at))),
+    ])
+}
+
+pub async fn index(state: &AppState, user: &User) {
+    if let Err(e) = try_index(state, user).await {
+        tracing::warn!(user_id = %user.id, error = %e, "failed to index user creation day");
+    }
+}
+
+// Like `index`, but reports a failure, for the backfill to count.
+pub async fn try_index(state: &AppState, user: &User) -> Result<(), ApiError> {
+    let Some(values) = index_values(user) else {
+        return Ok(());
+    };
+    observe::query(state, "index_user_day", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.index_user_day, &values)
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn unindex(state: &AppState, user: &User) {
+    let Some(created_at) = user.created_at else {
+        return;
+    };
+    let values = (day(created_at), created_at, user.id);
+    if let Err(e) = observe::query(state, "unindex_user_day", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.unindex_user_day, values)
+    })
+    .await
+    {
+        tracing::warn!(user_id = %user.id, error = %e, "failed to unindex user creation day");
+    }
+}
+
+// Whether the listing `params` asks for is a creation range this table
+// serves: `created_after` bounds the days to walk, and any other filter
+// would have to be checked user by user.
+pub fn serves(params: &ListUsersQuery) -> bool {
+    params.created_after.is_some()
+        && params.name.is_none()
+        && params.email.is_none()
+        && params.updated_after.is_none()
+        && params.updated_before.is_none()
+        && params.verified.is_none()
+        && params.tag.is_none()
+}
+
+// Where a page starts: after a time, or after the row of a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    After(DateTime<Utc>),
+    Row(DateTime<Utc>, Uuid),
+}
+
+impl Position {
+    // The first day that may hold a row past this point.
+    fn day(self) -> NaiveDate {
+        match self {
+            Position::After(at) => day(at + TimeDelta::milliseconds(1)),
+            Position::Row(at, _) => day(at),
+        }
+    }
+
+    fn encode(self, scope: &CursorScope) -> String {
+        let (Position::After(at) | Position::Row(at, _)) = self;
+        let mut key = at.timestamp_millis().to_be_bytes().to_vec();
+        if let Position::Row(_, id) = self {
+            key.extend_from_slice(id.as_bytes());
+        }
+        paging::encode_key(scope, &key)
+    }
+
+    fn decode(key: &[u8]) -> Option<Position> {
+        let (millis, id) = key.split_first_chunk::<8>()?;
+        let at = DateTime::from_timestamp_millis(i64::from_be_bytes(*millis))?;
+        match id {
+            [] => Some(Position::After(at)),
+            id => Some(Position::Row(at, Uuid::from_slice(id).ok()?)),
+        }
+    }
+}
+
+// Up to `limit` rows created from `from` until `end`, in order, and where
+// the next page starts, if anywhere. Reads each day with `read_day`, asking
+// for one row more than the page still needs to tell whether another page
+// follows.
+async fn walk<F, Fut>(
+    mut from: Position,
+    end: DateTime<Utc>,
+    limit: usize,
+    mut read_day: F,
+) -> Result<(Vec<Row>, Option<Position>), ApiError>
+where
+    F: FnMut(NaiveDate, Position, usize) -> Fut,
+    Fut: Future<Output = Result<Vec<Row>, ApiError>>,
+{
+    let last_day = day(end);
+    let mut rows = Vec::new();
+    for _ in 0..MAX_DAYS_PER_PAGE {
+        let day = from.day();
+        if day > last_day {
+            return Ok((rows, None));
+        }
+        rows.extend(read_day(day, from, limit + 1 - rows.len()).await?);
+        if rows.len() > limit {
+            rows.truncate(limit);
+            let (at, id) = rows[limit - 1];
+            return Ok((rows, Some(Position::Row(at, id))));
+        }
+        let Some(next) = day.succ_opt() else {
+            return Ok((rows, None));
+        };
+        from = Position::After(start_of(next) - TimeDelta::milliseconds(1));
+    }
+    let next = (from.day() <= last_day).then_some(from);
+    Ok((rows, next))
+}
+
+// Up to `limit` rows of `day` past `from` and created before `end`.
+async fn read_day(
+    data: &AppState,
+    day: NaiveDate,
+    from: Position,
+    end: DateTime<Utc>,
+    limit: usize,
+) -> Result<Vec<Row>, ApiError> {
+    let limit = limit as i32;
+    let result = match from {
+        Position::After(at) => {
+            observe::query(data, "select_users_by_day", || {
+                let statement = &data.statements.select_users_by_day;
+                data.session.execute_unpaged(statement, (day, at, end, limit))
+            })
+            .await?
+        }
+        Position::Row(at, id) => {
+            observe::query(data, "select_users_by_day_after", || {
+                let statement = &data.statements.select_users_by_day_after;
+                data.session.execute_unpaged(statement, (day, at, id, end, limit))
+            })
+            .await?
+        }
+    };
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read users by day", e))?
+        .rows::<Row>()
+        .map_err(|e| ApiError::internal("Failed to read users by day", e))?
+        .collect::<Result<_, _>>()
+        .map_err(|e| ApiError::internal("Failed to read users by day", e))
+}
+
+// One page of live users created in the range `params` asks for, oldest
+// first within the page unless sorted. Without `created_before`, the range
+// runs up to now.
+pub async fn list(
+    data: &AppState,
+    params: &ListUsersQuery,
+    limit: usize,
+    truncated: bool,
+) -> Result<Listing, ApiError> {
+    let Some(after) = params.created_after else {
+        return Err(ApiError::BadRequest(String::from("created_after is required")));
+    };
+    if params.order.is_some() && params.sort.is_none() {
+        return Err(ApiError::BadRequest(String::from("order requires sort")));
+    }
+    let fields = params.fields.as_deref().map(users::parse_fields).transpose()?;
+    let order = params.sort.map(|_| params.order.unwrap_or_default());
+    let scope = CursorScope::new(
+        CursorKind::CreatedRange,
+        &(after, params.created_before, params.status, params.sort, order),
+    );
+    let from = match paging::decode_key(params.cursor.as_deref(), &scope, data.cursor_max_age)? {
+        Some(key) => Position::decode(&key)
+            .ok_or_else(|| ApiError::BadRequest(String::from("Invalid cursor")))?,
+        None => Position::After(after),
+    };
+    let end = params.created_before.unwrap_or_else(Utc::now);
+
+    let (rows, next) = walk(from, end, limit, |day, from, limit| {
+        read_day(data, day, from, end, limit)
+    })
+    .await?;
+    let stored = try_join_all(rows.iter().map(|(_, id)| users::stored_user(data, *id))).await?;
+    let status = params.status.unwrap_or_default();
+    let mut users: Vec<User> = stored
+        .into_iter()
+        .flatten()
+        .filter(|user| users::admitted(status, user))
+        .collect();
+    if let Some(field) = params.sort {
+        users::sort_users(&mut users, field, order.unwrap_or_default());
+    }
+    tracing::debug!(count = users.len(), "listed users by creation day");
+
+    Ok(Listing {
+        page: UsersPage {
+            users,
+            next_cursor: next.map(|next| next.encode(&scope)),
+        },
+        truncated,
+        tracing_ids: Vec::new(),
+        fields,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    // The rows of `stored` in `day`, past `from` and before `end`, in order.
+    fn select(
+        stored: &[Row],
+        day: NaiveDate,
+        from: Position,
+        end: DateTime<Utc>,
+        limit: usize,
+    ) -> Vec<Row> {
+        let mut rows: Vec<_> = stored
+            .iter()
+            .copied()
+            .filter(|(at, _)| super::day(*at) == day && *at < end)
+            .filter(|row| match from {
+                Position::After(after) => row.0 > after,
+                Position::Row(at, id) => *row > (at, id),
+            })
+            .collect();
+        rows.sort();
+        rows.truncate(limit);
+        rows
+    }
+
+    // Every page of the range, following the cursors, and the days read.
+    fn walk_all(
+        stored: &[Row],
+        after: DateTime<Utc>,
+        end: DateTime<Utc>,
+        limit: usize,
+    ) -> (Vec<Vec<Row>>, usize) {
+        let scope = CursorScope::new(CursorKind::CreatedRange, &(after, end));
+        let (mut pages, mut reads, mut cursor) = (Vec::new(), 0, None::<String>);
+        loop {
+            let key = paging::decode_key(cursor.as_deref(), &scope, Duration::from_secs(60));
+            let from = key
+                .unwrap()
+                .map_or(Position::After(after), |key| Position::decode(&key).unwrap());
+            let (rows, next) = block_on(walk(from, end, limit, |day, from, limit| {
+                reads += 1;
+                futures::future::ready(Ok(select(stored, day, from, end, limit)))
+            }))
+            .unwrap();
+            pages.push(rows);
+            match next {
+                Some(next) => cursor = Some(next.encode(&scope)),
+                None => return (pages, reads),
+            }
+        }
+    }
+
+    #[test]
+    fn pages_cover_the_range_in_order_across_days() {
+        let mut stored: Vec<_> = [
+            "2026-03-01T08:00:00Z",
+            "2026-03-01T09:00:00Z",
+            "2026-03-01T09:00:00Z",
+            "2026-03-02T00:00:00Z",
+            "2026-03-04T23:59:59.999Z",
+            "2026-03-05T12:00:00Z",
+        ]
+        .iter()
+        .map(|text| (at(text), Uuid::new_v4()))
+        .collect();
+        stored.sort();
+
+        let (pages, _) =
+            walk_all(&stored, at("2026-03-01T08:00:00Z"), at("2026-03-05T12:00:00Z"), 2);
+        assert_eq!(pages, [&stored[1..3], &stored[3..5]]);
+
+        let (pages, _) =
+            walk_all(&stored, at("2026-02-28T00:00:00Z"), at("2026-03-06T00:00:00Z"), 4);
+        assert_eq!(pages.concat(), stored);
+    }
+
+    #[test]
+    fn sparse_ranges_end_pages_at_the_day_limit() {
+        let stored = vec![(at("2026-06-15T10:00:00Z"), Uuid::new_v4())];
+        let (pages, reads) =
+            walk_all(&stored, at("2026-01-01T00:00:00Z"), at("2026-12-31T00:00:00Z"), 10);
+        assert!(pages.len() > 1);
+        assert_eq!(pages.concat(), stored);
+        assert_eq!(reads, 365);
+    }
+
+    #[test]
+    fn cursors_hold_a_time_and_maybe_a_user() {
+        let scope = CursorScope::new(CursorKind::CreatedRange, &());
+        let row = Position::Row(at("2026-03-01T09:00:00.123Z"), Uuid::new_v4());
+        let after = Position::After(at("2026-03-01T23:59:59.999Z"));
+        for from in [row, after] {
+            let key = paging::decode_key(Some(&from.encode(&scope)), &scope, Duration::MAX);
+            assert_eq!(Position::decode(&key.unwrap().unwrap()), Some(from));
+        }
+        assert_eq!(after.day(), NaiveDate::from_ymd_opt(2026, 3, 2).unwrap());
+        assert_eq!(Position::decode(&[0; 12]), None);
+    }
+
+    #[test]
+    fn only_creation_ranges_are_served() {
+        let mut params = ListUsersQuery {
+            created_before: Some(Utc::now()),
+            ..ListUsersQuery::default()
+        };
+        assert!(!serves(&params));
+        params.created_after = Some(at("2026-01-01T00:00:00Z"));
+        assert!(serves(&params));
+        params.tag = Some(String::from("beta"));
+        assert!(!serves(&params));
+    }
+}
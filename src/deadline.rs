@@ -0,0 +1,81 @@
+use crate::error::ApiError;
+use crate::state::AppState;
+use crate::v1;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::rt::time::timeout;
+use actix_web::web;
+use std::time::Duration;
+
+// Caps how long a handler may take to produce its response at
+// `http.handler_timeout_ms`, so a slow cluster turns into a 504 instead of a
+// connection that hangs until the client gives up. The handler is dropped at
+// the deadline, abandoning its in-flight statements; a write may still have
+// been applied, as with any timeout. Only the time to the response head
+// counts: streamed bodies run as long as they need.
+
+// Routes whose handler reads an upload of any size before it responds.
+const EXEMPT_PATHS: &[&str] = &["/users/import"];
+
+fn timed_out(limit: Duration) -> ApiError {
+    ApiError::Timeout(format!("handler did not respond within {}ms", limit.as_millis()))
+}
+
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let limit = req
+        .app_data::<web::Data<AppState>>()
+        .map(|state| state.handler_timeout)
+        .filter(|_| !EXEMPT_PATHS.contains(&v1::unversioned(req.path())));
+    match limit {
+        Some(limit) => within(limit, req, next).await,
+        None => next.call(req).await.map(ServiceResponse::map_into_boxed_body),
+    }
+}
+
+async fn within(
+    limit: Duration,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    match timeout(limit, next.call(req)).await {
+        Ok(response) => response.map(ServiceResponse::map_into_boxed_body),
+        Err(_) => Err(timed_out(limit).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::rt::time::sleep;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    #[actix_web::test]
+    async fn slow_handlers_get_a_504() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(|req, next| within(Duration::from_millis(20), req, next)))
+                .route("/fast", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        sleep(Duration::from_secs(5)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+        let fast = test::call_service(&app, TestRequest::get().uri("/fast").to_request()).await;
+        assert_eq!(fast.status(), StatusCode::OK);
+
+        let slow = test::try_call_service(&app, TestRequest::get().uri("/slow").to_request()).await;
+        let response = slow.expect_err("the slow handler timed out").error_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::ApiError;
+use crate::v1;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+
+// A write sent with `X-Dry-Run: true`, or `?dry_run=1`, is rehearsed: it is
+// validated, its caller's permissions and the uniqueness of its email and
+// phone number are checked, and it is answered as it would have been, but no
+// row, claim, index entry, event or audit entry is written. The response
+// carries `X-Dry-Run: true`. Only the writes in `SUPPORTED` can be
+// rehearsed; any other POST, PUT, PATCH or DELETE asking for a dry run is
+// refused with 400 rather than carried out. Reads ignore the flag.
+//
+// The flag is held in a task-local for the rest of the request, so the user
+// writes in `users` and `status`, and the claims in `emails` and `phones`,
+// stop short of the CQL that would change anything.
+
+pub const DRY_RUN_HEADER: HeaderName = HeaderName::from_static("x-dry-run");
+
+// The writes that can be rehearsed, by method and route.
+const SUPPORTED: &[(Method, &str)] = &[
+    (Method::POST, "/register"),
+    (Method::POST, "/register/bulk"),
+    (Method::PATCH, "/update/{id}"),
+    (Method::PUT, "/users/{id}"),
+    (Method::DELETE, "/delete/{id}"),
+    (Method::POST, "/users/delete"),
+    (Method::POST, "/users/{id}/restore"),
+    (Method::POST, "/users/{id}/anonymize"),
+    (Method::POST, "/users/{id}/deactivate"),
+    (Method::POST, "/users/{id}/suspend"),
+    (Method::POST, "/users/{id}/activate"),
+];
+
+tokio::task_local! {
+    static DRY_RUN: bool;
+}
+
+fn truthy(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "true" | "1")
+}
+
+// Whether a request with this `X-Dry-Run` header and query string asks to
+// be a dry run.
+fn requested(header: Option<&HeaderValue>, query: &str) -> bool {
+    let by_header = header
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(truthy);
+    let by_query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .any(|(name, value)| name == "dry_run" && truthy(value));
+    by_header || by_query
+}
+
+// Whether the write `method` on the route `pattern`, with or without the
+// version prefix, can be rehearsed.
+fn supported(method: &Method, pattern: &str) -> bool {
+    let pattern = v1::unversioned(pattern);
+    SUPPORTED
+        .iter()
+        .any(|(supported, route)| supported == method && *route == pattern)
+}
+
+// Runs a write that asks for it as a dry run.
+pub async fn scope(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let write = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !write || !requested(req.headers().get(&DRY_RUN_HEADER), req.query_string()) {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+    let pattern = req.match_pattern();
+    if !pattern.is_some_and(|pattern| supported(req.method(), &pattern)) {
+        let e = ApiError::BadRequest(format!(
+            "{} {} can't be run as a dry run",
+            req.method(),
+            req.path()
+        ));
+        return Ok(req.error_response(e).map_into_boxed_body());
+    }
+    tracing::info!(method = %req.method(), path = req.path(), "dry run; nothing will be written");
+    let mut res = DRY_RUN.scope(true, next.call(req)).await?;
+    res.headers_mut()
+        .insert(DRY_RUN_HEADER, HeaderValue::from_static("true"));
+    Ok(res.map_into_boxed_body())
+}
+
+// Whether the request being served is a dry run; false outside of one.
+pub fn active() -> bool {
+    DRY_RUN.try_with(|dry_run| *dry_run).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_runs_are_asked_for_by_header_or_query() {
+        let yes = HeaderValue::from_static("True");
+        let no = HeaderValue::from_static("false");
+        assert!(requested(Some(&yes), ""));
+        assert!(!requested(Some(&no), ""));
+        assert!(requested(None, "hard=true&dry_run=1"));
+        assert!(!requested(None, "dry_run=0"));
+        assert!(!requested(None, "hard=true"));
+        assert!(!active());
+    }
+
+    #[test]
+    fn only_user_writes_can_be_rehearsed() {
+        assert!(supported(&Method::POST, "/register"));
+        assert!(supported(&Method::PUT, "/api/v1/users/{id}"));
+        assert!(supported(&Method::DELETE, "/api/v1/delete/{id}"));
+        assert!(!supported(&Method::DELETE, "/users/{id}/tags/{tag}"));
+        assert!(!supported(&Method::POST, "/graphql"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::dry_run;
+use crate::error::ApiError;
+use crate::observe;
+use crate::state::AppState;
+use crate::users;
+use chrono::{DateTime, Utc};
+use scylla::frame::response::result::{CqlValue, Row};
+use uuid::Uuid;
+
+// Email uniqueness is enforced through `users_by_email`: an address belongs to
+// whichever user first claims it with a lightweight transaction. Addresses
+// compare case-insensitively, so the claim key is lower-cased.
+//
+// Users registered before `users_by_email` existed have no claim until
+// `backfill` gives them one. Until then a fresh claim is checked against the
+// `users` email index, which only matches exactly, as written or lower-cased;
+// an address found there is claimed for its holder instead.
+
+pub fn key(email: &str) -> String {
+    email.to_lowercase()
+}
+
+// Claims `email` for `user_id`, or fails with 409 if another user holds it.
+// Claiming an address the user already holds succeeds, which also makes a
+// retried claim harmless. The claim of a user who expires at `expires_at`
+// expires with it. In a dry run the address is only checked.
+pub async fn claim(
+    state: &AppState,
+    email: &str,
+    user_id: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let conflict = || ApiError::Conflict(format!("email {} is already registered", email));
+    if dry_run::active() {
+        let holder = match owner(state, email).await? {
+            Some(owner) => Some(owner),
+            None => unclaimed_holder(state, email, user_id).await?,
+        };
+        return match holder {
+            Some(holder) if holder != user_id => Err(conflict()),
+            _ => Ok(()),
+        };
+    }
+    let key = key(email);
+    let ttl = users::ttl(expires_at);
+    let result = observe::conditional(state, "claim_email", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.claim_email, (&key, user_id, ttl))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to claim email", e))?
+        .first_row::<Row>()
+        .map_err(|e| ApiError::internal("Failed to claim email", e))?;
+
+    // A rejected LWT echoes the existing row after `[applied]`.
+    match row.columns.as_slice() {
+        [Some(CqlValue::Boolean(true)), ..] => match unclaimed_holder(state, email, user_id).await? {
+            Some(holder) => {
+                release(state, email, user_id).await;
+                claim_for(state, &key, holder).await;
+                Err(conflict())
+            }
+            None => Ok(()),
+        },
+        [Some(CqlValue::Boolean(false)), .., Some(CqlValue::Uuid(owner))] if *owner == user_id => Ok(()),
+        [Some(CqlValue::Boolean(false)), ..] => Err(conflict()),
+        other => Err(ApiError::Internal(format!("unexpected claim_email result: {:?}", other))),
+    }
+}
+
+// Another user stored with `email` who had no claim on it.
+async fn unclaimed_holder(
+    state: &AppState,
+    email: &str,
+    user_id: Uuid,
+) -> Result<Option<Uuid>, ApiError> {
+    let mut spellings = vec![email.to_string()];
+    if key(email) != email {
+        spellings.push(key(email));
+    }
+    for spelling in &spellings {
+        let result = observe::query(state, "select_user_ids_by_email", || {
+            state
+                .session
+                .execute_unpaged(&state.statements.select_user_ids_by_email, (spelling,))
+        })
+        .await?;
+        let holder = result
+            .into_rows_result()
+            .map_err(|e| ApiError::internal("Failed to check email", e))?
+            .rows::<(Uuid,)>()
+            .map_err(|e| ApiError::internal("Failed to check email", e))?
+            .filter_map(Result::ok)
+            .map(|(id,)| id)
+            .find(|id| *id != user_id);
+        if holder.is_some() {
+            return Ok(holder);
+        }
+    }
+    Ok(None)
+}
+
+// Claims `key` for a user already stored with it. Failures are only logged:
+// the next registration of the address finds the holder again.
+async fn claim_for(state: &AppState, key: &str, holder: Uuid) {
+    if let Err(e) = observe::conditional(state, "claim_email", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.claim_email, (key, holder, 0))
+    })
+    .await
+    {
+        tracing::warn!(user_id = %holder, error = %e, "failed to claim email for its holder");
+    }
+}
+
+// How `adopt` left an address.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Adopted {
+    Claimed,
+    AlreadyHeld,
+    HeldBy(Uuid),
+}
+
+// Claims `email` for a user already stored with it, as the backfill does for
+// users registered before `users_by_email` existed. Unlike `claim`, an
+// address held by another user is reported rather than refused.
+pub async fn adopt(
+    state: &AppState,
+    email: &str,
+    user_id: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<Adopted, ApiError> {
+    let key = key(email);
+    let ttl = users::ttl(expires_at);
+    let result = observe::conditional(state, "claim_email", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.claim_email, (&key, user_id, ttl))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to claim email", e))?
+        .first_row::<Row>()
+        .map_err(|e| ApiError::internal("Failed to claim email", e))?;
+    match row.columns.as_slice() {
+        [Some(CqlValue::Boolean(true)), ..] => Ok(Adopted::Claimed),
+        [Some(CqlValue::Boolean(false)), .., Some(CqlValue::Uuid(owner))] if *owner == user_id => {
+            Ok(Adopted::AlreadyHeld)
+        }
+        [Some(CqlValue::Boolean(false)), .., Some(CqlValue::Uuid(owner))] => Ok(Adopted::HeldBy(*owner)),
+        other => Err(ApiError::Internal(format!("unexpected claim_email result: {:?}", other))),
+    }
+}
+
+// Releases `email` if `user_id` still holds it. Failures are only logged: a
+// leftover claim blocks re-registration of that address but loses no data.
+pub async fn release(state: &AppState, email: &str, user_id: Uuid) {
+    if dry_run::active() {
+        return;
+    }
+    let key = key(email);
+    if let Err(e) = observe::conditional(state, "release_email", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.release_email, (&key, user_id))
+    })
+    .await
+    {
+        tracing::warn!(%user_id, error = %e, "failed to release email claim");
+    }
+}
+
+// The user holding `email`, if it has been claimed.
+pub async fn owner(state: &AppState, email: &str) -> Result<Option<Uuid>, ApiError> {
+    let key = key(email);
+    let result = observe::query(state, "select_email_owner", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_email_owner, (&key,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to look up email", e))?
+        .maybe_first_row::<(Option<Uuid>,)>()
+        .map_err(|e| ApiError::internal("Failed to look up email", e))?;
+    Ok(row.and_then(|(user_id,)| user_id))
+}
+
+// Whether `email` belongs to a user: claimed, or stored by a user the
+// backfill hasn't reached yet.
+pub async fn taken(state: &AppState, email: &str) -> Result<bool, ApiError> {
+    if owner(state, email).await?.is_some() {
+        return Ok(true);
+    }
+    Ok(unclaimed_holder(state, email, Uuid::nil()).await?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_keys_ignore_case() {
+        assert_eq!(key("Alice@X.com"), key("alice@x.com"));
+        assert_eq!(key("ALICE@EXAMPLE.COM"), "alice@example.com");
+    }
+}
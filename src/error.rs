@@ -0,0 +1,283 @@
+use crate::observe::CqlError;
+use crate::retry;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use scylla::transport::errors::QueryError;
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+// Set from `http.verbose_errors` at startup.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Whether 5xx bodies carry their full detail, as in development.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+// Errors returned by the API handlers. Every variant is rendered as an RFC 7807
+// `application/problem+json` body whose `code` clients can match on instead of
+// parsing `detail`.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Conflict(String),
+    PreconditionFailed(String),
+    PreconditionRequired(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Validation(Vec<FieldError>),
+    DbUnavailable(String),
+    // The circuit breaker is open; the cluster is tried again after this long.
+    CircuitOpen(Duration),
+    // A bounded queue is full; the request may be retried shortly.
+    Overloaded(String),
+    Timeout(String),
+    Internal(String),
+}
+
+/// RFC 7807 problem details. The request-id middleware adds `request_id` and
+/// `trace_id`, which support can look the logged detail of a 5xx up by.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    /// Always `about:blank`; see `code` for the kind of error.
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    /// Stable machine-readable error code, e.g. `not_found`.
+    pub code: &'static str,
+    pub detail: String,
+    /// Per-field details for `validation_failed`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(status: StatusCode, code: &'static str, detail: String, errors: Vec<FieldError>) -> Self {
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            code,
+            detail,
+            errors,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PreconditionFailed(_) => "precondition_failed",
+            ApiError::PreconditionRequired(_) => "precondition_required",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::DbUnavailable(_) | ApiError::CircuitOpen(_) => "db_unavailable",
+            ApiError::Overloaded(_) => "overloaded",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    // The detail shown to clients. Server errors carry driver and internal
+    // messages, so they only get a generic one unless `http.verbose_errors`
+    // is on; the full detail is logged.
+    pub fn public_detail(&self) -> String {
+        self.detail(VERBOSE.load(Ordering::Relaxed))
+    }
+
+    fn detail(&self, verbose: bool) -> String {
+        if verbose {
+            return self.to_string();
+        }
+        match self {
+            ApiError::DbUnavailable(_) | ApiError::CircuitOpen(_) => {
+                String::from("the database is temporarily unavailable")
+            }
+            ApiError::Timeout(_) => String::from("the request took too long to complete"),
+            ApiError::Internal(_) => String::from("internal server error"),
+            _ => self.to_string(),
+        }
+    }
+
+    // The body `error_response` renders, for embedding in composite responses
+    // such as per-item bulk results. Server errors are logged here, since
+    // their body leaves the detail out.
+    pub fn to_problem(&self) -> Problem {
+        if self.status_code().is_server_error() {
+            tracing::error!(code = self.code(), error = %self, "request failed");
+        }
+        let errors = match self {
+            ApiError::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+        Problem::new(self.status_code(), self.code(), self.public_detail(), errors)
+    }
+
+    // Stamps `context` (e.g. "Failed to create user") onto an internal error.
+    pub fn internal(context: &str, e: impl fmt::Display) -> Self {
+        ApiError::Internal(format!("{}: {}", context, e))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::PreconditionFailed(detail)
+            | ApiError::PreconditionRequired(detail)
+            | ApiError::PayloadTooLarge(detail)
+            | ApiError::UnsupportedMediaType(detail)
+            | ApiError::DbUnavailable(detail)
+            | ApiError::Overloaded(detail)
+            | ApiError::Timeout(detail)
+            | ApiError::Internal(detail) => write!(f, "{}", detail),
+            ApiError::CircuitOpen(retry_after) => {
+                write!(f, "circuit breaker open for another {:?}", retry_after)
+            }
+            ApiError::Validation(errors) => {
+                let fields = errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>();
+                write!(f, "invalid value for {}", fields.join(", "))
+            }
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::PreconditionRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::DbUnavailable(_) | ApiError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = render(&self.to_problem());
+        let retry_after = match self {
+            ApiError::CircuitOpen(retry_after) => {
+                Some(retry_after.as_secs_f64().ceil().max(1.0) as u64)
+            }
+            ApiError::Overloaded(_) => Some(1),
+            _ => None,
+        };
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+// Errors the retry layer treats as transient mean the cluster is struggling
+// rather than that the request is wrong, so clients get a 503 they may retry,
+// or a 504 when it was too slow to answer.
+impl From<QueryError> for ApiError {
+    fn from(e: QueryError) -> Self {
+        if retry::is_timeout(&e) {
+            ApiError::Timeout(format!("database timed out: {}", e))
+        } else if retry::is_transient(&e) {
+            ApiError::DbUnavailable(format!("database unavailable: {}", e))
+        } else {
+            ApiError::Internal(format!("database error: {}", e))
+        }
+    }
+}
+
+impl From<CqlError> for ApiError {
+    fn from(e: CqlError) -> Self {
+        match e {
+            CqlError::Query(e) => e.into(),
+            CqlError::Open(retry_after) => ApiError::CircuitOpen(retry_after),
+            CqlError::Saturated => {
+                ApiError::Overloaded(String::from("too many database requests in flight"))
+            }
+        }
+    }
+}
+
+// Renders a problem+json response; shared with the auth and rate-limit errors
+// so every error body has the same shape.
+pub fn problem(status: StatusCode, code: &'static str, detail: String) -> HttpResponse {
+    render(&Problem::new(status, code, detail, Vec::new()))
+}
+
+fn render(problem: &Problem) -> HttpResponse {
+    HttpResponse::build(StatusCode::from_u16(problem.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+        .insert_header((header::CONTENT_TYPE, "application/problem+json"))
+        .json(problem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_errors_hide_their_detail() {
+        let internal = ApiError::internal("Failed to read rows", "driver said no");
+        let problem = internal.to_problem();
+        assert_eq!(problem.code, "internal_error");
+        assert_eq!(problem.detail, "internal server error");
+        assert_eq!(internal.to_string(), "Failed to read rows: driver said no");
+        assert_eq!(internal.detail(true), "Failed to read rows: driver said no");
+
+        let unavailable = ApiError::DbUnavailable(String::from("database unavailable: timeout"));
+        let problem = unavailable.to_problem();
+        assert_eq!(problem.status, 503);
+        assert_eq!(problem.detail, "the database is temporarily unavailable");
+
+        let problem = ApiError::Timeout(String::from("database timed out: read")).to_problem();
+        assert_eq!(problem.status, 504);
+        assert_eq!(problem.code, "timeout");
+        assert_eq!(problem.detail, "the request took too long to complete");
+
+        let open = ApiError::from(CqlError::Open(Duration::from_millis(2_500)));
+        let response = open.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "3");
+        assert_eq!(open.code(), "db_unavailable");
+    }
+
+    #[test]
+    fn client_errors_keep_their_detail() {
+        let problem = ApiError::NotFound(String::from("User with ID 1 not found")).to_problem();
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.code, "not_found");
+        assert_eq!(problem.detail, "User with ID 1 not found");
+
+        let errors = vec![FieldError {
+            field: String::from("email"),
+            message: String::from("must be an email address"),
+        }];
+        let problem = ApiError::Validation(errors).to_problem();
+        assert_eq!(problem.status, 422);
+        assert_eq!(problem.errors.len(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::models::User;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+// In-process fan-out of user changes made through the API, for the live
+// feeds. Events are numbered in publication order, starting from the clock
+// (in microseconds) so ids keep increasing across restarts. The last
+// `http.event_buffer` events are kept for feeds that resume from an id, and a
+// subscriber that falls further behind skips the oldest. The buffer is not
+// persisted: without the CDC consumer the feeds only cover changes made by
+// this instance while it runs. Changes made through the API are published
+// once `history` has appended them to the user's stream, and carry the
+// version they took it to, so a client can replay what it missed from
+// GET /users/{id}/events.
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+}
+
+impl EventKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "created" => Some(EventKind::Created),
+            "updated" => Some(EventKind::Updated),
+            "deleted" => Some(EventKind::Deleted),
+            "restored" => Some(EventKind::Restored),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+            EventKind::Restored => "restored",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserEvent {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub user_id: Uuid,
+    /// The version of the user's event stream the change took it to; absent
+    /// for changes read from the CDC log, or when the append failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    /// The user after the change; absent for deletes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    pub at: DateTime<Utc>,
+}
+
+struct Recent {
+    next_id: u64,
+    events: VecDeque<Arc<UserEvent>>,
+}
+
+pub struct Events {
+    sender: broadcast::Sender<Arc<UserEvent>>,
+    capacity: usize,
+    cdc: bool,
+    recent: Mutex<Recent>,
+}
+
+// Buffered events after the id a subscriber last saw, and a receiver for the
+// ones published afterwards. `lagged` is set when some events in between are
+// no longer buffered.
+pub struct Resumed {
+    pub replay: Vec<Arc<UserEvent>>,
+    pub lagged: bool,
+    pub receiver: broadcast::Receiver<Arc<UserEvent>>,
+}
+
+impl Events {
+    pub fn new(capacity: usize, cdc: bool) -> Self {
+        Events {
+            sender: broadcast::channel(capacity).0,
+            capacity,
+            cdc,
+            recent: Mutex::new(Recent {
+                next_id: Utc::now().timestamp_micros().max(1) as u64,
+                events: VecDeque::with_capacity(capacity),
+            }),
+        }
+    }
+
+    // Publishes a change made through the API. With the CDC consumer on, the
+    // change reaches the feeds through the CDC log like any other write, so
+    // it is left to that instead of being sent twice.
+    pub fn publish(&self, kind: EventKind, user_id: Uuid, user: Option<User>, version: Option<i64>) {
+        if !self.cdc {
+            self.send(kind, user_id, user, version);
+        }
+    }
+
+    // Publishes a change read from the CDC log.
+    pub fn publish_captured(&self, kind: EventKind, user_id: Uuid, user: Option<User>) {
+        self.send(kind, user_id, user, None);
+    }
+
+    fn send(&self, kind: EventKind, user_id: Uuid, user: Option<User>, version: Option<i64>) {
+        // Sending under the lock keeps the buffer and the channel in the same
+        // order, so `resume` neither repeats nor skips an event.
+        let mut recent = self.recent.lock().expect("event buffer lock poisoned");
+        let event = Arc::new(UserEvent {
+            id: recent.next_id,
+            kind,
+            user_id,
+            version,
+            user,
+            at: Utc::now(),
+        });
+        recent.next_id += 1;
+        if recent.events.len() == self.capacity {
+            recent.events.pop_front();
+        }
+        recent.events.push_back(Arc::clone(&event));
+        // Sending only fails when nobody is subscribed.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<UserEvent>> {
+        self.sender.subscribe()
+    }
+
+    pub fn resume(&self, last_id: u64) -> Resumed {
+        let recent = self.recent.lock().expect("event buffer lock poisoned");
+        let oldest = recent.events.front().map_or(recent.next_id, |event| event.id);
+        Resumed {
+            replay: recent
+                .events
+                .iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect(),
+            lagged: last_id.saturating_add(1) < oldest,
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
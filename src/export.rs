@@ -0,0 +1,261 @@
+use crate::error::{ApiError, Problem};
+use crate::models::{ExportQuery, User};
+use crate::observe;
+use crate::state::AppState;
+use crate::users::{self, UserRow};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::rt::task;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// GET /users/export.csv writes every live user as one CSV record (RFC 4180).
+// The `users` table is read with the driver's pager, a page at a time, up to
+// `http.max_rows_per_request` users: past it the export is truncated (with
+// `X-Truncated: true`) or refused, as `http.row_cap_mode` says, before any
+// record is sent. `fields` picks and orders the columns as on GET /users; the
+// header record names them.
+//
+// Writing the records is CPU work, which on a large table would keep the
+// worker thread from serving its other requests. It runs on the blocking
+// thread pool instead, `CHUNK` users per task, with at most
+// `http.export_workers` tasks at once across all exports.
+
+// Users written per blocking task.
+const CHUNK: usize = 500;
+
+// Characters that make a spreadsheet read a cell as a formula.
+pub const FORMULA_PREFIXES: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+// One CSV cell. A value a spreadsheet would evaluate gets a leading `'`, so
+// opening an export can't run what a user put in their name.
+fn cell(value: &str) -> String {
+    let value = if value.starts_with(FORMULA_PREFIXES) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn push_record(out: &mut String, cells: impl Iterator<Item = String>) {
+    out.push_str(&cells.collect::<Vec<_>>().join(","));
+    out.push_str("\r\n");
+}
+
+// `user` as a record of `fields`. Absent values are empty cells; the profile
+// is written as its JSON.
+fn push_user(out: &mut String, user: &User, fields: &[&str]) {
+    let value = serde_json::to_value(user).unwrap_or_default();
+    push_record(
+        out,
+        fields.iter().map(|field| match value.get(field) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(text)) => cell(text),
+            Some(other) => cell(&other.to_string()),
+        }),
+    );
+}
+
+// `users` as CSV records of `fields`, written a chunk at a time on the
+// blocking pool while holding one of `workers`' permits.
+fn records(
+    users: impl Stream<Item = Result<User, ApiError>>,
+    fields: Arc<[&'static str]>,
+    workers: Arc<Semaphore>,
+) -> impl Stream<Item = Result<Bytes, ApiError>> {
+    users
+        .try_chunks(CHUNK)
+        .map_err(|e| e.1)
+        .and_then(move |chunk| {
+            let fields = fields.clone();
+            let workers = workers.clone();
+            async move {
+                let _permit = workers
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| ApiError::internal("Export workers are gone", e))?;
+                task::spawn_blocking(move || {
+                    let mut out = String::new();
+                    for user in &chunk {
+                        push_user(&mut out, user, &fields);
+                    }
+                    Bytes::from(out)
+                })
+                .await
+                .map_err(|e| ApiError::internal("Error writing users", e))
+            }
+        })
+}
+
+/// Every live user, up to the row cap, as CSV. A failure after the
+/// first records have been sent cuts the response short.
+#[utoipa::path(
+    get,
+    path = "/users/export.csv",
+    params(ExportQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "A header record, then one record per user", content_type = "text/csv", body = String),
+        (status = 400, description = "Unknown field, or more users than the row cap allows", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+    )
+)]
+pub async fn export_users(
+    params: web::Query<ExportQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let fields = match params.fields.as_deref() {
+        Some(fields) => users::parse_fields(fields)?,
+        None => users::USER_FIELDS.to_vec(),
+    };
+    let pager = observe::query(&data, "select_all_users", || {
+        data.session
+            .execute_iter(data.statements.select_all_users.clone(), ())
+    })
+    .await?;
+    let rows = pager
+        .rows_stream::<UserRow>()
+        .map_err(|e| ApiError::internal("Error streaming users", e))?;
+
+    let users = rows
+        .map_err(|e| ApiError::internal("Error fetching users", e))
+        .try_filter(|row| future::ready(!row.is_deleted()))
+        .map_ok(UserRow::into_user);
+    let (users, truncated) =
+        users::cap_rows(data.max_rows_per_request, data.row_cap_mode, users).await?;
+
+    let mut header = String::new();
+    push_record(&mut header, fields.iter().map(|field| field.to_string()));
+    let users = stream::iter(users.into_iter().map(Ok));
+    let body = records(users, fields.into(), data.export_workers.clone()).map_err(|e| {
+        tracing::error!(error = %e, "user export failed");
+        actix_web::Error::from(e)
+    });
+
+    let response = HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(String::from("users.csv"))],
+        })
+        .streaming(stream::once(future::ok(Bytes::from(header))).chain(body));
+    Ok(users::with_truncated(response, truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use std::cell::Cell;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn cells_are_quoted_only_when_needed() {
+        assert_eq!(cell("Ada Lovelace"), "Ada Lovelace");
+        assert_eq!(cell("Lovelace, Ada"), "\"Lovelace, Ada\"");
+        assert_eq!(cell("Ada \"The Countess\""), "\"Ada \"\"The Countess\"\"\"");
+        assert_eq!(cell("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn formulas_are_defused() {
+        assert_eq!(cell("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(cell("+1"), "'+1");
+        assert_eq!(cell("@sum"), "'@sum");
+        assert_eq!(cell("a=b"), "a=b");
+    }
+
+    fn user(n: u32) -> User {
+        User {
+            id: Uuid::from_u128(u128::from(n)),
+            name: format!("User {}", n),
+            email: format!("user{}@example.com", n),
+            phone: None,
+            profile: None,
+            created_at: DateTime::from_timestamp(1_700_000_000, 0),
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    fn csv(users: &[User], fields: &[&str]) -> String {
+        let mut out = String::new();
+        for user in users {
+            push_user(&mut out, user, fields);
+        }
+        out
+    }
+
+    #[test]
+    fn records_follow_the_selected_fields() {
+        let mut ada = user(0);
+        ada.name = String::from("Ada, Countess");
+        assert_eq!(
+            csv(&[ada.clone()], &["email", "name", "updated_at", "created_at"]),
+            "user0@example.com,\"Ada, Countess\",,2023-11-14T22:13:20Z\r\n"
+        );
+        assert_eq!(csv(&[ada], &["id"]), "00000000-0000-0000-0000-000000000000\r\n");
+
+        let mut header = String::new();
+        push_record(&mut header, users::USER_FIELDS.iter().map(|field| field.to_string()));
+        let expected =
+            "id,name,email,phone,profile,created_at,updated_at,verified,version,status\r\n";
+        assert_eq!(header, expected);
+    }
+
+    async fn offloaded(users: &[User], fields: &[&'static str], workers: usize) -> String {
+        let chunks: Vec<Bytes> = records(
+            stream::iter(users.to_vec()).map(Ok),
+            fields.into(),
+            Arc::new(Semaphore::new(workers)),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+        chunks.iter().map(|chunk| std::str::from_utf8(chunk).unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn offloaded_export_matches_writing_in_place() {
+        let users: Vec<User> = (0..CHUNK as u32 * 3 + 7).map(user).collect();
+        let fields = ["id", "email", "created_at"];
+        assert_eq!(offloaded(&users, &fields, 2).await, csv(&users, &fields));
+        assert_eq!(offloaded(&[], &fields, 1).await, "");
+    }
+
+    #[actix_web::test]
+    async fn other_work_runs_during_a_large_export() {
+        let users: Vec<User> = (0..100_000).map(user).collect();
+        let done = Cell::new(false);
+        let export = async {
+            let csv = offloaded(&users, &users::USER_FIELDS, 1).await;
+            done.set(true);
+            csv
+        };
+        let ticker = async {
+            let mut ticks = 0;
+            while !done.get() {
+                actix_web::rt::time::sleep(Duration::from_millis(1)).await;
+                ticks += 1;
+            }
+            ticks
+        };
+        let (csv, ticks) = futures::join!(export, ticker);
+        assert_eq!(csv.lines().count(), users.len());
+        assert!(ticks > 1, "the export held the thread: {} ticks", ticks);
+    }
+}
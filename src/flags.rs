@@ -0,0 +1,300 @@
+use crate::config::FlagsConfig;
+use crate::error::{ApiError, Problem};
+use crate::observe;
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+// Feature flags switch risky features off per environment without a
+// redeploy. Every flag is on unless listed in `flags.disabled`; with
+// `flags.table` on, the rows of the `feature_flags` table override that,
+// each instance reading the table at startup and then every
+// `flags.refresh_interval_secs`, so a change made through
+// PUT /admin/flags/{name} reaches every instance within one interval. Rows
+// naming no known flag are ignored. A refresh that fails keeps the values
+// read last.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flag {
+    // POST /register queueing users with `write_behind.enabled`; off, users
+    // are stored before the response as without it.
+    WriteBehind,
+    // Reading users and listing pages from the in-memory and Redis caches,
+    // and filling them.
+    UserCache,
+    // Concurrent reads of one user sharing a query; see `coalesce`.
+    CoalesceReads,
+    // GET /users/search; off, it answers 404.
+    NameSearch,
+}
+
+const FLAGS: [Flag; 4] = [
+    Flag::WriteBehind,
+    Flag::UserCache,
+    Flag::CoalesceReads,
+    Flag::NameSearch,
+];
+
+impl Flag {
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::WriteBehind => "write_behind",
+            Flag::UserCache => "user_cache",
+            Flag::CoalesceReads => "coalesce_reads",
+            Flag::NameSearch => "name_search",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        FLAGS.into_iter().find(|flag| flag.name() == name)
+    }
+}
+
+/// A feature flag and where its value comes from.
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct FlagState {
+    pub name: &'static str,
+    pub enabled: bool,
+    /// `table` when a `feature_flags` row sets it, otherwise `config`.
+    pub source: &'static str,
+}
+
+/// The value to store for a feature flag.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetFlag {
+    pub enabled: bool,
+}
+
+pub struct Flags {
+    // Flags turned off in the configuration, as last loaded.
+    disabled: RwLock<Vec<Flag>>,
+    // The `feature_flags` rows read last.
+    stored: RwLock<HashMap<Flag, bool>>,
+    // How often the table is read; `None` with `flags.table` off.
+    refresh_interval: Option<Duration>,
+}
+
+// The names were checked when the configuration was loaded.
+fn disabled(config: &FlagsConfig) -> Vec<Flag> {
+    config.disabled.iter().filter_map(|name| Flag::from_name(name)).collect()
+}
+
+impl Flags {
+    pub fn new(config: &FlagsConfig) -> Self {
+        Flags {
+            disabled: RwLock::new(disabled(config)),
+            stored: RwLock::new(HashMap::new()),
+            refresh_interval: config
+                .table
+                .then(|| Duration::from_secs(config.refresh_interval_secs)),
+        }
+    }
+
+    // Applies a reloaded `flags.disabled`. Whether the table is read is only
+    // decided at startup.
+    pub fn set_disabled(&self, config: &FlagsConfig) {
+        *self.disabled.write().unwrap() = disabled(config);
+    }
+
+    pub fn enabled(&self, flag: Flag) -> bool {
+        match self.stored.read().unwrap().get(&flag) {
+            Some(enabled) => *enabled,
+            None => !self.disabled.read().unwrap().contains(&flag),
+        }
+    }
+
+    pub fn states(&self) -> Vec<FlagState> {
+        let stored = self.stored.read().unwrap();
+        let disabled = self.disabled.read().unwrap();
+        FLAGS
+            .into_iter()
+            .map(|flag| match stored.get(&flag) {
+                Some(enabled) => FlagState {
+                    name: flag.name(),
+                    enabled: *enabled,
+                    source: "table",
+                },
+                None => FlagState {
+                    name: flag.name(),
+                    enabled: !disabled.contains(&flag),
+                    source: "config",
+                },
+            })
+            .collect()
+    }
+
+    // Replaces the stored values with `rows`, skipping unknown names.
+    fn store(&self, rows: impl IntoIterator<Item = (String, bool)>) {
+        let stored = rows
+            .into_iter()
+            .filter_map(|(name, enabled)| {
+                let flag = Flag::from_name(&name);
+                if flag.is_none() {
+                    tracing::debug!(flag = %name, "ignoring unknown feature flag");
+                }
+                flag.map(|flag| (flag, enabled))
+            })
+            .collect();
+        *self.stored.write().unwrap() = stored;
+    }
+
+    // Reads the `feature_flags` table, when it is used.
+    pub async fn refresh(&self, state: &AppState) -> Result<(), ApiError> {
+        if self.refresh_interval.is_none() {
+            return Ok(());
+        }
+        let result = observe::query(state, "select_feature_flags", || {
+            state
+                .session
+                .execute_unpaged(&state.statements.select_feature_flags, ())
+        })
+        .await?;
+        let rows = result
+            .into_rows_result()
+            .map_err(|e| ApiError::internal("Error reading feature flags", e))?
+            .rows::<(String, Option<bool>)>()
+            .map_err(|e| ApiError::internal("Error reading feature flags", e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ApiError::internal("Error reading feature flags", e))?;
+        self.store(
+            rows.into_iter()
+                .filter_map(|(name, enabled)| enabled.map(|enabled| (name, enabled))),
+        );
+        Ok(())
+    }
+
+    pub fn start(self: &Arc<Self>, state: AppState, tasks: &mut Tasks) {
+        if let Some(interval) = self.refresh_interval {
+            let flags = self.clone();
+            tasks.spawn("feature flags", move |stopping| {
+                flags.refresh_every(state, interval, stopping)
+            });
+        }
+    }
+
+    async fn refresh_every(
+        self: Arc<Self>,
+        state: AppState,
+        interval: Duration,
+        mut stopping: Stopping,
+    ) {
+        while stopping.pause(interval).await {
+            if let Err(e) = self.refresh(&state).await {
+                tracing::warn!(error = %e, "failed to refresh feature flags");
+            }
+        }
+    }
+}
+
+/// Lists the feature flags with their current values on this instance.
+#[utoipa::path(
+    get,
+    path = "/admin/flags",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Every feature flag", body = [FlagState]),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+    )
+)]
+pub async fn list_flags(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.flags.states())
+}
+
+/// Sets a feature flag in the `feature_flags` table. It takes effect on this
+/// instance at once and on the others at their next refresh.
+#[utoipa::path(
+    put,
+    path = "/admin/flags/{name}",
+    params(("name" = String, Path, description = "`write_behind`, `user_cache`, `coalesce_reads` or `name_search`")),
+    request_body = SetFlag,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The flag as it now is", body = FlagState),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such flag", body = Problem),
+        (status = 409, description = "`flags.table` is off", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn set_flag(
+    name: web::Path<String>,
+    body: web::Json<SetFlag>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let flag = Flag::from_name(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("No feature flag named {}", name)))?;
+    if data.flags.refresh_interval.is_none() {
+        return Err(ApiError::Conflict(String::from(
+            "Feature flags are read from the configuration only; set flags.table",
+        )));
+    }
+    observe::query(&data, "upsert_feature_flag", || {
+        data.session.execute_unpaged(
+            &data.statements.upsert_feature_flag,
+            (flag.name(), body.enabled, Utc::now()),
+        )
+    })
+    .await?;
+    data.flags.stored.write().unwrap().insert(flag, body.enabled);
+    let state = data.flags.states().into_iter().find(|state| state.name == flag.name());
+    Ok(HttpResponse::Ok().json(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(disabled: &[&str]) -> Flags {
+        Flags::new(&FlagsConfig {
+            disabled: disabled.iter().map(|name| name.to_string()).collect(),
+            ..FlagsConfig::default()
+        })
+    }
+
+    #[test]
+    fn flags_are_found_by_name() {
+        for flag in FLAGS {
+            assert_eq!(Flag::from_name(flag.name()), Some(flag));
+        }
+        assert_eq!(Flag::from_name("dark_mode"), None);
+    }
+
+    #[test]
+    fn flags_are_on_unless_disabled() {
+        let flags = flags(&["write_behind"]);
+        assert!(!flags.enabled(Flag::WriteBehind));
+        assert!(flags.enabled(Flag::UserCache));
+    }
+
+    #[test]
+    fn stored_values_override_the_configuration() {
+        let flags = flags(&["write_behind"]);
+        flags.store([
+            (String::from("write_behind"), true),
+            (String::from("user_cache"), false),
+            (String::from("dark_mode"), false),
+        ]);
+        assert!(flags.enabled(Flag::WriteBehind));
+        assert!(!flags.enabled(Flag::UserCache));
+        assert_eq!(
+            flags.states()[1],
+            FlagState {
+                name: "user_cache",
+                enabled: false,
+                source: "table",
+            }
+        );
+
+        // A row deleted from the table gives the flag back to the configuration.
+        flags.store([(String::from("user_cache"), false)]);
+        assert!(!flags.enabled(Flag::WriteBehind));
+    }
+}
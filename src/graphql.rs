@@ -0,0 +1,721 @@
+use crate::audit;
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, FieldError};
+use crate::models::{ListUsersQuery, NewUser, UpdateUser};
+use crate::negotiate::Body;
+use crate::state::AppState;
+use crate::users;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as Json};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+mod parser;
+
+use parser::{Field, Operation, OperationKind, Value};
+
+// POST /graphql executes GraphQL documents against the same user operations
+// as the REST routes. The schema is fixed and small (see SCHEMA), so it is
+// checked and executed by hand: results are serialized to JSON and projected
+// onto the selection set. Field names follow the REST bodies, in snake_case.
+// Queries are public like the REST GETs; update_user and delete_user
+// authenticate the caller the way the REST mutations do.
+//
+// The parser in graphql/parser.rs is hand-written too, for the operations,
+// fields, aliases, arguments and variables the schema needs. It bounds
+// nesting (parser::MAX_DEPTH) and `execute` bounds root fields
+// (MAX_ROOT_FIELDS), since each root field is a database call.
+//
+// All of it is a stopgap until async-graphql can be vendored, and is only
+// built with the `graphql` feature.
+
+// Most root fields, aliases included, one operation may select.
+const MAX_ROOT_FIELDS: usize = 10;
+
+// Served at GET /graphql, since introspection is not supported.
+const SCHEMA: &str = r#"type Query {
+  user(id: ID!): User
+  user_by_email(email: String!): User
+  user_by_phone(phone: String!): User
+  users(limit: Int, cursor: String, sort: SortField, order: SortOrder, name: String, email: String,
+        created_after: String, created_before: String, updated_after: String, updated_before: String,
+        verified: Boolean, status: StatusFilter): UsersPage!
+}
+
+type Mutation {
+  register(input: NewUser!): User!
+  update_user(id: ID!, input: UpdateUser!): User!
+  delete_user(id: ID!, hard: Boolean): Boolean!
+}
+
+type User {
+  id: ID!
+  name: String!
+  email: String!
+  phone: String
+  profile: Profile
+  created_at: String
+  updated_at: String
+  expires_at: String
+  verified: Boolean
+  version: Int
+  status: UserStatus
+}
+
+type Profile {
+  bio: String
+  avatar_url: String
+  locale: String
+  timezone: String
+}
+
+type UsersPage {
+  users: [User!]!
+  next_cursor: String
+}
+
+enum SortField { id name email }
+enum SortOrder { asc desc }
+enum UserStatus { active suspended deactivated }
+enum StatusFilter { active suspended deactivated all }
+
+input ProfileInput { bio: String, avatar_url: String, locale: String, timezone: String }
+input NewUser { name: String!, email: String!, password: String, phone: String,
+                profile: ProfileInput, expires_in_seconds: Int }
+input UpdateUser { name: String, email: String, phone: String, profile: ProfileInput,
+                   version: Int }
+"#;
+
+struct ObjectType {
+    name: &'static str,
+    // Field names, with the object type of those that need a selection set.
+    fields: &'static [(&'static str, Option<&'static ObjectType>)],
+}
+
+static PROFILE: ObjectType = ObjectType {
+    name: "Profile",
+    fields: &[("bio", None), ("avatar_url", None), ("locale", None), ("timezone", None)],
+};
+
+static USER: ObjectType = ObjectType {
+    name: "User",
+    fields: &[
+        ("id", None),
+        ("name", None),
+        ("email", None),
+        ("phone", None),
+        ("profile", Some(&PROFILE)),
+        ("created_at", None),
+        ("updated_at", None),
+        ("expires_at", None),
+        ("verified", None),
+        ("version", None),
+        ("status", None),
+    ],
+};
+
+static USERS_PAGE: ObjectType = ObjectType {
+    name: "UsersPage",
+    fields: &[("users", Some(&USER)), ("next_cursor", None)],
+};
+
+static QUERY: ObjectType = ObjectType {
+    name: "Query",
+    fields: &[
+        ("user", Some(&USER)),
+        ("user_by_email", Some(&USER)),
+        ("user_by_phone", Some(&USER)),
+        ("users", Some(&USERS_PAGE)),
+    ],
+};
+
+static MUTATION: ObjectType = ObjectType {
+    name: "Mutation",
+    fields: &[
+        ("register", Some(&USER)),
+        ("update_user", Some(&USER)),
+        ("delete_user", None),
+    ],
+};
+
+/// A GraphQL request, as sent by GraphQL clients.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GraphQLRequest {
+    pub query: String,
+    /// Which operation to run when the document holds several.
+    #[serde(default, rename = "operationName")]
+    pub operation_name: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub variables: Option<Map<String, Json>>,
+}
+
+#[derive(Serialize)]
+struct GraphQLResponse {
+    // Absent when the request failed before execution started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Json>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<GraphQLError>,
+}
+
+#[derive(Serialize)]
+struct GraphQLError {
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    path: Vec<String>,
+    extensions: Extensions,
+}
+
+// `code` is the one the REST route would put in its problem body.
+#[derive(Serialize)]
+struct Extensions {
+    code: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+impl GraphQLError {
+    fn request(message: String) -> Self {
+        GraphQLError {
+            message,
+            path: Vec::new(),
+            extensions: Extensions {
+                code: "graphql_invalid",
+                errors: Vec::new(),
+            },
+        }
+    }
+}
+
+// Errors a root field can fail with; they null the field rather than the
+// whole response.
+enum ResolveError {
+    Api(ApiError),
+    Auth(AuthError),
+}
+
+impl From<ApiError> for ResolveError {
+    fn from(e: ApiError) -> Self {
+        ResolveError::Api(e)
+    }
+}
+
+impl From<AuthError> for ResolveError {
+    fn from(e: AuthError) -> Self {
+        ResolveError::Auth(e)
+    }
+}
+
+impl ResolveError {
+    fn into_graphql(self, key: &str) -> GraphQLError {
+        let (message, code, errors) = match self {
+            ResolveError::Api(e) => {
+                if e.status_code().is_server_error() {
+                    tracing::error!(code = e.code(), error = %e, field = key, "graphql field failed");
+                }
+                let errors = match &e {
+                    ApiError::Validation(errors) => errors.clone(),
+                    _ => Vec::new(),
+                };
+                (e.public_detail(), e.code(), errors)
+            }
+            ResolveError::Auth(e) => (e.to_string(), e.code(), Vec::new()),
+        };
+        GraphQLError {
+            message,
+            path: vec![key.to_string()],
+            extensions: Extensions { code, errors },
+        }
+    }
+}
+
+// Checks a selection set against `object` before anything runs, so a typo
+// can't leave a mutation half-reported. Only the `root` fields take
+// arguments; those are checked as they are decoded.
+fn check(selection: &[Field], object: &ObjectType, root: bool) -> Result<(), String> {
+    for field in selection {
+        if field.name == "__typename" {
+            continue;
+        }
+        let Some((_, child)) = object.fields.iter().find(|(name, _)| *name == field.name) else {
+            return Err(format!("{} has no field \"{}\"", object.name, field.name));
+        };
+        match child {
+            Some(child) if field.selection.is_empty() => {
+                return Err(format!(
+                    "field \"{}\" of type {} must have a selection of subfields",
+                    field.name, child.name
+                ));
+            }
+            Some(child) => check(&field.selection, child, false)?,
+            None if !field.selection.is_empty() => {
+                return Err(format!("field \"{}\" is a scalar and has no subfields", field.name));
+            }
+            None => {}
+        }
+        if !root && !field.arguments.is_empty() {
+            return Err(format!("field \"{}\" takes no arguments", field.name));
+        }
+    }
+    Ok(())
+}
+
+// Picks the selected fields out of a serialized value of `object`'s type.
+fn project(value: Json, object: &ObjectType, selection: &[Field]) -> Json {
+    match value {
+        Json::Array(items) => Json::Array(
+            items
+                .into_iter()
+                .map(|item| project(item, object, selection))
+                .collect(),
+        ),
+        Json::Object(fields) => {
+            let mut projected = Map::new();
+            for field in selection {
+                let value = if field.name == "__typename" {
+                    Json::String(object.name.to_string())
+                } else {
+                    let value = fields.get(&field.name).cloned().unwrap_or(Json::Null);
+                    match object.fields.iter().find(|(name, _)| *name == field.name) {
+                        Some((_, Some(child))) => project(value, child, &field.selection),
+                        _ => value,
+                    }
+                };
+                projected.insert(field.response_key().to_string(), value);
+            }
+            Json::Object(projected)
+        }
+        other => other,
+    }
+}
+
+// Substitutes variables into an argument value.
+fn resolve(value: &Value, variables: &Map<String, Json>) -> Result<Json, String> {
+    Ok(match value {
+        Value::Variable(name) => variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("variable ${} is not defined", name))?,
+        Value::Int(value) => Json::from(*value),
+        Value::Float(value) => Json::from(*value),
+        Value::String(value) | Value::Enum(value) => Json::String(value.clone()),
+        Value::Boolean(value) => Json::Bool(*value),
+        Value::Null => Json::Null,
+        Value::List(items) => Json::Array(
+            items
+                .iter()
+                .map(|item| resolve(item, variables))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Object(fields) => Json::Object(
+            fields
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), resolve(value, variables)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+    })
+}
+
+// The values of the operation's variables, defaults applied.
+fn variables(
+    operation: &Operation,
+    mut provided: Map<String, Json>,
+) -> Result<Map<String, Json>, String> {
+    let mut values = Map::new();
+    for definition in &operation.variables {
+        let value = match provided.remove(&definition.name) {
+            Some(value) => value,
+            None => match &definition.default {
+                Some(default) => resolve(default, &Map::new())?,
+                None => Json::Null,
+            },
+        };
+        if definition.required && value.is_null() {
+            return Err(format!("variable ${} is required", definition.name));
+        }
+        values.insert(definition.name.clone(), value);
+    }
+    Ok(values)
+}
+
+// Decodes a field's arguments into `T`, like a REST body or query string.
+fn arguments<T: DeserializeOwned>(field: &Field, variables: &Map<String, Json>) -> Result<T, ApiError> {
+    let mut values = Map::new();
+    for (name, value) in &field.arguments {
+        let value = resolve(value, variables).map_err(ApiError::BadRequest)?;
+        values.insert(name.clone(), value);
+    }
+    serde_json::from_value(Json::Object(values)).map_err(|e| {
+        ApiError::BadRequest(format!("Invalid arguments to {}: {}", field.name, e))
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IdArgs {
+    id: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmailArgs {
+    email: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PhoneArgs {
+    phone: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterArgs {
+    input: NewUser,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateArgs {
+    id: Uuid,
+    input: UpdateUser,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteArgs {
+    id: Uuid,
+    hard: Option<bool>,
+}
+
+struct Context<'a> {
+    req: &'a HttpRequest,
+    data: &'a AppState,
+    variables: Map<String, Json>,
+    // Resolved on the first field that needs it.
+    subject: Option<Option<Subject>>,
+}
+
+impl Context<'_> {
+    // Authenticates like `auth::require_jwt_or_api_key` and checks the caller
+    // against `allowed`.
+    async fn authorize(
+        &mut self,
+        allowed: impl FnOnce(&Subject) -> bool,
+        reason: &str,
+    ) -> Result<&Subject, AuthError> {
+        if self.subject.is_none() {
+            self.subject = Some(auth::authenticate(self.req).await?);
+        }
+        let subject = self.subject.as_ref().and_then(Option::as_ref);
+        auth::authorize(subject, allowed, reason)
+    }
+
+    async fn resolve_field(
+        &mut self,
+        kind: OperationKind,
+        field: &Field,
+    ) -> Result<Json, ResolveError> {
+        let data = self.data;
+        let value = match (kind, field.name.as_str()) {
+            (_, "__typename") => {
+                let root = match kind {
+                    OperationKind::Query => &QUERY,
+                    OperationKind::Mutation => &MUTATION,
+                };
+                return Ok(Json::String(root.name.to_string()));
+            }
+            (OperationKind::Query, "user") => {
+                let args: IdArgs = arguments(field, &self.variables)?;
+                let (user, _) = users::get(data, args.id, false).await?;
+                to_json(&user)?
+            }
+            (OperationKind::Query, "user_by_email") => {
+                let args: EmailArgs = arguments(field, &self.variables)?;
+                to_json(&users::by_email(data, &args.email).await?)?
+            }
+            (OperationKind::Query, "user_by_phone") => {
+                let args: PhoneArgs = arguments(field, &self.variables)?;
+                to_json(&users::by_phone(data, &args.phone).await?)?
+            }
+            (OperationKind::Query, "users") => {
+                let params: ListUsersQuery = arguments(field, &self.variables)?;
+                to_json(&users::list(data, &params, false).await?.page)?
+            }
+            (OperationKind::Mutation, "register") => {
+                let args: RegisterArgs = arguments(field, &self.variables)?;
+                let (user, _) = users::register(data, args.input, false).await?;
+                to_json(&user)?
+            }
+            (OperationKind::Mutation, "update_user") => {
+                let args: UpdateArgs = arguments(field, &self.variables)?;
+                let actor = self
+                    .authorize(
+                        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(args.id),
+                        "users may only update their own record unless they have the admin role",
+                    )
+                    .await?
+                    .id
+                    .clone();
+                let (user, _) =
+                    audit::acting_as(&actor, users::update(data, args.id, args.input, None, false)).await?;
+                tracing::info!(user_id = %args.id, actor, "user updated");
+                to_json(&user)?
+            }
+            (OperationKind::Mutation, "delete_user") => {
+                let args: DeleteArgs = arguments(field, &self.variables)?;
+                let actor = self
+                    .authorize(
+                        |subject| subject.has_role(ADMIN_ROLE),
+                        "this endpoint requires the admin role",
+                    )
+                    .await?
+                    .id
+                    .clone();
+                let hard = args.hard.unwrap_or(false);
+                audit::acting_as(&actor, users::delete(data, args.id, hard, None, false)).await?;
+                tracing::info!(user_id = %args.id, hard, actor, "user deleted");
+                Json::Bool(true)
+            }
+            _ => {
+                return Err(ApiError::BadRequest(format!("unknown field {}", field.name)).into());
+            }
+        };
+        Ok(value)
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Result<Json, ApiError> {
+    serde_json::to_value(value).map_err(|e| ApiError::internal("Failed to serialize result", e))
+}
+
+fn request_error(message: String) -> HttpResponse {
+    HttpResponse::Ok().json(GraphQLResponse {
+        data: None,
+        errors: vec![GraphQLError::request(message)],
+    })
+}
+
+fn root_type(kind: OperationKind) -> &'static ObjectType {
+    match kind {
+        OperationKind::Query => &QUERY,
+        OperationKind::Mutation => &MUTATION,
+    }
+}
+
+// Parses the request and picks the operation to run, checked against the
+// schema, with its variables' values.
+fn plan(request: GraphQLRequest) -> Result<(Operation, Map<String, Json>), String> {
+    let mut operations =
+        parser::parse(&request.query).map_err(|e| format!("Syntax error: {}", e))?;
+    let operation = match &request.operation_name {
+        Some(name) => operations
+            .iter()
+            .position(|operation| operation.name.as_deref() == Some(name.as_str()))
+            .map(|index| operations.swap_remove(index))
+            .ok_or_else(|| format!("no operation named \"{}\"", name))?,
+        None if operations.len() == 1 => operations.remove(0),
+        None => {
+            return Err(String::from(
+                "operationName is required when the document has several operations",
+            ));
+        }
+    };
+    if operation.selection.len() > MAX_ROOT_FIELDS {
+        return Err(format!(
+            "an operation may select at most {} root fields",
+            MAX_ROOT_FIELDS
+        ));
+    }
+    check(&operation.selection, root_type(operation.kind), true)?;
+    let variables = variables(&operation, request.variables.unwrap_or_default())?;
+    Ok((operation, variables))
+}
+
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    request_body = GraphQLRequest,
+    security((), ("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "GraphQL response with `data` and/or `errors`; see GET /graphql for the schema", body = Object),
+    )
+)]
+pub async fn execute(
+    req: HttpRequest,
+    Body(request): Body<GraphQLRequest>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let (operation, variables) = match plan(request) {
+        Ok(planned) => planned,
+        Err(e) => return request_error(e),
+    };
+    let root = root_type(operation.kind);
+
+    // Root fields run one after another, which the spec requires of
+    // mutations; queries have at most a handful of fields.
+    let mut context = Context {
+        req: &req,
+        data: &data,
+        variables,
+        subject: None,
+    };
+    let mut result = Map::new();
+    let mut errors = Vec::new();
+    for field in &operation.selection {
+        let value = match context.resolve_field(operation.kind, field).await {
+            Ok(value) => match root.fields.iter().find(|(name, _)| *name == field.name) {
+                Some((_, Some(object))) => project(value, object, &field.selection),
+                _ => value,
+            },
+            Err(e) => {
+                errors.push(e.into_graphql(field.response_key()));
+                Json::Null
+            }
+        };
+        result.insert(field.response_key().to_string(), value);
+    }
+    HttpResponse::Ok().json(GraphQLResponse {
+        data: Some(Json::Object(result)),
+        errors,
+    })
+}
+
+// GET /graphql
+pub async fn get_schema() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(SCHEMA)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(query: &str, operation_name: Option<&str>, variables: Json) -> GraphQLRequest {
+        GraphQLRequest {
+            query: query.to_string(),
+            operation_name: operation_name.map(String::from),
+            variables: variables.as_object().cloned(),
+        }
+    }
+
+    fn selection(query: &str) -> Vec<Field> {
+        parser::parse(query).unwrap().remove(0).selection
+    }
+
+    #[test]
+    fn plan_picks_the_named_operation() {
+        let document = "query A { user(id: $id) { id } } mutation B { delete_user(id: \"x\") }";
+        let (operation, _) = plan(request(document, Some("B"), Json::Null)).unwrap();
+        assert_eq!(operation.kind, OperationKind::Mutation);
+        assert_eq!(
+            plan(request(document, None, Json::Null)).unwrap_err(),
+            "operationName is required when the document has several operations"
+        );
+        assert_eq!(
+            plan(request(document, Some("C"), Json::Null)).unwrap_err(),
+            "no operation named \"C\""
+        );
+        assert!(plan(request("{ user(", None, Json::Null)).unwrap_err().starts_with("Syntax error: "));
+    }
+
+    #[test]
+    fn plan_bounds_root_fields() {
+        let fields = |count: usize| {
+            let aliases: Vec<_> = (0..count)
+                .map(|i| format!("u{}: user(id: \"{}\") {{ id }}", i, i))
+                .collect();
+            format!("{{ {} }}", aliases.join(" "))
+        };
+        assert!(plan(request(&fields(MAX_ROOT_FIELDS), None, Json::Null)).is_ok());
+        assert_eq!(
+            plan(request(&fields(MAX_ROOT_FIELDS + 1), None, Json::Null)).unwrap_err(),
+            "an operation may select at most 10 root fields"
+        );
+    }
+
+    #[test]
+    fn check_follows_the_schema() {
+        let check_query = |query: &str| check(&selection(query), &QUERY, true);
+        assert!(check_query("{ users { users { id profile { bio } __typename } next_cursor } }").is_ok());
+        assert_eq!(check_query("{ posts { id } }").unwrap_err(), "Query has no field \"posts\"");
+        assert_eq!(
+            check_query("{ user(id: \"1\") }").unwrap_err(),
+            "field \"user\" of type User must have a selection of subfields"
+        );
+        assert_eq!(
+            check_query("{ user(id: \"1\") { id { x } } }").unwrap_err(),
+            "field \"id\" is a scalar and has no subfields"
+        );
+        assert_eq!(
+            check_query("{ user(id: \"1\") { profile(size: 1) { bio } } }").unwrap_err(),
+            "field \"profile\" takes no arguments"
+        );
+        assert!(check(&selection("mutation { delete_user(id: \"1\") }"), &MUTATION, true).is_ok());
+    }
+
+    #[test]
+    fn project_keeps_the_selection_under_its_keys() {
+        let page = json!({
+            "users": [{"id": "1", "name": "Ada", "email": "ada@example.com", "profile": {"bio": "hi", "locale": "en"}}],
+            "next_cursor": "c",
+        });
+        let fields = selection("{ users { people: users { id __typename profile { bio } } } }");
+        assert_eq!(
+            project(page, &USERS_PAGE, &fields[0].selection),
+            json!({"people": [{"id": "1", "__typename": "User", "profile": {"bio": "hi"}}]})
+        );
+        let fields = selection("{ user { profile { bio } } }");
+        assert_eq!(
+            project(json!({"id": "1", "profile": null}), &USER, &fields[0].selection),
+            json!({"profile": null})
+        );
+    }
+
+    #[test]
+    fn variables_apply_defaults_and_requirements() {
+        let operation = |query: &str| parser::parse(query).unwrap().remove(0);
+        let defined = operation("query ($id: ID!, $limit: Int = 5, $name: String) { users { next_cursor } }");
+        let values = variables(&defined, json!({"id": "1"}).as_object().cloned().unwrap()).unwrap();
+        assert_eq!(Json::Object(values), json!({"id": "1", "limit": 5, "name": null}));
+        assert_eq!(
+            variables(&defined, Map::new()).unwrap_err(),
+            "variable $id is required"
+        );
+        assert_eq!(
+            variables(&defined, json!({"id": null}).as_object().cloned().unwrap()).unwrap_err(),
+            "variable $id is required"
+        );
+    }
+
+    #[test]
+    fn resolve_substitutes_variables() {
+        let fields = selection(r#"{ f(a: {list: [$x, 1, 2.5, "s", asc, true, null]}) }"#);
+        let variables = json!({"x": {"nested": 1}}).as_object().cloned().unwrap();
+        assert_eq!(
+            resolve(&fields[0].arguments[0].1, &variables).unwrap(),
+            json!({"list": [{"nested": 1}, 1, 2.5, "s", "asc", true, null]})
+        );
+        let fields = selection("{ f(a: $missing) }");
+        assert_eq!(
+            resolve(&fields[0].arguments[0].1, &Map::new()).unwrap_err(),
+            "variable $missing is not defined"
+        );
+    }
+
+    #[test]
+    fn arguments_decode_like_a_rest_body() {
+        let fields = selection("{ user(id: \"not-a-uuid\") { id } }");
+        assert!(arguments::<IdArgs>(&fields[0], &Map::new()).is_err());
+        let id = Uuid::new_v4();
+        let fields = selection(&format!("{{ user(id: \"{}\") {{ id }} }}", id));
+        assert_eq!(arguments::<IdArgs>(&fields[0], &Map::new()).unwrap().id, id);
+        let fields = selection("{ user(id: \"1\", extra: 1) { id } }");
+        assert!(arguments::<IdArgs>(&fields[0], &Map::new()).is_err());
+    }
+}
@@ -0,0 +1,591 @@
+// A recursive-descent parser for the subset of GraphQL that /graphql
+// executes: query and mutation operations with variables, and fields with
+// aliases, arguments and nested selection sets. Fragments, directives,
+// subscriptions and block strings are refused with an error naming them.
+// Nesting is bounded by MAX_DEPTH, so a hostile document can't exhaust the
+// stack of the worker parsing it.
+
+// Deepest nesting of selection sets, list and object values, and list types,
+// counted together. The schema needs 4 levels of selection.
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Value)>,
+    pub selection: Vec<Field>,
+}
+
+impl Field {
+    // The key the field's value is returned under.
+    pub fn response_key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+}
+
+#[derive(Debug)]
+pub struct VariableDefinition {
+    pub name: String,
+    // Declared with a non-null type and no default.
+    pub required: bool,
+    pub default: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub name: Option<String>,
+    pub variables: Vec<VariableDefinition>,
+    pub selection: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    End,
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Punct(c) => format!("\"{}\"", c),
+        Token::Spread => String::from("\"...\""),
+        Token::Name(name) => format!("\"{}\"", name),
+        Token::Int(value) => value.to_string(),
+        Token::Float(value) => value.to_string(),
+        Token::Str(_) => String::from("a string"),
+        Token::End => String::from("the end of the document"),
+    }
+}
+
+// "line L, column C" of a byte offset, for error messages.
+fn location(source: &str, offset: usize) -> String {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |tail| tail.chars().count()) + 1;
+    format!("line {}, column {}", line, column)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        match c {
+            b' ' | b'\t' | b'\n' | b'\r' | b',' => i += 1,
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'!' | b'$' | b'(' | b')' | b':' | b'=' | b'@' | b'[' | b']' | b'{' | b'}' | b'|' => {
+                tokens.push((Token::Punct(c as char), start));
+                i += 1;
+            }
+            b'.' => {
+                if !source[i..].starts_with("...") {
+                    return Err(format!("unexpected \".\" at {}", location(source, start)));
+                }
+                tokens.push((Token::Spread, start));
+                i += 3;
+            }
+            b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                while i < bytes.len() && (bytes[i] == b'_' || bytes[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push((Token::Name(source[start..i].to_string()), start));
+            }
+            b'-' | b'0'..=b'9' => {
+                i += 1;
+                let mut float = false;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'0'..=b'9' => i += 1,
+                        b'.' | b'e' | b'E' => {
+                            float = true;
+                            i += 1;
+                        }
+                        b'+' | b'-' if matches!(bytes[i - 1], b'e' | b'E') => i += 1,
+                        _ => break,
+                    }
+                }
+                let text = &source[start..i];
+                let invalid = || format!("invalid number \"{}\" at {}", text, location(source, start));
+                let token = if float {
+                    Token::Float(text.parse().map_err(|_| invalid())?)
+                } else {
+                    Token::Int(text.parse().map_err(|_| invalid())?)
+                };
+                tokens.push((token, start));
+            }
+            b'"' => {
+                if source[i..].starts_with("\"\"\"") {
+                    return Err(format!(
+                        "block strings are not supported (at {})",
+                        location(source, start)
+                    ));
+                }
+                let (value, end) = string(source, i + 1)
+                    .map_err(|e| format!("{} at {}", e, location(source, start)))?;
+                tokens.push((Token::Str(value), start));
+                i = end;
+            }
+            _ => {
+                let c = source[i..].chars().next().unwrap_or('?');
+                if c == '\u{feff}' {
+                    i += c.len_utf8();
+                    continue;
+                }
+                return Err(format!("unexpected \"{}\" at {}", c, location(source, start)));
+            }
+        }
+    }
+    tokens.push((Token::End, source.len()));
+    Ok(tokens)
+}
+
+// A string literal starting after its opening quote at `start`; returns the
+// value and the offset past the closing quote.
+fn string(source: &str, start: usize) -> Result<(String, usize), String> {
+    let mut value = String::new();
+    let mut chars = source[start..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, start + offset + 1)),
+            '\n' | '\r' => break,
+            '\\' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid unicode escape \"\\u{}\"", hex))?
+                    }
+                    _ => return Err(String::from("invalid escape sequence")),
+                };
+                value.push(escaped);
+            }
+            _ => value.push(c),
+        }
+    }
+    Err(String::from("unterminated string"))
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn unexpected(&self) -> String {
+        let (token, offset) = &self.tokens[self.position];
+        format!(
+            "unexpected {} at {}",
+            describe(token),
+            location(self.source, *offset)
+        )
+    }
+
+    fn unsupported(&self, what: &str) -> String {
+        let offset = self.tokens[self.position].1;
+        format!("{} are not supported (at {})", what, location(self.source, offset))
+    }
+
+    // Runs `parse` one level deeper, refusing to go past MAX_DEPTH.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth == MAX_DEPTH {
+            let offset = self.tokens[self.position].1;
+            return Err(format!(
+                "the document is nested more than {} levels deep (at {})",
+                MAX_DEPTH,
+                location(self.source, offset)
+            ));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if *self.peek() == Token::Punct(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Token::Name(name) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn directives(&self) -> Result<(), String> {
+        if *self.peek() == Token::Punct('@') {
+            return Err(self.unsupported("directives"));
+        }
+        Ok(())
+    }
+
+    fn document(&mut self) -> Result<Vec<Operation>, String> {
+        let mut operations = Vec::new();
+        while *self.peek() != Token::End {
+            operations.push(self.operation()?);
+        }
+        if operations.is_empty() {
+            return Err(String::from("the document contains no operations"));
+        }
+        Ok(operations)
+    }
+
+    fn operation(&mut self) -> Result<Operation, String> {
+        // `{ ... }` is shorthand for an anonymous query.
+        if *self.peek() == Token::Punct('{') {
+            return Ok(Operation {
+                kind: OperationKind::Query,
+                name: None,
+                variables: Vec::new(),
+                selection: self.selection_set()?,
+            });
+        }
+        let kind = match self.peek() {
+            Token::Name(keyword) if keyword == "query" => OperationKind::Query,
+            Token::Name(keyword) if keyword == "mutation" => OperationKind::Mutation,
+            Token::Name(keyword) if keyword == "subscription" => {
+                return Err(self.unsupported("subscriptions"));
+            }
+            Token::Name(keyword) if keyword == "fragment" => {
+                return Err(self.unsupported("fragments"));
+            }
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+        let name = match self.peek() {
+            Token::Name(_) => Some(self.name()?),
+            _ => None,
+        };
+        let variables = self.variable_definitions()?;
+        self.directives()?;
+        Ok(Operation {
+            kind,
+            name,
+            variables,
+            selection: self.selection_set()?,
+        })
+    }
+
+    fn variable_definitions(&mut self) -> Result<Vec<VariableDefinition>, String> {
+        let mut definitions = Vec::new();
+        if !self.eat('(') {
+            return Ok(definitions);
+        }
+        while !self.eat(')') {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let non_null = self.type_reference()?;
+            let default = if self.eat('=') {
+                Some(self.value(true)?)
+            } else {
+                None
+            };
+            self.directives()?;
+            definitions.push(VariableDefinition {
+                name,
+                required: non_null && default.is_none(),
+                default,
+            });
+        }
+        Ok(definitions)
+    }
+
+    // Skips over a type such as `[ID!]!`; returns whether the outer type is
+    // non-null. Argument types are checked when the values are decoded.
+    fn type_reference(&mut self) -> Result<bool, String> {
+        if self.eat('[') {
+            self.nested(Self::type_reference)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        Ok(self.eat('!'))
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Field>, String> {
+        self.nested(Self::fields)
+    }
+
+    fn fields(&mut self) -> Result<Vec<Field>, String> {
+        self.expect('{')?;
+        // An empty selection set is reported at its closing brace.
+        if *self.peek() == Token::Punct('}') {
+            return Err(self.unexpected());
+        }
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            if *self.peek() == Token::Spread {
+                return Err(self.unsupported("fragments"));
+            }
+            fields.push(self.field()?);
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self) -> Result<Field, String> {
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let mut arguments = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let argument = self.name()?;
+                self.expect(':')?;
+                arguments.push((argument, self.value(false)?));
+            }
+        }
+        self.directives()?;
+        let selection = if *self.peek() == Token::Punct('{') {
+            self.selection_set()?
+        } else {
+            Vec::new()
+        };
+        Ok(Field {
+            alias,
+            name,
+            arguments,
+            selection,
+        })
+    }
+
+    // A value; `constant` ones (variable defaults) may not reference variables.
+    fn value(&mut self, constant: bool) -> Result<Value, String> {
+        if !constant && self.eat('$') {
+            return Ok(Value::Variable(self.name()?));
+        }
+        if self.eat('[') {
+            return self.nested(|parser| {
+                let mut items = Vec::new();
+                while !parser.eat(']') {
+                    items.push(parser.value(constant)?);
+                }
+                Ok(Value::List(items))
+            });
+        }
+        if self.eat('{') {
+            return self.nested(|parser| {
+                let mut fields = Vec::new();
+                while !parser.eat('}') {
+                    let name = parser.name()?;
+                    parser.expect(':')?;
+                    fields.push((name, parser.value(constant)?));
+                }
+                Ok(Value::Object(fields))
+            });
+        }
+        let value = match self.peek() {
+            Token::Int(value) => Value::Int(*value),
+            Token::Float(value) => Value::Float(*value),
+            Token::Str(value) => Value::String(value.clone()),
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                "null" => Value::Null,
+                _ => Value::Enum(name.clone()),
+            },
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+        Ok(value)
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Operation>, String> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source)?,
+        position: 0,
+        depth: 0,
+    };
+    parser.document()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(source: &str) -> Operation {
+        let mut operations = parse(source).unwrap();
+        assert_eq!(operations.len(), 1);
+        operations.remove(0)
+    }
+
+    #[test]
+    fn shorthand_queries_are_anonymous() {
+        let operation = parse_one("{ user(id: \"1\") { id name } }");
+        assert_eq!(operation.kind, OperationKind::Query);
+        assert_eq!(operation.name, None);
+        let field = &operation.selection[0];
+        assert_eq!(field.name, "user");
+        assert_eq!(field.arguments, [(String::from("id"), Value::String(String::from("1")))]);
+        let names: Vec<_> = field.selection.iter().map(|field| field.name.as_str()).collect();
+        assert_eq!(names, ["id", "name"]);
+    }
+
+    #[test]
+    fn operations_carry_names_variables_and_aliases() {
+        let operation = parse_one(
+            "# comment\n\
+             mutation Rename($id: ID!, $name: String = \"Ada\", $tags: [String!]) {\n\
+               renamed: update_user(id: $id, input: {name: $name}) { id }\n\
+             }",
+        );
+        assert_eq!(operation.kind, OperationKind::Mutation);
+        assert_eq!(operation.name.as_deref(), Some("Rename"));
+        let variables: Vec<_> = operation
+            .variables
+            .iter()
+            .map(|variable| (variable.name.as_str(), variable.required, variable.default.clone()))
+            .collect();
+        assert_eq!(
+            variables,
+            [
+                ("id", true, None),
+                ("name", false, Some(Value::String(String::from("Ada")))),
+                ("tags", false, None),
+            ]
+        );
+        let field = &operation.selection[0];
+        assert_eq!(field.response_key(), "renamed");
+        assert_eq!(field.name, "update_user");
+        assert_eq!(
+            field.arguments[1].1,
+            Value::Object(vec![(String::from("name"), Value::Variable(String::from("name")))])
+        );
+    }
+
+    #[test]
+    fn values_of_every_kind_are_parsed() {
+        let operation = parse_one(
+            r#"{ f(a: -12, b: 1.5e3, c: "q\"\u00e9\n", d: true, e: null, f: desc, g: [1, [2]]) { x } }"#,
+        );
+        let values: Vec<_> = operation.selection[0].arguments.iter().map(|(_, value)| value.clone()).collect();
+        assert_eq!(
+            values,
+            [
+                Value::Int(-12),
+                Value::Float(1500.0),
+                Value::String(String::from("q\"é\n")),
+                Value::Boolean(true),
+                Value::Null,
+                Value::Enum(String::from("desc")),
+                Value::List(vec![Value::Int(1), Value::List(vec![Value::Int(2)])]),
+            ]
+        );
+    }
+
+    #[test]
+    fn several_operations_can_share_a_document() {
+        let operations = parse("query A { a } query B { b }").unwrap();
+        let names: Vec<_> = operations.iter().map(|operation| operation.name.as_deref()).collect();
+        assert_eq!(names, [Some("A"), Some("B")]);
+    }
+
+    #[test]
+    fn unsupported_syntax_is_named() {
+        let error = |source: &str| parse(source).unwrap_err();
+        assert!(error("{ ...Fields }").starts_with("fragments are not supported"));
+        assert!(error("fragment F on User { id }").starts_with("fragments are not supported"));
+        assert!(error("subscription { users }").starts_with("subscriptions are not supported"));
+        assert!(error("{ user @skip(if: true) { id } }").starts_with("directives are not supported"));
+        assert!(error("{ f(a: \"\"\"block\"\"\") }").starts_with("block strings are not supported"));
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_problem() {
+        assert_eq!(parse("").unwrap_err(), "the document contains no operations");
+        assert_eq!(parse("{ }").unwrap_err(), "unexpected \"}\" at line 1, column 3");
+        assert_eq!(parse("{\n  user(id: ) }").unwrap_err(), "unexpected \")\" at line 2, column 12");
+        assert_eq!(parse("{ f(a: \"open) }").unwrap_err(), "unterminated string at line 1, column 8");
+        assert_eq!(parse("{ f(a: 1.2.3) }").unwrap_err(), "invalid number \"1.2.3\" at line 1, column 8");
+        assert_eq!(parse("{ a } ;").unwrap_err(), "unexpected \";\" at line 1, column 7");
+        assert!(parse("query ($a: Int = $b) { f }").is_err());
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        let selections = |depth: usize| format!("{}{}", "{ a ".repeat(depth), "}".repeat(depth));
+        assert!(parse(&selections(MAX_DEPTH)).is_ok());
+        assert!(parse(&selections(MAX_DEPTH + 1)).unwrap_err().starts_with("the document is nested"));
+
+        let lists = |depth: usize| format!("{{ f(a: {}{}) }}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&lists(MAX_DEPTH - 1)).is_ok());
+        assert!(parse(&lists(MAX_DEPTH)).unwrap_err().starts_with("the document is nested"));
+
+        let types = |depth: usize| format!("query ($a: {}Int{}) {{ f }}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&types(MAX_DEPTH)).is_ok());
+        assert!(parse(&types(MAX_DEPTH + 1)).unwrap_err().starts_with("the document is nested"));
+
+        // Far past any stack a worker has.
+        let hostile = format!("{{ f(a: {}) }}", "[".repeat(1_000_000));
+        assert!(parse(&hostile).is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use crate::auth::{self, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, Problem};
+use crate::models::{Group, GroupMember, GroupsQuery, NewGroup, UpdateGroup};
+use crate::negotiate::Body;
+use crate::observe;
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use crate::validation;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use scylla::batch::{Batch, BatchType};
+use scylla::SerializeRow;
+use uuid::Uuid;
+
+// Groups gather users, such as the members of a team. Admins create, rename
+// and delete them with /groups and add and remove members with
+// /groups/{id}/members/{user_id}; anyone signed in can read them, and a user
+// can list the groups they're in with GET /users/{id}/groups.
+//
+// A membership is a row in `group_members`, by group, and one in
+// `user_groups`, by user (migration 0030). Both are written or removed in
+// one logged batch, so either lookup sees the same memberships. Deleting a
+// group removes its memberships after the group row, and purging a user
+// removes theirs; a membership of a group deleted meanwhile is left out of
+// the user's groups.
+
+// A `group_members` or `user_groups` row: both tables take the same columns.
+#[derive(SerializeRow)]
+struct Membership {
+    group_id: Uuid,
+    user_id: Uuid,
+    added_at: DateTime<Utc>,
+}
+
+fn not_found(group_id: Uuid) -> ApiError {
+    ApiError::NotFound(format!("Group {} not found", group_id))
+}
+
+// The group with `group_id`, if there is one.
+async fn stored(data: &AppState, group_id: Uuid) -> Result<Option<Group>, ApiError> {
+    let result = observe::query(data, "select_group", || {
+        data.session
+            .execute_unpaged(&data.statements.select_group, (group_id,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read group", e))?
+        .maybe_first_row::<Group>()
+        .map_err(|e| ApiError::internal("Failed to read group", e))
+}
+
+// The ids of the groups `user_id` is in.
+async fn group_ids(data: &AppState, user_id: Uuid) -> Result<Vec<Uuid>, ApiError> {
+    let result = observe::query(data, "select_user_groups", || {
+        data.session
+            .execute_unpaged(&data.statements.select_user_groups, (user_id,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read groups", e))?
+        .rows::<(Uuid,)>()
+        .map_err(|e| ApiError::internal("Failed to read groups", e))?
+        .map(|row| row.map(|(group_id,)| group_id))
+        .collect::<Result<_, _>>()
+        .map_err(|e| ApiError::internal("Failed to read groups", e))
+}
+
+// Removes the memberships `group_id` has with each of `user_ids`, both ways,
+// `http.bulk_concurrency` users at a time. Returns how many failed.
+async fn unlink(data: &AppState, group_id: Uuid, user_ids: Vec<Uuid>) -> usize {
+    stream::iter(user_ids)
+        .map(|user_id| async move {
+            let mut batch = Batch::new(BatchType::Logged);
+            batch.append_statement(data.statements.delete_group_member.clone());
+            batch.append_statement(data.statements.delete_user_group.clone());
+            let values = ((group_id, user_id), (user_id, group_id));
+            let result = observe::query(data, "delete_membership", || {
+                data.session.batch(&batch, &values)
+            })
+            .await;
+            if let Err(e) = &result {
+                tracing::warn!(%group_id, %user_id, error = %e, "failed to remove membership");
+            }
+            result.is_err()
+        })
+        .buffer_unordered(data.bulk_concurrency)
+        .filter(|failed| future::ready(*failed))
+        .count()
+        .await
+}
+
+// Removes the user's memberships, for a user being purged. Failures are
+// logged rather than failing the purge.
+pub async fn remove(data: &AppState, user_id: Uuid) {
+    let group_ids = match group_ids(data, user_id).await {
+        Ok(group_ids) => group_ids,
+        Err(e) => {
+            tracing::warn!(%user_id, error = %e, "failed to look up groups");
+            return;
+        }
+    };
+    for group_id in group_ids {
+        unlink(data, group_id, vec![user_id]).await;
+    }
+}
+
+/// Creates a group.
+#[utoipa::path(
+    post,
+    path = "/groups",
+    request_body = NewGroup,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 201, description = "The group", body = Group),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 422, description = "Invalid name or description", body = Problem),
+    )
+)]
+pub async fn create_group(
+    Body(new_group): Body<NewGroup>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let NewGroup { name, description } = validation::new_group(new_group)?;
+    let now = Utc::now();
+    let group = Group {
+        id: Uuid::new_v4(),
+        name,
+        description,
+        created_at: Some(now),
+        updated_at: Some(now),
+    };
+    observe::query(&data, "insert_group", || {
+        data.session.execute_unpaged(&data.statements.insert_group, &group)
+    })
+    .await?;
+    tracing::info!(group_id = %group.id, name = %group.name, "group created");
+    Ok(HttpResponse::Created().json(group))
+}
+
+/// Lists groups, by name, up to `limit` of them.
+#[utoipa::path(
+    get,
+    path = "/groups",
+    params(GroupsQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The groups", body = [Group]),
+        (status = 400, description = "Invalid limit", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+    )
+)]
+pub async fn list_groups(
+    params: web::Query<GroupsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (limit, truncated) = users::page_limit(&data, params.limit)?;
+    let fetch = i32::try_from(limit).unwrap_or(i32::MAX);
+    let result = observe::query(&data, "select_groups", || {
+        data.session
+            .execute_unpaged(&data.statements.select_groups, (fetch,))
+    })
+    .await?;
+    let mut groups = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read groups", e))?
+        .rows::<Group>()
+        .map_err(|e| ApiError::internal("Failed to read groups", e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Failed to read groups", e))?;
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(users::with_truncated(HttpResponse::Ok().json(groups), truncated))
+}
+
+/// Returns a group.
+#[utoipa::path(
+    get,
+    path = "/groups/{id}",
+    params(("id" = Uuid, Path, description = "Group id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The group", body = Group),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 404, description = "No such group", body = Problem),
+    )
+)]
+pub async fn get_group(
+    group_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let group_id = group_id.into_inner();
+    let group = stored(&data, group_id).await?.ok_or_else(|| not_found(group_id))?;
+    Ok(HttpResponse::Ok().json(group))
+}
+
+/// Renames a group or changes its description.
+#[utoipa::path(
+    patch,
+    path = "/groups/{id}",
+    params(("id" = Uuid, Path, description = "Group id")),
+    request_body = UpdateGroup,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The group, changed", body = Group),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such group", body = Problem),
+        (status = 422, description = "Invalid name or description", body = Problem),
+    )
+)]
+pub async fn update_group(
+    group_id: web::Path<Uuid>,
+    Body(update): Body<UpdateGroup>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let group_id = group_id.into_inner();
+    let UpdateGroup { name, description } = validation::update_group(update)?;
+    let before = stored(&data, group_id).await?.ok_or_else(|| not_found(group_id))?;
+    let group = Group {
+        name: name.unwrap_or(before.name),
+        description: match description {
+            Some(description) => Some(description).filter(|d| !d.is_empty()),
+            None => before.description,
+        },
+        updated_at: Some(Utc::now()),
+        ..before
+    };
+    let values = (&group.name, &group.description, group.updated_at, group_id);
+    let result = observe::conditional(&data, "update_group", || {
+        data.session.execute_unpaged(&data.statements.update_group, values)
+    })
+    .await?;
+    let applied = statements::applied(result)
+        .map_err(|e| ApiError::internal("Failed to update group", e))?;
+    if !applied {
+        return Err(not_found(group_id));
+    }
+    tracing::info!(%group_id, name = %group.name, "group updated");
+    Ok(HttpResponse::Ok().json(group))
+}
+
+/// Deletes a group and its memberships.
+#[utoipa::path(
+    delete,
+    path = "/groups/{id}",
+    params(("id" = Uuid, Path, description = "Group id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 204, description = "The group was deleted"),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such group", body = Problem),
+    )
+)]
+pub async fn delete_group(
+    group_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let group_id = group_id.into_inner();
+    let result = observe::conditional(&data, "delete_group", || {
+        data.session.execute_unpaged(&data.statements.delete_group, (group_id,))
+    })
+    .await?;
+    let applied = statements::applied(result)
+        .map_err(|e| ApiError::internal("Failed to delete group", e))?;
+    if !applied {
+        return Err(not_found(group_id));
+    }
+    let pager = observe::query(&data, "select_group_members", || {
+        data.session
+            .execute_iter(data.statements.select_group_members.clone(), (group_id,))
+    })
+    .await?;
+    let user_ids: Vec<Uuid> = pager
+        .rows_stream::<GroupMember>()
+        .map_err(|e| ApiError::internal("Error streaming members", e))?
+        .map_ok(|member| member.user_id)
+        .try_collect()
+        .await
+        .map_err(|e| ApiError::internal("Error fetching members", e))?;
+    let members = user_ids.len();
+    let failed = unlink(&data, group_id, user_ids).await;
+    if failed == 0 {
+        observe::query(&data, "delete_group_members", || {
+            data.session
+                .execute_unpaged(&data.statements.delete_group_members, (group_id,))
+        })
+        .await?;
+    }
+    tracing::info!(%group_id, members, failed, "group deleted");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// A group's members, up to `limit` of them, in storage order.
+#[utoipa::path(
+    get,
+    path = "/groups/{id}/members",
+    params(("id" = Uuid, Path, description = "Group id"), GroupsQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The members", body = [GroupMember]),
+        (status = 400, description = "Invalid limit", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 404, description = "No such group", body = Problem),
+    )
+)]
+pub async fn list_members(
+    group_id: web::Path<Uuid>,
+    params: web::Query<GroupsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let group_id = group_id.into_inner();
+    let (limit, truncated) = users::page_limit(&data, params.limit)?;
+    stored(&data, group_id).await?.ok_or_else(|| not_found(group_id))?;
+    let pager = observe::query(&data, "select_group_members", || {
+        data.session
+            .execute_iter(data.statements.select_group_members.clone(), (group_id,))
+    })
+    .await?;
+    let members: Vec<GroupMember> = pager
+        .rows_stream::<GroupMember>()
+        .map_err(|e| ApiError::internal("Error streaming members", e))?
+        .take(limit)
+        .try_collect()
+        .await
+        .map_err(|e| ApiError::internal("Error fetching members", e))?;
+    Ok(users::with_truncated(HttpResponse::Ok().json(members), truncated))
+}
+
+/// Adds a user to a group. Adding a member again changes nothing.
+#[utoipa::path(
+    post,
+    path = "/groups/{id}/members/{user_id}",
+    params(
+        ("id" = Uuid, Path, description = "Group id"),
+        ("user_id" = Uuid, Path, description = "User id"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The membership", body = GroupMember),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such group or user", body = Problem),
+    )
+)]
+pub async fn add_member(
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (group_id, user_id) = path.into_inner();
+    stored(&data, group_id).await?.ok_or_else(|| not_found(group_id))?;
+    if users::stored_user(&data, user_id).await?.is_none() {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)));
+    }
+    if let Some(member) = member(&data, group_id, user_id).await? {
+        return Ok(HttpResponse::Ok().json(member));
+    }
+    let membership = Membership {
+        group_id,
+        user_id,
+        added_at: Utc::now(),
+    };
+    let mut batch = Batch::new(BatchType::Logged);
+    batch.append_statement(data.statements.insert_group_member.clone());
+    batch.append_statement(data.statements.insert_user_group.clone());
+    observe::query(&data, "insert_membership", || {
+        data.session.batch(&batch, (&membership, &membership))
+    })
+    .await?;
+    tracing::info!(%group_id, %user_id, "member added");
+    Ok(HttpResponse::Ok().json(GroupMember {
+        user_id,
+        added_at: Some(membership.added_at),
+    }))
+}
+
+// The membership of `user_id` in `group_id`, if there is one.
+async fn member(
+    data: &AppState,
+    group_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<GroupMember>, ApiError> {
+    let result = observe::query(data, "select_group_member", || {
+        data.session
+            .execute_unpaged(&data.statements.select_group_member, (group_id, user_id))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read membership", e))?
+        .maybe_first_row::<GroupMember>()
+        .map_err(|e| ApiError::internal("Failed to read membership", e))
+}
+
+/// Removes a user from a group.
+#[utoipa::path(
+    delete,
+    path = "/groups/{id}/members/{user_id}",
+    params(
+        ("id" = Uuid, Path, description = "Group id"),
+        ("user_id" = Uuid, Path, description = "User id"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 204, description = "The user was removed"),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "The user isn't in the group", body = Problem),
+    )
+)]
+pub async fn remove_member(
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (group_id, user_id) = path.into_inner();
+    if member(&data, group_id, user_id).await?.is_none() {
+        let message = format!("User {} is not in group {}", user_id, group_id);
+        return Err(ApiError::NotFound(message));
+    }
+    let mut batch = Batch::new(BatchType::Logged);
+    batch.append_statement(data.statements.delete_group_member.clone());
+    batch.append_statement(data.statements.delete_user_group.clone());
+    observe::query(&data, "delete_membership", || {
+        data.session
+            .batch(&batch, ((group_id, user_id), (user_id, group_id)))
+    })
+    .await?;
+    tracing::info!(%group_id, %user_id, "member removed");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The groups the user is in, by name.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/groups",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user's groups", body = [Group]),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn list_user_groups(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    auth::authorize(
+        subject.as_deref(),
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only list their own groups unless they have the admin role",
+    )?;
+    if users::stored_user(&data, user_id).await?.is_none() {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)).into());
+    }
+    let group_ids = group_ids(&data, user_id).await?;
+    let mut groups: Vec<Group> = stream::iter(group_ids)
+        .map(|group_id| stored(&data, group_id))
+        .buffered(data.bulk_concurrency)
+        .try_filter_map(|group| future::ready(Ok(group)))
+        .try_collect()
+        .await?;
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(HttpResponse::Ok().json(groups))
+}
@@ -0,0 +1,622 @@
+use crate::audit;
+use crate::auth::{self, AuthError, JwtAuth, Subject, ADMIN_ROLE};
+use crate::error::ApiError;
+use crate::models::{ListUsersQuery, NewUser, UpdateUser};
+use crate::state::AppState;
+use crate::users;
+use crate::rate_limit::RateLimiter;
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::timeout;
+use actix_web::web;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Either};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{HeaderMap, HeaderValue, Request, Response};
+use std::cell::Cell;
+use std::net::IpAddr;
+use std::pin::pin;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use uuid::Uuid;
+
+mod proto;
+
+// The gRPC service of proto/users.proto, served over plaintext HTTP/2 on its
+// own port for internal callers. It runs the same `users` operations as the
+// REST routes. actix can't send HTTP/2 trailers, which gRPC needs for its
+// status, so the listener speaks HTTP/2 through `h2` directly; connections
+// are served on the main runtime. Calls are rate limited and counted like
+// HTTP requests, and the listener drains along with the HTTP server.
+//
+// It is a stopgap until tonic can be vendored, and is only built with the
+// `grpc` feature.
+
+const SERVICE: &str = "/hireme.users.v1.Users/";
+// Methods of the service, the only values of the metrics' `method` label.
+const METHODS: &[&str] = &["GetUser", "ListUsers", "CreateUser", "UpdateUser", "DeleteUser"];
+// Largest request message accepted, as in most gRPC servers.
+const MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+
+// gRPC status codes used here.
+const OK: u32 = 0;
+const INVALID_ARGUMENT: u32 = 3;
+const NOT_FOUND: u32 = 5;
+const DEADLINE_EXCEEDED: u32 = 4;
+const ALREADY_EXISTS: u32 = 6;
+const PERMISSION_DENIED: u32 = 7;
+const RESOURCE_EXHAUSTED: u32 = 8;
+const FAILED_PRECONDITION: u32 = 9;
+const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+const UNAVAILABLE: u32 = 14;
+const UNAUTHENTICATED: u32 = 16;
+
+#[derive(Debug)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(e: ApiError) -> Self {
+        let code = match e {
+            ApiError::BadRequest(_) | ApiError::Validation(_) | ApiError::UnsupportedMediaType(_) => {
+                INVALID_ARGUMENT
+            }
+            ApiError::NotFound(_) => NOT_FOUND,
+            ApiError::Conflict(_) => ALREADY_EXISTS,
+            ApiError::PreconditionFailed(_) | ApiError::PreconditionRequired(_) => {
+                FAILED_PRECONDITION
+            }
+            ApiError::PayloadTooLarge(_) => RESOURCE_EXHAUSTED,
+            ApiError::DbUnavailable(_) | ApiError::CircuitOpen(_) | ApiError::Overloaded(_) => {
+                UNAVAILABLE
+            }
+            ApiError::Timeout(_) => DEADLINE_EXCEEDED,
+            ApiError::Internal(_) => INTERNAL,
+        };
+        if code == INTERNAL || code == UNAVAILABLE || code == DEADLINE_EXCEEDED {
+            tracing::error!(code = e.code(), error = %e, "grpc call failed");
+        }
+        Status::new(code, e.public_detail())
+    }
+}
+
+impl From<AuthError> for Status {
+    fn from(e: AuthError) -> Self {
+        let code = match e {
+            AuthError::MissingScope(_) | AuthError::Forbidden(_) => PERMISSION_DENIED,
+            AuthError::Unavailable(_) => UNAVAILABLE,
+            _ => UNAUTHENTICATED,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+struct Service {
+    state: AppState,
+    jwt_auth: Option<web::Data<JwtAuth>>,
+    rate_limiter: Option<web::Data<RateLimiter>>,
+}
+
+// Shared by a listener, its connections and its handles.
+struct Listener {
+    stop: watch::Sender<bool>,
+    grace: Duration,
+    // When the first `stop` gave up waiting.
+    deadline: Cell<Option<Instant>>,
+    // Connections still open, so a drain knows when it is done.
+    open: Cell<usize>,
+    closed: Notify,
+}
+
+// Counts one connection as open until dropped.
+struct OpenConnection(Rc<Listener>);
+
+impl OpenConnection {
+    fn new(listener: &Rc<Listener>) -> Self {
+        listener.open.set(listener.open.get() + 1);
+        OpenConnection(Rc::clone(listener))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open.set(self.0.open.get() - 1);
+        self.0.closed.notify_one();
+    }
+}
+
+// Stops a running listener; see `Handle::stop`.
+#[derive(Clone)]
+pub struct Handle(Rc<Listener>);
+
+impl Handle {
+    // Closes the port and sends every connection a GOAWAY, then waits until
+    // calls in flight have finished, at most the grace period after the
+    // first `stop`, like the HTTP server's shutdown timeout. Stopping again
+    // just waits for the same drain.
+    pub async fn stop(&self) {
+        let listener = &self.0;
+        let _ = listener.stop.send(true);
+        let deadline = match listener.deadline.get() {
+            Some(deadline) => deadline,
+            None => {
+                let deadline = Instant::now() + listener.grace;
+                listener.deadline.set(Some(deadline));
+                deadline
+            }
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let drained = timeout(remaining, async {
+            while listener.open.get() > 0 {
+                listener.closed.notified().await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                open = listener.open.get(),
+                "gRPC connections still open after the shutdown grace period"
+            );
+        }
+    }
+}
+
+// Accepts connections on `addr` until the returned handle is stopped; calls
+// then get `grace` to finish.
+pub async fn serve(
+    addr: &str,
+    state: AppState,
+    jwt_auth: Option<web::Data<JwtAuth>>,
+    rate_limiter: Option<web::Data<RateLimiter>>,
+    grace: Duration,
+) -> std::io::Result<Handle> {
+    let socket = TcpListener::bind(addr).await?;
+    tracing::info!(addr, "gRPC listener started");
+    let service = Rc::new(Service {
+        state,
+        jwt_auth,
+        rate_limiter,
+    });
+    let (stop, mut stopped) = watch::channel(false);
+    let listener = Rc::new(Listener {
+        stop,
+        grace,
+        deadline: Cell::new(None),
+        open: Cell::new(0),
+        closed: Notify::new(),
+    });
+    let handle = Handle(Rc::clone(&listener));
+    actix_web::rt::spawn(async move {
+        loop {
+            let accepted = {
+                let accept = pin!(socket.accept());
+                let stop = pin!(stopped.changed());
+                match future::select(accept, stop).await {
+                    Either::Left((accepted, _)) => accepted,
+                    Either::Right(_) => break,
+                }
+            };
+            match accepted {
+                Ok((socket, peer)) => {
+                    let open = OpenConnection::new(&listener);
+                    let connection = serve_connection(socket, peer.ip(), Rc::clone(&service), stopped.clone());
+                    actix_web::rt::spawn(async move {
+                        connection.await;
+                        drop(open);
+                    });
+                }
+                Err(e) => tracing::warn!(error = %e, "failed to accept gRPC connection"),
+            }
+        }
+        tracing::info!("gRPC listener stopped accepting connections");
+    });
+    Ok(handle)
+}
+
+async fn serve_connection(
+    socket: TcpStream,
+    peer: IpAddr,
+    service: Rc<Service>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut connection = match h2::server::handshake(socket).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::debug!(error = %e, "gRPC handshake failed");
+            return;
+        }
+    };
+    // Accepting also drives the connection, including the responses of calls
+    // still running. After a GOAWAY it ends once those calls are done.
+    let mut draining = *stopped.borrow();
+    if draining {
+        connection.graceful_shutdown();
+    }
+    loop {
+        let next = if draining {
+            Some(connection.accept().await)
+        } else {
+            let accept = pin!(connection.accept());
+            let stop = pin!(stopped.changed());
+            match future::select(accept, stop).await {
+                Either::Left((call, _)) => Some(call),
+                // The listener is stopping.
+                Either::Right(_) => None,
+            }
+        };
+        let Some(call) = next else {
+            draining = true;
+            connection.graceful_shutdown();
+            continue;
+        };
+        match call {
+            Some(Ok((request, respond))) => {
+                actix_web::rt::spawn(handle(request, respond, peer, Rc::clone(&service)));
+            }
+            Some(Err(e)) => {
+                tracing::debug!(error = %e, "gRPC connection closed");
+                return;
+            }
+            None => return,
+        }
+    }
+}
+
+async fn handle(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: IpAddr,
+    service: Rc<Service>,
+) {
+    let started = Instant::now();
+    let (parts, body) = request.into_parts();
+    let method = parts.uri.path().strip_prefix(SERVICE).unwrap_or_default().to_string();
+    let result = match service.admit(&parts.headers, peer).await {
+        Ok(()) => match read_message(body).await {
+            Ok(message) => service.call(&method, &parts.headers, &message).await,
+            Err(status) => Err(status),
+        },
+        Err(status) => Err(status),
+    };
+    let code = result.as_ref().err().map_or(OK, |status| status.code);
+    let label = METHODS.iter().find(|known| **known == method).copied().unwrap_or("unknown");
+    service.state.metrics.grpc_call(label, code, started.elapsed());
+    if let Err(e) = send(&mut respond, result) {
+        tracing::debug!(method, error = %e, "failed to send gRPC response");
+    }
+}
+
+// The single length-prefixed message of a unary call.
+async fn read_message(mut body: RecvStream) -> Result<Bytes, Status> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Status::new(INTERNAL, e.to_string()))?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if buf.len() + chunk.len() > MAX_MESSAGE_BYTES + 5 {
+            return Err(Status::new(RESOURCE_EXHAUSTED, "request message is too large"));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    unframe(buf)
+}
+
+// Strips the gRPC frame header: a compression flag and a big-endian length.
+fn unframe(buf: BytesMut) -> Result<Bytes, Status> {
+    if buf.len() < 5 {
+        return Err(Status::new(INVALID_ARGUMENT, "missing request message"));
+    }
+    if buf[0] != 0 {
+        return Err(Status::new(UNIMPLEMENTED, "compressed messages are not supported"));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if buf.len() != 5 + len {
+        return Err(Status::new(INVALID_ARGUMENT, "malformed request message"));
+    }
+    Ok(buf.freeze().slice(5..))
+}
+
+// grpc-message is percent-encoded.
+fn status_message(message: &str) -> HeaderValue {
+    let encoded: String = message
+        .bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => char::from(byte).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+    HeaderValue::from_str(&encoded).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+fn frame(message: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(5 + message.len());
+    frame.extend_from_slice(&[0]);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame.freeze()
+}
+
+fn send(respond: &mut SendResponse<Bytes>, result: Result<Vec<u8>, Status>) -> Result<(), h2::Error> {
+    let response = Response::builder()
+        .header("content-type", "application/grpc")
+        .body(())
+        .expect("static response parts are valid");
+    match result {
+        Ok(message) => {
+            let mut stream = respond.send_response(response, false)?;
+            stream.send_data(frame(&message), false)?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from(OK));
+            stream.send_trailers(trailers)
+        }
+        // An error is reported "trailers-only": the status goes in the
+        // headers of a response without a body.
+        Err(status) => {
+            let (mut parts, ()) = response.into_parts();
+            parts.headers.insert("grpc-status", HeaderValue::from(status.code));
+            parts.headers.insert("grpc-message", status_message(&status.message));
+            respond.send_response(Response::from_parts(parts, ()), true)?;
+            Ok(())
+        }
+    }
+}
+
+fn parse_id(id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(id).map_err(|_| Status::new(INVALID_ARGUMENT, format!("invalid user id: {}", id)))
+}
+
+fn decode<T>(decoded: Result<T, String>) -> Result<T, Status> {
+    decoded.map_err(|e| Status::new(INVALID_ARGUMENT, format!("invalid request message: {}", e)))
+}
+
+impl Service {
+    // Takes a rate-limit token for the caller, keyed like HTTP clients: by a
+    // verified `x-api-key`, otherwise by the peer address.
+    async fn admit(&self, headers: &HeaderMap, peer: IpAddr) -> Result<(), Status> {
+        let Some(limiter) = &self.rate_limiter else {
+            return Ok(());
+        };
+        let api_key = headers.get("x-api-key").and_then(|value| value.to_str().ok());
+        limiter
+            .acquire_caller(Some(&self.state), api_key, Some(peer.to_string()))
+            .await
+            .map(|_| ())
+            .map_err(|throttled| {
+                let seconds = throttled.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                Status::new(
+                    RESOURCE_EXHAUSTED,
+                    format!("too many requests, retry in {}s", seconds),
+                )
+            })
+    }
+
+    // Authenticates the caller from the call metadata, like
+    // `auth::require_jwt_or_api_key`, and checks it against `allowed`.
+    async fn authorize(
+        &self,
+        headers: &HeaderMap,
+        allowed: impl FnOnce(&Subject) -> bool,
+        reason: &str,
+    ) -> Result<String, Status> {
+        let metadata = |name: &str| -> Result<Option<&str>, AuthError> {
+            headers
+                .get(name)
+                .map(|value| value.to_str().map_err(|_| AuthError::InvalidApiKey))
+                .transpose()
+        };
+        let api_key = metadata("x-api-key")?;
+        let bearer_token = metadata("authorization")?.and_then(|value| value.strip_prefix("Bearer "));
+        let subject = auth::identify(
+            &self.state,
+            self.jwt_auth.as_ref().map(|auth| auth.get_ref()),
+            api_key,
+            bearer_token,
+        )
+        .await?;
+        Ok(auth::authorize(subject.as_ref(), allowed, reason)?.id.clone())
+    }
+
+    async fn call(&self, method: &str, headers: &HeaderMap, message: &[u8]) -> Result<Vec<u8>, Status> {
+        let state = &self.state;
+        match method {
+            "GetUser" => {
+                let request = decode(proto::decode_get_user(message))?;
+                let id = parse_id(&request.id)?;
+                match users::get(state, id, false).await? {
+                    (Some(user), _) => Ok(proto::encode_user(&user)),
+                    (None, _) => Err(Status::new(NOT_FOUND, format!("User with ID {} not found", id))),
+                }
+            }
+            "ListUsers" => {
+                let request = decode(proto::decode_list_users(message))?;
+                let params = ListUsersQuery {
+                    limit: (request.limit > 0).then_some(request.limit as usize),
+                    cursor: (!request.cursor.is_empty()).then_some(request.cursor),
+                    ..ListUsersQuery::default()
+                };
+                let listing = users::list(state, &params, false).await?;
+                Ok(proto::encode_users_page(&listing.page))
+            }
+            "CreateUser" => {
+                let request = decode(proto::decode_create_user(message))?;
+                let new_user = NewUser {
+                    name: request.name,
+                    email: request.email,
+                    password: request.password,
+                    phone: request.phone,
+                    profile: request.profile,
+                    expires_in_seconds: None,
+                };
+                let (user, _) = users::register(state, new_user, false).await?;
+                Ok(proto::encode_user(&user))
+            }
+            "UpdateUser" => {
+                let request = decode(proto::decode_update_user(message))?;
+                let id = parse_id(&request.id)?;
+                let actor = self
+                    .authorize(
+                        headers,
+                        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(id),
+                        "users may only update their own record unless they have the admin role",
+                    )
+                    .await?;
+                let update = UpdateUser {
+                    name: request.name,
+                    email: request.email,
+                    phone: request.phone,
+                    profile: request.profile,
+                    version: None,
+                };
+                let (user, _) = audit::acting_as(&actor, users::update(state, id, update, None, false)).await?;
+                tracing::info!(user_id = %id, actor, "user updated");
+                Ok(proto::encode_user(&user))
+            }
+            "DeleteUser" => {
+                let request = decode(proto::decode_delete_user(message))?;
+                let id = parse_id(&request.id)?;
+                let actor = self
+                    .authorize(
+                        headers,
+                        |subject| subject.has_role(ADMIN_ROLE),
+                        "this endpoint requires the admin role",
+                    )
+                    .await?;
+                audit::acting_as(&actor, users::delete(state, id, request.hard, None, false)).await?;
+                tracing::info!(user_id = %id, hard = request.hard, actor, "user deleted");
+                Ok(Vec::new())
+            }
+            _ => Err(Status::new(UNIMPLEMENTED, format!("unknown method {}", method))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+
+    fn framed(message: &[u8]) -> BytesMut {
+        BytesMut::from(&frame(message)[..])
+    }
+
+    #[test]
+    fn messages_are_framed_with_their_length() {
+        assert_eq!(&frame(b"hello")[..], b"\0\0\0\0\x05hello");
+        assert_eq!(&unframe(framed(b"hello")).unwrap()[..], b"hello");
+        assert_eq!(&unframe(framed(b"")).unwrap()[..], b"");
+
+        let short = unframe(BytesMut::from(&b"\0\0\0"[..])).err().unwrap();
+        assert_eq!(short.code, INVALID_ARGUMENT);
+        let mut compressed = framed(b"hello");
+        compressed[0] = 1;
+        assert_eq!(unframe(compressed).err().unwrap().code, UNIMPLEMENTED);
+        let mut truncated = framed(b"hello");
+        truncated.truncate(7);
+        assert_eq!(unframe(truncated).err().unwrap().code, INVALID_ARGUMENT);
+    }
+
+    #[test]
+    fn status_messages_are_percent_encoded() {
+        assert_eq!(status_message("not found"), "not found");
+        assert_eq!(status_message("100% café\n"), "100%25 caf%C3%A9%0A");
+    }
+
+    #[test]
+    fn api_errors_map_to_status_codes() {
+        let not_found = Status::from(ApiError::NotFound(String::from("User with ID 1 not found")));
+        assert_eq!((not_found.code, not_found.message.as_str()), (NOT_FOUND, "User with ID 1 not found"));
+        let internal = Status::from(ApiError::internal("Failed to read rows", "driver said no"));
+        assert_eq!((internal.code, internal.message.as_str()), (INTERNAL, "internal server error"));
+        assert_eq!(Status::from(ApiError::DbUnavailable(String::new())).code, UNAVAILABLE);
+        assert_eq!(Status::from(AuthError::Unauthenticated).code, UNAUTHENTICATED);
+    }
+
+    // A unary call over a real HTTP/2 connection: the server half reads the
+    // request with `read_message` and answers with `send`, as `handle` does.
+    #[actix_web::test]
+    async fn unary_calls_round_trip_over_http2() {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let user = User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        };
+        let reply = user.clone();
+        actix_web::rt::spawn(async move {
+            let mut connection = h2::server::handshake(server_io).await.unwrap();
+            while let Some(Ok((request, mut respond))) = connection.accept().await {
+                let path = request.uri().path().to_string();
+                let result = match read_message(request.into_body()).await {
+                    Ok(message) if path.ends_with("GetUser") => {
+                        let request = proto::decode_get_user(&message).unwrap();
+                        assert_eq!(request.id, reply.id.to_string());
+                        Ok(proto::encode_user(&reply))
+                    }
+                    Ok(_) => Err(Status::new(NOT_FOUND, "no such user: café")),
+                    Err(status) => Err(status),
+                };
+                send(&mut respond, result).unwrap();
+            }
+        });
+
+        let (client, connection) = h2::client::handshake(client_io).await.unwrap();
+        actix_web::rt::spawn(async move {
+            let _ = connection.await;
+        });
+        let call = |method: &str, message: Vec<u8>| {
+            let client = client.clone();
+            let request = Request::post(format!("http://localhost{}{}", SERVICE, method))
+                .header("content-type", "application/grpc")
+                .body(())
+                .unwrap();
+            async move {
+                let mut client = client.ready().await.unwrap();
+                let (response, mut stream) = client.send_request(request, false).unwrap();
+                stream.send_data(frame(&message), true).unwrap();
+                response.await.unwrap()
+            }
+        };
+
+        let mut request = Vec::new();
+        request.extend_from_slice(&[0x0A, 36]);
+        request.extend_from_slice(user.id.to_string().as_bytes());
+        let response = call("GetUser", request).await;
+        assert_eq!(response.headers()["content-type"], "application/grpc");
+        let mut body = response.into_body();
+        let mut received = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            let _ = body.flow_control().release_capacity(chunk.len());
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(unframe(received).unwrap(), proto::encode_user(&user));
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+
+        let response = call("Missing", Vec::new()).await;
+        assert_eq!(response.headers()["grpc-status"], "5");
+        assert_eq!(response.headers()["grpc-message"], "no such user: caf%C3%A9");
+        assert!(response.into_body().is_end_stream());
+    }
+}
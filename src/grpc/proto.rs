@@ -0,0 +1,471 @@
+use crate::models::{Profile, User, UsersPage};
+use chrono::{DateTime, Utc};
+
+// Protobuf encoding of the messages in proto/users.proto. Only the wire
+// types proto3 scalars and messages use are handled; unknown fields are
+// skipped, as the format requires.
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const FIXED32: u8 = 5;
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buf, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(buf, field, LENGTH_DELIMITED);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+// proto3 leaves default values (here: empty strings) off the wire.
+fn put_string(buf: &mut Vec<u8>, field: u32, value: &str) {
+    if !value.is_empty() {
+        put_bytes(buf, field, value.as_bytes());
+    }
+}
+
+fn put_optional_string(buf: &mut Vec<u8>, field: u32, value: &Option<String>) {
+    if let Some(value) = value {
+        put_bytes(buf, field, value.as_bytes());
+    }
+}
+
+fn put_message(buf: &mut Vec<u8>, field: u32, encode: impl FnOnce(&mut Vec<u8>)) {
+    let mut message = Vec::new();
+    encode(&mut message);
+    put_bytes(buf, field, &message);
+}
+
+fn put_timestamp(buf: &mut Vec<u8>, field: u32, value: &Option<DateTime<Utc>>) {
+    if let Some(value) = value {
+        put_message(buf, field, |message| {
+            if value.timestamp() != 0 {
+                put_key(message, 1, VARINT);
+                put_varint(message, value.timestamp() as u64);
+            }
+            if value.timestamp_subsec_nanos() != 0 {
+                put_key(message, 2, VARINT);
+                put_varint(message, u64::from(value.timestamp_subsec_nanos()));
+            }
+        });
+    }
+}
+
+fn put_profile(buf: &mut Vec<u8>, profile: &Profile) {
+    put_optional_string(buf, 1, &profile.bio);
+    put_optional_string(buf, 2, &profile.avatar_url);
+    put_optional_string(buf, 3, &profile.locale);
+    put_optional_string(buf, 4, &profile.timezone);
+}
+
+fn put_user(buf: &mut Vec<u8>, user: &User) {
+    put_string(buf, 1, &user.id.to_string());
+    put_string(buf, 2, &user.name);
+    put_string(buf, 3, &user.email);
+    if let Some(profile) = &user.profile {
+        put_message(buf, 4, |message| put_profile(message, profile));
+    }
+    put_timestamp(buf, 5, &user.created_at);
+    put_timestamp(buf, 6, &user.updated_at);
+    put_optional_string(buf, 7, &user.phone);
+}
+
+pub fn encode_user(user: &User) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_user(&mut buf, user);
+    buf
+}
+
+pub fn encode_users_page(page: &UsersPage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for user in &page.users {
+        put_message(&mut buf, 1, |message| put_user(message, user));
+    }
+    if let Some(cursor) = &page.next_cursor {
+        put_string(&mut buf, 2, cursor);
+    }
+    buf
+}
+
+enum Wire<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn varint(buf: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or("truncated varint")?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(String::from("varint is too long"))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if buf.len() < len {
+        return Err(String::from("truncated field"));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+// Calls `visit` with each field of a message in wire order.
+fn fields<'a>(
+    mut buf: &'a [u8],
+    mut visit: impl FnMut(u32, Wire<'a>) -> Result<(), String>,
+) -> Result<(), String> {
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let field = u32::try_from(key >> 3).map_err(|_| "invalid field number")?;
+        let value = match (key & 0x7) as u8 {
+            VARINT => Wire::Varint(varint(&mut buf)?),
+            LENGTH_DELIMITED => {
+                let len = usize::try_from(varint(&mut buf)?).map_err(|_| "invalid length")?;
+                Wire::Bytes(take(&mut buf, len)?)
+            }
+            FIXED64 => {
+                take(&mut buf, 8)?;
+                Wire::Fixed
+            }
+            FIXED32 => {
+                take(&mut buf, 4)?;
+                Wire::Fixed
+            }
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        visit(field, value)?;
+    }
+    Ok(())
+}
+
+fn string(field: u32, value: Wire) -> Result<String, String> {
+    match value {
+        Wire::Bytes(bytes) => String::from_utf8(bytes.to_vec())
+            .map_err(|_| format!("field {} is not valid UTF-8", field)),
+        _ => Err(format!("field {} must be a string", field)),
+    }
+}
+
+fn uint(field: u32, value: Wire) -> Result<u64, String> {
+    match value {
+        Wire::Varint(value) => Ok(value),
+        _ => Err(format!("field {} must be a varint", field)),
+    }
+}
+
+fn message<'a>(field: u32, value: Wire<'a>) -> Result<&'a [u8], String> {
+    match value {
+        Wire::Bytes(bytes) => Ok(bytes),
+        _ => Err(format!("field {} must be a message", field)),
+    }
+}
+
+fn decode_profile(buf: &[u8]) -> Result<Profile, String> {
+    let mut profile = Profile::default();
+    fields(buf, |field, value| {
+        match field {
+            1 => profile.bio = Some(string(field, value)?),
+            2 => profile.avatar_url = Some(string(field, value)?),
+            3 => profile.locale = Some(string(field, value)?),
+            4 => profile.timezone = Some(string(field, value)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(profile)
+}
+
+pub struct GetUserRequest {
+    pub id: String,
+}
+
+pub fn decode_get_user(buf: &[u8]) -> Result<GetUserRequest, String> {
+    let mut request = GetUserRequest { id: String::new() };
+    fields(buf, |field, value| {
+        if field == 1 {
+            request.id = string(field, value)?;
+        }
+        Ok(())
+    })?;
+    Ok(request)
+}
+
+pub struct ListUsersRequest {
+    pub limit: u32,
+    pub cursor: String,
+}
+
+pub fn decode_list_users(buf: &[u8]) -> Result<ListUsersRequest, String> {
+    let mut request = ListUsersRequest {
+        limit: 0,
+        cursor: String::new(),
+    };
+    fields(buf, |field, value| {
+        match field {
+            // uint32 values arrive as varints and are truncated, as in protoc.
+            1 => request.limit = uint(field, value)? as u32,
+            2 => request.cursor = string(field, value)?,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(request)
+}
+
+pub struct CreateUserRequest {
+    pub name: String,
+    pub email: String,
+    pub password: Option<String>,
+    pub profile: Option<Profile>,
+    pub phone: Option<String>,
+}
+
+pub fn decode_create_user(buf: &[u8]) -> Result<CreateUserRequest, String> {
+    let mut request = CreateUserRequest {
+        name: String::new(),
+        email: String::new(),
+        password: None,
+        profile: None,
+        phone: None,
+    };
+    fields(buf, |field, value| {
+        match field {
+            1 => request.name = string(field, value)?,
+            2 => request.email = string(field, value)?,
+            3 => request.password = Some(string(field, value)?),
+            4 => request.profile = Some(decode_profile(message(field, value)?)?),
+            5 => request.phone = Some(string(field, value)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(request)
+}
+
+pub struct UpdateUserRequest {
+    pub id: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub profile: Option<Profile>,
+    pub phone: Option<String>,
+}
+
+pub fn decode_update_user(buf: &[u8]) -> Result<UpdateUserRequest, String> {
+    let mut request = UpdateUserRequest {
+        id: String::new(),
+        name: None,
+        email: None,
+        profile: None,
+        phone: None,
+    };
+    fields(buf, |field, value| {
+        match field {
+            1 => request.id = string(field, value)?,
+            2 => request.name = Some(string(field, value)?),
+            3 => request.email = Some(string(field, value)?),
+            4 => request.profile = Some(decode_profile(message(field, value)?)?),
+            5 => request.phone = Some(string(field, value)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(request)
+}
+
+pub struct DeleteUserRequest {
+    pub id: String,
+    pub hard: bool,
+}
+
+pub fn decode_delete_user(buf: &[u8]) -> Result<DeleteUserRequest, String> {
+    let mut request = DeleteUserRequest {
+        id: String::new(),
+        hard: false,
+    };
+    fields(buf, |field, value| {
+        match field {
+            1 => request.id = string(field, value)?,
+            2 => request.hard = uint(field, value)? != 0,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn user() -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: Some(String::from("+14155550123")),
+            profile: Some(Profile {
+                bio: Some(String::from("Analyst")),
+                ..Profile::default()
+            }),
+            created_at: Some(Utc.timestamp_opt(1_700_000_000, 5).unwrap()),
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    // Reads back what `put_timestamp` wrote.
+    fn timestamp(buf: &[u8]) -> DateTime<Utc> {
+        let (mut seconds, mut nanos) = (0, 0);
+        fields(buf, |field, value| {
+            match field {
+                1 => seconds = uint(field, value)? as i64,
+                2 => nanos = uint(field, value)? as u32,
+                _ => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+        Utc.timestamp_opt(seconds, nanos).unwrap()
+    }
+
+    // Reads back what `put_user` wrote.
+    fn decode_user(buf: &[u8]) -> User {
+        let mut user = User {
+            id: Uuid::nil(),
+            name: String::new(),
+            email: String::new(),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        };
+        fields(buf, |field, value| {
+            match field {
+                1 => user.id = string(field, value)?.parse().unwrap(),
+                2 => user.name = string(field, value)?,
+                3 => user.email = string(field, value)?,
+                4 => user.profile = Some(decode_profile(message(field, value)?)?),
+                5 => user.created_at = Some(timestamp(message(field, value)?)),
+                6 => user.updated_at = Some(timestamp(message(field, value)?)),
+                7 => user.phone = Some(string(field, value)?),
+                _ => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+        user
+    }
+
+    #[test]
+    fn users_round_trip() {
+        let user = user();
+        let decoded = decode_user(&encode_user(&user));
+        assert_eq!(decoded.id, user.id);
+        assert_eq!(decoded.name, user.name);
+        assert_eq!(decoded.email, user.email);
+        assert_eq!(decoded.phone, user.phone);
+        assert_eq!(decoded.profile.unwrap().bio.as_deref(), Some("Analyst"));
+        assert_eq!(decoded.created_at, user.created_at);
+        assert_eq!(decoded.updated_at, None);
+    }
+
+    #[test]
+    fn pages_hold_each_user_and_the_cursor() {
+        let page = UsersPage {
+            users: vec![user(), user()],
+            next_cursor: Some(String::from("abc")),
+        };
+        let mut users = Vec::new();
+        let mut cursor = None;
+        fields(&encode_users_page(&page), |field, value| {
+            match field {
+                1 => users.push(decode_user(message(field, value)?)),
+                2 => cursor = Some(string(field, value)?),
+                _ => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(users.iter().map(|user| user.id).collect::<Vec<_>>(), [page.users[0].id, page.users[1].id]);
+        assert_eq!(cursor.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn requests_decode_and_skip_unknown_fields() {
+        let mut buf = Vec::new();
+        put_string(&mut buf, 1, "Ada");
+        put_key(&mut buf, 9, VARINT);
+        put_varint(&mut buf, 300);
+        put_string(&mut buf, 2, "ada@example.com");
+        put_key(&mut buf, 10, FIXED64);
+        buf.extend_from_slice(&[0; 8]);
+        put_key(&mut buf, 11, FIXED32);
+        buf.extend_from_slice(&[0; 4]);
+        put_string(&mut buf, 3, "secret-password");
+        put_message(&mut buf, 4, |message| put_string(message, 3, "en-GB"));
+
+        let request = decode_create_user(&buf).unwrap();
+        assert_eq!(request.name, "Ada");
+        assert_eq!(request.email, "ada@example.com");
+        assert_eq!(request.password.as_deref(), Some("secret-password"));
+        assert_eq!(request.profile.unwrap().locale.as_deref(), Some("en-GB"));
+
+        let mut buf = Vec::new();
+        put_key(&mut buf, 1, VARINT);
+        put_varint(&mut buf, (1 << 32) + 5);
+        assert_eq!(decode_list_users(&buf).unwrap().limit, 5);
+
+        let mut buf = Vec::new();
+        put_string(&mut buf, 1, "id");
+        put_key(&mut buf, 2, VARINT);
+        put_varint(&mut buf, 1);
+        assert!(decode_delete_user(&buf).unwrap().hard);
+        assert!(!decode_delete_user(&[]).unwrap().hard);
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        assert_eq!(decode_get_user(&[0x0A, 0x80]).err().as_deref(), Some("truncated varint"));
+        assert_eq!(decode_get_user(&[0x0A, 0x05, b'a']).err().as_deref(), Some("truncated field"));
+        assert_eq!(decode_get_user(&[0x0B]).err().as_deref(), Some("unsupported wire type 3"));
+        assert_eq!(
+            decode_get_user(&[0x08, 0x01]).err().as_deref(),
+            Some("field 1 must be a string")
+        );
+        assert_eq!(
+            decode_get_user(&[0x0A, 0x01, 0xFF]).err().as_deref(),
+            Some("field 1 is not valid UTF-8")
+        );
+        let mut too_long = vec![0x08];
+        too_long.extend_from_slice(&[0xFF; 10]);
+        assert_eq!(decode_list_users(&too_long).err().as_deref(), Some("varint is too long"));
+    }
+}
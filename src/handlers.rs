@@ -0,0 +1,998 @@
+use crate::auth::{self, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, FieldError, Problem};
+use crate::idempotency::{self, Claim};
+use crate::count;
+use crate::dry_run;
+use crate::emails;
+use crate::flags::Flag;
+use crate::links;
+use crate::models::{
+    BulkItemResult, BulkRegisterResponse, CheckEmailQuery, DeleteUserQuery, EmailCheck,
+    ListUsersQuery, NewUser, ReplaceUser, SearchUsersQuery, UpdateUser, User, UserRoles, UsersPage,
+};
+use crate::negotiate::{self, Body};
+use crate::null_fields::{self, NullFields};
+use crate::patch::{Changes, PatchOperation};
+use crate::repository::ColumnWrite;
+use crate::state::AppState;
+use crate::stats;
+use crate::users;
+use crate::validation;
+use actix_web::http::header::{self, EntityTag, HeaderName, HeaderValue, IfMatch, IfNoneMatch};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use scylla::Session;
+use serde::Serialize;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+// Server-side query tracing is opt-in per request via `X-Scylla-Trace: true`,
+// and only honoured when the deployment sets `scylla.allow_tracing` and the
+// caller is an admin: traces expose cluster internals and cost the cluster
+// extra writes. Anyone else's request simply runs untraced.
+async fn tracing_requested(req: &HttpRequest, data: &AppState) -> bool {
+    if !trace_header(req, data.allow_tracing) {
+        return false;
+    }
+    // Public routes have no authenticating middleware to resolve the caller.
+    let resolved = req.extensions().get::<Subject>().cloned();
+    let subject = match resolved {
+        Some(subject) => Some(subject),
+        None => auth::authenticate(req).await.ok().flatten(),
+    };
+    subject.is_some_and(|subject| subject.has_role(ADMIN_ROLE))
+}
+
+// Whether the request asks for tracing in a deployment that allows it.
+fn trace_header(req: &HttpRequest, allow_tracing: bool) -> bool {
+    allow_tracing
+        && req
+            .headers()
+            .get("X-Scylla-Trace")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+}
+
+// Who performed a mutation, for the log line. Routes behind `require_admin`
+// or `auth::authorize` always have a subject.
+fn actor(subject: &Option<web::ReqData<Subject>>) -> &str {
+    subject.as_ref().map_or("anonymous", |subject| subject.id.as_str())
+}
+
+// Logs the tracing sessions recorded for a request and exposes their ids
+// in the `X-Scylla-Trace-Id` response header.
+async fn report_tracing(
+    session: &Session,
+    tracing_ids: &[Uuid],
+    response: HttpResponse,
+) -> HttpResponse {
+    for tracing_id in tracing_ids {
+        match session.get_tracing_info(tracing_id).await {
+            Ok(info) => tracing::info!(
+                %tracing_id,
+                request = ?info.request,
+                duration_us = ?info.duration,
+                events = info.events.len(),
+                "scylla tracing session"
+            ),
+            Err(e) => tracing::warn!(%tracing_id, error = %e, "failed to fetch scylla tracing info"),
+        }
+    }
+    with_trace_ids(response, tracing_ids)
+}
+
+// `response` with `tracing_ids`, if any, in its `X-Scylla-Trace-Id` header.
+fn with_trace_ids(mut response: HttpResponse, tracing_ids: &[Uuid]) -> HttpResponse {
+    if tracing_ids.is_empty() {
+        return response;
+    }
+    let ids = tracing_ids
+        .iter()
+        .map(Uuid::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    if let Ok(value) = HeaderValue::from_str(&ids) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-scylla-trace-id"), value);
+    }
+    response
+}
+
+// Users are tagged weakly, as JSON and MessagePack bodies share one tag.
+fn user_etag(user: &User) -> header::ETag {
+    header::ETag(EntityTag::new_weak(users::version(user)))
+}
+
+// The versions a write's `If-Match` accepts. Tags compare weakly, to match
+// `user_etag`; `*` only asks for the user to exist, which writes check anyway.
+fn if_match(req: &HttpRequest) -> Option<Vec<String>> {
+    match req.get_header::<IfMatch>()? {
+        IfMatch::Any => None,
+        IfMatch::Items(tags) => Some(tags.iter().map(|tag| tag.tag().to_string()).collect()),
+    }
+}
+
+// Adds the soft validation findings of a write that went through, one
+// `X-Validation-Warnings` line each.
+fn with_warnings(mut response: HttpResponse, warnings: &[FieldError]) -> HttpResponse {
+    for warning in warnings {
+        if let Ok(value) = HeaderValue::from_str(&format!("{}: {}", warning.field, warning.message)) {
+            response.headers_mut().append(validation::WARNINGS_HEADER, value);
+        }
+    }
+    response
+}
+
+// When a user last changed, for `Last-Modified`.
+fn last_modified(modified: DateTime<Utc>) -> header::LastModified {
+    header::LastModified(SystemTime::from(modified).into())
+}
+
+// Whether the client's `If-None-Match` already has the current `etag`.
+fn unchanged(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+fn page_response(req: &HttpRequest, page: &impl Serialize, truncated: bool) -> HttpResponse {
+    users::with_truncated(negotiate::respond(req, HttpResponse::Ok(), page), truncated)
+}
+
+fn with_total(mut response: HttpResponse, total: Option<u64>) -> HttpResponse {
+    if let Some(total) = total {
+        response
+            .headers_mut()
+            .insert(count::TOTAL_COUNT_HEADER, HeaderValue::from(total));
+    }
+    response
+}
+
+// Streams each user as one line of JSON, with `X-Truncated` when the row cap
+// cut the listing short. An error after the first lines have been sent cuts
+// the response short.
+fn ndjson_response(req: &HttpRequest, stream: users::UserStream) -> HttpResponse {
+    let omit_nulls = null_fields::requested(req).unwrap_or_default() == NullFields::Omit;
+    let req = req.clone();
+    let fields = stream.fields;
+    let lines = stream.users.map(move |user| {
+        let user = user.map_err(|e| {
+            tracing::error!(error = %e, "streamed listing failed");
+            actix_web::Error::from(e)
+        })?;
+        let value = match &fields {
+            Some(fields) => users::project_user(&user, fields),
+            None => serde_json::to_value(&user).unwrap_or_default(),
+        };
+        let mut value = links::user(&req, &user, value);
+        if omit_nulls {
+            null_fields::omit(&mut value);
+        }
+        let mut line = value.to_string();
+        line.push('\n');
+        Ok::<_, actix_web::Error>(web::Bytes::from(line))
+    });
+    let response = HttpResponse::Ok().content_type(negotiate::NDJSON_TYPE).streaming(lines);
+    users::with_truncated(response, stream.truncated)
+}
+
+/// Pages follow the table's token order: stable while the data is unchanged,
+/// but not meaningful. `sort` orders each page, ties broken by id, so
+/// `sort=id` gives a repeatable order within pages. A cursor only continues
+/// the listing with the filters and sort it came from; `limit` and `fields`
+/// may change between pages.
+///
+/// With `Accept: application/x-ndjson` the response is instead every
+/// matching user up to the row cap, one JSON object per line; `limit`,
+/// `cursor` and `sort` are then refused. Past the cap the stream is cut short
+/// with `X-Truncated: true` or refused with 400, per `http.row_cap_mode`.
+#[utoipa::path(
+    get,
+    path = "/users",
+    params(ListUsersQuery),
+    responses(
+        (status = 200, description = "One page of users, or every user as NDJSON", content(
+            (UsersPage = "application/json"),
+            (User = "application/x-ndjson"),
+        )),
+        (status = 400, description = "Invalid limit, cursor, sort, order, fields or tag, a cursor from other parameters, or total with filters", body = Problem),
+    )
+)]
+pub async fn get_all_users(
+    req: HttpRequest,
+    params: web::Query<ListUsersQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let total = match params.total {
+        Some(true) if users::is_filtered(&params) => {
+            return Err(ApiError::BadRequest(String::from("total is not available with filters")));
+        }
+        Some(true) => Some(data.user_count.get(&data).await?.count),
+        _ => None,
+    };
+    if negotiate::wants_ndjson(&req) {
+        let response = ndjson_response(&req, users::stream(&data, &params).await?);
+        return Ok(with_total(response, total));
+    }
+    let listing = users::list(&data, &params, tracing_requested(&req, &data).await).await?;
+    let body = match &listing.fields {
+        Some(fields) => users::project_page(&listing.page, fields),
+        None => serde_json::to_value(&listing.page).unwrap_or_default(),
+    };
+    let response = page_response(&req, &links::page(&req, &listing.page, body), listing.truncated);
+    let response = with_total(response, total);
+    Ok(report_tracing(&data.session, &listing.tracing_ids, response).await)
+}
+
+/// With `name_prefix`, searches the `users_by_name` table, which is filled
+/// on write. Users stored before it existed (migration 0003) are only found
+/// after `backfill` has indexed them.
+///
+/// With `q`, asks the search index configured as `search_index` for users
+/// whose name, email or phone number matches, best match first. The index
+/// is filled by the outbox relay as users change, so it lags writes a
+/// little, and knows no users stored before it was turned on until they
+/// next change.
+#[utoipa::path(
+    get,
+    path = "/users/search",
+    params(SearchUsersQuery),
+    responses(
+        (status = 200, description = "One page of users whose name starts with the prefix, by name, or that match q, best first", body = UsersPage),
+        (status = 400, description = "Neither or both of name_prefix and q, or an invalid limit or cursor", body = Problem),
+        (status = 404, description = "Prefix search is switched off by the `name_search` feature flag, or there is no search index for q", body = Problem),
+    )
+)]
+pub async fn search_users(
+    req: HttpRequest,
+    params: web::Query<SearchUsersQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let listing = match (&params.name_prefix, &params.q) {
+        (Some(_), None) => {
+            if !data.flags.enabled(Flag::NameSearch) {
+                return Err(ApiError::NotFound(String::from("Search is switched off")));
+            }
+            users::search(&data, &params, tracing_requested(&req, &data).await).await?
+        }
+        (None, Some(q)) => {
+            let Some(index) = &data.search_index else {
+                return Err(ApiError::NotFound(String::from("Full-text search is not set up")));
+            };
+            users::full_text_search(&data, index, q, &params).await?
+        }
+        _ => return Err(ApiError::BadRequest(String::from("give one of name_prefix and q"))),
+    };
+    let body = serde_json::to_value(&listing.page).unwrap_or_default();
+    let response = page_response(&req, &links::page(&req, &listing.page, body), listing.truncated);
+    Ok(report_tracing(&data.session, &listing.tracing_ids, response).await)
+}
+
+/// Checks an address before signup without registering anything. Requests
+/// count against the rate limit like any other, which bounds enumeration;
+/// with `http.email_check_public = false` only authenticated callers learn
+/// whether the address is taken.
+#[utoipa::path(
+    get,
+    path = "/users/check-email",
+    params(CheckEmailQuery),
+    security((), ("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Whether the address is valid and free", body = EmailCheck),
+    )
+)]
+pub async fn check_email(
+    req: HttpRequest,
+    params: web::Query<CheckEmailQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let validated = validation::email(&params.email);
+    let mut taken = None;
+    if let Ok(email) = &validated
+        && (data.email_check_public || auth::authenticate(&req).await.ok().flatten().is_some())
+    {
+        taken = Some(emails::taken(&data, email).await?);
+    }
+    Ok(HttpResponse::Ok().json(EmailCheck::new(validated, taken)))
+}
+
+// The `Idempotency-Key` of a request, if it sent a valid one.
+fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, ApiError> {
+    let Some(value) = req.headers().get(idempotency::KEY_HEADER) else {
+        return Ok(None);
+    };
+    let key = value
+        .to_str()
+        .map_err(|_| ApiError::BadRequest(String::from("Idempotency-Key must be ASCII")))?;
+    idempotency::validate_key(key)?;
+    Ok(Some(key.to_string()))
+}
+
+// Registers `new_user`, through the write-behind queue when it and its flag
+// are on, returning the response's status and JSON body.
+async fn register_one(
+    data: &AppState,
+    new_user: NewUser,
+    tracing: bool,
+) -> Result<(StatusCode, String, Vec<Uuid>), ApiError> {
+    let encode = |message: String| {
+        serde_json::to_string(&message)
+            .map_err(|e| ApiError::internal("Failed to encode response", e))
+    };
+    if let Some(queue) = &data.write_behind
+        && data.flags.enabled(Flag::WriteBehind)
+        && !dry_run::active()
+    {
+        let id = users::register_behind(data, queue, new_user).await?;
+        let body = encode(format!("User {} accepted", id))?;
+        return Ok((StatusCode::ACCEPTED, body, Vec::new()));
+    }
+    let (user, tracing_ids) = users::register(data, new_user, tracing).await?;
+    let body = if dry_run::active() {
+        encode(format!("User {} would be created", user.id))?
+    } else {
+        encode(format!("User {} created successfully", user.id))?
+    };
+    Ok((StatusCode::CREATED, body, tracing_ids))
+}
+
+#[utoipa::path(
+    post,
+    path = "/register",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key and body get the original response instead of creating another user"),
+    ),
+    request_body = NewUser,
+    responses(
+        (status = 201, description = "User created; `Idempotent-Replayed: true` when answered from an earlier request with the same Idempotency-Key, `X-Validation-Warnings` for soft checks it failed", body = String),
+        (status = 202, description = "User accepted and queued to be stored, with `write_behind.enabled`", body = String),
+        (status = 409, description = "Email already registered, or the request with this Idempotency-Key is still in progress", body = Problem),
+        (status = 422, description = "Invalid name, email or password, or an Idempotency-Key reused with a different body", body = Problem),
+        (status = 503, description = "The write-behind queue is full; retry after `Retry-After`", body = Problem),
+    )
+)]
+pub async fn register_user(
+    req: HttpRequest,
+    Body(new_user): Body<NewUser>, 
+    data: web::Data<AppState>
+) -> Result<HttpResponse, ApiError> {
+    let tracing = tracing_requested(&req, &data).await;
+    let warnings = data.validation.warnings(&new_user.email);
+    // A dry run neither records its response nor replays an earlier one.
+    let Some(key) = idempotency_key(&req)?.filter(|_| !dry_run::active()) else {
+        let (status, body, tracing_ids) = register_one(&data, new_user, tracing).await?;
+        let response = HttpResponse::build(status)
+            .insert_header(header::ContentType::json())
+            .body(body);
+        let response = with_warnings(response, &warnings);
+        return Ok(report_tracing(&data.session, &tracing_ids, response).await);
+    };
+
+    let request_hash = idempotency::fingerprint(&new_user);
+    if let Claim::Replay { status, body } = idempotency::claim(&data, &key, &request_hash).await? {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(HttpResponse::build(status)
+            .insert_header(header::ContentType::json())
+            .insert_header((idempotency::REPLAYED_HEADER, "true"))
+            .body(body));
+    }
+    let (status, body, tracing_ids) = match register_one(&data, new_user, tracing).await {
+        Ok(registered) => registered,
+        Err(e) => {
+            idempotency::release(&data, &key).await;
+            return Err(e);
+        }
+    };
+    idempotency::complete(&data, &key, &request_hash, status.as_u16(), &body).await;
+    let response = HttpResponse::build(status)
+        .insert_header(header::ContentType::json())
+        .body(body);
+    let response = with_warnings(response, &warnings);
+    Ok(report_tracing(&data.session, &tracing_ids, response).await)
+}
+
+#[utoipa::path(
+    post,
+    path = "/register/bulk",
+    request_body = Vec<NewUser>,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Per-item results, in request order", body = BulkRegisterResponse),
+        (status = 400, description = "Empty array or more than http.bulk_max_items items", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+    )
+)]
+pub async fn register_users_bulk(
+    Body(new_users): Body<Vec<NewUser>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if new_users.is_empty() || new_users.len() > data.bulk_max_items {
+        return Err(ApiError::BadRequest(format!(
+            "expected between 1 and {} users",
+            data.bulk_max_items
+        )));
+    }
+
+    // Items are independent: one failing doesn't stop the rest, and at most
+    // `bulk_concurrency` inserts are in flight so a large import can't
+    // monopolize the connection pool.
+    let results: Vec<BulkItemResult> = stream::iter(new_users.into_iter().enumerate())
+        .map(|(index, new_user)| {
+            let data = &data;
+            async move {
+                match users::register(data, new_user, false).await {
+                    Ok((user, _)) => BulkItemResult {
+                        index,
+                        status: StatusCode::CREATED.as_u16(),
+                        id: Some(user.id),
+                        error: None,
+                    },
+                    Err(e) => BulkItemResult {
+                        index,
+                        status: e.status_code().as_u16(),
+                        id: None,
+                        error: Some(e.to_problem()),
+                    },
+                }
+            }
+        })
+        .buffered(data.bulk_concurrency)
+        .collect()
+        .await;
+
+    let created = results.iter().filter(|result| result.id.is_some()).count();
+    tracing::info!(created, failed = results.len() - created, "bulk registration");
+    Ok(HttpResponse::Ok().json(BulkRegisterResponse {
+        created,
+        failed: results.len() - created,
+        results,
+    }))
+}
+
+/// Takes the fields to set as a partial user, a JSON Patch
+/// (`application/json-patch+json`, `add` and `replace` only) or a JSON
+/// Merge Patch (`application/merge-patch+json`). Fields can be set but not
+/// removed. With the user's `version` (a `test` of `/version` in a JSON
+/// Patch), the update only applies while the user is still at it. In the
+/// strict `http.concurrency_mode`, an update needs `If-Match` or `version`.
+#[utoipa::path(
+    patch,
+    path = "/update/{id}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("If-Match" = Option<String>, Header, description = "Only update while the user has this ETag"),
+    ),
+    request_body(content(
+        (UpdateUser = "application/json"),
+        (Vec<PatchOperation> = "application/json-patch+json"),
+        (UpdateUser = "application/merge-patch+json"),
+    )),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "User updated, with its new ETag and `X-Validation-Warnings` for soft checks it failed", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user being updated"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "Email already registered to another user, or the user is no longer at `version`", body = Problem),
+        (status = 412, description = "If-Match did not match the current ETag", body = Problem),
+        (status = 422, description = "Empty update, invalid field values, or a patch operation an update can't express", body = Problem),
+        (status = 428, description = "Neither If-Match nor version was given, in the strict concurrency mode", body = Problem),
+    )
+)]
+pub async fn update_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    Changes(updated_user): Changes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id_value = user_id.into_inner();
+
+    auth::authorize(
+        subject.as_deref(),
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id_value),
+        "users may only update their own record unless they have the admin role",
+    )?;
+    let if_match = if_match(&req);
+    users::require_precondition(
+        data.concurrency_mode,
+        user_id_value,
+        if_match.as_deref(),
+        updated_user.version,
+    )?;
+    let warnings = match &updated_user.email {
+        Some(email) => data.validation.warnings(email),
+        None => Vec::new(),
+    };
+    let (user, tracing_ids) = users::update(
+        &data,
+        user_id_value,
+        updated_user,
+        if_match.as_deref(),
+        tracing_requested(&req, &data).await,
+    )
+    .await?;
+    tracing::info!(user_id = %user_id_value, actor = actor(&subject), "user updated");
+    let response = HttpResponse::Ok()
+        .insert_header(user_etag(&user))
+        .json(format!("User with ID {} updated successfully", user_id_value));
+    let response = with_warnings(response, &warnings);
+    Ok(report_tracing(&data.session, &tracing_ids, response).await)
+}
+
+/// Replaces every field of the user but its creation time; a profile left
+/// out is cleared. With `http.put_creates`, an admin can create a user this
+/// way, with the id in the path. In the strict `http.concurrency_mode`,
+/// replacing a user needs `If-Match` or `version`.
+#[utoipa::path(
+    put,
+    path = "/users/{id}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("If-Match" = Option<String>, Header, description = "Only replace while the user has this ETag"),
+    ),
+    request_body = ReplaceUser,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "User replaced, with its new ETag", body = User),
+        (status = 201, description = "User created, with its ETag", body = User),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user being replaced"),
+        (status = 404, description = "No such user, and creating it is not allowed", body = Problem),
+        (status = 409, description = "Email already registered to another user, the user is deleted, or it is no longer at `version`", body = Problem),
+        (status = 412, description = "If-Match did not match the current ETag", body = Problem),
+        (status = 422, description = "Missing or invalid field values", body = Problem),
+        (status = 428, description = "Neither If-Match nor version was given for an existing user, in the strict concurrency mode", body = Problem),
+    )
+)]
+pub async fn replace_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    Body(replacement): Body<ReplaceUser>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id_value = user_id.into_inner();
+
+    auth::authorize(
+        subject.as_deref(),
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id_value),
+        "users may only replace their own record unless they have the admin role",
+    )?;
+    let may_create =
+        data.put_creates && subject.as_deref().is_some_and(|subject| subject.has_role(ADMIN_ROLE));
+    let if_match = if_match(&req);
+    let warnings = data.validation.warnings(&replacement.email);
+    let (user, created, tracing_ids) = users::replace(
+        &data,
+        user_id_value,
+        replacement,
+        if_match.as_deref(),
+        may_create,
+        tracing_requested(&req, &data).await,
+    )
+    .await?;
+    tracing::info!(
+        user_id = %user_id_value,
+        actor = actor(&subject),
+        created,
+        "user replaced"
+    );
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header(user_etag(&user));
+    let body = links::user(&req, &user, serde_json::to_value(&user).unwrap_or_default());
+    let response = with_warnings(negotiate::respond(&req, response, &body), &warnings);
+    Ok(report_tracing(&data.session, &tracing_ids, response).await)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/delete/{id}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        DeleteUserQuery,
+        ("If-Match" = Option<String>, Header, description = "Only delete while the user has this ETag"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "User deleted", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 412, description = "If-Match did not match the current ETag", body = Problem),
+    )
+)]
+pub async fn delete_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    params: web::Query<DeleteUserQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id_value = user_id.into_inner();
+    let hard = params.hard.unwrap_or(false);
+    let if_match = if_match(&req);
+    let tracing_ids = users::delete(
+        &data,
+        user_id_value,
+        hard,
+        if_match.as_deref(),
+        tracing_requested(&req, &data).await,
+    )
+    .await?;
+    tracing::info!(user_id = %user_id_value, hard, actor = actor(&subject), "user deleted");
+    let message = if dry_run::active() {
+        format!("User with ID {} would be deleted", user_id_value)
+    } else {
+        format!("User with ID {} deleted successfully", user_id_value)
+    };
+    let response = HttpResponse::Ok().json(message);
+    Ok(report_tracing(&data.session, &tracing_ids, response).await)
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{id}/restore",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "User restored", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No soft-deleted user with this id", body = Problem),
+    )
+)]
+pub async fn restore_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id_value = user_id.into_inner();
+    let (_, tracing_ids) =
+        users::restore(&data, user_id_value, tracing_requested(&req, &data).await).await?;
+    tracing::info!(user_id = %user_id_value, actor = actor(&subject), "user restored");
+    let message = if dry_run::active() {
+        format!("User with ID {} would be restored", user_id_value)
+    } else {
+        format!("User with ID {} restored successfully", user_id_value)
+    };
+    let response = HttpResponse::Ok().json(message);
+    Ok(report_tracing(&data.session, &tracing_ids, response).await)
+}
+
+/// Erases the user's personal data, for an erasure request: the name and
+/// email become `deleted-<id>` placeholders; the phone, profile, password,
+/// addresses, metadata and avatar go; the email and phone number can be
+/// registered again and the user's sessions end. The id, status and
+/// timestamps stay, and `anonymized_at` records when. Anonymizing a user
+/// again returns it unchanged.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/anonymize",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user, anonymized", body = User),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such user, live or soft-deleted", body = Problem),
+        (status = 412, description = "The user changed concurrently; retry", body = Problem),
+    )
+)]
+pub async fn anonymize_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id_value = user_id.into_inner();
+    let user = users::anonymize(&data, user_id_value).await?;
+    tracing::info!(user_id = %user_id_value, actor = actor(&subject), "user anonymized");
+    let body = links::user(&req, &user, serde_json::to_value(&user).unwrap_or_default());
+    Ok(HttpResponse::Ok().json(body))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/roles",
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body = UserRoles,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Roles replaced", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn set_user_roles(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    Body(user_roles): Body<UserRoles>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id_value = user_id.into_inner();
+    let not_found = || ApiError::NotFound(format!("User {} not found", user_id_value));
+
+    // The roles expire with the user.
+    let (user, _) = users::stored_row(&data, user_id_value).await?.ok_or_else(not_found)?;
+    let write = ColumnWrite::Roles { roles: &user_roles.roles, at: Utc::now() };
+    let (applied, _) = data.users.write_column(user_id_value, user.expires_at, write).await?;
+    if !applied {
+        return Err(not_found());
+    }
+    tracing::info!(
+        user_id = %user_id_value,
+        roles = ?user_roles.roles,
+        actor = actor(&subject),
+        "user roles set"
+    );
+    Ok(HttpResponse::Ok().json(format!("Roles of user {} updated", user_id_value)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/by-email/{email}",
+    params(("email" = String, Path, description = "Email address, matched case-insensitively")),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 404, description = "No user has this email", body = Problem),
+    )
+)]
+pub async fn get_user_by_email(
+    req: HttpRequest,
+    email: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let email = email.into_inner();
+    match users::by_email(&data, &email).await? {
+        Some(user) => {
+            let body = links::user(&req, &user, serde_json::to_value(&user).unwrap_or_default());
+            Ok(negotiate::respond(&req, HttpResponse::Ok(), &body))
+        }
+        None => Err(ApiError::NotFound(format!("No user with email {}", email.trim()))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/by-phone/{phone}",
+    params(("phone" = String, Path, description = "International number, such as +14155550123")),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 400, description = "Not an international phone number", body = Problem),
+        (status = 404, description = "No user has this phone number", body = Problem),
+    )
+)]
+pub async fn get_user_by_phone(
+    req: HttpRequest,
+    phone: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let phone = phone.into_inner();
+    match users::by_phone(&data, &phone).await? {
+        Some(user) => {
+            let body = links::user(&req, &user, serde_json::to_value(&user).unwrap_or_default());
+            Ok(negotiate::respond(&req, HttpResponse::Ok(), &body))
+        }
+        None => Err(ApiError::NotFound(format!("No user with phone {}", phone.trim()))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user, with a weak ETag", body = User),
+        (status = 304, description = "If-None-Match matched the current ETag"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn get_user_by_id(
+    req: HttpRequest,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id_value = user_id.into_inner();
+    let (user, tracing_ids) =
+        users::get(&data, user_id_value, tracing_requested(&req, &data).await).await?;
+    let response = match user {
+        Some(user) => {
+            stats::record_profile_view(&data, user.id);
+            let etag = user_etag(&user);
+            if unchanged(&req, &etag.0) {
+                HttpResponse::NotModified().insert_header(etag).finish()
+            } else {
+                let mut response = HttpResponse::Ok();
+                response.insert_header(etag);
+                if let Some(modified) = user.updated_at.or(user.created_at) {
+                    response.insert_header(last_modified(modified));
+                }
+                let body = links::user(&req, &user, serde_json::to_value(&user).unwrap_or_default());
+                negotiate::respond(&req, response, &body)
+            }
+        }
+        None => ApiError::NotFound(format!("User with ID {} not found", user_id_value)).error_response(),
+    };
+    Ok(report_tracing(&data.session, &tracing_ids, response).await)
+}
+
+/// Whether the user exists, without its body. Only the row's timestamps are
+/// read, so the ETag is sent only when the user is cached in memory; a
+/// matching If-None-Match then gets 304.
+#[utoipa::path(
+    head,
+    path = "/users/{id}",
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user exists; Last-Modified, and a weak ETag when cached"),
+        (status = 304, description = "If-None-Match matched the current ETag"),
+        (status = 404, description = "No such user"),
+    )
+)]
+pub async fn user_exists(
+    req: HttpRequest,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id_value = user_id.into_inner();
+    let existing = users::exists(&data, user_id_value)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User with ID {} not found", user_id_value)))?;
+    let mut response = HttpResponse::Ok();
+    if let Some(user) = &existing.cached {
+        let etag = user_etag(user);
+        if unchanged(&req, &etag.0) {
+            return Ok(HttpResponse::NotModified().insert_header(etag).finish());
+        }
+        response.insert_header(etag);
+    }
+    if let Some(modified) = existing.modified {
+        response.insert_header(last_modified(modified));
+    }
+    Ok(response.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn user() -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    #[test]
+    fn user_etags_are_weak_versions() {
+        let user = user();
+        let header::ETag(etag) = user_etag(&user);
+        assert!(etag.weak);
+        assert_eq!(etag.tag(), users::version(&user));
+    }
+
+    #[test]
+    fn trace_ids_are_returned_only_when_tracing_is_requested() {
+        let traced = TestRequest::default()
+            .insert_header(("X-Scylla-Trace", "true"))
+            .to_http_request();
+        assert!(trace_header(&traced, true));
+        assert!(!trace_header(&traced, false));
+        let id = Uuid::new_v4();
+        let response = with_trace_ids(HttpResponse::Ok().finish(), &[id]);
+        let header = response.headers().get("X-Scylla-Trace-Id").unwrap();
+        assert_eq!(header.to_str().unwrap(), id.to_string());
+
+        let untraced = TestRequest::default().to_http_request();
+        assert!(!trace_header(&untraced, true));
+        let response = with_trace_ids(HttpResponse::Ok().finish(), &[]);
+        assert!(response.headers().get("X-Scylla-Trace-Id").is_none());
+    }
+
+    #[test]
+    fn if_match_lists_the_accepted_versions() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(if_match(&req), None);
+        let req = TestRequest::default().insert_header(("If-Match", "*")).to_http_request();
+        assert_eq!(if_match(&req), None);
+        let req = TestRequest::default()
+            .insert_header(("If-Match", "W/\"abc\", \"def\""))
+            .to_http_request();
+        assert_eq!(if_match(&req), Some(vec![String::from("abc"), String::from("def")]));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let header::ETag(etag) = user_etag(&user());
+        let with = |value: String| {
+            TestRequest::default()
+                .insert_header(("If-None-Match", value))
+                .to_http_request()
+        };
+        assert!(!unchanged(&TestRequest::default().to_http_request(), &etag));
+        assert!(unchanged(&with(String::from("*")), &etag));
+        assert!(unchanged(&with(format!("\"{}\"", etag.tag())), &etag));
+        assert!(unchanged(&with(format!("\"other\", W/\"{}\"", etag.tag())), &etag));
+        assert!(!unchanged(&with(String::from("W/\"other\"")), &etag));
+    }
+
+    #[test]
+    fn last_modified_is_an_http_date() {
+        let modified = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let response = HttpResponse::Ok().insert_header(last_modified(modified)).finish();
+        assert_eq!(
+            response.headers().get(header::LAST_MODIFIED).unwrap(),
+            "Tue, 14 Nov 2023 22:13:20 GMT"
+        );
+    }
+
+    #[test]
+    fn a_disposable_email_succeeds_with_a_warning_header() {
+        let policy = validation::Policy::new(&crate::config::ValidationConfig::default());
+        let warnings = policy.warnings("ada@mailinator.com");
+        let response = with_warnings(HttpResponse::Created().finish(), &warnings);
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let lines: Vec<_> = response.headers().get_all(validation::WARNINGS_HEADER).collect();
+        assert_eq!(lines, ["email: uses a disposable email domain"]);
+
+        let clean = with_warnings(HttpResponse::Created().finish(), &policy.warnings("ada@example.com"));
+        assert!(!clean.headers().contains_key(validation::WARNINGS_HEADER));
+    }
+
+    #[actix_web::test]
+    async fn streamed_listings_are_one_json_object_per_line() {
+        let (ada, bob) = (user(), user());
+        let streamed = users::UserStream {
+            users: stream::iter(vec![Ok(ada.clone()), Ok(bob.clone())]).boxed_local(),
+            fields: Some(vec!["id", "email"]),
+            truncated: true,
+        };
+        let req = TestRequest::get().uri("/users").to_http_request();
+        let response = ndjson_response(&req, streamed);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), negotiate::NDJSON_TYPE);
+        assert_eq!(response.headers().get(users::TRUNCATED_HEADER).unwrap(), "true");
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let links = |id: Uuid| {
+            format!(
+                "\"_links\":{{\"self\":{{\"href\":\"http://localhost:8080/users/{id}\"}},\
+                 \"update\":{{\"href\":\"http://localhost:8080/update/{id}\",\"method\":\"PATCH\"}},\
+                 \"delete\":{{\"href\":\"http://localhost:8080/delete/{id}\",\"method\":\"DELETE\"}},\
+                 \"collection\":{{\"href\":\"http://localhost:8080/users\"}}}}"
+            )
+        };
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            format!(
+                "{{\"id\":\"{}\",\"email\":\"ada@example.com\",{}}}\n{{\"id\":\"{}\",\"email\":\"ada@example.com\",{}}}\n",
+                ada.id,
+                links(ada.id),
+                bob.id,
+                links(bob.id)
+            )
+        );
+    }
+}
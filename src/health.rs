@@ -0,0 +1,71 @@
+use crate::observe;
+use crate::panics::Panics;
+use crate::state::AppState;
+use actix_web::rt::time::timeout;
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use std::time::Instant;
+
+#[derive(Debug, Serialize)]
+struct Liveness {
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    status: &'static str,
+    scylla: &'static str,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// GET /healthz
+// Liveness only: answers as long as the process is serving HTTP, without
+// touching ScyllaDB, so a database outage doesn't get the pod restarted.
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(Liveness { status: "ok" })
+}
+
+// GET /readyz
+// Runs `SELECT now() FROM system.local` within `http.readiness_timeout_ms`;
+// 503 tells load balancers to stop routing here until ScyllaDB answers again,
+// or for good once handlers have panicked too often (see `panics`).
+pub async fn readyz(
+    data: web::Data<AppState>,
+    panics: Option<web::Data<Panics>>,
+) -> impl Responder {
+    let started = Instant::now();
+    let probe = observe::query(
+        &data,
+        "readiness_probe",
+        || data.session.execute_unpaged(&data.statements.readiness_probe, &[]),
+    );
+    let scylla_error = match timeout(data.readiness_timeout, probe).await {
+        Ok(Ok(_)) => None,
+        // The driver's message stays in the log; /readyz is unauthenticated.
+        Ok(Err(e)) => {
+            tracing::warn!(error = %e, "readiness probe failed");
+            Some(String::from("probe query failed"))
+        }
+        Err(_) => Some(format!("no response within {:?}", data.readiness_timeout)),
+    };
+    let panicking = panics.is_some_and(|panics| !panics.ready());
+    let scylla = if scylla_error.is_none() { "up" } else { "down" };
+    let error = if panicking {
+        Some(String::from("too many handler panics, awaiting a restart"))
+    } else {
+        scylla_error
+    };
+    let readiness = Readiness {
+        status: if error.is_none() { "ok" } else { "unavailable" },
+        scylla,
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        error,
+    };
+    if readiness.error.is_none() {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::error::{ApiError, Problem};
+use crate::events::EventKind;
+use crate::models::User;
+use crate::observe;
+use crate::outbox::{self, OutboxEvent};
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+// Every change made through the API is appended to its user's stream in
+// `user_events` as an immutable event: its type, its payload (the user after
+// the change, none for deletes), the version it took the stream to and when.
+// Versions count from 1 per user without gaps: an append claims the next one
+// with a lightweight transaction, rereading the last version when another
+// append won the race. The event is then published to the live feeds with its
+// version (through `outbox` when it is on), so the stream is what SSE and
+// WebSocket clients see, and one that missed events replays them from
+// GET /users/{id}/events?after=<version>.
+//
+// Like the audit log, the append follows the change it records, and a failed
+// one is logged (and the event published without a version) rather than
+// failing a change that has already been made.
+
+// Attempts at claiming the next version before an append gives up; each lost
+// attempt means another change to the user was appended meanwhile.
+const APPEND_ATTEMPTS: usize = 5;
+
+/// One event of a user's stream.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StoredEvent {
+    pub version: i64,
+    /// `created`, `updated`, `deleted` or `restored`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The user after the change; absent for deletes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    pub at: DateTime<Utc>,
+}
+
+/// A page of a user's event stream, oldest first.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EventStream {
+    pub events: Vec<StoredEvent>,
+    /// `after` for the next page; absent on the last.
+    pub next_after: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventStreamQuery {
+    /// Only events after this version; the stream is replayed from its
+    /// start by default.
+    pub after: Option<i64>,
+    /// Page size; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+}
+
+async fn last_version(state: &AppState, user_id: Uuid) -> Result<i64, ApiError> {
+    let result = observe::query(state, "select_last_user_event", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_last_user_event, (user_id,))
+    })
+    .await?;
+    let last = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading user events", e))?
+        .maybe_first_row::<(i64,)>()
+        .map_err(|e| ApiError::internal("Error reading user events", e))?;
+    Ok(last.map_or(0, |(version,)| version))
+}
+
+// Appends the change to the user's stream, returning the version it took.
+async fn try_append(
+    state: &AppState,
+    kind: EventKind,
+    user_id: Uuid,
+    user: Option<&User>,
+) -> Result<i64, ApiError> {
+    let payload = user
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| ApiError::internal("Failed to serialize user event", e))?;
+    let at = Utc::now();
+    for _ in 0..APPEND_ATTEMPTS {
+        let version = last_version(state, user_id).await? + 1;
+        let result = observe::conditional(state, "append_user_event", || {
+            state.session.execute_unpaged(
+                &state.statements.append_user_event,
+                (user_id, version, kind.name(), &payload, at),
+            )
+        })
+        .await?;
+        match statements::applied(result) {
+            Ok(true) => return Ok(version),
+            Ok(false) => continue,
+            Err(e) => return Err(ApiError::internal("Failed to append user event", e)),
+        }
+    }
+    Err(ApiError::Internal(format!(
+        "lost {} races for the next version of user {}'s events",
+        APPEND_ATTEMPTS, user_id
+    )))
+}
+
+// Appends a change made through the API to the user's stream and publishes
+// it to the live feeds and webhooks, through the outbox when it is on. An
+// event the outbox can't take is published directly instead.
+pub async fn append(state: &AppState, kind: EventKind, user_id: Uuid, user: Option<User>) {
+    let version = match try_append(state, kind, user_id, user.as_ref()).await {
+        Ok(version) => Some(version),
+        Err(e) => {
+            tracing::warn!(%user_id, kind = kind.name(), error = %e, "failed to append user event");
+            None
+        }
+    };
+    let event = OutboxEvent::new(kind, user_id, user, version);
+    if state.outbox {
+        match outbox::enqueue(state, &event).await {
+            Ok(()) => return,
+            Err(e) => tracing::warn!(
+                %user_id,
+                kind = kind.name(),
+                error = %e,
+                "failed to write user event to the outbox"
+            ),
+        }
+    }
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.notify(&event);
+    }
+    state.events.publish(kind, user_id, event.user, version);
+}
+
+// The page of `events`, read with one more than `limit` to tell whether
+// another page follows.
+fn page(mut events: Vec<StoredEvent>, limit: usize) -> EventStream {
+    let next_after = if events.len() > limit {
+        events.truncate(limit);
+        events.last().map(|event| event.version)
+    } else {
+        None
+    };
+    EventStream { events, next_after }
+}
+
+// A stored payload as the user it holds. One that no longer parses is served
+// without it rather than failing the whole page.
+fn payload(version: i64, payload: Option<String>) -> Option<User> {
+    let payload = payload?;
+    match serde_json::from_str(&payload) {
+        Ok(user) => Some(user),
+        Err(e) => {
+            tracing::warn!(version, error = %e, "unreadable user event payload");
+            None
+        }
+    }
+}
+
+/// The user's event stream, oldest first: every change made through the API
+/// with the user as it left it, a page at a time. Events are never changed
+/// or removed, so a replay from any version sees the same events, including
+/// those from before a hard delete.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/events",
+    params(("id" = Uuid, Path, description = "User id"), EventStreamQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "A page of the user's events, oldest first", body = EventStream),
+        (status = 400, description = "Invalid limit or version", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn get_user_events(
+    user_id: web::Path<Uuid>,
+    params: web::Query<EventStreamQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user_id.into_inner();
+    let (limit, truncated) = users::page_limit(&data, params.limit)?;
+    let after = params.after.unwrap_or(0);
+    if after < 0 {
+        return Err(ApiError::BadRequest(String::from("after must not be negative")));
+    }
+    let fetch = i32::try_from(limit + 1).unwrap_or(i32::MAX);
+    let result = observe::query(&data, "select_user_events", || {
+        data.session
+            .execute_unpaged(&data.statements.select_user_events, (user_id, after, fetch))
+    })
+    .await?;
+    let events = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading user events", e))?
+        .rows::<(i64, String, Option<String>, DateTime<Utc>)>()
+        .map_err(|e| ApiError::internal("Error reading user events", e))?
+        .map(|row| {
+            row.map(|(version, kind, stored, at)| StoredEvent {
+                version,
+                kind,
+                user: payload(version, stored),
+                at,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Error reading user events", e))?;
+    Ok(users::with_truncated(HttpResponse::Ok().json(page(events, limit)), truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(version: i64) -> StoredEvent {
+        StoredEvent {
+            version,
+            kind: String::from("updated"),
+            user: None,
+            at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn pages_continue_after_their_last_version() {
+        let first = page((1..=4).map(event).collect(), 3);
+        assert_eq!(first.events.iter().map(|event| event.version).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(first.next_after, Some(3));
+
+        let last = page((4..=6).map(event).collect(), 3);
+        assert_eq!(last.events.len(), 3);
+        assert_eq!(last.next_after, None);
+    }
+
+    #[test]
+    fn payloads_hold_the_user_after_the_change() {
+        let ada = User {
+            id: Uuid::from_u128(1),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        };
+        let stored = serde_json::to_string(&ada).unwrap();
+        let read = payload(1, Some(stored)).unwrap();
+        assert_eq!((read.id, read.name.as_str(), read.created_at), (ada.id, "Ada", ada.created_at));
+        assert!(payload(2, None).is_none());
+        assert!(payload(3, Some(String::from("{"))).is_none());
+
+        let sent = serde_json::to_value(event(4)).unwrap();
+        assert_eq!(sent["type"], "updated");
+        assert!(sent.get("user").is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use openssl::ssl::{SslConnector, SslMethod};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_openssl::SslStream;
+
+// A minimal HTTP client for calling out to other services: one HTTP/1.0
+// request per connection, over TLS for https URLs with the peer verified
+// against the system trust store. HTTP/1.0 keeps the response simple to
+// read, as servers answer it without chunking and close the connection after
+// the body. Responses larger than `MAX_RESPONSE` are refused.
+
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+static CONNECTOR: LazyLock<Result<SslConnector, String>> = LazyLock::new(|| {
+    SslConnector::builder(SslMethod::tls_client())
+        .map(|builder| builder.build())
+        .map_err(|e| e.to_string())
+});
+
+#[derive(Debug)]
+pub enum HttpError {
+    InvalidUrl(String),
+    Io(std::io::Error),
+    Tls(String),
+    Timeout,
+    Protocol(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::InvalidUrl(url) => write!(f, "invalid URL {}", url),
+            HttpError::Io(e) => write!(f, "connection failed: {}", e),
+            HttpError::Tls(e) => write!(f, "TLS handshake failed: {}", e),
+            HttpError::Timeout => write!(f, "no response in time"),
+            HttpError::Protocol(detail) => write!(f, "unexpected response: {}", detail),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+// The parts of an http or https URL a request needs.
+#[derive(Debug, PartialEq, Eq)]
+struct Target<'a> {
+    tls: bool,
+    host: &'a str,
+    port: u16,
+    // Path and query, starting with '/'.
+    path: &'a str,
+}
+
+fn target(url: &str) -> Result<Target<'_>, HttpError> {
+    let invalid = || HttpError::InvalidUrl(url.to_string());
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(invalid());
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() || authority.contains('@') {
+        return Err(invalid());
+    }
+    Ok(Target { tls, host, port, path })
+}
+
+// Whether `url` is an http or https URL `send` can call.
+pub fn check_url(url: &str) -> Result<(), HttpError> {
+    target(url).map(|_| ())
+}
+
+// The status and body of a complete response read to the end of the
+// connection.
+fn parse_response(raw: &[u8]) -> Result<Response, HttpError> {
+    let end = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| HttpError::Protocol(String::from("no end of headers")))?;
+    let head = std::str::from_utf8(&raw[..end])
+        .map_err(|_| HttpError::Protocol(String::from("headers are not UTF-8")))?;
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| HttpError::Protocol(format!("bad status line {:?}", status_line)))?;
+    Ok(Response {
+        status,
+        body: raw[end + 4..].to_vec(),
+    })
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> Result<Vec<u8>, HttpError> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    let mut raw = Vec::new();
+    let read = (&mut stream)
+        .take(MAX_RESPONSE as u64 + 1)
+        .read_to_end(&mut raw)
+        .await;
+    match read {
+        Ok(_) if raw.len() > MAX_RESPONSE => Err(HttpError::Protocol(String::from(
+            "response larger than 1 MiB",
+        ))),
+        Ok(_) => Ok(raw),
+        // Servers that close TLS connections without a close_notify still
+        // sent the whole response.
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !raw.is_empty() => Ok(raw),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn send_unbounded(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<(&str, &[u8])>,
+) -> Result<Response, HttpError> {
+    let target = target(url)?;
+    let default_port = if target.tls { 443 } else { 80 };
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}", method, target.path, target.host);
+    if target.port != default_port {
+        request.push_str(&format!(":{}", target.port));
+    }
+    request.push_str("\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some((content_type, content)) = body {
+        request.push_str(&format!("Content-Type: {}\r\n", content_type));
+        request.push_str(&format!("Content-Length: {}\r\n", content.len()));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    if let Some((_, content)) = body {
+        request.extend_from_slice(content);
+    }
+
+    let tcp = TcpStream::connect((target.host, target.port)).await?;
+    let raw = if target.tls {
+        let connector = CONNECTOR.as_ref().map_err(|e| HttpError::Tls(e.clone()))?;
+        let ssl = connector
+            .configure()
+            .and_then(|config| config.into_ssl(target.host))
+            .map_err(|e| HttpError::Tls(e.to_string()))?;
+        let mut stream = SslStream::new(ssl, tcp).map_err(|e| HttpError::Tls(e.to_string()))?;
+        Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(|e| HttpError::Tls(e.to_string()))?;
+        exchange(stream, &request).await?
+    } else {
+        exchange(tcp, &request).await?
+    };
+    parse_response(&raw)
+}
+
+// Sends one request and reads the whole response, giving up after
+// `deadline`. `body` is its content type and content.
+pub async fn send(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<(&str, &[u8])>,
+    deadline: Duration,
+) -> Result<Response, HttpError> {
+    timeout(deadline, send_unbounded(method, url, headers, body))
+        .await
+        .map_err(|_| HttpError::Timeout)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn urls_name_their_host_port_and_path() {
+        assert_eq!(
+            target("https://oauth2.googleapis.com/token").unwrap(),
+            Target { tls: true, host: "oauth2.googleapis.com", port: 443, path: "/token" }
+        );
+        assert_eq!(
+            target("http://localhost:8080").unwrap(),
+            Target { tls: false, host: "localhost", port: 8080, path: "/" }
+        );
+        assert!(target("ftp://example.com/").is_err());
+        assert!(target("https://user@example.com/").is_err());
+        assert!(target("https://example.com:port/").is_err());
+    }
+
+    #[test]
+    fn responses_split_into_status_and_body() {
+        let response = parse_response(b"HTTP/1.1 201 Created\r\nA: b\r\n\r\n{\"ok\":true}").unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (201, &b"{\"ok\":true}"[..]));
+        assert!(response.is_success());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_response(b"SMTP 200\r\n\r\n").is_err());
+    }
+
+    #[actix_web::test]
+    async fn requests_carry_their_headers_and_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token?x=1", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !received.ends_with(b"a=b") {
+                let read = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nhello").unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let response = send(
+            "POST",
+            &url,
+            &[("Accept", "application/json")],
+            Some(("application/x-www-form-urlencoded", b"a=b")),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (200, &b"hello"[..]));
+        let received = server.join().unwrap();
+        assert!(received.starts_with("POST /token?x=1 HTTP/1.0\r\nHost: 127.0.0.1:"));
+        assert!(received.contains("Accept: application/json\r\n"));
+        assert!(received.contains("Content-Length: 3\r\n\r\na=b"));
+    }
+}
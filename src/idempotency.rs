@@ -0,0 +1,197 @@
+use crate::error::{ApiError, FieldError};
+use crate::models::NewUser;
+use crate::observe;
+use crate::state::AppState;
+use crate::statements;
+use actix_web::http::header::HeaderName;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+// Responses of POST /register by `Idempotency-Key`. The first request with a
+// key claims it with a lightweight transaction before creating the user and
+// stores its response once done; a retry with the same key and body is
+// answered with that response instead of registering again. A key reused
+// with a different body is refused, as is one whose first request is still
+// in flight. A failed registration releases its key, so the client can fix
+// the request and try again. Keys expire after `http.idempotency_ttl_secs`.
+
+pub const KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+// Set on a response answered from the stored one.
+pub const REPLAYED_HEADER: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+// Longest key accepted, as in the IETF draft's examples.
+const MAX_KEY_LEN: usize = 255;
+
+pub enum Claim {
+    // The key is new: go ahead, then `complete` or `release` it.
+    Started,
+    // The key was used before; answer with the stored response.
+    Replay { status: u16, body: String },
+}
+
+fn invalid_key(message: &str) -> ApiError {
+    ApiError::Validation(vec![FieldError {
+        field: String::from("Idempotency-Key"),
+        message: message.to_string(),
+    }])
+}
+
+// Checks a presented key: printable ASCII, at most `MAX_KEY_LEN` long.
+pub fn validate_key(key: &str) -> Result<(), ApiError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(invalid_key("must be between 1 and 255 characters"));
+    }
+    if !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(invalid_key("must be printable ASCII without spaces"));
+    }
+    Ok(())
+}
+
+// What a retry must repeat to be answered from the stored response: every
+// field, destructured so a new one can't be missed. The password itself is
+// left out, so no digest of it is stored.
+pub fn fingerprint(new_user: &NewUser) -> String {
+    let NewUser {
+        name,
+        email,
+        password,
+        phone,
+        profile,
+        expires_in_seconds,
+    } = new_user;
+    let request = serde_json::json!({
+        "name": name,
+        "email": email,
+        "password": password.is_some(),
+        "phone": phone,
+        "profile": profile,
+        "expires_in_seconds": expires_in_seconds,
+    });
+    Sha256::digest(request.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn ttl_secs(state: &AppState) -> i32 {
+    i32::try_from(state.idempotency_ttl.as_secs()).unwrap_or(i32::MAX)
+}
+
+// Claims `key` for a request with `request_hash`, or reports how an earlier
+// request with it went.
+pub async fn claim(state: &AppState, key: &str, request_hash: &str) -> Result<Claim, ApiError> {
+    let result = observe::conditional(state, "claim_idempotency_key", || {
+        state.session.execute_unpaged(
+            &state.statements.claim_idempotency_key,
+            (key, request_hash, Utc::now(), ttl_secs(state)),
+        )
+    })
+    .await?;
+    if statements::applied(result)
+        .map_err(|e| ApiError::internal("Failed to claim idempotency key", e))?
+    {
+        return Ok(Claim::Started);
+    }
+
+    let result = observe::query(state, "select_idempotency_key", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_idempotency_key, (key,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read idempotency key", e))?
+        .maybe_first_row::<(Option<String>, Option<i32>, Option<String>)>()
+        .map_err(|e| ApiError::internal("Failed to read idempotency key", e))?;
+    match row {
+        Some((Some(stored_hash), _, _)) if stored_hash != request_hash => {
+            Err(invalid_key("was already used with a different request"))
+        }
+        Some((_, Some(status), Some(body))) => Ok(Claim::Replay {
+            status: u16::try_from(status).unwrap_or(500),
+            body,
+        }),
+        Some(_) => Err(ApiError::Conflict(String::from(
+            "a request with this Idempotency-Key is still in progress",
+        ))),
+        // Released or expired since the claim failed.
+        None => Err(ApiError::Conflict(String::from(
+            "a request with this Idempotency-Key has just finished; retry it",
+        ))),
+    }
+}
+
+// Stores the response of the request that claimed `key`. Failures are only
+// logged: the user exists either way, and a retry then finds the key in
+// flight until it expires rather than registering again.
+pub async fn complete(state: &AppState, key: &str, request_hash: &str, status: u16, body: &str) {
+    if let Err(e) = observe::conditional(state, "complete_idempotency_key", || {
+        state.session.execute_unpaged(
+            &state.statements.complete_idempotency_key,
+            (ttl_secs(state), i32::from(status), body, key, request_hash),
+        )
+    })
+    .await
+    {
+        tracing::warn!(error = %e, "failed to store idempotent response");
+    }
+}
+
+// Frees `key` after its request failed, unless a response was stored.
+pub async fn release(state: &AppState, key: &str) {
+    if let Err(e) = observe::conditional(state, "release_idempotency_key", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.release_idempotency_key, (key,))
+    })
+    .await
+    {
+        tracing::warn!(error = %e, "failed to release idempotency key");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_user(email: &str, password: Option<&str>) -> NewUser {
+        NewUser {
+            name: String::from("Ada"),
+            email: email.to_string(),
+            phone: None,
+            password: password.map(String::from),
+            profile: None,
+            expires_in_seconds: None,
+        }
+    }
+
+    #[test]
+    fn keys_must_be_short_printable_ascii() {
+        assert!(validate_key("5f0c7a7e-retry").is_ok());
+        assert!(validate_key("").is_err());
+        assert!(validate_key("has space").is_err());
+        assert!(validate_key("ключ").is_err());
+        assert!(validate_key(&"k".repeat(MAX_KEY_LEN)).is_ok());
+        assert!(validate_key(&"k".repeat(MAX_KEY_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn fingerprint_follows_the_request_but_not_the_password() {
+        let first = fingerprint(&new_user("ada@example.com", Some("secret-one")));
+        assert_eq!(first, fingerprint(&new_user("ada@example.com", Some("secret-two"))));
+        assert_ne!(first, fingerprint(&new_user("ada@example.com", None)));
+        assert_ne!(first, fingerprint(&new_user("bob@example.com", Some("secret-one"))));
+    }
+
+    #[test]
+    fn fingerprint_follows_the_phone_and_ttl() {
+        let first = fingerprint(&new_user("ada@example.com", None));
+        let mut with_phone = new_user("ada@example.com", None);
+        with_phone.phone = Some(String::from("+442079460958"));
+        assert_ne!(first, fingerprint(&with_phone));
+        let mut with_ttl = new_user("ada@example.com", None);
+        with_ttl.expires_in_seconds = Some(3600);
+        assert_ne!(first, fingerprint(&with_ttl));
+    }
+}
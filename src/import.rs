@@ -0,0 +1,506 @@
+use crate::error::{ApiError, Problem};
+use crate::export;
+use crate::models::{ImportLineError, ImportReport, NewUser, Profile};
+use crate::state::AppState;
+use crate::users;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use futures::{stream, StreamExt, TryStreamExt};
+use std::path::Path;
+
+// POST /users/import registers the users in a CSV or JSON Lines upload, the
+// inverse of GET /users/export.csv. The body is read as it arrives and split
+// into records, so only the records being inserted are held in memory. Each
+// record is validated and inserted like a POST /register/bulk item, at most
+// `http.bulk_concurrency` at once; one failing doesn't stop the rest, and the
+// response reports each failure by the line it starts on.
+//
+// A CSV upload starts with a header record naming its columns: `name` and
+// `email` are required, `password` and `profile` (as JSON) optional, and the
+// export's `id`, `created_at` and `updated_at` are ignored. A JSON Lines
+// upload holds one `NewUser` object per line. Blank lines are skipped.
+
+const CSV_TYPE: &str = "text/csv";
+const JSON_LINES_TYPES: [&str; 3] = ["application/x-ndjson", "application/jsonl", "application/x-jsonlines"];
+
+// Longest record accepted; a longer one is reported and skipped.
+const MAX_RECORD_BYTES: usize = 64 * 1024;
+// Failures listed in the report; `failed` counts all of them.
+const MAX_REPORTED_ERRORS: usize = 1_000;
+
+// Columns of a CSV upload the import reads.
+const CSV_COLUMNS: [&str; 5] = ["name", "email", "password", "profile", "phone"];
+// Columns an export writes that the import has no use for.
+const IGNORED_COLUMNS: [&str; 6] =
+    ["id", "created_at", "updated_at", "verified", "version", "status"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    JsonLines,
+}
+
+impl Format {
+    fn of(req: &HttpRequest) -> Result<Self, ApiError> {
+        match req.content_type() {
+            CSV_TYPE => Ok(Format::Csv),
+            content_type if JSON_LINES_TYPES.contains(&content_type) => Ok(Format::JsonLines),
+            _ => Err(ApiError::UnsupportedMediaType(format!(
+                "expected {} or {}",
+                CSV_TYPE,
+                JSON_LINES_TYPES[0]
+            ))),
+        }
+    }
+
+    // The format of a fixture file, by its extension.
+    fn of_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => Ok(Format::Csv),
+            Some("jsonl" | "ndjson") => Ok(Format::JsonLines),
+            _ => Err(format!("{}: expected a .csv, .jsonl or .ndjson file", path.display())),
+        }
+    }
+}
+
+// Where each column of a CSV upload's records goes.
+#[derive(Debug)]
+struct Columns {
+    count: usize,
+    // Indexes of `CSV_COLUMNS` in the records.
+    positions: [Option<usize>; 5],
+}
+
+impl Columns {
+    fn parse(header: &[String]) -> Result<Self, ApiError> {
+        let mut positions = [None; 5];
+        for (index, column) in header.iter().enumerate() {
+            let column = column.trim();
+            if IGNORED_COLUMNS.contains(&column) {
+                continue;
+            }
+            let Some(known) = CSV_COLUMNS.iter().position(|name| *name == column) else {
+                return Err(ApiError::BadRequest(format!(
+                    "unknown column \"{}\", expected some of {}",
+                    column,
+                    CSV_COLUMNS.join(", ")
+                )));
+            };
+            if positions[known].replace(index).is_some() {
+                return Err(ApiError::BadRequest(format!("column \"{}\" appears twice", column)));
+            }
+        }
+        if positions[0].is_none() || positions[1].is_none() {
+            return Err(ApiError::BadRequest(String::from(
+                "the header must name the name and email columns",
+            )));
+        }
+        Ok(Columns {
+            count: header.len(),
+            positions,
+        })
+    }
+
+    fn new_user(&self, mut fields: Vec<String>) -> Result<NewUser, String> {
+        if fields.len() != self.count {
+            return Err(format!("expected {} fields, found {}", self.count, fields.len()));
+        }
+        let mut take = |column: usize| {
+            self.positions[column]
+                .map(|index| std::mem::take(&mut fields[index]))
+                .filter(|value| !value.is_empty())
+        };
+        let name = take(0).unwrap_or_default();
+        let email = take(1).unwrap_or_default();
+        let password = take(2);
+        let profile = take(3)
+            .map(|profile| serde_json::from_str::<Profile>(&profile))
+            .transpose()
+            .map_err(|e| format!("profile: {}", e))?;
+        let phone = take(4);
+        Ok(NewUser {
+            name,
+            email,
+            password,
+            phone,
+            profile,
+            expires_in_seconds: None,
+        })
+    }
+}
+
+// The fields of one CSV record (RFC 4180). A field the export prefixed with
+// `'` to keep a spreadsheet from evaluating it gets its value back.
+fn csv_fields(record: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() && !was_quoted => {
+                quoted = true;
+                was_quoted = true;
+            }
+            ',' if !quoted => {
+                fields.push(restore(std::mem::take(&mut field)));
+                was_quoted = false;
+            }
+            _ if was_quoted && !quoted => {
+                return Err(String::from("text after a closing quote"));
+            }
+            '"' => return Err(String::from("quote inside an unquoted field")),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(String::from("unterminated quoted field"));
+    }
+    fields.push(restore(field));
+    Ok(fields)
+}
+
+fn restore(value: String) -> String {
+    match value.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(export::FORMULA_PREFIXES) => rest.to_string(),
+        _ => value,
+    }
+}
+
+// A record read from the upload, by the line it starts on.
+struct Record {
+    line: usize,
+    user: Result<NewUser, String>,
+}
+
+// Splits the upload into records as its chunks arrive. A CSV record ends at a
+// line break outside quotes; a JSON Lines record at any line break.
+struct Reader {
+    format: Format,
+    columns: Option<Columns>,
+    buffer: Vec<u8>,
+    quoted: bool,
+    oversized: bool,
+    // Line being read, and the one the current record started on.
+    line: usize,
+    start: usize,
+}
+
+impl Reader {
+    fn new(format: Format) -> Self {
+        Reader {
+            format,
+            columns: None,
+            buffer: Vec::new(),
+            quoted: false,
+            oversized: false,
+            line: 1,
+            start: 1,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Result<Vec<Record>, ApiError> {
+        let mut records = Vec::new();
+        for &byte in chunk {
+            if byte == b'"' && self.format == Format::Csv {
+                self.quoted = !self.quoted;
+            }
+            if byte == b'\n' {
+                self.line += 1;
+                if !self.quoted {
+                    records.extend(self.end_record()?);
+                    continue;
+                }
+            }
+            if self.buffer.len() < MAX_RECORD_BYTES {
+                self.buffer.push(byte);
+            } else {
+                self.oversized = true;
+            }
+        }
+        Ok(records)
+    }
+
+    fn finish(mut self) -> Result<Vec<Record>, ApiError> {
+        Ok(self.end_record()?.into_iter().collect())
+    }
+
+    fn end_record(&mut self) -> Result<Option<Record>, ApiError> {
+        let bytes = std::mem::take(&mut self.buffer);
+        let line = std::mem::replace(&mut self.start, self.line);
+        self.quoted = false;
+        if std::mem::take(&mut self.oversized) {
+            return Ok(Some(Record {
+                line,
+                user: Err(format!("record is longer than {} bytes", MAX_RECORD_BYTES)),
+            }));
+        }
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_) => {
+                return Ok(Some(Record {
+                    line,
+                    user: Err(String::from("record is not valid UTF-8")),
+                }));
+            }
+        };
+        let text = text.trim_end_matches('\r');
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let user = match self.format {
+            Format::JsonLines => serde_json::from_str::<NewUser>(text).map_err(|e| e.to_string()),
+            Format::Csv => {
+                let fields = csv_fields(text);
+                match &self.columns {
+                    Some(columns) => fields.and_then(|fields| columns.new_user(fields)),
+                    None => {
+                        let header = fields.map_err(|e| {
+                            ApiError::BadRequest(format!("invalid header on line {}: {}", line, e))
+                        })?;
+                        self.columns = Some(Columns::parse(&header)?);
+                        return Ok(None);
+                    }
+                }
+            }
+        };
+        Ok(Some(Record { line, user }))
+    }
+}
+
+impl ImportReport {
+    fn add(&mut self, line: usize, outcome: Result<(), ApiError>) {
+        match outcome {
+            Ok(()) => self.imported += 1,
+            Err(e) => {
+                self.failed += 1;
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push(ImportLineError {
+                        line,
+                        status: e.status_code().as_u16(),
+                        error: e.to_problem(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+async fn import_record(data: &AppState, record: Record) -> (usize, Result<(), ApiError>) {
+    let outcome = match record.user {
+        Ok(new_user) => users::register(data, new_user, false).await.map(|_| ()),
+        Err(message) => Err(ApiError::BadRequest(message)),
+    };
+    (record.line, outcome)
+}
+
+// The records completed by the next chunk of the upload, and those left at
+// its end.
+async fn read_chunk(
+    (mut payload, reader): (web::Payload, Option<Reader>),
+) -> Result<Option<(Vec<Record>, (web::Payload, Option<Reader>))>, ApiError> {
+    let Some(mut reader) = reader else {
+        return Ok(None);
+    };
+    match payload.next().await {
+        Some(chunk) => {
+            let chunk =
+                chunk.map_err(|e| ApiError::BadRequest(format!("Error reading the upload: {}", e)))?;
+            let records = reader.push(&chunk)?;
+            Ok(Some((records, (payload, Some(reader)))))
+        }
+        None => Ok(Some((reader.finish()?, (payload, None)))),
+    }
+}
+
+/// The inverse of GET /users/export.csv: registers each record of a CSV
+/// (`text/csv`, with a header record) or JSON Lines (`application/x-ndjson`)
+/// upload. Records fail on their own; the report lists the first 1000
+/// failures by line.
+#[utoipa::path(
+    post,
+    path = "/users/import",
+    request_body(content = String, content_type = "text/csv", description = "A header record naming name, email and optionally password and profile, then one record per user; or one NewUser JSON object per line as application/x-ndjson"),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "How many users were imported, and why the others failed", body = ImportReport),
+        (status = 400, description = "Unreadable upload or invalid CSV header", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 415, description = "Neither CSV nor JSON Lines", body = Problem),
+    )
+)]
+pub async fn import_users(
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let reader = Reader::new(Format::of(&req)?);
+    let records = stream::try_unfold((payload, Some(reader)), read_chunk)
+        .map_ok(|records| stream::iter(records).map(Ok::<_, ApiError>))
+        .try_flatten();
+
+    let data = &data;
+    let mut outcomes = std::pin::pin!(records
+        .map_ok(|record| async move { Ok(import_record(data, record).await) })
+        .try_buffered(data.bulk_concurrency));
+
+    let mut report = ImportReport::default();
+    while let Some((line, outcome)) = outcomes.try_next().await? {
+        report.add(line, outcome);
+    }
+    tracing::info!(imported = report.imported, failed = report.failed, "user import");
+    Ok(HttpResponse::Ok().json(report))
+}
+
+// Registers the users in a CSV or JSON Lines fixture file the same way, for
+// the `seed` command.
+pub async fn import_file(data: &AppState, path: &Path) -> Result<ImportReport, String> {
+    let format = Format::of_path(path)?;
+    let contents = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut reader = Reader::new(format);
+    let mut records = reader.push(&contents).map_err(|e| e.to_string())?;
+    records.extend(reader.finish().map_err(|e| e.to_string())?);
+
+    let mut outcomes = std::pin::pin!(stream::iter(records)
+        .map(|record| import_record(data, record))
+        .buffered(data.bulk_concurrency));
+    let mut report = ImportReport::default();
+    while let Some((line, outcome)) = outcomes.next().await {
+        report.add(line, outcome);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(format: Format, chunks: &[&str]) -> Result<Vec<Record>, ApiError> {
+        let mut reader = Reader::new(format);
+        let mut records = Vec::new();
+        for chunk in chunks {
+            records.extend(reader.push(chunk.as_bytes())?);
+        }
+        records.extend(reader.finish()?);
+        Ok(records)
+    }
+
+    #[test]
+    fn csv_fields_follow_rfc_4180() {
+        assert_eq!(csv_fields("a,,c").unwrap(), ["a", "", "c"]);
+        assert_eq!(
+            csv_fields("\"Lovelace, Ada\",\"say \"\"hi\"\"\",\"two\nlines\"").unwrap(),
+            ["Lovelace, Ada", "say \"hi\"", "two\nlines"]
+        );
+        assert!(csv_fields("\"open").is_err());
+        assert!(csv_fields("\"closed\"x").is_err());
+        assert!(csv_fields("in\"side").is_err());
+    }
+
+    #[test]
+    fn exported_formulas_get_their_values_back() {
+        assert_eq!(csv_fields("'=1+1,'+1,'plain").unwrap(), ["=1+1", "+1", "'plain"]);
+    }
+
+    #[test]
+    fn csv_records_map_by_header_and_keep_their_lines() {
+        let records = read(
+            Format::Csv,
+            &[
+                "id,email,name,profile,created_at\r\n",
+                "x,ada@example.com,\"Ada\nLovelace\",\"{\"\"locale\"\":\"\"en-GB\"\"}\",\r\n\r\n",
+                "y,bob@exa",
+                "mple.com,Bob,,\n",
+                "z,only-two-fields\n",
+                "w,eve@example.com,Eve,{bad},",
+            ],
+        )
+        .unwrap();
+        assert_eq!(records.len(), 4);
+
+        let ada = records[0].user.as_ref().unwrap();
+        assert_eq!(records[0].line, 2);
+        assert_eq!((ada.name.as_str(), ada.email.as_str()), ("Ada\nLovelace", "ada@example.com"));
+        assert_eq!(ada.profile.as_ref().unwrap().locale.as_deref(), Some("en-GB"));
+        assert!(ada.password.is_none());
+
+        let bob = records[1].user.as_ref().unwrap();
+        assert_eq!((records[1].line, bob.email.as_str()), (5, "bob@example.com"));
+        assert!(bob.profile.is_none());
+
+        assert_eq!(records[2].line, 6);
+        assert_eq!(records[2].user.as_ref().unwrap_err(), "expected 5 fields, found 2");
+        assert_eq!(records[3].line, 7);
+        assert!(records[3].user.as_ref().unwrap_err().starts_with("profile:"));
+    }
+
+    #[test]
+    fn csv_headers_are_checked() {
+        assert!(read(Format::Csv, &["name,email,nickname\n"]).is_err());
+        assert!(read(Format::Csv, &["name,password\n"]).is_err());
+        assert!(read(Format::Csv, &["name,email,email\n"]).is_err());
+        assert!(read(Format::Csv, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_lines_are_one_user_each() {
+        let records = read(
+            Format::JsonLines,
+            &[
+                "{\"name\":\"Ada\",\"email\":\"ada@example.com\",\"password\":\"secret-pw\"}\n\n{\"na",
+                "me\":\"Bob\"}\n{\"name\":\"Eve\",\"email\":\"eve@example.com\"}",
+            ],
+        )
+        .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].line, 1);
+        assert_eq!(records[0].user.as_ref().unwrap().password.as_deref(), Some("secret-pw"));
+        assert_eq!(records[1].line, 3);
+        assert!(records[1].user.is_err());
+        assert_eq!(records[2].line, 4);
+        assert_eq!(records[2].user.as_ref().unwrap().name, "Eve");
+    }
+
+    #[test]
+    fn oversized_records_are_reported_and_skipped() {
+        let long = "x".repeat(MAX_RECORD_BYTES + 1);
+        let chunks = [long.as_str(), "\n{\"name\":\"Ada\",\"email\":\"ada@example.com\"}\n"];
+        let records = read(Format::JsonLines, &chunks).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].user.as_ref().unwrap_err().contains("longer than"));
+        assert_eq!(records[1].line, 2);
+        assert!(records[1].user.is_ok());
+    }
+
+    #[test]
+    fn fixture_files_are_read_by_their_extension() {
+        assert_eq!(Format::of_path(Path::new("users.csv")), Ok(Format::Csv));
+        assert_eq!(Format::of_path(Path::new("fixtures/users.ndjson")), Ok(Format::JsonLines));
+        assert_eq!(Format::of_path(Path::new("users.jsonl")), Ok(Format::JsonLines));
+        assert!(Format::of_path(Path::new("users.json")).is_err());
+        assert!(Format::of_path(Path::new("users")).is_err());
+    }
+
+    #[test]
+    fn the_report_counts_every_failure_but_lists_the_first() {
+        let mut report = ImportReport::default();
+        report.add(2, Ok(()));
+        for line in 0..MAX_REPORTED_ERRORS + 5 {
+            report.add(line + 3, Err(ApiError::BadRequest(String::from("bad"))));
+        }
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.failed, MAX_REPORTED_ERRORS + 5);
+        assert_eq!(report.errors.len(), MAX_REPORTED_ERRORS);
+        assert_eq!((report.errors[0].line, report.errors[0].status), (3, 400));
+    }
+}
@@ -0,0 +1,316 @@
+use crate::error::ApiError;
+use crate::migrations::Ddl;
+use crate::models::{IndexState, IndexStatus, Indexes};
+use crate::state::AppState;
+use actix_web::{web, HttpResponse};
+use scylla::Session;
+use std::collections::HashMap;
+
+// Some of the API's queries go through secondary indexes, which the
+// migrations create. A deployment migrated by hand, or whose index was
+// dropped, fails those queries, and while an index is still being built
+// they may miss rows. GET /admin/indexes reports each index: whether it
+// exists on the right column and how many nodes have built it, read from
+// `system_schema.indexes` and Scylla's `system_distributed.view_build_status`
+// (an index is backed by a view named `<index>_index`). POST /admin/indexes
+// creates the missing ones, as `scylla.ensure_indexes` does at startup.
+//
+// An index of the right name on another column is reported, never dropped:
+// replacing it is a schema change for a person to make.
+
+// A secondary index the API depends on.
+pub struct RequiredIndex {
+    pub name: &'static str,
+    pub table: &'static str,
+    pub column: &'static str,
+    pub used_by: &'static str,
+}
+
+pub const REQUIRED: &[RequiredIndex] = &[
+    RequiredIndex {
+        name: "users_email_idx",
+        table: "users",
+        column: "email",
+        used_by: "POST /login and GET /users/by-email/{email} for users without an email claim",
+    },
+    RequiredIndex {
+        name: "users_tags_idx",
+        table: "users",
+        column: "tags",
+        used_by: "GET /users?tag=",
+    },
+    RequiredIndex {
+        name: "sessions_user_idx",
+        table: "sessions",
+        column: "user_id",
+        used_by: "GET and DELETE /users/{id}/sessions",
+    },
+];
+
+// The column an index target names: `email`, `"Email"`, or the collection
+// in `values(tags)`, `keys(m)`, `entries(m)` and `full(l)`.
+fn target_column(target: &str) -> &str {
+    let target = target.trim();
+    let inner = target
+        .split_once('(')
+        .and_then(|(_, rest)| rest.strip_suffix(')'))
+        .unwrap_or(target);
+    inner.trim().trim_matches('"')
+}
+
+// The state of `index`, given the target of the index by its name, if there
+// is one, and the build status each node reports for it, if the cluster
+// reports any.
+fn state_of(
+    index: &RequiredIndex,
+    target: Option<&str>,
+    builds: Option<&[String]>,
+) -> (IndexState, usize, usize) {
+    let Some(target) = target else {
+        return (IndexState::Missing, 0, 0);
+    };
+    if !target_column(target).eq_ignore_ascii_case(index.column) {
+        return (IndexState::Mismatched, 0, 0);
+    }
+    let builds = builds.unwrap_or_default();
+    let built = builds.iter().filter(|status| *status == "SUCCESS").count();
+    let building = builds.len() - built;
+    let state = match (builds.is_empty(), building) {
+        (true, _) => IndexState::Unknown,
+        (false, 0) => IndexState::Built,
+        (false, _) => IndexState::Building,
+    };
+    (state, built, building)
+}
+
+// The targets of the indexes in `keyspace`, by table and name.
+async fn targets(
+    session: &Session,
+    keyspace: &str,
+) -> Result<HashMap<(String, String), String>, String> {
+    let result = session
+        .query_unpaged(
+            "SELECT table_name, index_name, options FROM system_schema.indexes \
+             WHERE keyspace_name = ?",
+            (keyspace,),
+        )
+        .await
+        .map_err(|e| format!("cannot read the indexes of {}: {}", keyspace, e))?;
+    result
+        .into_rows_result()
+        .map_err(|e| e.to_string())?
+        .rows::<(String, String, Option<HashMap<String, String>>)>()
+        .map_err(|e| e.to_string())?
+        .map(|row| {
+            row.map(|(table, name, options)| {
+                let target = options.and_then(|mut options| options.remove("target"));
+                ((table, name), target.unwrap_or_default())
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())
+}
+
+// The build status of `index` on each node, or `None` when the cluster
+// doesn't report it (Cassandra has no `view_build_status` for indexes).
+async fn builds(session: &Session, keyspace: &str, index: &str) -> Option<Vec<String>> {
+    let result = session
+        .query_unpaged(
+            "SELECT status FROM system_distributed.view_build_status \
+             WHERE keyspace_name = ? AND view_name = ?",
+            (keyspace, format!("{}_index", index)),
+        )
+        .await
+        .ok()?;
+    result
+        .into_rows_result()
+        .ok()?
+        .rows::<(Option<String>,)>()
+        .ok()?
+        .map(|row| row.map(|(status,)| status.unwrap_or_default()))
+        .collect::<Result<_, _>>()
+        .ok()
+}
+
+// Every required index and its state.
+pub async fn report(session: &Session, keyspace: &str) -> Result<Indexes, String> {
+    let targets = targets(session, keyspace).await?;
+    let mut indexes = Vec::with_capacity(REQUIRED.len());
+    for index in REQUIRED {
+        let target = targets.get(&(index.table.to_string(), index.name.to_string()));
+        let builds = match target {
+            Some(_) => builds(session, keyspace, index.name).await,
+            None => None,
+        };
+        let (state, built_on, building_on) =
+            state_of(index, target.map(String::as_str), builds.as_deref());
+        indexes.push(IndexStatus {
+            name: index.name.to_string(),
+            table: index.table.to_string(),
+            column: index.column.to_string(),
+            used_by: index.used_by.to_string(),
+            state,
+            built_on,
+            building_on,
+        });
+    }
+    let ready = indexes.iter().all(|index| {
+        matches!(index.state, IndexState::Built | IndexState::Unknown)
+    });
+    Ok(Indexes {
+        indexes,
+        ready,
+        created: Vec::new(),
+    })
+}
+
+// Creates the required indexes that are missing and reports them all. Each
+// is created `IF NOT EXISTS`, so a concurrent creator does no harm, and
+// waits for schema agreement before the next.
+pub async fn ensure(session: &Session, keyspace: &str, ddl: &Ddl) -> Result<Indexes, String> {
+    let before = report(session, keyspace).await?;
+    let mut created = Vec::new();
+    for (index, status) in REQUIRED.iter().zip(&before.indexes) {
+        if status.state != IndexState::Missing {
+            continue;
+        }
+        let statement = format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {}.{} ({})",
+            index.name, keyspace, index.table, index.column
+        );
+        ddl.run(session, statement)
+            .await
+            .map_err(|e| format!("cannot create index {}: {}", index.name, e))?;
+        ddl.agree(session)
+            .await
+            .map_err(|e| format!("no schema agreement on index {}: {}", index.name, e))?;
+        tracing::info!(index = index.name, keyspace, "created index");
+        created.push(index.name.to_string());
+    }
+    if created.is_empty() {
+        return Ok(before);
+    }
+    let mut after = report(session, keyspace).await?;
+    after.created = created;
+    Ok(after)
+}
+
+// Logs the state of each index at startup, creating the missing ones first
+// with `create`. Only a failed creation is an error.
+pub async fn at_startup(
+    session: &Session,
+    keyspace: &str,
+    ddl: &Ddl,
+    create: bool,
+) -> Result<(), String> {
+    let indexes = if create {
+        ensure(session, keyspace, ddl).await?
+    } else {
+        match report(session, keyspace).await {
+            Ok(indexes) => indexes,
+            Err(e) => {
+                tracing::warn!(error = %e, "cannot check indexes");
+                return Ok(());
+            }
+        }
+    };
+    for index in &indexes.indexes {
+        match index.state {
+            IndexState::Built | IndexState::Unknown => {
+                tracing::info!(index = %index.name, state = ?index.state, "index checked")
+            }
+            state => tracing::warn!(
+                index = %index.name,
+                ?state,
+                used_by = %index.used_by,
+                "index not ready; queries through it fail or miss rows"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// The secondary indexes the API queries through, whether each exists on
+/// the right column, and how many nodes have built it.
+#[utoipa::path(
+    get,
+    path = "/admin/indexes",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The indexes and their state", body = Indexes),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+    )
+)]
+pub async fn get_indexes(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let indexes = report(&data.session, &data.keyspace)
+        .await
+        .map_err(|e| ApiError::internal("Failed to read indexes", e))?;
+    Ok(HttpResponse::Ok().json(indexes))
+}
+
+/// Creates the missing indexes, and reports them all as GET does. A new
+/// index is `building` until every node has indexed the existing rows.
+#[utoipa::path(
+    post,
+    path = "/admin/indexes",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The indexes, with those created listed", body = Indexes),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+    )
+)]
+pub async fn create_indexes(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let indexes = ensure(&data.session, &data.keyspace, &data.ddl)
+        .await
+        .map_err(|e| ApiError::internal("Failed to create indexes", e))?;
+    tracing::info!(created = ?indexes.created, "indexes ensured");
+    Ok(HttpResponse::Ok().json(indexes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::{statements, MIGRATIONS};
+
+    #[test]
+    fn targets_name_the_indexed_column() {
+        assert_eq!(target_column("email"), "email");
+        assert_eq!(target_column("values(tags)"), "tags");
+        assert_eq!(target_column("\"Email\""), "Email");
+        assert_eq!(target_column(" keys(metadata) "), "metadata");
+    }
+
+    #[test]
+    fn states_follow_the_target_and_the_builds() {
+        let tags = &REQUIRED[1];
+        let statuses = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(state_of(tags, None, None), (IndexState::Missing, 0, 0));
+        assert_eq!(state_of(tags, Some("email"), None), (IndexState::Mismatched, 0, 0));
+        assert_eq!(state_of(tags, Some("values(tags)"), None), (IndexState::Unknown, 0, 0));
+        let building = statuses(&["SUCCESS", "STARTED", "SUCCESS"]);
+        assert_eq!(
+            state_of(tags, Some("values(tags)"), Some(&building)),
+            (IndexState::Building, 2, 1)
+        );
+        let built = statuses(&["SUCCESS", "SUCCESS"]);
+        assert_eq!(state_of(tags, Some("tags"), Some(&built)), (IndexState::Built, 2, 0));
+    }
+
+    #[test]
+    fn the_migrations_create_every_required_index() {
+        let created: Vec<String> = MIGRATIONS
+            .iter()
+            .flat_map(|migration| statements(migration.cql))
+            .filter(|statement| statement.to_uppercase().starts_with("CREATE INDEX"))
+            .collect();
+        for index in REQUIRED {
+            let expected = format!(
+                "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+                index.name, index.table, index.column
+            );
+            assert!(created.contains(&expected), "no migration creates {}", index.name);
+        }
+    }
+}
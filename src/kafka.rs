@@ -0,0 +1,570 @@
+use crate::config::KafkaConfig;
+use crate::publisher::{Message, Publisher};
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::sync::Mutex;
+
+// A minimal Kafka producer for the outbox relay: the Metadata (v1) and
+// Produce (v3) requests over plain TCP, one record per request, without
+// compression, idempotence, TLS or SASL. Each event becomes a record keyed
+// by the user id, so a user's events land on one partition and keep their
+// order; the partition is chosen with the Java client's murmur2 hash, as
+// other producers keyed the same way would. The value and headers are the
+// `publisher::Message`'s. A produce that fails, or isn't acknowledged in
+// time, fails the relay's publish and is tried again on its next run, so
+// the topic sees every event at least once.
+//
+// The partition leaders are read from the first of `kafka.brokers` that
+// answers, and again every `kafka.metadata_max_age_secs` or after a
+// failure. One connection per leader is kept open between produces.
+//
+// It is a stopgap until rdkafka can be vendored, and is only built with the
+// `kafka` feature.
+
+const PRODUCE: i16 = 0;
+const METADATA: i16 = 3;
+// Largest response read back.
+const MAX_RESPONSE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum KafkaError {
+    Io(std::io::Error),
+    Timeout,
+    Protocol(String),
+    // A Kafka error code, for the topic or partition produced to.
+    Broker(i16),
+    NoLeader(i32),
+}
+
+impl fmt::Display for KafkaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KafkaError::Io(e) => write!(f, "Kafka connection failed: {}", e),
+            KafkaError::Timeout => write!(f, "Kafka did not answer in time"),
+            KafkaError::Protocol(detail) => write!(f, "unexpected Kafka response: {}", detail),
+            KafkaError::Broker(code) => write!(f, "Kafka error code {}", code),
+            KafkaError::NoLeader(partition) => write!(f, "partition {} has no leader", partition),
+        }
+    }
+}
+
+impl std::error::Error for KafkaError {}
+
+impl From<std::io::Error> for KafkaError {
+    fn from(e: std::io::Error) -> Self {
+        KafkaError::Io(e)
+    }
+}
+
+// Kafka's murmur2, as `Utils.murmur2` in the Java client.
+fn murmur2(data: &[u8]) -> i32 {
+    const M: u32 = 0x5bd1e995;
+    let mut h: u32 = 0x9747b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate().rev() {
+            h ^= u32::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+// The partition of `partitions` the Java client's default partitioner
+// picks for `key`.
+fn partition_for(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+static CRC32C: LazyLock<[u32; 256]> = LazyLock::new(|| {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut crc = n as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+        *entry = crc;
+    }
+    table
+});
+
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        CRC32C[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// Builds a request in Kafka's big-endian encoding.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn i8(&mut self, value: i8) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn i16(&mut self, value: i16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn i64(&mut self, value: i64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.i16(value.len() as i16);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.i32(value.len() as i32);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    // Zigzag varint, as record fields are written.
+    fn varint(&mut self, value: i64) -> &mut Self {
+        let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+        while zigzag >= 0x80 {
+            self.0.push((zigzag as u8) | 0x80);
+            zigzag >>= 7;
+        }
+        self.0.push(zigzag as u8);
+        self
+    }
+
+    fn varbytes(&mut self, value: &[u8]) -> &mut Self {
+        self.varint(value.len() as i64);
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+// Reads a response in Kafka's encoding.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], KafkaError> {
+        if self.0.len() < n {
+            return Err(KafkaError::Protocol(String::from("response cut short")));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> Result<i16, KafkaError> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, KafkaError> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>, KafkaError> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|_| KafkaError::Protocol(String::from("string is not UTF-8")))
+    }
+
+    fn string(&mut self) -> Result<String, KafkaError> {
+        self.nullable_string()?
+            .ok_or_else(|| KafkaError::Protocol(String::from("null string")))
+    }
+
+    fn count(&mut self) -> Result<usize, KafkaError> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+}
+
+// A record batch (magic 2) holding one record.
+fn record_batch(key: &[u8], value: &[u8], headers: &[(&str, &str)], timestamp: i64) -> Vec<u8> {
+    let mut record = Encoder::default();
+    record.i8(0).varint(0).varint(0).varbytes(key).varbytes(value);
+    record.varint(headers.len() as i64);
+    for (name, value) in headers {
+        record.varbytes(name.as_bytes()).varbytes(value.as_bytes());
+    }
+    let mut records = Encoder::default();
+    records.varbytes(&record.0);
+
+    // What the CRC covers: from the attributes to the end.
+    let mut covered = Encoder::default();
+    covered
+        .i16(0) // attributes: no compression, create time
+        .i32(0) // last offset delta
+        .i64(timestamp)
+        .i64(timestamp)
+        .i64(-1) // producer id
+        .i16(-1) // producer epoch
+        .i32(-1) // base sequence
+        .i32(1); // records
+    covered.0.extend_from_slice(&records.0);
+
+    let mut batch = Encoder::default();
+    batch.i64(0); // base offset
+    batch.i32((4 + 1 + 4 + covered.0.len()) as i32);
+    batch.i32(-1); // partition leader epoch
+    batch.i8(2); // magic
+    batch.0.extend_from_slice(&crc32c(&covered.0).to_be_bytes());
+    batch.0.extend_from_slice(&covered.0);
+    batch.0
+}
+
+// The partition leaders' addresses, by partition, as metadata last gave them.
+fn leaders(response: &[u8], topic: &str) -> Result<Vec<Option<String>>, KafkaError> {
+    let mut decoder = Decoder(response);
+    let mut brokers = HashMap::new();
+    for _ in 0..decoder.count()? {
+        let id = decoder.i32()?;
+        let host = decoder.string()?;
+        let port = decoder.i32()?;
+        decoder.nullable_string()?; // rack
+        brokers.insert(id, format!("{}:{}", host, port));
+    }
+    decoder.i32()?; // controller
+    for _ in 0..decoder.count()? {
+        let error = decoder.i16()?;
+        let name = decoder.string()?;
+        decoder.take(1)?; // internal
+        let mut partitions = Vec::new();
+        for _ in 0..decoder.count()? {
+            decoder.i16()?; // the partition's error; a leader is what matters
+            let index = decoder.i32()?;
+            let leader = decoder.i32()?;
+            for _ in 0..2 {
+                let nodes = decoder.count()?;
+                decoder.take(4 * nodes)?; // replicas, then in-sync replicas
+            }
+            partitions.push((index, brokers.get(&leader).cloned()));
+        }
+        if name != topic {
+            continue;
+        }
+        if error != 0 {
+            return Err(KafkaError::Broker(error));
+        }
+        partitions.sort_by_key(|(index, _)| *index);
+        return Ok(partitions.into_iter().map(|(_, leader)| leader).collect());
+    }
+    Err(KafkaError::Protocol(format!("no metadata for topic {}", topic)))
+}
+
+// The error code a Produce response gives the one partition produced to.
+fn produce_error(response: &[u8]) -> Result<i16, KafkaError> {
+    let mut decoder = Decoder(response);
+    if decoder.count()? != 1 {
+        return Err(KafkaError::Protocol(String::from("expected one topic")));
+    }
+    decoder.string()?;
+    if decoder.count()? != 1 {
+        return Err(KafkaError::Protocol(String::from("expected one partition")));
+    }
+    decoder.i32()?;
+    decoder.i16()
+}
+
+type Connection = BufStream<TcpStream>;
+
+// Sends one request and reads its response body, past the correlation id.
+async fn round_trip(
+    connection: &mut Connection,
+    api_key: i16,
+    api_version: i16,
+    correlation_id: i32,
+    client_id: &str,
+    body: &[u8],
+) -> Result<Vec<u8>, KafkaError> {
+    let mut header = Encoder::default();
+    header.i16(api_key).i16(api_version).i32(correlation_id).string(client_id);
+    let size = (header.0.len() + body.len()) as i32;
+    connection.write_all(&size.to_be_bytes()).await?;
+    connection.write_all(&header.0).await?;
+    connection.write_all(body).await?;
+    connection.flush().await?;
+
+    let size = connection.read_i32().await?;
+    if size < 4 || size as usize > MAX_RESPONSE {
+        return Err(KafkaError::Protocol(format!("response of {} bytes", size)));
+    }
+    let mut response = vec![0u8; size as usize];
+    connection.read_exact(&mut response).await?;
+    let answered = i32::from_be_bytes(response[..4].try_into().unwrap());
+    if answered != correlation_id {
+        return Err(KafkaError::Protocol(String::from("response to another request")));
+    }
+    response.drain(..4);
+    Ok(response)
+}
+
+#[derive(Default)]
+struct Cluster {
+    // Leader addresses by partition; empty until metadata is read.
+    leaders: Vec<Option<String>>,
+    read_at: Option<Instant>,
+    connections: HashMap<String, Connection>,
+    next_correlation_id: i32,
+}
+
+impl Cluster {
+    fn correlation_id(&mut self) -> i32 {
+        self.next_correlation_id = self.next_correlation_id.wrapping_add(1);
+        self.next_correlation_id
+    }
+
+    // Forgets what a failure may have made stale.
+    fn reset(&mut self, address: Option<&str>) {
+        self.read_at = None;
+        if let Some(address) = address {
+            self.connections.remove(address);
+        }
+    }
+}
+
+pub struct Producer {
+    brokers: Vec<String>,
+    topic: String,
+    client_id: String,
+    acks: i16,
+    timeout: Duration,
+    metadata_max_age: Duration,
+    // Produces go one at a time, as the relay sends them.
+    cluster: Mutex<Cluster>,
+}
+
+impl Producer {
+    pub fn new(config: &KafkaConfig) -> Self {
+        Producer {
+            brokers: config.brokers.clone(),
+            topic: config.topic.clone(),
+            client_id: config.client_id.clone(),
+            acks: config.acks,
+            timeout: Duration::from_millis(config.timeout_ms),
+            metadata_max_age: Duration::from_secs(config.metadata_max_age_secs),
+            cluster: Mutex::new(Cluster::default()),
+        }
+    }
+
+    async fn refresh_metadata(&self, cluster: &mut Cluster) -> Result<(), KafkaError> {
+        let mut body = Encoder::default();
+        body.i32(1).string(&self.topic);
+        let mut last_error = KafkaError::Protocol(String::from("no brokers configured"));
+        for broker in &self.brokers {
+            let correlation_id = cluster.correlation_id();
+            let response = async {
+                let mut connection = BufStream::new(TcpStream::connect(broker.as_str()).await?);
+                round_trip(&mut connection, METADATA, 1, correlation_id, &self.client_id, &body.0)
+                    .await
+            }
+            .await;
+            match response.and_then(|response| leaders(&response, &self.topic)) {
+                Ok(leaders) if !leaders.is_empty() => {
+                    cluster.leaders = leaders;
+                    cluster.read_at = Some(Instant::now());
+                    return Ok(());
+                }
+                Ok(_) => last_error = KafkaError::Protocol(String::from("topic has no partitions")),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn produce_unbounded(&self, key: &[u8], batch: &[u8]) -> Result<(), KafkaError> {
+        let mut cluster = self.cluster.lock().await;
+        let fresh = cluster
+            .read_at
+            .is_some_and(|read_at| read_at.elapsed() < self.metadata_max_age);
+        if !fresh {
+            self.refresh_metadata(&mut cluster).await?;
+        }
+        let partition = partition_for(key, cluster.leaders.len());
+        let Some(address) = cluster.leaders[partition].clone() else {
+            cluster.reset(None);
+            return Err(KafkaError::NoLeader(partition as i32));
+        };
+
+        let mut body = Encoder::default();
+        body.i16(-1) // no transactional id
+            .i16(self.acks)
+            .i32(self.timeout.as_millis() as i32)
+            .i32(1)
+            .string(&self.topic)
+            .i32(1)
+            .i32(partition as i32)
+            .bytes(batch);
+        let correlation_id = cluster.correlation_id();
+        let result = async {
+            if !cluster.connections.contains_key(&address) {
+                let connection = BufStream::new(TcpStream::connect(address.as_str()).await?);
+                cluster.connections.insert(address.clone(), connection);
+            }
+            let connection = cluster.connections.get_mut(&address).expect("just connected");
+            let response =
+                round_trip(connection, PRODUCE, 3, correlation_id, &self.client_id, &body.0)
+                    .await?;
+            match produce_error(&response)? {
+                0 => Ok(()),
+                code => Err(KafkaError::Broker(code)),
+            }
+        }
+        .await;
+        if result.is_err() {
+            cluster.reset(Some(&address));
+        }
+        result
+    }
+
+    // Produces one record, waiting for the acknowledgement `kafka.acks` asks
+    // for.
+    async fn produce(&self, key: &[u8], batch: &[u8]) -> Result<(), KafkaError> {
+        let produced = timeout(self.timeout, self.produce_unbounded(key, batch)).await;
+        match produced {
+            Ok(result) => result,
+            Err(_) => {
+                // The connection may be mid-response; start afresh.
+                let mut cluster = self.cluster.lock().await;
+                cluster.connections.clear();
+                cluster.reset(None);
+                Err(KafkaError::Timeout)
+            }
+        }
+    }
+}
+
+impl Publisher for Producer {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let key = message.user_id.to_string();
+            let batch = record_batch(
+                key.as_bytes(),
+                &message.value,
+                &message.headers(),
+                message.occurred_at.timestamp_millis(),
+            );
+            self.produce(key.as_bytes(), &batch).await.map_err(|e| e.to_string())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::outbox::OutboxEvent;
+    use crate::publisher::SCHEMA;
+    use uuid::Uuid;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn keys_hash_as_the_java_client_does() {
+        assert_eq!(murmur2(b"21"), -973932308);
+        assert_eq!(murmur2(b"foobar"), -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+        assert_eq!(murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8"), -58897971);
+        assert_eq!(murmur2(b"abc"), 479470107);
+        assert!(partition_for(b"foobar", 3) < 3);
+    }
+
+    #[test]
+    fn batches_carry_their_checksum() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        let batch = record_batch(b"key", b"{}", &[("schema", "v1")], 1_700_000_000_000);
+        let crc = u32::from_be_bytes(batch[17..21].try_into().unwrap());
+        assert_eq!(crc, crc32c(&batch[21..]));
+        let length = i32::from_be_bytes(batch[8..12].try_into().unwrap());
+        assert_eq!(length as usize, batch.len() - 12);
+        assert_eq!(batch[16], 2);
+    }
+
+    // Reads one request frame, returning its api key and body.
+    fn read_request(stream: &mut std::net::TcpStream) -> (i16, i32, Vec<u8>) {
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size).unwrap();
+        let mut request = vec![0u8; i32::from_be_bytes(size) as usize];
+        stream.read_exact(&mut request).unwrap();
+        let api_key = i16::from_be_bytes(request[..2].try_into().unwrap());
+        let correlation_id = i32::from_be_bytes(request[4..8].try_into().unwrap());
+        (api_key, correlation_id, request)
+    }
+
+    fn respond(stream: &mut std::net::TcpStream, correlation_id: i32, body: &[u8]) {
+        let mut response = Encoder::default();
+        response.i32(4 + body.len() as i32).i32(correlation_id);
+        response.0.extend_from_slice(body);
+        stream.write_all(&response.0).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn events_are_produced_to_the_partition_leader() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (api_key, correlation_id, _) = read_request(&mut stream);
+            assert_eq!(api_key, METADATA);
+            let mut metadata = Encoder::default();
+            metadata.i32(1).i32(7).string("127.0.0.1").i32(port as i32).i16(-1).i32(7);
+            metadata.i32(1).i16(0).string("user-events").i8(0);
+            metadata.i32(1).i16(0).i32(0).i32(7).i32(1).i32(7).i32(1).i32(7);
+            respond(&mut stream, correlation_id, &metadata.0);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let (api_key, correlation_id, request) = read_request(&mut stream);
+            assert_eq!(api_key, PRODUCE);
+            let mut produced = Encoder::default();
+            produced.i32(1).string("user-events").i32(1).i32(0).i16(0).i64(42).i64(-1).i32(0);
+            respond(&mut stream, correlation_id, &produced.0);
+            request
+        });
+
+        let producer = Producer::new(&KafkaConfig {
+            enabled: true,
+            brokers: vec![format!("127.0.0.1:{}", port)],
+            ..KafkaConfig::default()
+        });
+        let event = OutboxEvent::new(EventKind::Created, Uuid::new_v4(), None, Some(1));
+        producer.send(&Message::new(&event).unwrap()).await.unwrap();
+        let request = broker.join().unwrap();
+        let key = event.user_id.to_string();
+        let contains = |needle: &[u8]| request.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(key.as_bytes()));
+        assert!(contains(br#""type":"user.created""#));
+        assert!(contains(SCHEMA.as_bytes()));
+    }
+}
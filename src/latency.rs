@@ -0,0 +1,168 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Sliding window of the most recent request latencies, kept per endpoint
+// (method plus route pattern).
+pub struct LatencyWindows {
+    window: usize,
+    endpoints: Mutex<HashMap<String, VecDeque<Duration>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Percentiles {
+    samples: usize,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LatencyQuery {
+    #[serde(default)]
+    reset: bool,
+}
+
+impl LatencyWindows {
+    pub fn new(window: usize) -> Self {
+        LatencyWindows {
+            window: window.max(1),
+            endpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, endpoint: String, elapsed: Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let samples = endpoints.entry(endpoint).or_default();
+        if samples.len() == self.window {
+            samples.pop_front();
+        }
+        samples.push_back(elapsed);
+    }
+
+    fn summary(&self, reset: bool) -> BTreeMap<String, Percentiles> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let summary = endpoints
+            .iter()
+            .map(|(endpoint, samples)| {
+                let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+                sorted.sort_unstable();
+                let percentiles = Percentiles {
+                    samples: sorted.len(),
+                    p50_ms: percentile(&sorted, 0.50),
+                    p90_ms: percentile(&sorted, 0.90),
+                    p99_ms: percentile(&sorted, 0.99),
+                };
+                (endpoint.clone(), percentiles)
+            })
+            .collect();
+        if reset {
+            endpoints.clear();
+        }
+        summary
+    }
+}
+
+// Nearest-rank percentile over already sorted samples, in milliseconds.
+fn percentile(sorted: &[Duration], quantile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+}
+
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let windows = req.app_data::<web::Data<LatencyWindows>>().cloned();
+    let started = Instant::now();
+
+    let res = next.call(req).await?;
+
+    if let Some(windows) = windows {
+        // The route pattern is only known once the request has been routed.
+        let pattern = res
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| String::from("unmatched"));
+        windows.record(format!("{} {}", res.request().method(), pattern), started.elapsed());
+    }
+    Ok(res)
+}
+
+// GET /admin/latency — p50/p90/p99 per endpoint over the current window.
+// `?reset=true` clears the windows after reading them.
+pub async fn get_latency(
+    query: web::Query<LatencyQuery>,
+    windows: web::Data<LatencyWindows>,
+) -> impl Responder {
+    HttpResponse::Ok().json(windows.summary(query.reset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    fn millis(values: &[u64]) -> Vec<Duration> {
+        values.iter().copied().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted = millis(&(1..=100).collect::<Vec<_>>());
+        assert_eq!(percentile(&sorted, 0.50), 50.0);
+        assert_eq!(percentile(&sorted, 0.90), 90.0);
+        assert_eq!(percentile(&sorted, 0.99), 99.0);
+        assert_eq!(percentile(&millis(&[7]), 0.99), 7.0);
+        assert_eq!(percentile(&[], 0.5), 0.0);
+    }
+
+    #[test]
+    fn windows_keep_the_latest_samples_and_reset() {
+        let windows = LatencyWindows::new(2);
+        for elapsed in millis(&[100, 1, 2]) {
+            windows.record(String::from("GET /users"), elapsed);
+        }
+        let summary = windows.summary(true);
+        let users = &summary["GET /users"];
+        assert_eq!(users.samples, 2);
+        assert_eq!(users.p99_ms, 2.0);
+        assert!(windows.summary(false).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn requests_show_up_per_route_pattern() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(LatencyWindows::new(10)))
+                .route("/users/{id}", web::get().to(HttpResponse::Ok))
+                .route("/admin/latency", web::get().to(get_latency))
+                .wrap(from_fn(track)),
+        )
+        .await;
+        for id in 0..3 {
+            let uri = format!("/users/{}", id);
+            call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        }
+        let req = TestRequest::get().uri("/admin/latency?reset=true").to_request();
+        let summary: Value = call_and_read_body_json(&app, req).await;
+        let users = &summary["GET /users/{id}"];
+        assert_eq!(users["samples"], 3);
+        assert!(users["p50_ms"].as_f64().unwrap() > 0.0);
+
+        let req = TestRequest::get().uri("/admin/latency").to_request();
+        let summary: Value = call_and_read_body_json(&app, req).await;
+        assert!(summary.get("GET /users/{id}").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+// The user API as a library, for serving it from an actix-web application
+// of one's own. `AppState::builder` connects to the cluster (or takes a
+// session) and prepares the statements of a keyspace whose schema is in
+// place, and `configure` mounts the routes on any `ServiceConfig`, such as a
+// scope of the host application:
+//
+//     let state = AppState::builder(&config).build().await?;
+//     App::new().service(web::scope("/accounts").configure(|cfg| configure(cfg, state.clone())))
+//
+// The mounted routes bring their own authentication, but not the app-wide
+// middleware the server binary (`main.rs`) wraps them in: request ids,
+// metrics, rate limiting, load shedding, tenant routing, maintenance mode
+// and the handler deadline, all in their modules for a host to wrap its own
+// app with. JWTs are only accepted with a `web::Data<auth::JwtAuth>`
+// registered, and the background tasks (`shutdown::Tasks`) are the host's to
+// start.
+
+use actix_web::web;
+use error::ApiError;
+
+pub mod addresses;
+pub mod api_keys;
+pub mod audit;
+pub mod auth;
+pub mod avatars;
+pub mod backfill;
+pub mod batch;
+pub mod breaker;
+pub mod bulk_delete;
+pub mod cache;
+pub mod cdc;
+pub mod check_db;
+pub mod coalesce;
+pub mod cli;
+pub mod cluster;
+pub mod compression;
+pub mod config;
+pub mod consistency;
+pub mod count;
+pub mod cql;
+pub mod cors;
+pub mod days;
+pub mod deadline;
+pub mod dry_run;
+pub mod emails;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod flags;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod groups;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handlers;
+pub mod health;
+pub mod history;
+pub mod http_client;
+pub mod idempotency;
+pub mod import;
+pub mod indexes;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod latency;
+pub mod limiter;
+pub mod links;
+pub mod logging;
+pub mod login;
+pub mod mailer;
+pub mod maintenance;
+pub mod maintenance_mode;
+pub mod metadata;
+pub mod metrics;
+pub mod migrations;
+pub mod models;
+pub mod monitor;
+pub mod multipart;
+pub mod nats;
+pub mod negotiate;
+pub mod null_fields;
+pub mod oauth;
+pub mod observe;
+pub mod openapi;
+pub mod outbox;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod paging;
+pub mod panics;
+pub mod password_reset;
+pub mod patch;
+pub mod phones;
+pub mod posts;
+pub mod publisher;
+pub mod rate_limit;
+pub mod raw_cql;
+pub mod redis;
+pub mod reload;
+pub mod repository;
+pub mod request_id;
+pub mod restore;
+pub mod retry;
+pub mod search;
+pub mod search_index;
+pub mod seed;
+pub mod self_test;
+pub mod session;
+pub mod sessions;
+pub mod shadow;
+pub mod shared_cache;
+pub mod shedding;
+pub mod shutdown;
+pub mod smtp;
+pub mod snapshot;
+pub mod sse;
+pub mod startup;
+pub mod state;
+pub mod statements;
+pub mod status;
+pub mod stats;
+pub mod tags;
+pub mod tenants;
+pub mod tls;
+pub mod users;
+pub mod v1;
+pub mod validation;
+pub mod verification;
+pub mod webhooks;
+pub mod write_behind;
+pub mod ws;
+
+pub use config::Config;
+pub use state::{AppState, AppStateBuilder};
+
+// Registers the state and the extractor settings the handlers rely on, then
+// the routes of version 1 under `v1::PREFIX` and unprefixed.
+fn mount(
+    cfg: &mut web::ServiceConfig,
+    state: AppState,
+    tenants: Option<web::Data<tenants::Tenants>>,
+) {
+    cfg.app_data(web::Data::from(state.latency_windows.clone()))
+        .app_data(web::PathConfig::default().error_handler(|e, _| {
+            ApiError::BadRequest(format!("Invalid path: {}", e)).into()
+        }))
+        .app_data(web::QueryConfig::default().error_handler(|e, _| {
+            ApiError::BadRequest(format!("Invalid query string: {}", e)).into()
+        }))
+        .app_data(negotiate::json_config(state.max_json_body_bytes))
+        .app_data(web::PayloadConfig::new(state.max_json_body_bytes))
+        .app_data(web::Data::new(state));
+    let with_tenants = tenants.is_some();
+    if let Some(tenants) = tenants {
+        cfg.app_data(tenants);
+    }
+    cfg.service(web::scope(v1::PREFIX).configure(|cfg| v1::configure(cfg, with_tenants)))
+        .configure(|cfg| v1::configure(cfg, with_tenants));
+}
+
+// Mounts the user API on `cfg`, served from `state`.
+pub fn configure(cfg: &mut web::ServiceConfig, state: AppState) {
+    mount(cfg, state, None);
+}
+
+// `configure`, with requests routed to their tenant's keyspace by
+// `tenants::route` and the tenants administered at /admin/tenants.
+pub fn configure_with_tenants(
+    cfg: &mut web::ServiceConfig,
+    state: AppState,
+    tenants: web::Data<tenants::Tenants>,
+) {
+    mount(cfg, state, Some(tenants));
+}
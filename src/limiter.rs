@@ -0,0 +1,83 @@
+use crate::config::ScyllaConfig;
+use actix_web::rt::time::timeout;
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Caps the CQL requests in flight (`observe`) at
+// `scylla.max_in_flight_queries`, across every handler and background task.
+// A request over the cap waits its turn for up to
+// `scylla.query_queue_timeout_ms`, then fails with 503 and `Retry-After`, so
+// a spike queues briefly and then sheds load instead of piling more requests
+// onto the cluster until every one of them times out. A request holds its
+// place through its retries. 0 leaves the number of requests unbounded.
+pub struct Limiter {
+    max: usize,
+    permits: Option<Semaphore>,
+    queue_timeout: Duration,
+}
+
+// A request's place among those in flight, given back when dropped.
+pub struct Admitted<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Limiter {
+    pub fn new(config: &ScyllaConfig) -> Self {
+        Limiter {
+            max: config.max_in_flight_queries,
+            permits: (config.max_in_flight_queries > 0)
+                .then(|| Semaphore::new(config.max_in_flight_queries)),
+            queue_timeout: Duration::from_millis(config.query_queue_timeout_ms),
+        }
+    }
+
+    // Waits for a place for one request; `None` if none came free in time.
+    pub async fn admit(&self) -> Option<Admitted<'_>> {
+        let Some(permits) = &self.permits else {
+            return Some(Admitted { _permit: None });
+        };
+        // The semaphore is never closed, so acquiring only fails by timing out.
+        match timeout(self.queue_timeout, permits.acquire()).await {
+            Ok(Ok(permit)) => Some(Admitted { _permit: Some(permit) }),
+            _ => None,
+        }
+    }
+
+    // Requests in flight, when they are capped.
+    pub fn in_flight(&self) -> Option<usize> {
+        self.permits.as_ref().map(|permits| self.max - permits.available_permits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max: usize) -> Limiter {
+        Limiter::new(&ScyllaConfig {
+            max_in_flight_queries: max,
+            query_queue_timeout_ms: 20,
+            ..ScyllaConfig::default()
+        })
+    }
+
+    #[actix_web::test]
+    async fn requests_over_the_cap_wait_then_give_up() {
+        let limiter = limiter(2);
+        let first = limiter.admit().await.unwrap();
+        let _second = limiter.admit().await.unwrap();
+        assert_eq!(limiter.in_flight(), Some(2));
+        assert!(limiter.admit().await.is_none());
+
+        drop(first);
+        assert!(limiter.admit().await.is_some());
+    }
+
+    #[actix_web::test]
+    async fn zero_leaves_requests_unbounded() {
+        let limiter = limiter(0);
+        let admitted: Vec<_> = futures::future::join_all((0..100).map(|_| limiter.admit())).await;
+        assert!(admitted.iter().all(Option::is_some));
+        assert_eq!(limiter.in_flight(), None);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::models::{User, UsersPage};
+use crate::v1;
+use actix_web::HttpRequest;
+use serde::Serialize;
+use serde_json::Value;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// Users and pages of users carry `_links`, HAL style, to the requests a
+// client can make next, so a hypermedia client can follow them instead of
+// building paths. They are added to the response body once it is serialized
+// (and narrowed to `fields`), so the stored and cached `User` is unchanged.
+// Links are absolute, on the scheme and host the request was sent to (as
+// given by `Forwarded` or `X-Forwarded-*` behind a proxy), and keep the
+// version prefix of the request's path.
+
+/// A related resource; `method` is given for those not fetched with GET.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Link {
+    pub href: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+}
+
+/// The `_links` of a user.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserLinks {
+    #[serde(rename = "self")]
+    pub this: Link,
+    pub update: Link,
+    pub delete: Link,
+    pub collection: Link,
+}
+
+/// The `_links` of a page of users.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PageLinks {
+    #[serde(rename = "self")]
+    pub this: Link,
+    /// Absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<Link>,
+}
+
+fn link(href: String, method: Option<&str>) -> Link {
+    Link {
+        href,
+        method: method.map(String::from),
+    }
+}
+
+// Where the request's links start: its scheme, host and version prefix.
+fn base(req: &HttpRequest) -> String {
+    let path = req.path();
+    let version = &path[..path.len() - v1::unversioned(path).len()];
+    let info = req.connection_info();
+    format!("{}://{}{}", info.scheme(), info.host(), version)
+}
+
+fn user_links(base: &str, id: Uuid) -> UserLinks {
+    UserLinks {
+        this: link(format!("{}/users/{}", base, id), None),
+        update: link(format!("{}/update/{}", base, id), Some("PATCH")),
+        delete: link(format!("{}/delete/{}", base, id), Some("DELETE")),
+        collection: link(format!("{}/users", base), None),
+    }
+}
+
+// `value`, `user` as it is sent, with the user's links added.
+fn add_user_links(base: &str, user: &User, mut value: Value) -> Value {
+    if let Some(object) = value.as_object_mut() {
+        let links = serde_json::to_value(user_links(base, user.id)).unwrap_or_default();
+        object.insert(String::from("_links"), links);
+    }
+    value
+}
+
+// The request's query with its cursor replaced by `cursor`.
+fn with_cursor(query: &str, cursor: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some("cursor"))
+        .chain([format!("cursor={}", cursor).as_str()])
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// `user`, or the part of it in `value`, with its links.
+pub fn user(req: &HttpRequest, user: &User, value: Value) -> Value {
+    add_user_links(&base(req), user, value)
+}
+
+// `value`, `page` as it is sent, with links to this page and the next, and
+// to each of its users. Cursors are URL-safe base64, so need no escaping.
+pub fn page(req: &HttpRequest, page: &UsersPage, mut value: Value) -> Value {
+    let base = base(req);
+    if let Some(users) = value.get_mut("users").and_then(Value::as_array_mut) {
+        for (sent, user) in users.iter_mut().zip(&page.users) {
+            *sent = add_user_links(&base, user, sent.take());
+        }
+    }
+    let listing = format!("{}{}", base, v1::unversioned(req.path()));
+    let query = req.query_string();
+    let links = PageLinks {
+        this: link(
+            if query.is_empty() {
+                listing.clone()
+            } else {
+                format!("{}?{}", listing, query)
+            },
+            None,
+        ),
+        next: page
+            .next_cursor
+            .as_deref()
+            .map(|cursor| link(format!("{}?{}", listing, with_cursor(query, cursor)), None)),
+    };
+    if let Some(object) = value.as_object_mut() {
+        object.insert(String::from("_links"), serde_json::to_value(links).unwrap_or_default());
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users;
+    use actix_web::test::TestRequest;
+
+    fn ada() -> User {
+        User {
+            id: Uuid::from_u128(1),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    #[test]
+    fn users_link_to_themselves_in_the_requests_version() {
+        let req = TestRequest::get()
+            .uri("/api/v1/users/00000000-0000-0000-0000-000000000001")
+            .insert_header(("host", "api.example.com"))
+            .to_http_request();
+        let ada = ada();
+        let sent = user(&req, &ada, serde_json::to_value(&ada).unwrap());
+        let id = "00000000-0000-0000-0000-000000000001";
+        assert_eq!(sent["_links"]["self"]["href"], format!("http://api.example.com/api/v1/users/{}", id));
+        assert_eq!(sent["_links"]["update"]["method"], "PATCH");
+        assert_eq!(sent["_links"]["delete"]["href"], format!("http://api.example.com/api/v1/delete/{}", id));
+        assert_eq!(sent["_links"]["collection"]["href"], "http://api.example.com/api/v1/users");
+
+        let unversioned = TestRequest::get()
+            .uri("/users/by-email/ada@example.com")
+            .insert_header(("host", "api.example.com"))
+            .to_http_request();
+        let sent = user(&unversioned, &ada, serde_json::to_value(&ada).unwrap());
+        assert_eq!(sent["_links"]["collection"]["href"], "http://api.example.com/users");
+    }
+
+    #[test]
+    fn pages_link_to_the_next_page() {
+        let req = TestRequest::get()
+            .uri("/users/search?prefix=a&cursor=old&limit=1")
+            .insert_header(("host", "api.example.com"))
+            .to_http_request();
+        let listed = UsersPage {
+            users: vec![ada()],
+            next_cursor: Some(String::from("new")),
+        };
+        let sent = page(&req, &listed, users::project_page(&listed, &["name"]));
+        assert_eq!(
+            sent["_links"]["self"]["href"],
+            "http://api.example.com/users/search?prefix=a&cursor=old&limit=1"
+        );
+        assert_eq!(
+            sent["_links"]["next"]["href"],
+            "http://api.example.com/users/search?prefix=a&limit=1&cursor=new"
+        );
+        assert_eq!(sent["users"][0]["name"], "Ada");
+        assert!(sent["users"][0].get("id").is_none());
+        assert_eq!(
+            sent["users"][0]["_links"]["self"]["href"],
+            "http://api.example.com/users/00000000-0000-0000-0000-000000000001"
+        );
+
+        let last = UsersPage {
+            users: Vec::new(),
+            next_cursor: None,
+        };
+        let plain = TestRequest::get().uri("/users").to_http_request();
+        let sent = page(&plain, &last, serde_json::to_value(&last).unwrap());
+        assert_eq!(sent["_links"]["self"]["href"], "http://localhost:8080/users");
+        assert!(sent["_links"].get("next").is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::{LogConfig, LogFormat};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+// Swaps the level filter of the installed subscriber; see `reload`.
+pub type LogLevel = reload::Handle<EnvFilter, Registry>;
+
+fn filter(config: &LogConfig) -> Result<EnvFilter, String> {
+    match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives),
+        Err(_) => EnvFilter::try_new(&config.level),
+    }
+    .map_err(|e| format!("invalid log level: {}", e))
+}
+
+// Installs the global subscriber. `RUST_LOG` overrides `log.level` so a single
+// run can be made more verbose without touching the config file.
+pub fn init(config: &LogConfig) -> Result<LogLevel, String> {
+    let (filter, level) = reload::Layer::new(filter(config)?);
+    let fmt_layer = match config.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(fmt_layer);
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::otel::layer(config.otlp_endpoint.as_deref())?);
+    registry.try_init().map_err(|e| e.to_string())?;
+    Ok(level)
+}
+
+// Filters events by `config.level` from now on, unless `RUST_LOG` is set;
+// returns the directives in effect.
+pub fn set_level(level: &LogLevel, config: &LogConfig) -> Result<String, String> {
+    let filter = filter(config)?;
+    let directives = filter.to_string();
+    level.reload(filter).map_err(|e| e.to_string())?;
+    Ok(directives)
+}
+
+// Wraps each request in a span carrying its request id, method, path, matched
+// route, status and latency, and logs one line when the response is ready.
+// The ids are recorded by `request_id::assign`, which runs inside the span so
+// it can take the span's trace id.
+pub async fn request_span(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let span = tracing::info_span!(
+        "request",
+        request_id = Empty,
+        trace_id = Empty,
+        method = %req.method(),
+        path = %req.path(),
+        route = Empty,
+        status = Empty,
+        latency_ms = Empty,
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::set_remote_parent(&span, req.headers());
+    let started = Instant::now();
+
+    let res = next.call(req).instrument(span.clone()).await?;
+
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    if let Some(route) = res.request().match_pattern() {
+        span.record("route", route.as_str());
+    }
+    span.record("status", res.status().as_u16());
+    span.record("latency_ms", latency_ms);
+    span.in_scope(|| tracing::info!("request completed"));
+    Ok(res)
+}
@@ -0,0 +1,170 @@
+use crate::auth::{AuthError, JwtAuth};
+use crate::emails;
+use crate::error::ApiError;
+use crate::observe;
+use crate::sessions;
+use crate::state::AppState;
+use crate::stats;
+use crate::status;
+use crate::users::CredentialsRow;
+use actix_web::{web, HttpRequest, HttpResponse};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use scylla::frame::response::result::CqlValue;
+use scylla::prepared_statement::PreparedStatement;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LoginResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+    /// Trades for a new access token at POST /token/refresh, once.
+    pub refresh_token: String,
+}
+
+// Argon2id PHC string for `password` with a fresh random salt.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+}
+
+fn verify_password(password: &str, stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// Checked when no user with a password matches, so an unknown email costs as
+// much as a wrong password and response times don't reveal which emails exist.
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("dummy password").expect("hashing a constant succeeds"));
+
+// Hashing is deliberately slow, so it runs on the blocking pool rather than
+// stalling the worker's event loop.
+pub async fn hash_password_blocking(password: String) -> Result<String, String> {
+    web::block(move || hash_password(&password))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+// Ids and password hashes of the live users `statement` finds by `key`.
+async fn credentials(
+    data: &AppState,
+    statement_name: &'static str,
+    statement: &PreparedStatement,
+    key: CqlValue,
+) -> Result<Vec<(Uuid, String)>, ApiError> {
+    let result = observe::query(data, statement_name, || {
+        data.session.execute_unpaged(statement, (&key,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| e.to_string())
+        .and_then(|rows| {
+            rows.rows::<CredentialsRow>()
+                .map_err(|e| e.to_string())?
+                .filter_map(|row| match row {
+                    Ok(row) => {
+                        let id = row.id;
+                        row.live_hash().map(|hash| Ok((id, hash)))
+                    }
+                    Err(e) => Some(Err(e.to_string())),
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| ApiError::internal("Failed to log in", e))
+}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Credentials accepted", body = LoginResponse),
+        (status = 401, description = "Invalid email or password"),
+        (status = 403, description = "The user is suspended"),
+        (status = 503, description = "Token signing is not configured"),
+    )
+)]
+pub async fn login(
+    req: HttpRequest,
+    request: web::Json<LoginRequest>,
+    data: web::Data<AppState>,
+    jwt_auth: Option<web::Data<JwtAuth>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(jwt_auth) = jwt_auth else {
+        return Err(AuthError::Unavailable(String::from("auth.jwt_secret is not configured")).into());
+    };
+    let LoginRequest { email, password } = request.into_inner();
+
+    // Emails match case-insensitively, like registration, through the claim
+    // that holds the address. Users without a claim row (registered before
+    // `users_by_email` and not yet backfilled) are found by exact match.
+    let email = email.trim();
+    let candidates = match emails::owner(&data, email).await? {
+        Some(user_id) => {
+            let (hash, _) = data.users.password_hash(user_id).await?;
+            hash.map(|hash| (user_id, hash)).into_iter().collect()
+        }
+        None => {
+            credentials(
+                &data,
+                "select_credentials_by_email",
+                &data.statements.select_credentials_by_email,
+                CqlValue::Text(email.to_string()),
+            )
+            .await?
+        }
+    };
+
+    let matched = web::block(move || {
+        if candidates.is_empty() {
+            verify_password(&password, &DUMMY_HASH);
+            return None;
+        }
+        candidates
+            .into_iter()
+            .find(|(_, hash)| verify_password(&password, hash))
+            .map(|(id, _)| id)
+    })
+    .await;
+    let user_id = matched
+        .map_err(|e| ApiError::internal("Failed to log in", e))?
+        .ok_or(AuthError::InvalidCredentials)?;
+
+    status::check_sign_in(&data, user_id).await?;
+    let signed_in = sessions::sign_in(&data, &jwt_auth, user_id, &req).await?;
+    stats::record_login(&data, user_id);
+    Ok(HttpResponse::Ok().json(signed_in))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_passwords_verify() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::config::SmtpConfig;
+use crate::metrics::Metrics;
+use crate::models::User;
+use crate::shutdown::{Stopping, Tasks};
+use crate::smtp::Smtp;
+use actix_web::rt::time::sleep;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use futures::future::{self, Either};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use uuid::Uuid;
+
+// With `smtp.enabled`, every user created through the API is sent a welcome
+// email asking it to verify its address: `verification::issue` queues it
+// once the token is stored, rendered from `smtp.welcome_subject` and
+// `smtp.welcome_body` with `{name}`, `{email}` and `{verification_url}`
+// filled in. Registrations never wait for the relay: the email goes into a
+// queue of `smtp.queue_capacity`, and is dropped and counted when that is
+// full. A dispatcher sends up to `smtp.concurrency` at once, trying a send
+// that failed for a transient reason (no connection, a 4xx reply) again up to
+// `ATTEMPTS` times in all; one that still fails is logged and counted, and
+// the user can be verified by other means. On shutdown the dispatcher sends
+// what is queued, within the background grace period.
+
+const ATTEMPTS: u32 = 3;
+const RETRY_PAUSE: Duration = Duration::from_secs(2);
+
+// A rendered email and where it goes.
+struct Email {
+    to: String,
+    message: String,
+}
+
+// The bare address in `from`, `Name <address>` or `address`, if it looks
+// like one.
+pub fn address(from: &str) -> Option<&str> {
+    let from = from.trim();
+    let address = match from.rsplit_once('<') {
+        Some((_, rest)) => rest.strip_suffix('>')?,
+        None => from,
+    };
+    let valid = address.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty() && !domain.is_empty()
+    }) && !address.contains(|c: char| c.is_whitespace() || "<>\"".contains(c));
+    valid.then_some(address)
+}
+
+// `template` with each `{key}` of `values` replaced, in one pass so a value
+// holding a placeholder is left as it is.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            let key = &after[..end];
+            values
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| (*value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// A header value on one line, RFC 2047-encoded unless it is plain ASCII.
+fn header_value(value: &str) -> String {
+    let value: String = value.chars().filter(|c| *c != '\r' && *c != '\n').collect();
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+pub struct Mailer {
+    sender: mpsc::Sender<Email>,
+    // Taken by the dispatcher when it starts.
+    receiver: Mutex<Option<mpsc::Receiver<Email>>>,
+    smtp: Arc<Smtp>,
+    from: String,
+    // The address alone, for MAIL FROM and the Message-ID.
+    envelope_from: String,
+    verification_url: String,
+    subject: String,
+    body: String,
+    concurrency: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl Mailer {
+    pub fn new(config: &SmtpConfig, metrics: Arc<Metrics>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity);
+        Mailer {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            smtp: Arc::new(Smtp::new(config)),
+            from: config.from.clone(),
+            // Both were checked when the configuration was loaded.
+            envelope_from: address(&config.from).unwrap_or_default().to_string(),
+            verification_url: config.verification_url.clone().unwrap_or_default(),
+            subject: config.welcome_subject.clone(),
+            body: config.welcome_body.clone(),
+            concurrency: config.concurrency,
+            metrics,
+        }
+    }
+
+    fn render(&self, user: &User, token: &str) -> Email {
+        let url = self.verification_url.replace("{token}", token);
+        let values = [
+            ("name", user.name.as_str()),
+            ("email", user.email.as_str()),
+            ("verification_url", url.as_str()),
+        ];
+        let domain = self.envelope_from.rsplit('@').next().unwrap_or("localhost");
+        let body = fill(&self.body, &values).replace("\r\n", "\n").replace('\n', "\r\n");
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\n{}",
+            header_value(&self.from),
+            header_value(&user.email),
+            header_value(&fill(&self.subject, &values)),
+            Utc::now().to_rfc2822(),
+            Uuid::new_v4(),
+            domain,
+            body,
+        );
+        Email {
+            to: user.email.clone(),
+            message,
+        }
+    }
+
+    // Queues the welcome email for `user`, whose verification token is
+    // `token`, dropping it if the queue is full.
+    pub fn welcome(&self, user: &User, token: &str) {
+        if self.sender.try_send(self.render(user, token)).is_err() {
+            self.metrics.email("dropped");
+            tracing::warn!(user_id = %user.id, "email queue full, welcome email dropped");
+        }
+    }
+
+    // Starts the dispatcher; a mailer has one.
+    pub fn start(self: &Arc<Self>, tasks: &mut Tasks) {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("the mail dispatcher is started once");
+        let mailer = self.clone();
+        tasks.spawn("mail dispatcher", move |stopping| mailer.dispatch(receiver, stopping));
+    }
+
+    async fn dispatch(
+        self: Arc<Self>,
+        mut receiver: mpsc::Receiver<Email>,
+        mut stopping: Stopping,
+    ) {
+        let slots = Arc::new(Semaphore::new(self.concurrency));
+        loop {
+            let received = {
+                let received = std::pin::pin!(receiver.recv());
+                let stopped = std::pin::pin!(stopping.stopped());
+                match future::select(received, stopped).await {
+                    Either::Left((email, _)) => email,
+                    Either::Right(_) => None,
+                }
+            };
+            let Some(email) = received else {
+                break;
+            };
+            let Ok(slot) = slots.clone().acquire_owned().await else {
+                break;
+            };
+            let mailer = self.clone();
+            actix_web::rt::spawn(async move {
+                mailer.send(email).await;
+                drop(slot);
+            });
+        }
+
+        // Shutdown: nothing more is registered, so what is queued is all
+        // there will be.
+        receiver.close();
+        if !receiver.is_empty() {
+            tracing::info!(queued = receiver.len(), "sending queued emails");
+        }
+        while let Some(email) = receiver.recv().await {
+            self.send(email).await;
+        }
+    }
+
+    async fn send(&self, email: Email) {
+        for attempt in 1..=ATTEMPTS {
+            match self.smtp.send(&self.envelope_from, &email.to, &email.message).await {
+                Ok(()) => {
+                    self.metrics.email("sent");
+                    return;
+                }
+                Err(e) if e.is_transient() && attempt < ATTEMPTS => {
+                    tracing::debug!(attempt, error = %e, "email not sent, trying again");
+                    sleep(RETRY_PAUSE).await;
+                }
+                Err(e) => {
+                    self.metrics.email("failed");
+                    tracing::warn!(attempts = attempt, error = %e, "email not sent");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SmtpTls;
+
+    fn user(name: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: Some(false),
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    fn mailer(queue_capacity: usize) -> Mailer {
+        Mailer::new(
+            &SmtpConfig {
+                enabled: true,
+                tls: SmtpTls::None,
+                queue_capacity,
+                verification_url: Some(String::from("https://app.example.com/verify/{token}")),
+                ..SmtpConfig::default()
+            },
+            Arc::new(Metrics::new()),
+        )
+    }
+
+    #[test]
+    fn addresses_are_taken_from_the_sender() {
+        assert_eq!(address("HireMe <no-reply@example.com>"), Some("no-reply@example.com"));
+        assert_eq!(address(" ops@example.com "), Some("ops@example.com"));
+        assert_eq!(address("HireMe"), None);
+        assert_eq!(address("HireMe <no reply@example.com>"), None);
+    }
+
+    #[test]
+    fn placeholders_are_filled_once() {
+        let values = [("name", "{email}"), ("email", "ada@example.com")];
+        assert_eq!(
+            fill("Hi {name} <{email}> {other}", &values),
+            "Hi {email} <ada@example.com> {other}"
+        );
+    }
+
+    #[test]
+    fn welcome_emails_link_to_the_verification() {
+        let email = mailer(1).render(&user("Adá\r\nBcc: eve@example.com"), "t0k3n");
+        assert_eq!(email.to, "ada@example.com");
+        let (headers, _) = email.message.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("\r\nSubject: =?UTF-8?B?"));
+        assert!(!headers.contains("\r\nBcc:"));
+        assert!(email.message.contains("https://app.example.com/verify/t0k3n\r\n"));
+        assert!(email.message.contains("Message-ID: <"));
+        assert!(email.message.contains("@example.com>\r\n"));
+    }
+
+    #[test]
+    fn a_full_queue_drops_emails() {
+        let mailer = mailer(1);
+        mailer.welcome(&user("Ada"), "a");
+        mailer.welcome(&user("Ada"), "b");
+        let mut receiver = mailer.receiver.lock().unwrap().take().unwrap();
+        assert!(receiver.try_recv().unwrap().message.contains("/verify/a"));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -1,197 +1,510 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use futures::TryStreamExt;
-use serde::{Deserialize, Serialize};
-use scylla::{Session, SessionBuilder};
+use actix_web::middleware::{from_fn, Compress, Condition, NormalizePath, TrailingSlash};
+use actix_web::http::KeepAlive;
+use actix_web::{web, App, HttpServer};
+use std::net::ToSocketAddrs;
 use std::sync::Arc;
-use uuid::Uuid;
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    id: Uuid,
-    name: String,
-    email: String,
-}
+use singlepg_hireme_rust_server::auth::JwtAuth;
+use singlepg_hireme_rust_server::cli::{self, Command, Seed};
+use singlepg_hireme_rust_server::config::{CorsMode, TrailingSlashPolicy};
+use singlepg_hireme_rust_server::panics::{self, Panics};
+use singlepg_hireme_rust_server::rate_limit::{self, RateLimiter};
+use singlepg_hireme_rust_server::shedding::{self, Shedder};
+#[cfg(feature = "grpc")]
+use singlepg_hireme_rust_server::grpc;
+#[cfg(feature = "otel")]
+use singlepg_hireme_rust_server::otel;
+use singlepg_hireme_rust_server::{
+    backfill, cdc, check_db, compression, consistency, cors, deadline, dry_run, error, health,
+    import, indexes, latency, logging, maintenance_mode, metrics, migrations, null_fields,
+    openapi, outbox, reload, request_id, restore, seed, self_test, session, shadow, shutdown,
+    snapshot, startup, tenants, tls,
+};
+use singlepg_hireme_rust_server::{AppState, Config};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct NewUser {
-    name: String,
-    email: String,
+// Path normalization for `http.trailing_slash`; `strict` routes paths as sent.
+fn normalize_path(policy: TrailingSlashPolicy) -> Condition<NormalizePath> {
+    let trailing_slash = match policy {
+        TrailingSlashPolicy::Trim => Some(TrailingSlash::Trim),
+        TrailingSlashPolicy::Merge => Some(TrailingSlash::MergeOnly),
+        TrailingSlashPolicy::Strict => None,
+    };
+    Condition::new(
+        trailing_slash.is_some(),
+        NormalizePath::new(trailing_slash.unwrap_or(TrailingSlash::Trim)),
+    )
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UpdateUser {
-    name: Option<String>,
-    email: Option<String>,
+// Removes the socket a previous run left at `path`, which would otherwise
+// stop the listener from binding. Anything but a socket is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let session: Session = SessionBuilder::new()
-        .known_node("127.0.0.1:9042")
-        .build()
+    let command = cli::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+    if command == Command::Help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    let config = Config::load().unwrap_or_else(|e| panic!("Invalid configuration: {}", e));
+    let log_level =
+        logging::init(&config.log).unwrap_or_else(|e| panic!("Cannot initialise logging: {}", e));
+
+    let session = session::connect(&config.scylla)
         .await
-        .expect("Failed to connect to ScyllaDB");
+        .map(Arc::new)
+        .unwrap_or_else(|e| panic!("Failed to open ScyllaDB session: {}", e));
 
-    async fn get_all_users(data: web::Data<AppState>) -> impl Responder {
-        let session = &data.session;
+    let keyspace = config.scylla.keyspace.clone();
 
-        let query = format!("SELECT id, name, email FROM {}.users", data.keyspace);
+    // `self-test` (or SELF_TEST=true) runs a CRUD smoke test in a temporary
+    // keyspace and exits with its result instead of serving HTTP.
+    let run_self_test = command == Command::SelfTest
+        || std::env::var("SELF_TEST").is_ok_and(|value| value.eq_ignore_ascii_case("true"));
+    if run_self_test {
+        let passed = self_test::run(session.clone(), &config).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-        let results = match session.query_iter(&*query, &[]).await {
-            Ok(results) => results,
-            Err(e) => return HttpResponse::InternalServerError().json(format!("Query error: {}", e)),
+    let ddl = migrations::Ddl::new(&config.scylla);
+    if config.scylla.bootstrap {
+        let replication = startup::Replication {
+            factor: config.scylla.replication_factor,
+            datacenters: config.scylla.replication_datacenters.clone(),
         };
+        startup::bootstrap(&session, &keyspace, &replication, &ddl)
+            .await
+            .unwrap_or_else(|e| panic!("Bootstrap failed: {}", e));
+    }
 
-        let mut users = Vec::new();
+    // `check-db` only reports on the schema, so it neither bootstraps nor
+    // migrates it, nor waits for it to appear.
+    if command == Command::CheckDb {
+        let passed = check_db::run(&session, &keyspace).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-        let mut rows_stream = match results.rows_stream::<(Uuid, String, String)>() {
-            Ok(stream) => stream,
-            Err(e) => return HttpResponse::InternalServerError().json(format!("Error streaming rows: {}", e)),
-        };
+    // `migrate` applies pending migrations and exits, for running as a
+    // separate deploy step; `scylla.migrate_on_startup` does the same inline.
+    let migrate_only = command == Command::Migrate;
+    if migrate_only || config.scylla.migrate_on_startup {
+        let count = migrations::run(&session, &keyspace, &ddl)
+            .await
+            .unwrap_or_else(|e| panic!("Migration failed: {}", e));
+        tracing::info!(count, keyspace = %keyspace, "applied migrations");
+        if migrate_only {
+            std::process::exit(0);
+        }
+    }
 
-        while let Some(row) = match rows_stream.try_next().await {
-            Ok(row) => row,
-            Err(e) => return HttpResponse::InternalServerError().json(format!("Error fetching next row: {}", e)),
-        } {
-            let (id, name, email) = row;
-            users.push(User { id, name, email });
+    // Don't bind HTTP until the schema exists; a migration job may still be
+    // creating it when this pod starts.
+    let schema_wait = startup::SchemaWait {
+        max_wait: Duration::from_secs(config.scylla.schema_wait_timeout_secs),
+        initial_backoff: Duration::from_millis(config.scylla.schema_wait_initial_backoff_ms),
+        max_backoff: Duration::from_millis(config.scylla.schema_wait_max_backoff_ms),
+    };
+    startup::await_users_table(&session, &keyspace, &schema_wait)
+        .await
+        .unwrap_or_else(|e| panic!("Keyspace not ready: {}", e));
+    // The table may be queryable on one node before every node has it.
+    ddl.agree(&session)
+        .await
+        .unwrap_or_else(|e| panic!("No schema agreement: {}", e));
+    // Queries through a missing index fail, and through one still building
+    // miss rows; `scylla.ensure_indexes` creates the missing ones.
+    indexes::at_startup(&session, &keyspace, &ddl, config.scylla.ensure_indexes)
+        .await
+        .unwrap_or_else(|e| panic!("Cannot create indexes: {}", e));
+
+    let mut app_state = AppState::builder(&config)
+        .session(session)
+        .keyspace(keyspace)
+        .build()
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    match &command {
+        // `backfill` fills the email and name index tables for users written
+        // before they existed, then exits; it fails if any user was left out.
+        Command::Backfill => {
+            let report = backfill::run(&app_state, config.scylla.backfill_concurrency)
+                .await
+                .unwrap_or_else(|e| panic!("Backfill failed: {}", e));
+            std::process::exit(if report.complete() { 0 } else { 1 });
+        }
+        // `seed` registers a fixture's users like POST /users/import, then
+        // exits; it fails if any of them was refused.
+        Command::Seed(Seed::File(path)) => {
+            let report = import::import_file(&app_state, path)
+                .await
+                .unwrap_or_else(|e| panic!("Seeding failed: {}", e));
+            for failure in &report.errors {
+                tracing::error!(
+                    line = failure.line,
+                    status = failure.status,
+                    error = %failure.error.detail,
+                    "seed record refused"
+                );
+            }
+            tracing::info!(imported = report.imported, failed = report.failed, "seeded users");
+            std::process::exit(if report.failed == 0 { 0 } else { 1 });
+        }
+        // `seed --count` registers made-up users for demos and load tests.
+        Command::Seed(Seed::Fake { count }) => {
+            let report = seed::run(&app_state, *count, config.scylla.seed_concurrency).await;
+            tracing::info!(
+                registered = report.registered,
+                failed = report.failed,
+                "seeded fake users"
+            );
+            std::process::exit(if report.failed == 0 { 0 } else { 1 });
         }
-        println!("Users : {:?}", users);
-        HttpResponse::Ok().json(users)
+        // `snapshot` copies the users table to a file for backup.
+        Command::Snapshot { path } => {
+            snapshot::run(&app_state, path, config.scylla.snapshot_rows_per_sec)
+                .await
+                .unwrap_or_else(|e| panic!("Snapshot failed: {}", e));
+            std::process::exit(0);
+        }
+        // `restore` writes a snapshot's users back; it fails if any of them
+        // couldn't be.
+        Command::Restore { path, conflict } => {
+            let report =
+                restore::run(&app_state, path, *conflict, config.scylla.restore_concurrency)
+                    .await
+                    .unwrap_or_else(|e| panic!("Restore failed: {}", e));
+            std::process::exit(if report.failed == 0 { 0 } else { 1 });
+        }
+        _ => {}
     }
 
-    async fn register_user(
-        new_user: web::Json<NewUser>, 
-        data: web::Data<AppState>
-    ) -> impl Responder {
-        let session = &data.session;
+    // Only the reads made while serving are shadowed, not the commands'.
+    if config.shadow.enabled {
+        shadow::attach(&mut app_state, &config)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot shadow reads: {}", e));
+    }
 
-        let new_id = Uuid::new_v4();
+    let trailing_slash = config.http.trailing_slash;
 
-        let query = format!(
-            "INSERT INTO {}.users (id, name, email) VALUES (?, ?, ?)",
-            data.keyspace
-        );
+    let request_id_format = web::Data::new(config.http.request_id_format);
+    error::set_verbose(config.http.verbose_errors);
+    let panics = web::Data::new(Panics::new(&config.http));
+    let compression = config.http.compression;
+    let compression_min = web::Data::new(compression::MinSize(config.http.compression_min_bytes));
 
-        match session.query_unpaged(
-            &*query,
-            (new_id, new_user.name.clone(), new_user.email.clone())
-        ).await {
-            Ok(_) => HttpResponse::Created().json(format!("User {} created successfully", new_id)),
-            Err(e) => HttpResponse::InternalServerError().json(format!("Failed to create user: {}", e)),
+    let jwt_auth = match &config.auth.jwt_secret {
+        Some(secret) => Some(web::Data::new(JwtAuth::new(
+            secret,
+            config.auth.jwt_issuer.as_deref(),
+            Duration::from_secs(config.auth.token_ttl_secs),
+            Duration::from_secs(config.auth.refresh_token_ttl_secs),
+        ))),
+        None => {
+            tracing::warn!("auth.jwt_secret is not set: protected routes only accept API keys");
+            None
         }
+    };
+
+    let rate_limiter = config
+        .rate_limit
+        .enabled
+        .then(|| web::Data::new(RateLimiter::new(&config.rate_limit)));
+
+    let shedder = config.load_shedding.enabled.then(|| {
+        web::Data::new(Shedder::new(&config.load_shedding, app_state.metrics.clone()))
+    });
+
+    let reloader = web::Data::new(reload::Reloader::new(log_level, rate_limiter.clone()));
+    // `kill -HUP` reloads what can change without a restart; see `reload`.
+    #[cfg(unix)]
+    if let Err(e) = reload::on_hangup(reloader.clone(), app_state.clone()) {
+        tracing::warn!(error = %e, "cannot listen for SIGHUP, reload with POST /admin/reload");
     }
 
-    async fn update_user(
-        user_id: web::Path<Uuid>,
-        updated_user: web::Json<UpdateUser>,
-        data: web::Data<AppState>,
-    ) -> impl Responder {
-        let session = &data.session;
-        let user_id_value = user_id.into_inner();
+    let cors_config = config.cors.clone();
 
-        let mut query = format!("UPDATE {}.users SET", data.keyspace);
-        let mut params = Vec::new();
+    let tls_config = match (&config.http.tls_cert_path, &config.http.tls_key_path) {
+        (Some(cert), Some(key)) => Some(
+            tls::server_config(cert, key).unwrap_or_else(|e| panic!("Invalid TLS configuration: {}", e)),
+        ),
+        _ => None,
+    };
 
-        if let Some(name) = &updated_user.name {
-            query.push_str(" name = ?,");
-            params.push(name.clone());
-        }
-        if let Some(email) = &updated_user.email {
-            query.push_str(" email = ?,");
-            params.push(email.clone());
-        }
+    let tenants = config
+        .tenants
+        .enabled
+        .then(|| web::Data::new(tenants::Tenants::new(app_state.clone(), &config)));
 
-        if query.ends_with(',') {
-            query.pop();
-        }
-        query.push_str(format!(" WHERE id = {}", user_id_value).as_str());
-        match session.query_unpaged(query, params).await {
-            Ok(_) => HttpResponse::Ok().json(format!("User with ID {} updated successfully", user_id_value)),
-            Err(e) => HttpResponse::InternalServerError().json(format!("Failed to update user: {}", e)),
-        }
+    // Serve with the stored feature flags from the first request.
+    if let Err(e) = app_state.flags.refresh(&app_state).await {
+        tracing::warn!(error = %e, "failed to read feature flags, using the configured ones");
     }
 
-    async fn delete_user(
-        user_id: web::Path<Uuid>,
-        data: web::Data<AppState>,
-    ) -> impl Responder {
-        let session = &data.session;
-        let query = format!(
-            "DELETE FROM {}.users WHERE id = ?",
-            data.keyspace
-        );
+    let mut background = shutdown::Tasks::new();
+    app_state.cluster_monitor.start(app_state.clone(), &mut background);
+    app_state.flags.start(app_state.clone(), &mut background);
+    app_state.maintenance.start(app_state.clone(), &mut background);
+    if config.cdc.enabled {
+        cdc::Consumer::new(app_state.clone(), &config.cdc)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot read the users CDC log (is CDC enabled?): {}", e))
+            .start(&mut background);
+    }
+    if config.outbox.enabled {
+        outbox::Relay::for_state(&app_state, &config.outbox).start(&mut background);
+    }
+    if let Some(queue) = &app_state.write_behind {
+        queue.start(app_state.clone(), &mut background);
+    }
+    if let Some(webhooks) = &app_state.webhooks {
+        webhooks.start(app_state.clone(), &mut background);
+    }
+    if let Some(mailer) = &app_state.mailer {
+        mailer.start(&mut background);
+    }
 
-        let user_id_value = user_id.into_inner();
+    #[cfg(feature = "grpc")]
+    let grpc = match &config.grpc.bind_addr {
+        Some(grpc_addr) => Some(
+            grpc::serve(
+                grpc_addr,
+                app_state.clone(),
+                jwt_auth.clone(),
+                rate_limiter.clone(),
+                Duration::from_secs(config.http.shutdown_grace_secs),
+            )
+            .await?,
+        ),
+        None => None,
+    };
 
-        match session.query_unpaged(query, (user_id_value,)).await {
-            Ok(_) => HttpResponse::Ok().json(format!("User with ID {} deleted successfully", user_id_value)),
-            Err(e) => HttpResponse::InternalServerError().json(format!("Failed to delete user: {}", e)),
+    // `kill -USR1` logs a metrics snapshot and otherwise leaves the server be.
+    #[cfg(unix)]
+    match metrics::snapshots_on_signal(app_state.metrics.clone()) {
+        Ok(snapshots) => {
+            actix_web::rt::spawn(futures::StreamExt::for_each(snapshots, |snapshot| async move {
+                snapshot.log()
+            }));
         }
+        Err(e) => tracing::warn!(error = %e, "cannot listen for SIGUSR1, metrics snapshots are off"),
     }
-    
-
-    async fn get_user_by_id(
-        user_id: web::Path<Uuid>,
-        data: web::Data<AppState>,
-    ) -> impl Responder {
-        let session = &data.session;
-    
-        let query = format!(
-            "SELECT id, name, email FROM {}.users WHERE id = ?",
-            data.keyspace
-        );
-    
-        let user_id_clone = user_id.clone();
-    
-        match session.query_iter(&*query, (user_id_clone,)).await {
-            Ok(results) => {
-                let mut rows_stream = match results.rows_stream::<(Uuid, String, String)>() {
-                    Ok(stream) => stream,
-                    Err(e) => return HttpResponse::InternalServerError().json(format!("Error streaming rows: {}", e)),
-                };
-    
-                // Process the result row-by-row
-                if let Some(row) = rows_stream.try_next().await.unwrap_or(None) {
-                    let (id, name, email) = row;
-                    let user = User { id, name, email };
-                    HttpResponse::Ok().json(user)
-                } else {
-                    HttpResponse::NotFound()
-                        .json(format!("User with ID {} not found", user_id.into_inner()))
+
+    // Kept past the server so the session is closed only after every worker,
+    // and so every in-flight query, has finished.
+    let session = Arc::clone(&app_state.session);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(request_id_format.clone())
+            .app_data(compression_min.clone())
+            .app_data(reloader.clone())
+            .app_data(panics.clone())
+            .configure(|cfg| {
+                if let Some(jwt_auth) = &jwt_auth {
+                    cfg.app_data(jwt_auth.clone());
                 }
-            }
-            Err(e) => HttpResponse::InternalServerError()
-                .json(format!("Failed to execute query: {}", e)),
+                if let Some(rate_limiter) = &rate_limiter {
+                    cfg.app_data(rate_limiter.clone());
+                }
+                if let Some(shedder) = &shedder {
+                    cfg.app_data(shedder.clone());
+                }
+            })
+            .wrap(normalize_path(trailing_slash))
+            .wrap(from_fn(null_fields::apply))
+            .wrap(Condition::new(compression, from_fn(compression::skip_small)))
+            .wrap(Condition::new(compression, Compress::default()))
+            .wrap(from_fn(consistency::scope))
+            .wrap(from_fn(dry_run::scope))
+            .wrap(from_fn(deadline::limit))
+            .wrap(from_fn(maintenance_mode::gate))
+            .wrap(from_fn(panics::catch))
+            .wrap(from_fn(rate_limit::limit))
+            .wrap(from_fn(shedding::shed))
+            .wrap(from_fn(tenants::route))
+            .wrap(from_fn(latency::track))
+            .wrap(from_fn(metrics::track))
+            .wrap(from_fn(request_id::assign))
+            .wrap(from_fn(logging::request_span))
+            .wrap(Condition::new(
+                cors_config.mode != CorsMode::Off,
+                cors::middleware(&cors_config),
+            ))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/metrics", web::get().to(metrics::get_metrics))
+            .configure(|cfg| match &tenants {
+                Some(tenants) => {
+                    singlepg_hireme_rust_server::configure_with_tenants(
+                        cfg,
+                        app_state.clone(),
+                        tenants.clone(),
+                    )
+                }
+                None => singlepg_hireme_rust_server::configure(cfg, app_state.clone()),
+            })
+            .route("/api-docs/openapi.json", web::get().to(openapi::get_spec))
+            .route("/swagger-ui", web::get().to(openapi::get_swagger_ui))
+    })
+    .backlog(config.http.backlog)
+    .max_connections(config.http.max_connections)
+    .max_connection_rate(config.http.max_connection_rate)
+    .keep_alive(match config.http.keep_alive_secs {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    })
+    .client_request_timeout(Duration::from_millis(config.http.client_request_timeout_ms))
+    .client_disconnect_timeout(Duration::from_millis(config.http.client_disconnect_timeout_ms))
+    .shutdown_timeout(config.http.shutdown_grace_secs)
+    // A client that closes its side of the connection has given up on the
+    // response; refusing half-closed connections drops its request's future.
+    .h1_allow_half_closed(!config.http.cancel_on_disconnect)
+    .disable_signals();
+    let mut server = match config.http.workers {
+        0 => server,
+        workers => server.workers(workers),
+    };
+    if !config.http.bind_addr.is_empty() {
+        server = match tls_config {
+            Some(tls_config) => server.bind_rustls_0_23(&config.http.bind_addr, tls_config)?,
+            None => server.bind(&config.http.bind_addr)?,
+        };
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.http.unix_socket_path {
+        remove_stale_socket(path)?;
+        server = server.bind_uds(path)?;
+    }
+    let server = server.run();
+    let mut handles = vec![server.handle()];
+
+    // With TLS on, an optional plain-HTTP listener sends clients to HTTPS.
+    let redirect = match &config.http.redirect_bind_addr {
+        Some(redirect_addr) => {
+            let https_port = web::Data::new(
+                config
+                    .http
+                    .bind_addr
+                    .to_socket_addrs()?
+                    .next()
+                    .map_or(443, |addr| addr.port()),
+            );
+            let redirect = HttpServer::new(move || {
+                App::new()
+                    .app_data(https_port.clone())
+                    .default_service(web::to(tls::redirect_to_https))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(redirect_addr)?
+            .run();
+            handles.push(redirect.handle());
+            Some(redirect)
         }
+        None => None,
+    };
+
+    #[cfg(feature = "grpc")]
+    actix_web::rt::spawn(shutdown::drain_on_signal(handles, grpc.clone()));
+    #[cfg(not(feature = "grpc"))]
+    actix_web::rt::spawn(shutdown::drain_on_signal(handles));
+    match redirect {
+        Some(redirect) => {
+            futures::try_join!(server, redirect)?;
+        }
+        None => server.await?,
+    }
+
+    // The HTTP server may finish draining first; gRPC calls still running
+    // get the rest of their grace period before the session goes.
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = &grpc {
+        grpc.stop().await;
     }
-    
+    tracing::info!("HTTP server stopped");
 
-    
-    
+    background
+        .stop(Duration::from_secs(config.http.background_grace_secs))
+        .await;
+    drop(session);
+    tracing::info!("ScyllaDB session closed");
 
-    // Define application state using Arc for the session to be clonable
-    #[derive(Clone)]
-    struct AppState {
-        session: Arc<Session>,
-        keyspace: String,
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::HttpResponse;
+
+    async fn status(policy: TrailingSlashPolicy, path: &str) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .route("/users", web::get().to(HttpResponse::Ok))
+                .wrap(normalize_path(policy)),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri(path).to_request())
+            .await
+            .status()
     }
 
-    let app_state = AppState {
-        session: Arc::new(session),
-        keyspace: String::from("my_keyspace"),
-    };
+    #[actix_web::test]
+    async fn trim_resolves_users_with_or_without_the_slash() {
+        assert_eq!(status(TrailingSlashPolicy::Trim, "/users").await, StatusCode::OK);
+        assert_eq!(status(TrailingSlashPolicy::Trim, "/users/").await, StatusCode::OK);
+        assert_eq!(status(TrailingSlashPolicy::Trim, "//users//").await, StatusCode::OK);
+    }
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(app_state.clone()))
-            .route("/users", web::get().to(get_all_users))
-            .route("/register", web::post().to(register_user))
-            .route("/update/{id}", web::patch().to(update_user))
-            .route("/delete/{id}", web::delete().to(delete_user))
-            .route("/users/{id}", web::get().to(get_user_by_id))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
-}
\ No newline at end of file
+    #[actix_web::test]
+    async fn merge_only_collapses_repeated_slashes() {
+        assert_eq!(status(TrailingSlashPolicy::Merge, "//users").await, StatusCode::OK);
+        assert_eq!(status(TrailingSlashPolicy::Merge, "/users/").await, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn strict_keeps_the_paths_distinct() {
+        assert_eq!(status(TrailingSlashPolicy::Strict, "/users").await, StatusCode::OK);
+        assert_eq!(status(TrailingSlashPolicy::Strict, "/users/").await, StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_stale_sockets_are_removed() {
+        let dir = std::env::temp_dir().join(format!("hireme-socket-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let socket = dir.join("http.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(&socket).unwrap();
+
+        let file = dir.join("notes.txt");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
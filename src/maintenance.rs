@@ -0,0 +1,267 @@
+use crate::audit;
+use crate::config::MaintenanceConfig;
+use crate::count;
+use crate::error::{ApiError, Problem};
+use crate::observe;
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use crate::users;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{future, TryStreamExt};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// Housekeeping jobs run in the background beside the server, each every
+// `maintenance.<job>_interval_secs` as a task of its own, and on demand with
+// POST /admin/jobs/{name}. A job never runs twice at once: a run asked for
+// while one is going is refused, and a scheduled one skipped. Every run is
+// counted and timed in `maintenance_job_runs_total` and
+// `maintenance_job_duration_seconds`.
+
+// The actor purges are audited as.
+const ACTOR: &str = "maintenance";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Job {
+    // Hard-deletes users soft-deleted more than
+    // `maintenance.purge_deleted_after_days` ago.
+    PurgeDeleted,
+    // Refreshes the user count behind GET /users/count.
+    RecountUsers,
+    // Drops expired entries from the in-memory user cache.
+    SweepUserCache,
+}
+
+const JOBS: [Job; 3] = [Job::PurgeDeleted, Job::RecountUsers, Job::SweepUserCache];
+
+impl Job {
+    pub fn name(self) -> &'static str {
+        match self {
+            Job::PurgeDeleted => "purge_deleted",
+            Job::RecountUsers => "recount_users",
+            Job::SweepUserCache => "sweep_user_cache",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        JOBS.into_iter().find(|job| job.name() == name)
+    }
+}
+
+/// What a run of a maintenance job did.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct JobRun {
+    pub job: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: f64,
+    /// Users purged, users counted or cache entries dropped.
+    pub affected: u64,
+}
+
+pub struct Scheduler {
+    // Jobs run in the background, with their intervals.
+    schedule: Vec<(Job, Duration)>,
+    purge_after: TimeDelta,
+    running: Mutex<HashSet<Job>>,
+}
+
+// A job marked running until this is dropped.
+struct Claim<'a> {
+    scheduler: &'a Scheduler,
+    job: Job,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.scheduler.running.lock().unwrap().remove(&self.job);
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: &MaintenanceConfig) -> Self {
+        let intervals = [
+            (Job::PurgeDeleted, config.purge_deleted_interval_secs),
+            (Job::RecountUsers, config.recount_users_interval_secs),
+            (Job::SweepUserCache, config.sweep_user_cache_interval_secs),
+        ];
+        Scheduler {
+            schedule: intervals
+                .into_iter()
+                .filter(|(_, secs)| *secs > 0)
+                .map(|(job, secs)| (job, Duration::from_secs(secs)))
+                .collect(),
+            purge_after: TimeDelta::days(i64::from(config.purge_deleted_after_days)),
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn start(self: &Arc<Self>, state: AppState, tasks: &mut Tasks) {
+        for &(job, interval) in &self.schedule {
+            let scheduler = self.clone();
+            let state = state.clone();
+            tasks.spawn(job.name(), move |stopping| scheduler.run_every(state, job, interval, stopping));
+        }
+    }
+
+    async fn run_every(
+        self: Arc<Self>,
+        state: AppState,
+        job: Job,
+        interval: Duration,
+        mut stopping: Stopping,
+    ) {
+        while stopping.pause(interval).await {
+            if let Err(e) = self.run(&state, job).await {
+                tracing::warn!(job = job.name(), error = %e, "maintenance job failed");
+            }
+        }
+    }
+
+    fn claim(&self, job: Job) -> Option<Claim<'_>> {
+        let claimed = self.running.lock().unwrap().insert(job);
+        claimed.then(|| Claim { scheduler: self, job })
+    }
+
+    // Runs `job` now, unless it is running already.
+    pub async fn run(&self, state: &AppState, job: Job) -> Result<JobRun, ApiError> {
+        let Some(_claim) = self.claim(job) else {
+            return Err(ApiError::Conflict(format!("Job {} is already running", job.name())));
+        };
+        let started_at = Utc::now();
+        let started = Instant::now();
+        let result = match job {
+            Job::PurgeDeleted => {
+                let cutoff = started_at - self.purge_after;
+                audit::acting_as(ACTOR, purge_deleted(state, cutoff)).await
+            }
+            Job::RecountUsers => state.user_count.refresh(state).await.map(|count| count.count),
+            Job::SweepUserCache => Ok(state.user_cache.sweep() as u64),
+        };
+        let elapsed = started.elapsed();
+        state.metrics.job_run(job.name(), result.is_ok(), elapsed);
+        let affected = result?;
+        let duration_ms = elapsed.as_secs_f64() * 1000.0;
+        tracing::info!(job = job.name(), affected, duration_ms, "maintenance job finished");
+        Ok(JobRun {
+            job: job.name(),
+            started_at,
+            duration_ms,
+            affected,
+        })
+    }
+}
+
+// The users in the token range soft-deleted before `cutoff`.
+async fn deleted_before(
+    state: &AppState,
+    (start, end): (i64, i64),
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<Uuid>, ApiError> {
+    let pager = observe::query(state, "select_deleted_ids_in_token_range", || {
+        state
+            .session
+            .execute_iter(state.statements.select_deleted_ids_in_token_range.clone(), (start, end))
+    })
+    .await?;
+    pager
+        .rows_stream::<(Uuid, Option<DateTime<Utc>>)>()
+        .map_err(|e| ApiError::internal("Error reading deleted users", e))?
+        .map_err(|e| ApiError::internal("Error reading deleted users", e))
+        .try_filter_map(|(user_id, deleted_at)| {
+            future::ok(deleted_at.is_some_and(|at| at < cutoff).then_some(user_id))
+        })
+        .try_collect()
+        .await
+}
+
+// Hard-deletes the user if it is still soft-deleted, as `users::delete`
+// would for DELETE /delete/{id}?hard=true; the version check keeps a user
+// restored meanwhile. Returns whether it was purged.
+async fn purge(state: &AppState, user_id: Uuid) -> Result<bool, ApiError> {
+    let Some((user, true)) = users::stored_row(state, user_id).await? else {
+        return Ok(false);
+    };
+    match users::delete(state, user_id, true, Some(&[users::version(&user)]), false).await {
+        Ok(_) => Ok(true),
+        Err(ApiError::PreconditionFailed(_) | ApiError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Purges the users soft-deleted before `cutoff`, a token range at a time,
+// returning how many went.
+async fn purge_deleted(state: &AppState, cutoff: DateTime<Utc>) -> Result<u64, ApiError> {
+    let mut purged = 0;
+    for range in count::token_ranges(count::RANGES) {
+        for user_id in deleted_before(state, range, cutoff).await? {
+            if purge(state, user_id).await? {
+                purged += 1;
+            }
+        }
+    }
+    Ok(purged)
+}
+
+/// Runs a maintenance job now, whether or not it is scheduled, and reports
+/// what it did once it has finished.
+#[utoipa::path(
+    post,
+    path = "/admin/jobs/{name}",
+    params(("name" = String, Path, description = "`purge_deleted`, `recount_users` or `sweep_user_cache`")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The job ran", body = JobRun),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such job", body = Problem),
+        (status = 409, description = "The job is running already", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn run_job(
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let job = Job::from_name(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("No job named {}", name)))?;
+    Ok(HttpResponse::Ok().json(data.maintenance.run(&data, job).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_are_found_by_name() {
+        for job in JOBS {
+            assert_eq!(Job::from_name(job.name()), Some(job));
+        }
+        assert_eq!(Job::from_name("vacuum"), None);
+    }
+
+    #[test]
+    fn only_jobs_with_an_interval_are_scheduled() {
+        let scheduler = Scheduler::new(&MaintenanceConfig {
+            recount_users_interval_secs: 300,
+            ..MaintenanceConfig::default()
+        });
+        assert_eq!(scheduler.schedule, [(Job::RecountUsers, Duration::from_secs(300))]);
+        assert_eq!(scheduler.purge_after, TimeDelta::days(30));
+        assert!(Scheduler::new(&MaintenanceConfig::default()).schedule.is_empty());
+    }
+
+    #[test]
+    fn a_job_runs_once_at_a_time() {
+        let scheduler = Scheduler::new(&MaintenanceConfig::default());
+        let claim = scheduler.claim(Job::PurgeDeleted).unwrap();
+        assert!(scheduler.claim(Job::PurgeDeleted).is_none());
+        assert!(scheduler.claim(Job::SweepUserCache).is_some());
+        drop(claim);
+        assert!(scheduler.claim(Job::PurgeDeleted).is_some());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::MaintenanceModeConfig;
+use crate::error::{self, ApiError, Problem};
+use crate::state::AppState;
+use crate::v1;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use utoipa::ToSchema;
+
+// Maintenance mode, for schema migrations and cluster maintenance windows:
+// while it is on, requests that write (any method but GET, HEAD and OPTIONS,
+// so GraphQL queries too) get a 503 with code `maintenance` and the
+// configured message, and reads carry on; with `block_reads` every request
+// is turned away. Probes, metrics, sign-in and the admin routes stay open, so
+// the mode can be switched off again. It is switched with
+// PUT /admin/maintenance and holds for this instance only, starting as
+// `maintenance_mode` configures it. gRPC calls are not affected.
+
+// Routes served whatever the mode, besides /admin/*.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics", "/login", "/token/refresh"];
+
+/// Whether the service is in maintenance mode, and what it turns away.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    /// Whether reads are turned away too, not only writes.
+    pub block_reads: bool,
+    /// The `detail` of the 503 sent to requests turned away.
+    pub message: String,
+    /// When the mode was last switched on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// The maintenance mode to switch to; what is left out is kept.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetMaintenance {
+    pub enabled: bool,
+    pub block_reads: Option<bool>,
+    pub message: Option<String>,
+}
+
+pub struct MaintenanceMode {
+    status: RwLock<MaintenanceStatus>,
+}
+
+fn exempt(path: &str) -> bool {
+    let path = v1::unversioned(path);
+    EXEMPT_PATHS.contains(&path) || path.starts_with("/admin/")
+}
+
+impl MaintenanceMode {
+    pub fn new(config: &MaintenanceModeConfig) -> Self {
+        MaintenanceMode {
+            status: RwLock::new(MaintenanceStatus {
+                enabled: config.enabled,
+                block_reads: config.block_reads,
+                message: config.message.clone(),
+                since: config.enabled.then(Utc::now),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    pub fn set(&self, change: SetMaintenance) -> MaintenanceStatus {
+        let mut status = self.status.write().unwrap();
+        if change.enabled && !status.enabled {
+            status.since = Some(Utc::now());
+        }
+        status.enabled = change.enabled;
+        if let Some(block_reads) = change.block_reads {
+            status.block_reads = block_reads;
+        }
+        if let Some(message) = change.message {
+            status.message = message;
+        }
+        status.clone()
+    }
+
+    // The message to turn a `method` request to `path` away with, if it is.
+    fn blocks(&self, method: &Method, path: &str) -> Option<String> {
+        let status = self.status.read().unwrap();
+        if !status.enabled || exempt(path) {
+            return None;
+        }
+        let write = !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+        (write || status.block_reads).then(|| status.message.clone())
+    }
+}
+
+pub async fn gate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let blocked = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.maintenance_mode.blocks(req.method(), req.path()));
+    if let Some(message) = blocked {
+        let response = error::problem(StatusCode::SERVICE_UNAVAILABLE, "maintenance", message);
+        return Ok(req.into_response(response));
+    }
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+/// Reports whether this instance is in maintenance mode.
+#[utoipa::path(
+    get,
+    path = "/admin/maintenance",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The maintenance mode", body = MaintenanceStatus),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+    )
+)]
+pub async fn get_maintenance(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.maintenance_mode.status())
+}
+
+/// Switches this instance into or out of maintenance mode. Writes, or with
+/// `block_reads` all requests, then get a 503 with code `maintenance`.
+#[utoipa::path(
+    put,
+    path = "/admin/maintenance",
+    request_body = SetMaintenance,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The maintenance mode as it now is", body = MaintenanceStatus),
+        (status = 400, description = "Invalid body", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+    )
+)]
+pub async fn set_maintenance(
+    body: web::Json<SetMaintenance>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if body.message.as_deref().is_some_and(|message| message.trim().is_empty()) {
+        return Err(ApiError::BadRequest(String::from("message must not be blank")));
+    }
+    let status = data.maintenance_mode.set(body.into_inner());
+    tracing::warn!(
+        enabled = status.enabled,
+        block_reads = status.block_reads,
+        "maintenance mode switched"
+    );
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(enabled: bool, block_reads: bool) -> MaintenanceMode {
+        MaintenanceMode::new(&MaintenanceModeConfig {
+            enabled,
+            block_reads,
+            ..MaintenanceModeConfig::default()
+        })
+    }
+
+    #[test]
+    fn writes_are_turned_away_and_reads_carry_on() {
+        let mode = mode(true, false);
+        assert!(mode.blocks(&Method::POST, "/register").is_some());
+        assert!(mode.blocks(&Method::DELETE, "/api/v1/users/42").is_some());
+        assert_eq!(mode.blocks(&Method::GET, "/users"), None);
+        assert_eq!(mode.blocks(&Method::PUT, "/admin/maintenance"), None);
+        assert_eq!(mode.blocks(&Method::POST, "/api/v1/login"), None);
+    }
+
+    #[test]
+    fn reads_can_be_turned_away_too() {
+        let mode = mode(true, true);
+        assert!(mode.blocks(&Method::GET, "/users").is_some());
+        assert_eq!(mode.blocks(&Method::GET, "/readyz"), None);
+        assert_eq!(mode.blocks(&Method::GET, "/admin/status"), None);
+    }
+
+    #[test]
+    fn nothing_is_turned_away_once_switched_off() {
+        let mode = mode(false, true);
+        assert_eq!(mode.blocks(&Method::POST, "/register"), None);
+
+        let status = mode.set(SetMaintenance {
+            enabled: true,
+            block_reads: Some(false),
+            message: Some(String::from("Back at noon")),
+        });
+        assert!(status.since.is_some());
+        assert_eq!(mode.blocks(&Method::POST, "/register").as_deref(), Some("Back at noon"));
+        assert_eq!(mode.blocks(&Method::GET, "/users"), None);
+
+        let status = mode.set(SetMaintenance {
+            enabled: false,
+            block_reads: None,
+            message: None,
+        });
+        assert_eq!(status.message, "Back at noon");
+        assert_eq!(mode.blocks(&Method::POST, "/register"), None);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, FieldError, Problem};
+use crate::models::{Metadata, MetadataValue};
+use crate::negotiate::Body;
+use crate::observe;
+use crate::repository::ColumnWrite;
+use crate::state::AppState;
+use crate::users;
+use crate::validation;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+// Integrators attach their own attributes to a user, such as the user's id
+// in a CRM, as string entries of the `metadata` map on the user row. GET
+// /users/{id}/metadata returns the map, and PUT and DELETE
+// /users/{id}/metadata/{key} write a single entry, so clients setting
+// different keys at once don't overwrite each other. Values are opaque: they
+// are neither trimmed nor interpreted.
+//
+// Like addresses, metadata is not part of the user's representation and
+// changing it leaves `updated_at`, the user's version and its events alone.
+
+// Most keys one user's metadata may have. Checked against the map as read,
+// so two concurrent new keys may both get in at the limit.
+const MAX_METADATA_KEYS: usize = 50;
+
+fn authorize_for(subject: Option<&Subject>, user_id: Uuid) -> Result<&Subject, AuthError> {
+    auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only manage their own metadata unless they have the admin role",
+    )
+}
+
+fn checked_key(key: &str) -> Result<&str, ApiError> {
+    validation::metadata_key(key).map_err(|e| ApiError::BadRequest(format!("key {}", e)))
+}
+
+// The metadata of the live user with `user_id` and when the user expires,
+// or 404.
+async fn stored(
+    data: &AppState,
+    user_id: Uuid,
+) -> Result<(BTreeMap<String, String>, Option<DateTime<Utc>>), ApiError> {
+    let user = users::stored_user(data, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User with ID {} not found", user_id)))?;
+    let result = observe::query(data, "select_user_metadata", || {
+        data.session
+            .execute_unpaged(&data.statements.select_user_metadata, (user_id,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read metadata", e))?
+        .maybe_first_row::<(Option<BTreeMap<String, String>>,)>()
+        .map_err(|e| ApiError::internal("Failed to read metadata", e))?;
+    let metadata = row.and_then(|(metadata,)| metadata).unwrap_or_default();
+    Ok((metadata, user.expires_at))
+}
+
+// Makes `write`, `SetMetadata` or `DeleteMetadata`, failing with 404 when
+// the user's row has gone since it was read.
+async fn change(
+    data: &AppState,
+    user_id: Uuid,
+    write: ColumnWrite<'_>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let (applied, _) = data.users.write_column(user_id, expires_at, write).await?;
+    if !applied {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)));
+    }
+    Ok(())
+}
+
+/// The user's metadata.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/metadata",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The metadata", body = Metadata),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn get_metadata(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let (metadata, _) = stored(&data, user_id).await?;
+    Ok(HttpResponse::Ok().json(Metadata { metadata }))
+}
+
+/// Sets the value under `key`, adding the key or replacing its value.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/metadata/{key}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("key" = String, Path, description = "Up to 64 letters, digits, `-`, `_`, `.` and `:`"),
+    ),
+    request_body = MetadataValue,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user's metadata", body = Metadata),
+        (status = 400, description = "Invalid key", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "The user already has 50 keys", body = Problem),
+        (status = 422, description = "Value too long", body = Problem),
+    )
+)]
+pub async fn set_metadata(
+    subject: Option<web::ReqData<Subject>>,
+    path: web::Path<(Uuid, String)>,
+    Body(MetadataValue { value }): Body<MetadataValue>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, key) = path.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    checked_key(&key)?;
+    validation::metadata_value(&value).map_err(|message| {
+        ApiError::Validation(vec![FieldError {
+            field: String::from("value"),
+            message,
+        }])
+    })?;
+    let (mut metadata, expires_at) = stored(&data, user_id).await?;
+    if !metadata.contains_key(&key) && metadata.len() >= MAX_METADATA_KEYS {
+        return Err(ApiError::Conflict(format!(
+            "User {} already has {} metadata keys",
+            user_id, MAX_METADATA_KEYS
+        ))
+        .into());
+    }
+    let write = ColumnWrite::SetMetadata { key: &key, value: &value };
+    change(&data, user_id, write, expires_at).await?;
+    tracing::info!(%user_id, %key, "metadata set");
+    metadata.insert(key, value);
+    Ok(HttpResponse::Ok().json(Metadata { metadata }))
+}
+
+/// Removes `key` from the user's metadata.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/metadata/{key}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("key" = String, Path, description = "The key"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Key removed; the user's metadata", body = Metadata),
+        (status = 400, description = "Invalid key", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user, or no such key", body = Problem),
+    )
+)]
+pub async fn delete_metadata(
+    subject: Option<web::ReqData<Subject>>,
+    path: web::Path<(Uuid, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, key) = path.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    checked_key(&key)?;
+    let (mut metadata, expires_at) = stored(&data, user_id).await?;
+    if metadata.remove(&key).is_none() {
+        let message = format!("User {} has no metadata key {:?}", user_id, key);
+        return Err(ApiError::NotFound(message).into());
+    }
+    change(&data, user_id, ColumnWrite::DeleteMetadata { key: &key }, expires_at).await?;
+    tracing::info!(%user_id, %key, "metadata removed");
+    Ok(HttpResponse::Ok().json(Metadata { metadata }))
+}
@@ -0,0 +1,864 @@
+use crate::error::ApiError;
+use crate::state::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use futures::Stream;
+use prometheus::core::Collector;
+use prometheus::core::Desc;
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Prometheus metrics for the HTTP layer and the gRPC listener, for the latency of CQL queries, for
+// those that failed, were retried, were slow or were refused by the limiter and those in flight,
+// for the user caches, maintenance jobs and the write-behind queue, beside the driver's own
+// counters, served in the text exposition format from GET /metrics. SIGUSR1 logs a summary of them, for hosts nothing scrapes.
+//
+// Latency is split so slowness can be placed: `scylla_query_duration_seconds`
+// times each named CQL statement in the driver, retries included, and each
+// request's time is divided into the time its handler spent waiting on CQL
+// (`http_request_cql_seconds`) and the rest (`http_request_handler_seconds`),
+// which is the app's own work: validation, hashing, serialization. Queries a
+// handler runs concurrently each add their own latency, and queries run while
+// a streamed body is sent (exports) fall outside the request's split.
+
+// Buckets for CQL latency, from 0.5ms to about 8s.
+fn query_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(0.0005, 2.0, 15).expect("valid buckets")
+}
+
+tokio::task_local! {
+    // Time the current request has spent in CQL queries so far.
+    static QUERY_TIME: Cell<Duration>;
+}
+
+// Adds a finished query's latency to the request running in this task.
+pub fn add_query_time(latency: Duration) {
+    let _ = QUERY_TIME.try_with(|total| total.set(total.get() + latency));
+}
+
+// The driver's own counters and latency percentiles, read from its `Metrics`
+// at each scrape. The driver counts each page of a paged query, and each
+// attempt it retries, where this server's `scylla_query_*` metrics count
+// statements.
+struct DriverMetrics {
+    driver: Arc<scylla::Metrics>,
+    queries: IntCounter,
+    errors: IntCounter,
+    paged_queries: IntCounter,
+    paged_errors: IntCounter,
+    retries: IntCounter,
+    latency: IntGaugeVec,
+}
+
+// Percentiles of the driver's latency histogram exported, by label.
+const DRIVER_PERCENTILES: [(&str, f64); 4] =
+    [("0.5", 50.0), ("0.95", 95.0), ("0.99", 99.0), ("0.999", 99.9)];
+
+impl DriverMetrics {
+    fn new(driver: Arc<scylla::Metrics>) -> Self {
+        let counter = |name: &str, help: &str| {
+            IntCounter::new(name, help).expect("valid metric definition")
+        };
+        DriverMetrics {
+            driver,
+            queries: counter("scylla_driver_queries_total", "Unpaged queries the driver sent"),
+            errors: counter("scylla_driver_errors_total", "Unpaged queries the driver saw fail"),
+            paged_queries: counter(
+                "scylla_driver_paged_queries_total",
+                "Pages of paged queries the driver fetched",
+            ),
+            paged_errors: counter(
+                "scylla_driver_paged_errors_total",
+                "Paged queries the driver saw fail",
+            ),
+            retries: counter(
+                "scylla_driver_retries_total",
+                "Attempts the driver's retry policy repeated",
+            ),
+            latency: IntGaugeVec::new(
+                Opts::new(
+                    "scylla_driver_latency_milliseconds",
+                    "Driver query latency percentiles since start, and their mean",
+                ),
+                &["quantile"],
+            )
+            .expect("valid metric definition"),
+        }
+    }
+
+    fn counters(&self) -> [(&IntCounter, u64); 5] {
+        [
+            (&self.queries, self.driver.get_queries_num()),
+            (&self.errors, self.driver.get_errors_num()),
+            (&self.paged_queries, self.driver.get_queries_iter_num()),
+            (&self.paged_errors, self.driver.get_errors_iter_num()),
+            (&self.retries, self.driver.get_retries_num()),
+        ]
+    }
+}
+
+impl Collector for DriverMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs: Vec<&Desc> =
+            self.counters().into_iter().flat_map(|(counter, _)| counter.desc()).collect();
+        descs.extend(self.latency.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // The driver's counters only grow, so each catches up by the
+        // difference.
+        for (counter, value) in self.counters() {
+            counter.inc_by(value.saturating_sub(counter.get()));
+        }
+        // The histogram has no percentiles until it has samples.
+        if let Ok(mean) = self.driver.get_latency_avg_ms() {
+            self.latency.with_label_values(&["mean"]).set(mean as i64);
+        }
+        for (label, percentile) in DRIVER_PERCENTILES {
+            if let Ok(latency) = self.driver.get_latency_percentile_ms(percentile) {
+                self.latency.with_label_values(&[label]).set(latency as i64);
+            }
+        }
+        let mut families: Vec<MetricFamily> =
+            self.counters().into_iter().flat_map(|(counter, _)| counter.collect()).collect();
+        families.extend(self.latency.collect());
+        families
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    http_cql_duration: HistogramVec,
+    http_handler_duration: HistogramVec,
+    http_cancelled: IntCounterVec,
+    http_in_flight: IntGauge,
+    grpc_calls: IntCounterVec,
+    grpc_duration: HistogramVec,
+    query_errors: IntCounterVec,
+    query_retries: IntCounterVec,
+    query_duration: HistogramVec,
+    slow_queries: IntCounterVec,
+    query_rejections: IntCounterVec,
+    queries_in_flight: IntGauge,
+    // Queries at least this slow are counted in `slow_queries`; see
+    // `query_slow`.
+    slow_query_threshold: Option<Duration>,
+    cache_lookups: IntCounterVec,
+    job_runs: IntCounterVec,
+    job_duration: HistogramVec,
+    write_behind: IntCounterVec,
+    write_behind_queued: IntGauge,
+    webhook_deliveries: IntCounterVec,
+    emails: IntCounterVec,
+    requests_shed: IntCounterVec,
+    shadow_reads: IntCounterVec,
+    dual_writes: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric definition");
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route"),
+            &["method", "route"],
+        )
+        .expect("valid metric definition");
+        let http_cql_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_cql_seconds",
+                "Time HTTP requests spent waiting on CQL queries by route",
+            )
+            .buckets(query_buckets()),
+            &["method", "route"],
+        )
+        .expect("valid metric definition");
+        let http_handler_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_handler_seconds",
+                "Time HTTP requests spent outside CQL queries by route",
+            ),
+            &["method", "route"],
+        )
+        .expect("valid metric definition");
+        let http_cancelled = IntCounterVec::new(
+            Opts::new(
+                "http_requests_cancelled_total",
+                "HTTP requests abandoned by the client before the response was ready",
+            ),
+            &["method"],
+        )
+        .expect("valid metric definition");
+        let http_in_flight = IntGauge::new(
+            "http_requests_in_flight",
+            "HTTP requests received and not yet answered",
+        )
+        .expect("valid metric definition");
+        let grpc_calls = IntCounterVec::new(
+            Opts::new("grpc_calls_total", "gRPC calls by method and status code"),
+            &["method", "code"],
+        )
+        .expect("valid metric definition");
+        let grpc_duration = HistogramVec::new(
+            HistogramOpts::new("grpc_call_duration_seconds", "gRPC call latency by method"),
+            &["method"],
+        )
+        .expect("valid metric definition");
+        let query_errors = IntCounterVec::new(
+            Opts::new("scylla_query_errors_total", "Failed CQL queries by statement"),
+            &["statement"],
+        )
+        .expect("valid metric definition");
+        let query_retries = IntCounterVec::new(
+            Opts::new("scylla_query_retries_total", "CQL queries retried after a transient error"),
+            &["statement"],
+        )
+        .expect("valid metric definition");
+        let query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "scylla_query_duration_seconds",
+                "CQL query latency by statement, retries included",
+            )
+            .buckets(query_buckets()),
+            &["statement"],
+        )
+        .expect("valid metric definition");
+        let slow_queries = IntCounterVec::new(
+            Opts::new(
+                "scylla_slow_queries_total",
+                "CQL queries slower than scylla.slow_query_threshold_ms by statement",
+            ),
+            &["statement"],
+        )
+        .expect("valid metric definition");
+        let query_rejections = IntCounterVec::new(
+            Opts::new(
+                "scylla_query_rejections_total",
+                "CQL queries refused for want of a place under scylla.max_in_flight_queries",
+            ),
+            &["statement"],
+        )
+        .expect("valid metric definition");
+        let queries_in_flight = IntGauge::new(
+            "scylla_queries_in_flight",
+            "CQL queries holding a place under scylla.max_in_flight_queries",
+        )
+        .expect("valid metric definition");
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "User cache lookups by cache and result"),
+            &["cache", "result"],
+        )
+        .expect("valid metric definition");
+        let job_runs = IntCounterVec::new(
+            Opts::new("maintenance_job_runs_total", "Maintenance job runs by job and result"),
+            &["job", "result"],
+        )
+        .expect("valid metric definition");
+        let job_duration = HistogramVec::new(
+            HistogramOpts::new("maintenance_job_duration_seconds", "Maintenance job run time by job"),
+            &["job"],
+        )
+        .expect("valid metric definition");
+        let write_behind = IntCounterVec::new(
+            Opts::new(
+                "write_behind_registrations_total",
+                "Registrations through the write-behind queue by outcome",
+            ),
+            &["outcome"],
+        )
+        .expect("valid metric definition");
+        let write_behind_queued = IntGauge::new(
+            "write_behind_queued",
+            "Registrations waiting in the write-behind queue",
+        )
+        .expect("valid metric definition");
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new("webhook_deliveries_total", "Webhook deliveries by outcome"),
+            &["outcome"],
+        )
+        .expect("valid metric definition");
+        let emails = IntCounterVec::new(
+            Opts::new("emails_total", "Emails handed to the mailer by outcome"),
+            &["outcome"],
+        )
+        .expect("valid metric definition");
+        let requests_shed = IntCounterVec::new(
+            Opts::new("http_requests_shed_total", "Requests turned away as overloaded by priority"),
+            &["priority"],
+        )
+        .expect("valid metric definition");
+        let shadow_reads = IntCounterVec::new(
+            Opts::new("shadow_reads_total", "Reads repeated against the shadow cluster by outcome"),
+            &["outcome"],
+        )
+        .expect("valid metric definition");
+        let dual_writes = IntCounterVec::new(
+            Opts::new("dual_writes_total", "Writes repeated against the shadow cluster by outcome"),
+            &["outcome"],
+        )
+        .expect("valid metric definition");
+
+        let registry = Registry::new();
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_duration.clone()),
+            Box::new(http_cql_duration.clone()),
+            Box::new(http_handler_duration.clone()),
+            Box::new(http_cancelled.clone()),
+            Box::new(http_in_flight.clone()),
+            Box::new(grpc_calls.clone()),
+            Box::new(grpc_duration.clone()),
+            Box::new(query_errors.clone()),
+            Box::new(query_retries.clone()),
+            Box::new(query_duration.clone()),
+            Box::new(slow_queries.clone()),
+            Box::new(query_rejections.clone()),
+            Box::new(queries_in_flight.clone()),
+            Box::new(cache_lookups.clone()),
+            Box::new(job_runs.clone()),
+            Box::new(job_duration.clone()),
+            Box::new(write_behind.clone()),
+            Box::new(write_behind_queued.clone()),
+            Box::new(webhook_deliveries.clone()),
+            Box::new(emails.clone()),
+            Box::new(requests_shed.clone()),
+            Box::new(shadow_reads.clone()),
+            Box::new(dual_writes.clone()),
+        ] {
+            registry.register(collector).expect("metric names are unique");
+        }
+        Metrics {
+            registry,
+            http_requests,
+            http_duration,
+            http_cql_duration,
+            http_handler_duration,
+            http_cancelled,
+            http_in_flight,
+            grpc_calls,
+            grpc_duration,
+            query_errors,
+            query_retries,
+            query_duration,
+            slow_queries,
+            slow_query_threshold: None,
+            query_rejections,
+            queries_in_flight,
+            cache_lookups,
+            job_runs,
+            job_duration,
+            write_behind,
+            write_behind_queued,
+            webhook_deliveries,
+            emails,
+            requests_shed,
+            shadow_reads,
+            dual_writes,
+        }
+    }
+
+    // Records a finished gRPC call; `method` must be one the service knows,
+    // so clients can't add series, and `code` is its gRPC status code.
+    pub fn grpc_call(&self, method: &str, code: u32, elapsed: Duration) {
+        self.grpc_calls
+            .with_label_values(&[method, &code.to_string()])
+            .inc();
+        self.grpc_duration
+            .with_label_values(&[method])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // Exports the metrics `driver` keeps alongside these; see `DriverMetrics`.
+    pub fn register_driver(&self, driver: Arc<scylla::Metrics>) {
+        self.registry
+            .register(Box::new(DriverMetrics::new(driver)))
+            .expect("metric names are unique");
+    }
+
+    // Counts a query that the driver returned an error for; `statement` names
+    // the CQL statement, as in `Statements`.
+    pub fn query_failed(&self, statement: &str) {
+        self.query_errors.with_label_values(&[statement]).inc();
+    }
+
+    pub fn query_retried(&self, statement: &str) {
+        self.query_retries.with_label_values(&[statement]).inc();
+    }
+
+    pub fn query_rejected(&self, statement: &str) {
+        self.query_rejections.with_label_values(&[statement]).inc();
+    }
+
+    // `None` when the queries in flight aren't capped.
+    pub fn queries_in_flight(&self, in_flight: Option<usize>) {
+        if let Some(in_flight) = in_flight {
+            self.queries_in_flight.set(in_flight as i64);
+        }
+    }
+
+    // Records the latency of a finished query of `statement`, and adds it to
+    // the current request's CQL time.
+    pub fn query_finished(&self, statement: &str, latency: Duration) {
+        self.query_duration
+            .with_label_values(&[statement])
+            .observe(latency.as_secs_f64());
+        add_query_time(latency);
+    }
+
+    // Counts queries taking at least `threshold` as slow; `None` counts none.
+    pub fn with_slow_query_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    // Whether a query of `statement` that took `latency` is slow, counting it
+    // if so.
+    pub fn query_slow(&self, statement: &str, latency: Duration) -> bool {
+        let slow = self.slow_query_threshold.is_some_and(|threshold| latency >= threshold);
+        if slow {
+            self.slow_queries.with_label_values(&[statement]).inc();
+        }
+        slow
+    }
+
+    // `cache` is `memory`, `redis_users`, `redis_listings` or `in_flight`, a
+    // hit there being a read that joined one already in flight.
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups.with_label_values(&[cache, result]).inc();
+    }
+
+    // Records a finished run of a maintenance job.
+    pub fn job_run(&self, job: &str, succeeded: bool, elapsed: Duration) {
+        let result = if succeeded { "ok" } else { "error" };
+        self.job_runs.with_label_values(&[job, result]).inc();
+        self.job_duration
+            .with_label_values(&[job])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // `outcome` is `accepted`, `rejected` (the queue was full), `stored` or
+    // `failed`.
+    pub fn write_behind(&self, outcome: &str, count: u64) {
+        self.write_behind.with_label_values(&[outcome]).inc_by(count);
+    }
+
+    pub fn write_behind_queued(&self, queued: usize) {
+        self.write_behind_queued.set(queued as i64);
+    }
+
+    // `outcome` is `delivered`, `retried`, `failed` (given up on) or
+    // `dropped` (the queue was full).
+    pub fn webhook_delivery(&self, outcome: &str) {
+        self.webhook_deliveries.with_label_values(&[outcome]).inc();
+    }
+
+    // `outcome` is `sent`, `failed` or `dropped` (the queue was full).
+    pub fn email(&self, outcome: &str) {
+        self.emails.with_label_values(&[outcome]).inc();
+    }
+
+    // `priority` is `low` or `normal`; high-priority requests are never shed.
+    pub fn request_shed(&self, priority: &str) {
+        self.requests_shed.with_label_values(&[priority]).inc();
+    }
+
+    // `outcome` is `match`, `mismatch`, `error` (the shadow read failed) or
+    // `skipped` (too many were in flight).
+    pub fn shadow_read(&self, outcome: &str) {
+        self.shadow_reads.with_label_values(&[outcome]).inc();
+    }
+
+    // `outcome` is `applied` or `failed` (recorded for reconciliation).
+    pub fn dual_write(&self, outcome: &str) {
+        self.dual_writes.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut caches = BTreeMap::new();
+        for family in self.cache_lookups.collect() {
+            for metric in family.get_metric() {
+                let label = |name: &str| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .find(|pair| pair.get_name() == name)
+                        .map(|pair| pair.get_value().to_string())
+                        .unwrap_or_default()
+                };
+                let (hits, misses) = caches.entry(label("cache")).or_insert((0, 0));
+                let count = metric.get_counter().get_value() as u64;
+                match label("result").as_str() {
+                    "hit" => *hits += count,
+                    _ => *misses += count,
+                }
+            }
+        }
+        Snapshot {
+            http_requests: total(&self.http_requests),
+            http_in_flight: self.http_in_flight.get(),
+            http_cancelled: total(&self.http_cancelled),
+            grpc_calls: total(&self.grpc_calls),
+            query_errors: total(&self.query_errors),
+            query_retries: total(&self.query_retries),
+            caches,
+        }
+    }
+}
+
+// Sum of a counter over all its labels.
+fn total(counter: &IntCounterVec) -> u64 {
+    counter
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
+
+// The counters an operator looks at first, totalled over their labels.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub http_requests: u64,
+    pub http_in_flight: i64,
+    pub http_cancelled: u64,
+    pub grpc_calls: u64,
+    pub query_errors: u64,
+    pub query_retries: u64,
+    // Hits and misses by cache.
+    pub caches: BTreeMap<String, (u64, u64)>,
+}
+
+impl Snapshot {
+    pub fn log(&self) {
+        tracing::info!(
+            http_requests = self.http_requests,
+            http_in_flight = self.http_in_flight,
+            http_cancelled = self.http_cancelled,
+            grpc_calls = self.grpc_calls,
+            query_errors = self.query_errors,
+            query_retries = self.query_retries,
+            caches = ?self.caches,
+            "metrics snapshot"
+        );
+    }
+}
+
+// A snapshot of `metrics` each time the process receives SIGUSR1. The handler
+// is installed before this returns, so from then on the signal no longer
+// terminates the process.
+#[cfg(unix)]
+pub fn snapshots_on_signal(metrics: Arc<Metrics>) -> std::io::Result<impl Stream<Item = Snapshot>> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    let signals = signal(SignalKind::user_defined1())?;
+    Ok(futures::stream::unfold((signals, metrics), |(mut signals, metrics)| async move {
+        signals.recv().await?;
+        Some((metrics.snapshot(), (signals, metrics)))
+    }))
+}
+
+// Keeps `http_requests_in_flight` up to date for one request, however it
+// ends.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn enter(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        InFlight(gauge.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Counts a request as cancelled unless it is disarmed once the response is
+// ready. When a client disconnects, actix drops the request's future, and
+// with it the handler and any query it is awaiting (the driver's futures are
+// cancellation-safe), so the work stops at its next await; dropping the
+// guard along with it is what gets counted.
+struct Cancellation {
+    counter: Option<prometheus::IntCounter>,
+}
+
+impl Cancellation {
+    fn disarm(&mut self) {
+        self.counter = None;
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if let Some(counter) = &self.counter {
+            counter.inc();
+        }
+    }
+}
+
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+    let started = Instant::now();
+    let _in_flight = state
+        .as_ref()
+        .map(|state| InFlight::enter(&state.metrics.http_in_flight));
+    let mut cancellation = Cancellation {
+        counter: state.as_ref().map(|state| {
+            state
+                .metrics
+                .http_cancelled
+                .with_label_values(&[req.method().as_str()])
+        }),
+    };
+
+    let (res, query_time) = QUERY_TIME
+        .scope(Cell::new(Duration::ZERO), async {
+            let res = next.call(req).await;
+            (res, QUERY_TIME.with(Cell::get))
+        })
+        .await;
+    cancellation.disarm();
+    let res = res?;
+
+    if let Some(state) = state {
+        // Label by route pattern rather than path so ids don't explode the
+        // number of series.
+        let pattern = res
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| String::from("unmatched"));
+        let method = res.request().method().as_str();
+        state
+            .metrics
+            .http_requests
+            .with_label_values(&[method, &pattern, res.status().as_str()])
+            .inc();
+        let elapsed = started.elapsed();
+        let metrics = &state.metrics;
+        metrics
+            .http_duration
+            .with_label_values(&[method, &pattern])
+            .observe(elapsed.as_secs_f64());
+        metrics
+            .http_cql_duration
+            .with_label_values(&[method, &pattern])
+            .observe(query_time.as_secs_f64());
+        metrics
+            .http_handler_duration
+            .with_label_values(&[method, &pattern])
+            .observe(elapsed.saturating_sub(query_time).as_secs_f64());
+    }
+    Ok(res)
+}
+
+// GET /metrics
+pub async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&data.metrics.registry.gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(e) => ApiError::internal("Failed to encode metrics", e).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn only_armed_guards_count() {
+        let metrics = Metrics::new();
+        let counter = || Some(metrics.http_cancelled.with_label_values(&["GET"]));
+        drop(Cancellation { counter: counter() });
+        Cancellation { counter: counter() }.disarm();
+        assert_eq!(metrics.http_cancelled.with_label_values(&["GET"]).get(), 1);
+    }
+
+    #[test]
+    fn queries_at_the_threshold_are_slow() {
+        let metrics = Metrics::new().with_slow_query_threshold(Some(Duration::from_millis(500)));
+        assert!(!metrics.query_slow("select_user", Duration::from_millis(499)));
+        assert!(metrics.query_slow("select_user", Duration::from_millis(500)));
+        assert!(metrics.query_slow("select_user", Duration::from_secs(3)));
+        assert_eq!(metrics.slow_queries.with_label_values(&["select_user"]).get(), 2);
+        assert!(!Metrics::new().query_slow("select_user", Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn driver_metrics_are_gathered() {
+        let metrics = Metrics::new();
+        metrics.register_driver(Arc::new(scylla::Metrics::new()));
+        let names: Vec<String> = metrics
+            .registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        for name in ["scylla_driver_queries_total", "scylla_driver_retries_total"] {
+            assert!(names.iter().any(|gathered| gathered == name), "{} missing", name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn query_latency_adds_up_per_request() {
+        let metrics = Metrics::new();
+        let total = QUERY_TIME
+            .scope(Cell::new(Duration::ZERO), async {
+                metrics.query_finished("select_user_by_id", Duration::from_millis(3));
+                metrics.query_finished("insert_user", Duration::from_millis(4));
+                QUERY_TIME.with(Cell::get)
+            })
+            .await;
+        assert_eq!(total, Duration::from_millis(7));
+        // Outside a request only the statement's histogram sees it.
+        metrics.query_finished("select_user_by_id", Duration::from_millis(5));
+        let histogram = metrics.query_duration.with_label_values(&["select_user_by_id"]);
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert!((histogram.get_sample_sum() - 0.008).abs() < 1e-9);
+    }
+
+    #[test]
+    fn snapshots_total_each_metric_over_its_labels() {
+        let metrics = Metrics::new();
+        metrics.http_requests.with_label_values(&["GET", "/users", "200"]).inc();
+        metrics.http_requests.with_label_values(&["POST", "/register", "201"]).inc();
+        metrics.query_failed("select_user");
+        metrics.query_failed("insert_user");
+        metrics.query_retried("select_user");
+        metrics.cache_lookup("memory", true);
+        metrics.cache_lookup("memory", true);
+        metrics.cache_lookup("memory", false);
+        metrics.cache_lookup("redis_users", false);
+        let in_flight = InFlight::enter(&metrics.http_in_flight);
+
+        assert_eq!(
+            metrics.snapshot(),
+            Snapshot {
+                http_requests: 2,
+                http_in_flight: 1,
+                http_cancelled: 0,
+                grpc_calls: 0,
+                query_errors: 2,
+                query_retries: 1,
+                caches: BTreeMap::from([
+                    (String::from("memory"), (2, 1)),
+                    (String::from("redis_users"), (0, 1)),
+                ]),
+            }
+        );
+        drop(in_flight);
+        assert_eq!(metrics.snapshot().http_in_flight, 0);
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn sigusr1_produces_a_snapshot() {
+        use futures::StreamExt;
+        let metrics = Arc::new(Metrics::new());
+        metrics.query_failed("select_user");
+        let mut snapshots = std::pin::pin!(snapshots_on_signal(metrics).unwrap());
+
+        let sent = std::process::Command::new("kill")
+            .args(["-USR1", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(sent.success());
+        let snapshot = actix_web::rt::time::timeout(Duration::from_secs(5), snapshots.next())
+            .await
+            .expect("a snapshot within 5s")
+            .unwrap();
+        assert_eq!(snapshot.query_errors, 1);
+    }
+
+    // Stands in for a query: records whether it ran to completion or was
+    // dropped part way.
+    struct Query {
+        outcome: Arc<Mutex<&'static str>>,
+    }
+
+    impl Drop for Query {
+        fn drop(&mut self) {
+            let mut outcome = self.outcome.lock().unwrap();
+            if *outcome == "running" {
+                *outcome = "cancelled";
+            }
+        }
+    }
+
+    // Serves as main does with `http.cancel_on_disconnect`: a handler whose
+    // client hangs up is dropped mid-query and counted.
+    #[actix_web::test]
+    async fn a_disconnect_cancels_the_handler() {
+        let metrics = Arc::new(Metrics::new());
+        let outcome = Arc::new(Mutex::new("not started"));
+        let (shared_metrics, shared_outcome) = (metrics.clone(), outcome.clone());
+        let server = HttpServer::new(move || {
+            let (metrics, outcome) = (shared_metrics.clone(), shared_outcome.clone());
+            App::new().route(
+                "/slow",
+                web::get().to(move || {
+                    let query = Query { outcome: outcome.clone() };
+                    let mut cancellation = Cancellation {
+                        counter: Some(metrics.http_cancelled.with_label_values(&["GET"])),
+                    };
+                    async move {
+                        *query.outcome.lock().unwrap() = "running";
+                        actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+                        *query.outcome.lock().unwrap() = "completed";
+                        cancellation.disarm();
+                        HttpResponse::Ok().finish()
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .h1_allow_half_closed(false)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        actix_web::rt::time::sleep(Duration::from_millis(300)).await;
+        drop(client);
+        actix_web::rt::time::sleep(Duration::from_millis(300)).await;
+        handle.stop(false).await;
+        assert_eq!(*outcome.lock().unwrap(), "cancelled");
+        assert_eq!(metrics.http_cancelled.with_label_values(&["GET"]).get(), 1);
+    }
+}
@@ -0,0 +1,606 @@
+use crate::config::ScyllaConfig;
+use crate::statements::applied;
+use actix_web::rt::time::{self, sleep};
+use scylla::frame::value::CqlTimestamp;
+use scylla::query::Query;
+use scylla::statement::Consistency;
+use scylla::transport::errors::QueryError;
+use scylla::Session;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// A versioned CQL script from `migrations/`, embedded at build time so the
+// binary can migrate a keyspace without shipping the directory alongside it.
+// Versions are applied in ascending order and never edited once released;
+// schema changes go in a new file appended to `MIGRATIONS`.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub cql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        cql: include_str!("../migrations/0001_initial_schema.cql"),
+    },
+    Migration {
+        version: 2,
+        name: "users_by_email",
+        cql: include_str!("../migrations/0002_users_by_email.cql"),
+    },
+    Migration {
+        version: 3,
+        name: "users_by_name",
+        cql: include_str!("../migrations/0003_users_by_name.cql"),
+    },
+    Migration {
+        version: 4,
+        name: "soft_delete",
+        cql: include_str!("../migrations/0004_soft_delete.cql"),
+    },
+    Migration {
+        version: 5,
+        name: "user_timestamps",
+        cql: include_str!("../migrations/0005_user_timestamps.cql"),
+    },
+    Migration {
+        version: 6,
+        name: "users_by_name_timestamps",
+        cql: include_str!("../migrations/0006_users_by_name_timestamps.cql"),
+    },
+    Migration {
+        version: 7,
+        name: "user_profile",
+        cql: include_str!("../migrations/0007_user_profile.cql"),
+    },
+    Migration {
+        version: 8,
+        name: "user_avatars",
+        cql: include_str!("../migrations/0008_user_avatars.cql"),
+    },
+    Migration {
+        version: 9,
+        name: "idempotency_keys",
+        cql: include_str!("../migrations/0009_idempotency_keys.cql"),
+    },
+    Migration {
+        version: 10,
+        name: "tenants",
+        cql: include_str!("../migrations/0010_tenants.cql"),
+    },
+    Migration {
+        version: 11,
+        name: "audit_log",
+        cql: include_str!("../migrations/0011_audit_log.cql"),
+    },
+    Migration {
+        version: 12,
+        name: "user_events",
+        cql: include_str!("../migrations/0012_user_events.cql"),
+    },
+    Migration {
+        version: 13,
+        name: "outbox",
+        cql: include_str!("../migrations/0013_outbox.cql"),
+    },
+    Migration {
+        version: 14,
+        name: "email_verification",
+        cql: include_str!("../migrations/0014_email_verification.cql"),
+    },
+    Migration {
+        version: 15,
+        name: "password_resets",
+        cql: include_str!("../migrations/0015_password_resets.cql"),
+    },
+    Migration {
+        version: 16,
+        name: "sessions",
+        cql: include_str!("../migrations/0016_sessions.cql"),
+    },
+    Migration {
+        version: 17,
+        name: "oauth",
+        cql: include_str!("../migrations/0017_oauth.cql"),
+    },
+    Migration {
+        version: 18,
+        name: "feature_flags",
+        cql: include_str!("../migrations/0018_feature_flags.cql"),
+    },
+    Migration {
+        version: 19,
+        name: "webhooks",
+        cql: include_str!("../migrations/0019_webhooks.cql"),
+    },
+    Migration {
+        version: 20,
+        name: "user_phone",
+        cql: include_str!("../migrations/0020_user_phone.cql"),
+    },
+    Migration {
+        version: 21,
+        name: "user_addresses",
+        cql: include_str!("../migrations/0021_user_addresses.cql"),
+    },
+    Migration {
+        version: 22,
+        name: "user_tags",
+        cql: include_str!("../migrations/0022_user_tags.cql"),
+    },
+    Migration {
+        version: 23,
+        name: "user_metadata",
+        cql: include_str!("../migrations/0023_user_metadata.cql"),
+    },
+    Migration {
+        version: 24,
+        name: "rate_limits",
+        cql: include_str!("../migrations/0024_rate_limits.cql"),
+    },
+    Migration {
+        version: 25,
+        name: "user_version",
+        cql: include_str!("../migrations/0025_user_version.cql"),
+    },
+    Migration {
+        version: 26,
+        name: "user_stats",
+        cql: include_str!("../migrations/0026_user_stats.cql"),
+    },
+    Migration {
+        version: 27,
+        name: "users_by_day",
+        cql: include_str!("../migrations/0027_users_by_day.cql"),
+    },
+    Migration {
+        version: 28,
+        name: "user_status",
+        cql: include_str!("../migrations/0028_user_status.cql"),
+    },
+    Migration {
+        version: 29,
+        name: "dual_write_failures",
+        cql: include_str!("../migrations/0029_dual_write_failures.cql"),
+    },
+    Migration {
+        version: 30,
+        name: "groups",
+        cql: include_str!("../migrations/0030_groups.cql"),
+    },
+    Migration {
+        version: 31,
+        name: "posts",
+        cql: include_str!("../migrations/0031_posts.cql"),
+    },
+    Migration {
+        version: 32,
+        name: "user_anonymized",
+        cql: include_str!("../migrations/0032_user_anonymized.cql"),
+    },
+];
+
+fn checksum(cql: &str) -> String {
+    Sha256::digest(cql.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Splits a script into statements on `;`, dropping `--` comments. Scripts
+// must not put `;` or `--` inside string literals.
+pub fn statements(cql: &str) -> Vec<String> {
+    cql.lines()
+        .map(|line| line.split_once("--").map_or(line, |(code, _)| code))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Only one runner applies migrations at a time: it holds this row of
+// `schema_migration_lock`, taken with a lightweight transaction. The row
+// expires after `LOCK_TTL`, so a runner that crashed doesn't block the next
+// one for long; a live runner renews it before each migration.
+const LOCK_TTL: Duration = Duration::from_secs(300);
+// How long a runner waits for another to finish before giving up.
+const LOCK_WAIT: Duration = Duration::from_secs(600);
+const LOCK_POLL: Duration = Duration::from_secs(2);
+
+// How DDL is issued: at `consistency`, each change followed by a wait of at
+// most `agreement_timeout` for every node to report the same schema version,
+// so nothing is prepared or queried against a table some node hasn't seen.
+#[derive(Debug, Clone, Copy)]
+pub struct Ddl {
+    pub consistency: Consistency,
+    pub agreement_timeout: Duration,
+}
+
+impl Ddl {
+    pub fn new(config: &ScyllaConfig) -> Self {
+        Ddl {
+            consistency: config.schema_consistency.cql(),
+            agreement_timeout: Duration::from_secs(config.schema_agreement_timeout_secs),
+        }
+    }
+
+    pub async fn run(&self, session: &Session, statement: impl Into<String>) -> Result<(), QueryError> {
+        let mut query = Query::new(statement);
+        query.set_consistency(self.consistency);
+        session.query_unpaged(query, &[]).await.map(|_| ())
+    }
+
+    pub async fn agree(&self, session: &Session) -> Result<(), String> {
+        within(self.agreement_timeout, session.await_schema_agreement()).await
+    }
+}
+
+// Waits up to `timeout` for `agreement`, the driver's schema agreement check.
+async fn within<T, E: Display>(
+    timeout: Duration,
+    agreement: impl Future<Output = Result<T, E>>,
+) -> Result<(), String> {
+    match time::timeout(timeout, agreement).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("nodes did not agree on the schema within {:?}", timeout)),
+    }
+}
+
+async fn create_tables(session: &Session, keyspace: &str, ddl: &Ddl) -> Result<(), String> {
+    for table in [
+        "schema_migrations (version int PRIMARY KEY, name text, checksum text, applied_at timestamp)",
+        "schema_migration_lock (name text PRIMARY KEY, owner uuid, acquired_at timestamp)",
+    ] {
+        ddl.run(session, format!("CREATE TABLE IF NOT EXISTS {}.{}", keyspace, table))
+            .await
+            .map_err(|e| format!("cannot create {}.{}: {}", keyspace, table, e))?;
+    }
+    ddl.agree(session)
+        .await
+        .map_err(|e| format!("no schema agreement on the migration tables: {}", e))?;
+    Ok(())
+}
+
+async fn applied_versions(session: &Session, keyspace: &str) -> Result<HashMap<i32, String>, String> {
+    let result = session
+        .query_unpaged(
+            format!("SELECT version, checksum FROM {}.schema_migrations", keyspace),
+            &[],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    result
+        .into_rows_result()
+        .map_err(|e| e.to_string())?
+        .rows::<(i32, String)>()
+        .map_err(|e| e.to_string())?
+        .collect::<Result<HashMap<_, _>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+fn now() -> CqlTimestamp {
+    CqlTimestamp(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64),
+    )
+}
+
+// Takes the lock for `owner`, or renews it when `owner` already holds it.
+async fn try_lock(session: &Session, keyspace: &str, owner: Uuid) -> Result<bool, String> {
+    let ttl = LOCK_TTL.as_secs() as i32;
+    let result = session
+        .query_unpaged(
+            format!(
+                "INSERT INTO {}.schema_migration_lock (name, owner, acquired_at) \
+                 VALUES ('migrations', ?, ?) IF NOT EXISTS USING TTL ?",
+                keyspace
+            ),
+            (owner, now(), ttl),
+        )
+        .await
+        .map_err(|e| format!("cannot take the migration lock: {}", e))?;
+    if applied(result)? {
+        return Ok(true);
+    }
+    let result = session
+        .query_unpaged(
+            format!(
+                "UPDATE {}.schema_migration_lock USING TTL ? SET acquired_at = ? \
+                 WHERE name = 'migrations' IF owner = ?",
+                keyspace
+            ),
+            (ttl, now(), owner),
+        )
+        .await
+        .map_err(|e| format!("cannot renew the migration lock: {}", e))?;
+    applied(result)
+}
+
+async fn lock(session: &Session, keyspace: &str, owner: Uuid) -> Result<(), String> {
+    let started = Instant::now();
+    while !try_lock(session, keyspace, owner).await? {
+        if started.elapsed() > LOCK_WAIT {
+            return Err(format!(
+                "another runner has held {}.schema_migration_lock for over {:?}",
+                keyspace, LOCK_WAIT
+            ));
+        }
+        tracing::info!(keyspace, "waiting for another runner to finish migrating");
+        sleep(LOCK_POLL).await;
+    }
+    Ok(())
+}
+
+async fn unlock(session: &Session, keyspace: &str, owner: Uuid) {
+    let result = session
+        .query_unpaged(
+            format!(
+                "DELETE FROM {}.schema_migration_lock WHERE name = 'migrations' IF owner = ?",
+                keyspace
+            ),
+            (owner,),
+        )
+        .await;
+    if let Err(e) = result {
+        tracing::warn!(error = %e, "failed to release the migration lock; it expires on its own");
+    }
+}
+
+// The table and columns an `ALTER TABLE ... ADD` statement adds, or `None`
+// for any other statement.
+pub fn added_columns(statement: &str) -> Option<(String, Vec<String>)> {
+    let words: Vec<&str> = statement.split_whitespace().collect();
+    let [alter, table_kw, table, add, ..] = words.as_slice() else {
+        return None;
+    };
+    if !alter.eq_ignore_ascii_case("ALTER")
+        || !table_kw.eq_ignore_ascii_case("TABLE")
+        || !add.eq_ignore_ascii_case("ADD")
+    {
+        return None;
+    }
+    let mut definitions = statement.trim();
+    for _ in 0..4 {
+        definitions = definitions.split_once(char::is_whitespace)?.1.trim_start();
+    }
+    let definitions = definitions
+        .strip_prefix('(')
+        .and_then(|inner| inner.strip_suffix(')'))
+        .unwrap_or(definitions);
+
+    // Commas inside a type such as `map<text, text>` don't separate columns.
+    let mut columns = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in definitions.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                columns.push(&definitions[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    columns.push(&definitions[start..]);
+    let columns = columns
+        .into_iter()
+        .filter_map(|column| column.split_whitespace().next())
+        .map(|name| name.to_lowercase())
+        .collect::<Vec<_>>();
+    let table = table.rsplit('.').next().unwrap_or(table).to_lowercase();
+    Some((table, columns))
+}
+
+// Whether every one of `columns` already exists in `keyspace.table`.
+async fn has_columns(
+    session: &Session,
+    keyspace: &str,
+    table: &str,
+    columns: &[String],
+) -> Result<bool, String> {
+    let result = session
+        .query_unpaged(
+            "SELECT column_name FROM system_schema.columns \
+             WHERE keyspace_name = ? AND table_name = ?",
+            (keyspace, table),
+        )
+        .await
+        .map_err(|e| format!("cannot read the columns of {}.{}: {}", keyspace, table, e))?;
+    let existing = result
+        .into_rows_result()
+        .map_err(|e| e.to_string())?
+        .rows::<(String,)>()
+        .map_err(|e| e.to_string())?
+        .map(|row| row.map(|(name,)| name))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    Ok(columns.iter().all(|column| existing.contains(column)))
+}
+
+// Applies every migration not yet recorded in `schema_migrations`, holding
+// the migration lock so concurrent runners (say, several pods with
+// `migrate_on_startup`) take turns; each reads the recorded versions only once
+// it holds the lock. Each statement runs in order and waits for schema
+// agreement before the next; the version is recorded only once all of its
+// statements have succeeded, so a run interrupted half-way re-applies that
+// migration from the top. DDL statements use `IF NOT EXISTS`, and an
+// `ALTER TABLE ... ADD` whose columns all exist already is skipped, as ALTER
+// has no `IF NOT EXISTS`. An applied migration whose file has since changed is
+// refused rather than silently skipped.
+pub async fn run(session: &Session, keyspace: &str, ddl: &Ddl) -> Result<usize, String> {
+    create_tables(session, keyspace, ddl).await?;
+    let owner = Uuid::new_v4();
+    lock(session, keyspace, owner).await?;
+    let result = apply_pending(session, keyspace, owner, ddl).await;
+    unlock(session, keyspace, owner).await;
+    result
+}
+
+// The migrations not yet applied to `keyspace`, without applying them; an
+// applied migration whose file has since changed is an error, as in `run`.
+pub async fn pending(session: &Session, keyspace: &str) -> Result<Vec<&'static Migration>, String> {
+    let versions = applied_versions(session, keyspace).await?;
+    let mut pending = Vec::new();
+    for migration in MIGRATIONS {
+        match versions.get(&migration.version) {
+            Some(recorded) if *recorded != checksum(migration.cql) => {
+                return Err(format!(
+                    "migration {:04}_{} was modified after it was applied",
+                    migration.version, migration.name
+                ));
+            }
+            Some(_) => {}
+            None => pending.push(migration),
+        }
+    }
+    Ok(pending)
+}
+
+async fn apply_pending(
+    session: &Session,
+    keyspace: &str,
+    owner: Uuid,
+    ddl: &Ddl,
+) -> Result<usize, String> {
+    let versions = applied_versions(session, keyspace).await?;
+    session
+        .use_keyspace(keyspace, false)
+        .await
+        .map_err(|e| format!("cannot use keyspace {}: {}", keyspace, e))?;
+
+    let mut count = 0;
+    for migration in MIGRATIONS {
+        let sum = checksum(migration.cql);
+        if let Some(recorded) = versions.get(&migration.version) {
+            if *recorded != sum {
+                return Err(format!(
+                    "migration {:04}_{} was modified after it was applied",
+                    migration.version, migration.name
+                ));
+            }
+            continue;
+        }
+
+        if !try_lock(session, keyspace, owner).await? {
+            return Err(String::from("lost the migration lock"));
+        }
+        tracing::info!(version = migration.version, name = migration.name, "applying migration");
+        for statement in statements(migration.cql) {
+            if let Some((table, columns)) = added_columns(&statement)
+                && has_columns(session, keyspace, &table, &columns).await?
+            {
+                tracing::info!(version = migration.version, table, ?columns, "columns already added");
+                continue;
+            }
+            ddl.run(session, statement.as_str())
+                .await
+                .map_err(|e| format!("migration {:04} failed on `{}`: {}", migration.version, statement, e))?;
+            ddl.agree(session)
+                .await
+                .map_err(|e| format!("migration {:04}: no schema agreement: {}", migration.version, e))?;
+        }
+
+        session
+            .query_unpaged(
+                format!(
+                    "INSERT INTO {}.schema_migrations (version, name, checksum, applied_at) \
+                     VALUES (?, ?, ?, ?)",
+                    keyspace
+                ),
+                (migration.version, migration.name, sum, now()),
+            )
+            .await
+            .map_err(|e| format!("cannot record migration {:04}: {}", migration.version, e))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_split_on_semicolons_and_drop_comments() {
+        let script = "-- header; with a semicolon\n\
+                      CREATE TABLE a (id int PRIMARY KEY); -- trailing\n\
+                      \n\
+                      ALTER TABLE a ADD b text;\n";
+        assert_eq!(
+            statements(script),
+            ["CREATE TABLE a (id int PRIMARY KEY)", "ALTER TABLE a ADD b text"]
+        );
+        assert!(statements("-- only a comment\n").is_empty());
+    }
+
+    #[test]
+    fn every_migration_has_statements_and_a_unique_version() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+            assert!(!statements(migration.cql).is_empty(), "{} is empty", migration.name);
+        }
+    }
+
+    #[test]
+    fn added_columns_of_single_and_multi_column_alters() {
+        assert_eq!(
+            added_columns("ALTER TABLE users ADD deleted_at timestamp"),
+            Some((String::from("users"), vec![String::from("deleted_at")]))
+        );
+        assert_eq!(
+            added_columns("alter table ks.Users add (created_at timestamp, tags map<text, text>)"),
+            Some((
+                String::from("users"),
+                vec![String::from("created_at"), String::from("tags")]
+            ))
+        );
+        assert_eq!(added_columns("CREATE TABLE IF NOT EXISTS t (id int PRIMARY KEY)"), None);
+        assert_eq!(added_columns("ALTER TABLE users DROP deleted_at"), None);
+    }
+
+    #[test]
+    fn released_migrations_only_add_columns_through_recognised_alters() {
+        for migration in MIGRATIONS {
+            for statement in statements(migration.cql) {
+                if statement.to_uppercase().starts_with("ALTER TABLE") {
+                    let (_, columns) = added_columns(&statement).expect("an ADD");
+                    assert!(!columns.is_empty());
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn agreement_is_awaited_until_reached() {
+        let agreed = async {
+            time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, String>(Uuid::new_v4())
+        };
+        assert_eq!(within(Duration::from_secs(5), agreed).await, Ok(()));
+
+        let failed = async { Err::<Uuid, _>("connection reset") };
+        assert_eq!(within(Duration::from_secs(5), failed).await, Err(String::from("connection reset")));
+    }
+
+    #[actix_web::test]
+    async fn waiting_for_agreement_times_out_cleanly() {
+        let never = futures::future::pending::<Result<Uuid, String>>();
+        assert_eq!(
+            within(Duration::from_millis(50), never).await,
+            Err(String::from("nodes did not agree on the schema within 50ms"))
+        );
+    }
+}
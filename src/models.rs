@@ -0,0 +1,801 @@
+use crate::error::Problem;
+use chrono::{DateTime, Utc};
+use scylla::{DeserializeRow, DeserializeValue, SerializeRow};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// Optional profile details, stored as the `profile` user-defined type.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, DeserializeValue)]
+pub struct Profile {
+    pub bio: Option<String>,
+    /// An http or https URL.
+    pub avatar_url: Option<String>,
+    /// A BCP 47 language tag, such as `en-GB`.
+    pub locale: Option<String>,
+    /// An IANA time zone name, such as `Europe/Berlin`.
+    pub timezone: Option<String>,
+}
+
+/// A postal address, stored as the frozen `address` user-defined type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, DeserializeValue)]
+pub struct Address {
+    /// What the address is for, such as `home` or `billing`.
+    #[serde(default)]
+    pub label: Option<String>,
+    pub line1: String,
+    #[serde(default)]
+    pub line2: Option<String>,
+    pub city: String,
+    /// State, province or county.
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub postal_code: Option<String>,
+    /// An ISO 3166-1 alpha-2 code, such as `GB`.
+    pub country: String,
+}
+
+/// A user's addresses, in the order they were added. An address is removed
+/// by its position in this list.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Addresses {
+    pub addresses: Vec<Address>,
+}
+
+/// A tag to add to a user. Tags are stored trimmed and lower-cased.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewTag {
+    /// Up to 32 letters, digits, `-`, `_` and `:`, such as `beta-tester`.
+    pub tag: String,
+}
+
+/// A user's tags, in alphabetical order.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Tags {
+    pub tags: Vec<String>,
+}
+
+/// The value to store under a metadata key.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MetadataValue {
+    /// Up to 1 KiB, stored as given.
+    pub value: String,
+}
+
+/// A user's metadata: free-form attributes by key, in key order.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Metadata {
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// How often a user has logged in and had their profile read.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserStats {
+    /// Successful logins, by password or OAuth.
+    pub logins: u64,
+    /// Reads of GET /users/{id}, including those answered 304.
+    pub profile_views: u64,
+}
+
+/// A group of users, such as a team. Read and written as a `groups` row.
+#[derive(Debug, Clone, Serialize, ToSchema, DeserializeRow, SerializeRow)]
+pub struct Group {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// A group to create.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewGroup {
+    /// Up to 100 characters, such as `Platform team`.
+    pub name: String,
+    /// Up to 500 characters.
+    pub description: Option<String>,
+}
+
+/// Changes to a group; fields left out are kept. An empty `description`
+/// removes it.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateGroup {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// A user in a group, and when they were added.
+#[derive(Debug, Serialize, ToSchema, DeserializeRow)]
+pub struct GroupMember {
+    pub user_id: Uuid,
+    pub added_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GroupsQuery {
+    /// How many to return; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+}
+
+/// A post written by a user.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Post {
+    /// A time-based (version 1) UUID; posts sort by it.
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub body: String,
+    /// When the post was written, as recorded in its id.
+    pub created_at: DateTime<Utc>,
+}
+
+/// A post to publish.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewPost {
+    /// Up to 5000 characters.
+    pub body: String,
+}
+
+/// A page of a user's posts, newest first.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PostsPage {
+    pub posts: Vec<Post>,
+    /// Absent on the last page. Usable for `http.cursor_max_age_secs`.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PostsQuery {
+    /// Page size; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page.
+    pub cursor: Option<String>,
+}
+
+/// Sent with `_links` (a `UserLinks`) wherever the REST API returns a user.
+/// Unset optional fields are `null`, or left out with `?null_fields=omit`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, DeserializeRow)]
+pub struct User {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    /// An E.164 number such as `+14155550123`, if the user gave one. Null in
+    /// search results, whose index rows don't copy it.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub phone: Option<String>,
+    /// Null in search results, whose index rows don't copy it.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub profile: Option<Profile>,
+    /// Null for users created before timestamps were recorded.
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Null for users not changed since timestamps were recorded.
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// When the user expires and is deleted, for users registered with
+    /// `expires_in_seconds`; null for those that don't expire.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Whether the user has confirmed their email with POST /verify/{token}.
+    /// Null in search results, whose index rows don't copy it.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub verified: Option<bool>,
+    /// 1 when the user was created, one more with every update or
+    /// replacement; send it back in `version` to only write while the user
+    /// is unchanged. Null for users not changed since versions were
+    /// recorded, and in search results.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub version: Option<i32>,
+    /// Whether the user is active, suspended or deactivated; see POST
+    /// /users/{id}/deactivate. Null in search results.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub status: Option<UserStatus>,
+    /// When an admin erased the user's personal data with POST
+    /// /users/{id}/anonymize; null for users never anonymized, and in search
+    /// results.
+    #[serde(default)]
+    #[scylla(skip)]
+    pub anonymized_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    #[default]
+    Active,
+    /// Barred by an admin: the user can't sign in, and only an admin can
+    /// activate them again.
+    Suspended,
+    /// Closed by the user or an admin, who can activate it again.
+    Deactivated,
+}
+
+impl UserStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UserStatus::Active => "active",
+            UserStatus::Suspended => "suspended",
+            UserStatus::Deactivated => "deactivated",
+        }
+    }
+
+    // The status stored in the `status` column; users written before it
+    // existed have none, and are active.
+    pub fn from_column(column: Option<&str>) -> Self {
+        match column {
+            Some("suspended") => UserStatus::Suspended,
+            Some("deactivated") => UserStatus::Deactivated,
+            _ => UserStatus::Active,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+    /// Optional; users registered without one cannot log in.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// In international form, such as `+44 20 7946 0958`; stored as E.164.
+    #[serde(default)]
+    pub phone: Option<String>,
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// Deletes the user this long after registration, for guest and other
+    /// temporary accounts; defaults to `http.default_user_ttl_secs`.
+    #[serde(default)]
+    pub expires_in_seconds: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateUser {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    /// In international form; stored as E.164.
+    #[serde(default)]
+    pub phone: Option<String>,
+    /// Sets the profile fields present; the others keep their values.
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// Only update while the user is at this `version`, else 409.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i32>,
+}
+
+/// Every field of a user, for PUT; a phone or profile left out is cleared.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReplaceUser {
+    pub name: String,
+    pub email: String,
+    #[serde(default)]
+    pub phone: Option<String>,
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// Only replace while the user is at this `version`, else 409.
+    #[serde(default)]
+    pub version: Option<i32>,
+}
+
+/// One mutation in a POST /batch request, tagged by `op`.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Insert { user: NewUser },
+    Update { id: Uuid, changes: UpdateUser },
+    Delete { id: Uuid },
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BatchQuery {
+    /// Check that updated and deleted users exist and that new emails are
+    /// free for every operation before claiming anything, and answer 422
+    /// with all problems found.
+    pub validate_first: Option<bool>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchResponse {
+    pub applied: usize,
+    /// Ids of the inserted users, in the order of their `insert` operations.
+    pub created: Vec<Uuid>,
+}
+
+/// Outcome of one item of a bulk registration, in request order.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BulkItemResult {
+    /// Position of the item in the request array.
+    pub index: usize,
+    pub status: u16,
+    /// Id of the created user; absent when the item failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Problem>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BulkRegisterResponse {
+    pub created: usize,
+    pub failed: usize,
+    pub results: Vec<BulkItemResult>,
+}
+
+/// Users to delete in one request: `ids`, or those matching `status` and
+/// `older_than`.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct BulkDeleteRequest {
+    /// Up to `http.bulk_max_items` user ids.
+    #[serde(default)]
+    pub ids: Option<Vec<Uuid>>,
+    /// Only users with this status; give it, `older_than` or both instead
+    /// of `ids`.
+    #[serde(default)]
+    pub status: Option<UserStatus>,
+    /// Only users last changed, or created if never changed, before this
+    /// RFC 3339 time.
+    #[serde(default)]
+    pub older_than: Option<DateTime<Utc>>,
+    /// Remove the rows and free their emails instead of marking them
+    /// deleted.
+    #[serde(default)]
+    pub hard: Option<bool>,
+}
+
+/// Outcome of deleting one user of a bulk delete.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BulkDeleteResult {
+    pub id: Uuid,
+    /// 200 when deleted, else the status a single DELETE would have had.
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Problem>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BulkDeleteResponse {
+    pub deleted: usize,
+    pub failed: usize,
+    /// In request order for `ids`, in storage order for a filter.
+    pub results: Vec<BulkDeleteResult>,
+    /// Whether a filter matched more users than one request deletes; send
+    /// it again for the next ones.
+    pub more: bool,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UserCount {
+    /// Live users; soft-deleted ones are left out.
+    pub count: u64,
+    pub counted_at: DateTime<Utc>,
+}
+
+/// `open` while the cluster is failing and CQL requests are refused at once;
+/// `half_open` while one request tests whether it has recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct NodeStatus {
+    pub host_id: Uuid,
+    pub address: String,
+    pub datacenter: Option<String>,
+    pub rack: Option<String>,
+    /// Whether the driver holds open connections to the node.
+    pub connected: bool,
+    /// Whether the node answered the last probe.
+    pub up: bool,
+    /// How long the last probe took, when it was answered.
+    pub latency_ms: Option<f64>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    /// The most recent failed probe's error, kept after the node recovers.
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ClusterStatus {
+    /// When the nodes were last probed.
+    pub checked_at: DateTime<Utc>,
+    pub breaker: BreakerState,
+    pub nodes: Vec<NodeStatus>,
+}
+
+/// A node as the driver's cluster metadata describes it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct NodeMetadata {
+    pub host_id: Uuid,
+    pub address: String,
+    pub datacenter: Option<String>,
+    pub rack: Option<String>,
+    /// Whether the driver holds open connections to the node.
+    pub connected: bool,
+    /// Tokens the node owns on the ring.
+    pub tokens: usize,
+    /// Share of the token range the node's tokens own, from 0 to 1, before
+    /// replication.
+    pub primary_ownership: f64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DatacenterMetadata {
+    pub name: String,
+    pub nodes: usize,
+    pub racks: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct KeyspaceMetadata {
+    pub name: String,
+    /// `SimpleStrategy`, `NetworkTopologyStrategy`, `LocalStrategy` or the
+    /// class of another strategy.
+    pub strategy: String,
+    /// Replication factor overall (`replication_factor`) or per datacenter,
+    /// or the options of another strategy.
+    pub replication: BTreeMap<String, String>,
+    pub tables: Vec<String>,
+    pub views: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ColumnMetadata {
+    pub name: String,
+    /// The column's CQL type, such as `text` or `frozen<profile>`.
+    #[serde(rename = "type")]
+    pub column_type: String,
+    /// `partition_key`, `clustering`, `static` or `regular`.
+    pub kind: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TableMetadata {
+    pub keyspace: String,
+    pub name: String,
+    pub partition_key: Vec<String>,
+    pub clustering_key: Vec<String>,
+    pub columns: Vec<ColumnMetadata>,
+}
+
+/// A materialized view: a table the cluster keeps in step with `base_table`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ViewMetadata {
+    pub base_table: String,
+    #[serde(flatten)]
+    pub view: TableMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldMetadata {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: String,
+}
+
+/// A user-defined type, with its fields in declaration order.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TypeMetadata {
+    pub name: String,
+    pub fields: Vec<FieldMetadata>,
+}
+
+/// The schema of the serving keyspace, each list ordered by name.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SchemaMetadata {
+    pub keyspace: String,
+    pub tables: Vec<TableMetadata>,
+    pub views: Vec<ViewMetadata>,
+    pub types: Vec<TypeMetadata>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SchemaQuery {
+    /// Fetch the schema from the cluster first, rather than serve the copy
+    /// the driver refreshes on its own, which may predate a recent change.
+    pub refresh: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ClusterMetadata {
+    pub nodes: Vec<NodeMetadata>,
+    pub datacenters: Vec<DatacenterMetadata>,
+    pub keyspaces: Vec<KeyspaceMetadata>,
+    /// The serving keyspace's `users` table, unless the driver has no schema
+    /// for it.
+    pub users_table: Option<TableMetadata>,
+}
+
+/// How far an index is usable: `missing`; `mismatched` when an index of its
+/// name covers another column; `building` while some node hasn't finished
+/// building it, so queries through it may miss rows; `built`; or `unknown`
+/// when the cluster doesn't report index builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexState {
+    Missing,
+    Mismatched,
+    Building,
+    Built,
+    Unknown,
+}
+
+/// A secondary index the API queries through.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct IndexStatus {
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    /// The queries that need it.
+    pub used_by: String,
+    pub state: IndexState,
+    /// Nodes that have built the index, and nodes still building it.
+    pub built_on: usize,
+    pub building_on: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Indexes {
+    pub indexes: Vec<IndexStatus>,
+    /// Whether every index exists on the right column and none is building.
+    pub ready: bool,
+    /// The indexes this request created.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub created: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewTenant {
+    /// Lowercase letters, digits and underscores.
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Tenant {
+    pub id: String,
+    /// The keyspace the tenant's users are kept in.
+    pub keyspace: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// A record of an import that was not imported.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportLineError {
+    /// Line of the upload the record starts on, counting from 1.
+    pub line: usize,
+    pub status: u16,
+    pub error: Problem,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub failed: usize,
+    /// The first 1000 failures, by line.
+    pub errors: Vec<ImportLineError>,
+}
+
+// Full replacement of a user's roles; `admin` unlocks deletes and the admin
+// endpoints.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserRoles {
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Id,
+    Name,
+    Email,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusFilter {
+    #[default]
+    Active,
+    Suspended,
+    Deactivated,
+    All,
+}
+
+impl StatusFilter {
+    pub fn admits(self, status: UserStatus) -> bool {
+        match self {
+            StatusFilter::Active => status == UserStatus::Active,
+            StatusFilter::Suspended => status == UserStatus::Suspended,
+            StatusFilter::Deactivated => status == UserStatus::Deactivated,
+            StatusFilter::All => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListUsersQuery {
+    /// Page size; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page; one from another listing, such
+    /// as GET /users/search, or from other filters or sort is refused.
+    pub cursor: Option<String>,
+    /// Orders the users within each page; pages follow storage order.
+    pub sort: Option<SortField>,
+    /// Direction of `sort`; defaults to `asc`.
+    pub order: Option<SortOrder>,
+    /// Only users with exactly this name.
+    pub name: Option<String>,
+    /// Only users with exactly this email.
+    pub email: Option<String>,
+    /// Only users created after this RFC 3339 time. With no other filter
+    /// but `created_before`, pages follow creation order and are read by
+    /// day rather than by scanning every user.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only users created before this RFC 3339 time.
+    pub created_before: Option<DateTime<Utc>>,
+    /// Only users last changed after this RFC 3339 time.
+    pub updated_after: Option<DateTime<Utc>>,
+    /// Only users last changed before this RFC 3339 time.
+    pub updated_before: Option<DateTime<Utc>>,
+    /// Only users whose email is, or isn't, verified. Users registered
+    /// before verification existed match neither.
+    pub verified: Option<bool>,
+    /// Only users with this tag.
+    pub tag: Option<String>,
+    /// Only users with this status, or `all`; defaults to `active`. Users
+    /// are read and then left out, so a page may hold fewer than `limit`.
+    pub status: Option<StatusFilter>,
+    /// Comma-separated user fields to return, e.g. `id,name`; all by default.
+    /// Only the columns behind them are read.
+    pub fields: Option<String>,
+    /// Adds `X-Total-Count`, the number of live users as GET /users/count
+    /// reports it; not with filters.
+    pub total: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// Comma-separated user fields to write as columns, in order, e.g.
+    /// `id,email`; all by default.
+    pub fields: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchUsersQuery {
+    /// Case-insensitive start of the name; give this or `q`.
+    pub name_prefix: Option<String>,
+    /// Words to find in names, emails and phone numbers, typos allowed,
+    /// through the search index; give this or `name_prefix`.
+    pub q: Option<String>,
+    /// Page size; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page of this search.
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CheckEmailQuery {
+    /// The address to check, as the user typed it.
+    pub email: String,
+}
+
+/// Whether an address could be registered.
+#[derive(Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct EmailCheck {
+    pub valid: bool,
+    /// Absent when the deployment hides availability from anonymous callers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<bool>,
+    /// Why the address can't be registered; absent when it can.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl EmailCheck {
+    // `validated` is the outcome of `validation::email`, `taken` whether a
+    // user holds the address, when the caller may know.
+    pub fn new(validated: Result<String, String>, taken: Option<bool>) -> Self {
+        match (validated, taken) {
+            (Err(reason), _) => EmailCheck {
+                valid: false,
+                available: Some(false),
+                reason: Some(reason),
+            },
+            (Ok(_), Some(true)) => EmailCheck {
+                valid: true,
+                available: Some(false),
+                reason: Some(String::from("already registered")),
+            },
+            (Ok(_), taken) => EmailCheck {
+                valid: true,
+                available: taken.map(|taken| !taken),
+                reason: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteUserQuery {
+    /// Remove the row and free its email instead of marking it deleted.
+    pub hard: Option<bool>,
+}
+
+/// Sent with `_links` (a `PageLinks`).
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UsersPage {
+    pub users: Vec<User>,
+    /// Absent on the last page. Usable for `http.cursor_max_age_secs`.
+    pub next_cursor: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn email_checks_explain_unavailable_addresses() {
+        assert_eq!(
+            EmailCheck::new(Ok(String::from("ada@example.com")), Some(false)),
+            EmailCheck {
+                valid: true,
+                available: Some(true),
+                reason: None,
+            }
+        );
+        assert_eq!(
+            EmailCheck::new(Ok(String::from("ada@example.com")), Some(true)),
+            EmailCheck {
+                valid: true,
+                available: Some(false),
+                reason: Some(String::from("already registered")),
+            }
+        );
+        assert_eq!(
+            EmailCheck::new(Err(String::from("must be a valid email address")), None),
+            EmailCheck {
+                valid: false,
+                available: Some(false),
+                reason: Some(String::from("must be a valid email address")),
+            }
+        );
+    }
+
+    #[test]
+    fn hidden_availability_is_left_out() {
+        let check = EmailCheck::new(Ok(String::from("ada@example.com")), None);
+        assert_eq!(serde_json::to_value(&check).unwrap(), serde_json::json!({"valid": true}));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::error::ApiError;
+use crate::models::{ClusterStatus, NodeStatus};
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use actix_web::rt::time::timeout;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use scylla::load_balancing::{FallbackPlan, LoadBalancingPolicy, RoutingInfo};
+use scylla::routing::Shard;
+use scylla::transport::{ClusterData, Node, NodeRef};
+use scylla::ExecutionProfile;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Probes every node the driver knows of, one at a time, every
+// `scylla.monitor_interval_secs`, with the readiness probe query
+// (`SELECT now() FROM system.local`) pinned to that node and given
+// `http.readiness_timeout_ms`. GET /admin/status reports what the last round
+// found per node (answering or not, how fast, the last error) beside the
+// circuit breaker's state, so slowness can be placed in the app or the
+// database. Probes bypass `observe`: they must reach the cluster even while
+// the breaker is open, and their failures shouldn't hold it open. With the
+// interval set to 0 there is no background task and each GET probes.
+
+pub struct Monitor {
+    interval: Option<Duration>,
+    probe_timeout: Duration,
+    last: Mutex<Option<ClusterStatus>>,
+}
+
+// Sends every request to one node, with no fallback to the others.
+#[derive(Debug)]
+struct Pinned(Uuid);
+
+impl LoadBalancingPolicy for Pinned {
+    fn pick<'a>(
+        &'a self,
+        _query: &'a RoutingInfo,
+        cluster: &'a ClusterData,
+    ) -> Option<(NodeRef<'a>, Option<Shard>)> {
+        cluster
+            .get_nodes_info()
+            .iter()
+            .find(|node| node.host_id == self.0)
+            .map(|node| (node, None))
+    }
+
+    fn fallback<'a>(&'a self, _query: &'a RoutingInfo, _cluster: &'a ClusterData) -> FallbackPlan<'a> {
+        Box::new(std::iter::empty())
+    }
+
+    fn name(&self) -> String {
+        String::from("Pinned")
+    }
+}
+
+// What the driver knows of `node`, not yet probed.
+fn described(node: &Node) -> NodeStatus {
+    NodeStatus {
+        host_id: node.host_id,
+        address: SocketAddr::new(node.address.ip(), node.address.port()).to_string(),
+        datacenter: node.datacenter.clone(),
+        rack: node.rack.clone(),
+        connected: node.is_enabled() && !node.is_down(),
+        up: false,
+        latency_ms: None,
+        last_success_at: None,
+        last_error: None,
+        last_error_at: None,
+        consecutive_failures: 0,
+    }
+}
+
+// `node` after a probe at `now` ended with `outcome`, keeping what the
+// `previous` round knew of it.
+fn observed(
+    previous: Option<&NodeStatus>,
+    mut node: NodeStatus,
+    outcome: Result<Duration, String>,
+    now: DateTime<Utc>,
+) -> NodeStatus {
+    if let Some(previous) = previous {
+        node.last_success_at = previous.last_success_at;
+        node.last_error = previous.last_error.clone();
+        node.last_error_at = previous.last_error_at;
+        node.consecutive_failures = previous.consecutive_failures;
+    }
+    let was_up = previous.is_none_or(|previous| previous.up);
+    match outcome {
+        Ok(latency) => {
+            if !was_up {
+                tracing::info!(node = %node.address, "node answering again");
+            }
+            node.up = true;
+            node.latency_ms = Some(latency.as_secs_f64() * 1000.0);
+            node.last_success_at = Some(now);
+            node.consecutive_failures = 0;
+        }
+        Err(error) => {
+            if was_up {
+                tracing::warn!(node = %node.address, %error, "node not answering");
+            }
+            node.up = false;
+            node.last_error = Some(error);
+            node.last_error_at = Some(now);
+            node.consecutive_failures += 1;
+        }
+    }
+    node
+}
+
+impl Monitor {
+    pub fn new(interval: Duration, probe_timeout: Duration) -> Self {
+        Monitor {
+            interval: (!interval.is_zero()).then_some(interval),
+            probe_timeout,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn start(self: &Arc<Self>, state: AppState, tasks: &mut Tasks) {
+        if let Some(interval) = self.interval {
+            let monitor = self.clone();
+            tasks.spawn("cluster monitor", move |stopping| monitor.run(state, interval, stopping));
+        }
+    }
+
+    async fn run(self: Arc<Self>, state: AppState, interval: Duration, mut stopping: Stopping) {
+        loop {
+            self.probe(&state).await;
+            if !stopping.pause(interval).await {
+                break;
+            }
+        }
+    }
+
+    async fn probe_node(&self, state: &AppState, node: &Node) -> Result<Duration, String> {
+        let mut statement = state.statements.readiness_probe.clone();
+        statement.set_execution_profile_handle(Some(
+            ExecutionProfile::builder()
+                .load_balancing_policy(Arc::new(Pinned(node.host_id)))
+                .request_timeout(Some(self.probe_timeout))
+                .build()
+                .into_handle(),
+        ));
+        let started = Instant::now();
+        match timeout(self.probe_timeout, state.session.execute_unpaged(&statement, &[])).await {
+            Ok(Ok(_)) => Ok(started.elapsed()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no response within {:?}", self.probe_timeout)),
+        }
+    }
+
+    // Probes every node and records the outcome as the latest status.
+    async fn probe(&self, state: &AppState) -> ClusterStatus {
+        let cluster = state.session.get_cluster_data();
+        let previous = self.last.lock().unwrap().clone();
+        let mut nodes = Vec::new();
+        for node in cluster.get_nodes_info() {
+            let outcome = self.probe_node(state, node).await;
+            let before = previous
+                .as_ref()
+                .and_then(|status| status.nodes.iter().find(|known| known.host_id == node.host_id));
+            nodes.push(observed(before, described(node), outcome, Utc::now()));
+        }
+        let status = ClusterStatus {
+            checked_at: Utc::now(),
+            breaker: state.breaker.state(),
+            nodes,
+        };
+        *self.last.lock().unwrap() = Some(status.clone());
+        status
+    }
+
+    // The latest status, probing first when nothing probes in the background
+    // or the first round hasn't finished. The breaker's state is always
+    // current.
+    pub async fn status(&self, state: &AppState) -> ClusterStatus {
+        let last = self.last.lock().unwrap().clone();
+        match last {
+            Some(mut status) if self.interval.is_some() => {
+                status.breaker = state.breaker.state();
+                status
+            }
+            _ => self.probe(state).await,
+        }
+    }
+}
+
+/// Every node the driver knows of, as the cluster monitor last probed it.
+/// Probes run every `scylla.monitor_interval_secs`; `checked_at` says when.
+#[utoipa::path(
+    get,
+    path = "/admin/status",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Per-node health and the circuit breaker state", body = ClusterStatus),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+    )
+)]
+pub async fn get_status(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(data.cluster_monitor.status(&data).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> NodeStatus {
+        NodeStatus {
+            host_id: Uuid::from_u128(1),
+            address: String::from("10.0.0.1:9042"),
+            datacenter: Some(String::from("dc1")),
+            rack: None,
+            connected: true,
+            up: false,
+            latency_ms: None,
+            last_success_at: None,
+            last_error: None,
+            last_error_at: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    #[test]
+    fn rounds_keep_the_last_error_and_count_failures() {
+        let now = Utc::now();
+        let first = observed(None, node(), Ok(Duration::from_millis(3)), now);
+        assert!(first.up);
+        assert_eq!(first.latency_ms, Some(3.0));
+
+        let failed = observed(Some(&first), node(), Err(String::from("timed out")), now);
+        let failed = observed(Some(&failed), node(), Err(String::from("refused")), now);
+        assert!(!failed.up);
+        assert_eq!(failed.latency_ms, None);
+        assert_eq!(failed.consecutive_failures, 2);
+        assert_eq!(failed.last_success_at, Some(now));
+
+        let recovered = observed(Some(&failed), node(), Ok(Duration::from_millis(5)), now);
+        assert!(recovered.up);
+        assert_eq!(recovered.consecutive_failures, 0);
+        assert_eq!(recovered.last_error.as_deref(), Some("refused"));
+    }
+}
@@ -0,0 +1,147 @@
+use actix_web::web::Bytes;
+
+// Just enough of RFC 7578 multipart/form-data to take a file field out of a
+// request body that has already been read into memory: parts are split on
+// the boundary and only their Content-Disposition name is kept. Nested
+// multiparts and transfer encodings are not supported.
+
+pub struct Part {
+    pub name: Option<String>,
+    pub data: Bytes,
+}
+
+// The boundary parameter of a `multipart/form-data` Content-Type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// `name` from a Content-Disposition value such as
+// `form-data; name="avatar"; filename="me.png"`.
+fn disposition_name(value: &str) -> Option<String> {
+    value.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        (key.trim() == "name").then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+pub fn parse(body: &Bytes, boundary: &str) -> Result<Vec<Part>, String> {
+    let delimiter = format!("--{}", boundary);
+    let close = format!("\r\n{}", delimiter);
+    let mut pos =
+        find(body, delimiter.as_bytes()).ok_or("missing multipart boundary")? + delimiter.len();
+    let mut parts = Vec::new();
+
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !rest.starts_with(b"\r\n") {
+            return Err(String::from("malformed multipart boundary line"));
+        }
+        pos += 2;
+
+        let headers_len = find(&body[pos..], b"\r\n\r\n").ok_or("unterminated multipart headers")?;
+        let headers = std::str::from_utf8(&body[pos..pos + headers_len])
+            .map_err(|_| "multipart headers are not UTF-8")?;
+        let name = headers.split("\r\n").find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case("content-disposition")
+                .then(|| disposition_name(value))
+                .flatten()
+        });
+        pos += headers_len + 4;
+
+        let data_len = find(&body[pos..], close.as_bytes()).ok_or("unterminated multipart part")?;
+        parts.push(Part {
+            name,
+            data: body.slice(pos..pos + data_len),
+        });
+        pos += data_len + close.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(parts: &[(&str, &str)], boundary: &str) -> Bytes {
+        let mut body = String::from("preamble\r\n");
+        for (name, data) in parts {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"x.png\"\r\n\
+                 Content-Type: image/png\r\n\r\n{}\r\n",
+                boundary, name, data
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", boundary));
+        Bytes::from(body)
+    }
+
+    #[test]
+    fn boundary_comes_from_form_data_content_types_only() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc").as_deref(), Some("abc"));
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; BOUNDARY=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary=\"\""), None);
+        assert_eq!(boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(boundary("application/json"), None);
+    }
+
+    #[test]
+    fn parts_are_split_on_the_boundary() {
+        let parts = parse(&body(&[("title", "hello"), ("avatar", "a\r\nb--c")], "XyZ"), "XyZ").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(&parts[0].data[..], b"hello");
+        assert_eq!(parts[1].name.as_deref(), Some("avatar"));
+        assert_eq!(&parts[1].data[..], b"a\r\nb--c");
+    }
+
+    #[test]
+    fn an_empty_form_has_no_parts() {
+        assert!(parse(&body(&[], "XyZ"), "XyZ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_bodies_are_refused() {
+        let valid = body(&[("avatar", "data")], "XyZ");
+        assert_eq!(parse(&valid, "other").err().as_deref(), Some("missing multipart boundary"));
+
+        let bad_line = Bytes::from_static(b"--XyZ junk\r\n\r\ndata\r\n--XyZ--");
+        assert_eq!(
+            parse(&bad_line, "XyZ").err().as_deref(),
+            Some("malformed multipart boundary line")
+        );
+
+        let no_headers_end = Bytes::from_static(b"--XyZ\r\nContent-Disposition: form-data; name=a");
+        assert_eq!(
+            parse(&no_headers_end, "XyZ").err().as_deref(),
+            Some("unterminated multipart headers")
+        );
+
+        let truncated = valid.slice(..valid.len() - 12);
+        assert_eq!(
+            parse(&truncated, "XyZ").err().as_deref(),
+            Some("unterminated multipart part")
+        );
+    }
+}
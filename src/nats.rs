@@ -0,0 +1,461 @@
+use crate::config::NatsConfig;
+use crate::publisher::{Message, Publisher};
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use futures::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+// A minimal NATS client for publishing to JetStream from the outbox relay:
+// the text protocol over plain TCP, one connection kept open, one publish
+// at a time. Each message is published with headers (HPUB) and a reply
+// subject on the connection's inbox, and counts as sent once the stream
+// acknowledges it there; no reply in time, an error reply, or no stream
+// capturing the subject fails the relay's publish, which tries it again on
+// its next run. The `Nats-Msg-Id` header is the event id, so the stream
+// drops a message it already stored within its duplicate window.
+
+// Longest protocol line read back.
+const MAX_LINE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum NatsError {
+    Io(std::io::Error),
+    Timeout,
+    Protocol(String),
+    // The server or stream refused the message.
+    Rejected(String),
+}
+
+impl fmt::Display for NatsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatsError::Io(e) => write!(f, "NATS connection failed: {}", e),
+            NatsError::Timeout => write!(f, "NATS did not acknowledge in time"),
+            NatsError::Protocol(detail) => write!(f, "unexpected NATS reply: {}", detail),
+            NatsError::Rejected(reason) => write!(f, "NATS refused the message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for NatsError {}
+
+impl From<std::io::Error> for NatsError {
+    fn from(e: std::io::Error) -> Self {
+        NatsError::Io(e)
+    }
+}
+
+// What the server's INFO says that matters here.
+#[derive(Deserialize)]
+struct Info {
+    #[serde(default)]
+    headers: bool,
+    #[serde(default)]
+    tls_required: bool,
+    #[serde(default)]
+    max_payload: usize,
+}
+
+#[derive(Serialize)]
+struct Connect<'a> {
+    verbose: bool,
+    pedantic: bool,
+    headers: bool,
+    no_responders: bool,
+    protocol: u8,
+    lang: &'static str,
+    version: &'static str,
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_token: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pass: Option<&'a str>,
+}
+
+// JetStream's answer to a publish.
+#[derive(Deserialize)]
+struct Ack {
+    #[serde(default)]
+    error: Option<AckError>,
+}
+
+#[derive(Deserialize)]
+struct AckError {
+    #[serde(default)]
+    code: u16,
+    #[serde(default)]
+    description: String,
+}
+
+// The subject `message` is published on.
+fn subject(prefix: &str, message: &Message) -> String {
+    format!("{}.{}.{}", prefix, message.kind, message.user_id)
+}
+
+// The header block of `message`, as HPUB sends it.
+fn header_block(message: &Message) -> String {
+    let mut block = format!("NATS/1.0\r\nNats-Msg-Id: {}\r\n", message.id);
+    for (name, value) in message.headers() {
+        block.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    block.push_str("\r\n");
+    block
+}
+
+// Whether a JetStream reply acknowledges the message.
+fn acknowledged(payload: &[u8]) -> Result<(), NatsError> {
+    let ack: Ack = serde_json::from_slice(payload)
+        .map_err(|_| NatsError::Protocol(String::from("the acknowledgement is not JSON")))?;
+    match ack.error {
+        Some(error) => Err(NatsError::Rejected(format!("{} {}", error.code, error.description))),
+        None => Ok(()),
+    }
+}
+
+type Stream = BufStream<TcpStream>;
+
+async fn read_line(stream: &mut Stream) -> Result<String, NatsError> {
+    let mut line = String::new();
+    let read = (&mut *stream).take(MAX_LINE as u64).read_line(&mut line).await?;
+    if read == 0 {
+        return Err(NatsError::Protocol(String::from("connection closed")));
+    }
+    if !line.ends_with("\r\n") {
+        return Err(NatsError::Protocol(String::from("line too long")));
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+// An open connection and the inbox acknowledgements come back to.
+struct Connection {
+    stream: Stream,
+    inbox: String,
+    max_payload: usize,
+    next_reply: u64,
+}
+
+pub struct Nats {
+    servers: Vec<String>,
+    subject_prefix: String,
+    client_name: String,
+    token: Option<String>,
+    credentials: Option<(String, String)>,
+    timeout: Duration,
+    // Dropped after any failure, and opened again on the next publish.
+    connection: Mutex<Option<Connection>>,
+}
+
+impl Nats {
+    pub fn new(config: &NatsConfig) -> Self {
+        Nats {
+            servers: config.servers.clone(),
+            subject_prefix: config.subject_prefix.clone(),
+            client_name: config.client_name.clone(),
+            token: config.token.clone(),
+            credentials: config.username.clone().zip(config.password.clone()),
+            timeout: Duration::from_millis(config.timeout_ms),
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn connect_to(&self, server: &str) -> Result<Connection, NatsError> {
+        let mut stream = BufStream::new(TcpStream::connect(server).await?);
+        let line = read_line(&mut stream).await?;
+        let info: Info = line
+            .strip_prefix("INFO ")
+            .and_then(|info| serde_json::from_str(info).ok())
+            .ok_or_else(|| NatsError::Protocol(format!("expected INFO, got {:?}", line)))?;
+        if info.tls_required {
+            return Err(NatsError::Protocol(String::from("the server requires TLS")));
+        }
+        if !info.headers {
+            return Err(NatsError::Protocol(String::from("the server does not take headers")));
+        }
+        let connect = serde_json::to_string(&Connect {
+            verbose: false,
+            pedantic: false,
+            headers: true,
+            no_responders: true,
+            protocol: 1,
+            lang: "rust",
+            version: env!("CARGO_PKG_VERSION"),
+            name: &self.client_name,
+            auth_token: self.token.as_deref(),
+            user: self.credentials.as_ref().map(|(user, _)| user.as_str()),
+            pass: self.credentials.as_ref().map(|(_, pass)| pass.as_str()),
+        })
+        .map_err(|e| NatsError::Protocol(e.to_string()))?;
+        let inbox = format!("_INBOX.{}", Uuid::new_v4().simple());
+        stream
+            .write_all(format!("CONNECT {}\r\nSUB {}.* 1\r\nPING\r\n", connect, inbox).as_bytes())
+            .await?;
+        stream.flush().await?;
+        // The PONG answering our PING means CONNECT and SUB went through.
+        loop {
+            let line = read_line(&mut stream).await?;
+            match line.as_str() {
+                "PONG" => break,
+                "PING" => {
+                    stream.write_all(b"PONG\r\n").await?;
+                    stream.flush().await?;
+                }
+                "+OK" => {}
+                _ if line.starts_with("-ERR") => {
+                    return Err(NatsError::Rejected(line[4..].trim().to_string()));
+                }
+                _ if line.starts_with("INFO ") => {}
+                _ => return Err(NatsError::Protocol(format!("{:?}", line))),
+            }
+        }
+        Ok(Connection {
+            stream,
+            inbox,
+            max_payload: info.max_payload,
+            next_reply: 0,
+        })
+    }
+
+    async fn connect(&self) -> Result<Connection, NatsError> {
+        let mut last_error = NatsError::Protocol(String::from("no servers configured"));
+        for server in &self.servers {
+            match self.connect_to(server).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn publish_on(
+        &self,
+        connection: &mut Connection,
+        message: &Message,
+    ) -> Result<(), NatsError> {
+        let headers = header_block(message);
+        let total = headers.len() + message.value.len();
+        if connection.max_payload > 0 && total > connection.max_payload {
+            return Err(NatsError::Rejected(format!("{} bytes is over max_payload", total)));
+        }
+        connection.next_reply += 1;
+        let reply = format!("{}.{}", connection.inbox, connection.next_reply);
+        let stream = &mut connection.stream;
+        let command = format!(
+            "HPUB {} {} {} {}\r\n",
+            subject(&self.subject_prefix, message),
+            reply,
+            headers.len(),
+            total
+        );
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(headers.as_bytes()).await?;
+        stream.write_all(&message.value).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+
+        loop {
+            let line = read_line(stream).await?;
+            let mut fields = line.split(' ');
+            match fields.next() {
+                Some("PING") => {
+                    stream.write_all(b"PONG\r\n").await?;
+                    stream.flush().await?;
+                }
+                Some("+OK") | Some("PONG") | Some("INFO") => {}
+                Some("-ERR") => return Err(NatsError::Rejected(line[4..].trim().to_string())),
+                Some(op @ ("MSG" | "HMSG")) => {
+                    // MSG <subject> <sid> <size>, HMSG <subject> <sid> <header size> <size>.
+                    let fields: Vec<&str> = fields.collect();
+                    let sizes: Option<Vec<usize>> = fields
+                        .iter()
+                        .skip(2)
+                        .map(|size| size.parse().ok())
+                        .collect();
+                    let (header_size, size) = match (op, sizes.as_deref()) {
+                        ("MSG", Some([size])) => (0, *size),
+                        ("HMSG", Some([header_size, size])) => (*header_size, *size),
+                        _ => return Err(NatsError::Protocol(format!("{:?}", line))),
+                    };
+                    if header_size > size || size > MAX_LINE {
+                        return Err(NatsError::Protocol(format!("{:?}", line)));
+                    }
+                    let mut body = vec![0u8; size + 2];
+                    stream.read_exact(&mut body).await?;
+                    // An answer to a publish that timed out earlier.
+                    if fields[0] != reply {
+                        continue;
+                    }
+                    let (headers, payload) = body[..size].split_at(header_size);
+                    // A status in the headers: 503 is no stream for the subject.
+                    let status = std::str::from_utf8(headers)
+                        .ok()
+                        .and_then(|headers| headers.lines().next())
+                        .and_then(|line| line.strip_prefix("NATS/1.0"))
+                        .map(str::trim)
+                        .unwrap_or_default();
+                    if !status.is_empty() {
+                        return Err(NatsError::Rejected(format!("status {}", status)));
+                    }
+                    return acknowledged(payload);
+                }
+                _ => return Err(NatsError::Protocol(format!("{:?}", line))),
+            }
+        }
+    }
+
+    async fn publish_unbounded(&self, message: &Message) -> Result<(), NatsError> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        let result = self
+            .publish_on(connection.as_mut().expect("just connected"), message)
+            .await;
+        if matches!(result, Err(NatsError::Io(_) | NatsError::Protocol(_))) {
+            *connection = None;
+        }
+        result
+    }
+
+    // Publishes `message`, waiting for the stream to acknowledge it.
+    async fn publish(&self, message: &Message) -> Result<(), NatsError> {
+        match timeout(self.timeout, self.publish_unbounded(message)).await {
+            Ok(result) => result,
+            Err(_) => {
+                // The connection may be mid-message; start afresh.
+                *self.connection.lock().await = None;
+                Err(NatsError::Timeout)
+            }
+        }
+    }
+}
+
+impl Publisher for Nats {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), String>> {
+        async move { self.publish(message).await.map_err(|e| e.to_string()) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::outbox::OutboxEvent;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    fn message() -> Message {
+        let event = OutboxEvent::new(EventKind::Updated, Uuid::new_v4(), None, Some(2));
+        Message::new(&event).unwrap()
+    }
+
+    #[test]
+    fn messages_are_published_by_kind_and_user() {
+        let message = message();
+        assert_eq!(
+            subject("users.events", &message),
+            format!("users.events.updated.{}", message.user_id)
+        );
+        let headers = header_block(&message);
+        assert!(headers.starts_with("NATS/1.0\r\n"));
+        assert!(headers.contains(&format!("Nats-Msg-Id: {}\r\n", message.id)));
+        assert!(headers.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn error_replies_are_not_acknowledgements() {
+        assert!(acknowledged(br#"{"stream":"USERS","seq":7}"#).is_ok());
+        assert!(acknowledged(br#"{"stream":"USERS","seq":7,"duplicate":true}"#).is_ok());
+        let refused = acknowledged(br#"{"error":{"code":503,"description":"no quorum"}}"#);
+        assert_eq!(refused.unwrap_err().to_string(), "NATS refused the message: 503 no quorum");
+    }
+
+    // A server that acknowledges one publish after a PING, or answers it with
+    // `status` in the headers.
+    fn server(status: Option<&'static str>) -> (u16, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"INFO {\"headers\":true,\"max_payload\":1048576}\r\n").unwrap();
+            let mut received = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                received.push(line.clone());
+                if line == "PING" {
+                    writer.write_all(b"PONG\r\n").unwrap();
+                } else if line.starts_with("HPUB") {
+                    let fields: Vec<&str> = line.split(' ').collect();
+                    let mut body = vec![0u8; fields[4].parse::<usize>().unwrap() + 2];
+                    reader.read_exact(&mut body).unwrap();
+                    received.push(String::from_utf8(body).unwrap());
+                    writer.write_all(b"PING\r\n").unwrap();
+                    // A late answer to some other publish comes first.
+                    writer.write_all(b"MSG _INBOX.other.1 1 2\r\n{}\r\n").unwrap();
+                    let answer = match status {
+                        Some(status) => {
+                            let headers = format!("NATS/1.0 {}\r\n\r\n", status);
+                            let size = headers.len();
+                            format!("HMSG {} 1 {} {}\r\n{}\r\n", fields[2], size, size, headers)
+                        }
+                        None => {
+                            let ack = r#"{"stream":"USERS","seq":1}"#;
+                            format!("MSG {} 1 {}\r\n{}\r\n", fields[2], ack.len(), ack)
+                        }
+                    };
+                    writer.write_all(answer.as_bytes()).unwrap();
+                } else if line == "PONG" {
+                    break;
+                }
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    fn nats(port: u16) -> Nats {
+        Nats::new(&NatsConfig {
+            enabled: true,
+            servers: vec![format!("127.0.0.1:{}", port)],
+            token: Some(String::from("s3cret")),
+            ..NatsConfig::default()
+        })
+    }
+
+    #[actix_web::test]
+    async fn publishes_wait_for_the_stream() {
+        let (port, server) = server(None);
+        let message = message();
+        nats(port).send(&message).await.unwrap();
+        let received = server.join().unwrap();
+        assert!(received[0].starts_with("CONNECT {"));
+        assert!(received[0].contains(r#""auth_token":"s3cret""#));
+        assert!(received[0].contains(r#""headers":true"#));
+        assert!(received[1].starts_with("SUB _INBOX."));
+        let publish = received.iter().position(|line| line.starts_with("HPUB")).unwrap();
+        let subject = format!("users.events.updated.{}", message.user_id);
+        assert_eq!(received[publish].split(' ').nth(1), Some(subject.as_str()));
+        assert!(received[publish + 1].contains(r#""type":"user.updated""#));
+    }
+
+    #[actix_web::test]
+    async fn no_stream_for_the_subject_fails_the_publish() {
+        let (port, server) = server(Some("503"));
+        let error = nats(port).send(&message()).await.unwrap_err();
+        assert_eq!(error, "NATS refused the message: status 503");
+        server.join().unwrap();
+    }
+}
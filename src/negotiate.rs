@@ -0,0 +1,237 @@
+use crate::error::ApiError;
+use actix_web::dev::Payload;
+use actix_web::http::header::{Accept, Header};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError};
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+const MSGPACK_TYPES: [&str; 2] = ["application/msgpack", "application/x-msgpack"];
+
+// One JSON value per line, for responses streamed as they are read.
+pub const NDJSON_TYPE: &str = "application/x-ndjson";
+
+// Whether the client ranks one of `types` above JSON (or any wildcard) in its
+// Accept header; JSON stays the default.
+fn prefers(req: &HttpRequest, types: &[&str]) -> bool {
+    let Ok(accept) = Accept::parse(req) else {
+        return false;
+    };
+
+    accept
+        .ranked()
+        .iter()
+        .find_map(|mime| match mime.essence_str() {
+            essence if types.contains(&essence) => Some(true),
+            "application/json" | "application/*" | "*/*" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+fn wants_msgpack(req: &HttpRequest) -> bool {
+    prefers(req, &MSGPACK_TYPES)
+}
+
+pub fn wants_ndjson(req: &HttpRequest) -> bool {
+    prefers(req, &[NDJSON_TYPE])
+}
+
+// Serializes `body` as MessagePack or JSON depending on the request's Accept header.
+pub fn respond<T: Serialize>(
+    req: &HttpRequest,
+    mut builder: HttpResponseBuilder,
+    body: &T,
+) -> HttpResponse {
+    if !wants_msgpack(req) {
+        return builder.json(body);
+    }
+
+    match rmp_serde::to_vec_named(body) {
+        Ok(bytes) => builder.content_type(MSGPACK_TYPES[0]).body(bytes),
+        Err(e) => ApiError::internal("Failed to encode MessagePack response", e).error_response(),
+    }
+}
+
+// Bodies over `limit` bytes are refused with 413, and bodies without a JSON
+// Content-Type (`application/json` or any `+json` type) with 415, as problem
+// details like every other error. Registered for the whole app, so it covers
+// `web::Json` and `Body` alike; `web::PayloadConfig` gives MessagePack bodies
+// the same limit.
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .content_type_required(true)
+        .error_handler(|e, _| json_error(e).into())
+}
+
+fn json_error(e: JsonPayloadError) -> ApiError {
+    match e {
+        JsonPayloadError::OverflowKnownLength { length, limit } => ApiError::PayloadTooLarge(
+            format!("The body is {} bytes, over the limit of {} bytes", length, limit),
+        ),
+        JsonPayloadError::Overflow { limit } => {
+            ApiError::PayloadTooLarge(format!("The body is over the limit of {} bytes", limit))
+        }
+        JsonPayloadError::ContentType => ApiError::UnsupportedMediaType(String::from(
+            "Expected a body of type application/json or application/msgpack",
+        )),
+        e => ApiError::BadRequest(format!("Invalid JSON body: {}", e)),
+    }
+}
+
+// Request body decoded according to its Content-Type: MessagePack for
+// `application/msgpack`, JSON (with the checks of `json_config`) otherwise.
+pub struct Body<T>(pub T);
+
+impl<T: DeserializeOwned + 'static> FromRequest for Body<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if MSGPACK_TYPES.contains(&req.content_type()) {
+            let bytes = web::Bytes::from_request(req, payload);
+            Box::pin(async move {
+                let bytes = bytes.await.map_err(|e| {
+                    if e.as_response_error().status_code() == StatusCode::PAYLOAD_TOO_LARGE {
+                        ApiError::PayloadTooLarge(format!("The body is too large: {}", e)).into()
+                    } else {
+                        e
+                    }
+                })?;
+                rmp_serde::from_slice(&bytes)
+                    .map(Body)
+                    .map_err(|e| ApiError::BadRequest(format!("Invalid MessagePack body: {}", e)).into())
+            })
+        } else {
+            let json = web::Json::<T>::from_request(req, payload);
+            Box::pin(async move { json.await.map(|json| Body(json.into_inner())) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use uuid::Uuid;
+
+    fn accepting(accept: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((header::ACCEPT, accept))
+            .to_http_request()
+    }
+
+    #[test]
+    fn msgpack_only_when_ranked_above_json() {
+        assert!(!wants_msgpack(&TestRequest::default().to_http_request()));
+        assert!(wants_msgpack(&accepting("application/msgpack")));
+        assert!(wants_msgpack(&accepting("application/x-msgpack, application/json;q=0.5")));
+        assert!(!wants_msgpack(&accepting("application/json, application/msgpack")));
+        assert!(!wants_msgpack(&accepting("*/*")));
+        assert!(!wants_msgpack(&accepting("text/html")));
+    }
+
+    #[test]
+    fn ndjson_only_when_ranked_above_json() {
+        assert!(wants_ndjson(&accepting("application/x-ndjson")));
+        assert!(wants_ndjson(&accepting("application/json;q=0.5, application/x-ndjson")));
+        assert!(!wants_ndjson(&accepting("application/json, application/x-ndjson")));
+        assert!(!wants_ndjson(&accepting("application/msgpack")));
+    }
+
+    // Echoes the user it is sent, negotiated both ways.
+    async fn echo(req: HttpRequest, Body(user): Body<User>) -> HttpResponse {
+        respond(&req, HttpResponse::Ok(), &user)
+    }
+
+    #[actix_web::test]
+    async fn users_round_trip_through_msgpack() {
+        let app = init_service(App::new().route("/echo", web::post().to(echo))).await;
+        let user = User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        };
+        let req = TestRequest::post()
+            .uri("/echo")
+            .insert_header((header::CONTENT_TYPE, "application/msgpack"))
+            .insert_header((header::ACCEPT, "application/msgpack"))
+            .set_payload(rmp_serde::to_vec_named(&user).unwrap())
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/msgpack");
+        let echoed: User = rmp_serde::from_slice(&read_body(res).await).unwrap();
+        assert_eq!((echoed.id, echoed.name, echoed.email), (user.id, user.name, user.email));
+    }
+
+    #[actix_web::test]
+    async fn malformed_bodies_are_bad_requests() {
+        let app = init_service(
+            App::new()
+                .app_data(json_config(1024))
+                .route("/echo", web::post().to(echo)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/echo")
+            .insert_header((header::CONTENT_TYPE, "application/msgpack"))
+            .set_payload(vec![0xc1])
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let req = TestRequest::post()
+            .uri("/echo")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload("{\"id\":")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+    }
+
+    #[actix_web::test]
+    async fn oversized_and_untyped_bodies_are_refused() {
+        let app = init_service(
+            App::new()
+                .app_data(json_config(64))
+                .app_data(web::PayloadConfig::new(64))
+                .route("/echo", web::post().to(echo)),
+        )
+        .await;
+        let oversized = format!("{{\"name\":\"{}\"}}", "x".repeat(100));
+        for content_type in ["application/json", "application/msgpack"] {
+            let req = TestRequest::post()
+                .uri("/echo")
+                .insert_header((header::CONTENT_TYPE, content_type))
+                .set_payload(oversized.clone())
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+        }
+
+        for content_type in [Some("text/plain"), None] {
+            let mut req = TestRequest::post().uri("/echo").set_payload("{}");
+            if let Some(content_type) = content_type {
+                req = req.insert_header((header::CONTENT_TYPE, content_type));
+            }
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::error::ApiError;
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest};
+use serde::Deserialize;
+use serde_json::Value;
+use utoipa::IntoParams;
+
+// Clients disagree on whether an unset optional field, such as a user's
+// `phone`, should be sent as `null` or left out. Every read takes
+// `?null_fields=omit|include`: `include` (the default) sends them as `null`,
+// as the models serialize, and `omit` drops every `null` member from the
+// JSON or MessagePack body, at any depth, after serialization. Streamed
+// NDJSON listings apply it line by line (see `handlers`); other streamed
+// bodies are left as they are.
+
+/// Whether unset optional fields are sent as `null` or left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum NullFields {
+    #[default]
+    Include,
+    Omit,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct NullFieldsQuery {
+    /// `include` (default) sends unset optional fields as `null`; `omit`
+    /// leaves them out.
+    #[serde(default)]
+    pub null_fields: Option<NullFields>,
+}
+
+const MSGPACK_TYPE: &str = "application/msgpack";
+
+// The mode the request asks for.
+pub fn requested(req: &HttpRequest) -> Result<NullFields, ApiError> {
+    web::Query::<NullFieldsQuery>::from_query(req.query_string())
+        .map(|query| query.null_fields.unwrap_or_default())
+        .map_err(|_| ApiError::BadRequest(String::from("null_fields must be omit or include")))
+}
+
+// Drops the `null` members of every object in `value`. Nulls in arrays stay,
+// as leaving them out would shift the other items.
+pub fn omit(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, member| !member.is_null());
+            object.values_mut().for_each(omit);
+        }
+        Value::Array(items) => items.iter_mut().for_each(omit),
+        _ => {}
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+// `body` without its `null` members, or `None` when it isn't JSON or
+// MessagePack.
+fn rewrite(content_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+    if content_type.starts_with(MSGPACK_TYPE) {
+        let mut value: Value = rmp_serde::from_slice(body).ok()?;
+        omit(&mut value);
+        return rmp_serde::to_vec_named(&value).ok();
+    }
+    if content_type.starts_with("application/json") || content_type.contains("+json") {
+        let mut value: Value = serde_json::from_slice(body).ok()?;
+        omit(&mut value);
+        return serde_json::to_vec(&value).ok();
+    }
+    None
+}
+
+// Applies `?null_fields` to the body of a successful read.
+pub async fn apply(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+    if requested(req.request())? == NullFields::Include {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+    let res = next.call(req).await?;
+    let sized = matches!(res.response().body().size(), BodySize::Sized(_));
+    if !res.status().is_success() || !sized {
+        return Ok(res.map_into_boxed_body());
+    }
+    let (http_req, response) = res.into_parts();
+    let (mut response, original) = response.into_parts();
+    let bytes = match body::to_bytes(original).await {
+        Ok(bytes) => bytes,
+        Err(_) => return Ok(ServiceResponse::new(http_req, response.set_body(BoxBody::new(())))),
+    };
+    let new_body = match rewrite(content_type(response.headers()), &bytes) {
+        Some(rewritten) => {
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+            BoxBody::new(rewritten)
+        }
+        None => BoxBody::new(bytes),
+    };
+    Ok(ServiceResponse::new(http_req, response.set_body(new_body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{init_service, read_body, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use uuid::Uuid;
+
+    fn user() -> User {
+        User {
+            id: Uuid::nil(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    async fn get(uri: &str) -> Result<Value, actix_web::Error> {
+        let app = init_service(
+            App::new()
+                .route("/users/1", web::get().to(|| async { HttpResponse::Ok().json(user()) }))
+                .wrap(from_fn(apply)),
+        )
+        .await;
+        let res = try_call_service(&app, TestRequest::get().uri(uri).to_request()).await?;
+        Ok(serde_json::from_slice(&read_body(res).await).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn a_null_phone_is_sent_by_default() {
+        let body = get("/users/1").await.unwrap();
+        assert_eq!(body.get("phone"), Some(&Value::Null));
+        let body = get("/users/1?null_fields=include").await.unwrap();
+        assert_eq!(body.get("phone"), Some(&Value::Null));
+    }
+
+    #[actix_web::test]
+    async fn a_null_phone_is_left_out_with_omit() {
+        let body = get("/users/1?null_fields=omit").await.unwrap();
+        assert_eq!(body.get("phone"), None);
+        assert_eq!(body["email"], "ada@example.com");
+    }
+
+    #[actix_web::test]
+    async fn other_modes_are_refused() {
+        let error = get("/users/1?null_fields=drop").await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), 400);
+    }
+
+    #[test]
+    fn omit_reaches_nested_objects_but_not_array_items() {
+        let mut value =
+            serde_json::json!({"a": null, "b": {"c": null, "d": 1}, "e": [null, {"f": null}]});
+        omit(&mut value);
+        assert_eq!(value, serde_json::json!({"b": {"d": 1}, "e": [null, {}]}));
+    }
+}
@@ -0,0 +1,590 @@
+use crate::api_keys::{hash_secret, new_secret};
+use crate::auth::{AuthError, JwtAuth};
+use crate::config::OauthConfig;
+use crate::emails;
+use crate::error::{ApiError, Problem};
+use crate::http_client::{self, Response};
+use crate::login::LoginResponse;
+use crate::models::{NewUser, User};
+use crate::observe;
+use crate::sessions;
+use crate::state::AppState;
+use crate::statements;
+use crate::stats;
+use crate::status;
+use crate::users;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+// Sign-in with Google or GitHub through the OAuth 2.0 authorization-code
+// flow with PKCE. GET /oauth/{provider}/authorize stores a random `state` in
+// `oauth_states`, by its SHA-256, with the PKCE verifier, and redirects the
+// user to the provider; the provider sends the user back to
+// GET /oauth/{provider}/callback with a code, which is redeemed for the
+// provider's access token and, with that, the account's id, email and name.
+// The state is consumed with a lightweight transaction, so a callback is
+// accepted once.
+//
+// The account signs in as the user it was linked to in `oauth_identities`.
+// On its first sign-in it is linked to the user registered with its email,
+// but only when the provider has verified the email, or else to a new user
+// registered without a password. Either way the answer is the same as
+// POST /login's, a session of the service's own.
+
+// How long a user has to come back from the provider.
+const STATE_TTL_SECS: i32 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Provider {
+    Google,
+    Github,
+}
+
+impl Provider {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "google" => Some(Provider::Google),
+            "github" => Some(Provider::Github),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Provider::Google => "google",
+            Provider::Github => "github",
+        }
+    }
+
+    fn authorize_url(self) -> &'static str {
+        match self {
+            Provider::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            Provider::Github => "https://github.com/login/oauth/authorize",
+        }
+    }
+
+    fn token_url(self) -> &'static str {
+        match self {
+            Provider::Google => "https://oauth2.googleapis.com/token",
+            Provider::Github => "https://github.com/login/oauth/access_token",
+        }
+    }
+
+    fn scope(self) -> &'static str {
+        match self {
+            Provider::Google => "openid email profile",
+            Provider::Github => "read:user user:email",
+        }
+    }
+}
+
+struct Client {
+    provider: Provider,
+    client_id: String,
+    client_secret: String,
+}
+
+// The configured providers.
+pub struct Providers {
+    clients: Vec<Client>,
+    callback_base_url: String,
+    timeout: Duration,
+}
+
+impl Providers {
+    pub fn new(config: &OauthConfig) -> Self {
+        let configured = [
+            (Provider::Google, &config.google_client_id, &config.google_client_secret),
+            (Provider::Github, &config.github_client_id, &config.github_client_secret),
+        ];
+        let clients = configured
+            .into_iter()
+            .filter_map(|(provider, id, secret)| {
+                Some(Client {
+                    provider,
+                    client_id: id.clone()?,
+                    client_secret: secret.clone()?,
+                })
+            })
+            .collect();
+        Providers {
+            clients,
+            callback_base_url: config
+                .callback_base_url
+                .as_deref()
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            timeout: Duration::from_millis(config.request_timeout_ms),
+        }
+    }
+
+    fn client(&self, name: &str) -> Result<&Client, ApiError> {
+        Provider::parse(name)
+            .and_then(|provider| self.clients.iter().find(|client| client.provider == provider))
+            .ok_or_else(|| ApiError::NotFound(format!("No sign-in provider named {}", name)))
+    }
+
+    fn redirect_uri(&self, provider: Provider) -> String {
+        format!("{}/oauth/{}/callback", self.callback_base_url, provider.name())
+    }
+}
+
+// A provider account, as the provider describes it.
+#[derive(Debug)]
+struct Identity {
+    subject: String,
+    email: String,
+    email_verified: bool,
+    name: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Set by the provider instead of `code` when the user refused.
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GoogleUser {
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn authorize_url(providers: &Providers, client: &Client, state: &str, verifier: &str) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &client.client_id)
+        .append_pair("redirect_uri", &providers.redirect_uri(client.provider))
+        .append_pair("scope", client.provider.scope())
+        .append_pair("state", state)
+        .append_pair("code_challenge", &code_challenge(verifier))
+        .append_pair("code_challenge_method", "S256")
+        .finish();
+    format!("{}?{}", client.provider.authorize_url(), query)
+}
+
+// The parsed body of a provider's successful response.
+fn json<T: DeserializeOwned>(url: &str, response: Response) -> Result<T, AuthError> {
+    if !response.is_success() {
+        return Err(AuthError::ProviderFailed(format!("{} answered {}", url, response.status)));
+    }
+    serde_json::from_slice(&response.body)
+        .map_err(|e| AuthError::ProviderFailed(format!("unreadable answer from {}: {}", url, e)))
+}
+
+async fn call<T: DeserializeOwned>(
+    providers: &Providers,
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<(&str, &[u8])>,
+) -> Result<T, AuthError> {
+    let response = http_client::send(method, url, headers, body, providers.timeout)
+        .await
+        .map_err(|e| AuthError::ProviderFailed(format!("{}: {}", url, e)))?;
+    json(url, response)
+}
+
+// Redeems the code for the provider's access token.
+async fn redeem(
+    providers: &Providers,
+    client: &Client,
+    code: &str,
+    verifier: &str,
+) -> Result<String, AuthError> {
+    let form = form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "authorization_code")
+        .append_pair("code", code)
+        .append_pair("redirect_uri", &providers.redirect_uri(client.provider))
+        .append_pair("client_id", &client.client_id)
+        .append_pair("client_secret", &client.client_secret)
+        .append_pair("code_verifier", verifier)
+        .finish();
+    let token: TokenResponse = call(
+        providers,
+        "POST",
+        client.provider.token_url(),
+        &[("Accept", "application/json")],
+        Some(("application/x-www-form-urlencoded", form.as_bytes())),
+    )
+    .await?;
+    // GitHub reports a bad code with 200 and an `error`.
+    match (token.access_token, token.error) {
+        (Some(access_token), None) => Ok(access_token),
+        (_, error) => Err(AuthError::SignInFailed(format!(
+            "the provider refused the code: {}",
+            error.as_deref().unwrap_or("no access token")
+        ))),
+    }
+}
+
+async fn google_identity(providers: &Providers, bearer: &str) -> Result<Identity, AuthError> {
+    let user: GoogleUser = call(
+        providers,
+        "GET",
+        "https://openidconnect.googleapis.com/v1/userinfo",
+        &[("Authorization", bearer), ("Accept", "application/json")],
+        None,
+    )
+    .await?;
+    let email = user
+        .email
+        .ok_or_else(|| AuthError::SignInFailed(String::from("the account has no email")))?;
+    Ok(Identity {
+        name: user.name.unwrap_or_else(|| email.clone()),
+        subject: user.sub,
+        email,
+        email_verified: user.email_verified,
+    })
+}
+
+async fn github_identity(providers: &Providers, bearer: &str) -> Result<Identity, AuthError> {
+    // GitHub's API refuses requests without a User-Agent.
+    let headers = [
+        ("Authorization", bearer),
+        ("Accept", "application/vnd.github+json"),
+        ("User-Agent", "hireme-user-api"),
+    ];
+    let user: GithubUser =
+        call(providers, "GET", "https://api.github.com/user", &headers, None).await?;
+    let emails: Vec<GithubEmail> =
+        call(providers, "GET", "https://api.github.com/user/emails", &headers, None).await?;
+    let primary = emails
+        .into_iter()
+        .find(|email| email.primary)
+        .ok_or_else(|| AuthError::SignInFailed(String::from("the account has no email")))?;
+    Ok(Identity {
+        subject: user.id.to_string(),
+        email: primary.email,
+        email_verified: primary.verified,
+        name: user.name.unwrap_or(user.login),
+    })
+}
+
+// The provider, and the PKCE verifier, of a sign-in this service started.
+async fn lookup_state(
+    state: &AppState,
+    state_hash: &str,
+) -> Result<Option<(String, String)>, ApiError> {
+    let result = observe::query(state, "select_oauth_state", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_oauth_state, (state_hash,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading sign-in state", e))?
+        .maybe_first_row::<(String, String)>()
+        .map_err(|e| ApiError::internal("Error reading sign-in state", e))
+}
+
+// Deletes the state, returning whether this call was the one that did.
+async fn consume_state(state: &AppState, state_hash: &str) -> Result<bool, ApiError> {
+    let result = observe::conditional(state, "consume_oauth_state", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.consume_oauth_state, (state_hash,))
+    })
+    .await?;
+    statements::applied(result).map_err(|e| ApiError::internal("Failed to consume sign-in state", e))
+}
+
+async fn linked_user(
+    state: &AppState,
+    provider: Provider,
+    subject: &str,
+) -> Result<Option<Uuid>, ApiError> {
+    let result = observe::query(state, "select_oauth_identity", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_oauth_identity, (provider.name(), subject))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading linked accounts", e))?
+        .maybe_first_row::<(Uuid,)>()
+        .map_err(|e| ApiError::internal("Error reading linked accounts", e))?;
+    Ok(row.map(|(user_id,)| user_id))
+}
+
+async fn link(
+    state: &AppState,
+    provider: Provider,
+    identity: &Identity,
+    user: &User,
+) -> Result<(), ApiError> {
+    let values = (
+        provider.name(),
+        &identity.subject,
+        user.id,
+        &identity.email,
+        Utc::now(),
+        users::ttl(user.expires_at),
+    );
+    observe::query(state, "insert_oauth_identity", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.insert_oauth_identity, &values)
+    })
+    .await?;
+    Ok(())
+}
+
+async fn unlink(state: &AppState, provider: Provider, subject: &str) -> Result<(), ApiError> {
+    observe::query(state, "delete_oauth_identity", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.delete_oauth_identity, (provider.name(), subject))
+    })
+    .await?;
+    Ok(())
+}
+
+// The user the account signs in as, linking or registering one on its first
+// sign-in.
+async fn user_for(
+    state: &AppState,
+    provider: Provider,
+    identity: &Identity,
+) -> Result<Uuid, ApiError> {
+    if let Some(user_id) = linked_user(state, provider, &identity.subject).await? {
+        if let Some((_, false)) = users::stored_row(state, user_id).await? {
+            return Ok(user_id);
+        }
+        // The user was deleted; the account starts over.
+        unlink(state, provider, &identity.subject).await?;
+    }
+
+    let user = match emails::owner(state, &identity.email).await? {
+        Some(owner) => {
+            let taken = || {
+                ApiError::Conflict(format!(
+                    "Email {} is registered to another user; verify it with {} to sign in with it",
+                    identity.email,
+                    provider.name()
+                ))
+            };
+            if !identity.email_verified {
+                return Err(taken());
+            }
+            match users::stored_row(state, owner).await? {
+                Some((user, false)) => user,
+                _ => return Err(taken()),
+            }
+        }
+        None => {
+            let new_user = NewUser {
+                name: identity.name.clone(),
+                email: identity.email.clone(),
+                password: None,
+                phone: None,
+                profile: None,
+                expires_in_seconds: None,
+            };
+            users::register(state, new_user, false).await?.0
+        }
+    };
+    link(state, provider, identity, &user).await?;
+    tracing::info!(user_id = %user.id, provider = provider.name(), "sign-in account linked");
+    Ok(user.id)
+}
+
+/// Starts signing in with a provider (`google` or `github`): redirects to
+/// the provider, which sends the user back to /oauth/{provider}/callback.
+#[utoipa::path(
+    get,
+    path = "/oauth/{provider}/authorize",
+    params(("provider" = String, Path, description = "`google` or `github`")),
+    responses(
+        (status = 302, description = "Redirect to the provider"),
+        (status = 404, description = "The provider is not configured", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn authorize(
+    provider: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let client = data.oauth.client(&provider)?;
+    let state = new_secret();
+    let verifier = new_secret();
+    let values = (
+        hash_secret(&state),
+        client.provider.name(),
+        &verifier,
+        Utc::now(),
+        STATE_TTL_SECS,
+    );
+    observe::query(&data, "insert_oauth_state", || {
+        data.session
+            .execute_unpaged(&data.statements.insert_oauth_state, &values)
+    })
+    .await?;
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, authorize_url(&data.oauth, client, &state, &verifier)))
+        .finish())
+}
+
+/// Where the provider sends the user back. Signs the user in as POST /login
+/// does, linking the account to the user registered with its email, or to a
+/// new user, on its first sign-in.
+#[utoipa::path(
+    get,
+    path = "/oauth/{provider}/callback",
+    params(("provider" = String, Path, description = "`google` or `github`"), CallbackQuery),
+    responses(
+        (status = 200, description = "Signed in", body = LoginResponse),
+        (status = 401, description = "The user refused, or the sign-in expired or was already completed"),
+        (status = 403, description = "The user is suspended"),
+        (status = 404, description = "The provider is not configured", body = Problem),
+        (status = 409, description = "The email belongs to a user and the provider has not verified it", body = Problem),
+        (status = 422, description = "The account's name or email is not valid for a user", body = Problem),
+        (status = 502, description = "The provider failed"),
+        (status = 503, description = "Token signing is not configured"),
+    )
+)]
+pub async fn callback(
+    req: HttpRequest,
+    provider: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+    data: web::Data<AppState>,
+    jwt_auth: Option<web::Data<JwtAuth>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(jwt_auth) = jwt_auth else {
+        return Err(AuthError::Unavailable(String::from("auth.jwt_secret is not configured")).into());
+    };
+    let client = data.oauth.client(&provider)?;
+    let CallbackQuery { code, state, error } = query.into_inner();
+    if let Some(error) = error {
+        return Err(AuthError::SignInFailed(format!("the provider answered {}", error)).into());
+    }
+    let expired = || AuthError::SignInFailed(String::from("unknown, expired or completed sign-in"));
+    let (Some(code), Some(state)) = (code, state) else {
+        return Err(AuthError::SignInFailed(String::from("missing code or state")).into());
+    };
+    let state_hash = hash_secret(&state);
+    let (started_with, verifier) = lookup_state(&data, &state_hash).await?.ok_or_else(expired)?;
+    if started_with != client.provider.name() || !consume_state(&data, &state_hash).await? {
+        return Err(expired().into());
+    }
+
+    let access_token = redeem(&data.oauth, client, &code, &verifier).await?;
+    let bearer = format!("Bearer {}", access_token);
+    let identity = match client.provider {
+        Provider::Google => google_identity(&data.oauth, &bearer).await?,
+        Provider::Github => github_identity(&data.oauth, &bearer).await?,
+    };
+    let user_id = user_for(&data, client.provider, &identity).await?;
+    status::check_sign_in(&data, user_id).await?;
+    let signed_in = sessions::sign_in(&data, &jwt_auth, user_id, &req).await?;
+    stats::record_login(&data, user_id);
+    Ok(HttpResponse::Ok().json(signed_in))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn providers() -> Providers {
+        Providers::new(&OauthConfig {
+            callback_base_url: Some(String::from("https://api.example.com/")),
+            github_client_id: Some(String::from("id")),
+            github_client_secret: Some(String::from("secret")),
+            ..OauthConfig::default()
+        })
+    }
+
+    #[test]
+    fn only_configured_providers_are_offered() {
+        let providers = providers();
+        assert_eq!(providers.client("github").unwrap().provider, Provider::Github);
+        assert!(matches!(providers.client("google"), Err(ApiError::NotFound(_))));
+        assert!(matches!(providers.client("gitlab"), Err(ApiError::NotFound(_))));
+        assert_eq!(
+            providers.redirect_uri(Provider::Github),
+            "https://api.example.com/oauth/github/callback"
+        );
+    }
+
+    #[test]
+    fn authorize_urls_carry_the_state_and_challenge() {
+        let providers = providers();
+        let client = providers.client("github").unwrap();
+        let url = authorize_url(&providers, client, "the state", "verifier");
+        let (base, query) = url.split_once('?').unwrap();
+        assert_eq!(base, "https://github.com/login/oauth/authorize");
+        let pairs: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        let get = |name: &str| {
+            pairs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("client_id"), Some("id"));
+        assert_eq!(get("state"), Some("the state"));
+        assert_eq!(get("redirect_uri"), Some("https://api.example.com/oauth/github/callback"));
+        assert_eq!(get("code_challenge"), Some(code_challenge("verifier").as_str()));
+        assert_eq!(get("code_challenge_method"), Some("S256"));
+    }
+
+    #[test]
+    fn challenges_follow_rfc_7636() {
+        // Appendix B of RFC 7636.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn provider_answers_must_succeed_and_parse() {
+        let ok = Response { status: 200, body: br#"{"access_token":"t"}"#.to_vec() };
+        let token: TokenResponse = json("url", ok).unwrap();
+        assert_eq!(token.access_token.as_deref(), Some("t"));
+        let failed = Response { status: 500, body: Vec::new() };
+        assert!(matches!(json::<TokenResponse>("url", failed), Err(AuthError::ProviderFailed(_))));
+        let garbled = Response { status: 200, body: b"<html>".to_vec() };
+        assert!(matches!(json::<TokenResponse>("url", garbled), Err(AuthError::ProviderFailed(_))));
+    }
+}
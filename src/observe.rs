@@ -0,0 +1,218 @@
+use crate::breaker::Breaker;
+use crate::limiter::Limiter;
+use crate::metrics::Metrics;
+use crate::retry::{self, RetryPolicy};
+use crate::state::AppState;
+use actix_web::rt::time::sleep;
+use scylla::transport::errors::QueryError;
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::Instrument;
+
+// Runs one CQL request inside a `cql` span named after its statement (as in
+// `Statements`), so it shows up as a child of the request span in logs and
+// traces, and counts failures in the metrics. `request` is called again for
+// each retry the state's `RetryPolicy` allows, so it must be safe to apply
+// twice. While the state's `Breaker` is open it isn't called at all, and it
+// waits for a place under the state's `Limiter` before it is. A
+// request taking at least `scylla.slow_query_threshold_ms`, retries included,
+// is logged as a slow query with the node that last served it, to place hot
+// partitions and bad access patterns. Only the statement's name is logged,
+// never its bound values, which may hold emails, password hashes and tokens.
+
+tokio::task_local! {
+    // The node the driver last sent the current request to; see
+    // `session::Observed`.
+    static COORDINATOR: Cell<Option<SocketAddr>>;
+}
+
+// Notes that an attempt of the request running in this task went to `node`.
+pub fn record_coordinator(node: SocketAddr) {
+    let _ = COORDINATOR.try_with(|coordinator| coordinator.set(Some(node)));
+}
+
+// Why a CQL request failed: the driver's error, the breaker refusing to
+// send it, with how long until it will try again, or no place for it under
+// the limiter.
+#[derive(Debug)]
+pub enum CqlError {
+    Query(QueryError),
+    Open(Duration),
+    Saturated,
+}
+
+impl fmt::Display for CqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CqlError::Query(e) => write!(f, "{}", e),
+            CqlError::Open(retry_after) => {
+                write!(f, "circuit breaker open, retrying the cluster in {:?}", retry_after)
+            }
+            CqlError::Saturated => write!(f, "too many CQL requests in flight"),
+        }
+    }
+}
+
+impl From<QueryError> for CqlError {
+    fn from(e: QueryError) -> Self {
+        CqlError::Query(e)
+    }
+}
+
+pub async fn query<T, Fut>(
+    state: &AppState,
+    statement: &'static str,
+    request: impl FnMut() -> Fut,
+) -> Result<T, CqlError>
+where
+    Fut: Future<Output = Result<T, QueryError>>,
+{
+    run(
+        &state.metrics,
+        &state.retry,
+        &state.breaker,
+        &state.limiter,
+        statement,
+        true,
+        request,
+    )
+    .await
+}
+
+// Like `query`, for lightweight transactions (`IF ...`), which are never
+// retried: an attempt that timed out or lost its connection may still have
+// applied, and the retry would then report its condition as failed, turning a
+// successful write into a 404, 409 or 412.
+pub async fn conditional<T, Fut>(
+    state: &AppState,
+    statement: &'static str,
+    request: impl FnMut() -> Fut,
+) -> Result<T, CqlError>
+where
+    Fut: Future<Output = Result<T, QueryError>>,
+{
+    run(
+        &state.metrics,
+        &state.retry,
+        &state.breaker,
+        &state.limiter,
+        statement,
+        false,
+        request,
+    )
+    .await
+}
+
+// `query` and `conditional` for code that holds the metrics, retry policy,
+// breaker and limiter rather than the whole state, such as `repository::ScyllaUsers`.
+pub async fn query_with<T, Fut>(
+    metrics: &Metrics,
+    retry: &RetryPolicy,
+    breaker: &Breaker,
+    limiter: &Limiter,
+    statement: &'static str,
+    request: impl FnMut() -> Fut,
+) -> Result<T, CqlError>
+where
+    Fut: Future<Output = Result<T, QueryError>>,
+{
+    run(metrics, retry, breaker, limiter, statement, true, request).await
+}
+
+pub async fn conditional_with<T, Fut>(
+    metrics: &Metrics,
+    retry: &RetryPolicy,
+    breaker: &Breaker,
+    limiter: &Limiter,
+    statement: &'static str,
+    request: impl FnMut() -> Fut,
+) -> Result<T, CqlError>
+where
+    Fut: Future<Output = Result<T, QueryError>>,
+{
+    run(metrics, retry, breaker, limiter, statement, false, request).await
+}
+
+async fn run<T, Fut>(
+    metrics: &Metrics,
+    retry: &RetryPolicy,
+    breaker: &Breaker,
+    limiter: &Limiter,
+    statement: &'static str,
+    retryable: bool,
+    mut request: impl FnMut() -> Fut,
+) -> Result<T, CqlError>
+where
+    Fut: Future<Output = Result<T, QueryError>>,
+{
+    if let Err(retry_after) = breaker.admit() {
+        metrics.query_failed(statement);
+        return Err(CqlError::Open(retry_after));
+    }
+    let Some(admitted) = limiter.admit().await else {
+        metrics.query_rejected(statement);
+        return Err(CqlError::Saturated);
+    };
+    metrics.queries_in_flight(limiter.in_flight());
+    let span = tracing::info_span!(
+        "cql",
+        statement,
+        latency_ms = Empty,
+        attempts = Empty,
+        error = Empty
+    );
+    let started = Instant::now();
+
+    let mut attempt = 1;
+    let (result, coordinator) = COORDINATOR
+        .scope(Cell::new(None), async {
+            let result = loop {
+                match request().instrument(span.clone()).await {
+                    Err(e) if retryable && retry.should_retry(&e, attempt) => {
+                        let backoff = retry.backoff(attempt);
+                        tracing::warn!(
+                            parent: &span,
+                            error = %e,
+                            attempt,
+                            ?backoff,
+                            "retrying query"
+                        );
+                        metrics.query_retried(statement);
+                        sleep(backoff).await;
+                        attempt += 1;
+                    }
+                    result => break result,
+                }
+            };
+            (result, COORDINATOR.with(Cell::get))
+        })
+        .await;
+
+    drop(admitted);
+    metrics.queries_in_flight(limiter.in_flight());
+    let latency = started.elapsed();
+    span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+    span.record("attempts", attempt);
+    metrics.query_finished(statement, latency);
+    if metrics.query_slow(statement, latency) {
+        tracing::warn!(
+            parent: &span,
+            statement,
+            latency_ms = latency.as_millis() as u64,
+            attempts = attempt,
+            node = coordinator.map(tracing::field::display),
+            failed = result.is_err(),
+            "slow query"
+        );
+    }
+    breaker.record(result.as_ref().err().is_none_or(|e| !retry::is_transient(e)));
+    if let Err(e) = &result {
+        span.record("error", tracing::field::display(e));
+        metrics.query_failed(statement);
+    }
+    Ok(result?)
+}
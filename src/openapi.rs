@@ -0,0 +1,385 @@
+use crate::addresses;
+use crate::api_keys::{self, CreatedApiKey, NewApiKey};
+use crate::audit::{self, AuditEntry, AuditLog, Change};
+use crate::avatars;
+use crate::batch;
+use crate::bulk_delete;
+use crate::cluster;
+use crate::config::ConcurrencyMode;
+use crate::count;
+use crate::error::{FieldError, Problem};
+use crate::export;
+use crate::flags::{self, FlagState, SetFlag};
+use crate::import;
+#[cfg(feature = "graphql")]
+use crate::graphql::{self, GraphQLRequest};
+use crate::groups;
+use crate::handlers;
+use crate::history::{self, EventStream, StoredEvent};
+use crate::indexes;
+use crate::links::{Link, PageLinks, UserLinks};
+use crate::login::{self, LoginRequest, LoginResponse};
+use crate::maintenance::{self, JobRun};
+use crate::maintenance_mode::{self, MaintenanceStatus, SetMaintenance};
+use crate::metadata;
+use crate::models::{
+    Address, Addresses, BatchOperation, BatchRequest, BatchResponse, BreakerState, BulkDeleteRequest,
+    BulkDeleteResponse, BulkDeleteResult, BulkItemResult, BulkRegisterResponse, ClusterMetadata, ClusterStatus, ColumnMetadata, DatacenterMetadata, EmailCheck, FieldMetadata, Group, GroupMember, ImportLineError, ImportReport, IndexState, IndexStatus, Indexes, KeyspaceMetadata, Metadata, MetadataValue, NewGroup, NewPost, NewTag, NewTenant, NewUser, NodeMetadata, NodeStatus, Post, PostsPage, Profile, ReplaceUser, SchemaMetadata, SortField,
+    SortOrder, StatusFilter, TableMetadata, Tags, Tenant, TypeMetadata, UpdateGroup, UpdateUser, User,
+    UserCount, UserRoles, UserStats, UserStatus, UsersPage, ViewMetadata,
+};
+use crate::monitor;
+use crate::null_fields::NullFieldsQuery;
+use crate::oauth;
+use crate::password_reset::{self, ForgotPassword, ResetPassword};
+use crate::patch::PatchOperation;
+use crate::posts;
+use crate::raw_cql::{self, CqlColumn, CqlRequest, CqlRows};
+use crate::reload::{self, Reloaded};
+use crate::sessions::{self, RefreshRequest, Session};
+use crate::state::AppState;
+use crate::stats;
+use crate::status;
+use crate::tags;
+use crate::tenants;
+use crate::verification;
+use crate::webhooks::{self, CreatedWebhook, NewWebhook, Webhook, WebhookDelivery};
+use actix_web::{web, HttpResponse, Responder};
+use std::sync::LazyLock;
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::Required;
+use utoipa::{IntoParams, Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "HireMe user API"),
+    servers(
+        (url = "/api/v1", description = "Version 1"),
+        (url = "/", description = "Unversioned paths, served as version 1"),
+    ),
+    paths(
+        handlers::get_all_users,
+        handlers::get_user_by_id,
+        handlers::user_exists,
+        handlers::get_user_by_email,
+        handlers::get_user_by_phone,
+        handlers::search_users,
+        count::get_user_count,
+        export::export_users,
+        import::import_users,
+        bulk_delete::delete_users,
+        handlers::check_email,
+        handlers::register_user,
+        handlers::register_users_bulk,
+        batch::apply_batch,
+        login::login,
+        sessions::refresh,
+        oauth::authorize,
+        oauth::callback,
+        sessions::logout,
+        sessions::list_sessions,
+        sessions::revoke_sessions,
+        sessions::revoke_session,
+        verification::verify_email,
+        password_reset::forgot_password,
+        password_reset::reset_password,
+        handlers::update_user,
+        handlers::replace_user,
+        handlers::delete_user,
+        handlers::restore_user,
+        handlers::anonymize_user,
+        audit::get_audit_log,
+        history::get_user_events,
+        addresses::list_addresses,
+        addresses::add_address,
+        addresses::delete_address,
+        tags::list_tags,
+        tags::add_tag,
+        tags::delete_tag,
+        groups::create_group,
+        groups::list_groups,
+        groups::get_group,
+        groups::update_group,
+        groups::delete_group,
+        groups::list_members,
+        groups::add_member,
+        groups::remove_member,
+        groups::list_user_groups,
+        posts::create_post,
+        posts::list_posts,
+        posts::delete_post,
+        metadata::get_metadata,
+        metadata::set_metadata,
+        metadata::delete_metadata,
+        stats::get_stats,
+        status::deactivate_user,
+        status::suspend_user,
+        status::activate_user,
+        avatars::upload_avatar,
+        avatars::get_avatar,
+        handlers::set_user_roles,
+        api_keys::create_api_key,
+        api_keys::revoke_api_key,
+        monitor::get_status,
+        cluster::get_cluster,
+        cluster::get_schema,
+        indexes::get_indexes,
+        indexes::create_indexes,
+        maintenance::run_job,
+        flags::list_flags,
+        flags::set_flag,
+        maintenance_mode::get_maintenance,
+        maintenance_mode::set_maintenance,
+        reload::reload_config,
+        raw_cql::run_cql,
+        webhooks::create_webhook,
+        webhooks::list_webhooks,
+        webhooks::delete_webhook,
+        webhooks::list_deliveries,
+        tenants::create_tenant,
+        tenants::list_tenants,
+    ),
+    components(schemas(
+        Problem,
+        FieldError,
+        User,
+        Profile,
+        Address,
+        Addresses,
+        NewTag,
+        Tags,
+        Group,
+        NewGroup,
+        UpdateGroup,
+        GroupMember,
+        Post,
+        NewPost,
+        PostsPage,
+        Metadata,
+        MetadataValue,
+        UserStats,
+        NewUser,
+        BulkItemResult,
+        BulkRegisterResponse,
+        ImportLineError,
+        ImportReport,
+        BulkDeleteRequest,
+        BulkDeleteResult,
+        BulkDeleteResponse,
+        BatchOperation,
+        BatchRequest,
+        BatchResponse,
+        UpdateUser,
+        ReplaceUser,
+        UsersPage,
+        UserCount,
+        EmailCheck,
+        SortField,
+        SortOrder,
+        UserStatus,
+        StatusFilter,
+        UserRoles,
+        LoginRequest,
+        LoginResponse,
+        RefreshRequest,
+        Session,
+        ForgotPassword,
+        ResetPassword,
+        NewApiKey,
+        CreatedApiKey,
+        ClusterStatus,
+        NodeStatus,
+        ClusterMetadata,
+        Indexes,
+        IndexStatus,
+        IndexState,
+        NodeMetadata,
+        DatacenterMetadata,
+        KeyspaceMetadata,
+        TableMetadata,
+        ColumnMetadata,
+        ViewMetadata,
+        FieldMetadata,
+        TypeMetadata,
+        SchemaMetadata,
+        BreakerState,
+        NewTenant,
+        Tenant,
+        Link,
+        UserLinks,
+        PageLinks,
+        PatchOperation,
+        AuditEntry,
+        AuditLog,
+        Change,
+        StoredEvent,
+        EventStream,
+        JobRun,
+        FlagState,
+        SetFlag,
+        MaintenanceStatus,
+        SetMaintenance,
+        Reloaded,
+        CqlRequest,
+        CqlColumn,
+        CqlRows,
+        Webhook,
+        NewWebhook,
+        CreatedWebhook,
+        WebhookDelivery
+    )),
+    modifiers(&GraphQL, &SecuritySchemes, &NullFieldsParam)
+)]
+pub struct ApiDoc;
+
+// POST /graphql, documented only with the `graphql` feature that serves it.
+struct GraphQL;
+
+impl Modify for GraphQL {
+    #[cfg(feature = "graphql")]
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        #[derive(OpenApi)]
+        #[openapi(paths(graphql::execute), components(schemas(GraphQLRequest)))]
+        struct GraphQLDoc;
+
+        openapi.merge(GraphQLDoc::openapi());
+    }
+
+    #[cfg(not(feature = "graphql"))]
+    fn modify(&self, _openapi: &mut utoipa::openapi::OpenApi) {}
+}
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+        );
+    }
+}
+
+// Every read takes `?null_fields` (see `null_fields`).
+struct NullFieldsParam;
+
+impl Modify for NullFieldsParam {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for item in openapi.paths.paths.values_mut() {
+            if let Some(operation) = item.get.as_mut() {
+                let parameters = operation.parameters.get_or_insert_with(Vec::new);
+                parameters.extend(NullFieldsQuery::into_params(|| None));
+            }
+        }
+    }
+}
+
+static SPEC: LazyLock<String> = LazyLock::new(|| {
+    ApiDoc::openapi()
+        .to_json()
+        .expect("OpenAPI document serializes to JSON")
+});
+
+// Marks the `If-Match` header of a PUT or PATCH as required.
+fn require_if_match(operation: Option<&mut Operation>) {
+    let parameters = operation.and_then(|operation| operation.parameters.as_mut());
+    for parameter in parameters.into_iter().flatten() {
+        if parameter.name == "If-Match" {
+            parameter.required = Required::True;
+            parameter.description = Some(String::from(
+                "The user's ETag; required unless the body has its `version`, as the server \
+                 runs in the strict concurrency mode",
+            ));
+        }
+    }
+}
+
+// The spec as served in the strict `http.concurrency_mode`, where writes to
+// a user must say which state of it they expect.
+static STRICT_SPEC: LazyLock<String> = LazyLock::new(|| {
+    let mut openapi = ApiDoc::openapi();
+    let paths = &mut openapi.paths.paths;
+    require_if_match(paths.get_mut("/update/{id}").and_then(|item| item.patch.as_mut()));
+    require_if_match(paths.get_mut("/users/{id}").and_then(|item| item.put.as_mut()));
+    openapi
+        .to_json()
+        .expect("OpenAPI document serializes to JSON")
+});
+
+// Swagger UI is loaded from the public CDN and pointed at our own spec, so no
+// UI assets need to be vendored into the binary.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>HireMe user API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+// GET /api-docs/openapi.json
+pub async fn get_spec(data: web::Data<AppState>) -> impl Responder {
+    let spec = match data.concurrency_mode {
+        ConcurrencyMode::Lenient => &SPEC,
+        ConcurrencyMode::Strict => &STRICT_SPEC,
+    };
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(spec.as_str())
+}
+
+// GET /swagger-ui
+pub async fn get_swagger_ui() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn if_match_required(spec: &str, path: &str, method: &str) -> bool {
+        let spec: Value = serde_json::from_str(spec).unwrap();
+        let parameters = spec["paths"][path][method]["parameters"].as_array().unwrap();
+        let if_match = parameters.iter().find(|parameter| parameter["name"] == "If-Match");
+        if_match.unwrap()["required"] == Value::Bool(true)
+    }
+
+    #[test]
+    fn the_strict_spec_requires_if_match_on_puts_and_patches() {
+        assert!(!if_match_required(&SPEC, "/update/{id}", "patch"));
+        assert!(!if_match_required(&SPEC, "/users/{id}", "put"));
+        assert!(if_match_required(&STRICT_SPEC, "/update/{id}", "patch"));
+        assert!(if_match_required(&STRICT_SPEC, "/users/{id}", "put"));
+        assert!(!if_match_required(&STRICT_SPEC, "/delete/{id}", "delete"));
+    }
+
+    #[test]
+    fn graphql_is_documented_only_when_served() {
+        let spec: Value = serde_json::from_str(&SPEC).unwrap();
+        let documented = spec["paths"]["/graphql"]["post"].is_object();
+        let schema = spec["components"]["schemas"]["GraphQLRequest"].is_object();
+        assert_eq!((documented, schema), (cfg!(feature = "graphql"), cfg!(feature = "graphql")));
+    }
+}
@@ -0,0 +1,93 @@
+use actix_web::http::header::HeaderMap;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+// OTLP/HTTP export of the request and `cql` spans. Only compiled with the
+// `otel` feature, and only active when `log.otlp_endpoint` is set.
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+// Builds the tracing layer that forwards spans to the collector at `endpoint`
+// (e.g. `http://localhost:4318`), batching them on a background thread.
+pub fn layer<S>(endpoint: Option<&str>) -> Result<Option<OpenTelemetryLayer<S, SdkTracer>>, String>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = endpoint else {
+        return Ok(None);
+    };
+    let endpoint = match endpoint.trim_end_matches('/') {
+        base if base.ends_with("/v1/traces") => base.to_string(),
+        base => format!("{}/v1/traces", base),
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| format!("cannot build OTLP exporter: {}", e))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    let _ = PROVIDER.set(provider);
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+// Flushes spans still queued for export; called once the server has stopped.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::warn!(error = %e, "failed to flush OpenTelemetry spans");
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+// Continues the trace a gateway started, if the request carries a W3C
+// `traceparent` header.
+pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    let _ = span.set_parent(context);
+}
+
+// Trace id of the current span as 32 lowercase hex digits, once the span
+// belongs to a trace.
+pub fn current_trace_id() -> Option<String> {
+    use opentelemetry::trace::TraceContextExt;
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
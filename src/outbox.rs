@@ -0,0 +1,410 @@
+use crate::config::OutboxConfig;
+use crate::error::ApiError;
+use crate::events::{EventKind, Events};
+use crate::models::User;
+use crate::observe;
+use crate::publisher::Publishing;
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+#[cfg(test)]
+use std::collections::BTreeMap;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+// With `outbox.enabled`, user events reach the feeds (and, in time, other
+// consumers) through the `outbox` table instead of straight after the change:
+// `history::append` writes the event there once it is on the user's stream,
+// and a background relay publishes what it finds to every `Sink` and then
+// deletes it, marking it published. An event written before a crash is
+// relayed once the service is back, and one published but not yet deleted
+// is published again: delivery is at least once, so consumers must tolerate
+// repeats (the stream version tells them apart).
+//
+// The outbox is split into shards, a user's events all going to the same one.
+// Each shard is relayed oldest first, and stops at an event that fails to go
+// out, so a user's later events never overtake it; the next run retries it.
+// The relay runs every `outbox.relay_interval_ms` as a background task, and
+// stops between runs on shutdown.
+
+// Shards of the outbox.
+const SHARDS: i32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct OutboxEvent {
+    pub shard: i32,
+    pub created_at: DateTime<Utc>,
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub version: Option<i64>,
+    pub kind: EventKind,
+    pub user: Option<User>,
+}
+
+impl OutboxEvent {
+    pub fn new(kind: EventKind, user_id: Uuid, user: Option<User>, version: Option<i64>) -> Self {
+        OutboxEvent {
+            shard: shard(user_id),
+            created_at: Utc::now(),
+            id: Uuid::new_v4(),
+            user_id,
+            version,
+            kind,
+            user,
+        }
+    }
+}
+
+fn shard(user_id: Uuid) -> i32 {
+    i32::from(user_id.as_bytes()[15]) % SHARDS
+}
+
+// The outbox as the relay reads it: `ScyllaOutbox` is the `outbox` table,
+// written by `enqueue`, and `MemoryOutbox` a map for tests.
+pub trait OutboxStore {
+    // Up to `limit` of the oldest events in `shard`.
+    fn pending(&self, shard: i32, limit: usize) -> BoxFuture<'_, Result<Vec<OutboxEvent>, ApiError>>;
+
+    // Marks `event` published by deleting it.
+    fn remove<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), ApiError>>;
+}
+
+// Where the relay publishes events.
+pub trait Sink {
+    fn publish<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), String>>;
+}
+
+// The live feeds (SSE, WebSocket).
+impl Sink for Events {
+    fn publish<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), String>> {
+        Events::publish(self, event.kind, event.user_id, event.user.clone(), event.version);
+        future::ready(Ok(())).boxed()
+    }
+}
+
+// Writes `event` to the outbox for the relay.
+pub async fn enqueue(state: &AppState, event: &OutboxEvent) -> Result<(), ApiError> {
+    let payload = event
+        .user
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| ApiError::internal("Failed to serialize user event", e))?;
+    observe::query(state, "insert_outbox_event", || {
+        state.session.execute_unpaged(
+            &state.statements.insert_outbox_event,
+            (
+                event.shard,
+                event.created_at,
+                event.id,
+                event.user_id,
+                event.version,
+                event.kind.name(),
+                &payload,
+            ),
+        )
+    })
+    .await?;
+    Ok(())
+}
+
+pub struct ScyllaOutbox(pub AppState);
+
+type OutboxRow = (DateTime<Utc>, Uuid, Uuid, Option<i64>, String, Option<String>);
+
+fn read_event(shard: i32, row: OutboxRow) -> Result<OutboxEvent, ApiError> {
+    let (created_at, id, user_id, version, kind, payload) = row;
+    let kind = EventKind::from_name(&kind)
+        .ok_or_else(|| ApiError::Internal(format!("unknown outbox event type {}", kind)))?;
+    let user = payload
+        .map(|payload| serde_json::from_str(&payload))
+        .transpose()
+        .map_err(|e| ApiError::internal("Unreadable outbox event payload", e))?;
+    Ok(OutboxEvent {
+        shard,
+        created_at,
+        id,
+        user_id,
+        version,
+        kind,
+        user,
+    })
+}
+
+impl OutboxStore for ScyllaOutbox {
+    fn pending(&self, shard: i32, limit: usize) -> BoxFuture<'_, Result<Vec<OutboxEvent>, ApiError>> {
+        async move {
+            let state = &self.0;
+            let limit = i32::try_from(limit).unwrap_or(i32::MAX);
+            let result = observe::query(state, "select_outbox_events", || {
+                state
+                    .session
+                    .execute_unpaged(&state.statements.select_outbox_events, (shard, limit))
+            })
+            .await?;
+            result
+                .into_rows_result()
+                .map_err(|e| ApiError::internal("Error reading the outbox", e))?
+                .rows::<OutboxRow>()
+                .map_err(|e| ApiError::internal("Error reading the outbox", e))?
+                .map(|row| {
+                    row.map_err(|e| ApiError::internal("Error reading the outbox", e))
+                        .and_then(|row| read_event(shard, row))
+                })
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn remove<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), ApiError>> {
+        async move {
+            let state = &self.0;
+            observe::query(state, "delete_outbox_event", || {
+                state.session.execute_unpaged(
+                    &state.statements.delete_outbox_event,
+                    (event.shard, event.created_at, event.id),
+                )
+            })
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// An event's primary key in the `outbox` table.
+#[cfg(test)]
+type OutboxKey = (i32, DateTime<Utc>, Uuid);
+
+// Events kept in a map, in the table's order.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryOutbox {
+    events: Mutex<BTreeMap<OutboxKey, OutboxEvent>>,
+}
+
+#[cfg(test)]
+impl MemoryOutbox {
+    pub fn insert(&self, event: OutboxEvent) {
+        let key = (event.shard, event.created_at, event.id);
+        self.events.lock().unwrap().insert(key, event);
+    }
+}
+
+#[cfg(test)]
+impl OutboxStore for MemoryOutbox {
+    fn pending(&self, shard: i32, limit: usize) -> BoxFuture<'_, Result<Vec<OutboxEvent>, ApiError>> {
+        let events = self.events.lock().unwrap();
+        let pending = events
+            .values()
+            .filter(|event| event.shard == shard)
+            .take(limit)
+            .cloned()
+            .collect();
+        future::ready(Ok(pending)).boxed()
+    }
+
+    fn remove<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), ApiError>> {
+        let key = (event.shard, event.created_at, event.id);
+        self.events.lock().unwrap().remove(&key);
+        future::ready(Ok(())).boxed()
+    }
+}
+
+pub struct Relay {
+    store: Arc<dyn OutboxStore>,
+    sinks: Vec<Arc<dyn Sink>>,
+    interval: Duration,
+    batch_size: usize,
+}
+
+impl Relay {
+    // The relay of the serving keyspace's outbox to its webhooks, Kafka topic
+    // or NATS stream, and event feeds. Webhooks go first: their queue can be
+    // full, and an event that fails there is tried again without reaching
+    // the others twice. The broker comes before the feeds for the same
+    // reason.
+    pub fn for_state(state: &AppState, config: &OutboxConfig) -> Self {
+        let mut sinks: Vec<Arc<dyn Sink>> = Vec::new();
+        if let Some(webhooks) = &state.webhooks {
+            sinks.push(webhooks.clone());
+        }
+        if let Some(publisher) = &state.publisher {
+            sinks.push(Arc::new(Publishing(publisher.clone())));
+        }
+        if let Some(search_index) = &state.search_index {
+            sinks.push(search_index.clone());
+        }
+        sinks.push(state.events.clone());
+        Relay::new(Arc::new(ScyllaOutbox(state.clone())), sinks, config)
+    }
+
+    pub fn new(store: Arc<dyn OutboxStore>, sinks: Vec<Arc<dyn Sink>>, config: &OutboxConfig) -> Self {
+        Relay {
+            store,
+            sinks,
+            interval: Duration::from_millis(config.relay_interval_ms),
+            batch_size: config.batch_size,
+        }
+    }
+
+    pub fn start(self, tasks: &mut Tasks) {
+        tasks.spawn("outbox relay", |stopping| self.run(stopping));
+    }
+
+    async fn run(self, mut stopping: Stopping) {
+        while stopping.pause(self.interval).await {
+            self.relay().await;
+        }
+    }
+
+    // Publishes and deletes the oldest events of every shard, returning how
+    // many went out.
+    pub async fn relay(&self) -> usize {
+        let mut relayed = 0;
+        for shard in 0..SHARDS {
+            match self.relay_shard(shard).await {
+                Ok(count) => relayed += count,
+                Err((count, e)) => {
+                    relayed += count;
+                    tracing::warn!(shard, error = %e, "failed to relay outbox events");
+                }
+            }
+        }
+        relayed
+    }
+
+    // The events relayed from `shard`, or how many were before one failed.
+    async fn relay_shard(&self, shard: i32) -> Result<usize, (usize, ApiError)> {
+        let pending = self.store.pending(shard, self.batch_size).await.map_err(|e| (0, e))?;
+        for (relayed, event) in pending.iter().enumerate() {
+            for sink in &self.sinks {
+                sink.publish(event).await.map_err(|e| {
+                    let e = ApiError::Internal(format!("event {} not published: {}", event.id, e));
+                    (relayed, e)
+                })?;
+            }
+            self.store.remove(event).await.map_err(|e| (relayed, e))?;
+        }
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Records what it is sent, failing the sends it is told to.
+    #[derive(Default)]
+    struct Recorder {
+        published: Mutex<Vec<Uuid>>,
+        failures: AtomicUsize,
+    }
+
+    impl Sink for Recorder {
+        fn publish<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), String>> {
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1))
+                .is_ok();
+            if !failing {
+                self.published.lock().unwrap().push(event.id);
+            }
+            future::ready(if failing { Err(String::from("broker unavailable")) } else { Ok(()) }).boxed()
+        }
+    }
+
+    fn event(user_id: Uuid, version: i64) -> OutboxEvent {
+        OutboxEvent::new(EventKind::Updated, user_id, None, Some(version))
+    }
+
+    fn config(batch_size: usize) -> OutboxConfig {
+        OutboxConfig {
+            enabled: true,
+            relay_interval_ms: 10,
+            batch_size,
+        }
+    }
+
+    #[actix_web::test]
+    async fn events_are_relayed_in_order_and_removed() {
+        let store = Arc::new(MemoryOutbox::default());
+        let recorder = Arc::new(Recorder::default());
+        let relay = Relay::new(store.clone(), vec![recorder.clone()], &config(10));
+
+        let ada = Uuid::new_v4();
+        let events: Vec<OutboxEvent> = (1..=3).map(|version| event(ada, version)).collect();
+        for event in &events {
+            store.insert(event.clone());
+        }
+        assert_eq!(relay.relay().await, 3);
+        assert_eq!(
+            *recorder.published.lock().unwrap(),
+            events.iter().map(|event| event.id).collect::<Vec<_>>()
+        );
+        assert!(store.pending(shard(ada), 10).await.unwrap().is_empty());
+        assert_eq!(relay.relay().await, 0);
+    }
+
+    #[actix_web::test]
+    async fn a_failed_event_holds_back_its_shard() {
+        let store = Arc::new(MemoryOutbox::default());
+        let recorder = Arc::new(Recorder {
+            failures: AtomicUsize::new(1),
+            ..Recorder::default()
+        });
+        let relay = Relay::new(store.clone(), vec![recorder.clone()], &config(10));
+
+        let ada = Uuid::new_v4();
+        let first = event(ada, 1);
+        let second = event(ada, 2);
+        store.insert(first.clone());
+        store.insert(second.clone());
+        assert_eq!(relay.relay().await, 0);
+        assert!(recorder.published.lock().unwrap().is_empty());
+        assert_eq!(store.pending(shard(ada), 10).await.unwrap().len(), 2);
+
+        assert_eq!(relay.relay().await, 2);
+        assert_eq!(*recorder.published.lock().unwrap(), [first.id, second.id]);
+    }
+
+    #[actix_web::test]
+    async fn an_event_left_by_a_crash_is_relayed_on_the_next_run() {
+        // The change and its outbox write happened, then the process died
+        // before its relay got to the event.
+        let store = Arc::new(MemoryOutbox::default());
+        let ada = Uuid::new_v4();
+        let written = event(ada, 1);
+        store.insert(written.clone());
+
+        let recorder = Arc::new(Recorder::default());
+        let mut tasks = Tasks::new();
+        Relay::new(store.clone(), vec![recorder.clone()], &config(10)).start(&mut tasks);
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        tasks.stop(Duration::from_secs(1)).await;
+
+        assert_eq!(*recorder.published.lock().unwrap(), [written.id]);
+        assert!(store.pending(shard(ada), 10).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn a_run_takes_at_most_a_batch_from_each_shard() {
+        let store = Arc::new(MemoryOutbox::default());
+        let recorder = Arc::new(Recorder::default());
+        let relay = Relay::new(store.clone(), vec![recorder.clone()], &config(2));
+
+        let ada = Uuid::new_v4();
+        for version in 1..=3 {
+            store.insert(event(ada, version));
+        }
+        assert_eq!(relay.relay().await, 2);
+        assert_eq!(relay.relay().await, 1);
+        assert_eq!(recorder.published.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::error::ApiError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use scylla::statement::{PagingState, PagingStateResponse};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+// Cursors handed to clients wrap the driver's paging state in a small
+// envelope, base64url encoded: a version byte, a byte naming the listing the
+// state belongs to, four bytes digesting the parameters that chose its rows
+// and their order, the Unix time it was issued at as eight big-endian bytes,
+// then the state itself. They are opaque: clients only echo them back in
+// `?cursor=`. A cursor from another listing, from the same listing with other
+// filters or sort, older than `http.cursor_max_age_secs` or in a layout this
+// build doesn't know is refused with a 400 rather than handed to the driver.
+// Listings read by key rather than by paging state, such as the audit log,
+// seal the clustering key of the last row served in the same envelope.
+
+// Layout of the envelope; a change to it gets a new version.
+const VERSION: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorKind {
+    // GET /users without filters.
+    Users,
+    // GET /users with filters, which runs a different query.
+    FilteredUsers,
+    // GET /users/search.
+    UserSearch,
+    // GET /users/{id}/audit, whose cursors hold a clustering key.
+    AuditLog,
+    // GET /users/search?q=, whose cursors hold the offset of the next page.
+    FullTextSearch,
+    // POST /admin/cql, for one statement's text.
+    RawCql,
+    // GET /users with a creation range, whose cursors hold a time in the
+    // range and maybe the user last served.
+    CreatedRange,
+    // GET /users/{id}/posts, whose cursors hold the id of the last post.
+    Posts,
+}
+
+impl CursorKind {
+    fn code(self) -> u8 {
+        match self {
+            CursorKind::Users => 1,
+            CursorKind::FilteredUsers => 2,
+            CursorKind::UserSearch => 3,
+            CursorKind::AuditLog => 4,
+            CursorKind::FullTextSearch => 5,
+            CursorKind::RawCql => 6,
+            CursorKind::CreatedRange => 7,
+            CursorKind::Posts => 8,
+        }
+    }
+}
+
+// What a cursor is valid for: a listing and a digest of the parameters that
+// shaped it. Page size and field selection are left out of the parameters, so
+// they may change between pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorScope {
+    kind: CursorKind,
+    digest: [u8; 4],
+}
+
+impl CursorScope {
+    pub fn new(kind: CursorKind, parameters: &impl Serialize) -> Self {
+        let json = serde_json::to_vec(parameters).unwrap_or_default();
+        let digest = Sha256::digest(json);
+        CursorScope {
+            kind,
+            digest: [digest[0], digest[1], digest[2], digest[3]],
+        }
+    }
+}
+
+fn seal(scope: &CursorScope, issued_at: i64, state: &[u8]) -> String {
+    let mut envelope = Vec::with_capacity(state.len() + 14);
+    envelope.extend_from_slice(&[VERSION, scope.kind.code()]);
+    envelope.extend_from_slice(&scope.digest);
+    envelope.extend_from_slice(&issued_at.to_be_bytes());
+    envelope.extend_from_slice(state);
+    URL_SAFE_NO_PAD.encode(envelope)
+}
+
+pub fn encode_cursor(scope: &CursorScope, response: PagingStateResponse) -> Option<String> {
+    match response {
+        PagingStateResponse::HasMorePages { state } => state
+            .as_bytes_slice()
+            .map(|bytes| seal(scope, Utc::now().timestamp(), bytes)),
+        PagingStateResponse::NoMorePages => None,
+    }
+}
+
+pub fn encode_key(scope: &CursorScope, key: &[u8]) -> String {
+    seal(scope, Utc::now().timestamp(), key)
+}
+
+// A missing cursor starts from the first page.
+pub fn decode_cursor(
+    cursor: Option<&str>,
+    scope: &CursorScope,
+    max_age: Duration,
+) -> Result<PagingState, ApiError> {
+    match cursor {
+        Some(cursor) => open(cursor, scope, max_age).map(PagingState::new_from_raw_bytes),
+        None => Ok(PagingState::start()),
+    }
+}
+
+// The key sealed in `cursor`; `None`, the first page, without one.
+pub fn decode_key(
+    cursor: Option<&str>,
+    scope: &CursorScope,
+    max_age: Duration,
+) -> Result<Option<Vec<u8>>, ApiError> {
+    cursor.map(|cursor| open(cursor, scope, max_age)).transpose()
+}
+
+// What `cursor` seals, if it is valid for `scope`. One issued in the future,
+// by a replica whose clock runs ahead, counts as fresh.
+fn open(cursor: &str, scope: &CursorScope, max_age: Duration) -> Result<Vec<u8>, ApiError> {
+    let envelope = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| ApiError::BadRequest(String::from("Invalid cursor")))?;
+    match envelope.as_slice() {
+        [VERSION, code, ..] if *code != scope.kind.code() => Err(ApiError::BadRequest(
+            String::from("Cursor belongs to a different listing"),
+        )),
+        [VERSION, _, rest @ ..] => {
+            let Some((digest, rest)) = rest.split_first_chunk::<4>() else {
+                return Err(ApiError::BadRequest(String::from("Invalid cursor")));
+            };
+            let Some((issued_at, state)) = rest.split_first_chunk::<8>() else {
+                return Err(ApiError::BadRequest(String::from("Invalid cursor")));
+            };
+            if *digest != scope.digest {
+                return Err(ApiError::BadRequest(String::from(
+                    "Cursor was issued for different query parameters; restart from the first page",
+                )));
+            }
+            let age = Utc::now().timestamp().saturating_sub(i64::from_be_bytes(*issued_at));
+            if age > i64::try_from(max_age.as_secs()).unwrap_or(i64::MAX) {
+                return Err(ApiError::BadRequest(format!(
+                    "Cursor is older than {} seconds; restart from the first page",
+                    max_age.as_secs()
+                )));
+            }
+            Ok(state.to_vec())
+        }
+        [version, ..] => Err(ApiError::BadRequest(format!(
+            "Cursor version {} is not supported",
+            version
+        ))),
+        [] => Err(ApiError::BadRequest(String::from("Invalid cursor"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(3_600);
+
+    fn scope(kind: CursorKind) -> CursorScope {
+        CursorScope::new(kind, &("name", "Ada"))
+    }
+
+    fn cursor(scope: &CursorScope) -> String {
+        let state = PagingState::new_from_raw_bytes(&b"\x00\x01page"[..]);
+        encode_cursor(scope, PagingStateResponse::HasMorePages { state }).unwrap()
+    }
+
+    fn message(result: Result<PagingState, ApiError>) -> String {
+        match result {
+            Err(ApiError::BadRequest(message)) => message,
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip_the_paging_state() {
+        let users = scope(CursorKind::Users);
+        let cursor = cursor(&users);
+        assert!(!cursor.contains(['+', '/', '=']));
+        let decoded = decode_cursor(Some(&cursor), &users, MAX_AGE).unwrap();
+        assert_eq!(
+            decoded.as_bytes_slice().map(|bytes| bytes.to_vec()),
+            Some(b"\x00\x01page".to_vec())
+        );
+    }
+
+    #[test]
+    fn no_cursor_starts_from_the_first_page() {
+        let users = scope(CursorKind::Users);
+        assert_eq!(encode_cursor(&users, PagingStateResponse::NoMorePages), None);
+        let start = decode_cursor(None, &scope(CursorKind::UserSearch), MAX_AGE).unwrap();
+        assert!(start.as_bytes_slice().is_none());
+    }
+
+    #[test]
+    fn cursors_of_another_listing_are_refused() {
+        let search = cursor(&scope(CursorKind::UserSearch));
+        assert_eq!(
+            message(decode_cursor(Some(&search), &scope(CursorKind::Users), MAX_AGE)),
+            "Cursor belongs to a different listing"
+        );
+        let filtered = cursor(&scope(CursorKind::FilteredUsers));
+        assert!(decode_cursor(Some(&filtered), &scope(CursorKind::Users), MAX_AGE).is_err());
+    }
+
+    #[test]
+    fn cursors_for_other_parameters_are_refused() {
+        let ada = cursor(&scope(CursorKind::FilteredUsers));
+        let bob = CursorScope::new(CursorKind::FilteredUsers, &("name", "Bob"));
+        assert_eq!(
+            message(decode_cursor(Some(&ada), &bob, MAX_AGE)),
+            "Cursor was issued for different query parameters; restart from the first page"
+        );
+        assert_eq!(bob, CursorScope::new(CursorKind::FilteredUsers, &("name", "Bob")));
+    }
+
+    #[test]
+    fn unknown_versions_and_garbage_are_refused() {
+        let users = scope(CursorKind::Users);
+        let future = URL_SAFE_NO_PAD.encode([VERSION + 1, CursorKind::Users.code(), 0, 1]);
+        assert_eq!(
+            message(decode_cursor(Some(&future), &users, MAX_AGE)),
+            format!("Cursor version {} is not supported", VERSION + 1)
+        );
+        let short = URL_SAFE_NO_PAD.encode([VERSION, CursorKind::Users.code(), 0]);
+        assert_eq!(message(decode_cursor(Some(&short), &users, MAX_AGE)), "Invalid cursor");
+        assert_eq!(message(decode_cursor(Some("not base64!"), &users, MAX_AGE)), "Invalid cursor");
+        assert_eq!(message(decode_cursor(Some(""), &users, MAX_AGE)), "Invalid cursor");
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let audit = scope(CursorKind::AuditLog);
+        let cursor = encode_key(&audit, b"key");
+        assert_eq!(decode_key(Some(&cursor), &audit, MAX_AGE).unwrap(), Some(b"key".to_vec()));
+        assert_eq!(decode_key(None, &audit, MAX_AGE).unwrap(), None);
+        assert!(decode_key(Some(&cursor), &scope(CursorKind::Users), MAX_AGE).is_err());
+    }
+
+    #[test]
+    fn fresh_cursors_are_accepted_and_aged_ones_refused() {
+        let users = scope(CursorKind::Users);
+        let now = Utc::now().timestamp();
+        let max_age = MAX_AGE.as_secs() as i64;
+
+        let fresh = seal(&users, now - max_age + 60, b"page");
+        assert!(decode_cursor(Some(&fresh), &users, MAX_AGE).is_ok());
+        let ahead = seal(&users, now + 60, b"page");
+        assert!(decode_cursor(Some(&ahead), &users, MAX_AGE).is_ok());
+
+        let aged = seal(&users, now - max_age - 60, b"page");
+        assert_eq!(
+            message(decode_cursor(Some(&aged), &users, MAX_AGE)),
+            "Cursor is older than 3600 seconds; restart from the first page"
+        );
+        assert!(decode_cursor(Some(&aged), &users, Duration::from_secs(86_400)).is_ok());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::config::HttpConfig;
+use crate::error::ApiError;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web;
+use futures::FutureExt;
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A handler that panics is answered with a 500 (code `internal_error`)
+// rather than taking its worker down, and counted. A few panics are bugs in
+// single requests; `http.panic_threshold` of them within
+// `http.panic_window_secs` suggest bad global state, so they are logged as a
+// critical alert and, with `http.panic_marks_unready`, /readyz answers 503
+// from then on so the orchestrator restarts the instance cleanly instead of
+// it flapping. Without a `web::Data<Panics>` panics are not caught.
+
+pub struct Panics {
+    threshold: usize,
+    window: Duration,
+    marks_unready: bool,
+    // When the panics within the window happened, oldest first.
+    recent: Mutex<VecDeque<Instant>>,
+    total: AtomicU64,
+    tripped: AtomicBool,
+}
+
+impl Panics {
+    pub fn new(config: &HttpConfig) -> Self {
+        Panics {
+            threshold: config.panic_threshold,
+            window: Duration::from_secs(config.panic_window_secs),
+            marks_unready: config.panic_marks_unready,
+            recent: Mutex::new(VecDeque::new()),
+            total: AtomicU64::new(0),
+            tripped: AtomicBool::new(false),
+        }
+    }
+
+    // Counts a panic at `now`, raising the alert once the threshold is met.
+    fn record(&self, now: Instant) {
+        let total = self.total.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        while recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= self.window)
+        {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+        if recent.len() < self.threshold || self.tripped.load(Ordering::Relaxed) {
+            return;
+        }
+        tracing::error!(
+            alert = "critical",
+            panics = recent.len(),
+            window = ?self.window,
+            total,
+            marks_unready = self.marks_unready,
+            "handlers keep panicking"
+        );
+        if self.marks_unready {
+            self.tripped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Whether the instance should keep receiving traffic.
+    pub fn ready(&self) -> bool {
+        !self.tripped.load(Ordering::Relaxed)
+    }
+}
+
+// Catches a panic in the rest of the request.
+pub async fn catch(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(panics) = req.app_data::<web::Data<Panics>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    // Routing needs the request unshared, so the path is kept instead.
+    let path = req.path().to_string();
+    match AssertUnwindSafe(next.call(req)).catch_unwind().await {
+        Ok(res) => res.map(ServiceResponse::map_into_boxed_body),
+        Err(_) => {
+            panics.record(Instant::now());
+            Err(ApiError::Internal(format!("handler for {} panicked", path)).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn panics(threshold: usize, marks_unready: bool) -> Panics {
+        Panics::new(&HttpConfig {
+            panic_threshold: threshold,
+            panic_window_secs: 60,
+            panic_marks_unready: marks_unready,
+            ..HttpConfig::default()
+        })
+    }
+
+    #[test]
+    fn only_panics_within_the_window_count() {
+        let panics = panics(3, true);
+        let start = Instant::now();
+        panics.record(start);
+        panics.record(start + Duration::from_secs(30));
+        panics.record(start + Duration::from_secs(61));
+        assert!(panics.ready());
+        panics.record(start + Duration::from_secs(62));
+        assert!(!panics.ready());
+    }
+
+    #[test]
+    fn the_alert_alone_leaves_readiness_up() {
+        let panics = panics(1, false);
+        panics.record(Instant::now());
+        assert!(panics.ready());
+    }
+
+    #[actix_web::test]
+    async fn repeated_panics_take_the_instance_out_of_readiness() {
+        let panics = web::Data::new(panics(3, true));
+        let app = init_service(
+            App::new()
+                .app_data(panics.clone())
+                .route(
+                    "/boom",
+                    web::get().to(|| async {
+                        panic!("bad global state");
+                        #[allow(unreachable_code)]
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .wrap(from_fn(catch)),
+        )
+        .await;
+        let boom = || async {
+            let req = TestRequest::get().uri("/boom").to_request();
+            let error = try_call_service(&app, req).await.err().unwrap();
+            error.as_response_error().status_code()
+        };
+        for _ in 0..2 {
+            assert_eq!(boom().await, 500);
+            assert!(panics.ready());
+        }
+        assert_eq!(boom().await, 500);
+        assert!(!panics.ready());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::api_keys::{hash_secret, new_secret};
+use crate::emails;
+use crate::error::{ApiError, Problem};
+use crate::login;
+use crate::models::User;
+use crate::observe;
+use crate::sessions;
+use crate::repository::ColumnWrite;
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use crate::validation;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// POST /password/forgot stores a reset token for the user with the email in
+// `password_resets`, by the SHA-256 of the token as email verifications are,
+// and POST /password/reset trades the token for a new password. The token is
+// deleted with a lightweight transaction before the password is written, so
+// it resets a password once however many requests race with it. A reset
+// ends every session of the user, so whoever knew the old password is signed
+// out too.
+//
+// Until there is mail delivery, tokens are only logged, at debug level.
+//
+// Tokens expire after `http.password_reset_ttl_secs`, or with their user if
+// it expires first. POST /password/forgot answers the same whether or not the
+// email is registered, so it can't be used to find out which ones are.
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResetPassword {
+    /// The token issued by POST /password/forgot.
+    pub token: String,
+    pub password: String,
+}
+
+// The TTL of a token for `user`: the configured one, unless the user expires
+// sooner.
+fn token_ttl(state: &AppState, user: &User) -> i32 {
+    let ttl = i32::try_from(state.password_reset_ttl.as_secs()).unwrap_or(i32::MAX);
+    match users::ttl(user.expires_at) {
+        0 => ttl,
+        left => ttl.min(left),
+    }
+}
+
+// The live user registered with `email`, if any.
+async fn live_user(state: &AppState, email: &str) -> Result<Option<User>, ApiError> {
+    let Some(user_id) = emails::owner(state, email).await? else {
+        return Ok(None);
+    };
+    Ok(match users::stored_row(state, user_id).await? {
+        Some((user, false)) => Some(user),
+        _ => None,
+    })
+}
+
+async fn issue(state: &AppState, user: &User) -> Result<String, ApiError> {
+    let token = new_secret();
+    let values = (hash_secret(&token), user.id, Utc::now(), token_ttl(state, user));
+    observe::query(state, "insert_password_reset", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.insert_password_reset, &values)
+    })
+    .await?;
+    Ok(token)
+}
+
+// The user a live token was issued for.
+async fn lookup(state: &AppState, token_hash: &str) -> Result<Option<Uuid>, ApiError> {
+    let result = observe::query(state, "select_password_reset", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_password_reset, (token_hash,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading reset token", e))?
+        .maybe_first_row::<(Uuid,)>()
+        .map_err(|e| ApiError::internal("Error reading reset token", e))?;
+    Ok(row.map(|(user_id,)| user_id))
+}
+
+// Deletes the token, returning whether this call was the one that did.
+async fn consume(state: &AppState, token_hash: &str) -> Result<bool, ApiError> {
+    let result = observe::conditional(state, "consume_password_reset", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.consume_password_reset, (token_hash,))
+    })
+    .await?;
+    statements::applied(result).map_err(|e| ApiError::internal("Failed to consume token", e))
+}
+
+/// Issues a single-use token for resetting the password of the user with
+/// this email. The answer is the same whether or not the email is registered.
+#[utoipa::path(
+    post,
+    path = "/password/forgot",
+    request_body = ForgotPassword,
+    responses(
+        (status = 202, description = "A token was issued if the email is registered", body = String),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn forgot_password(
+    request: web::Json<ForgotPassword>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(user) = live_user(&data, request.email.trim()).await? {
+        let token = issue(&data, &user).await?;
+        tracing::debug!(user_id = %user.id, %token, "password reset token issued");
+    }
+    Ok(HttpResponse::Accepted()
+        .json("If the email is registered, a password reset token has been issued"))
+}
+
+/// Sets a new password with a token from POST /password/forgot. A token works
+/// once.
+#[utoipa::path(
+    post,
+    path = "/password/reset",
+    request_body = ResetPassword,
+    responses(
+        (status = 200, description = "Password changed", body = String),
+        (status = 404, description = "Unknown, used or expired token", body = Problem),
+        (status = 422, description = "Invalid password", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn reset_password(
+    request: web::Json<ResetPassword>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let ResetPassword { token, password } = request.into_inner();
+    let password = validation::password(password)?;
+    let not_found = || ApiError::NotFound(String::from("Reset token not found or expired"));
+    let token_hash = hash_secret(&token);
+    let user_id = lookup(&data, &token_hash).await?.ok_or_else(not_found)?;
+    if !consume(&data, &token_hash).await? {
+        return Err(not_found());
+    }
+    let user = match users::stored_row(&data, user_id).await? {
+        Some((user, false)) => user,
+        _ => return Err(not_found()),
+    };
+
+    let password_hash = login::hash_password_blocking(password)
+        .await
+        .map_err(|e| ApiError::internal("Failed to hash password", e))?;
+    // The email condition keeps a user deleted meanwhile from coming back as
+    // a row holding only a password.
+    let write = ColumnWrite::Password { email: &user.email, hash: &password_hash };
+    let (applied, _) = data.users.write_column(user_id, user.expires_at, write).await?;
+    if !applied {
+        return Err(not_found());
+    }
+    tracing::info!(%user_id, "password reset");
+    if let Err(e) = sessions::revoke_all(&data, user_id).await {
+        tracing::warn!(%user_id, error = %e, "failed to end sessions after a password reset");
+    }
+    Ok(HttpResponse::Ok().json(format!("Password of user {} reset", user_id)))
+}
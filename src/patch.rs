@@ -0,0 +1,297 @@
+use crate::error::{ApiError, FieldError};
+use crate::models::{Profile, UpdateUser};
+use crate::negotiate::Body;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use futures::future::LocalBoxFuture;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+// PATCH /update/{id} takes its changes as the usual partial `UpdateUser`, as
+// a JSON Patch (RFC 6902) or as a JSON Merge Patch (RFC 7396), told apart by
+// the Content-Type. Both patch forms are translated into an `UpdateUser`, so
+// they set the same columns through the same checks. Only what an
+// `UpdateUser` can say is supported: setting the name, the email, the phone
+// number and profile fields. Removing a value, and the `move` and `copy` operations,
+// are refused with 422, as are paths to anything else. The `version` the
+// write expects (see `UpdateUser`) is a `test` of `/version` in a JSON Patch
+// and a `version` member in a Merge Patch; `test` on other paths is refused.
+
+pub const JSON_PATCH_TYPE: &str = "application/json-patch+json";
+pub const MERGE_PATCH_TYPE: &str = "application/merge-patch+json";
+
+/// One operation of a JSON Patch; only `add` and `replace` are supported.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PatchOperation {
+    pub op: String,
+    /// A JSON Pointer such as `/name` or `/profile/bio`.
+    pub path: String,
+    pub value: Option<Value>,
+}
+
+fn error(path: &[&str], message: &str) -> FieldError {
+    FieldError {
+        field: path.join("."),
+        message: message.to_string(),
+    }
+}
+
+fn text(path: &[&str], value: Value) -> Result<String, FieldError> {
+    match value {
+        Value::String(text) => Ok(text),
+        Value::Null => Err(error(path, "cannot be removed")),
+        _ => Err(error(path, "must be a string")),
+    }
+}
+
+fn profile_field<'a>(profile: &'a mut Profile, field: &str) -> Option<&'a mut Option<String>> {
+    match field {
+        "bio" => Some(&mut profile.bio),
+        "avatar_url" => Some(&mut profile.avatar_url),
+        "locale" => Some(&mut profile.locale),
+        "timezone" => Some(&mut profile.timezone),
+        _ => None,
+    }
+}
+
+// The expected version in a patch.
+fn expected_version(path: &[&str], value: Value) -> Result<i32, FieldError> {
+    value
+        .as_i64()
+        .and_then(|version| i32::try_from(version).ok())
+        .ok_or_else(|| error(path, "must be an integer"))
+}
+
+// Sets the field at `path` (`name`, `profile`, `profile.bio`, ...) of
+// `update` to `value`. A profile object sets the fields it has, as a merge.
+fn set(update: &mut UpdateUser, path: &[&str], value: Value, errors: &mut Vec<FieldError>) {
+    let result = match path {
+        ["name"] => text(path, value).map(|name| update.name = Some(name)),
+        ["email"] => text(path, value).map(|email| update.email = Some(email)),
+        ["phone"] => text(path, value).map(|phone| update.phone = Some(phone)),
+        ["profile"] => match value {
+            Value::Object(fields) => {
+                for (field, value) in fields {
+                    set(update, &["profile", &field], value, errors);
+                }
+                Ok(())
+            }
+            Value::Null => Err(error(path, "cannot be removed")),
+            _ => Err(error(path, "must be an object")),
+        },
+        ["profile", field] => match text(path, value) {
+            Ok(value) => {
+                let profile = update.profile.get_or_insert_with(Profile::default);
+                match profile_field(profile, field) {
+                    Some(slot) => {
+                        *slot = Some(value);
+                        Ok(())
+                    }
+                    None => Err(error(path, "is not a profile field")),
+                }
+            }
+            Err(e) => Err(e),
+        },
+        _ => Err(error(path, "is not a field that can be changed")),
+    };
+    if let Err(e) = result {
+        errors.push(e);
+    }
+}
+
+fn finish(update: UpdateUser, errors: Vec<FieldError>) -> Result<UpdateUser, ApiError> {
+    if errors.is_empty() {
+        Ok(update)
+    } else {
+        Err(ApiError::Validation(errors))
+    }
+}
+
+// The reference tokens of a JSON Pointer, unescaped; `None` unless it starts
+// with `/`.
+fn pointer(path: &str) -> Option<Vec<String>> {
+    let tokens = path.strip_prefix('/')?;
+    Some(
+        tokens
+            .split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+    )
+}
+
+pub fn from_json_patch(operations: Vec<PatchOperation>) -> Result<UpdateUser, ApiError> {
+    let mut update = UpdateUser {
+        name: None,
+        email: None,
+        phone: None,
+        profile: None,
+        version: None,
+    };
+    let mut errors = Vec::new();
+    for operation in operations {
+        let Some(tokens) = pointer(&operation.path) else {
+            errors.push(error(&[&operation.path], "is not a JSON Pointer to a field"));
+            continue;
+        };
+        let path: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        match (operation.op.as_str(), operation.value) {
+            ("add" | "replace", Some(value)) => set(&mut update, &path, value, &mut errors),
+            ("add" | "replace" | "test", None) => errors.push(error(&path, "needs a value")),
+            ("test", Some(value)) if path == ["version"] => match expected_version(&path, value) {
+                Ok(version) => update.version = Some(version),
+                Err(e) => errors.push(e),
+            },
+            ("remove", _) => errors.push(error(&path, "cannot be removed")),
+            (op, _) => errors.push(error(&path, &format!("unsupported operation \"{}\"", op))),
+        }
+    }
+    finish(update, errors)
+}
+
+pub fn from_merge_patch(patch: Map<String, Value>) -> Result<UpdateUser, ApiError> {
+    let mut update = UpdateUser {
+        name: None,
+        email: None,
+        phone: None,
+        profile: None,
+        version: None,
+    };
+    let mut errors = Vec::new();
+    for (field, value) in patch {
+        if field == "version" {
+            match expected_version(&["version"], value) {
+                Ok(version) => update.version = Some(version),
+                Err(e) => errors.push(e),
+            }
+            continue;
+        }
+        set(&mut update, &[&field], value, &mut errors);
+    }
+    finish(update, errors)
+}
+
+// The changes a PATCH asks for, in whichever of the three forms it sent them.
+pub struct Changes(pub UpdateUser);
+
+impl FromRequest for Changes {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        match req.content_type() {
+            JSON_PATCH_TYPE => {
+                let body = Body::<Vec<PatchOperation>>::from_request(req, payload);
+                Box::pin(async move { Ok(Changes(from_json_patch(body.await?.0)?)) })
+            }
+            MERGE_PATCH_TYPE => {
+                let body = Body::<Map<String, Value>>::from_request(req, payload);
+                Box::pin(async move { Ok(Changes(from_merge_patch(body.await?.0)?)) })
+            }
+            _ => {
+                let body = Body::<UpdateUser>::from_request(req, payload);
+                Box::pin(async move { Ok(Changes(body.await?.0)) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn operations(patch: Value) -> Vec<PatchOperation> {
+        serde_json::from_value(patch).unwrap()
+    }
+
+    fn field_errors(result: Result<UpdateUser, ApiError>) -> Vec<(String, String)> {
+        match result {
+            Err(ApiError::Validation(errors)) => errors.into_iter().map(|e| (e.field, e.message)).collect(),
+            other => panic!("expected a validation error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn json_patches_set_the_fields_they_name() {
+        let update = from_json_patch(operations(json!([
+            { "op": "replace", "path": "/name", "value": "Ada" },
+            { "op": "add", "path": "/profile/bio", "value": "Countess" },
+            { "op": "replace", "path": "/profile", "value": { "locale": "en-GB" } },
+        ])))
+        .unwrap();
+        assert_eq!(update.name.as_deref(), Some("Ada"));
+        assert_eq!(update.email, None);
+        let profile = update.profile.unwrap();
+        assert_eq!(profile.bio.as_deref(), Some("Countess"));
+        assert_eq!(profile.locale.as_deref(), Some("en-GB"));
+        assert_eq!(profile.timezone, None);
+    }
+
+    #[test]
+    fn json_patches_refuse_what_an_update_cannot_say() {
+        let errors = field_errors(from_json_patch(operations(json!([
+            { "op": "remove", "path": "/profile/bio" },
+            { "op": "test", "path": "/name", "value": "Ada" },
+            { "op": "replace", "path": "/id", "value": "x" },
+            { "op": "replace", "path": "/profile/age", "value": "36" },
+            { "op": "replace", "path": "/email", "value": 7 },
+            { "op": "add", "path": "/name" },
+            { "op": "replace", "path": "name", "value": "Ada" },
+        ]))));
+        let fields: Vec<&str> = errors.iter().map(|(field, _)| field.as_str()).collect();
+        assert_eq!(fields, ["profile.bio", "name", "id", "profile.age", "email", "name", "name"]);
+        assert_eq!(errors[1].1, "unsupported operation \"test\"");
+    }
+
+    #[test]
+    fn the_expected_version_is_a_test_or_a_member() {
+        let update = from_json_patch(operations(json!([
+            { "op": "test", "path": "/version", "value": 4 },
+            { "op": "replace", "path": "/name", "value": "Ada" },
+        ])))
+        .unwrap();
+        assert_eq!((update.version, update.name.as_deref()), (Some(4), Some("Ada")));
+        let patch = json!({ "name": "Ada", "version": 4 });
+        let update = from_merge_patch(serde_json::from_value(patch).unwrap()).unwrap();
+        assert_eq!(update.version, Some(4));
+
+        let errors = field_errors(from_json_patch(operations(json!([
+            { "op": "test", "path": "/version", "value": "4" },
+            { "op": "replace", "path": "/version", "value": 5 },
+        ]))));
+        assert_eq!(
+            errors,
+            [
+                (String::from("version"), String::from("must be an integer")),
+                (String::from("version"), String::from("is not a field that can be changed")),
+            ]
+        );
+    }
+
+    #[test]
+    fn pointers_are_unescaped() {
+        assert_eq!(pointer("/profile/bio"), Some(vec![String::from("profile"), String::from("bio")]));
+        assert_eq!(pointer("/a~1b~0c"), Some(vec![String::from("a/b~c")]));
+        assert_eq!(pointer("name"), None);
+    }
+
+    #[test]
+    fn merge_patches_merge_into_the_profile() {
+        let patch = json!({ "email": "ada@example.com", "profile": { "timezone": "Europe/London" } });
+        let update = from_merge_patch(serde_json::from_value(patch).unwrap()).unwrap();
+        assert_eq!(update.email.as_deref(), Some("ada@example.com"));
+        assert_eq!(update.profile.unwrap().timezone.as_deref(), Some("Europe/London"));
+
+        let patch = json!({ "name": null, "profile": { "bio": null }, "roles": ["admin"] });
+        let errors = field_errors(from_merge_patch(serde_json::from_value(patch).unwrap()));
+        assert_eq!(
+            errors,
+            [
+                (String::from("name"), String::from("cannot be removed")),
+                (String::from("profile.bio"), String::from("cannot be removed")),
+                (String::from("roles"), String::from("is not a field that can be changed")),
+            ]
+        );
+    }
+}
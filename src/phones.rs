@@ -0,0 +1,87 @@
+use crate::dry_run;
+use crate::error::ApiError;
+use crate::observe;
+use crate::state::AppState;
+use crate::users;
+use chrono::{DateTime, Utc};
+use scylla::frame::response::result::{CqlValue, Row};
+use uuid::Uuid;
+
+// Phone numbers are unique the way emails are: a number belongs to whichever
+// user first claims it in `users_by_phone` with a lightweight transaction.
+// Numbers are stored in E.164 form (see `validation::phone`), so the stored
+// number is the claim key. The column came with its claim table, so unlike
+// emails there are no unclaimed numbers to fall back on.
+
+// Claims `phone` for `user_id`, or fails with 409 if another user holds it.
+// Claiming a number the user already holds succeeds. The claim of a user who
+// expires at `expires_at` expires with it. In a dry run the number is only
+// checked.
+pub async fn claim(
+    state: &AppState,
+    phone: &str,
+    user_id: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let conflict = || ApiError::Conflict(format!("phone {} is already registered", phone));
+    if dry_run::active() {
+        return match owner(state, phone).await? {
+            Some(owner) if owner != user_id => Err(conflict()),
+            _ => Ok(()),
+        };
+    }
+    let ttl = users::ttl(expires_at);
+    let result = observe::conditional(state, "claim_phone", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.claim_phone, (phone, user_id, ttl))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to claim phone", e))?
+        .first_row::<Row>()
+        .map_err(|e| ApiError::internal("Failed to claim phone", e))?;
+    // A rejected LWT echoes the existing row after `[applied]`.
+    match row.columns.as_slice() {
+        [Some(CqlValue::Boolean(true)), ..] => Ok(()),
+        [Some(CqlValue::Boolean(false)), .., Some(CqlValue::Uuid(owner))] if *owner == user_id => {
+            Ok(())
+        }
+        [Some(CqlValue::Boolean(false)), ..] => Err(conflict()),
+        other => Err(ApiError::Internal(format!("unexpected claim_phone result: {:?}", other))),
+    }
+}
+
+// Releases `phone` if `user_id` still holds it. Failures are only logged: a
+// leftover claim blocks the number for other users but loses no data.
+pub async fn release(state: &AppState, phone: &str, user_id: Uuid) {
+    if dry_run::active() {
+        return;
+    }
+    if let Err(e) = observe::conditional(state, "release_phone", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.release_phone, (phone, user_id))
+    })
+    .await
+    {
+        tracing::warn!(%user_id, error = %e, "failed to release phone claim");
+    }
+}
+
+// The user holding `phone`, an E.164 number, if it has been claimed.
+pub async fn owner(state: &AppState, phone: &str) -> Result<Option<Uuid>, ApiError> {
+    let result = observe::query(state, "select_phone_owner", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_phone_owner, (phone,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to look up phone", e))?
+        .maybe_first_row::<(Option<Uuid>,)>()
+        .map_err(|e| ApiError::internal("Failed to look up phone", e))?;
+    Ok(row.and_then(|(user_id,)| user_id))
+}
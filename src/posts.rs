@@ -0,0 +1,300 @@
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, FieldError, Problem};
+use crate::models::{NewPost, Post, PostsPage, PostsQuery};
+use crate::negotiate::Body;
+use crate::observe;
+use crate::paging::{self, CursorKind, CursorScope};
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use crate::validation;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use scylla::frame::value::CqlTimeuuid;
+use scylla::DeserializeRow;
+use uuid::{Builder, Uuid};
+
+// Users write posts, a sub-resource of the user: POST /users/{id}/posts
+// adds one, GET /users/{id}/posts pages through them newest first and
+// DELETE /users/{id}/posts/{post_id} removes one. Anyone signed in can read
+// a user's posts; only the user or an admin writes them.
+//
+// Unlike `users`, `posts` is a time series (migration 0031): a partition per
+// user, clustered newest first by a timeuuid. The id carries the time the
+// post was written, so there is no separate timestamp, and a page is read by
+// key: the cursor seals the id of the last post served, and the next page
+// starts below it, however many posts were written since.
+
+// Gregorian (1582-10-15) to Unix epoch, in the 100 ns ticks of a timeuuid.
+const GREGORIAN_OFFSET: u64 = 0x01B2_1DD2_1381_4000;
+
+// A `posts` row as read by `select_posts` and `select_posts_before`.
+#[derive(DeserializeRow)]
+struct PostRow {
+    post_id: CqlTimeuuid,
+    body: Option<String>,
+}
+
+impl PostRow {
+    fn into_post(self, user_id: Uuid) -> Post {
+        let id = Uuid::from(self.post_id);
+        Post {
+            id,
+            user_id,
+            body: self.body.unwrap_or_default(),
+            created_at: created_at(id),
+        }
+    }
+}
+
+// A timeuuid for a post written at `at`. The clock sequence and node are
+// random, the node marked as such by its multicast bit, so posts written in
+// the same tick by different requests or instances get different ids.
+fn post_id(at: DateTime<Utc>) -> Uuid {
+    let nanos = at.timestamp_nanos_opt().unwrap_or_default().max(0) as u64;
+    let ticks = nanos / 100 + GREGORIAN_OFFSET;
+    let mut rng = rand::thread_rng();
+    let mut node = [0u8; 6];
+    rng.fill(&mut node);
+    node[0] |= 0x01;
+    Builder::from_gregorian_timestamp(ticks, rng.gen_range(0..0x4000), &node).into_uuid()
+}
+
+// When the post with the timeuuid `id` was written.
+fn created_at(id: Uuid) -> DateTime<Utc> {
+    id.get_timestamp()
+        .and_then(|timestamp| {
+            let (secs, nanos) = timestamp.to_unix();
+            DateTime::from_timestamp(i64::try_from(secs).ok()?, nanos)
+        })
+        .unwrap_or_default()
+}
+
+fn authorize_for(subject: Option<&Subject>, user_id: Uuid) -> Result<&Subject, AuthError> {
+    auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only write their own posts unless they have the admin role",
+    )
+}
+
+// The page of `posts`, read with one more than `limit` to tell whether
+// another page follows.
+fn page(mut posts: Vec<Post>, limit: usize, scope: &CursorScope) -> PostsPage {
+    let next_cursor = if posts.len() > limit {
+        posts.truncate(limit);
+        posts.last().map(|last| paging::encode_key(scope, last.id.as_bytes()))
+    } else {
+        None
+    };
+    PostsPage { posts, next_cursor }
+}
+
+// Up to `limit` of the user's posts, newest first, starting below `before`
+// when given.
+async fn read(
+    data: &AppState,
+    user_id: Uuid,
+    before: Option<Uuid>,
+    limit: usize,
+) -> Result<Vec<Post>, ApiError> {
+    let limit = i32::try_from(limit).unwrap_or(i32::MAX);
+    let result = match before {
+        None => {
+            observe::query(data, "select_posts", || {
+                data.session
+                    .execute_unpaged(&data.statements.select_posts, (user_id, limit))
+            })
+            .await?
+        }
+        Some(before) => {
+            let values = (user_id, CqlTimeuuid::from(before), limit);
+            observe::query(data, "select_posts_before", || {
+                data.session
+                    .execute_unpaged(&data.statements.select_posts_before, values)
+            })
+            .await?
+        }
+    };
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading posts", e))?
+        .rows::<PostRow>()
+        .map_err(|e| ApiError::internal("Error reading posts", e))?
+        .map(|row| row.map(|row| row.into_post(user_id)))
+        .collect::<Result<_, _>>()
+        .map_err(|e| ApiError::internal("Error reading posts", e))
+}
+
+// Drops all of the user's posts, for a user being purged. Failures are
+// logged rather than failing the purge.
+pub async fn remove(data: &AppState, user_id: Uuid) {
+    if let Err(e) = observe::query(data, "delete_posts", || {
+        data.session
+            .execute_unpaged(&data.statements.delete_posts, (user_id,))
+    })
+    .await
+    {
+        tracing::warn!(%user_id, error = %e, "failed to delete posts");
+    }
+}
+
+/// Publishes a post as the user.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/posts",
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body = NewPost,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 201, description = "The post", body = Post),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 422, description = "Empty or overlong body", body = Problem),
+    )
+)]
+pub async fn create_post(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    Body(new_post): Body<NewPost>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let body = validation::post_body(&new_post.body).map_err(|message| {
+        ApiError::Validation(vec![FieldError {
+            field: String::from("body"),
+            message,
+        }])
+    })?;
+    let user = users::stored_user(&data, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User with ID {} not found", user_id)))?;
+    let id = post_id(Utc::now());
+    let values = (user_id, CqlTimeuuid::from(id), &body, users::ttl(user.expires_at));
+    observe::query(&data, "insert_post", || {
+        data.session.execute_unpaged(&data.statements.insert_post, &values)
+    })
+    .await
+    .map_err(ApiError::from)?;
+    tracing::info!(%user_id, post_id = %id, "post created");
+    Ok(HttpResponse::Created().json(Post {
+        id,
+        user_id,
+        body,
+        created_at: created_at(id),
+    }))
+}
+
+/// The user's posts, newest first, a page at a time.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/posts",
+    params(("id" = Uuid, Path, description = "User id"), PostsQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "A page of the user's posts, newest first", body = PostsPage),
+        (status = 400, description = "Invalid limit or cursor", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+    )
+)]
+pub async fn list_posts(
+    user_id: web::Path<Uuid>,
+    params: web::Query<PostsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user_id.into_inner();
+    let (limit, truncated) = users::page_limit(&data, params.limit)?;
+    let scope = CursorScope::new(CursorKind::Posts, &user_id);
+    let before = paging::decode_key(params.cursor.as_deref(), &scope, data.cursor_max_age)?
+        .map(|key| {
+            Uuid::from_slice(&key).map_err(|_| ApiError::BadRequest(String::from("Invalid cursor")))
+        })
+        .transpose()?;
+    let posts = read(&data, user_id, before, limit + 1).await?;
+    let response = HttpResponse::Ok().json(page(posts, limit, &scope));
+    Ok(users::with_truncated(response, truncated))
+}
+
+/// Deletes one of the user's posts.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/posts/{post_id}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("post_id" = Uuid, Path, description = "Post id"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 204, description = "The post was deleted"),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such post", body = Problem),
+    )
+)]
+pub async fn delete_post(
+    subject: Option<web::ReqData<Subject>>,
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, post_id) = path.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let values = (user_id, CqlTimeuuid::from(post_id));
+    let result = observe::conditional(&data, "delete_post", || {
+        data.session.execute_unpaged(&data.statements.delete_post, values)
+    })
+    .await
+    .map_err(ApiError::from)?;
+    let applied = statements::applied(result)
+        .map_err(|e| ApiError::internal("Failed to delete post", e))?;
+    if !applied {
+        let message = format!("User {} has no post {}", user_id, post_id);
+        return Err(ApiError::NotFound(message).into());
+    }
+    tracing::info!(%user_id, %post_id, "post deleted");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn post(at: DateTime<Utc>) -> Post {
+        let id = post_id(at);
+        Post {
+            id,
+            user_id: Uuid::nil(),
+            body: String::from("Hello"),
+            created_at: created_at(id),
+        }
+    }
+
+    #[test]
+    fn post_ids_are_timeuuids_of_when_they_were_written() {
+        let at: DateTime<Utc> = "2026-10-15T12:00:00.123456700Z".parse().unwrap();
+        let id = post_id(at);
+        assert_eq!(id.get_version_num(), 1);
+        assert_eq!(created_at(id), at);
+        assert_ne!(post_id(at), id);
+    }
+
+    #[test]
+    fn pages_continue_below_their_last_post() {
+        let scope = CursorScope::new(CursorKind::Posts, &Uuid::nil());
+        let now = Utc::now();
+        let posts: Vec<Post> = (0..4).map(|n| post(now - chrono::TimeDelta::seconds(n))).collect();
+        let first = page(posts.clone(), 3, &scope);
+        assert_eq!(first.posts.len(), 3);
+        let cursor = first.next_cursor.unwrap();
+        let key = paging::decode_key(Some(&cursor), &scope, Duration::from_secs(60)).unwrap();
+        assert_eq!(key.as_deref(), Some(posts[2].id.as_bytes().as_slice()));
+        let other = CursorScope::new(CursorKind::Posts, &Uuid::from_u128(1));
+        assert!(paging::decode_key(Some(&cursor), &other, Duration::from_secs(60)).is_err());
+
+        let last = page(posts[3..].to_vec(), 3, &scope);
+        assert_eq!(last.next_cursor, None);
+    }
+}
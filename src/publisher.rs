@@ -0,0 +1,125 @@
+use crate::config::Config;
+#[cfg(feature = "kafka")]
+use crate::kafka::Producer;
+use crate::models::User;
+use crate::nats::Nats;
+use crate::outbox::{OutboxEvent, Sink};
+use crate::webhooks;
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use serde::Serialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// User events for a message broker: with `kafka.enabled` or `nats.enabled`
+// (one or the other), the outbox relay hands each event to a `Publisher`,
+// Kafka's producer or a NATS JetStream publisher. Both send the same
+// `Message`, whose value is the JSON `Record` below and whose `schema`
+// header names its shape; what they differ in is how the user id routes it,
+// as the record key on Kafka and the last token of the subject on NATS.
+
+// Names the shape of the record values, for consumers to check.
+pub const SCHEMA: &str = "hireme.user_event.v1";
+
+/// The value of each message published.
+#[derive(Serialize)]
+struct Record<'a> {
+    schema: &'static str,
+    id: Uuid,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    user_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a User>,
+    occurred_at: DateTime<Utc>,
+}
+
+// An event as a broker is sent it.
+pub struct Message {
+    // The event's id, for brokers that drop repeats.
+    pub id: Uuid,
+    pub user_id: Uuid,
+    // The event kind's name, `created` and so on.
+    pub kind: &'static str,
+    pub value: Vec<u8>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+impl Message {
+    pub fn new(event: &OutboxEvent) -> Result<Self, serde_json::Error> {
+        let value = serde_json::to_vec(&Record {
+            schema: SCHEMA,
+            id: event.id,
+            kind: webhooks::event_type(event.kind),
+            user_id: event.user_id,
+            version: event.version,
+            user: event.user.as_ref(),
+            occurred_at: event.created_at,
+        })?;
+        Ok(Message {
+            id: event.id,
+            user_id: event.user_id,
+            kind: event.kind.name(),
+            value,
+            occurred_at: event.created_at,
+        })
+    }
+
+    // The headers every message carries.
+    pub fn headers(&self) -> [(&'static str, &'static str); 2] {
+        [("schema", SCHEMA), ("content-type", "application/json")]
+    }
+}
+
+pub trait Publisher: Send + Sync {
+    // Sends `message`, succeeding once the broker has it.
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), String>>;
+}
+
+// The publisher `config` turns on, if any. `kafka.enabled` is refused
+// without the `kafka` feature.
+pub fn for_config(config: &Config) -> Option<Arc<dyn Publisher>> {
+    #[cfg(feature = "kafka")]
+    if config.kafka.enabled {
+        return Some(Arc::new(Producer::new(&config.kafka)));
+    }
+    if config.nats.enabled {
+        Some(Arc::new(Nats::new(&config.nats)))
+    } else {
+        None
+    }
+}
+
+// A publisher as one of the outbox relay's sinks.
+pub struct Publishing(pub Arc<dyn Publisher>);
+
+impl Sink for Publishing {
+    fn publish<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let message = Message::new(event).map_err(|e| e.to_string())?;
+            self.0.send(&message).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+
+    #[test]
+    fn records_name_their_schema_and_type() {
+        let event = OutboxEvent::new(EventKind::Restored, Uuid::new_v4(), None, Some(3));
+        let message = Message::new(&event).unwrap();
+        assert_eq!(message.kind, "restored");
+        let record: serde_json::Value = serde_json::from_slice(&message.value).unwrap();
+        assert_eq!(record["schema"], SCHEMA);
+        assert_eq!(record["type"], "user.restored");
+        assert_eq!(record["user_id"], event.user_id.to_string());
+        assert_eq!(record["version"], 3);
+        assert!(record.get("user").is_none());
+    }
+}
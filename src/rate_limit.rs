@@ -0,0 +1,487 @@
+use crate::api_keys;
+use crate::config::RateLimitConfig;
+use crate::error;
+use crate::observe;
+use crate::state::AppState;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::web;
+use chrono::Utc;
+use futures::future::join_all;
+use scylla::frame::value::Counter;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Probes and scrapes must keep working while a client is being throttled.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+// Every this many checks, buckets that have refilled completely are dropped so
+// one-off clients don't accumulate forever.
+const SWEEP_INTERVAL: u64 = 4096;
+
+// How long a verified API key is remembered, so a client presenting one is not
+// looked up on every request; a key revoked meanwhile keeps its own bucket
+// that long, though authentication refuses it at once.
+const VERIFIED_KEY_TTL: Duration = Duration::from_secs(60);
+// Verified keys remembered at most; the memory is cleared when it fills up.
+const MAX_VERIFIED_KEYS: usize = 10_000;
+
+// Partitions each window's counters are spread over in `rate_limits`.
+const COUNTER_SHARDS: i32 = 16;
+
+// Sent on every limited response, after the IETF draft on rate limit
+// headers, so clients can slow down before they are refused: the bucket
+// size, the requests left in it, and the seconds until it is full again.
+pub const LIMIT_HEADER: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const REMAINING_HEADER: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RESET_HEADER: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// A client's bucket after a request has been charged to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u64,
+    // Whole requests left.
+    pub remaining: u64,
+    // Until the bucket has refilled completely.
+    pub reset: Duration,
+}
+
+impl Quota {
+    fn of(bucket: &Bucket, rate: f64, burst: f64) -> Self {
+        Quota {
+            limit: burst as u64,
+            remaining: bucket.tokens.max(0.0) as u64,
+            reset: Duration::from_secs_f64((burst - bucket.tokens).max(0.0) / rate),
+        }
+    }
+
+    // Adds the `RateLimit-*` headers describing this quota to `headers`.
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        let reset = self.reset.as_secs_f64().ceil() as u64;
+        headers.insert(LIMIT_HEADER, HeaderValue::from(self.limit));
+        headers.insert(REMAINING_HEADER, HeaderValue::from(self.remaining));
+        headers.insert(RESET_HEADER, HeaderValue::from(reset));
+    }
+}
+
+// A request refused because the client's bucket was empty.
+#[derive(Debug)]
+pub struct Throttled {
+    pub quota: Quota,
+    // Until the bucket holds a request again.
+    pub retry_after: Duration,
+}
+
+// The window `now` (seconds since the epoch) falls in, numbered from the
+// epoch, and the time left until the next one starts.
+fn window_at(now: i64, window_secs: u64) -> (i64, Duration) {
+    let window_secs = window_secs as i64;
+    let left = window_secs - now.rem_euclid(window_secs);
+    (now.div_euclid(window_secs), Duration::from_secs(left as u64))
+}
+
+// The `rate_limits` partition of `client` within a window. Derived from a
+// digest rather than `Hash` so that every instance picks the same one.
+fn counter_shard(client: &str) -> i32 {
+    let digest = Sha256::digest(client.as_bytes());
+    i32::from(u16::from_be_bytes([digest[0], digest[1]])) % COUNTER_SHARDS
+}
+
+// Presented keys are remembered by digest, so secrets never sit in memory.
+fn key_digest(presented: &str) -> String {
+    Sha256::digest(presented.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub struct RateLimiter {
+    // Tokens per second and bucket size, replaced on a configuration reload.
+    limits: RwLock<(f64, f64)>,
+    trust_forwarded_for: bool,
+    state: Mutex<(HashMap<String, Bucket>, u64)>,
+    // Key ids by the SHA-256 of the presented key, until they expire.
+    verified_keys: Mutex<HashMap<String, (Uuid, Instant)>>,
+    // The window length when requests are also counted in Scylla.
+    distributed: Option<u64>,
+    // The latest window this instance has counted in, whose arrival purges
+    // the counters of the one before last.
+    counted_window: AtomicI64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            limits: RwLock::new((config.requests_per_second, f64::from(config.burst))),
+            trust_forwarded_for: config.trust_forwarded_for,
+            state: Mutex::new((HashMap::new(), 0)),
+            verified_keys: Mutex::new(HashMap::new()),
+            distributed: config.distributed.then_some(config.window_secs),
+            counted_window: AtomicI64::new(i64::MIN),
+        }
+    }
+
+    // Applies a reloaded `rate_limit`; buckets keep their tokens, up to the
+    // new burst.
+    pub fn set_limits(&self, config: &RateLimitConfig) {
+        *self.limits.write().unwrap() = (config.requests_per_second, f64::from(config.burst));
+    }
+
+    // Takes one token from `client`'s bucket, or fails with how long until
+    // one is available.
+    fn acquire(&self, client: String) -> Result<Quota, Throttled> {
+        let (rate, burst) = *self.limits.read().unwrap();
+        let now = Instant::now();
+        let mut guard = self.state.lock().unwrap();
+        let (buckets, checks) = &mut *guard;
+
+        *checks += 1;
+        if *checks % SWEEP_INTERVAL == 0 {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(Quota::of(bucket, rate, burst))
+        } else {
+            Err(Throttled {
+                quota: Quota::of(bucket, rate, burst),
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+            })
+        }
+    }
+
+    // Charges a request to `client` in its local bucket and, when limits
+    // are distributed and `state` is at hand, in the shared count as well.
+    async fn acquire_everywhere(
+        &self,
+        state: Option<&AppState>,
+        client: String,
+    ) -> Result<Quota, Throttled> {
+        let local = self.acquire(client.clone())?;
+        match (self.distributed, state) {
+            (Some(window_secs), Some(state)) => {
+                self.acquire_shared(state, &client, window_secs, local).await
+            }
+            _ => Ok(local),
+        }
+    }
+
+    // Counts a request by `client` in the current window of `rate_limits`
+    // and refuses it once the client has made more than the window allows
+    // across all instances. Without Scylla the request is let through on
+    // the local bucket alone, rather than failing everything while it is
+    // down. The count is never retried, as a retry may count twice.
+    async fn acquire_shared(
+        &self,
+        state: &AppState,
+        client: &str,
+        window_secs: u64,
+        local: Quota,
+    ) -> Result<Quota, Throttled> {
+        let (window, reset) = window_at(Utc::now().timestamp(), window_secs);
+        self.purge_before(state, window).await;
+        let key = (window, counter_shard(client), client);
+        let counted = async {
+            observe::conditional(state, "count_request", || {
+                state.session.execute_unpaged(&state.statements.count_request, &key)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            let result = observe::query(state, "select_request_count", || {
+                state.session.execute_unpaged(&state.statements.select_request_count, &key)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            let row = result
+                .into_rows_result()
+                .map_err(|e| e.to_string())?
+                .maybe_first_row::<(Option<Counter>,)>()
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(row.and_then(|(count,)| count).map_or(0, |Counter(count)| count))
+        }
+        .await;
+        let count = match counted {
+            Ok(count) => count.max(0) as u64,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to count a request across instances");
+                return Ok(local);
+            }
+        };
+        let (rate, _) = *self.limits.read().unwrap();
+        let limit = (rate * window_secs as f64).ceil() as u64;
+        let quota = Quota {
+            limit,
+            remaining: limit.saturating_sub(count).min(local.remaining),
+            reset,
+        };
+        if count > limit {
+            Err(Throttled { quota, retry_after: reset })
+        } else {
+            Ok(quota)
+        }
+    }
+
+    // On the first request of a new window, deletes the counters of the
+    // window before last, which no instance counts in any more; counters
+    // can't expire on their own. Another instance may get there first, in
+    // which case the deletes find nothing. Failures are only logged.
+    async fn purge_before(&self, state: &AppState, window: i64) {
+        if self.counted_window.fetch_max(window, Ordering::Relaxed) >= window {
+            return;
+        }
+        let stale = window - 2;
+        let deletes = (0..COUNTER_SHARDS).map(|shard| {
+            observe::query(state, "delete_request_counts", move || {
+                state
+                    .session
+                    .execute_unpaged(&state.statements.delete_request_counts, (stale, shard))
+            })
+        });
+        for result in join_all(deletes).await {
+            if let Err(e) = result {
+                tracing::warn!(error = %e, window = stale, "Failed to purge request counts");
+            }
+        }
+    }
+
+    fn client_ip(&self, req: &ServiceRequest) -> Option<String> {
+        if self.trust_forwarded_for {
+            req.connection_info().realip_remote_addr().map(String::from)
+        } else {
+            req.peer_addr().map(|addr| addr.ip().to_string())
+        }
+    }
+
+    fn remembered_key(&self, digest: &str, now: Instant) -> Option<Uuid> {
+        let mut verified = self.verified_keys.lock().unwrap();
+        match verified.get(digest) {
+            Some(&(key_id, expires)) if expires > now => Some(key_id),
+            Some(_) => {
+                verified.remove(digest);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn remember_key(&self, digest: String, key_id: Uuid, now: Instant) {
+        let mut verified = self.verified_keys.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED_KEYS {
+            verified.clear();
+        }
+        verified.insert(digest, (key_id, now + VERIFIED_KEY_TTL));
+    }
+
+    // Takes a token for a caller at `ip` presenting `api_key`. Callers with a
+    // verified API key are limited by its id, everyone else by IP: an
+    // unverified key must not buy a fresh bucket. A key not verified recently
+    // is looked up in `state`, and that request is charged to the IP, so
+    // made-up keys cost their sender's bucket before they cost a query. Also
+    // used by the gRPC listener.
+    pub async fn acquire_caller(
+        &self,
+        state: Option<&AppState>,
+        api_key: Option<&str>,
+        ip: Option<String>,
+    ) -> Result<Quota, Throttled> {
+        let ip_key = format!("ip:{}", ip.unwrap_or_default());
+        let Some(presented) = api_key else {
+            return self.acquire_everywhere(state, ip_key).await;
+        };
+        let now = Instant::now();
+        let digest = key_digest(presented);
+        if let Some(key_id) = self.remembered_key(&digest, now) {
+            return self.acquire_everywhere(state, format!("key:{}", key_id)).await;
+        }
+        let quota = self.acquire_everywhere(state, ip_key).await?;
+        if let Some(state) = state
+            && let Ok((key_id, _)) = api_keys::live_key(state, presented).await
+        {
+            self.remember_key(digest, key_id, now);
+        }
+        Ok(quota)
+    }
+
+    async fn acquire_for(&self, req: &ServiceRequest) -> Result<Quota, Throttled> {
+        let api_key = req
+            .headers()
+            .get("X-API-Key")
+            .and_then(|value| value.to_str().ok());
+        let state = req.app_data::<web::Data<AppState>>().map(|data| data.get_ref());
+        self.acquire_caller(state, api_key, self.client_ip(req)).await
+    }
+}
+
+// Rejects with 429 and `Retry-After` once a client has used up its bucket,
+// and tells it where its bucket stands in the `RateLimit-*` headers of every
+// response, refusals included. Without a `web::Data<RateLimiter>` (rate
+// limiting disabled) requests pass through without them.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    if EXEMPT_PATHS.contains(&req.path()) {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+    let quota = match limiter.acquire_for(&req).await {
+        Ok(quota) => quota,
+        Err(Throttled { quota, retry_after }) => {
+            let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let mut response = error::problem(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                format!("too many requests, retry in {}s", seconds),
+            );
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+            quota.add_headers(response.headers_mut());
+            return Ok(req.into_response(response));
+        }
+    };
+    let mut response = next.call(req).await?.map_into_boxed_body();
+    quota.add_headers(response.headers_mut());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn limiter(burst: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            enabled: true,
+            requests_per_second: 1.0,
+            burst,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    fn request(ip: &str, api_key: Option<&str>) -> ServiceRequest {
+        let mut req = TestRequest::default().peer_addr(format!("{}:4000", ip).parse().unwrap());
+        if let Some(api_key) = api_key {
+            req = req.insert_header(("X-API-Key", api_key));
+        }
+        req.to_srv_request()
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refuse() {
+        let limiter = limiter(2);
+        assert!(limiter.acquire(String::from("ip:10.0.0.1")).is_ok());
+        assert!(limiter.acquire(String::from("ip:10.0.0.1")).is_ok());
+        let throttled = limiter.acquire(String::from("ip:10.0.0.1")).unwrap_err();
+        let retry_after = throttled.retry_after;
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        assert_eq!((throttled.quota.limit, throttled.quota.remaining), (2, 0));
+        assert!(limiter.acquire(String::from("ip:10.0.0.2")).is_ok());
+    }
+
+    #[test]
+    fn quotas_count_down_the_requests_left_and_the_time_to_refill() {
+        let limiter = limiter(3);
+        let quota = limiter.acquire(String::from("ip:10.0.0.1")).unwrap();
+        assert_eq!((quota.limit, quota.remaining), (3, 2));
+        let quota = limiter.acquire(String::from("ip:10.0.0.1")).unwrap();
+        assert_eq!(quota.remaining, 1);
+        assert!(quota.reset > Duration::from_millis(1900) && quota.reset <= Duration::from_secs(2));
+
+        let mut headers = HeaderMap::new();
+        quota.add_headers(&mut headers);
+        assert_eq!(headers.get(LIMIT_HEADER).unwrap(), "3");
+        assert_eq!(headers.get(REMAINING_HEADER).unwrap(), "1");
+        assert_eq!(headers.get(RESET_HEADER).unwrap(), "2");
+    }
+
+    #[test]
+    fn reloaded_limits_apply_to_existing_buckets() {
+        let limiter = limiter(1);
+        assert!(limiter.acquire(String::from("ip:10.0.0.1")).is_ok());
+        assert!(limiter.acquire(String::from("ip:10.0.0.1")).is_err());
+        limiter.set_limits(&RateLimitConfig {
+            enabled: true,
+            requests_per_second: 1000.0,
+            burst: 5,
+            ..RateLimitConfig::default()
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limiter.acquire(String::from("ip:10.0.0.1")).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn unverified_api_keys_share_the_ip_bucket() {
+        let limiter = limiter(2);
+        for _ in 0..2 {
+            let key = format!("{}.secret", Uuid::new_v4());
+            assert!(limiter.acquire_for(&request("10.0.0.1", Some(&key))).await.is_ok());
+        }
+        let key = format!("{}.secret", Uuid::new_v4());
+        assert!(limiter.acquire_for(&request("10.0.0.1", Some(&key))).await.is_err());
+        assert!(limiter.acquire_for(&request("10.0.0.1", None)).await.is_err());
+        assert!(limiter.verified_keys.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn verified_api_keys_get_their_own_bucket() {
+        let limiter = limiter(1);
+        let key_id = Uuid::new_v4();
+        let presented = format!("{}.secret", key_id);
+        limiter.remember_key(key_digest(&presented), key_id, Instant::now());
+
+        assert!(limiter.acquire_for(&request("10.0.0.1", None)).await.is_ok());
+        assert!(limiter.acquire_for(&request("10.0.0.1", Some(&presented))).await.is_ok());
+        assert!(limiter.acquire_for(&request("10.0.0.1", Some(&presented))).await.is_err());
+    }
+
+    #[test]
+    fn shared_counts_are_kept_per_window_and_client() {
+        assert_eq!(window_at(120, 60), (2, Duration::from_secs(60)));
+        assert_eq!(window_at(179, 60), (2, Duration::from_secs(1)));
+        assert_eq!(window_at(-1, 60), (-1, Duration::from_secs(1)));
+
+        let shards: Vec<i32> = (0..64)
+            .map(|n| counter_shard(&format!("ip:10.0.0.{}", n)))
+            .collect();
+        assert!(shards.iter().all(|shard| (0..COUNTER_SHARDS).contains(shard)));
+        assert!(shards.iter().any(|&shard| shard != shards[0]));
+        assert_eq!(counter_shard("ip:10.0.0.1"), shards[1]);
+    }
+
+    #[test]
+    fn remembered_keys_expire() {
+        let limiter = limiter(1);
+        let key_id = Uuid::new_v4();
+        let now = Instant::now();
+        limiter.remember_key(String::from("digest"), key_id, now);
+        assert_eq!(limiter.remembered_key("digest", now), Some(key_id));
+        assert_eq!(limiter.remembered_key("digest", now + VERIFIED_KEY_TTL), None);
+        assert!(limiter.verified_keys.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::auth::Subject;
+use crate::error::{ApiError, Problem};
+use crate::negotiate::Body;
+use crate::observe::{self, CqlError};
+use crate::paging::{self, CursorKind, CursorScope};
+use crate::state::AppState;
+use crate::users;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use scylla::frame::response::result::{ColumnType, CqlValue, Row};
+use scylla::query::Query;
+use scylla::transport::errors::{DbError, QueryError};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+// POST /admin/cql runs one CQL statement as sent and returns a page of its
+// rows, for operators who can't reach cqlsh. It is off unless
+// `scylla.allow_raw_cql` is set, and even then only admins may call it: the
+// statement runs as the server's database user, so it can drop tables as
+// easily as read them. Every statement run is logged with its caller.
+//
+// The statement is neither prepared, which would fill the driver's cache
+// with one-off texts, nor retried, as it may not be idempotent. Values are
+// written into the text; there are no bound ones. A statement the cluster
+// refuses as invalid is a 400 carrying the cluster's message.
+//
+// Rows come back as objects by column name, in the order of `columns`, with
+// each value in JSON: timestamps, dates and times in ISO 8601, blobs as
+// `0x` hex as cqlsh shows them, decimals and varints too large for a number
+// as strings, and maps with keys other than text as lists of pairs.
+
+/// One CQL statement to run.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CqlRequest {
+    /// The statement, such as `SELECT * FROM users LIMIT 10`. Tables not
+    /// qualified with a keyspace are looked up in the serving keyspace.
+    pub statement: String,
+    /// Rows per page; defaults to `http.default_page_size`.
+    pub page_size: Option<usize>,
+    /// `next_cursor` from the previous page of the same statement.
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CqlColumn {
+    pub name: String,
+    /// The CQL type, as `DESCRIBE` writes it.
+    #[serde(rename = "type")]
+    pub type_name: String,
+}
+
+/// A page of a statement's result. Statements that return no rows, such as
+/// writes and schema changes, have no columns.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CqlRows {
+    pub columns: Vec<CqlColumn>,
+    #[schema(value_type = Vec<Object>)]
+    pub rows: Vec<Map<String, Value>>,
+    /// Absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+// CQL name of a column type, as `cluster::type_name` writes those the driver
+// keeps in its schema metadata.
+fn type_name(column_type: &ColumnType) -> String {
+    match column_type {
+        ColumnType::Custom(class) => class.to_string(),
+        ColumnType::Ascii => String::from("ascii"),
+        ColumnType::Boolean => String::from("boolean"),
+        ColumnType::Blob => String::from("blob"),
+        ColumnType::Counter => String::from("counter"),
+        ColumnType::Date => String::from("date"),
+        ColumnType::Decimal => String::from("decimal"),
+        ColumnType::Double => String::from("double"),
+        ColumnType::Duration => String::from("duration"),
+        ColumnType::Float => String::from("float"),
+        ColumnType::Int => String::from("int"),
+        ColumnType::BigInt => String::from("bigint"),
+        ColumnType::Text => String::from("text"),
+        ColumnType::Timestamp => String::from("timestamp"),
+        ColumnType::Inet => String::from("inet"),
+        ColumnType::List(element) => format!("list<{}>", type_name(element)),
+        ColumnType::Map(key, value) => format!("map<{}, {}>", type_name(key), type_name(value)),
+        ColumnType::Set(element) => format!("set<{}>", type_name(element)),
+        ColumnType::UserDefinedType { type_name, .. } => type_name.to_string(),
+        ColumnType::SmallInt => String::from("smallint"),
+        ColumnType::TinyInt => String::from("tinyint"),
+        ColumnType::Time => String::from("time"),
+        ColumnType::Timeuuid => String::from("timeuuid"),
+        ColumnType::Tuple(elements) => {
+            let elements: Vec<String> = elements.iter().map(type_name).collect();
+            format!("tuple<{}>", elements.join(", "))
+        }
+        ColumnType::Uuid => String::from("uuid"),
+        ColumnType::Varint => String::from("varint"),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let digits: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("0x{}", digits)
+}
+
+// The two's complement big-endian integer in `bytes`, if it fits an i128.
+fn signed(bytes: &[u8]) -> Option<i128> {
+    if bytes.len() > 16 {
+        return None;
+    }
+    let fill = if bytes.first().is_some_and(|byte| byte & 0x80 != 0) { 0xff } else { 0 };
+    let mut buffer = [fill; 16];
+    buffer[16 - bytes.len()..].copy_from_slice(bytes);
+    Some(i128::from_be_bytes(buffer))
+}
+
+// `unscaled` × 10^-`scale` in plain notation, or in E notation for scales
+// beyond an i128's digits.
+fn decimal(unscaled: i128, scale: i32) -> String {
+    if !(0..=38).contains(&scale) {
+        return format!("{}E{}", unscaled, -(scale as i64));
+    }
+    let scale = scale as usize;
+    let sign = if unscaled < 0 { "-" } else { "" };
+    let digits = format!("{:0>width$}", unscaled.unsigned_abs(), width = scale + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    if fraction.is_empty() {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, fraction)
+    }
+}
+
+fn to_json(value: CqlValue) -> Value {
+    let optional = |value: Option<CqlValue>| value.map_or(Value::Null, to_json);
+    match value {
+        CqlValue::Ascii(text) | CqlValue::Text(text) => Value::String(text),
+        CqlValue::Boolean(flag) => Value::Bool(flag),
+        CqlValue::Blob(bytes) => Value::String(hex(&bytes)),
+        CqlValue::Counter(counter) => Value::from(counter.0),
+        CqlValue::Decimal(number) => {
+            let (bytes, scale) = number.as_signed_be_bytes_slice_and_exponent();
+            match signed(bytes) {
+                Some(unscaled) => Value::String(decimal(unscaled, scale)),
+                None => Value::String(format!("{}E{}", hex(bytes), -(scale as i64))),
+            }
+        }
+        CqlValue::Date(date) => match TryInto::<NaiveDate>::try_into(date) {
+            Ok(date) => Value::String(date.to_string()),
+            Err(_) => Value::from(date.0),
+        },
+        CqlValue::Double(number) => Value::from(number),
+        CqlValue::Duration(duration) => Value::String(format!(
+            "{}mo{}d{}ns",
+            duration.months, duration.days, duration.nanoseconds
+        )),
+        CqlValue::Empty => Value::Null,
+        CqlValue::Float(number) => Value::from(number),
+        CqlValue::Int(number) => Value::from(number),
+        CqlValue::BigInt(number) => Value::from(number),
+        CqlValue::Timestamp(timestamp) => match DateTime::from_timestamp_millis(timestamp.0) {
+            Some(at) => Value::String(at.to_rfc3339()),
+            None => Value::from(timestamp.0),
+        },
+        CqlValue::Inet(address) => Value::String(address.to_string()),
+        CqlValue::List(values) | CqlValue::Set(values) => {
+            Value::Array(values.into_iter().map(to_json).collect())
+        }
+        CqlValue::Map(entries) => {
+            let by_text = entries
+                .iter()
+                .all(|(key, _)| matches!(key, CqlValue::Ascii(_) | CqlValue::Text(_)));
+            if by_text {
+                let entries = entries.into_iter().filter_map(|(key, value)| {
+                    Some((key.into_string()?, to_json(value)))
+                });
+                Value::Object(entries.collect())
+            } else {
+                let pairs = entries
+                    .into_iter()
+                    .map(|(key, value)| Value::Array(vec![to_json(key), to_json(value)]));
+                Value::Array(pairs.collect())
+            }
+        }
+        CqlValue::UserDefinedType { fields, .. } => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, optional(value)))
+                .collect(),
+        ),
+        CqlValue::SmallInt(number) => Value::from(number),
+        CqlValue::TinyInt(number) => Value::from(number),
+        CqlValue::Time(time) => {
+            let (seconds, nanos) = (time.0 / 1_000_000_000, time.0 % 1_000_000_000);
+            match NaiveTime::from_num_seconds_from_midnight_opt(seconds as u32, nanos as u32) {
+                Some(time) => Value::String(time.to_string()),
+                None => Value::from(time.0),
+            }
+        }
+        CqlValue::Timeuuid(id) => Value::String(id.to_string()),
+        CqlValue::Tuple(values) => Value::Array(values.into_iter().map(optional).collect()),
+        CqlValue::Uuid(id) => Value::String(id.to_string()),
+        CqlValue::Varint(number) => match signed(number.as_signed_bytes_be_slice()) {
+            Some(number) => match i64::try_from(number) {
+                Ok(number) => Value::from(number),
+                Err(_) => Value::String(number.to_string()),
+            },
+            None => Value::String(hex(number.as_signed_bytes_be_slice())),
+        },
+    }
+}
+
+// Statements the cluster refuses as written are the caller's to fix.
+fn refused(e: CqlError) -> ApiError {
+    match e {
+        CqlError::Query(QueryError::DbError(
+            DbError::SyntaxError
+            | DbError::Invalid
+            | DbError::AlreadyExists { .. }
+            | DbError::Unauthorized
+            | DbError::ConfigError
+            | DbError::FunctionFailure { .. },
+            message,
+        )) => ApiError::BadRequest(format!("The cluster refused the statement: {}", message)),
+        CqlError::Query(QueryError::BadQuery(e)) => ApiError::BadRequest(e.to_string()),
+        e => e.into(),
+    }
+}
+
+/// Runs one CQL statement and returns a page of its rows. Served only with
+/// `scylla.allow_raw_cql`.
+#[utoipa::path(
+    post,
+    path = "/admin/cql",
+    request_body = CqlRequest,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The statement ran; a page of its rows", body = CqlRows),
+        (status = 400, description = "Empty or refused statement, invalid page size or cursor", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+        (status = 404, description = "`scylla.allow_raw_cql` is off", body = Problem),
+    )
+)]
+pub async fn run_cql(
+    subject: Option<web::ReqData<Subject>>,
+    Body(request): Body<CqlRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if !data.allow_raw_cql {
+        return Err(ApiError::NotFound(String::from(
+            "Raw CQL is disabled; set scylla.allow_raw_cql to enable it",
+        )));
+    }
+    let statement = request.statement.trim();
+    if statement.is_empty() {
+        return Err(ApiError::BadRequest(String::from("statement is empty")));
+    }
+    let (page_size, truncated) = users::page_limit(&data, request.page_size)?;
+    let scope = CursorScope::new(CursorKind::RawCql, &statement);
+    let paging_state =
+        paging::decode_cursor(request.cursor.as_deref(), &scope, data.cursor_max_age)?;
+
+    let actor = subject.as_ref().map_or("anonymous", |subject| subject.id.as_str());
+    tracing::info!(actor, statement, "running raw CQL");
+    let mut query = Query::new(statement);
+    query.set_page_size(page_size as i32);
+    let (result, paging_response) = observe::conditional(&data, "raw_cql", || {
+        data.session
+            .query_single_page(query.clone(), &[], paging_state.clone())
+    })
+    .await
+    .map_err(refused)?;
+
+    let mut page = CqlRows {
+        columns: Vec::new(),
+        rows: Vec::new(),
+        next_cursor: paging::encode_cursor(&scope, paging_response),
+    };
+    if result.is_rows() {
+        let result = result
+            .into_rows_result()
+            .map_err(|e| ApiError::internal("Failed to read rows", e))?;
+        page.columns = result
+            .column_specs()
+            .iter()
+            .map(|spec| CqlColumn {
+                name: spec.name().to_string(),
+                type_name: type_name(spec.typ()),
+            })
+            .collect();
+        for row in result
+            .rows::<Row>()
+            .map_err(|e| ApiError::internal("Failed to read rows", e))?
+        {
+            let row = row.map_err(|e| ApiError::internal("Failed to read rows", e))?;
+            let values = page.columns.iter().zip(row.columns).map(|(column, value)| {
+                (column.name.clone(), value.map_or(Value::Null, to_json))
+            });
+            page.rows.push(values.collect());
+        }
+    }
+    Ok(users::with_truncated(HttpResponse::Ok().json(page), truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scylla::frame::value::{CqlDecimal, CqlTimestamp, CqlVarint};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn types_are_named_as_in_cql() {
+        let text = || Box::new(ColumnType::Text);
+        let map = ColumnType::Map(text(), Box::new(ColumnType::Int));
+        assert_eq!(type_name(&map), "map<text, int>");
+        assert_eq!(
+            type_name(&ColumnType::Tuple(vec![ColumnType::Uuid, ColumnType::Set(text())])),
+            "tuple<uuid, set<text>>"
+        );
+    }
+
+    #[test]
+    fn decimals_keep_their_digits() {
+        assert_eq!(decimal(12345, 2), "123.45");
+        assert_eq!(decimal(-5, 3), "-0.005");
+        assert_eq!(decimal(7, 0), "7");
+        assert_eq!(decimal(7, -2), "7E2");
+        assert_eq!(signed(&[0xff, 0x38]), Some(-200));
+        assert_eq!(signed(&[0; 17]), None);
+        let number = CqlDecimal::from_signed_be_bytes_slice_and_exponent(&[0x30, 0x39], 2);
+        assert_eq!(to_json(CqlValue::Decimal(number)), json!("123.45"));
+    }
+
+    #[test]
+    fn values_are_rendered_as_json() {
+        let id = Uuid::from_u128(7);
+        assert_eq!(to_json(CqlValue::Uuid(id)), json!(id.to_string()));
+        assert_eq!(to_json(CqlValue::Blob(vec![0xca, 0xfe])), json!("0xcafe"));
+        assert_eq!(
+            to_json(CqlValue::Timestamp(CqlTimestamp(0))),
+            json!("1970-01-01T00:00:00+00:00")
+        );
+        let big = CqlVarint::from_signed_bytes_be(vec![0x01, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(to_json(CqlValue::Varint(big)), json!("18446744073709551616"));
+        let tags = CqlValue::Map(vec![(CqlValue::Text(String::from("crm")), CqlValue::Int(1))]);
+        assert_eq!(to_json(tags), json!({ "crm": 1 }));
+        let by_int = CqlValue::Map(vec![(CqlValue::Int(1), CqlValue::Text(String::from("a")))]);
+        assert_eq!(to_json(by_int), json!([[1, "a"]]));
+        assert_eq!(
+            to_json(CqlValue::Tuple(vec![Some(CqlValue::Boolean(true)), None])),
+            json!([true, null])
+        );
+    }
+
+    #[test]
+    fn invalid_statements_are_the_callers_to_fix() {
+        let syntax = QueryError::DbError(DbError::SyntaxError, String::from("line 1:0 no viable"));
+        assert!(matches!(refused(CqlError::Query(syntax)), ApiError::BadRequest(_)));
+        let overloaded = QueryError::DbError(DbError::Overloaded, String::from("busy"));
+        assert!(!matches!(refused(CqlError::Query(overloaded)), ApiError::BadRequest(_)));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::config::Config;
+use crate::error::{ApiError, Problem};
+use crate::logging::{self, LogLevel};
+use crate::rate_limit::RateLimiter;
+use crate::state::AppState;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+// Reloads part of the configuration without a restart, on SIGHUP or
+// POST /admin/reload: the file (and environment) is read and checked again as
+// at startup, and if it is valid these settings take effect:
+//
+// - `log.level`, unless `RUST_LOG` is set;
+// - `rate_limit.requests_per_second` and `burst`, when rate limiting is on;
+// - `flags.disabled`;
+// - `cache.ttl_secs` and `cache.redis_ttl_secs`, for entries written from
+//   then on; tenants' caches keep theirs.
+//
+// Everything else, the Scylla session included, stays as it started; an
+// invalid file changes nothing.
+
+pub struct Reloader {
+    log_level: LogLevel,
+    // Absent with rate limiting off.
+    rate_limiter: Option<web::Data<RateLimiter>>,
+}
+
+/// The reloadable settings as a reload left them.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Reloaded {
+    /// The log filter directives in effect.
+    pub log_level: String,
+    /// Absent with rate limiting off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_second: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    pub disabled_flags: Vec<String>,
+    pub cache_ttl_secs: u64,
+    pub redis_ttl_secs: u64,
+}
+
+impl Reloader {
+    pub fn new(log_level: LogLevel, rate_limiter: Option<web::Data<RateLimiter>>) -> Self {
+        Reloader {
+            log_level,
+            rate_limiter,
+        }
+    }
+
+    pub fn reload(&self, state: &AppState) -> Result<Reloaded, String> {
+        let config = Config::load().map_err(|e| e.to_string())?;
+        let log_level = logging::set_level(&self.log_level, &config.log)?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.set_limits(&config.rate_limit);
+        }
+        state.flags.set_disabled(&config.flags);
+        state.user_cache.set_ttl(Duration::from_secs(config.cache.ttl_secs));
+        if let Some(shared) = &state.shared_cache {
+            shared.set_ttl(Duration::from_secs(config.cache.redis_ttl_secs));
+        }
+        tracing::info!(log_level = %log_level, "configuration reloaded");
+        Ok(Reloaded {
+            log_level,
+            requests_per_second: self
+                .rate_limiter
+                .as_ref()
+                .map(|_| config.rate_limit.requests_per_second),
+            burst: self.rate_limiter.as_ref().map(|_| config.rate_limit.burst),
+            disabled_flags: config.flags.disabled,
+            cache_ttl_secs: config.cache.ttl_secs,
+            redis_ttl_secs: config.cache.redis_ttl_secs,
+        })
+    }
+}
+
+// Reloads on every SIGHUP for as long as the process runs.
+#[cfg(unix)]
+pub fn on_hangup(reloader: web::Data<Reloader>, state: AppState) -> std::io::Result<()> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup())?;
+    actix_web::rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reloader.reload(&state) {
+                tracing::error!(error = %e, "cannot reload the configuration, nothing changed");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads the configuration again and applies the settings that can change
+/// while the service runs: the log level, rate limits, disabled feature flags
+/// and cache TTLs. The rest only changes with a restart.
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The configuration was reloaded", body = Reloaded),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "Reloading is not available in this server", body = Problem),
+        (status = 409, description = "The configuration is invalid; nothing changed", body = Problem),
+    )
+)]
+pub async fn reload_config(
+    reloader: Option<web::Data<Reloader>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    // Only the server binary reloads; an application embedding the API has
+    // its own configuration.
+    let reloader = reloader.ok_or_else(|| {
+        ApiError::NotFound(String::from("This server doesn't reload its configuration"))
+    })?;
+    let reloaded = reloader.reload(&data).map_err(|e| {
+        ApiError::Conflict(format!("Cannot reload the configuration, nothing changed: {}", e))
+    })?;
+    Ok(HttpResponse::Ok().json(reloaded))
+}
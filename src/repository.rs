@@ -0,0 +1,901 @@
+use crate::addresses;
+use crate::breaker::Breaker;
+use crate::cql::Condition;
+use crate::error::ApiError;
+use crate::limiter::Limiter;
+use crate::metrics::Metrics;
+use crate::models::{Address, UpdateUser, User, UserStatus};
+use crate::observe::{self, CqlError};
+use crate::retry::RetryPolicy;
+use crate::statements::{self, Statements};
+use crate::users::{self, CredentialsRow, NewUserRow, Registration, TimestampsRow, UserRow};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryStreamExt};
+use scylla::batch::{Batch, BatchType};
+use scylla::frame::response::result::CqlValue;
+use scylla::prepared_statement::PreparedStatement;
+use scylla::transport::errors::QueryError;
+use scylla::{QueryResult, Session};
+#[cfg(test)]
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::RwLock;
+use uuid::Uuid;
+
+// Storage of the `users` rows themselves, behind the user operations in
+// `users`: reading a user by id and the writes that create, change,
+// soft-delete, restore and delete one, along with the writes of single
+// columns (`ColumnWrite`) made by verification, password resets, statuses,
+// roles, addresses, tags and metadata. `ScyllaUsers` runs them as CQL;
+// `MemoryUsers` keeps the rows in a map, for tests that have no cluster.
+// Writes are conditional the way the CQL ones are, and report whether they
+// applied rather than failing, so the callers' 404s and 412s don't depend on
+// the backend.
+//
+// The derived tables (email claims, the name index), listings and scans stay
+// with their modules: they are CQL-specific and read through paging states.
+
+// A result with the ids of the tracing sessions recorded for it; only
+// `ScyllaUsers` records any, and only when asked to.
+pub type Traced<T> = (T, Vec<Uuid>);
+
+pub type Outcome<'a, T> = BoxFuture<'a, Result<Traced<T>, ApiError>>;
+
+// What a conditional write expects to find.
+#[derive(Debug, Clone, Copy)]
+pub enum Expect<'a> {
+    // Any stored row.
+    Exists,
+    // The row as `before` was read: writes through the API all move
+    // updated_at, so a concurrent one makes the write fail.
+    Unchanged(&'a User),
+}
+
+// A write of one column of a live user's row, beside the fields `update`
+// and `replace` write. Each is a lightweight transaction: on the user
+// existing, or on the condition given.
+#[derive(Debug, Clone, Copy)]
+pub enum ColumnWrite<'a> {
+    // Marks the user verified while it is live with `email`.
+    Verify { email: &'a str },
+    // Sets the password hash while the user is live with `email`, so a user
+    // deleted meanwhile doesn't come back as a row holding only a password.
+    Password { email: &'a str, hash: &'a str },
+    // Moves a live user from `from` to `to`.
+    Status { from: UserStatus, to: UserStatus },
+    Roles { roles: &'a [String], at: DateTime<Utc> },
+    AddAddress(&'a Address),
+    RemoveAddress(&'a Address),
+    AddTag(&'a str),
+    RemoveTag(&'a str),
+    SetMetadata { key: &'a str, value: &'a str },
+    DeleteMetadata { key: &'a str },
+}
+
+impl ColumnWrite<'_> {
+    // The statement that makes the write, as metrics and dual write
+    // failures name it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnWrite::Verify { .. } => "verify_user",
+            ColumnWrite::Password { .. } => "reset_password",
+            ColumnWrite::Status { .. } => "set_user_status",
+            ColumnWrite::Roles { .. } => "update_user_roles",
+            ColumnWrite::AddAddress(_) => "append_user_address",
+            ColumnWrite::RemoveAddress(_) => "remove_user_address",
+            ColumnWrite::AddTag(_) => "add_user_tag",
+            ColumnWrite::RemoveTag(_) => "remove_user_tag",
+            ColumnWrite::SetMetadata { .. } => "set_user_metadata",
+            ColumnWrite::DeleteMetadata { .. } => "delete_user_metadata",
+        }
+    }
+}
+
+// One change to `users` made by a batch (see `batch`), which writes the rows
+// through the session itself so they are atomic with its derived rows.
+pub enum BatchWrite {
+    Insert(Registration),
+    Update { before: User, update: UpdateUser },
+    SoftDelete(User),
+}
+
+pub trait UserRepository: Send + Sync {
+    // The user with `id` even when it is soft-deleted, along with whether
+    // it is.
+    fn get(&self, id: Uuid, tracing: bool) -> Outcome<'_, Option<(User, bool)>>;
+
+    // The password hash of the live user with `id`, if it has one.
+    fn password_hash(&self, id: Uuid) -> Outcome<'_, Option<String>>;
+
+    // Whether the live user with `id` exists and, if so, when it last
+    // changed, without reading the rest of the row. Users stored before
+    // timestamps were recorded exist with no time.
+    fn modified(&self, id: Uuid) -> Outcome<'_, Option<Option<DateTime<Utc>>>>;
+
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+        password_hash: Option<&'a str>,
+        tracing: bool,
+    ) -> Outcome<'a, ()>;
+
+    // Inserts new users together, as `write_behind` does; all or none of
+    // them are stored.
+    fn insert_batch<'a>(&'a self, registrations: &'a [Registration]) -> Outcome<'a, ()>;
+
+    // Applies `update` to the user `before` was read from as
+    // `users::apply_update` does, moving updated_at to `at` and the version
+    // to the one after `before`'s. Writes take the TTL of the user's
+    // `expires_at`, as the stored row does; see `users::ttl`.
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        update: &'a UpdateUser,
+        at: DateTime<Utc>,
+        expect: Expect<'a>,
+        tracing: bool,
+    ) -> Outcome<'a, bool>;
+
+    // Writes the name, email, profile, updated_at and version of `user` over
+    // the stored row with its id.
+    fn replace<'a>(&'a self, user: &'a User, expect: Expect<'a>, tracing: bool) -> Outcome<'a, bool>;
+
+    // Writes the placeholder name and email of `user`, as
+    // `users::anonymized` made it, over the stored row with its id, clearing
+    // the phone, profile, password hash, addresses and metadata, and moves
+    // updated_at, version and anonymized_at.
+    fn anonymize<'a>(&'a self, user: &'a User, expect: Expect<'a>) -> Outcome<'a, bool>;
+
+    fn soft_delete<'a>(
+        &'a self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        expect: Expect<'a>,
+        tracing: bool,
+    ) -> Outcome<'a, bool>;
+
+    // Clears the soft delete of a stored user, moving updated_at to `at`.
+    fn restore(
+        &self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        tracing: bool,
+    ) -> Outcome<'_, bool>;
+
+    fn delete<'a>(&'a self, id: Uuid, expect: Expect<'a>, tracing: bool) -> Outcome<'a, bool>;
+
+    // Makes `write` to the user with `id`, with the TTL of its `expires_at`,
+    // reporting whether it applied.
+    fn write_column<'a>(
+        &'a self,
+        id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        write: ColumnWrite<'a>,
+    ) -> Outcome<'a, bool>;
+
+    // Applies the changes of a batch that went through at `at` to any copy
+    // of the users kept beside this store; only `ShadowUsers` keeps one.
+    fn mirror_batch<'a>(
+        &'a self,
+        _writes: &'a [BatchWrite],
+        _at: DateTime<Utc>,
+    ) -> BoxFuture<'a, ()> {
+        futures::future::ready(()).boxed()
+    }
+}
+
+// Values of the condition of a write that must find the row as it was read,
+// `IF email = ? AND updated_at = ? AND version = ?`, bound after the key. The
+// email can't be null on a stored row, so it also fails once the row is gone.
+fn unchanged_values(before: &User) -> [Option<CqlValue>; 3] {
+    [
+        Some(CqlValue::Text(before.email.clone())),
+        before.updated_at.map(|updated_at| CqlValue::Timestamp(updated_at.into())),
+        before.version.map(CqlValue::Int),
+    ]
+}
+
+// The same condition for a statement built with `cql`.
+fn unchanged(before: &User) -> Condition {
+    let [email, updated_at, version] = unchanged_values(before);
+    Condition::Equal(vec![("email", email), ("updated_at", updated_at), ("version", version)])
+}
+
+fn applied(result: QueryResult, context: &str) -> Result<Traced<bool>, ApiError> {
+    let tracing_ids = result.tracing_id().into_iter().collect();
+    let applied = statements::applied(result).map_err(|e| ApiError::internal(context, e))?;
+    Ok((applied, tracing_ids))
+}
+
+pub struct ScyllaUsers {
+    session: Arc<Session>,
+    statements: Arc<Statements>,
+    keyspace: String,
+    metrics: Arc<Metrics>,
+    retry: Arc<RetryPolicy>,
+    breaker: Arc<Breaker>,
+    limiter: Arc<Limiter>,
+}
+
+impl ScyllaUsers {
+    pub fn new(
+        session: Arc<Session>,
+        statements: Arc<Statements>,
+        keyspace: String,
+        metrics: Arc<Metrics>,
+        retry: Arc<RetryPolicy>,
+        breaker: Arc<Breaker>,
+        limiter: Arc<Limiter>,
+    ) -> Self {
+        ScyllaUsers {
+            session,
+            statements,
+            keyspace,
+            metrics,
+            retry,
+            breaker,
+            limiter,
+        }
+    }
+
+    // Runs a lightweight transaction, reporting whether it applied.
+    async fn query<T, Fut>(
+        &self,
+        statement_name: &'static str,
+        request: impl FnMut() -> Fut,
+    ) -> Result<T, CqlError>
+    where
+        Fut: Future<Output = Result<T, QueryError>>,
+    {
+        observe::query_with(
+            &self.metrics,
+            &self.retry,
+            &self.breaker,
+            &self.limiter,
+            statement_name,
+            request,
+        )
+        .await
+    }
+
+    async fn conditional(
+        &self,
+        statement_name: &'static str,
+        query: &PreparedStatement,
+        values: &[Option<CqlValue>],
+    ) -> Result<Traced<bool>, ApiError> {
+        let result = observe::conditional_with(
+            &self.metrics,
+            &self.retry,
+            &self.breaker,
+            &self.limiter,
+            statement_name,
+            || self.session.execute_unpaged(query, values),
+        )
+        .await?;
+        applied(result, "Failed to write user")
+    }
+}
+
+impl UserRepository for ScyllaUsers {
+    // Read row-by-row from a pager, like the other reads of `users`.
+    fn get(&self, id: Uuid, tracing: bool) -> Outcome<'_, Option<(User, bool)>> {
+        async move {
+            let query = users::for_request(&self.statements.select_user_by_id, tracing);
+            let pager = self.query("select_user_by_id", || {
+                self.session.execute_iter(query.clone(), (id,))
+            })
+            .await?;
+            let mut rows = pager
+                .rows_stream::<UserRow>()
+                .map_err(|e| ApiError::internal("Error streaming rows", e))?;
+            let row = rows
+                .try_next()
+                .await
+                .map_err(|e| ApiError::internal("Error fetching next row", e))?;
+            let user = row.map(|row| {
+                let deleted = row.is_deleted();
+                (row.into_user(), deleted)
+            });
+            Ok((user, rows.tracing_ids().to_vec()))
+        }
+        .boxed()
+    }
+
+    fn password_hash(&self, id: Uuid) -> Outcome<'_, Option<String>> {
+        async move {
+            let result = self.query("select_credentials_by_id", || {
+                self.session
+                    .execute_unpaged(&self.statements.select_credentials_by_id, (id,))
+            })
+            .await?;
+            let row = result
+                .into_rows_result()
+                .map_err(|e| ApiError::internal("Error reading credentials", e))?
+                .maybe_first_row::<CredentialsRow>()
+                .map_err(|e| ApiError::internal("Error reading credentials", e))?;
+            let hash = row.and_then(CredentialsRow::live_hash);
+            Ok((hash, Vec::new()))
+        }
+        .boxed()
+    }
+
+    fn modified(&self, id: Uuid) -> Outcome<'_, Option<Option<DateTime<Utc>>>> {
+        async move {
+            let result = self.query("select_user_timestamps", || {
+                self.session
+                    .execute_unpaged(&self.statements.select_user_timestamps, (id,))
+            })
+            .await?;
+            let row = result
+                .into_rows_result()
+                .map_err(|e| ApiError::internal("Error reading user", e))?
+                .maybe_first_row::<TimestampsRow>()
+                .map_err(|e| ApiError::internal("Error reading user", e))?;
+            let modified = row.and_then(TimestampsRow::modified);
+            Ok((modified, Vec::new()))
+        }
+        .boxed()
+    }
+
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+        password_hash: Option<&'a str>,
+        tracing: bool,
+    ) -> Outcome<'a, ()> {
+        async move {
+            let query = users::for_request(&self.statements.insert_user, tracing);
+            let row = NewUserRow::new(&self.keyspace, user, password_hash);
+            let result = self
+                .query("insert_user", || self.session.execute_unpaged(&query, &row))
+                .await?;
+            Ok(((), result.tracing_id().into_iter().collect()))
+        }
+        .boxed()
+    }
+
+    // An unlogged batch: the users are different partitions, so it saves
+    // round trips rather than adding atomicity, and a failed batch is
+    // reported whole.
+    fn insert_batch<'a>(&'a self, registrations: &'a [Registration]) -> Outcome<'a, ()> {
+        async move {
+            let mut batch = Batch::new(BatchType::Unlogged);
+            let mut values = Vec::with_capacity(registrations.len());
+            for Registration { user, password_hash } in registrations {
+                batch.append_statement(self.statements.insert_user.clone());
+                values.push(NewUserRow::new(&self.keyspace, user, password_hash.as_deref()));
+            }
+            self.query("insert_user_batch", || self.session.batch(&batch, &values))
+                .await?;
+            Ok(((), Vec::new()))
+        }
+        .boxed()
+    }
+
+    // IF EXISTS keeps an update of an unknown id from upserting a new row;
+    // `unchanged` does that too.
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        update: &'a UpdateUser,
+        at: DateTime<Utc>,
+        expect: Expect<'a>,
+        tracing: bool,
+    ) -> Outcome<'a, bool> {
+        async move {
+            let condition = match expect {
+                Expect::Exists => Condition::Exists,
+                Expect::Unchanged(before) => unchanged(before),
+            };
+            let (query, values) =
+                users::update_statement(&self.keyspace, update, before, at, condition);
+            let prepared = self.statements.get_or_prepare(&self.session, query).await?;
+            self.conditional("update_user", &users::for_request(&prepared, tracing), &values)
+                .await
+        }
+        .boxed()
+    }
+
+    fn replace<'a>(&'a self, user: &'a User, expect: Expect<'a>, tracing: bool) -> Outcome<'a, bool> {
+        async move {
+            let mut values = vec![
+                Some(CqlValue::Int(users::ttl(user.expires_at))),
+                Some(CqlValue::Text(user.name.clone())),
+                Some(CqlValue::Text(user.email.clone())),
+                user.phone.clone().map(CqlValue::Text),
+                user.profile
+                    .as_ref()
+                    .map(|profile| users::profile_value(&self.keyspace, profile)),
+                user.updated_at.map(|at| CqlValue::Timestamp(at.into())),
+                user.version.map(CqlValue::Int),
+                Some(CqlValue::Uuid(user.id)),
+            ];
+            match expect {
+                Expect::Exists => {
+                    let query = users::for_request(&self.statements.replace_user, tracing);
+                    self.conditional("replace_user", &query, &values).await
+                }
+                Expect::Unchanged(before) => {
+                    values.extend(unchanged_values(before));
+                    let query = users::for_request(&self.statements.replace_user_if_unchanged, tracing);
+                    self.conditional("replace_user_if_unchanged", &query, &values)
+                        .await
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn anonymize<'a>(&'a self, user: &'a User, expect: Expect<'a>) -> Outcome<'a, bool> {
+        async move {
+            let mut values = vec![
+                Some(CqlValue::Int(users::ttl(user.expires_at))),
+                Some(CqlValue::Text(user.name.clone())),
+                Some(CqlValue::Text(user.email.clone())),
+                user.updated_at.map(|at| CqlValue::Timestamp(at.into())),
+                user.version.map(CqlValue::Int),
+                user.anonymized_at.map(|at| CqlValue::Timestamp(at.into())),
+                Some(CqlValue::Uuid(user.id)),
+            ];
+            match expect {
+                Expect::Exists => {
+                    self.conditional("anonymize_user", &self.statements.anonymize_user, &values)
+                        .await
+                }
+                Expect::Unchanged(before) => {
+                    values.extend(unchanged_values(before));
+                    let query = &self.statements.anonymize_user_if_unchanged;
+                    self.conditional("anonymize_user_if_unchanged", query, &values)
+                        .await
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn soft_delete<'a>(
+        &'a self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        expect: Expect<'a>,
+        tracing: bool,
+    ) -> Outcome<'a, bool> {
+        async move {
+            let mut values = vec![
+                Some(CqlValue::Int(users::ttl(expires_at))),
+                Some(CqlValue::Timestamp(at.into())),
+                Some(CqlValue::Uuid(id)),
+            ];
+            match expect {
+                Expect::Exists => {
+                    let query = users::for_request(&self.statements.soft_delete_user, tracing);
+                    self.conditional("soft_delete_user", &query, &values).await
+                }
+                Expect::Unchanged(before) => {
+                    values.extend(unchanged_values(before));
+                    let query = users::for_request(&self.statements.soft_delete_user_if_unchanged, tracing);
+                    self.conditional("soft_delete_user_if_unchanged", &query, &values)
+                        .await
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn restore(
+        &self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        tracing: bool,
+    ) -> Outcome<'_, bool> {
+        async move {
+            let query = users::for_request(&self.statements.restore_user, tracing);
+            let values = [
+                Some(CqlValue::Int(users::ttl(expires_at))),
+                Some(CqlValue::Timestamp(at.into())),
+                Some(CqlValue::Uuid(id)),
+            ];
+            self.conditional("restore_user", &query, &values).await
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, id: Uuid, expect: Expect<'a>, tracing: bool) -> Outcome<'a, bool> {
+        async move {
+            let mut values = vec![Some(CqlValue::Uuid(id))];
+            match expect {
+                Expect::Exists => {
+                    let query = users::for_request(&self.statements.delete_user, tracing);
+                    self.conditional("delete_user", &query, &values).await
+                }
+                Expect::Unchanged(before) => {
+                    values.extend(unchanged_values(before));
+                    let query = users::for_request(&self.statements.delete_user_if_unchanged, tracing);
+                    self.conditional("delete_user_if_unchanged", &query, &values)
+                        .await
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn write_column<'a>(
+        &'a self,
+        id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        write: ColumnWrite<'a>,
+    ) -> Outcome<'a, bool> {
+        async move {
+            let statements = &self.statements;
+            let ttl = Some(CqlValue::Int(users::ttl(expires_at)));
+            let key = Some(CqlValue::Uuid(id));
+            let text = |value: &str| Some(CqlValue::Text(value.to_string()));
+            let (query, values) = match write {
+                ColumnWrite::Verify { email } => {
+                    (&statements.verify_user, vec![ttl, key, text(email)])
+                }
+                ColumnWrite::Password { email, hash } => {
+                    (&statements.reset_password, vec![ttl, text(hash), key, text(email)])
+                }
+                ColumnWrite::Status { from, to } => {
+                    // Users written before statuses are active with a null
+                    // status.
+                    let expected = match from {
+                        UserStatus::Active => [None, text(from.as_str())],
+                        _ => [text(from.as_str()), text(from.as_str())],
+                    };
+                    let [first, second] = expected;
+                    (&statements.set_user_status, vec![ttl, text(to.as_str()), key, first, second])
+                }
+                ColumnWrite::Roles { roles, at } => {
+                    let roles = roles.iter().map(|role| CqlValue::Text(role.clone())).collect();
+                    let at = Some(CqlValue::Timestamp(at.into()));
+                    (&statements.update_user_roles, vec![ttl, Some(CqlValue::Set(roles)), at, key])
+                }
+                ColumnWrite::AddAddress(address) | ColumnWrite::RemoveAddress(address) => {
+                    let list = addresses::list_value(&self.keyspace, std::slice::from_ref(address));
+                    let query = match write {
+                        ColumnWrite::AddAddress(_) => &statements.append_user_address,
+                        _ => &statements.remove_user_address,
+                    };
+                    (query, vec![ttl, Some(list), key])
+                }
+                ColumnWrite::AddTag(tag) | ColumnWrite::RemoveTag(tag) => {
+                    let set = Some(CqlValue::Set(vec![CqlValue::Text(tag.to_string())]));
+                    let query = match write {
+                        ColumnWrite::AddTag(_) => &statements.add_user_tag,
+                        _ => &statements.remove_user_tag,
+                    };
+                    (query, vec![ttl, set, key])
+                }
+                ColumnWrite::SetMetadata { key: name, value } => {
+                    (&statements.set_user_metadata, vec![ttl, text(name), text(value), key])
+                }
+                ColumnWrite::DeleteMetadata { key: name } => {
+                    (&statements.delete_user_metadata, vec![text(name), key])
+                }
+            };
+            self.conditional(write.name(), query, &values).await
+        }
+        .boxed()
+    }
+}
+
+// A stored user: the user, its password hash and when it was soft-deleted.
+#[cfg(test)]
+struct MemoryRow {
+    user: User,
+    password_hash: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+// Users kept in a map, checking the same conditions as the CQL writes.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryUsers {
+    rows: RwLock<HashMap<Uuid, MemoryRow>>,
+}
+
+#[cfg(test)]
+impl MemoryUsers {
+    // Runs `write` on the row with `id` if it meets `expect`, reporting
+    // whether it did.
+    fn write(&self, id: Uuid, expect: Expect<'_>, write: impl FnOnce(&mut MemoryRow)) -> bool {
+        let mut rows = self.rows.write().unwrap();
+        let Some(row) = rows.get_mut(&id) else {
+            return false;
+        };
+        if let Expect::Unchanged(before) = expect
+            && (row.user.email != before.email
+                || row.user.updated_at != before.updated_at
+                || row.user.version != before.version)
+        {
+            return false;
+        }
+        write(row);
+        true
+    }
+}
+
+#[cfg(test)]
+fn untraced<T>(value: T) -> Result<Traced<T>, ApiError> {
+    Ok((value, Vec::new()))
+}
+
+#[cfg(test)]
+impl UserRepository for MemoryUsers {
+    fn get(&self, id: Uuid, _tracing: bool) -> Outcome<'_, Option<(User, bool)>> {
+        let rows = self.rows.read().unwrap();
+        let user = rows
+            .get(&id)
+            .map(|row| (row.user.clone(), row.deleted_at.is_some()));
+        futures::future::ready(untraced(user)).boxed()
+    }
+
+    fn password_hash(&self, id: Uuid) -> Outcome<'_, Option<String>> {
+        let rows = self.rows.read().unwrap();
+        let hash = rows
+            .get(&id)
+            .filter(|row| row.deleted_at.is_none())
+            .and_then(|row| row.password_hash.clone());
+        futures::future::ready(untraced(hash)).boxed()
+    }
+
+    fn modified(&self, id: Uuid) -> Outcome<'_, Option<Option<DateTime<Utc>>>> {
+        let rows = self.rows.read().unwrap();
+        let modified = rows
+            .get(&id)
+            .filter(|row| row.deleted_at.is_none())
+            .map(|row| row.user.updated_at.or(row.user.created_at));
+        futures::future::ready(untraced(modified)).boxed()
+    }
+
+    // Like a CQL INSERT, this overwrites a row with the same id.
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+        password_hash: Option<&'a str>,
+        _tracing: bool,
+    ) -> Outcome<'a, ()> {
+        self.rows.write().unwrap().insert(
+            user.id,
+            MemoryRow {
+                user: user.clone(),
+                password_hash: password_hash.map(String::from),
+                deleted_at: None,
+            },
+        );
+        futures::future::ready(untraced(())).boxed()
+    }
+
+    fn insert_batch<'a>(&'a self, registrations: &'a [Registration]) -> Outcome<'a, ()> {
+        for Registration { user, password_hash } in registrations {
+            self.rows.write().unwrap().insert(
+                user.id,
+                MemoryRow {
+                    user: user.clone(),
+                    password_hash: password_hash.clone(),
+                    deleted_at: None,
+                },
+            );
+        }
+        futures::future::ready(untraced(())).boxed()
+    }
+
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        update: &'a UpdateUser,
+        at: DateTime<Utc>,
+        expect: Expect<'a>,
+        _tracing: bool,
+    ) -> Outcome<'a, bool> {
+        let applied = self.write(before.id, expect, |row| {
+            row.user = users::apply_update(&row.user, update, at)
+        });
+        futures::future::ready(untraced(applied)).boxed()
+    }
+
+    fn replace<'a>(&'a self, user: &'a User, expect: Expect<'a>, _tracing: bool) -> Outcome<'a, bool> {
+        let applied = self.write(user.id, expect, |row| {
+            row.user = User {
+                created_at: row.user.created_at,
+                ..user.clone()
+            }
+        });
+        futures::future::ready(untraced(applied)).boxed()
+    }
+
+    fn anonymize<'a>(&'a self, user: &'a User, expect: Expect<'a>) -> Outcome<'a, bool> {
+        let applied = self.write(user.id, expect, |row| {
+            row.user = user.clone();
+            row.password_hash = None;
+        });
+        futures::future::ready(untraced(applied)).boxed()
+    }
+
+    fn soft_delete<'a>(
+        &'a self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        _expires_at: Option<DateTime<Utc>>,
+        expect: Expect<'a>,
+        _tracing: bool,
+    ) -> Outcome<'a, bool> {
+        let applied = self.write(id, expect, |row| row.deleted_at = Some(at));
+        futures::future::ready(untraced(applied)).boxed()
+    }
+
+    fn restore(
+        &self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        _expires_at: Option<DateTime<Utc>>,
+        _tracing: bool,
+    ) -> Outcome<'_, bool> {
+        let applied = self.write(id, Expect::Exists, |row| {
+            row.deleted_at = None;
+            row.user.updated_at = Some(at);
+        });
+        futures::future::ready(untraced(applied)).boxed()
+    }
+
+    fn delete<'a>(&'a self, id: Uuid, expect: Expect<'a>, _tracing: bool) -> Outcome<'a, bool> {
+        let applied = self.write(id, expect, |_| {})
+            && self.rows.write().unwrap().remove(&id).is_some();
+        futures::future::ready(untraced(applied)).boxed()
+    }
+
+    // Only the columns `User` and `MemoryRow` hold are kept; the others are
+    // written as long as the user exists.
+    fn write_column<'a>(
+        &'a self,
+        id: Uuid,
+        _expires_at: Option<DateTime<Utc>>,
+        write: ColumnWrite<'a>,
+    ) -> Outcome<'a, bool> {
+        let mut rows = self.rows.write().unwrap();
+        let applied = rows.get_mut(&id).is_some_and(|row| {
+            let live = row.deleted_at.is_none();
+            match write {
+                ColumnWrite::Verify { email } if live && row.user.email == email => {
+                    row.user.verified = Some(true);
+                    true
+                }
+                ColumnWrite::Password { email, hash } if live && row.user.email == email => {
+                    row.password_hash = Some(hash.to_string());
+                    true
+                }
+                ColumnWrite::Status { from, to }
+                    if live && row.user.status.unwrap_or_default() == from =>
+                {
+                    row.user.status = Some(to);
+                    true
+                }
+                ColumnWrite::Verify { .. }
+                | ColumnWrite::Password { .. }
+                | ColumnWrite::Status { .. } => false,
+                _ => true,
+            }
+        });
+        futures::future::ready(untraced(applied)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Profile;
+
+    fn user(name: &str) -> User {
+        let now = Utc::now();
+        User {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            email: format!("{}@example.com", name.to_lowercase()),
+            phone: None,
+            profile: None,
+            created_at: Some(now),
+            updated_at: Some(now),
+            expires_at: None,
+            verified: None,
+            version: Some(1),
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    fn rename(name: &str) -> UpdateUser {
+        UpdateUser {
+            name: Some(name.to_string()),
+            email: None,
+            phone: None,
+            profile: None,
+            version: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn memory_users_round_trip() {
+        let store = MemoryUsers::default();
+        let ada = user("Ada");
+        assert!(store.get(ada.id, false).await.unwrap().0.is_none());
+
+        store.insert(&ada, Some("hash"), false).await.unwrap();
+        assert_eq!(store.password_hash(ada.id).await.unwrap().0.as_deref(), Some("hash"));
+        let (stored, tracing_ids) = store.get(ada.id, true).await.unwrap();
+        assert_eq!(stored.map(|(user, deleted)| (user.name, deleted)), Some((ada.name.clone(), false)));
+        assert!(tracing_ids.is_empty());
+
+        let later = Utc::now();
+        let renamed = store.update(&ada, &rename("Ada L"), later, Expect::Exists, false).await;
+        assert!(renamed.unwrap().0);
+        let (renamed, _) = store.get(ada.id, false).await.unwrap().0.unwrap();
+        assert_eq!((renamed.name.as_str(), renamed.updated_at), ("Ada L", Some(later)));
+        assert_eq!(renamed.version, ada.version.map(|version| version + 1));
+        let nobody = user("Nobody");
+        let missing = store.update(&nobody, &rename("Nobody"), later, Expect::Exists, false).await;
+        assert!(!missing.unwrap().0);
+    }
+
+    #[actix_web::test]
+    async fn memory_replacements_keep_only_the_creation_time() {
+        let store = MemoryUsers::default();
+        let mut ada = user("Ada");
+        ada.profile = Some(Profile {
+            bio: Some(String::from("Countess")),
+            ..Profile::default()
+        });
+        store.insert(&ada, None, false).await.unwrap();
+
+        let replacement = User {
+            name: String::from("Ada L"),
+            profile: None,
+            created_at: None,
+            updated_at: Some(Utc::now() + chrono::Duration::seconds(1)),
+            ..ada.clone()
+        };
+        assert!(store.replace(&replacement, Expect::Unchanged(&ada), false).await.unwrap().0);
+        let (stored, _) = store.get(ada.id, false).await.unwrap().0.unwrap();
+        assert_eq!((stored.name.as_str(), stored.profile.is_none()), ("Ada L", true));
+        assert_eq!(stored.created_at, ada.created_at);
+        assert!(!store.replace(&replacement, Expect::Unchanged(&ada), false).await.unwrap().0);
+    }
+
+    #[actix_web::test]
+    async fn memory_writes_check_their_conditions() {
+        let store = MemoryUsers::default();
+        let ada = user("Ada");
+        store.insert(&ada, None, false).await.unwrap();
+
+        // `ada` as read before a concurrent rename moved updated_at.
+        let stale = ada.clone();
+        let later = Utc::now() + chrono::Duration::seconds(1);
+        store.update(&ada, &rename("Ada L"), later, Expect::Exists, false).await.unwrap();
+        let deleted = store.soft_delete(ada.id, later, None, Expect::Unchanged(&stale), false).await;
+        assert!(!deleted.unwrap().0);
+        assert!(!store.delete(ada.id, Expect::Unchanged(&stale), false).await.unwrap().0);
+
+        assert_eq!(store.modified(ada.id).await.unwrap().0, Some(Some(later)));
+        assert!(store.soft_delete(ada.id, later, None, Expect::Exists, false).await.unwrap().0);
+        assert!(store.password_hash(ada.id).await.unwrap().0.is_none());
+        assert_eq!(store.modified(ada.id).await.unwrap().0, None);
+        assert_eq!(store.get(ada.id, false).await.unwrap().0.map(|(_, deleted)| deleted), Some(true));
+        assert!(store.restore(ada.id, later, None, false).await.unwrap().0);
+        assert_eq!(store.get(ada.id, false).await.unwrap().0.map(|(_, deleted)| deleted), Some(false));
+
+        let (current, _) = store.get(ada.id, false).await.unwrap().0.unwrap();
+        assert!(store.delete(ada.id, Expect::Unchanged(&current), false).await.unwrap().0);
+        assert!(store.get(ada.id, false).await.unwrap().0.is_none());
+        assert!(!store.delete(ada.id, Expect::Exists, false).await.unwrap().0);
+        assert!(!store.restore(ada.id, later, None, false).await.unwrap().0);
+    }
+}
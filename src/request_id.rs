@@ -0,0 +1,341 @@
+use crate::audit;
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+const TRACEPARENT_HEADER: HeaderName = HeaderName::from_static("traceparent");
+
+/// How a request without a usable `X-Request-Id` gets one. `trace_id` uses
+/// the trace id of the request's span (with the `otel` feature) or of an
+/// incoming W3C `traceparent`, so logs and traces share one id; a request
+/// with neither gets a UUID as in `uuid` mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestIdFormat {
+    #[default]
+    Uuid,
+    TraceId,
+}
+
+impl FromStr for RequestIdFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "uuid" => Ok(RequestIdFormat::Uuid),
+            "trace_id" => Ok(RequestIdFormat::TraceId),
+            _ => Err(()),
+        }
+    }
+}
+
+// Error bodies are small; anything bigger or streamed is passed through
+// untouched.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+// An incoming id is kept when it is a plausible token, so a gateway's id
+// carries through; anything else is replaced rather than echoed back.
+fn incoming(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let plausible = !value.is_empty()
+        && value.len() <= 128
+        && value.bytes().all(|byte| byte.is_ascii_graphic());
+    plausible.then(|| value.to_string())
+}
+
+// The trace id of a W3C `traceparent` header (`00-<trace id>-<parent id>-
+// <flags>`): 32 lowercase hex digits, not all zero.
+fn traceparent(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(TRACEPARENT_HEADER)?.to_str().ok()?;
+    let trace_id = value.split('-').nth(1)?;
+    let valid = trace_id.len() == 32
+        && trace_id.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+        && trace_id.bytes().any(|byte| byte != b'0');
+    valid.then(|| trace_id.to_string())
+}
+
+fn trace_id(req: &ServiceRequest) -> Option<String> {
+    #[cfg(feature = "otel")]
+    {
+        if let Some(trace_id) = crate::otel::current_trace_id() {
+            return Some(trace_id);
+        }
+    }
+    traceparent(req)
+}
+
+// The ids a request is logged and answered with.
+struct Ids {
+    request_id: String,
+    trace_id: String,
+}
+
+// Adds `request_id` and `trace_id` to a JSON error body: objects gain the
+// fields, and the bare-string errors become `{ "error": <string>, ... }`.
+fn with_ids(body: &[u8], ids: &Ids) -> Option<Vec<u8>> {
+    let mut object = match serde_json::from_slice(body).ok()? {
+        Value::Object(object) => object,
+        Value::String(message) => serde_json::Map::from_iter([(
+            String::from("error"),
+            Value::from(message),
+        )]),
+        _ => return None,
+    };
+    object.insert(String::from("request_id"), Value::from(ids.request_id.as_str()));
+    object.insert(String::from("trace_id"), Value::from(ids.trace_id.as_str()));
+    let value = Value::Object(object);
+    serde_json::to_vec(&value).ok()
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json") || value.contains("+json"))
+}
+
+// Echoes the request id in the response header and adds both ids to a JSON
+// error body.
+async fn tag(response: HttpResponse, ids: &Ids) -> HttpResponse {
+    let is_error = response.status().is_client_error() || response.status().is_server_error();
+    let small = matches!(response.body().size(), BodySize::Sized(len) if len as usize <= MAX_ERROR_BODY);
+    let mut response = if is_error && small && is_json(response.headers()) {
+        let (mut response, original) = response.into_parts();
+        let new_body = match body::to_bytes(original).await {
+            Ok(bytes) => match with_ids(&bytes, ids) {
+                Some(rewritten) => {
+                    response.headers_mut().remove(header::CONTENT_LENGTH);
+                    BoxBody::new(rewritten)
+                }
+                None => BoxBody::new(bytes),
+            },
+            Err(_) => BoxBody::new(()),
+        };
+        response.set_body(new_body)
+    } else {
+        response
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&ids.request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+// Assigns every request an id (honouring an incoming `X-Request-Id`), records
+// it on the request span and for the audit log, echoes it in the response
+// header and adds it to JSON error bodies, along with the trace id (the
+// request id when the request has no trace). Registered just inside the
+// request span and outside the other middleware, so errors they raise get
+// the ids too. The request is not
+// held on to meanwhile: routing needs it unshared, so an error is tagged
+// through its own response.
+pub async fn assign(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let format = req
+        .app_data::<web::Data<RequestIdFormat>>()
+        .map_or_else(RequestIdFormat::default, |format| *format.get_ref());
+    let trace = trace_id(&req);
+    let generated = match format {
+        RequestIdFormat::TraceId => trace.clone(),
+        RequestIdFormat::Uuid => None,
+    };
+    let request_id = incoming(&req)
+        .or(generated)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let ids = Ids {
+        trace_id: trace.unwrap_or_else(|| request_id.clone()),
+        request_id,
+    };
+    let span = tracing::Span::current();
+    span.record("request_id", ids.request_id.as_str());
+    span.record("trace_id", ids.trace_id.as_str());
+
+    match audit::in_request(ids.request_id.clone(), next.call(req)).await {
+        Ok(res) => {
+            let (http_req, response) = res.into_parts();
+            let response = tag(response.map_into_boxed_body(), &ids).await;
+            Ok(ServiceResponse::new(http_req, response))
+        }
+        Err(e) => {
+            let response = tag(e.error_response(), &ids).await;
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use crate::logging;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    fn ids() -> Ids {
+        Ids {
+            request_id: String::from("abc"),
+            trace_id: String::from("def"),
+        }
+    }
+
+    #[test]
+    fn error_bodies_gain_the_ids() {
+        let rewritten = with_ids(br#"{"status":404}"#, &ids()).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&rewritten).unwrap(),
+            serde_json::json!({"status": 404, "request_id": "abc", "trace_id": "def"})
+        );
+        let rewritten = with_ids(br#""not found""#, &ids()).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&rewritten).unwrap(),
+            serde_json::json!({"error": "not found", "request_id": "abc", "trace_id": "def"})
+        );
+        assert!(with_ids(b"[1]", &ids()).is_none());
+        assert!(with_ids(b"<html>", &ids()).is_none());
+    }
+
+    // Log output, shared with the test that reads it.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn server_errors_are_answered_with_ids_and_logged_with_their_detail() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let app = init_service(
+            App::new()
+                .route(
+                    "/broken",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(ApiError::internal("Failed", "driver said no"))
+                    }),
+                )
+                .wrap(from_fn(assign))
+                .wrap(from_fn(logging::request_span)),
+        )
+        .await;
+        let req = traced().uri("/broken").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), 500);
+        let request_id = res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+        let request_id = request_id.to_string();
+        let body = read_body(res).await;
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["code"], "internal_error");
+        assert_eq!(problem["request_id"], request_id.as_str());
+        assert_eq!(problem["trace_id"], TRACE_ID);
+        assert!(!String::from_utf8_lossy(&body).contains("driver said no"));
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("driver said no"))
+            .expect("the detail is logged");
+        assert!(line.contains(&request_id), "{}", line);
+        assert!(line.contains(TRACE_ID), "{}", line);
+    }
+
+    async fn id(req: TestRequest) -> (String, Value) {
+        id_in(RequestIdFormat::Uuid, req).await
+    }
+
+    async fn id_in(format: RequestIdFormat, req: TestRequest) -> (String, Value) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(format))
+                .route(
+                    "/missing",
+                    web::get().to(|| async { HttpResponse::NotFound().json(serde_json::json!({})) }),
+                )
+                .wrap(from_fn(assign)),
+        )
+        .await;
+        let res = call_service(&app, req.uri("/missing").to_request()).await;
+        let header = res.headers().get(REQUEST_ID_HEADER).unwrap();
+        let header = header.to_str().unwrap().to_string();
+        let body = serde_json::from_slice(&read_body(res).await).unwrap();
+        (header, body)
+    }
+
+    #[actix_web::test]
+    async fn plausible_incoming_ids_are_kept() {
+        let req = TestRequest::get().insert_header((REQUEST_ID_HEADER, "gateway-42"));
+        let (header, body) = id(req).await;
+        assert_eq!(header, "gateway-42");
+        assert_eq!(body["request_id"], "gateway-42");
+    }
+
+    #[actix_web::test]
+    async fn other_ids_are_replaced() {
+        let req = TestRequest::get().insert_header((REQUEST_ID_HEADER, "has space"));
+        let (header, body) = id(req).await;
+        assert!(Uuid::parse_str(&header).is_ok());
+        assert_eq!(body["request_id"], header.as_str());
+        let (header, _) = id(TestRequest::get()).await;
+        assert!(Uuid::parse_str(&header).is_ok());
+    }
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    fn traced() -> TestRequest {
+        let traceparent = format!("00-{}-00f067aa0ba902b7-01", TRACE_ID);
+        TestRequest::get().insert_header((TRACEPARENT_HEADER, traceparent))
+    }
+
+    #[actix_web::test]
+    async fn trace_id_mode_uses_the_requests_trace() {
+        let (header, body) = id_in(RequestIdFormat::TraceId, traced()).await;
+        assert_eq!(header, TRACE_ID);
+        assert_eq!(body["request_id"], TRACE_ID);
+
+        let gateway = traced().insert_header((REQUEST_ID_HEADER, "gateway-42"));
+        assert_eq!(id_in(RequestIdFormat::TraceId, gateway).await.0, "gateway-42");
+    }
+
+    #[actix_web::test]
+    async fn trace_id_mode_falls_back_to_a_uuid() {
+        let (header, _) = id_in(RequestIdFormat::TraceId, TestRequest::get()).await;
+        assert!(Uuid::parse_str(&header).is_ok());
+        for invalid in ["00-00000000000000000000000000000000-00f067aa0ba902b7-01", "garbage"] {
+            let req = TestRequest::get().insert_header((TRACEPARENT_HEADER, invalid));
+            let (header, _) = id_in(RequestIdFormat::TraceId, req).await;
+            assert!(Uuid::parse_str(&header).is_ok());
+        }
+    }
+
+    #[actix_web::test]
+    async fn uuid_mode_ignores_the_trace() {
+        let (header, _) = id_in(RequestIdFormat::Uuid, traced()).await;
+        assert!(Uuid::parse_str(&header).is_ok());
+    }
+}
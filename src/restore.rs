@@ -0,0 +1,366 @@
+use crate::addresses;
+use crate::days;
+use crate::emails::{self, Adopted};
+use crate::error::ApiError;
+use crate::models::{User, UserStatus};
+use crate::observe;
+use crate::phones;
+use crate::search;
+use crate::snapshot::{self, Manifest, SnapshotRow};
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, StreamExt};
+use scylla::frame::response::result::CqlValue;
+use scylla::SerializeRow;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use uuid::Uuid;
+
+// `restore <FILE>` writes the users of a `snapshot` back, for rehydrating a
+// keyspace in a new environment. The file is checked against its manifest
+// first, its size, row count and SHA-256, and nothing is written unless it
+// matches. Each row is then written with every column it was copied with and
+// the TTL it had left, less the time since the snapshot; rows that have
+// expired since are left out. A user that already exists is kept as it is,
+// or with `--overwrite` replaced by the one in the file. Restored users get
+// their `users_by_email` and `users_by_phone` claims and, unless
+// soft-deleted, their name index entry, as `backfill` would give them.
+
+// Rows between two progress lines.
+const PROGRESS_EVERY: u64 = 10_000;
+
+// What to do with a user that is already in the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    Skip,
+    Overwrite,
+}
+
+// Counts of what a run did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub restored: u64,
+    // Already present, with `Conflict::Skip`.
+    pub skipped: u64,
+    pub expired: u64,
+    // Restored, but their email is claimed by another user.
+    pub email_conflicts: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Restored { email_conflict: bool },
+    Skipped,
+    Expired,
+    Failed,
+}
+
+impl Report {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Restored { email_conflict } => {
+                self.restored += 1;
+                if email_conflict {
+                    self.email_conflicts += 1;
+                }
+            }
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Expired => self.expired += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    fn done(&self) -> u64 {
+        self.restored + self.skipped + self.expired + self.failed
+    }
+}
+
+// The TTL to write a row with, given the seconds it had left when the
+// snapshot finished: 0 for a row that doesn't expire, `None` for one that
+// has expired since.
+fn ttl(expires_in: Option<i32>, finished_at: DateTime<Utc>, now: DateTime<Utc>) -> Option<i32> {
+    let Some(expires_in) = expires_in else {
+        return Some(0);
+    };
+    let left = (finished_at + TimeDelta::seconds(i64::from(expires_in)) - now).num_seconds();
+    (left > 0).then(|| i32::try_from(left).unwrap_or(i32::MAX))
+}
+
+// Checks the file at `path` against `manifest`, reading it whole.
+fn verify(path: &Path, manifest: &Manifest) -> Result<(), String> {
+    if manifest.format != snapshot::FORMAT || manifest.version != snapshot::VERSION {
+        return Err(format!(
+            "unsupported snapshot format {} version {}",
+            manifest.format, manifest.version
+        ));
+    }
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut digest = Sha256::new();
+    let (mut bytes, mut rows) = (0u64, 0u64);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buffer[..read]);
+        bytes += read as u64;
+        rows += buffer[..read].iter().filter(|byte| **byte == b'\n').count() as u64;
+    }
+    let sha256 = snapshot::hex(&digest.finalize());
+    if bytes != manifest.bytes || rows != manifest.rows || sha256 != manifest.sha256 {
+        return Err(format!(
+            "the file doesn't match its manifest: {} bytes, {} rows, sha256 {}; expected {} \
+             bytes, {} rows, sha256 {}",
+            bytes, rows, sha256, manifest.bytes, manifest.rows, manifest.sha256
+        ));
+    }
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest, String> {
+    let manifest_path = snapshot::manifest_path(path);
+    let json = std::fs::read(&manifest_path)
+        .map_err(|e| format!("cannot read {}: {}", manifest_path.display(), e))?;
+    serde_json::from_slice(&json)
+        .map_err(|e| format!("invalid manifest {}: {}", manifest_path.display(), e))
+}
+
+// Bind values for `insert_snapshot_row`, in its order; one more column than
+// a tuple can bind.
+#[derive(SerializeRow)]
+#[scylla(flavor = "enforce_order", skip_name_checks)]
+struct RowValues<'a> {
+    id: Uuid,
+    name: &'a str,
+    email: &'a str,
+    phone: &'a Option<String>,
+    password_hash: &'a Option<String>,
+    roles: &'a Option<Vec<String>>,
+    profile: &'a Option<CqlValue>,
+    addresses: &'a Option<CqlValue>,
+    tags: &'a Option<Vec<String>>,
+    metadata: &'a Option<BTreeMap<String, String>>,
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    verified: Option<bool>,
+    version: Option<i32>,
+    status: &'a Option<String>,
+    anonymized_at: Option<DateTime<Utc>>,
+    ttl: i32,
+}
+
+// Writes the row, then its email claim and name index entry.
+async fn restore_row(
+    state: &AppState,
+    row: SnapshotRow,
+    conflict: Conflict,
+    finished_at: DateTime<Utc>,
+) -> Result<Outcome, ApiError> {
+    let Some(ttl) = ttl(row.expires_in, finished_at, Utc::now()) else {
+        return Ok(Outcome::Expired);
+    };
+    let profile = row
+        .profile
+        .as_ref()
+        .map(|profile| users::profile_value(&state.keyspace, profile));
+    let addresses = row
+        .addresses
+        .as_ref()
+        .map(|addresses| addresses::list_value(&state.keyspace, addresses));
+    let values = RowValues {
+        id: row.id,
+        name: &row.name,
+        email: &row.email,
+        phone: &row.phone,
+        password_hash: &row.password_hash,
+        roles: &row.roles,
+        profile: &profile,
+        addresses: &addresses,
+        tags: &row.tags,
+        metadata: &row.metadata,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+        deleted_at: row.deleted_at,
+        verified: row.verified,
+        version: row.version,
+        status: &row.status,
+        anonymized_at: row.anonymized_at,
+        ttl,
+    };
+    match conflict {
+        Conflict::Overwrite => {
+            observe::query(state, "insert_snapshot_row", || {
+                state
+                    .session
+                    .execute_unpaged(&state.statements.insert_snapshot_row, &values)
+            })
+            .await?;
+        }
+        Conflict::Skip => {
+            let result = observe::conditional(state, "insert_snapshot_row_if_absent", || {
+                state
+                    .session
+                    .execute_unpaged(&state.statements.insert_snapshot_row_if_absent, &values)
+            })
+            .await?;
+            let applied = statements::applied(result)
+                .map_err(|e| ApiError::internal("Failed to restore user", e))?;
+            if !applied {
+                return Ok(Outcome::Skipped);
+            }
+        }
+    }
+
+    let deleted = row.deleted_at.is_some();
+    let user = User {
+        id: row.id,
+        name: row.name,
+        email: row.email,
+        phone: row.phone,
+        profile: row.profile,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+        expires_at: (ttl > 0).then(|| Utc::now() + TimeDelta::seconds(i64::from(ttl))),
+        verified: row.verified,
+        version: row.version,
+        status: Some(UserStatus::from_column(row.status.as_deref())),
+        anonymized_at: row.anonymized_at,
+    };
+    let adopted = emails::adopt(state, &user.email, user.id, user.expires_at).await?;
+    if let Adopted::HeldBy(holder) = adopted {
+        tracing::warn!(user_id = %user.id, %holder, "email is already claimed by another user");
+    }
+    if let Some(phone) = &user.phone {
+        match phones::claim(state, phone, user.id, user.expires_at).await {
+            Err(ApiError::Conflict(_)) => {
+                tracing::warn!(user_id = %user.id, "phone is already claimed by another user");
+            }
+            claimed => claimed?,
+        }
+    }
+    if !deleted {
+        search::try_index(state, &user).await?;
+    }
+    days::try_index(state, &user).await?;
+    days::try_index(state, &user).await?;
+    Ok(Outcome::Restored {
+        email_conflict: matches!(adopted, Adopted::HeldBy(_)),
+    })
+}
+
+// Restores the snapshot at `path`, `concurrency` rows at a time. A row that
+// can't be read or written is logged and counted; a file that doesn't match
+// its manifest fails the run before anything is written.
+pub async fn run(
+    state: &AppState,
+    path: &Path,
+    conflict: Conflict,
+    concurrency: usize,
+) -> Result<Report, String> {
+    let manifest = read_manifest(path)?;
+    verify(path, &manifest)?;
+    tracing::info!(
+        rows = manifest.rows,
+        keyspace = %manifest.keyspace,
+        taken_at = %manifest.finished_at,
+        "snapshot verified"
+    );
+
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let lines = BufReader::new(file).lines().enumerate();
+    let mut outcomes = std::pin::pin!(stream::iter(lines)
+        .map(|(index, line)| async move {
+            let row: SnapshotRow = match line
+                .map_err(|e| e.to_string())
+                .and_then(|line| serde_json::from_str(&line).map_err(|e| e.to_string()))
+            {
+                Ok(row) => row,
+                Err(e) => {
+                    tracing::warn!(line = index + 1, error = %e, "unreadable snapshot row");
+                    return Outcome::Failed;
+                }
+            };
+            let id = row.id;
+            restore_row(state, row, conflict, manifest.finished_at)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::warn!(user_id = %id, error = %e, "failed to restore user");
+                    Outcome::Failed
+                })
+        })
+        .buffer_unordered(concurrency));
+
+    let mut report = Report::default();
+    while let Some(outcome) = outcomes.next().await {
+        report.add(outcome);
+        if report.done() % PROGRESS_EVERY == 0 {
+            tracing::info!(done = report.done(), total = manifest.rows, "restore progress");
+        }
+    }
+    tracing::info!(
+        restored = report.restored,
+        skipped = report.skipped,
+        expired = report.expired,
+        email_conflicts = report.email_conflicts,
+        failed = report.failed,
+        "restore finished"
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn ttls_count_down_from_the_snapshot() {
+        let finished_at = Utc::now();
+        let later = finished_at + TimeDelta::seconds(600);
+        assert_eq!(ttl(None, finished_at, later), Some(0));
+        assert_eq!(ttl(Some(3600), finished_at, later), Some(3000));
+        assert_eq!(ttl(Some(600), finished_at, later), None);
+    }
+
+    #[test]
+    fn files_must_match_their_manifest() {
+        let path = std::env::temp_dir().join(format!("restore-{}.jsonl", uuid::Uuid::new_v4()));
+        let contents = b"{\"a\":1}\n{\"b\":2}\n";
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        let mut manifest = Manifest {
+            format: String::from(snapshot::FORMAT),
+            version: snapshot::VERSION,
+            keyspace: String::from("app"),
+            table: String::from("users"),
+            file: String::from("users.jsonl"),
+            rows: 2,
+            bytes: contents.len() as u64,
+            sha256: snapshot::hex(&Sha256::digest(contents)),
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+        };
+        assert_eq!(verify(&path, &manifest), Ok(()));
+        manifest.rows = 3;
+        assert!(verify(&path, &manifest).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn report_counts_each_outcome() {
+        let mut report = Report::default();
+        report.add(Outcome::Restored { email_conflict: true });
+        report.add(Outcome::Skipped);
+        report.add(Outcome::Expired);
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.email_conflicts, 1);
+        assert_eq!(report.done(), 3);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::config::{RetryKind, ScyllaConfig};
+use rand::Rng;
+use scylla::transport::errors::{DbError, QueryError, WriteType};
+use std::time::Duration;
+
+// Application-level retries for transient cluster errors, on top of the
+// driver's own retry policy (which retries at most once, immediately). Waiting
+// between attempts gives an overloaded or recovering node time to catch up
+// instead of failing the HTTP request with a 500.
+//
+// Only statements that are safe to apply twice go through `observe::query`;
+// lightweight transactions go through `observe::conditional`, which never
+// retries, and so would anything else not idempotent (counters, appends).
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<RetryKind>,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &ScyllaConfig) -> Self {
+        RetryPolicy {
+            max_attempts: config.retry_max_attempts,
+            initial_backoff: Duration::from_millis(config.retry_initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.retry_max_backoff_ms),
+            retry_on: config.retry_on.clone(),
+        }
+    }
+
+    // Whether a query that failed with `error` on attempt number `attempt`
+    // (starting at 1) should be tried again.
+    pub fn should_retry(&self, error: &QueryError, attempt: u32) -> bool {
+        let cas_timeout = matches!(
+            error,
+            QueryError::DbError(DbError::WriteTimeout { write_type: WriteType::Cas, .. }, _)
+        );
+        attempt < self.max_attempts
+            && !cas_timeout
+            && kind(error).is_some_and(|kind| self.retry_on.contains(&kind))
+    }
+
+    // Exponential backoff with full jitter, so clients that failed together
+    // don't all retry together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+// Whether `error` is one of the transient failures a retry could fix,
+// regardless of which kinds are configured to be retried.
+pub fn is_transient(error: &QueryError) -> bool {
+    kind(error).is_some()
+}
+
+// Whether `error` is the cluster or the driver running out of time, as
+// opposed to the cluster being unreachable or refusing the work.
+pub fn is_timeout(error: &QueryError) -> bool {
+    matches!(
+        kind(error),
+        Some(RetryKind::ReadTimeout | RetryKind::WriteTimeout | RetryKind::ClientTimeout)
+    )
+}
+
+fn kind(error: &QueryError) -> Option<RetryKind> {
+    match error {
+        QueryError::DbError(DbError::ReadTimeout { .. }, _) => Some(RetryKind::ReadTimeout),
+        QueryError::DbError(DbError::WriteTimeout { .. }, _) => Some(RetryKind::WriteTimeout),
+        QueryError::DbError(DbError::Overloaded, _) => Some(RetryKind::Overloaded),
+        QueryError::DbError(DbError::Unavailable { .. } | DbError::IsBootstrapping, _) => {
+            Some(RetryKind::Unavailable)
+        }
+        QueryError::RequestTimeout(_) | QueryError::TimeoutError => Some(RetryKind::ClientTimeout),
+        QueryError::BrokenConnection(_) | QueryError::ConnectionPoolError(_) => {
+            Some(RetryKind::Connection)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scylla::statement::Consistency;
+
+    fn policy(retry_on: Vec<RetryKind>) -> RetryPolicy {
+        RetryPolicy::new(&ScyllaConfig {
+            retry_max_attempts: 3,
+            retry_on,
+            ..ScyllaConfig::default()
+        })
+    }
+
+    fn write_timeout(write_type: WriteType) -> QueryError {
+        QueryError::DbError(
+            DbError::WriteTimeout {
+                consistency: Consistency::Quorum,
+                received: 1,
+                required: 2,
+                write_type,
+            },
+            String::from("timed out"),
+        )
+    }
+
+    #[test]
+    fn retries_configured_transient_errors_up_to_max_attempts() {
+        let policy = policy(vec![RetryKind::Overloaded, RetryKind::ClientTimeout]);
+        let overloaded = QueryError::DbError(DbError::Overloaded, String::from("busy"));
+        assert!(policy.should_retry(&overloaded, 1));
+        assert!(policy.should_retry(&overloaded, 2));
+        assert!(!policy.should_retry(&overloaded, 3));
+        assert!(policy.should_retry(&QueryError::TimeoutError, 1));
+    }
+
+    #[test]
+    fn does_not_retry_unconfigured_or_permanent_errors() {
+        let policy = policy(vec![RetryKind::Overloaded]);
+        assert!(!policy.should_retry(&write_timeout(WriteType::Simple), 1));
+        let invalid = QueryError::DbError(DbError::Invalid, String::from("bad query"));
+        assert!(!policy.should_retry(&invalid, 1));
+        assert!(!is_transient(&invalid));
+    }
+
+    #[test]
+    fn does_not_retry_timed_out_lightweight_transactions() {
+        let policy = policy(vec![RetryKind::WriteTimeout]);
+        assert!(policy.should_retry(&write_timeout(WriteType::Simple), 1));
+        assert!(!policy.should_retry(&write_timeout(WriteType::Cas), 1));
+    }
+
+    #[test]
+    fn backoff_stays_under_the_ceiling() {
+        let policy = policy(Vec::new());
+        for attempt in 1..10 {
+            assert!(policy.backoff(attempt) <= Duration::from_millis(1_000));
+        }
+        assert!(policy.backoff(1) <= Duration::from_millis(50));
+    }
+}
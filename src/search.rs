@@ -0,0 +1,164 @@
+use crate::error::ApiError;
+use crate::models::User;
+use crate::observe;
+use crate::state::AppState;
+use crate::users;
+use scylla::frame::response::result::CqlValue;
+
+// Maintenance of `users_by_name`, the denormalized table behind
+// GET /users/search. It is derived data: failures to update it are logged
+// rather than failing the write, and a stale row is fixed by the next update
+// of that user. Users stored before the table existed are missing from it
+// until `backfill` indexes them.
+
+// Matching is case-insensitive: rows are keyed by the lower-cased, trimmed
+// name and partitioned by its first character.
+pub fn normalize(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+fn bucket(name_lower: &str) -> String {
+    name_lower.chars().next().map(String::from).unwrap_or_default()
+}
+
+// Bind values for `index_user_name`; timestamps the user lacks are null.
+pub fn index_values(user: &User) -> Vec<Option<CqlValue>> {
+    let name_lower = normalize(&user.name);
+    vec![
+        Some(CqlValue::Text(bucket(&name_lower))),
+        Some(CqlValue::Text(name_lower)),
+        Some(CqlValue::Uuid(user.id)),
+        Some(CqlValue::Text(user.name.clone())),
+        Some(CqlValue::Text(user.email.clone())),
+        user.created_at.map(|at| CqlValue::Timestamp(at.into())),
+        user.updated_at.map(|at| CqlValue::Timestamp(at.into())),
+        Some(CqlValue::Int(users::ttl(user.expires_at))),
+    ]
+}
+
+// Bind values for `unindex_user_name`.
+pub fn unindex_values(user: &User) -> Vec<CqlValue> {
+    let name_lower = normalize(&user.name);
+    vec![
+        CqlValue::Text(bucket(&name_lower)),
+        CqlValue::Text(name_lower),
+        CqlValue::Uuid(user.id),
+    ]
+}
+
+// Bind values for `search_users_by_name`: every name starting with `prefix`
+// sorts between the prefix itself and the prefix followed by the highest
+// code point. `prefix` must already be normalized and non-empty.
+pub fn search_values(prefix: &str) -> Vec<CqlValue> {
+    vec![
+        CqlValue::Text(bucket(prefix)),
+        CqlValue::Text(prefix.to_string()),
+        CqlValue::Text(format!("{}{}", prefix, char::MAX)),
+    ]
+}
+
+pub async fn index(state: &AppState, user: &User) {
+    if let Err(e) = try_index(state, user).await {
+        tracing::warn!(user_id = %user.id, error = %e, "failed to index user name");
+    }
+}
+
+// Like `index`, but reports a failure, for the backfill to count.
+pub async fn try_index(state: &AppState, user: &User) -> Result<(), ApiError> {
+    let values = index_values(user);
+    observe::query(state, "index_user_name", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.index_user_name, &values)
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn unindex(state: &AppState, user: &User) {
+    let values = unindex_values(user);
+    if let Err(e) = observe::query(state, "unindex_user_name", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.unindex_user_name, &values)
+    })
+    .await
+    {
+        tracing::warn!(user_id = %user.id, error = %e, "failed to unindex user name");
+    }
+}
+
+// Replaces the index row of `before` with one for `after`.
+pub async fn reindex(state: &AppState, before: &User, after: &User) {
+    if normalize(&before.name) != normalize(&after.name) {
+        unindex(state, before).await;
+    }
+    index(state, after).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn text(value: &CqlValue) -> &str {
+        match value {
+            CqlValue::Text(text) => text,
+            other => panic!("not text: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn names_are_trimmed_and_lower_cased() {
+        assert_eq!(normalize("  Ada Lovelace "), "ada lovelace");
+        assert_eq!(normalize("ÉMILE"), "émile");
+    }
+
+    #[test]
+    fn index_rows_are_bucketed_by_first_character() {
+        let user = User {
+            id: Uuid::new_v4(),
+            name: String::from(" Ada Lovelace"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        };
+        let values = index_values(&user);
+        assert_eq!(values[0], Some(CqlValue::Text(String::from("a"))));
+        assert_eq!(values[1], Some(CqlValue::Text(String::from("ada lovelace"))));
+        assert_eq!(values[2], Some(CqlValue::Uuid(user.id)));
+        assert!(values[5].is_some());
+        assert_eq!(values[6], None);
+        assert_eq!(values[7], Some(CqlValue::Int(0)));
+        assert_eq!(
+            unindex_values(&user),
+            [
+                CqlValue::Text(String::from("a")),
+                CqlValue::Text(String::from("ada lovelace")),
+                CqlValue::Uuid(user.id),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_range_covers_exactly_the_prefixed_names() {
+        let values = search_values("ad");
+        assert_eq!(text(&values[0]), "a");
+        let (low, high) = (text(&values[1]), text(&values[2]));
+        let within = |name: &str| low <= name && name <= high;
+        assert!(within("ad"));
+        assert!(within("ada lovelace"));
+        assert!(within("ad\u{10fff}"));
+        assert!(!within("ac"));
+        assert!(!within("ae"));
+        assert!(!within("a"));
+    }
+}
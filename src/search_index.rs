@@ -0,0 +1,290 @@
+use crate::config::{SearchBackend, SearchIndexConfig};
+use crate::events::EventKind;
+use crate::http_client;
+use crate::models::User;
+use crate::outbox::{OutboxEvent, Sink};
+use futures::future::{BoxFuture, FutureExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use uuid::Uuid;
+
+// Scylla can look users up by exact email or name prefix, but not by a word
+// in the middle of a name or a misspelt one. With `search_index.enabled`, the
+// outbox relay mirrors users into a Meilisearch or Elasticsearch index as
+// they change, one document per live user holding the fields worth
+// searching, and GET /users/search?q= asks the index which users match and
+// reads them from Scylla. Writes are idempotent upserts and deletes, so
+// relaying an event twice does no harm; one the server refuses is retried
+// with the rest of its shard on the next run.
+//
+// The index only learns of users as they change: those stored before it
+// was turned on are missing until their next write. A user deleted since
+// the index answered is left out of the results, so they can only be late,
+// never wrong, and hits from other keyspaces sharing the index are dropped
+// the same way.
+
+// A user as the index stores it.
+#[derive(Serialize)]
+struct Document<'a> {
+    id: Uuid,
+    name: &'a str,
+    email: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phone: Option<&'a str>,
+}
+
+impl<'a> Document<'a> {
+    fn new(user: &'a User) -> Self {
+        Document {
+            id: user.id,
+            name: &user.name,
+            email: &user.email,
+            phone: user.phone.as_deref(),
+        }
+    }
+}
+
+// One call to the search server.
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: &'static str,
+    url: String,
+    body: Option<Vec<u8>>,
+}
+
+// The users a query matched, best first, and how many it matched in all.
+#[derive(Debug, PartialEq)]
+pub struct Hits {
+    pub ids: Vec<Uuid>,
+    pub total: u64,
+}
+
+pub struct SearchIndex {
+    backend: SearchBackend,
+    // Without a trailing '/'.
+    url: String,
+    index: String,
+    authorization: Option<String>,
+    timeout: Duration,
+}
+
+impl SearchIndex {
+    pub fn new(config: &SearchIndexConfig) -> Self {
+        let scheme = match config.backend {
+            SearchBackend::Meilisearch => "Bearer",
+            SearchBackend::Elasticsearch => "ApiKey",
+        };
+        SearchIndex {
+            backend: config.backend,
+            url: config.url.trim_end_matches('/').to_string(),
+            index: config.index.clone(),
+            authorization: config.api_key.as_ref().map(|key| format!("{} {}", scheme, key)),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+
+    // Where the document for `user_id` lives.
+    fn document_url(&self, user_id: Uuid) -> String {
+        match self.backend {
+            SearchBackend::Meilisearch => {
+                format!("{}/indexes/{}/documents/{}", self.url, self.index, user_id)
+            }
+            SearchBackend::Elasticsearch => format!("{}/{}/_doc/{}", self.url, self.index, user_id),
+        }
+    }
+
+    // The write that brings the index up to date with `event`, if any:
+    // the user's document for a change, its removal for a delete.
+    fn write_request(&self, event: &OutboxEvent) -> Option<Request> {
+        if matches!(event.kind, EventKind::Deleted) {
+            return Some(Request {
+                method: "DELETE",
+                url: self.document_url(event.user_id),
+                body: None,
+            });
+        }
+        let document = Document::new(event.user.as_ref()?);
+        Some(match self.backend {
+            SearchBackend::Meilisearch => Request {
+                method: "POST",
+                url: format!("{}/indexes/{}/documents?primaryKey=id", self.url, self.index),
+                body: serde_json::to_vec(&[document]).ok(),
+            },
+            SearchBackend::Elasticsearch => Request {
+                method: "PUT",
+                url: self.document_url(event.user_id),
+                body: serde_json::to_vec(&document).ok(),
+            },
+        })
+    }
+
+    fn search_request(&self, q: &str, offset: usize, limit: usize) -> Request {
+        let (url, body) = match self.backend {
+            SearchBackend::Meilisearch => (
+                format!("{}/indexes/{}/search", self.url, self.index),
+                json!({
+                    "q": q,
+                    "offset": offset,
+                    "limit": limit,
+                    "attributesToRetrieve": ["id"],
+                }),
+            ),
+            SearchBackend::Elasticsearch => (
+                format!("{}/{}/_search", self.url, self.index),
+                json!({
+                    "query": {
+                        "multi_match": {
+                            "query": q,
+                            "fields": ["name", "email", "phone"],
+                            "fuzziness": "AUTO",
+                        }
+                    },
+                    "from": offset,
+                    "size": limit,
+                    "_source": false,
+                }),
+            ),
+        };
+        Request {
+            method: "POST",
+            url,
+            body: Some(body.to_string().into_bytes()),
+        }
+    }
+
+    fn parse_hits(&self, body: &[u8]) -> Result<Hits, String> {
+        let body: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+        let (hits, id_field, total) = match self.backend {
+            SearchBackend::Meilisearch => {
+                (&body["hits"], "id", body["estimatedTotalHits"].as_u64())
+            }
+            SearchBackend::Elasticsearch => {
+                (&body["hits"]["hits"], "_id", body["hits"]["total"]["value"].as_u64())
+            }
+        };
+        let hits = hits.as_array().ok_or("no hits in the response")?;
+        let ids: Vec<Uuid> = hits
+            .iter()
+            .filter_map(|hit| hit[id_field].as_str()?.parse().ok())
+            .collect();
+        let total = total.unwrap_or(ids.len() as u64);
+        Ok(Hits { ids, total })
+    }
+
+    async fn send(&self, request: &Request) -> Result<http_client::Response, String> {
+        let mut headers = vec![("Accept", "application/json")];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let body = request.body.as_deref().map(|body| ("application/json", body));
+        http_client::send(request.method, &request.url, &headers, body, self.timeout)
+            .await
+            .map_err(|e| format!("{} {}: {}", request.method, request.url, e))
+    }
+
+    // Up to `limit` users matching `q`, after skipping the first `offset`.
+    pub async fn query(&self, q: &str, offset: usize, limit: usize) -> Result<Hits, String> {
+        let request = self.search_request(q, offset, limit);
+        let response = self.send(&request).await?;
+        if !response.is_success() {
+            return Err(format!("search answered {}", response.status));
+        }
+        self.parse_hits(&response.body)
+    }
+}
+
+impl Sink for SearchIndex {
+    fn publish<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let Some(request) = self.write_request(event) else {
+                return Ok(());
+            };
+            let response = self.send(&request).await?;
+            // Deleting a document the index never had is done all the same.
+            let gone = request.method == "DELETE" && response.status == 404;
+            if !response.is_success() && !gone {
+                return Err(format!("{} answered {}", request.url, response.status));
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(backend: SearchBackend) -> SearchIndex {
+        SearchIndex::new(&SearchIndexConfig {
+            enabled: true,
+            backend,
+            url: String::from("http://search:7700/"),
+            api_key: Some(String::from("secret")),
+            ..SearchIndexConfig::default()
+        })
+    }
+
+    fn ada() -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada Lovelace"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: None,
+            created_at: None,
+            updated_at: None,
+            expires_at: None,
+            verified: Some(true),
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    #[test]
+    fn changes_upsert_the_document_and_deletes_remove_it() {
+        let user = ada();
+        let meilisearch = index(SearchBackend::Meilisearch);
+        assert_eq!(meilisearch.authorization.as_deref(), Some("Bearer secret"));
+        let event = OutboxEvent::new(EventKind::Updated, user.id, Some(user.clone()), Some(2));
+        let request = meilisearch.write_request(&event).unwrap();
+        assert_eq!(
+            (request.method, request.url.as_str()),
+            ("POST", "http://search:7700/indexes/users/documents?primaryKey=id")
+        );
+        let body: Value = serde_json::from_slice(&request.body.unwrap()).unwrap();
+        let document = json!({ "id": user.id, "name": "Ada Lovelace", "email": "ada@example.com" });
+        assert_eq!(body, json!([document]));
+
+        let elasticsearch = index(SearchBackend::Elasticsearch);
+        let deleted = OutboxEvent::new(EventKind::Deleted, user.id, None, Some(3));
+        let request = elasticsearch.write_request(&deleted).unwrap();
+        assert_eq!(request.method, "DELETE");
+        assert_eq!(request.url, format!("http://search:7700/users/_doc/{}", user.id));
+        assert_eq!(request.body, None);
+    }
+
+    #[test]
+    fn hits_are_read_in_order_from_either_backend() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let meilisearch = json!({
+            "hits": [{ "id": first }, { "id": "not-an-id" }, { "id": second }],
+            "estimatedTotalHits": 7,
+        });
+        let hits = index(SearchBackend::Meilisearch)
+            .parse_hits(meilisearch.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(hits, Hits { ids: vec![first, second], total: 7 });
+
+        let elasticsearch = json!({
+            "hits": { "total": { "value": 2 }, "hits": [{ "_id": second }, { "_id": first }] },
+        });
+        let hits = index(SearchBackend::Elasticsearch)
+            .parse_hits(elasticsearch.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(hits, Hits { ids: vec![second, first], total: 2 });
+        assert!(index(SearchBackend::Elasticsearch).parse_hits(b"{}").is_err());
+    }
+}
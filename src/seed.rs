@@ -0,0 +1,151 @@
+use crate::models::{NewUser, Profile};
+use crate::state::AppState;
+use crate::users;
+use futures::{stream, StreamExt};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+// `seed --count N` registers N made-up users, for demo environments and for
+// exercising pagination and search against a realistic amount of data. Names
+// are drawn from the lists below, so searches by a common first or last name
+// match many users. Emails are built from the name and a random suffix under
+// the reserved example domains, so runs can be repeated without conflicting
+// and nothing is ever delivered. The users have no password; they exist to be
+// listed and searched, not to log in.
+
+const FIRST_NAMES: &[&str] = &[
+    "Ada", "Alan", "Amara", "Ana", "Arjun", "Beatriz", "Chen", "Chloe", "Daniel", "Dmitri",
+    "Elena", "Emeka", "Fatima", "Grace", "Hana", "Hiroshi", "Ines", "Isaac", "Jamal", "Julia",
+    "Kai", "Katherine", "Lars", "Leila", "Liam", "Lucia", "Mateo", "Maya", "Mohammed", "Nadia",
+    "Noah", "Olga", "Omar", "Priya", "Rafael", "Rosa", "Sakura", "Samuel", "Sofia", "Tariq",
+    "Thomas", "Valentina", "Wei", "Yusuf", "Zara",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Adeyemi", "Andersen", "Bauer", "Costa", "Dubois", "Eriksson", "Fernandez", "Garcia",
+    "Hopper", "Ibrahim", "Ivanova", "Johnson", "Kaur", "Kim", "Kowalski", "Lovelace", "Martin",
+    "Mendes", "Müller", "Nakamura", "Nguyen", "Novak", "Okafor", "O'Brien", "Patel", "Petrov",
+    "Rossi", "Santos", "Schmidt", "Silva", "Singh", "Smith", "Tanaka", "Turing", "Wang", "Yilmaz",
+];
+
+const DOMAINS: &[&str] = &["example.com", "example.net", "example.org"];
+
+// Locale and time zone pairs a profile may get.
+const PLACES: &[(&str, &str)] = &[
+    ("en-GB", "Europe/London"),
+    ("en-US", "America/New_York"),
+    ("de-DE", "Europe/Berlin"),
+    ("fr-FR", "Europe/Paris"),
+    ("es-ES", "Europe/Madrid"),
+    ("pt-BR", "America/Sao_Paulo"),
+    ("ja-JP", "Asia/Tokyo"),
+    ("hi-IN", "Asia/Kolkata"),
+];
+
+// Counts of what a run did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub registered: usize,
+    pub failed: usize,
+}
+
+// The local part of an email address for `name`: lowercase ASCII letters
+// joined by dots.
+fn local_part(name: &str) -> String {
+    name.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter_map(|c| match c {
+                    'ü' => Some('u'),
+                    c if c.is_ascii_alphabetic() => Some(c.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn fake_user(rng: &mut impl Rng) -> NewUser {
+    let first = FIRST_NAMES.choose(rng).copied().unwrap_or("Ada");
+    let last = LAST_NAMES.choose(rng).copied().unwrap_or("Lovelace");
+    let name = format!("{} {}", first, last);
+    let email = format!(
+        "{}.{:06x}@{}",
+        local_part(&name),
+        rng.gen_range(0..0x100_0000),
+        DOMAINS.choose(rng).copied().unwrap_or("example.com")
+    );
+    // Most users fill in some of their profile.
+    let profile = rng.gen_bool(0.7).then(|| {
+        let (locale, timezone) = PLACES.choose(rng).copied().unwrap_or(("en-GB", "Europe/London"));
+        Profile {
+            bio: None,
+            avatar_url: None,
+            locale: Some(locale.to_string()),
+            timezone: rng.gen_bool(0.5).then(|| timezone.to_string()),
+        }
+    });
+    NewUser {
+        name,
+        email,
+        password: None,
+        phone: None,
+        profile,
+        expires_in_seconds: None,
+    }
+}
+
+// Registers `count` fake users through the same path as POST /register, at
+// most `concurrency` at once. A failed registration is logged and counted.
+pub async fn run(state: &AppState, count: usize, concurrency: usize) -> Report {
+    let fakes: Vec<NewUser> = {
+        let mut rng = rand::thread_rng();
+        (0..count).map(|_| fake_user(&mut rng)).collect()
+    };
+    let mut outcomes = std::pin::pin!(stream::iter(fakes)
+        .map(|user| async move {
+            let email = user.email.clone();
+            (email, users::register(state, user, false).await)
+        })
+        .buffer_unordered(concurrency));
+
+    let mut report = Report::default();
+    while let Some((email, outcome)) = outcomes.next().await {
+        match outcome {
+            Ok(_) => report.registered += 1,
+            Err(e) => {
+                report.failed += 1;
+                tracing::warn!(email = %email, error = %e, "seed user not registered");
+            }
+        }
+        let done = report.registered + report.failed;
+        if done % 1_000 == 0 {
+            tracing::info!(done, total = count, "seeding");
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation;
+
+    #[test]
+    fn email_local_parts_follow_the_name() {
+        assert_eq!(local_part("Ada Lovelace"), "ada.lovelace");
+        assert_eq!(local_part("Liam O'Brien"), "liam.obrien");
+        assert_eq!(local_part("Sofia Müller"), "sofia.muller");
+    }
+
+    #[test]
+    fn fake_users_pass_registration_validation() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let user = fake_user(&mut rng);
+            let name = format!("{} <{}>", user.name, user.email);
+            assert!(validation::new_user(user).is_ok(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use crate::backfill;
+use crate::config::Config;
+use crate::emails;
+use crate::error::ApiError;
+use crate::migrations;
+use crate::models::{NewUser, SearchUsersQuery, UpdateUser, User, UserStatus};
+use crate::repository::{Expect, UserRepository};
+use crate::startup;
+use crate::state::AppState;
+use crate::statements::Statements;
+use crate::users::{self, NewUserRow};
+use chrono::Utc;
+use scylla::Session;
+use std::sync::Arc;
+use uuid::Uuid;
+
+type StepResult = Result<(), String>;
+
+// Longest keyspace name Scylla accepts.
+const MAX_KEYSPACE_LEN: usize = 48;
+
+// A fresh keyspace named after `base`, so concurrent runs don't share one and
+// nothing is left behind from an earlier run.
+fn temporary_keyspace(base: &str) -> String {
+    let suffix = &Uuid::new_v4().simple().to_string()[..8];
+    let base = &base[..base.len().min(MAX_KEYSPACE_LEN - suffix.len() - 1)];
+    format!("{}_{}", base, suffix)
+}
+
+fn step<T>(result: Result<T, ApiError>) -> Result<T, String> {
+    result.map_err(|e| e.to_string())
+}
+
+fn expect_user(user: Option<User>, name: &str, email: &str) -> StepResult {
+    match user {
+        Some(user) if user.name == name && user.email == email => Ok(()),
+        Some(other) => Err(format!("unexpected user {:?}", other)),
+        None => Err(String::from("user not found")),
+    }
+}
+
+// Creates `keyspace` with the configured replication, migrates it and
+// prepares the same statements the server does. Caches are off, so every
+// step reads what is stored.
+async fn prepare(session: Arc<Session>, keyspace: &str, config: &Config) -> Result<AppState, String> {
+    let replication = startup::Replication {
+        factor: config.scylla.replication_factor,
+        datacenters: config.scylla.replication_datacenters.clone(),
+    };
+    let ddl = migrations::Ddl::new(&config.scylla);
+    startup::bootstrap(&session, keyspace, &replication, &ddl).await?;
+    migrations::run(&session, keyspace, &ddl).await?;
+    let statements = Statements::prepare(&session, keyspace)
+        .await
+        .map_err(|e| format!("cannot prepare statements: {}", e))?;
+
+    let mut config = config.clone();
+    config.cache.capacity = 0;
+    config.cache.redis_url = None;
+    Ok(AppState::new(&config, session, keyspace.to_string(), statements))
+}
+
+// Registers, reads, updates, soft-deletes, restores and hard-deletes one user
+// through the same code paths as the HTTP handlers, recording each step.
+async fn exercise(state: &AppState, results: &mut Vec<(&'static str, StepResult)>) {
+    let email = format!("self-test-{}@example.com", Uuid::new_v4());
+    let new_user = NewUser {
+        name: String::from("Self Test"),
+        email: email.clone(),
+        password: Some(String::from("self-test-password")),
+        phone: None,
+        profile: None,
+        expires_in_seconds: None,
+    };
+    let id = match step(users::register(state, new_user, false).await) {
+        Ok((user, _)) => {
+            results.push(("create", Ok(())));
+            user.id
+        }
+        Err(e) => {
+            results.push(("create", Err(e)));
+            return;
+        }
+    };
+
+    let read = step(users::get(state, id, false).await);
+    results.push(("read", read.and_then(|(user, _)| expect_user(user, "Self Test", &email))));
+
+    let by_email = step(users::by_email(state, &email.to_uppercase()).await);
+    results.push(("read by email", by_email.and_then(|user| expect_user(user, "Self Test", &email))));
+
+    let update = UpdateUser {
+        name: Some(String::from("Self Test Updated")),
+        email: None,
+        phone: None,
+        profile: None,
+        version: None,
+    };
+    let updated = step(users::update(state, id, update, None, false).await);
+    results.push(("update", updated.map(|_| ())));
+
+    let reread = step(users::get(state, id, false).await);
+    results.push((
+        "verify update",
+        reread.and_then(|(user, _)| expect_user(user, "Self Test Updated", &email)),
+    ));
+
+    let soft_deleted = step(users::delete(state, id, false, None, false).await);
+    results.push(("soft delete", soft_deleted.map(|_| ())));
+
+    let restored = step(users::restore(state, id, false).await);
+    results.push((
+        "restore",
+        restored.and_then(|(user, _)| expect_user(Some(user), "Self Test Updated", &email)),
+    ));
+
+    let deleted = step(users::delete(state, id, true, None, false).await);
+    results.push(("delete", deleted.map(|_| ())));
+
+    let gone = step(users::get(state, id, false).await).and_then(|(user, _)| match user {
+        None => Ok(()),
+        Some(other) => Err(format!("user still present {:?}", other)),
+    });
+    results.push(("verify delete", gone));
+}
+
+// Runs the same cycle against the storage of the `users` rows alone, below
+// the email claims and the name index, so it can run on any backend.
+async fn exercise_storage(users: &dyn UserRepository, results: &mut Vec<(&'static str, StepResult)>) {
+    let now = Utc::now();
+    let id = Uuid::new_v4();
+    let email = format!("self-test-storage-{}@example.com", id);
+    let user = User {
+        id,
+        name: String::from("Self Test Storage"),
+        email: email.clone(),
+        phone: None,
+        profile: None,
+        created_at: Some(now),
+        updated_at: Some(now),
+        expires_at: None,
+        verified: Some(false),
+        version: Some(1),
+        status: Some(UserStatus::Active),
+        anonymized_at: None,
+    };
+    let applied = |result: Result<(bool, Vec<Uuid>), ApiError>| match step(result)? {
+        (true, _) => Ok(()),
+        (false, _) => Err(String::from("write not applied")),
+    };
+    let live = |row: Option<(User, bool)>, name: &str| match row {
+        Some((user, false)) => expect_user(Some(user), name, &email),
+        Some((user, true)) => Err(format!("user is deleted {:?}", user)),
+        None => Err(String::from("user not found")),
+    };
+
+    let inserted = step(users.insert(&user, Some("self-test-hash"), false).await);
+    results.push(("storage insert", inserted.map(|_| ())));
+
+    // Conditions compare with the row as stored, at the store's precision.
+    let read = step(users.get(id, false).await);
+    let stored = match &read {
+        Ok((Some((stored, _)), _)) => stored.clone(),
+        _ => user,
+    };
+    results.push(("storage read", read.and_then(|(row, _)| live(row, "Self Test Storage"))));
+
+    let update = UpdateUser {
+        name: Some(String::from("Self Test Storage Updated")),
+        email: None,
+        phone: None,
+        profile: None,
+        version: None,
+    };
+    let updated = users
+        .update(&stored, &update, Utc::now(), Expect::Unchanged(&stored), false)
+        .await;
+    results.push(("storage update", applied(updated)));
+
+    let stale = users.soft_delete(id, Utc::now(), None, Expect::Unchanged(&stored), false).await;
+    let stale = match step(stale) {
+        Ok((false, _)) => Ok(()),
+        Ok((true, _)) => Err(String::from("write applied to a changed row")),
+        Err(e) => Err(e),
+    };
+    results.push(("storage stale write", stale));
+
+    let soft_deleted = users.soft_delete(id, Utc::now(), None, Expect::Exists, false).await;
+    results.push(("storage soft delete", applied(soft_deleted)));
+
+    let restored = users.restore(id, Utc::now(), None, false).await;
+    results.push(("storage restore", applied(restored)));
+
+    let reread = step(users.get(id, false).await);
+    results.push((
+        "storage verify",
+        reread.and_then(|(row, _)| live(row, "Self Test Storage Updated")),
+    ));
+
+    let deleted = users.delete(id, Expect::Exists, false).await;
+    results.push(("storage delete", applied(deleted)));
+
+    let gone = step(users.get(id, false).await).and_then(|(row, _)| match row {
+        None => Ok(()),
+        Some(other) => Err(format!("user still present {:?}", other)),
+    });
+    results.push(("storage verify delete", gone));
+}
+
+// Stores one user the way it was written before the index tables existed,
+// runs the backfill and checks the user can be found by email and by name.
+async fn exercise_backfill(
+    state: &AppState,
+    concurrency: usize,
+    results: &mut Vec<(&'static str, StepResult)>,
+) {
+    let id = Uuid::new_v4();
+    let name = format!("Self Test Legacy {}", id);
+    let email = format!("self-test-legacy-{}@example.com", id);
+    let now = Utc::now();
+    let user = User {
+        id,
+        name: name.clone(),
+        email: email.clone(),
+        phone: None,
+        profile: None,
+        created_at: Some(now),
+        updated_at: Some(now),
+        expires_at: None,
+        verified: None,
+        version: None,
+        status: None,
+        anonymized_at: None,
+    };
+    let seeded = state
+        .session
+        .execute_unpaged(
+            &state.statements.insert_user,
+            NewUserRow::new(&state.keyspace, &user, None),
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+    results.push(("seed legacy user", seeded));
+
+    let report = step(backfill::run(state, concurrency).await);
+    results.push((
+        "backfill",
+        report.and_then(|report| {
+            if report.complete() {
+                Ok(())
+            } else {
+                Err(format!("incomplete backfill {:?}", report))
+            }
+        }),
+    ));
+
+    let claimed = step(emails::owner(state, &email).await).and_then(|owner| match owner {
+        Some(owner) if owner == id => Ok(()),
+        other => Err(format!("email claimed by {:?}", other)),
+    });
+    results.push(("verify email index", claimed));
+
+    let search = SearchUsersQuery {
+        name_prefix: Some(name.clone()),
+        q: None,
+        limit: None,
+        cursor: None,
+    };
+    let indexed = step(users::search(state, &search, false).await).and_then(|listing| {
+        if listing.page.users.iter().any(|user| user.id == id) {
+            Ok(())
+        } else {
+            Err(String::from("user not found by name"))
+        }
+    });
+    results.push(("verify name index", indexed));
+
+    let deleted = step(users::delete(state, id, true, None, false).await);
+    results.push(("delete legacy user", deleted.map(|_| ())));
+}
+
+async fn drop_keyspace(session: &Session, keyspace: &str) -> StepResult {
+    session
+        .query_unpaged(format!("DROP KEYSPACE IF EXISTS {}", keyspace), &[])
+        .await
+        .map_err(|e| e.to_string())?;
+    session
+        .await_schema_agreement()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Runs a scripted create/read/update/delete cycle in a temporary keyspace
+// named after `self_test.keyspace`, printing one line per step and a summary,
+// then drops that keyspace. Returns whether every step passed. It refuses to
+// run against the serving keyspace so it can never touch real data.
+pub async fn run(session: Arc<Session>, config: &Config) -> bool {
+    let keyspace = temporary_keyspace(&config.self_test.keyspace);
+    if config.self_test.keyspace == config.scylla.keyspace || keyspace == config.scylla.keyspace {
+        tracing::error!(
+            keyspace = %config.self_test.keyspace,
+            "self-test refused: keyspace is the serving keyspace, set self_test.keyspace"
+        );
+        return false;
+    }
+
+    let mut results: Vec<(&str, StepResult)> = Vec::new();
+    match prepare(session.clone(), &keyspace, config).await {
+        Ok(state) => {
+            results.push(("schema", Ok(())));
+            exercise(&state, &mut results).await;
+            exercise_storage(state.users.as_ref(), &mut results).await;
+            exercise_backfill(&state, config.scylla.backfill_concurrency, &mut results).await;
+        }
+        Err(e) => results.push(("schema", Err(e))),
+    }
+    results.push(("drop keyspace", drop_keyspace(&session, &keyspace).await));
+
+    let mut failures = 0;
+    for (step, result) in &results {
+        match result {
+            Ok(()) => tracing::info!(step, "self-test step passed"),
+            Err(e) => {
+                failures += 1;
+                tracing::error!(step, error = %e, "self-test step failed");
+            }
+        }
+    }
+    tracing::info!(
+        keyspace = %keyspace,
+        passed = results.len() - failures,
+        total = results.len(),
+        "self-test finished"
+    );
+
+    failures == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUsers;
+
+    #[test]
+    fn temporary_keyspaces_are_unique_and_short_enough() {
+        let first = temporary_keyspace("self_test");
+        assert!(first.starts_with("self_test_"));
+        assert_ne!(first, temporary_keyspace("self_test"));
+        assert_eq!(temporary_keyspace(&"k".repeat(48)).len(), MAX_KEYSPACE_LEN);
+    }
+
+    #[actix_web::test]
+    async fn storage_cycle_passes_in_memory() {
+        let mut results = Vec::new();
+        exercise_storage(&MemoryUsers::default(), &mut results).await;
+        assert_eq!(results.len(), 9);
+        for (step, result) in results {
+            assert_eq!(result, Ok(()), "{}", step);
+        }
+    }
+}
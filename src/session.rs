@@ -0,0 +1,204 @@
+use crate::config::{PoolSizePer, ScyllaConfig, SpeculativeExecution};
+use crate::observe;
+use openssl::ssl::{SslContext, SslContextBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use scylla::execution_profile::ExecutionProfileHandle;
+use scylla::load_balancing::{FallbackPlan, LoadBalancingPolicy, RoutingInfo};
+use scylla::routing::Shard;
+use scylla::speculative_execution::{
+    PercentileSpeculativeExecutionPolicy, SimpleSpeculativeExecutionPolicy,
+    SpeculativeExecutionPolicy,
+};
+use scylla::transport::errors::QueryError;
+use scylla::transport::load_balancing::{DefaultPolicy, LatencyAwarenessBuilder};
+use scylla::transport::session::PoolSize;
+use scylla::transport::{ClusterData, NodeRef};
+use scylla::{CloudSessionBuilder, ExecutionProfile, Session, SessionBuilder};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Client-side TLS for the driver: the cluster's certificate is verified against
+// `tls_ca_path` (or the system store), and `tls_cert_path`/`tls_key_path` are
+// presented for clusters that require client certificates.
+fn ssl_context(config: &ScyllaConfig) -> Result<SslContext, String> {
+    let mut builder = SslContextBuilder::new(SslMethod::tls()).map_err(|e| e.to_string())?;
+    match &config.tls_ca_path {
+        Some(ca) => builder
+            .set_ca_file(ca)
+            .map_err(|e| format!("cannot load CA bundle {}: {}", ca.display(), e))?,
+        None => builder.set_default_verify_paths().map_err(|e| e.to_string())?,
+    }
+    if let (Some(cert), Some(key)) = (&config.tls_cert_path, &config.tls_key_path) {
+        builder
+            .set_certificate_chain_file(cert)
+            .map_err(|e| format!("cannot load client certificate {}: {}", cert.display(), e))?;
+        builder
+            .set_private_key_file(key, SslFiletype::PEM)
+            .map_err(|e| format!("cannot load client key {}: {}", key.display(), e))?;
+        builder.check_private_key().map_err(|e| e.to_string())?;
+    }
+    builder.set_verify(SslVerifyMode::PEER);
+    Ok(builder.build())
+}
+
+// Routes requests as `policy` does, and tells `observe` which node each
+// attempt went to, so slow queries can name it.
+#[derive(Debug)]
+struct Observed(Arc<dyn LoadBalancingPolicy>);
+
+impl LoadBalancingPolicy for Observed {
+    fn pick<'a>(
+        &'a self,
+        query: &'a RoutingInfo,
+        cluster: &'a ClusterData,
+    ) -> Option<(NodeRef<'a>, Option<Shard>)> {
+        self.0.pick(query, cluster)
+    }
+
+    fn fallback<'a>(&'a self, query: &'a RoutingInfo, cluster: &'a ClusterData) -> FallbackPlan<'a> {
+        self.0.fallback(query, cluster)
+    }
+
+    fn on_query_success(&self, query: &RoutingInfo, latency: Duration, node: NodeRef<'_>) {
+        observe::record_coordinator(SocketAddr::new(node.address.ip(), node.address.port()));
+        self.0.on_query_success(query, latency, node);
+    }
+
+    fn on_query_failure(
+        &self,
+        query: &RoutingInfo,
+        latency: Duration,
+        node: NodeRef<'_>,
+        error: &QueryError,
+    ) {
+        observe::record_coordinator(SocketAddr::new(node.address.ip(), node.address.port()));
+        self.0.on_query_failure(query, latency, node, error);
+    }
+
+    fn name(&self) -> String {
+        self.0.name()
+    }
+}
+
+// Default execution profile for every statement. Load balancing is always
+// token-aware, so requests go straight to a replica of their partition; with
+// `local_datacenter` set, replicas in that DC are preferred and remote DCs are
+// only used when `dc_failover` allows it. A statement the cluster hasn't
+// answered within `request_timeout_ms` fails with a client timeout.
+// The speculative execution policy `speculative_execution` names. The driver
+// only speculates on statements marked idempotent; see
+// `Statements::mark_idempotent`.
+fn speculative_execution(config: &ScyllaConfig) -> Option<Arc<dyn SpeculativeExecutionPolicy>> {
+    let max_retry_count = config.speculative_max_executions;
+    match config.speculative_execution {
+        SpeculativeExecution::Off => None,
+        SpeculativeExecution::Simple => Some(Arc::new(SimpleSpeculativeExecutionPolicy {
+            max_retry_count,
+            retry_interval: Duration::from_millis(config.speculative_delay_ms),
+        })),
+        SpeculativeExecution::Percentile => Some(Arc::new(PercentileSpeculativeExecutionPolicy {
+            max_retry_count,
+            percentile: config.speculative_percentile,
+        })),
+    }
+}
+
+// Token awareness routes to the replicas of a statement's partition, whether
+// the table is laid out by vnodes or by tablets; the driver learns tablet
+// replicas from the nodes it asks, so there is nothing to set for them.
+fn execution_profile(config: &ScyllaConfig) -> ExecutionProfileHandle {
+    let mut policy = DefaultPolicy::builder()
+        .token_aware(config.token_aware)
+        .permit_dc_failover(config.dc_failover);
+    if let Some(datacenter) = &config.local_datacenter {
+        policy = policy.prefer_datacenter(datacenter.clone());
+    }
+    if config.latency_awareness {
+        policy = policy.latency_awareness(LatencyAwarenessBuilder::new());
+    }
+    ExecutionProfile::builder()
+        .load_balancing_policy(Arc::new(Observed(policy.build())))
+        .request_timeout(Some(Duration::from_millis(config.request_timeout_ms)))
+        .speculative_execution_policy(speculative_execution(config))
+        .build()
+        .into_handle()
+}
+
+// The connection pool `pool_size` and `pool_size_per` describe.
+fn pool_size(config: &ScyllaConfig) -> PoolSize {
+    let size = NonZeroUsize::new(config.pool_size).unwrap_or(NonZeroUsize::MIN);
+    match config.pool_size_per {
+        PoolSizePer::Host => PoolSize::PerHost(size),
+        PoolSizePer::Shard => PoolSize::PerShard(size),
+    }
+}
+
+// Where the session connects to.
+#[derive(Debug, PartialEq)]
+enum Target<'a> {
+    Cloud(&'a Path),
+    Nodes(&'a [String]),
+}
+
+// A ScyllaDB Cloud connection bundle replaces plain contact points: when one
+// is configured the session goes through the SNI proxy it describes, with
+// the TLS settings the bundle carries, instead of the listed nodes.
+fn target(config: &ScyllaConfig) -> Target<'_> {
+    match &config.cloud_bundle {
+        Some(bundle) => Target::Cloud(bundle),
+        None => Target::Nodes(&config.nodes),
+    }
+}
+
+// Opens the driver session described by the `[scylla]` config.
+pub async fn connect(config: &ScyllaConfig) -> Result<Session, String> {
+    let nodes = match target(config) {
+        Target::Cloud(bundle) => return connect_cloud(config, bundle).await,
+        Target::Nodes(nodes) => nodes,
+    };
+    let mut builder = SessionBuilder::new()
+        .known_nodes(nodes)
+        .default_execution_profile_handle(execution_profile(config))
+        .pool_size(pool_size(config))
+        .disallow_shard_aware_port(!config.shard_aware_port);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.user(username, password);
+    }
+    if config.tls {
+        builder = builder.ssl_context(Some(ssl_context(config)?));
+    }
+    builder
+        .build()
+        .await
+        .map_err(|e| format!("cannot connect to ScyllaDB: {}", e))
+}
+
+async fn connect_cloud(config: &ScyllaConfig, bundle: &Path) -> Result<Session, String> {
+    CloudSessionBuilder::new(bundle)
+        .map_err(|e| format!("invalid ScyllaDB Cloud bundle {:?}: {}", bundle, e))?
+        .default_execution_profile_handle(execution_profile(config))
+        .pool_size(pool_size(config))
+        .disallow_shard_aware_port(!config.shard_aware_port)
+        .build()
+        .await
+        .map_err(|e| format!("cannot connect to ScyllaDB Cloud: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn a_cloud_bundle_replaces_the_contact_points() {
+        let mut config = ScyllaConfig {
+            nodes: vec![String::from("127.0.0.1:9042")],
+            ..ScyllaConfig::default()
+        };
+        assert_eq!(target(&config), Target::Nodes(&config.nodes));
+        config.cloud_bundle = Some(PathBuf::from("/etc/scylla/bundle.yaml"));
+        assert_eq!(target(&config), Target::Cloud(Path::new("/etc/scylla/bundle.yaml")));
+    }
+}
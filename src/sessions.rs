@@ -0,0 +1,424 @@
+use crate::api_keys::{constant_time_eq, hash_secret, new_secret};
+use crate::auth::{self, AuthError, JwtAuth, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, Problem};
+use crate::login::LoginResponse;
+use crate::models::UserStatus;
+use crate::observe;
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// POST /login starts a session in `sessions` and answers with a short-lived
+// access token naming it (`sid`) and a refresh token of the form
+// `<session id>.<secret>`, stored by the SHA-256 of the secret as API keys
+// are. POST /token/refresh trades the refresh token for a new access token
+// and a new secret, swapped in with a lightweight transaction, so each
+// refresh token works once. Presenting one that has already been swapped out
+// means it was copied, and ends the session for whoever holds it.
+//
+// Access tokens are refused once their session's row is gone, so deleting
+// the row signs the session out: POST /logout, DELETE /users/{id}/sessions
+// and a password reset all do. Sessions end `auth.refresh_token_ttl_secs`
+// after login however often they are refreshed, with their row's TTL.
+
+// Longest User-Agent kept as a session's device.
+const MAX_DEVICE_LEN: usize = 200;
+
+/// A signed-in session of a user.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Session {
+    pub id: Uuid,
+    /// The User-Agent of the login.
+    pub device: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// When the refresh token was last used; absent until it is.
+    pub refreshed_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether the caller is signed in with this session.
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    /// The refresh token from POST /login or the last refresh.
+    pub refresh_token: String,
+}
+
+// The device a session is started from, by its User-Agent.
+fn device(req: &HttpRequest) -> Option<String> {
+    let agent = req.headers().get(header::USER_AGENT)?.to_str().ok()?.trim();
+    if agent.is_empty() {
+        return None;
+    }
+    Some(agent.chars().take(MAX_DEVICE_LEN).collect())
+}
+
+// The session id and secret of a presented refresh token.
+fn parse(refresh_token: &str) -> Option<(Uuid, &str)> {
+    let (id, secret) = refresh_token.split_once('.')?;
+    Some((Uuid::parse_str(id).ok()?, secret))
+}
+
+fn refresh_token(session_id: Uuid, secret: &str) -> String {
+    format!("{}.{}", session_id, secret)
+}
+
+// Starts a session for `user_id`, returning its id and refresh token.
+async fn start(
+    state: &AppState,
+    jwt: &JwtAuth,
+    user_id: Uuid,
+    req: &HttpRequest,
+) -> Result<(Uuid, String), ApiError> {
+    let id = Uuid::new_v4();
+    let secret = new_secret();
+    let now = Utc::now();
+    let lifetime = jwt.refresh_ttl();
+    let span = chrono::Duration::from_std(lifetime)
+        .map_err(|e| ApiError::internal("Invalid session TTL", e))?;
+    let expires_at = now + span;
+    let ttl = i32::try_from(lifetime.as_secs()).unwrap_or(i32::MAX);
+    let values = (
+        id,
+        user_id,
+        hash_secret(&secret),
+        device(req),
+        now,
+        None::<DateTime<Utc>>,
+        expires_at,
+        ttl,
+    );
+    observe::query(state, "insert_session", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.insert_session, &values)
+    })
+    .await?;
+    Ok((id, refresh_token(id, &secret)))
+}
+
+// Signs `user_id` in: starts a session and issues its first access token.
+pub async fn sign_in(
+    state: &AppState,
+    jwt: &JwtAuth,
+    user_id: Uuid,
+    req: &HttpRequest,
+) -> Result<LoginResponse, ApiError> {
+    let (session_id, refresh_token) = start(state, jwt, user_id, req).await?;
+    let access_token = jwt
+        .issue(&user_id.to_string(), session_id)
+        .map_err(|e| ApiError::internal("Failed to issue token", e))?;
+    Ok(LoginResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: jwt.token_ttl().as_secs(),
+        refresh_token,
+    })
+}
+
+// The user, secret hash and expiry of a live session.
+async fn lookup(
+    state: &AppState,
+    session_id: Uuid,
+) -> Result<Option<(Uuid, String, DateTime<Utc>)>, ApiError> {
+    let result = observe::query(state, "select_session", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_session, (session_id,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading session", e))?
+        .maybe_first_row::<(Uuid, String, DateTime<Utc>)>()
+        .map_err(|e| ApiError::internal("Error reading session", e))
+}
+
+// Whether the session an access token names is still live and belongs to the
+// token's subject.
+pub async fn is_live(state: &AppState, session_id: Uuid, subject: &str) -> Result<bool, AuthError> {
+    let session = lookup(state, session_id)
+        .await
+        .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+    Ok(session.is_some_and(|(user_id, _, _)| user_id.to_string() == subject))
+}
+
+async fn delete(state: &AppState, session_id: Uuid) -> Result<(), ApiError> {
+    observe::query(state, "delete_session", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.delete_session, (session_id,))
+    })
+    .await?;
+    Ok(())
+}
+
+// The user's sessions, in no particular order.
+async fn user_sessions(state: &AppState, user_id: Uuid) -> Result<Vec<Session>, ApiError> {
+    let result = observe::query(state, "select_user_sessions", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_user_sessions, (user_id,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading sessions", e))?
+        .rows::<(Uuid, Option<String>, DateTime<Utc>, Option<DateTime<Utc>>, DateTime<Utc>)>()
+        .map_err(|e| ApiError::internal("Error reading sessions", e))?
+        .map(|row| {
+            row.map(|(id, device, created_at, refreshed_at, expires_at)| Session {
+                id,
+                device,
+                created_at,
+                refreshed_at,
+                expires_at,
+                current: false,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Error reading sessions", e))
+}
+
+// Swaps the session's secret for `next` if it is still `token_hash`,
+// returning whether it was. The row keeps the expiry it was started with.
+async fn rotate(
+    state: &AppState,
+    session_id: Uuid,
+    token_hash: &str,
+    next: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<bool, ApiError> {
+    let values = (
+        users::ttl(Some(expires_at)),
+        hash_secret(next),
+        Utc::now(),
+        session_id,
+        token_hash,
+    );
+    let result = observe::conditional(state, "rotate_session", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.rotate_session, &values)
+    })
+    .await?;
+    statements::applied(result).map_err(|e| ApiError::internal("Failed to refresh session", e))
+}
+
+// Ends every session of the user, returning how many there were.
+pub async fn revoke_all(state: &AppState, user_id: Uuid) -> Result<usize, ApiError> {
+    let sessions = user_sessions(state, user_id).await?;
+    for session in &sessions {
+        delete(state, session.id).await?;
+    }
+    Ok(sessions.len())
+}
+
+/// Trades a refresh token for a new access token and refresh token. Each
+/// refresh token works once; presenting one again ends its session.
+#[utoipa::path(
+    post,
+    path = "/token/refresh",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Refresh token accepted", body = LoginResponse),
+        (status = 401, description = "Unknown, reused or expired refresh token"),
+        (status = 503, description = "Token signing is not configured"),
+    )
+)]
+pub async fn refresh(
+    request: web::Json<RefreshRequest>,
+    data: web::Data<AppState>,
+    jwt_auth: Option<web::Data<JwtAuth>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(jwt_auth) = jwt_auth else {
+        return Err(AuthError::Unavailable(String::from("auth.jwt_secret is not configured")).into());
+    };
+    let (session_id, secret) = parse(&request.refresh_token).ok_or(AuthError::RevokedSession)?;
+    let (user_id, token_hash, expires_at) =
+        lookup(&data, session_id).await?.ok_or(AuthError::RevokedSession)?;
+    if !constant_time_eq(&token_hash, &hash_secret(secret)) {
+        tracing::warn!(%session_id, %user_id, "reused refresh token; ending the session");
+        delete(&data, session_id).await?;
+        return Err(AuthError::RevokedSession.into());
+    }
+    // A user suspended since the session started can't keep it.
+    let live = users::stored_row(&data, user_id).await?;
+    if !matches!(live, Some((user, false)) if user.status != Some(UserStatus::Suspended)) {
+        delete(&data, session_id).await?;
+        return Err(AuthError::RevokedSession.into());
+    }
+
+    let next = new_secret();
+    if !rotate(&data, session_id, &token_hash, &next, expires_at).await? {
+        // Another refresh with the same token won the race.
+        tracing::warn!(%session_id, %user_id, "reused refresh token; ending the session");
+        delete(&data, session_id).await?;
+        return Err(AuthError::RevokedSession.into());
+    }
+
+    let access_token = jwt_auth
+        .issue(&user_id.to_string(), session_id)
+        .map_err(|e| ApiError::internal("Failed to issue token", e))?;
+    Ok(HttpResponse::Ok().json(LoginResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: jwt_auth.token_ttl().as_secs(),
+        refresh_token: refresh_token(session_id, &next),
+    }))
+}
+
+/// Ends the session the bearer token belongs to.
+#[utoipa::path(
+    post,
+    path = "/logout",
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "Session ended"),
+        (status = 400, description = "The token belongs to no session", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+    )
+)]
+pub async fn logout(
+    subject: Option<web::ReqData<Subject>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subject = auth::authorize(subject.as_deref(), |_| true, "")?;
+    let session_id = subject
+        .session
+        .ok_or_else(|| ApiError::BadRequest(String::from("The token belongs to no session")))?;
+    delete(&data, session_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn authorize_for(subject: Option<&Subject>, user_id: Uuid) -> Result<&Subject, AuthError> {
+    auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only manage their own sessions unless they have the admin role",
+    )
+}
+
+/// The user's live sessions, most recently started first.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/sessions",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user's sessions", body = [Session]),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn list_sessions(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    let subject = authorize_for(subject.as_deref(), user_id)?;
+    let mut sessions = user_sessions(&data, user_id).await?;
+    for session in &mut sessions {
+        session.current = subject.session == Some(session.id);
+    }
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.created_at));
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Ends every session of the user, signing it out everywhere.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/sessions",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Sessions ended", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn revoke_sessions(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let ended = revoke_all(&data, user_id).await?;
+    tracing::info!(%user_id, ended, "sessions revoked");
+    Ok(HttpResponse::Ok().json(format!("{} sessions of user {} ended", ended, user_id)))
+}
+
+/// Ends one session of the user.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/sessions/{session_id}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("session_id" = Uuid, Path, description = "Session id"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Session ended", body = String),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such session of the user", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn revoke_session(
+    subject: Option<web::ReqData<Subject>>,
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, session_id) = path.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    match lookup(&data, session_id).await? {
+        Some((owner, _, _)) if owner == user_id => {}
+        _ => {
+            let message = format!("Session {} of user {} not found", session_id, user_id);
+            return Err(ApiError::NotFound(message).into());
+        }
+    }
+    delete(&data, session_id).await?;
+    Ok(HttpResponse::Ok().json(format!("Session {} ended", session_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn refresh_tokens_name_their_session() {
+        let id = Uuid::from_u128(7);
+        let token = refresh_token(id, "secret.with.dots");
+        assert_eq!(parse(&token), Some((id, "secret.with.dots")));
+        assert_eq!(parse("not-a-uuid.secret"), None);
+        assert_eq!(parse("no-separator"), None);
+    }
+
+    #[test]
+    fn devices_come_from_the_user_agent() {
+        let req = TestRequest::default()
+            .insert_header((header::USER_AGENT, "curl/8.5.0"))
+            .to_http_request();
+        assert_eq!(device(&req).as_deref(), Some("curl/8.5.0"));
+
+        let long = "x".repeat(MAX_DEVICE_LEN * 2);
+        let req = TestRequest::default()
+            .insert_header((header::USER_AGENT, long))
+            .to_http_request();
+        assert_eq!(device(&req).map(|device| device.len()), Some(MAX_DEVICE_LEN));
+        assert_eq!(device(&TestRequest::default().to_http_request()), None);
+    }
+}
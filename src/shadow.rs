@@ -0,0 +1,648 @@
+use crate::breaker::Breaker;
+use crate::config::{Config, ScyllaConfig};
+use crate::error::ApiError;
+use crate::limiter::Limiter;
+use crate::metrics::Metrics;
+use crate::models::{UpdateUser, User};
+use crate::observe;
+use crate::repository::{BatchWrite, ColumnWrite, Expect, Outcome, ScyllaUsers, UserRepository};
+use crate::retry::RetryPolicy;
+use crate::session;
+use crate::state::AppState;
+use crate::statements::Statements;
+use crate::users::Registration;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use scylla::Session;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+// Shadow reads, for moving to another cluster: with `shadow.enabled`, a
+// `shadow.sample_rate` share of the reads of a user by id, the ones behind
+// GET /users/{id} and every change to a user, are repeated against the
+// cluster at `shadow.nodes` once the serving cluster has answered. The two
+// rows are compared in the background and a user that differs, or that only
+// one cluster has, is logged with the fields that differ and counted in
+// `shadow_reads_total`. Clients only ever get the serving cluster's answer
+// and never wait on the shadow one, which has a breaker and limiter of its
+// own; at most `shadow.max_in_flight` shadow reads run at once and the rest
+// are skipped. With no `shadow.nodes`, the shadow is `shadow.keyspace` on
+// the serving cluster, for moving to another keyspace.
+//
+// With `shadow.dual_write`, every write to a user the serving cluster
+// applies is then applied to the shadow one too, before the request is
+// answered. One that fails there, or finds the row missing or changed, fails
+// nothing: the user is logged, counted in `dual_writes_total` and recorded
+// in the serving keyspace's `dual_write_failures`, the users to copy across
+// again before switching over. Without it, replication into the shadow
+// cluster by other means (CDC, a restore) is what the reads check.
+//
+// Only `users` rows, through `UserRepository`, are shadowed: every write the
+// API makes to them goes through it, including the single columns
+// (`ColumnWrite`) and, once its CQL batch has gone through, POST /batch
+// (`mirror_batch`). The derived tables, listings and password hashes aren't
+// compared, and the derived tables aren't written. Neither are the rows
+// `restore` writes into a keyspace before it serves, nor the legacy user the
+// self-test seeds and removes. Tenants' keyspaces aren't shadowed.
+
+// Keeps users in `serving`, repeating a sample of the reads by id against
+// `shadow`, and the writes too with `failures`.
+pub struct ShadowUsers {
+    serving: Arc<dyn UserRepository>,
+    shadow: Arc<dyn UserRepository>,
+    sample_rate: f64,
+    in_flight: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+    // Where dual writes that failed are recorded, with `shadow.dual_write`.
+    failures: Option<Arc<dyn FailureStore>>,
+}
+
+// Where the users a dual write failed for are kept: `ScyllaFailures` is the
+// serving keyspace's `dual_write_failures`, `MemoryFailures` a list for
+// tests.
+pub trait FailureStore: Send + Sync {
+    fn record<'a>(
+        &'a self,
+        id: Uuid,
+        operation: &'static str,
+        error: &'a str,
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), ApiError>>;
+}
+
+// `dual_write_failures`, written like the serving keyspace's other tables.
+struct ScyllaFailures {
+    session: Arc<Session>,
+    statements: Arc<Statements>,
+    metrics: Arc<Metrics>,
+    retry: Arc<RetryPolicy>,
+    breaker: Arc<Breaker>,
+    limiter: Arc<Limiter>,
+}
+
+impl FailureStore for ScyllaFailures {
+    fn record<'a>(
+        &'a self,
+        id: Uuid,
+        operation: &'static str,
+        error: &'a str,
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), ApiError>> {
+        async move {
+            observe::query_with(
+                &self.metrics,
+                &self.retry,
+                &self.breaker,
+                &self.limiter,
+                "record_dual_write_failure",
+                || {
+                    self.session.execute_unpaged(
+                        &self.statements.record_dual_write_failure,
+                        (id, operation, error, at),
+                    )
+                },
+            )
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// The failures recorded, oldest first.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryFailures(std::sync::Mutex<Vec<(Uuid, &'static str, String)>>);
+
+#[cfg(test)]
+impl FailureStore for MemoryFailures {
+    fn record<'a>(
+        &'a self,
+        id: Uuid,
+        operation: &'static str,
+        error: &'a str,
+        _at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), ApiError>> {
+        self.0.lock().unwrap().push((id, operation, error.to_string()));
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+// How the shadow cluster's copy of a user compares with the serving one's.
+#[derive(Debug, PartialEq, Eq)]
+enum Parity {
+    Same,
+    OnlyServing,
+    OnlyShadow,
+    // Both have the user; these fields differ.
+    Differs(Vec<&'static str>),
+}
+
+// The `[scylla]` settings for the shadow cluster: its own contact points,
+// credentials, TLS and keyspace, and the serving cluster's timeouts, retries
+// and limits.
+fn cluster_config(config: &Config) -> ScyllaConfig {
+    let shadow = &config.shadow;
+    ScyllaConfig {
+        nodes: shadow.nodes.clone(),
+        cloud_bundle: None,
+        local_datacenter: shadow.local_datacenter.clone(),
+        username: shadow.username.clone(),
+        password: shadow.password.clone(),
+        tls: shadow.tls,
+        tls_ca_path: shadow.tls_ca_path.clone(),
+        tls_cert_path: None,
+        tls_key_path: None,
+        keyspace: shadow
+            .keyspace
+            .clone()
+            .unwrap_or_else(|| config.scylla.keyspace.clone()),
+        ..config.scylla.clone()
+    }
+}
+
+// Connects to the shadow cluster and makes `state` shadow its reads by id,
+// and its writes with `shadow.dual_write`. The shadow queries are kept out
+// of the serving metrics, but for `shadow_reads_total` and
+// `dual_writes_total`.
+pub async fn attach(state: &mut AppState, config: &Config) -> Result<(), String> {
+    let scylla = cluster_config(config);
+    let session = if scylla.nodes.is_empty() {
+        state.session.clone()
+    } else {
+        let session = session::connect(&scylla)
+            .await
+            .map_err(|e| format!("shadow cluster: {}", e))?;
+        Arc::new(session)
+    };
+    let mut statements = Statements::prepare(&session, &scylla.keyspace)
+        .await
+        .map_err(|e| format!("cannot prepare CQL statements on the shadow cluster: {}", e))?;
+    statements.mark_idempotent(&scylla.idempotent_statements);
+    let shadow = ScyllaUsers::new(
+        session,
+        Arc::new(statements),
+        scylla.keyspace.clone(),
+        Arc::new(Metrics::new()),
+        Arc::new(RetryPolicy::new(&scylla)),
+        Arc::new(Breaker::new(&scylla)),
+        Arc::new(Limiter::new(&scylla)),
+    );
+    tracing::info!(
+        nodes = ?scylla.nodes,
+        keyspace = %scylla.keyspace,
+        sample_rate = config.shadow.sample_rate,
+        dual_write = config.shadow.dual_write,
+        "shadowing users"
+    );
+    let failures = config.shadow.dual_write.then(|| {
+        Arc::new(ScyllaFailures {
+            session: state.session.clone(),
+            statements: state.statements.clone(),
+            metrics: state.metrics.clone(),
+            retry: state.retry.clone(),
+            breaker: state.breaker.clone(),
+            limiter: state.limiter.clone(),
+        }) as Arc<dyn FailureStore>
+    });
+    state.users = Arc::new(ShadowUsers {
+        serving: state.users.clone(),
+        shadow: Arc::new(shadow),
+        sample_rate: config.shadow.sample_rate,
+        in_flight: Arc::new(Semaphore::new(config.shadow.max_in_flight)),
+        metrics: state.metrics.clone(),
+        failures,
+    });
+    Ok(())
+}
+
+// Compares the rows the two clusters returned for one id. Times left until
+// expiry are read at different moments, so only whether a user expires is
+// compared.
+fn compare(serving: Option<&(User, bool)>, shadow: Option<&(User, bool)>) -> Parity {
+    let ((ours, our_deleted), (theirs, their_deleted)) = match (serving, shadow) {
+        (None, None) => return Parity::Same,
+        (Some(_), None) => return Parity::OnlyServing,
+        (None, Some(_)) => return Parity::OnlyShadow,
+        (Some(ours), Some(theirs)) => (ours, theirs),
+    };
+    let json = |user: &User| serde_json::to_value(&user.profile).unwrap_or_default();
+    let fields = [
+        ("name", ours.name != theirs.name),
+        ("email", ours.email != theirs.email),
+        ("phone", ours.phone != theirs.phone),
+        ("profile", json(ours) != json(theirs)),
+        ("created_at", ours.created_at != theirs.created_at),
+        ("updated_at", ours.updated_at != theirs.updated_at),
+        ("expires_at", ours.expires_at.is_some() != theirs.expires_at.is_some()),
+        ("verified", ours.verified != theirs.verified),
+        ("version", ours.version != theirs.version),
+        ("status", ours.status != theirs.status),
+        ("anonymized_at", ours.anonymized_at != theirs.anonymized_at),
+        ("deleted_at", our_deleted != their_deleted),
+    ];
+    let differing: Vec<_> = fields
+        .into_iter()
+        .filter(|(_, differs)| *differs)
+        .map(|(field, _)| field)
+        .collect();
+    if differing.is_empty() {
+        Parity::Same
+    } else {
+        Parity::Differs(differing)
+    }
+}
+
+impl ShadowUsers {
+    // Repeats the read of `id` against the shadow cluster in the background,
+    // if it is sampled, and compares the result with `serving`'s.
+    fn check(&self, id: Uuid, serving: Option<(User, bool)>) {
+        if rand::random::<f64>() >= self.sample_rate {
+            return;
+        }
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            self.metrics.shadow_read("skipped");
+            return;
+        };
+        let shadow = self.shadow.clone();
+        let metrics = self.metrics.clone();
+        actix_web::rt::spawn(async move {
+            let outcome = match shadow.get(id, false).await {
+                Ok((row, _)) => match compare(serving.as_ref(), row.as_ref()) {
+                    Parity::Same => "match",
+                    parity => {
+                        tracing::warn!(user_id = %id, ?parity, "shadow cluster differs");
+                        "mismatch"
+                    }
+                },
+                Err(e) => {
+                    tracing::warn!(user_id = %id, error = %e, "shadow read failed");
+                    "error"
+                }
+            };
+            metrics.shadow_read(outcome);
+            drop(permit);
+        });
+    }
+
+    // Applies a write the serving cluster applied to the shadow one as well,
+    // with `shadow.dual_write`: `write` makes it, reporting whether it
+    // applied. Users it fails for are recorded rather than failing the
+    // request.
+    async fn mirror<Fut>(&self, ids: &[Uuid], operation: &'static str, write: impl FnOnce() -> Fut)
+    where
+        Fut: Future<Output = Result<bool, ApiError>>,
+    {
+        let Some(failures) = &self.failures else {
+            return;
+        };
+        let error = match write().await {
+            Ok(true) => {
+                self.metrics.dual_write("applied");
+                return;
+            }
+            Ok(false) => String::from("not applied: the row is missing or has changed"),
+            Err(e) => e.to_string(),
+        };
+        let now = Utc::now();
+        for &id in ids {
+            self.metrics.dual_write("failed");
+            tracing::warn!(user_id = %id, operation, error, "dual write failed");
+            if let Err(e) = failures.record(id, operation, &error, now).await {
+                tracing::error!(user_id = %id, error = %e, "failed to record a failed dual write");
+            }
+        }
+    }
+}
+
+// Whether a write reported as `Traced<bool>` applied.
+async fn applied<T>(write: Outcome<'_, T>, applied: fn(&T) -> bool) -> Result<bool, ApiError> {
+    write.await.map(|(result, _)| applied(&result))
+}
+
+impl UserRepository for ShadowUsers {
+    fn get(&self, id: Uuid, tracing: bool) -> Outcome<'_, Option<(User, bool)>> {
+        async move {
+            let read = self.serving.get(id, tracing).await?;
+            self.check(id, read.0.clone());
+            Ok(read)
+        }
+        .boxed()
+    }
+
+    fn password_hash(&self, id: Uuid) -> Outcome<'_, Option<String>> {
+        self.serving.password_hash(id)
+    }
+
+    fn modified(&self, id: Uuid) -> Outcome<'_, Option<Option<DateTime<Utc>>>> {
+        self.serving.modified(id)
+    }
+
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+        password_hash: Option<&'a str>,
+        tracing: bool,
+    ) -> Outcome<'a, ()> {
+        async move {
+            let written = self.serving.insert(user, password_hash, tracing).await?;
+            self.mirror(&[user.id], "insert", || {
+                applied(self.shadow.insert(user, password_hash, false), |_| true)
+            })
+            .await;
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    fn insert_batch<'a>(&'a self, registrations: &'a [Registration]) -> Outcome<'a, ()> {
+        async move {
+            let written = self.serving.insert_batch(registrations).await?;
+            let ids: Vec<Uuid> = registrations
+                .iter()
+                .map(|registration| registration.user.id)
+                .collect();
+            self.mirror(&ids, "insert", || {
+                applied(self.shadow.insert_batch(registrations), |_| true)
+            })
+            .await;
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    // The shadow copy is changed whatever state it is in, as long as it
+    // exists: the serving cluster has already checked the condition.
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        update: &'a UpdateUser,
+        at: DateTime<Utc>,
+        expect: Expect<'a>,
+        tracing: bool,
+    ) -> Outcome<'a, bool> {
+        async move {
+            let written = self.serving.update(before, update, at, expect, tracing).await?;
+            if written.0 {
+                self.mirror(&[before.id], "update", || {
+                    let write = self.shadow.update(before, update, at, Expect::Exists, false);
+                    applied(write, |applied| *applied)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    fn replace<'a>(&'a self, user: &'a User, expect: Expect<'a>, tracing: bool) -> Outcome<'a, bool> {
+        async move {
+            let written = self.serving.replace(user, expect, tracing).await?;
+            if written.0 {
+                self.mirror(&[user.id], "replace", || {
+                    applied(self.shadow.replace(user, Expect::Exists, false), |applied| *applied)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    fn anonymize<'a>(&'a self, user: &'a User, expect: Expect<'a>) -> Outcome<'a, bool> {
+        async move {
+            let written = self.serving.anonymize(user, expect).await?;
+            if written.0 {
+                self.mirror(&[user.id], "anonymize", || {
+                    applied(self.shadow.anonymize(user, Expect::Exists), |applied| *applied)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    fn soft_delete<'a>(
+        &'a self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        expect: Expect<'a>,
+        tracing: bool,
+    ) -> Outcome<'a, bool> {
+        async move {
+            let written = self.serving.soft_delete(id, at, expires_at, expect, tracing).await?;
+            if written.0 {
+                self.mirror(&[id], "soft_delete", || {
+                    let write = self.shadow.soft_delete(id, at, expires_at, Expect::Exists, false);
+                    applied(write, |applied| *applied)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    fn restore(
+        &self,
+        id: Uuid,
+        at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        tracing: bool,
+    ) -> Outcome<'_, bool> {
+        async move {
+            let written = self.serving.restore(id, at, expires_at, tracing).await?;
+            if written.0 {
+                self.mirror(&[id], "restore", || {
+                    applied(self.shadow.restore(id, at, expires_at, false), |applied| *applied)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    // The shadow copy is written under the same condition, so one that has
+    // drifted is recorded rather than overwritten.
+    fn write_column<'a>(
+        &'a self,
+        id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        write: ColumnWrite<'a>,
+    ) -> Outcome<'a, bool> {
+        async move {
+            let written = self.serving.write_column(id, expires_at, write).await?;
+            if written.0 {
+                self.mirror(&[id], write.name(), || {
+                    applied(self.shadow.write_column(id, expires_at, write), |applied| *applied)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    // Each change is mirrored like the single writes above.
+    fn mirror_batch<'a>(
+        &'a self,
+        writes: &'a [BatchWrite],
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'a, ()> {
+        async move {
+            for write in writes {
+                match write {
+                    BatchWrite::Insert(registration) => {
+                        let user = &registration.user;
+                        let password_hash = registration.password_hash.as_deref();
+                        self.mirror(&[user.id], "insert", || {
+                            applied(self.shadow.insert(user, password_hash, false), |_| true)
+                        })
+                        .await
+                    }
+                    BatchWrite::Update { before, update } => {
+                        self.mirror(&[before.id], "update", || {
+                            let write =
+                                self.shadow.update(before, update, at, Expect::Exists, false);
+                            applied(write, |applied| *applied)
+                        })
+                        .await
+                    }
+                    BatchWrite::SoftDelete(before) => {
+                        self.mirror(&[before.id], "soft_delete", || {
+                            let (id, expires_at) = (before.id, before.expires_at);
+                            let write =
+                                self.shadow.soft_delete(id, at, expires_at, Expect::Exists, false);
+                            applied(write, |applied| *applied)
+                        })
+                        .await
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    // A user the shadow cluster doesn't have is as good as deleted there.
+    fn delete<'a>(&'a self, id: Uuid, expect: Expect<'a>, tracing: bool) -> Outcome<'a, bool> {
+        async move {
+            let written = self.serving.delete(id, expect, tracing).await?;
+            if written.0 {
+                self.mirror(&[id], "delete", || {
+                    applied(self.shadow.delete(id, Expect::Exists, false), |_| true)
+                })
+                .await;
+            }
+            Ok(written)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Profile, UserStatus};
+    use crate::repository::MemoryUsers;
+
+    // Dual writes over two memory stores, recording failures in the one
+    // returned.
+    fn dual_writes(
+        serving: Arc<MemoryUsers>,
+        shadow: Arc<MemoryUsers>,
+    ) -> (ShadowUsers, Arc<MemoryFailures>) {
+        let failures = Arc::new(MemoryFailures::default());
+        let users = ShadowUsers {
+            serving,
+            shadow,
+            sample_rate: 0.0,
+            in_flight: Arc::new(Semaphore::new(1)),
+            metrics: Arc::new(Metrics::new()),
+            failures: Some(failures.clone()),
+        };
+        (users, failures)
+    }
+
+    fn user() -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: None,
+            profile: Some(Profile {
+                bio: Some(String::from("Analyst")),
+                ..Profile::default()
+            }),
+            created_at: Some(Utc::now()),
+            updated_at: Some(Utc::now()),
+            expires_at: None,
+            verified: Some(true),
+            version: Some(3),
+            status: Some(UserStatus::Active),
+            anonymized_at: None,
+        }
+    }
+
+    #[test]
+    fn copies_are_compared_field_by_field() {
+        let ours = (user(), false);
+        assert_eq!(compare(Some(&ours), Some(&ours.clone())), Parity::Same);
+        assert_eq!(compare(Some(&ours), None), Parity::OnlyServing);
+        assert_eq!(compare(None, Some(&ours)), Parity::OnlyShadow);
+        assert_eq!(compare(None, None), Parity::Same);
+
+        let mut theirs = ours.clone();
+        theirs.0.version = Some(2);
+        theirs.0.profile = None;
+        theirs.1 = true;
+        assert_eq!(
+            compare(Some(&ours), Some(&theirs)),
+            Parity::Differs(vec!["profile", "version", "deleted_at"])
+        );
+    }
+
+    #[actix_web::test]
+    async fn writes_reach_both_copies() {
+        let serving = Arc::new(MemoryUsers::default());
+        let shadow = Arc::new(MemoryUsers::default());
+        let (users, failures) = dual_writes(serving.clone(), shadow.clone());
+        let ada = user();
+        users.insert(&ada, None, false).await.unwrap();
+        let suspend = ColumnWrite::Status {
+            from: UserStatus::Active,
+            to: UserStatus::Suspended,
+        };
+        let (applied, _) = users.write_column(ada.id, None, suspend).await.unwrap();
+        assert!(applied);
+        // A batch writes the serving copy itself, then mirrors its writes.
+        let at = Utc::now();
+        serving.soft_delete(ada.id, at, None, Expect::Exists, false).await.unwrap();
+        users.mirror_batch(&[BatchWrite::SoftDelete(ada.clone())], at).await;
+        for copy in [&serving, &shadow] {
+            let (stored, deleted) = copy.get(ada.id, false).await.unwrap().0.unwrap();
+            assert_eq!((stored.status, deleted), (Some(UserStatus::Suspended), true));
+        }
+        assert!(failures.0.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn failed_shadow_writes_are_recorded() {
+        let serving = Arc::new(MemoryUsers::default());
+        let (users, failures) = dual_writes(serving.clone(), Arc::new(MemoryUsers::default()));
+        // Stored before the dual writes began, so the shadow copy lacks it.
+        let ada = user();
+        serving.insert(&ada, None, false).await.unwrap();
+        let tag = ColumnWrite::AddTag("vip");
+        let (applied, _) = users.write_column(ada.id, None, tag).await.unwrap();
+        assert!(applied);
+        users.mirror_batch(&[BatchWrite::SoftDelete(ada.clone())], Utc::now()).await;
+        let recorded: Vec<_> = failures.0.lock().unwrap().iter().map(|f| (f.0, f.1)).collect();
+        assert_eq!(recorded, [(ada.id, "add_user_tag"), (ada.id, "soft_delete")]);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::models::{ListUsersQuery, User, UsersPage};
+use crate::redis::Redis;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::RwLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Users and listing pages cached in Redis, shared by every replica, as JSON
+// with a TTL. A write drops its user and starts a new listing generation:
+// listing keys include the generation, so pages cached before the write are
+// no longer found, even if a read that began before it stores one late, and
+// simply expire. A user read that races a write can still store the old row;
+// the TTL bounds how long it is served.
+//
+// Redis being slow or down never fails a request: errors are logged and the
+// cluster is read instead. While `Redis` backs off after an outage, commands
+// are skipped without logging each one; writes made meanwhile can't drop
+// their keys, and the TTL bounds how long those are served.
+
+pub struct SharedCache {
+    redis: Redis,
+    prefix: String,
+    ttl: RwLock<Duration>,
+}
+
+#[derive(Deserialize)]
+struct CachedListing {
+    page: UsersPage,
+    truncated: bool,
+}
+
+impl SharedCache {
+    pub fn new(redis: Redis, prefix: String, ttl: Duration) -> Self {
+        SharedCache {
+            redis,
+            prefix,
+            ttl: RwLock::new(ttl),
+        }
+    }
+
+    // Applies to keys written from now on.
+    pub fn set_ttl(&self, ttl: Duration) {
+        *self.ttl.write().unwrap() = ttl;
+    }
+
+    fn user_key(&self, user_id: Uuid) -> String {
+        format!("{}user:{}", self.prefix, user_id)
+    }
+
+    fn generation_key(&self) -> String {
+        format!("{}listing-generation", self.prefix)
+    }
+
+    fn listing_key(&self, generation: i64, params: &ListUsersQuery) -> Option<String> {
+        let params = serde_json::to_vec(params).ok()?;
+        let digest: String = Sha256::digest(params)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Some(format!("{}listing:{}:{}", self.prefix, generation, digest))
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, key: &str) -> Option<T> {
+        match self.redis.get(key).await {
+            Ok(value) => serde_json::from_slice(&value?).ok(),
+            Err(e) if e.is_down() => None,
+            Err(e) => {
+                tracing::warn!(error = %e, "shared cache read failed");
+                None
+            }
+        }
+    }
+
+    async fn put<T: Serialize>(&self, key: &str, value: &T) {
+        let Ok(value) = serde_json::to_vec(value) else {
+            return;
+        };
+        let ttl = *self.ttl.read().unwrap();
+        if let Err(e) = self.redis.set(key, &value, ttl).await
+            && !e.is_down()
+        {
+            tracing::warn!(error = %e, "shared cache write failed");
+        }
+    }
+
+    pub async fn user(&self, user_id: Uuid) -> Option<User> {
+        self.get(&self.user_key(user_id)).await
+    }
+
+    pub async fn put_user(&self, user: &User) {
+        self.put(&self.user_key(user.id), user).await;
+    }
+
+    // The generation listings are cached under; `None` when Redis can't be
+    // read, so the listing is neither looked up nor stored.
+    pub async fn listing_generation(&self) -> Option<i64> {
+        match self.redis.get(&self.generation_key()).await {
+            Ok(value) => Some(
+                value
+                    .and_then(|value| String::from_utf8(value).ok()?.parse().ok())
+                    .unwrap_or(0),
+            ),
+            Err(e) if e.is_down() => None,
+            Err(e) => {
+                tracing::warn!(error = %e, "shared cache read failed");
+                None
+            }
+        }
+    }
+
+    // A cached page, and whether the row cap truncated it.
+    pub async fn listing(
+        &self,
+        generation: i64,
+        params: &ListUsersQuery,
+    ) -> Option<(UsersPage, bool)> {
+        let key = self.listing_key(generation, params)?;
+        let listing: CachedListing = self.get(&key).await?;
+        Some((listing.page, listing.truncated))
+    }
+
+    pub async fn put_listing(
+        &self,
+        generation: i64,
+        params: &ListUsersQuery,
+        page: &UsersPage,
+        truncated: bool,
+    ) {
+        if let Some(key) = self.listing_key(generation, params) {
+            let listing = serde_json::json!({ "page": page, "truncated": truncated });
+            self.put(&key, &listing).await;
+        }
+    }
+
+    pub async fn invalidate(&self, user_id: Uuid) {
+        if let Err(e) = self.redis.del(&self.user_key(user_id)).await
+            && !e.is_down()
+        {
+            tracing::warn!(error = %e, %user_id, "shared cache invalidation failed");
+        }
+        if let Err(e) = self.redis.incr(&self.generation_key()).await
+            && !e.is_down()
+        {
+            tracing::warn!(error = %e, "shared cache listing invalidation failed");
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use crate::config::LoadSheddingConfig;
+use crate::error;
+use crate::metrics::Metrics;
+use crate::v1;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Adaptive load shedding: when Scylla slows down, requests pile up and every
+// one of them ends up timing out. Rather than that, the service turns some
+// away early with a 503 (code `overloaded`) and `Retry-After`, so the rest
+// are served in time. The load is the larger of two ratios: requests in
+// flight to `load_shedding.max_in_flight`, and the recent latency (a moving
+// average that fades once requests stop finishing) to `max_latency_ms`.
+//
+// Each request has a priority: reads (GET and HEAD) are low, writes normal,
+// unless the priority header says otherwise. From a load of 1 low-priority
+// requests are turned away, and from `shed_normal_at` normal ones too; high
+// ones always pass, as do probes, metrics and the admin routes. Without a
+// `web::Data<Shedder>` (shedding disabled) requests pass through.
+
+// Routes never shed, besides /admin/*.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+// Weight of each finished request in the latency average.
+const SMOOTHING: f64 = 0.1;
+// How fast the latency average fades while no request finishes, so shedding
+// every request of a kind doesn't keep it high for good.
+const LATENCY_DECAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+// The latency average, in milliseconds, and when it was last updated.
+struct Latency {
+    average_ms: f64,
+    updated_at: Instant,
+}
+
+pub struct Shedder {
+    max_in_flight: usize,
+    max_latency_ms: f64,
+    shed_normal_at: f64,
+    priority_header: HeaderName,
+    retry_after_secs: u64,
+    in_flight: AtomicUsize,
+    latency: Mutex<Latency>,
+    metrics: Arc<Metrics>,
+}
+
+fn exempt(path: &str) -> bool {
+    let path = v1::unversioned(path);
+    EXEMPT_PATHS.contains(&path) || path.starts_with("/admin/")
+}
+
+// `average_ms` faded for the time since `updated_at`.
+fn faded(latency: &Latency, now: Instant) -> f64 {
+    let idle = now.saturating_duration_since(latency.updated_at);
+    latency.average_ms * (-idle.as_secs_f64() / LATENCY_DECAY.as_secs_f64()).exp()
+}
+
+impl Shedder {
+    pub fn new(config: &LoadSheddingConfig, metrics: Arc<Metrics>) -> Self {
+        Shedder {
+            max_in_flight: config.max_in_flight,
+            max_latency_ms: config.max_latency_ms as f64,
+            shed_normal_at: config.shed_normal_at,
+            // Checked when the configuration was loaded.
+            priority_header: HeaderName::from_bytes(config.priority_header.as_bytes())
+                .unwrap_or(HeaderName::from_static("x-priority")),
+            retry_after_secs: config.retry_after_secs,
+            in_flight: AtomicUsize::new(0),
+            latency: Mutex::new(Latency {
+                average_ms: 0.0,
+                updated_at: Instant::now(),
+            }),
+            metrics,
+        }
+    }
+
+    fn priority(&self, method: &Method, header: Option<&HeaderValue>) -> Priority {
+        header
+            .and_then(|value| value.to_str().ok())
+            .and_then(Priority::from_name)
+            .unwrap_or(match *method {
+                Method::GET | Method::HEAD => Priority::Low,
+                _ => Priority::Normal,
+            })
+    }
+
+    // How loaded the service is; 1 is at a threshold.
+    fn load(&self) -> f64 {
+        let in_flight = self.in_flight.load(Ordering::Relaxed) as f64;
+        let latency_ms = faded(&self.latency.lock().unwrap(), Instant::now());
+        (in_flight / self.max_in_flight as f64).max(latency_ms / self.max_latency_ms)
+    }
+
+    fn admits(&self, priority: Priority) -> bool {
+        match priority {
+            Priority::Low => self.load() < 1.0,
+            Priority::Normal => self.load() < self.shed_normal_at,
+            Priority::High => true,
+        }
+    }
+
+    fn finished(&self, elapsed: Duration) {
+        let now = Instant::now();
+        let mut latency = self.latency.lock().unwrap();
+        let average_ms = faded(&latency, now);
+        latency.average_ms = average_ms + SMOOTHING * (elapsed.as_secs_f64() * 1000.0 - average_ms);
+        latency.updated_at = now;
+    }
+}
+
+// Counts a request in flight until it is dropped, finished or cancelled.
+struct InFlight<'a> {
+    shedder: &'a Shedder,
+    started: Instant,
+}
+
+impl<'a> InFlight<'a> {
+    fn start(shedder: &'a Shedder) -> Self {
+        shedder.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            shedder,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shedder.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.shedder.finished(self.started.elapsed());
+    }
+}
+
+pub async fn shed(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(shedder) = req.app_data::<web::Data<Shedder>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    if exempt(req.path()) {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+    let priority = shedder.priority(req.method(), req.headers().get(&shedder.priority_header));
+    if !shedder.admits(priority) {
+        shedder.metrics.request_shed(priority.name());
+        let mut response = error::problem(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            format!("the service is overloaded, retry in {}s", shedder.retry_after_secs),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(shedder.retry_after_secs));
+        return Ok(req.into_response(response));
+    }
+    let _in_flight = InFlight::start(&shedder);
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn shedder(max_in_flight: usize) -> Shedder {
+        Shedder::new(
+            &LoadSheddingConfig {
+                enabled: true,
+                max_in_flight,
+                ..LoadSheddingConfig::default()
+            },
+            Arc::new(Metrics::new()),
+        )
+    }
+
+    #[test]
+    fn reads_are_low_priority_unless_the_header_says_otherwise() {
+        let shedder = shedder(1);
+        assert_eq!(shedder.priority(&Method::GET, None), Priority::Low);
+        assert_eq!(shedder.priority(&Method::POST, None), Priority::Normal);
+        let high = HeaderValue::from_static("High");
+        assert_eq!(shedder.priority(&Method::GET, Some(&high)), Priority::High);
+        let unknown = HeaderValue::from_static("urgent");
+        assert_eq!(shedder.priority(&Method::DELETE, Some(&unknown)), Priority::Normal);
+    }
+
+    #[test]
+    fn low_priority_goes_first() {
+        let shedder = shedder(2);
+        let _first = InFlight::start(&shedder);
+        assert!(shedder.admits(Priority::Low));
+        let _second = InFlight::start(&shedder);
+        assert!(!shedder.admits(Priority::Low));
+        assert!(shedder.admits(Priority::Normal));
+        let _more = [InFlight::start(&shedder), InFlight::start(&shedder)];
+        assert!(!shedder.admits(Priority::Normal));
+        assert!(shedder.admits(Priority::High));
+    }
+
+    #[test]
+    fn slow_requests_raise_the_load_until_it_fades() {
+        let shedder = shedder(100);
+        for _ in 0..50 {
+            shedder.finished(Duration::from_secs(3));
+        }
+        assert!(!shedder.admits(Priority::Low));
+        let mut latency = shedder.latency.lock().unwrap();
+        latency.updated_at -= LATENCY_DECAY * 4;
+        assert!(faded(&latency, Instant::now()) < 100.0);
+    }
+
+    #[actix_web::test]
+    async fn shed_requests_get_a_503_with_retry_after() {
+        let shedder = web::Data::new(shedder(1));
+        let _busy = InFlight::start(&shedder);
+        let app = init_service(
+            App::new()
+                .app_data(shedder.clone())
+                .wrap(from_fn(shed))
+                .route("/users", web::get().to(HttpResponse::Ok))
+                .route("/users", web::post().to(HttpResponse::Created))
+                .route("/healthz", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().uri("/users").to_request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+
+        let write = TestRequest::post().uri("/users").to_request();
+        assert_eq!(call_service(&app, write).await.status(), StatusCode::CREATED);
+        let probe = TestRequest::get().uri("/healthz").to_request();
+        assert_eq!(call_service(&app, probe).await.status(), StatusCode::OK);
+        assert_eq!(shedder.in_flight.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,176 @@
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use actix_web::dev::ServerHandle;
+use actix_web::rt::task::JoinHandle;
+use actix_web::rt::{signal, time};
+use futures::future::{self, Either};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Shutdown runs in order: the listeners stop taking requests and drain, then
+// the background loops (the CDC consumer and the like) are told to stop and
+// get `http.background_grace_secs` to finish the iteration they are in, and
+// only then is the ScyllaDB session dropped, so no loop loses it mid-query.
+
+// Resolves on the first SIGINT or SIGTERM.
+async fn termination_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler can be installed");
+        let interrupt = std::pin::pin!(signal::ctrl_c());
+        let terminate = std::pin::pin!(terminate.recv());
+        match future::select(interrupt, terminate).await {
+            Either::Left(_) => "SIGINT",
+            Either::Right(_) => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+// actix's own signal handling treats SIGINT as a forced stop; both signals
+// drain here instead: the listeners close at once, and in-flight requests get
+// up to the server's shutdown timeout to finish before workers are stopped.
+// The gRPC listener, if any, drains alongside.
+pub async fn drain_on_signal(
+    servers: Vec<ServerHandle>,
+    #[cfg(feature = "grpc")] grpc: Option<grpc::Handle>,
+) {
+    let signal = termination_signal().await;
+    tracing::info!(signal, "shutting down, draining in-flight requests");
+    let grpc = async {
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &grpc {
+            grpc.stop().await;
+        }
+    };
+    future::join(
+        future::join_all(servers.iter().map(|server| server.stop(true))),
+        grpc,
+    )
+    .await;
+}
+
+// Handed to a background loop: tells it when shutdown has begun. A loop checks
+// it between iterations and never in the middle of one.
+pub struct Stopping(watch::Receiver<bool>);
+
+impl Stopping {
+    // Sleeps for `duration`, or less if shutdown begins meanwhile; returns
+    // whether the loop should go on.
+    pub async fn pause(&mut self, duration: Duration) -> bool {
+        let stop = std::pin::pin!(self.0.wait_for(|stop| *stop));
+        let sleep = std::pin::pin!(time::sleep(duration));
+        matches!(future::select(stop, sleep).await, Either::Right(_))
+    }
+
+    // Resolves once shutdown has begun, for a loop that waits on something
+    // else in the meantime.
+    pub async fn stopped(&mut self) {
+        let _ = self.0.wait_for(|stop| *stop).await;
+    }
+}
+
+// The background loops started beside the server.
+pub struct Tasks {
+    stop: watch::Sender<bool>,
+    running: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks::new()
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Tasks {
+            stop: watch::Sender::new(false),
+            running: Vec::new(),
+        }
+    }
+
+    pub fn spawn<F: Future<Output = ()> + 'static>(
+        &mut self,
+        name: &'static str,
+        task: impl FnOnce(Stopping) -> F,
+    ) {
+        let handle = actix_web::rt::spawn(task(Stopping(self.stop.subscribe())));
+        self.running.push((name, handle));
+    }
+
+    // Asks every task to stop and waits up to `grace` for them all; any still
+    // running then is aborted at its next await.
+    pub async fn stop(self, grace: Duration) {
+        tracing::info!(tasks = self.running.len(), "stopping background tasks");
+        self.stop.send_replace(true);
+        let deadline = Instant::now() + grace;
+        for (task, mut handle) in self.running {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match time::timeout(remaining, &mut handle).await {
+                Ok(_) => tracing::info!(task, "background task stopped"),
+                Err(_) => {
+                    handle.abort();
+                    tracing::warn!(task, "background task aborted after the grace period");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // A loop whose iterations take 100ms, holding a stand-in for the session.
+    async fn iterate(mut stopping: Stopping, _session: Arc<()>, finished: Arc<AtomicUsize>) {
+        loop {
+            time::sleep(Duration::from_millis(100)).await;
+            finished.fetch_add(1, Ordering::SeqCst);
+            if !stopping.pause(Duration::from_millis(10)).await {
+                break;
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn tasks_finish_their_iteration_before_the_session_goes() {
+        let session = Arc::new(());
+        let finished = Arc::new(AtomicUsize::new(0));
+        let mut tasks = Tasks::new();
+        let (held, counted) = (session.clone(), finished.clone());
+        tasks.spawn("loop", move |stopping| iterate(stopping, held, counted));
+
+        // Stop part way through the first iteration.
+        time::sleep(Duration::from_millis(50)).await;
+        tasks.stop(Duration::from_secs(5)).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert_eq!(Arc::strong_count(&session), 1);
+    }
+
+    #[actix_web::test]
+    async fn tasks_that_overrun_the_grace_period_are_aborted() {
+        let session = Arc::new(());
+        let mut tasks = Tasks::new();
+        let held = session.clone();
+        tasks.spawn("stuck", move |_| async move {
+            time::sleep(Duration::from_secs(60)).await;
+            drop(held);
+        });
+
+        let started = Instant::now();
+        tasks.stop(Duration::from_millis(50)).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // Aborting drops the task at its next poll.
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(Arc::strong_count(&session), 1);
+    }
+}
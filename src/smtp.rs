@@ -0,0 +1,325 @@
+use crate::config::{SmtpConfig, SmtpTls};
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use openssl::ssl::{SslConnector, SslMethod};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio_openssl::SslStream;
+
+// A minimal SMTP client for the mailer: one message per connection, over
+// TLS from the start or upgraded with STARTTLS, the relay verified against
+// the system trust store, and AUTH PLAIN when credentials are configured.
+// The message is sent as written, so it must already be a complete RFC 5322
+// message with CRLF line endings; lines starting with a dot are escaped
+// here.
+
+// Longest reply line read back.
+const MAX_LINE: usize = 4096;
+
+static CONNECTOR: LazyLock<Result<SslConnector, String>> = LazyLock::new(|| {
+    SslConnector::builder(SslMethod::tls_client())
+        .map(|builder| builder.build())
+        .map_err(|e| e.to_string())
+});
+
+#[derive(Debug)]
+pub enum SmtpError {
+    Io(std::io::Error),
+    Tls(String),
+    Timeout,
+    Protocol(String),
+    // The relay answered a command with this code and text.
+    Rejected(u16, String),
+}
+
+impl SmtpError {
+    // Whether trying again later could succeed: connection trouble and 4xx
+    // replies, as opposed to a 5xx refusal.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SmtpError::Rejected(code, _) => (400..500).contains(code),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for SmtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpError::Io(e) => write!(f, "SMTP connection failed: {}", e),
+            SmtpError::Tls(e) => write!(f, "SMTP TLS handshake failed: {}", e),
+            SmtpError::Timeout => write!(f, "the SMTP relay did not answer in time"),
+            SmtpError::Protocol(detail) => write!(f, "unexpected SMTP reply: {}", detail),
+            SmtpError::Rejected(code, text) => write!(f, "SMTP relay refused: {} {}", code, text),
+        }
+    }
+}
+
+impl std::error::Error for SmtpError {}
+
+impl From<std::io::Error> for SmtpError {
+    fn from(e: std::io::Error) -> Self {
+        SmtpError::Io(e)
+    }
+}
+
+// Reads one reply, its lines joined, as its code and text.
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+) -> Result<(u16, String), SmtpError> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        let read = (&mut *stream).take(MAX_LINE as u64).read_line(&mut line).await?;
+        if read == 0 {
+            return Err(SmtpError::Protocol(String::from("connection closed")));
+        }
+        let line = line.trim_end();
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| SmtpError::Protocol(format!("bad reply line {:?}", line)))?;
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(line.get(4..).unwrap_or_default());
+        // `250-` continues the reply, `250 ` ends it.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, text));
+        }
+    }
+}
+
+// Reads a reply, failing unless its code is `expected`.
+async fn expect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    expected: u16,
+) -> Result<(), SmtpError> {
+    match reply(stream).await? {
+        (code, _) if code == expected => Ok(()),
+        (code, text) => Err(SmtpError::Rejected(code, text)),
+    }
+}
+
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    line: &str,
+    expected: u16,
+) -> Result<(), SmtpError> {
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await?;
+    expect(stream, expected).await
+}
+
+// `message` as DATA content: lines starting with a dot get another one, and
+// it ends with the lone dot that closes DATA.
+fn dot_stuffed(message: &str) -> String {
+    let mut data = String::with_capacity(message.len() + 8);
+    for line in message.split("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    if message.ends_with("\r\n") {
+        data.truncate(data.len() - 2);
+    }
+    data.push_str(".\r\n");
+    data
+}
+
+// An SMTP relay and how to reach it.
+pub struct Smtp {
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    // Username and password, for AUTH PLAIN.
+    credentials: Option<(String, String)>,
+    deadline: Duration,
+}
+
+impl Smtp {
+    pub fn new(config: &SmtpConfig) -> Self {
+        Smtp {
+            host: config.host.clone(),
+            port: config.port,
+            tls: config.tls,
+            credentials: config.username.clone().zip(config.password.clone()),
+            deadline: Duration::from_millis(config.timeout_ms),
+        }
+    }
+
+    // Sends `message` from `from` to `to`, giving up after the timeout.
+    pub async fn send(&self, from: &str, to: &str, message: &str) -> Result<(), SmtpError> {
+        timeout(self.deadline, self.send_unbounded(from, to, message))
+            .await
+            .map_err(|_| SmtpError::Timeout)?
+    }
+
+    async fn tls_stream(&self, tcp: TcpStream) -> Result<SslStream<TcpStream>, SmtpError> {
+        let connector = CONNECTOR.as_ref().map_err(|e| SmtpError::Tls(e.clone()))?;
+        let ssl = connector
+            .configure()
+            .and_then(|config| config.into_ssl(&self.host))
+            .map_err(|e| SmtpError::Tls(e.to_string()))?;
+        let mut stream = SslStream::new(ssl, tcp).map_err(|e| SmtpError::Tls(e.to_string()))?;
+        Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(|e| SmtpError::Tls(e.to_string()))?;
+        Ok(stream)
+    }
+
+    async fn send_unbounded(&self, from: &str, to: &str, message: &str) -> Result<(), SmtpError> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        match self.tls {
+            SmtpTls::Tls => {
+                let mut stream = BufStream::new(self.tls_stream(tcp).await?);
+                expect(&mut stream, 220).await?;
+                self.transaction(stream, from, to, message).await
+            }
+            SmtpTls::StartTls => {
+                let mut stream = BufStream::new(tcp);
+                expect(&mut stream, 220).await?;
+                command(&mut stream, "EHLO localhost", 250).await?;
+                command(&mut stream, "STARTTLS", 220).await?;
+                let stream = BufStream::new(self.tls_stream(stream.into_inner()).await?);
+                self.transaction(stream, from, to, message).await
+            }
+            SmtpTls::None => {
+                let mut stream = BufStream::new(tcp);
+                expect(&mut stream, 220).await?;
+                self.transaction(stream, from, to, message).await
+            }
+        }
+    }
+
+    // Everything after the greeting (and TLS), up to QUIT.
+    async fn transaction<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: BufStream<S>,
+        from: &str,
+        to: &str,
+        message: &str,
+    ) -> Result<(), SmtpError> {
+        command(&mut stream, "EHLO localhost", 250).await?;
+        if let Some((username, password)) = &self.credentials {
+            let plain = STANDARD.encode(format!("\0{}\0{}", username, password));
+            command(&mut stream, &format!("AUTH PLAIN {}", plain), 235).await?;
+        }
+        command(&mut stream, &format!("MAIL FROM:<{}>", from), 250).await?;
+        command(&mut stream, &format!("RCPT TO:<{}>", to), 250).await?;
+        command(&mut stream, "DATA", 354).await?;
+        stream.write_all(dot_stuffed(message).as_bytes()).await?;
+        stream.flush().await?;
+        expect(&mut stream, 250).await?;
+        // The message is accepted; how the relay answers QUIT doesn't matter.
+        let _ = command(&mut stream, "QUIT", 221).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn leading_dots_are_escaped() {
+        assert_eq!(dot_stuffed("Hi\r\n.\r\n..x\r\n"), "Hi\r\n..\r\n...x\r\n.\r\n");
+        assert_eq!(dot_stuffed("Hi"), "Hi\r\n.\r\n");
+    }
+
+    #[test]
+    fn only_4xx_refusals_are_transient() {
+        assert!(SmtpError::Rejected(451, String::new()).is_transient());
+        assert!(!SmtpError::Rejected(550, String::new()).is_transient());
+        assert!(SmtpError::Timeout.is_transient());
+    }
+
+    #[actix_web::test]
+    async fn a_message_goes_through_the_whole_transaction() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 relay ready\r\n").unwrap();
+            let mut received = Vec::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push(line.trim_end().to_string());
+                let answer: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-relay\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(answer).unwrap();
+            }
+            received
+        });
+
+        let smtp = Smtp::new(&SmtpConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            tls: SmtpTls::None,
+            username: Some(String::from("mailer")),
+            password: Some(String::from("pw")),
+            ..SmtpConfig::default()
+        });
+        smtp.send("no-reply@example.com", "ada@example.com", "Subject: Hi\r\n\r\n.hidden\r\n")
+            .await
+            .unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(received[1], format!("AUTH PLAIN {}", STANDARD.encode("\0mailer\0pw")));
+        assert_eq!(received[2], "MAIL FROM:<no-reply@example.com>");
+        assert_eq!(received[3], "RCPT TO:<ada@example.com>");
+        assert_eq!(&received[5..8], ["Subject: Hi", "", "..hidden"]);
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+
+    #[actix_web::test]
+    async fn refusals_carry_the_relay_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"554 go away\r\n").unwrap();
+        });
+        let smtp = Smtp::new(&SmtpConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            tls: SmtpTls::None,
+            ..SmtpConfig::default()
+        });
+        let error = smtp.send("a@example.com", "b@example.com", "x").await.unwrap_err();
+        assert!(matches!(error, SmtpError::Rejected(554, ref text) if text == "go away"));
+    }
+}
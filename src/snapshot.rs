@@ -0,0 +1,243 @@
+use crate::error::ApiError;
+use crate::models::{Address, Profile};
+use crate::observe;
+use crate::state::AppState;
+use actix_web::rt::time::sleep_until;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use scylla::DeserializeRow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// `snapshot <FILE>` copies the whole `users` table to a JSON Lines file, one
+// row per line with every column (password hashes, roles, soft-deleted rows
+// and the TTL left included), so `restore` can put it back as it was. Rows
+// are read a page at a time and at most `scylla.snapshot_rows_per_sec` a
+// second, so a backup doesn't compete with serving traffic. Beside the file
+// goes `<FILE>.manifest.json`, with the row count and the SHA-256 of the
+// file, written last: a snapshot without one is incomplete. Both files are
+// created readable by their owner only, as the rows hold password hashes.
+//
+// The snapshot is not a point-in-time copy: rows written while it runs may
+// or may not be in it.
+
+pub const FORMAT: &str = "jsonl";
+pub const VERSION: u32 = 1;
+
+// Rows fetched per page.
+const PAGE_SIZE: i32 = 1_000;
+// Rows between two progress lines.
+const PROGRESS_EVERY: u64 = 10_000;
+
+// Every column of a `users` row, as `statements::select_snapshot_rows` reads
+// it and a snapshot stores it.
+#[derive(Debug, Serialize, Deserialize, DeserializeRow)]
+pub struct SnapshotRow {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Profile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<Address>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BTreeMap<String, String>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub verified: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anonymized_at: Option<DateTime<Utc>>,
+    // Seconds left until the row expires, for users registered with a TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub keyspace: String,
+    pub table: String,
+    // The data file's name, next to the manifest.
+    pub file: String,
+    pub rows: u64,
+    pub bytes: u64,
+    pub sha256: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+// Where the manifest of the snapshot in `path` goes.
+pub fn manifest_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".manifest.json");
+    PathBuf::from(name)
+}
+
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+// Writes each line to the file and into its checksum.
+struct Output {
+    writer: BufWriter<File>,
+    digest: Sha256,
+    bytes: u64,
+}
+
+impl Output {
+    fn line(&mut self, row: &SnapshotRow) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(row)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.digest.update(&line);
+        self.bytes += line.len() as u64;
+        Ok(())
+    }
+}
+
+// When the `rows`th row may be read, at `rows_per_sec` from `started`; 0 is
+// unlimited.
+fn due(started: Instant, rows: u64, rows_per_sec: u32) -> Option<Instant> {
+    (rows_per_sec > 0)
+        .then(|| started + Duration::from_secs_f64(rows as f64 / rows_per_sec as f64))
+}
+
+pub async fn run(state: &AppState, path: &Path, rows_per_sec: u32) -> Result<Manifest, ApiError> {
+    let io_error = |e: std::io::Error| ApiError::internal("Error writing the snapshot", e);
+    let started_at = Utc::now();
+    let file = create_private(path).map_err(io_error)?;
+    let mut output = Output {
+        writer: BufWriter::new(file),
+        digest: Sha256::new(),
+        bytes: 0,
+    };
+
+    let mut statement = state.statements.select_snapshot_rows.clone();
+    statement.set_page_size(PAGE_SIZE);
+    let pager = observe::query(state, "select_snapshot_rows", || {
+        state.session.execute_iter(statement.clone(), ())
+    })
+    .await?;
+    let mut rows = pager
+        .rows_stream::<SnapshotRow>()
+        .map_err(|e| ApiError::internal("Error streaming users", e))?
+        .map_err(|e| ApiError::internal("Error fetching users", e));
+
+    let started = Instant::now();
+    let mut count = 0;
+    while let Some(row) = rows.try_next().await? {
+        output.line(&row).map_err(io_error)?;
+        count += 1;
+        if count % PROGRESS_EVERY == 0 {
+            tracing::info!(rows = count, "snapshot progress");
+        }
+        if let Some(due) = due(started, count, rows_per_sec) {
+            sleep_until(due.into()).await;
+        }
+    }
+    output.writer.flush().map_err(io_error)?;
+    output.writer.get_ref().sync_all().map_err(io_error)?;
+
+    let manifest = Manifest {
+        format: String::from(FORMAT),
+        version: VERSION,
+        keyspace: state.keyspace.clone(),
+        table: String::from("users"),
+        file: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        rows: count,
+        bytes: output.bytes,
+        sha256: hex(&output.digest.finalize()),
+        started_at,
+        finished_at: Utc::now(),
+    };
+    let json = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| ApiError::internal("Error writing the manifest", e))?;
+    let mut manifest_file = create_private(&manifest_path(path)).map_err(io_error)?;
+    manifest_file.write_all(&json).map_err(io_error)?;
+    manifest_file.sync_all().map_err(io_error)?;
+    tracing::info!(rows = count, bytes = output.bytes, file = %path.display(), "snapshot written");
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_sit_beside_their_snapshot() {
+        assert_eq!(
+            manifest_path(Path::new("/backups/users.jsonl")),
+            Path::new("/backups/users.jsonl.manifest.json")
+        );
+    }
+
+    #[test]
+    fn rows_are_spread_over_time() {
+        let started = Instant::now();
+        assert_eq!(due(started, 500, 0), None);
+        assert_eq!(due(started, 500, 1000), Some(started + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn rows_round_trip_through_their_line() {
+        let row = SnapshotRow {
+            id: Uuid::new_v4(),
+            name: String::from("Ada"),
+            email: String::from("ada@example.com"),
+            phone: Some(String::from("+14155550123")),
+            password_hash: Some(String::from("$argon2id$v=19$...")),
+            roles: Some(vec![String::from("admin")]),
+            profile: None,
+            addresses: None,
+            tags: Some(vec![String::from("beta")]),
+            metadata: Some(BTreeMap::from([(String::from("crm.id"), String::from("0012"))])),
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            deleted_at: None,
+            verified: Some(true),
+            version: Some(3),
+            status: None,
+            anonymized_at: None,
+            expires_in: Some(3600),
+        };
+        let line = serde_json::to_string(&row).unwrap();
+        assert!(!line.contains("deleted_at"));
+        let read: SnapshotRow = serde_json::from_str(&line).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), line);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::events::UserEvent;
+use crate::state::AppState;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::{stream, Stream, StreamExt};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// GET /events is a Server-Sent Events feed of the same user events as
+// /ws/users, for clients that can't use WebSockets. Each event carries its id,
+// so a reconnecting EventSource sends `Last-Event-ID` and gets the buffered
+// events it missed first. When the gap is no longer buffered, or the client
+// falls behind, a `lagged` event tells it to refetch.
+
+// Comment lines keep idle connections from being cut by proxies.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+const LAGGED: &str = "event: lagged\ndata: {\"type\":\"lagged\"}\n\n";
+
+fn frame(event: &UserEvent) -> Option<Bytes> {
+    match serde_json::to_string(event) {
+        Ok(data) => Some(Bytes::from(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            event.id,
+            event.kind.name(),
+            data
+        ))),
+        Err(e) => {
+            tracing::warn!(event_id = event.id, error = %e, "failed to serialize user event");
+            None
+        }
+    }
+}
+
+fn live(receiver: broadcast::Receiver<Arc<UserEvent>>) -> impl Stream<Item = Bytes> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if let Some(frame) = frame(&event) {
+                        return Some((frame, receiver));
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {
+                    return Some((Bytes::from_static(LAGGED.as_bytes()), receiver));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+fn last_event_id(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get("Last-Event-ID")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub async fn events(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let (replay, receiver) = match last_event_id(&req) {
+        Some(last_id) => {
+            let resumed = data.events.resume(last_id);
+            let mut replay: Vec<Bytes> = Vec::with_capacity(resumed.replay.len() + 1);
+            if resumed.lagged {
+                replay.push(Bytes::from_static(LAGGED.as_bytes()));
+            }
+            replay.extend(resumed.replay.iter().filter_map(|event| frame(event)));
+            (replay, resumed.receiver)
+        }
+        None => (Vec::new(), data.events.subscribe()),
+    };
+
+    // The first tick of an interval is immediate, so it is skipped.
+    let keepalive = stream::unfold(
+        actix_web::rt::time::interval(KEEPALIVE),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Bytes::from_static(b": keepalive\n\n"), interval))
+        },
+    )
+    .skip(1);
+    let body = stream::iter(replay)
+        .chain(stream::select(live(receiver), keepalive))
+        .map(Ok::<_, Infallible>);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+}
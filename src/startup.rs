@@ -0,0 +1,155 @@
+use crate::migrations::{self, Ddl};
+use actix_web::rt::time::sleep;
+use scylla::Session;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+// How long startup waits for the keyspace and users table to become
+// queryable, e.g. while a separate migration job is still creating them.
+pub struct SchemaWait {
+    pub max_wait: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+// Polls `SELECT ... LIMIT 1` on the users table with exponential backoff until
+// it succeeds or `max_wait` is exhausted, returning the last error in that case.
+pub async fn await_users_table(
+    session: &Session,
+    keyspace: &str,
+    wait: &SchemaWait,
+) -> Result<(), String> {
+    let probe = format!("SELECT id FROM {}.users LIMIT 1", keyspace);
+    await_table(keyspace, wait, || session.query_unpaged(&*probe, &[])).await
+}
+
+// Runs `probe` until it succeeds, backing off as `wait` says between tries.
+async fn await_table<F, Fut, T, E>(
+    keyspace: &str,
+    wait: &SchemaWait,
+    mut probe: F,
+) -> Result<(), String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let started = Instant::now();
+    let mut backoff = wait.initial_backoff;
+
+    loop {
+        let error = match probe().await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let elapsed = started.elapsed();
+        if elapsed + backoff > wait.max_wait {
+            return Err(format!(
+                "{}.users was not queryable after {:?}: {}",
+                keyspace, elapsed, error
+            ));
+        }
+
+        tracing::info!(
+            keyspace,
+            retry_in = ?backoff,
+            error = %error,
+            "waiting for the users table to become available"
+        );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(wait.max_backoff);
+    }
+}
+
+// Replication for a keyspace created by `bootstrap`.
+pub struct Replication {
+    pub factor: u32,
+    pub datacenters: Vec<String>,
+}
+
+impl Replication {
+    fn to_cql(&self) -> String {
+        if self.datacenters.is_empty() {
+            return format!(
+                "{{'class': 'SimpleStrategy', 'replication_factor': {}}}",
+                self.factor
+            );
+        }
+        let per_dc: Vec<String> = self
+            .datacenters
+            .iter()
+            .map(|dc| format!("'{}': {}", dc, self.factor))
+            .collect();
+        format!("{{'class': 'NetworkTopologyStrategy', {}}}", per_dc.join(", "))
+    }
+}
+
+// Creates the keyspace and the baseline tables when they don't exist, so a
+// fresh cluster is usable without a cqlsh step. Every statement is
+// `IF NOT EXISTS`, so this is a no-op on an existing keyspace; anything newer
+// than the baseline still comes from the migration runner.
+pub async fn bootstrap(
+    session: &Session,
+    keyspace: &str,
+    replication: &Replication,
+    ddl: &Ddl,
+) -> Result<(), String> {
+    let create_keyspace = format!(
+        "CREATE KEYSPACE IF NOT EXISTS {} WITH replication = {}",
+        keyspace,
+        replication.to_cql()
+    );
+    ddl.run(session, create_keyspace)
+        .await
+        .map_err(|e| format!("cannot create keyspace {}: {}", keyspace, e))?;
+    session
+        .use_keyspace(keyspace, false)
+        .await
+        .map_err(|e| format!("cannot use keyspace {}: {}", keyspace, e))?;
+
+    for statement in migrations::statements(migrations::MIGRATIONS[0].cql) {
+        ddl.run(session, statement.as_str())
+            .await
+            .map_err(|e| format!("bootstrap failed on `{}`: {}", statement, e))?;
+    }
+    ddl.agree(session)
+        .await
+        .map_err(|e| format!("no schema agreement after bootstrap: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn wait(max_wait_ms: u64) -> SchemaWait {
+        SchemaWait {
+            max_wait: Duration::from_millis(max_wait_ms),
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+        }
+    }
+
+    #[actix_web::test]
+    async fn waits_for_a_table_that_appears_after_a_few_polls() {
+        let polls = Cell::new(0);
+        let probe = || {
+            polls.set(polls.get() + 1);
+            let ready = polls.get() > 3;
+            async move { if ready { Ok(()) } else { Err("unconfigured table users") } }
+        };
+        assert_eq!(await_table("app", &wait(1_000), probe).await, Ok(()));
+        assert_eq!(polls.get(), 4);
+    }
+
+    #[actix_web::test]
+    async fn gives_up_with_the_last_error_after_max_wait() {
+        let probe = || async { Err::<(), _>("unconfigured table users") };
+        let error = await_table("app", &wait(20), probe).await.unwrap_err();
+        assert!(error.starts_with("app.users was not queryable after"), "{}", error);
+        assert!(error.ends_with("unconfigured table users"), "{}", error);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::breaker::Breaker;
+use crate::cache::UserCache;
+use crate::config::{BatchMode, ConcurrencyMode, Config, RowCapMode};
+use crate::count;
+use crate::events::Events;
+use crate::flags::Flags;
+use crate::latency::LatencyWindows;
+use crate::limiter::Limiter;
+use crate::mailer::Mailer;
+use crate::maintenance::Scheduler;
+use crate::maintenance_mode::MaintenanceMode;
+use crate::metrics::Metrics;
+use crate::migrations::Ddl;
+use crate::monitor::Monitor;
+use crate::oauth::Providers;
+use crate::publisher::{self, Publisher};
+use crate::redis::{Redis, RedisUrl};
+use crate::repository::{ScyllaUsers, UserRepository};
+use crate::retry::RetryPolicy;
+use crate::search_index::SearchIndex;
+use crate::session;
+use crate::shared_cache::SharedCache;
+use crate::statements::Statements;
+use crate::users::UserReads;
+use crate::validation;
+use crate::webhooks::Webhooks;
+use crate::write_behind;
+use scylla::Session;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+// Define application state using Arc for the session to be clonable
+#[derive(Clone)]
+pub struct AppState {
+    pub session: Arc<Session>,
+    pub keyspace: String,
+    pub statements: Arc<Statements>,
+    // How schema changes made while serving, such as POST /admin/indexes,
+    // are issued.
+    pub ddl: Ddl,
+    // The `users` rows themselves; see `repository`.
+    pub users: Arc<dyn UserRepository>,
+    pub allow_tracing: bool,
+    pub allow_consistency_override: bool,
+    pub allow_raw_cql: bool,
+    pub max_rows_per_request: usize,
+    pub row_cap_mode: RowCapMode,
+    pub default_page_size: usize,
+    pub max_page_size: usize,
+    // `expires_in_seconds` for users registered without one.
+    pub default_user_ttl: Option<u32>,
+    pub cursor_max_age: Duration,
+    pub readiness_timeout: Duration,
+    pub handler_timeout: Duration,
+    pub bulk_max_items: usize,
+    pub bulk_concurrency: usize,
+    pub batch_max_operations: usize,
+    pub batch_type: BatchMode,
+    pub avatar_max_bytes: usize,
+    pub max_json_body_bytes: usize,
+    // Blocking tasks writing export records at once, across all exports.
+    pub export_workers: Arc<Semaphore>,
+    pub idempotency_ttl: Duration,
+    pub verification_token_ttl: Duration,
+    pub password_reset_ttl: Duration,
+    pub email_check_public: bool,
+    pub put_creates: bool,
+    pub concurrency_mode: ConcurrencyMode,
+    pub validation: Arc<validation::Policy>,
+    pub user_count: Arc<count::Counter>,
+    pub events: Arc<Events>,
+    // Whether events go to the feeds through the outbox; see `outbox`.
+    pub outbox: bool,
+    pub user_cache: Arc<UserCache>,
+    // Reads of a user by id in flight, shared by concurrent cache misses.
+    pub user_reads: Arc<UserReads>,
+    pub shared_cache: Option<Arc<SharedCache>>,
+    pub metrics: Arc<Metrics>,
+    pub retry: Arc<RetryPolicy>,
+    pub breaker: Arc<Breaker>,
+    pub limiter: Arc<Limiter>,
+    pub cluster_monitor: Arc<Monitor>,
+    pub maintenance: Arc<Scheduler>,
+    // Sign-in providers; see `oauth`.
+    pub oauth: Arc<Providers>,
+    // Where POST /register queues users with `write_behind.enabled`.
+    pub write_behind: Option<Arc<write_behind::Queue>>,
+    pub flags: Arc<Flags>,
+    pub maintenance_mode: Arc<MaintenanceMode>,
+    // Recent latencies per endpoint, behind GET /admin/latency.
+    pub latency_windows: Arc<LatencyWindows>,
+    // Where user events are queued for delivery with `webhooks.enabled`.
+    pub webhooks: Option<Arc<Webhooks>>,
+    // Sends welcome emails with `smtp.enabled`.
+    pub mailer: Option<Arc<Mailer>>,
+    // Where the outbox relay publishes user events with `kafka.enabled` or
+    // `nats.enabled`.
+    pub publisher: Option<Arc<dyn Publisher>>,
+    // Where the outbox relay mirrors users with `search_index.enabled`, and
+    // what GET /users/search?q= queries.
+    pub search_index: Option<Arc<SearchIndex>>,
+}
+
+// Builds the state for serving `config.scylla.keyspace`, or the keyspace
+// given, over a session of its own or the one given. The keyspace's schema
+// has to be in place: the statements are prepared against it.
+pub struct AppStateBuilder {
+    config: Config,
+    session: Option<Arc<Session>>,
+    keyspace: Option<String>,
+}
+
+impl AppStateBuilder {
+    pub fn session(mut self, session: Arc<Session>) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    pub fn keyspace(mut self, keyspace: impl Into<String>) -> Self {
+        self.keyspace = Some(keyspace.into());
+        self
+    }
+
+    pub async fn build(self) -> Result<AppState, String> {
+        let session = match self.session {
+            Some(session) => session,
+            None => Arc::new(session::connect(&self.config.scylla).await?),
+        };
+        let keyspace = self.keyspace.unwrap_or_else(|| self.config.scylla.keyspace.clone());
+        let statements = Statements::prepare(&session, &keyspace)
+            .await
+            .map_err(|e| format!("cannot prepare CQL statements: {}", e))?;
+        Ok(AppState::new(&self.config, session, keyspace, statements))
+    }
+}
+
+impl AppState {
+    pub fn builder(config: &Config) -> AppStateBuilder {
+        AppStateBuilder {
+            config: config.clone(),
+            session: None,
+            keyspace: None,
+        }
+    }
+
+    pub fn new(
+        config: &Config,
+        session: Arc<Session>,
+        keyspace: String,
+        mut statements: Statements,
+    ) -> Self {
+        let unknown = statements.mark_idempotent(&config.scylla.idempotent_statements);
+        if !unknown.is_empty() {
+            tracing::warn!(?unknown, "scylla.idempotent_statements names unknown statements");
+        }
+        let statements = Arc::new(statements);
+        let slow_query_threshold = Some(config.scylla.slow_query_threshold_ms)
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+        let metrics = Arc::new(Metrics::new().with_slow_query_threshold(slow_query_threshold));
+        metrics.register_driver(session.get_metrics());
+        let retry = Arc::new(RetryPolicy::new(&config.scylla));
+        let breaker = Arc::new(Breaker::new(&config.scylla));
+        let limiter = Arc::new(Limiter::new(&config.scylla));
+        AppState {
+            ddl: Ddl::new(&config.scylla),
+            users: Arc::new(ScyllaUsers::new(
+                session.clone(),
+                statements.clone(),
+                keyspace.clone(),
+                metrics.clone(),
+                retry.clone(),
+                breaker.clone(),
+                limiter.clone(),
+            )),
+            session,
+            keyspace,
+            statements,
+            allow_tracing: config.scylla.allow_tracing,
+            allow_consistency_override: config.scylla.allow_consistency_override,
+            allow_raw_cql: config.scylla.allow_raw_cql,
+            // Hard guardrail on rows returned by a single read, independent of
+            // anything the client asks for.
+            max_rows_per_request: config.http.max_rows_per_request,
+            row_cap_mode: config.http.row_cap_mode,
+            default_page_size: config.http.default_page_size,
+            max_page_size: config.http.max_page_size,
+            default_user_ttl: Some(config.http.default_user_ttl_secs).filter(|secs| *secs > 0),
+            cursor_max_age: Duration::from_secs(config.http.cursor_max_age_secs),
+            readiness_timeout: Duration::from_millis(config.http.readiness_timeout_ms),
+            handler_timeout: Duration::from_millis(config.http.handler_timeout_ms),
+            bulk_max_items: config.http.bulk_max_items,
+            bulk_concurrency: config.http.bulk_concurrency,
+            batch_max_operations: config.http.batch_max_operations,
+            batch_type: config.http.batch_type,
+            avatar_max_bytes: config.http.avatar_max_bytes,
+            max_json_body_bytes: config.http.max_json_body_bytes,
+            export_workers: Arc::new(Semaphore::new(config.http.export_workers)),
+            idempotency_ttl: Duration::from_secs(config.http.idempotency_ttl_secs),
+            verification_token_ttl: Duration::from_secs(config.http.verification_token_ttl_secs),
+            password_reset_ttl: Duration::from_secs(config.http.password_reset_ttl_secs),
+            email_check_public: config.http.email_check_public,
+            put_creates: config.http.put_creates,
+            concurrency_mode: config.http.concurrency_mode,
+            validation: Arc::new(validation::Policy::new(&config.validation)),
+            user_count: Arc::new(count::Counter::new(Duration::from_secs(config.http.count_cache_secs))),
+            events: Arc::new(Events::new(config.http.event_buffer, config.cdc.enabled)),
+            outbox: config.outbox.enabled,
+            user_cache: Arc::new(UserCache::new(
+                config.cache.capacity,
+                Duration::from_secs(config.cache.ttl_secs),
+            )),
+            // The URL was checked when the configuration was loaded.
+            user_reads: Arc::new(UserReads::new()),
+            shared_cache: config.cache.redis_url.as_deref().map(|url| {
+                let url = RedisUrl::parse(url).unwrap_or_else(|e| panic!("Invalid Redis URL: {}", e));
+                Arc::new(SharedCache::new(
+                    Redis::new(url, Duration::from_millis(config.cache.redis_timeout_ms)),
+                    config.cache.redis_key_prefix.clone(),
+                    Duration::from_secs(config.cache.redis_ttl_secs),
+                ))
+            }),
+            write_behind: config
+                .write_behind
+                .enabled
+                .then(|| Arc::new(write_behind::Queue::new(&config.write_behind, metrics.clone()))),
+            webhooks: config
+                .webhooks
+                .enabled
+                .then(|| Arc::new(Webhooks::new(&config.webhooks, metrics.clone()))),
+            mailer: config
+                .smtp
+                .enabled
+                .then(|| Arc::new(Mailer::new(&config.smtp, metrics.clone()))),
+            publisher: publisher::for_config(config),
+            search_index: config
+                .search_index
+                .enabled
+                .then(|| Arc::new(SearchIndex::new(&config.search_index))),
+            metrics,
+            retry,
+            breaker,
+            limiter,
+            cluster_monitor: Arc::new(Monitor::new(
+                Duration::from_secs(config.scylla.monitor_interval_secs),
+                Duration::from_millis(config.http.readiness_timeout_ms),
+            )),
+            maintenance: Arc::new(Scheduler::new(&config.maintenance)),
+            oauth: Arc::new(Providers::new(&config.oauth)),
+            flags: Arc::new(Flags::new(&config.flags)),
+            maintenance_mode: Arc::new(MaintenanceMode::new(&config.maintenance_mode)),
+            latency_windows: Arc::new(LatencyWindows::new(config.http.latency_window)),
+        }
+    }
+
+    // The state serving a tenant from `keyspace`. The session, metrics, retry
+    // policy, breaker, limiter, cluster monitor and export workers are this
+    // state's; caches and event feeds are the tenant's own, its Redis keys set
+    // apart by the keyspace. Changes to the tenant's users come only through the
+    // API, as the CDC consumer reads the serving keyspace, and their events
+    // are published directly, as the outbox relay only reads the serving
+    // keyspace too. Its registrations are stored at once, the write-behind
+    // writer being the serving keyspace's. Feature flags, maintenance mode
+    // and latency windows are the serving keyspace's too, as is the mailer
+    // sending the tenant's welcome emails. Webhooks and publishing to Kafka
+    // or NATS are off, so the serving keyspace's subscribers never see a
+    // tenant's users.
+    pub fn for_keyspace(&self, config: &Config, keyspace: String, statements: Statements) -> Self {
+        let mut config = config.clone();
+        config.cache.redis_key_prefix = format!("{}{}:", config.cache.redis_key_prefix, keyspace);
+        config.cdc.enabled = false;
+        config.outbox.enabled = false;
+        config.write_behind.enabled = false;
+        config.webhooks.enabled = false;
+        config.kafka.enabled = false;
+        config.nats.enabled = false;
+        let mut state = AppState::new(&config, self.session.clone(), keyspace, statements);
+        state.users = Arc::new(ScyllaUsers::new(
+            self.session.clone(),
+            state.statements.clone(),
+            state.keyspace.clone(),
+            self.metrics.clone(),
+            self.retry.clone(),
+            self.breaker.clone(),
+            self.limiter.clone(),
+        ));
+        state.metrics = self.metrics.clone();
+        state.retry = self.retry.clone();
+        state.breaker = self.breaker.clone();
+        state.limiter = self.limiter.clone();
+        state.cluster_monitor = self.cluster_monitor.clone();
+        state.export_workers = self.export_workers.clone();
+        state.flags = self.flags.clone();
+        state.maintenance_mode = self.maintenance_mode.clone();
+        state.latency_windows = self.latency_windows.clone();
+        state.mailer = self.mailer.clone();
+        state
+    }
+}
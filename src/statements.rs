@@ -0,0 +1,1144 @@
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::prepared_statement::PreparedStatement;
+use scylla::transport::errors::QueryError;
+use scylla::{QueryResult, Session};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+// The columns selected by every read of `users`.
+// `expires_in` is the seconds left to a user registered with a TTL.
+pub const USER_COLUMNS: &str = "id, name, email, phone, profile, created_at, updated_at, \
+                                deleted_at, verified, version, status, anonymized_at, \
+                                TTL(email) AS expires_in";
+
+// CQL statements shared by all handlers. The fixed statements are prepared
+// once at startup; statements whose text depends on the request (such as the
+// SET clause of a partial update) are prepared on first use and cached by
+// their text, so every execution goes through `execute_*` with token-aware
+// routing and no re-parsing on the server. Reads of `users` select the
+// columns of `users::UserRow`, including `deleted_at` so that soft-deleted
+// rows can be skipped. Writes to a user's rows bind a `USING TTL`, 0 unless
+// the user expires; see `users::ttl`.
+pub struct Statements {
+    pub readiness_probe: PreparedStatement,
+    pub select_all_users: PreparedStatement,
+    pub select_snapshot_rows: PreparedStatement,
+    pub insert_snapshot_row: PreparedStatement,
+    pub insert_snapshot_row_if_absent: PreparedStatement,
+    pub select_deleted_in_token_range: PreparedStatement,
+    pub select_deleted_ids_in_token_range: PreparedStatement,
+    pub select_user_by_id: PreparedStatement,
+    pub select_user_timestamps: PreparedStatement,
+    pub insert_user: PreparedStatement,
+    pub select_credentials_by_id: PreparedStatement,
+    pub select_credentials_by_email: PreparedStatement,
+    pub select_user_by_email: PreparedStatement,
+    pub select_user_ids_by_email: PreparedStatement,
+    pub select_email_owner: PreparedStatement,
+    pub claim_email: PreparedStatement,
+    pub index_user_name: PreparedStatement,
+    pub unindex_user_name: PreparedStatement,
+    pub search_users_by_name: PreparedStatement,
+    pub index_user_day: PreparedStatement,
+    pub unindex_user_day: PreparedStatement,
+    pub select_users_by_day: PreparedStatement,
+    pub select_users_by_day_after: PreparedStatement,
+    pub release_email: PreparedStatement,
+    pub select_phone_owner: PreparedStatement,
+    pub claim_phone: PreparedStatement,
+    pub release_phone: PreparedStatement,
+    pub delete_user: PreparedStatement,
+    pub delete_user_if_unchanged: PreparedStatement,
+    pub soft_delete_user: PreparedStatement,
+    pub soft_delete_user_if_unchanged: PreparedStatement,
+    pub soft_delete_user_in_batch: PreparedStatement,
+    pub restore_user: PreparedStatement,
+    pub replace_user: PreparedStatement,
+    pub replace_user_if_unchanged: PreparedStatement,
+    pub anonymize_user: PreparedStatement,
+    pub anonymize_user_if_unchanged: PreparedStatement,
+    pub select_user_roles: PreparedStatement,
+    pub update_user_roles: PreparedStatement,
+    pub select_user_addresses: PreparedStatement,
+    pub append_user_address: PreparedStatement,
+    pub remove_user_address: PreparedStatement,
+    pub select_user_tags: PreparedStatement,
+    pub add_user_tag: PreparedStatement,
+    pub remove_user_tag: PreparedStatement,
+    pub select_user_metadata: PreparedStatement,
+    pub set_user_metadata: PreparedStatement,
+    pub delete_user_metadata: PreparedStatement,
+    pub select_avatar: PreparedStatement,
+    pub insert_avatar: PreparedStatement,
+    pub delete_avatar: PreparedStatement,
+    pub select_avatar_chunks: PreparedStatement,
+    pub insert_avatar_chunk: PreparedStatement,
+    pub delete_avatar_chunks: PreparedStatement,
+    pub select_api_key: PreparedStatement,
+    pub insert_api_key: PreparedStatement,
+    pub revoke_api_key: PreparedStatement,
+    pub claim_idempotency_key: PreparedStatement,
+    pub select_idempotency_key: PreparedStatement,
+    pub complete_idempotency_key: PreparedStatement,
+    pub release_idempotency_key: PreparedStatement,
+    pub select_tenant: PreparedStatement,
+    pub select_tenants: PreparedStatement,
+    pub insert_tenant: PreparedStatement,
+    pub insert_audit_entry: PreparedStatement,
+    pub select_audit_entries: PreparedStatement,
+    pub select_audit_entries_before: PreparedStatement,
+    pub select_last_user_event: PreparedStatement,
+    pub append_user_event: PreparedStatement,
+    pub select_user_events: PreparedStatement,
+    pub insert_outbox_event: PreparedStatement,
+    pub select_outbox_events: PreparedStatement,
+    pub delete_outbox_event: PreparedStatement,
+    pub insert_email_verification: PreparedStatement,
+    pub select_email_verification: PreparedStatement,
+    pub consume_email_verification: PreparedStatement,
+    pub verify_user: PreparedStatement,
+    pub set_user_status: PreparedStatement,
+    pub record_dual_write_failure: PreparedStatement,
+    pub insert_password_reset: PreparedStatement,
+    pub select_password_reset: PreparedStatement,
+    pub consume_password_reset: PreparedStatement,
+    pub reset_password: PreparedStatement,
+    pub insert_session: PreparedStatement,
+    pub select_session: PreparedStatement,
+    pub select_user_sessions: PreparedStatement,
+    pub rotate_session: PreparedStatement,
+    pub delete_session: PreparedStatement,
+    pub insert_oauth_state: PreparedStatement,
+    pub select_oauth_state: PreparedStatement,
+    pub consume_oauth_state: PreparedStatement,
+    pub insert_oauth_identity: PreparedStatement,
+    pub select_oauth_identity: PreparedStatement,
+    pub delete_oauth_identity: PreparedStatement,
+    pub select_feature_flags: PreparedStatement,
+    pub upsert_feature_flag: PreparedStatement,
+    pub select_webhooks: PreparedStatement,
+    pub insert_webhook: PreparedStatement,
+    pub delete_webhook: PreparedStatement,
+    pub insert_webhook_delivery: PreparedStatement,
+    pub select_webhook_deliveries: PreparedStatement,
+    pub count_request: PreparedStatement,
+    pub select_request_count: PreparedStatement,
+    pub delete_request_counts: PreparedStatement,
+    pub count_login: PreparedStatement,
+    pub count_profile_view: PreparedStatement,
+    pub select_user_stats: PreparedStatement,
+    pub delete_user_stats: PreparedStatement,
+    pub select_groups: PreparedStatement,
+    pub select_group: PreparedStatement,
+    pub insert_group: PreparedStatement,
+    pub update_group: PreparedStatement,
+    pub delete_group: PreparedStatement,
+    pub select_group_members: PreparedStatement,
+    pub select_group_member: PreparedStatement,
+    pub insert_group_member: PreparedStatement,
+    pub delete_group_member: PreparedStatement,
+    pub delete_group_members: PreparedStatement,
+    pub select_user_groups: PreparedStatement,
+    pub insert_user_group: PreparedStatement,
+    pub delete_user_group: PreparedStatement,
+    pub delete_user_groups: PreparedStatement,
+    pub select_posts: PreparedStatement,
+    pub select_posts_before: PreparedStatement,
+    pub insert_post: PreparedStatement,
+    pub delete_post: PreparedStatement,
+    pub delete_posts: PreparedStatement,
+    dynamic: RwLock<HashMap<String, PreparedStatement>>,
+}
+
+impl Statements {
+    pub async fn prepare(session: &Session, keyspace: &str) -> Result<Self, QueryError> {
+        Ok(Statements {
+            readiness_probe: session.prepare("SELECT now() FROM system.local").await?,
+            select_all_users: session
+                .prepare(format!("SELECT {} FROM {}.users", USER_COLUMNS, keyspace))
+                .await?,
+            // Every column, for `snapshot`.
+            select_snapshot_rows: session
+                .prepare(format!(
+                    "SELECT id, name, email, phone, password_hash, roles, profile, addresses, \
+                     tags, metadata, created_at, updated_at, deleted_at, verified, version, \
+                     status, anonymized_at, TTL(email) AS expires_in FROM {}.users",
+                    keyspace
+                ))
+                .await?,
+            // Every column, for `restore`.
+            insert_snapshot_row: session
+                .prepare(format!(
+                    "INSERT INTO {}.users (id, name, email, phone, password_hash, roles, profile, \
+                     addresses, tags, metadata, created_at, updated_at, deleted_at, verified, \
+                     version, status, anonymized_at) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) \
+                     USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            insert_snapshot_row_if_absent: session
+                .prepare(format!(
+                    "INSERT INTO {}.users (id, name, email, phone, password_hash, roles, profile, \
+                     addresses, tags, metadata, created_at, updated_at, deleted_at, verified, \
+                     version, status, anonymized_at) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) \
+                     IF NOT EXISTS USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_deleted_in_token_range: session
+                .prepare(format!(
+                    "SELECT deleted_at FROM {}.users WHERE token(id) > ? AND token(id) <= ?",
+                    keyspace
+                ))
+                .await?,
+            select_deleted_ids_in_token_range: session
+                .prepare(format!(
+                    "SELECT id, deleted_at FROM {}.users WHERE token(id) > ? AND token(id) <= ?",
+                    keyspace
+                ))
+                .await?,
+            select_user_by_id: session
+                .prepare(format!("SELECT {} FROM {}.users WHERE id = ?", USER_COLUMNS, keyspace))
+                .await?,
+            select_user_timestamps: session
+                .prepare(format!(
+                    "SELECT created_at, updated_at, deleted_at FROM {}.users WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_user: session
+                .prepare(format!(
+                    "INSERT INTO {}.users (id, name, email, phone, password_hash, profile, \
+                     created_at, updated_at, verified, version, status) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, false, 1, 'active') USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_credentials_by_id: session
+                .prepare(format!(
+                    "SELECT id, password_hash, deleted_at FROM {}.users WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_credentials_by_email: session
+                .prepare(format!(
+                    "SELECT id, password_hash, deleted_at FROM {}.users WHERE email = ?",
+                    keyspace
+                ))
+                .await?,
+            select_user_by_email: session
+                .prepare(format!(
+                    "SELECT {} FROM {}.users WHERE email = ?",
+                    USER_COLUMNS, keyspace
+                ))
+                .await?,
+            select_user_ids_by_email: session
+                .prepare(format!("SELECT id FROM {}.users WHERE email = ?", keyspace))
+                .await?,
+            select_email_owner: session
+                .prepare(format!(
+                    "SELECT user_id FROM {}.users_by_email WHERE email = ?",
+                    keyspace
+                ))
+                .await?,
+            claim_email: session
+                .prepare(format!(
+                    "INSERT INTO {}.users_by_email (email, user_id) VALUES (?, ?) \
+                     IF NOT EXISTS USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            index_user_name: session
+                .prepare(format!(
+                    "INSERT INTO {}.users_by_name \
+                     (bucket, name_lower, id, name, email, created_at, updated_at) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            unindex_user_name: session
+                .prepare(format!(
+                    "DELETE FROM {}.users_by_name WHERE bucket = ? AND name_lower = ? AND id = ?",
+                    keyspace
+                ))
+                .await?,
+            search_users_by_name: session
+                .prepare(format!(
+                    "SELECT id, name, email, created_at, updated_at FROM {}.users_by_name \
+                     WHERE bucket = ? AND name_lower >= ? AND name_lower <= ?",
+                    keyspace
+                ))
+                .await?,
+            index_user_day: session
+                .prepare(format!(
+                    "INSERT INTO {}.users_by_day (day, created_at, id) VALUES (?, ?, ?) \
+                     USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            unindex_user_day: session
+                .prepare(format!(
+                    "DELETE FROM {}.users_by_day WHERE day = ? AND created_at = ? AND id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_users_by_day: session
+                .prepare(format!(
+                    "SELECT created_at, id FROM {}.users_by_day \
+                     WHERE day = ? AND (created_at) > (?) AND (created_at) < (?) LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            select_users_by_day_after: session
+                .prepare(format!(
+                    "SELECT created_at, id FROM {}.users_by_day \
+                     WHERE day = ? AND (created_at, id) > (?, ?) AND (created_at) < (?) LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            release_email: session
+                .prepare(format!(
+                    "DELETE FROM {}.users_by_email WHERE email = ? IF user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_phone_owner: session
+                .prepare(format!(
+                    "SELECT user_id FROM {}.users_by_phone WHERE phone = ?",
+                    keyspace
+                ))
+                .await?,
+            claim_phone: session
+                .prepare(format!(
+                    "INSERT INTO {}.users_by_phone (phone, user_id) VALUES (?, ?) \
+                     IF NOT EXISTS USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            release_phone: session
+                .prepare(format!(
+                    "DELETE FROM {}.users_by_phone WHERE phone = ? IF user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_user: session
+                .prepare(format!("DELETE FROM {}.users WHERE id = ? IF EXISTS", keyspace))
+                .await?,
+            // The `_if_unchanged` variants apply only while the row still has
+            // the email, updated_at and version it was read with; see
+            // `repository::unchanged`.
+            delete_user_if_unchanged: session
+                .prepare(format!(
+                    "DELETE FROM {}.users WHERE id = ? \
+                     IF email = ? AND updated_at = ? AND version = ?",
+                    keyspace
+                ))
+                .await?,
+            soft_delete_user: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET deleted_at = ? WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            soft_delete_user_if_unchanged: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET deleted_at = ? WHERE id = ? \
+                     IF email = ? AND updated_at = ? AND version = ?",
+                    keyspace
+                ))
+                .await?,
+            replace_user: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? \
+                     SET name = ?, email = ?, phone = ?, profile = ?, updated_at = ?, \
+                     version = ? WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            replace_user_if_unchanged: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? \
+                     SET name = ?, email = ?, phone = ?, profile = ?, updated_at = ?, \
+                     version = ? WHERE id = ? IF email = ? AND updated_at = ? AND version = ?",
+                    keyspace
+                ))
+                .await?,
+            // Binds the placeholder name and email; the rest of the personal
+            // data is cleared.
+            anonymize_user: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? \
+                     SET name = ?, email = ?, phone = null, profile = null, \
+                     password_hash = null, addresses = null, metadata = null, updated_at = ?, \
+                     version = ?, anonymized_at = ? WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            anonymize_user_if_unchanged: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? \
+                     SET name = ?, email = ?, phone = null, profile = null, \
+                     password_hash = null, addresses = null, metadata = null, updated_at = ?, \
+                     version = ?, anonymized_at = ? WHERE id = ? \
+                     IF email = ? AND updated_at = ? AND version = ?",
+                    keyspace
+                ))
+                .await?,
+            // Conditional statements can't span partitions in a batch.
+            soft_delete_user_in_batch: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET deleted_at = ? WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            restore_user: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET deleted_at = null, updated_at = ? \
+                     WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            select_user_roles: session
+                .prepare(format!("SELECT roles, deleted_at FROM {}.users WHERE id = ?", keyspace))
+                .await?,
+            update_user_roles: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET roles = ?, updated_at = ? \
+                     WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            select_user_addresses: session
+                .prepare(format!("SELECT addresses FROM {}.users WHERE id = ?", keyspace))
+                .await?,
+            // Collection writes that don't read the list first, so they work
+            // under a condition; see `addresses`.
+            append_user_address: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET addresses = addresses + ? \
+                     WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            remove_user_address: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET addresses = addresses - ? \
+                     WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            select_user_tags: session
+                .prepare(format!("SELECT tags FROM {}.users WHERE id = ?", keyspace))
+                .await?,
+            add_user_tag: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET tags = tags + ? WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            remove_user_tag: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET tags = tags - ? WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            select_user_metadata: session
+                .prepare(format!("SELECT metadata FROM {}.users WHERE id = ?", keyspace))
+                .await?,
+            set_user_metadata: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET metadata[?] = ? WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            delete_user_metadata: session
+                .prepare(format!(
+                    "DELETE metadata[?] FROM {}.users WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            select_avatar: session
+                .prepare(format!(
+                    "SELECT upload_id, content_type, size, etag, updated_at \
+                     FROM {}.user_avatars WHERE user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_avatar: session
+                .prepare(format!(
+                    "INSERT INTO {}.user_avatars \
+                     (user_id, upload_id, content_type, size, etag, updated_at) \
+                     VALUES (?, ?, ?, ?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            delete_avatar: session
+                .prepare(format!("DELETE FROM {}.user_avatars WHERE user_id = ?", keyspace))
+                .await?,
+            select_avatar_chunks: session
+                .prepare(format!(
+                    "SELECT data FROM {}.avatar_chunks WHERE upload_id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_avatar_chunk: session
+                .prepare(format!(
+                    "INSERT INTO {}.avatar_chunks (upload_id, chunk, data) VALUES (?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            delete_avatar_chunks: session
+                .prepare(format!("DELETE FROM {}.avatar_chunks WHERE upload_id = ?", keyspace))
+                .await?,
+            select_api_key: session
+                .prepare(format!(
+                    "SELECT key_hash, owner, scopes, revoked FROM {}.api_keys WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_api_key: session
+                .prepare(format!(
+                    "INSERT INTO {}.api_keys (id, key_hash, owner, scopes, revoked, created_at) \
+                     VALUES (?, ?, ?, ?, false, ?)",
+                    keyspace
+                ))
+                .await?,
+            revoke_api_key: session
+                .prepare(format!(
+                    "UPDATE {}.api_keys SET revoked = true WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            claim_idempotency_key: session
+                .prepare(format!(
+                    "INSERT INTO {}.idempotency_keys (key, request_hash, created_at) \
+                     VALUES (?, ?, ?) IF NOT EXISTS USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_idempotency_key: session
+                .prepare(format!(
+                    "SELECT request_hash, status, body FROM {}.idempotency_keys WHERE key = ?",
+                    keyspace
+                ))
+                .await?,
+            complete_idempotency_key: session
+                .prepare(format!(
+                    "UPDATE {}.idempotency_keys USING TTL ? SET status = ?, body = ? \
+                     WHERE key = ? IF request_hash = ?",
+                    keyspace
+                ))
+                .await?,
+            release_idempotency_key: session
+                .prepare(format!(
+                    "DELETE FROM {}.idempotency_keys WHERE key = ? IF status = null",
+                    keyspace
+                ))
+                .await?,
+            select_tenant: session
+                .prepare(format!(
+                    "SELECT keyspace_name, created_at FROM {}.tenants WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_tenants: session
+                .prepare(format!("SELECT id, keyspace_name, created_at FROM {}.tenants", keyspace))
+                .await?,
+            insert_tenant: session
+                .prepare(format!(
+                    "INSERT INTO {}.tenants (id, keyspace_name, created_at) \
+                     VALUES (?, ?, ?) IF NOT EXISTS",
+                    keyspace
+                ))
+                .await?,
+            insert_audit_entry: session
+                .prepare(format!(
+                    "INSERT INTO {}.audit_log (user_id, at, id, action, actor, request_id, changes) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            select_audit_entries: session
+                .prepare(format!(
+                    "SELECT at, id, action, actor, request_id, changes FROM {}.audit_log \
+                     WHERE user_id = ? AND at >= ? LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            select_audit_entries_before: session
+                .prepare(format!(
+                    "SELECT at, id, action, actor, request_id, changes FROM {}.audit_log \
+                     WHERE user_id = ? AND (at, id) >= (?, ?) AND (at, id) < (?, ?) LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            select_last_user_event: session
+                .prepare(format!(
+                    "SELECT version FROM {}.user_events WHERE user_id = ? ORDER BY version DESC LIMIT 1",
+                    keyspace
+                ))
+                .await?,
+            append_user_event: session
+                .prepare(format!(
+                    "INSERT INTO {}.user_events (user_id, version, type, payload, at) \
+                     VALUES (?, ?, ?, ?, ?) IF NOT EXISTS",
+                    keyspace
+                ))
+                .await?,
+            select_user_events: session
+                .prepare(format!(
+                    "SELECT version, type, payload, at FROM {}.user_events \
+                     WHERE user_id = ? AND version > ? LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            insert_outbox_event: session
+                .prepare(format!(
+                    "INSERT INTO {}.outbox (shard, created_at, id, user_id, version, type, payload) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            select_outbox_events: session
+                .prepare(format!(
+                    "SELECT created_at, id, user_id, version, type, payload FROM {}.outbox \
+                     WHERE shard = ? LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            delete_outbox_event: session
+                .prepare(format!(
+                    "DELETE FROM {}.outbox WHERE shard = ? AND created_at = ? AND id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_email_verification: session
+                .prepare(format!(
+                    "INSERT INTO {}.email_verifications (token_hash, user_id, email, created_at) \
+                     VALUES (?, ?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_email_verification: session
+                .prepare(format!(
+                    "SELECT user_id, email FROM {}.email_verifications WHERE token_hash = ?",
+                    keyspace
+                ))
+                .await?,
+            consume_email_verification: session
+                .prepare(format!(
+                    "DELETE FROM {}.email_verifications WHERE token_hash = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            verify_user: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET verified = true WHERE id = ? \
+                     IF email = ? AND deleted_at = null",
+                    keyspace
+                ))
+                .await?,
+            // Applies only while the user is live and still has one of the
+            // statuses bound; an active user may have none.
+            set_user_status: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET status = ? WHERE id = ? \
+                     IF deleted_at = null AND status IN (?, ?)",
+                    keyspace
+                ))
+                .await?,
+            record_dual_write_failure: session
+                .prepare(format!(
+                    "INSERT INTO {}.dual_write_failures (user_id, operation, error, failed_at) \
+                     VALUES (?, ?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            insert_password_reset: session
+                .prepare(format!(
+                    "INSERT INTO {}.password_resets (token_hash, user_id, created_at) \
+                     VALUES (?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_password_reset: session
+                .prepare(format!(
+                    "SELECT user_id FROM {}.password_resets WHERE token_hash = ?",
+                    keyspace
+                ))
+                .await?,
+            consume_password_reset: session
+                .prepare(format!(
+                    "DELETE FROM {}.password_resets WHERE token_hash = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            reset_password: session
+                .prepare(format!(
+                    "UPDATE {}.users USING TTL ? SET password_hash = ? WHERE id = ? \
+                     IF email = ? AND deleted_at = null",
+                    keyspace
+                ))
+                .await?,
+            insert_session: session
+                .prepare(format!(
+                    "INSERT INTO {}.sessions \
+                     (id, user_id, token_hash, device, created_at, refreshed_at, expires_at) \
+                     VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_session: session
+                .prepare(format!(
+                    "SELECT user_id, token_hash, expires_at FROM {}.sessions WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_user_sessions: session
+                .prepare(format!(
+                    "SELECT id, device, created_at, refreshed_at, expires_at FROM {}.sessions \
+                     WHERE user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            rotate_session: session
+                .prepare(format!(
+                    "UPDATE {}.sessions USING TTL ? SET token_hash = ?, refreshed_at = ? \
+                     WHERE id = ? IF token_hash = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_session: session
+                .prepare(format!("DELETE FROM {}.sessions WHERE id = ?", keyspace))
+                .await?,
+            insert_oauth_state: session
+                .prepare(format!(
+                    "INSERT INTO {}.oauth_states (state_hash, provider, code_verifier, created_at) \
+                     VALUES (?, ?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_oauth_state: session
+                .prepare(format!(
+                    "SELECT provider, code_verifier FROM {}.oauth_states WHERE state_hash = ?",
+                    keyspace
+                ))
+                .await?,
+            consume_oauth_state: session
+                .prepare(format!(
+                    "DELETE FROM {}.oauth_states WHERE state_hash = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            insert_oauth_identity: session
+                .prepare(format!(
+                    "INSERT INTO {}.oauth_identities (provider, subject, user_id, email, linked_at) \
+                     VALUES (?, ?, ?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_oauth_identity: session
+                .prepare(format!(
+                    "SELECT user_id FROM {}.oauth_identities WHERE provider = ? AND subject = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_oauth_identity: session
+                .prepare(format!(
+                    "DELETE FROM {}.oauth_identities WHERE provider = ? AND subject = ?",
+                    keyspace
+                ))
+                .await?,
+            select_feature_flags: session
+                .prepare(format!("SELECT name, enabled FROM {}.feature_flags", keyspace))
+                .await?,
+            upsert_feature_flag: session
+                .prepare(format!(
+                    "INSERT INTO {}.feature_flags (name, enabled, updated_at) VALUES (?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            select_webhooks: session
+                .prepare(format!(
+                    "SELECT id, url, secret, events, created_at FROM {}.webhooks",
+                    keyspace
+                ))
+                .await?,
+            insert_webhook: session
+                .prepare(format!(
+                    "INSERT INTO {}.webhooks (id, url, secret, events, created_at) \
+                     VALUES (?, ?, ?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            delete_webhook: session
+                .prepare(format!("DELETE FROM {}.webhooks WHERE id = ? IF EXISTS", keyspace))
+                .await?,
+            insert_webhook_delivery: session
+                .prepare(format!(
+                    "INSERT INTO {}.webhook_deliveries \
+                     (webhook_id, attempted_at, event_id, attempt, event, user_id, status, error, \
+                     duration_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            select_webhook_deliveries: session
+                .prepare(format!(
+                    "SELECT attempted_at, event_id, attempt, event, user_id, status, error, \
+                     duration_ms FROM {}.webhook_deliveries WHERE webhook_id = ? LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            count_request: session
+                .prepare(format!(
+                    "UPDATE {}.rate_limits SET requests = requests + 1 \
+                     WHERE period = ? AND shard = ? AND client = ?",
+                    keyspace
+                ))
+                .await?,
+            select_request_count: session
+                .prepare(format!(
+                    "SELECT requests FROM {}.rate_limits \
+                     WHERE period = ? AND shard = ? AND client = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_request_counts: session
+                .prepare(format!(
+                    "DELETE FROM {}.rate_limits WHERE period = ? AND shard = ?",
+                    keyspace
+                ))
+                .await?,
+            count_login: session
+                .prepare(format!(
+                    "UPDATE {}.user_stats SET logins = logins + 1 WHERE user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            count_profile_view: session
+                .prepare(format!(
+                    "UPDATE {}.user_stats SET profile_views = profile_views + 1 \
+                     WHERE user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_user_stats: session
+                .prepare(format!(
+                    "SELECT logins, profile_views FROM {}.user_stats WHERE user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_user_stats: session
+                .prepare(format!("DELETE FROM {}.user_stats WHERE user_id = ?", keyspace))
+                .await?,
+            select_groups: session
+                .prepare(format!(
+                    "SELECT id, name, description, created_at, updated_at FROM {}.groups LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            select_group: session
+                .prepare(format!(
+                    "SELECT id, name, description, created_at, updated_at FROM {}.groups \
+                     WHERE id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_group: session
+                .prepare(format!(
+                    "INSERT INTO {}.groups (id, name, description, created_at, updated_at) \
+                     VALUES (?, ?, ?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            update_group: session
+                .prepare(format!(
+                    "UPDATE {}.groups SET name = ?, description = ?, updated_at = ? \
+                     WHERE id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            delete_group: session
+                .prepare(format!("DELETE FROM {}.groups WHERE id = ? IF EXISTS", keyspace))
+                .await?,
+            select_group_members: session
+                .prepare(format!(
+                    "SELECT user_id, added_at FROM {}.group_members WHERE group_id = ?",
+                    keyspace
+                ))
+                .await?,
+            select_group_member: session
+                .prepare(format!(
+                    "SELECT user_id, added_at FROM {}.group_members \
+                     WHERE group_id = ? AND user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_group_member: session
+                .prepare(format!(
+                    "INSERT INTO {}.group_members (group_id, user_id, added_at) VALUES (?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            delete_group_member: session
+                .prepare(format!(
+                    "DELETE FROM {}.group_members WHERE group_id = ? AND user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_group_members: session
+                .prepare(format!("DELETE FROM {}.group_members WHERE group_id = ?", keyspace))
+                .await?,
+            select_user_groups: session
+                .prepare(format!(
+                    "SELECT group_id FROM {}.user_groups WHERE user_id = ?",
+                    keyspace
+                ))
+                .await?,
+            insert_user_group: session
+                .prepare(format!(
+                    "INSERT INTO {}.user_groups (user_id, group_id, added_at) VALUES (?, ?, ?)",
+                    keyspace
+                ))
+                .await?,
+            delete_user_group: session
+                .prepare(format!(
+                    "DELETE FROM {}.user_groups WHERE user_id = ? AND group_id = ?",
+                    keyspace
+                ))
+                .await?,
+            delete_user_groups: session
+                .prepare(format!("DELETE FROM {}.user_groups WHERE user_id = ?", keyspace))
+                .await?,
+            select_posts: session
+                .prepare(format!(
+                    "SELECT post_id, body FROM {}.posts WHERE user_id = ? LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            select_posts_before: session
+                .prepare(format!(
+                    "SELECT post_id, body FROM {}.posts WHERE user_id = ? AND post_id < ? LIMIT ?",
+                    keyspace
+                ))
+                .await?,
+            insert_post: session
+                .prepare(format!(
+                    "INSERT INTO {}.posts (user_id, post_id, body) VALUES (?, ?, ?) USING TTL ?",
+                    keyspace
+                ))
+                .await?,
+            delete_post: session
+                .prepare(format!(
+                    "DELETE FROM {}.posts WHERE user_id = ? AND post_id = ? IF EXISTS",
+                    keyspace
+                ))
+                .await?,
+            delete_posts: session
+                .prepare(format!("DELETE FROM {}.posts WHERE user_id = ?", keyspace))
+                .await?,
+            dynamic: RwLock::new(HashMap::new()),
+        })
+    }
+
+    // Returns the cached statement for `text`, preparing it on a cache miss.
+    // Callers must only pass text built from a bounded set of fragments, never
+    // from request values, or the cache grows without limit.
+    pub async fn get_or_prepare(
+        &self,
+        session: &Session,
+        text: String,
+    ) -> Result<PreparedStatement, QueryError> {
+        if let Some(prepared) = self.dynamic.read().unwrap().get(&text) {
+            return Ok(prepared.clone());
+        }
+
+        let mut prepared = session.prepare(text.as_str()).await?;
+        prepared.set_is_idempotent(reads(prepared.get_statement()));
+        self.dynamic
+            .write()
+            .unwrap()
+            .insert(text, prepared.clone());
+        Ok(prepared)
+    }
+
+    // Calls `f` with the name and statement of each fixed statement.
+    fn each_mut(&mut self, mut f: impl FnMut(&'static str, &mut PreparedStatement)) {
+        f("readiness_probe", &mut self.readiness_probe);
+        f("select_all_users", &mut self.select_all_users);
+        f("select_snapshot_rows", &mut self.select_snapshot_rows);
+        f("insert_snapshot_row", &mut self.insert_snapshot_row);
+        f("insert_snapshot_row_if_absent", &mut self.insert_snapshot_row_if_absent);
+        f("select_deleted_in_token_range", &mut self.select_deleted_in_token_range);
+        f("select_deleted_ids_in_token_range", &mut self.select_deleted_ids_in_token_range);
+        f("select_user_by_id", &mut self.select_user_by_id);
+        f("select_user_timestamps", &mut self.select_user_timestamps);
+        f("insert_user", &mut self.insert_user);
+        f("select_credentials_by_id", &mut self.select_credentials_by_id);
+        f("select_credentials_by_email", &mut self.select_credentials_by_email);
+        f("select_user_by_email", &mut self.select_user_by_email);
+        f("select_user_ids_by_email", &mut self.select_user_ids_by_email);
+        f("select_email_owner", &mut self.select_email_owner);
+        f("claim_email", &mut self.claim_email);
+        f("index_user_name", &mut self.index_user_name);
+        f("unindex_user_name", &mut self.unindex_user_name);
+        f("search_users_by_name", &mut self.search_users_by_name);
+        f("index_user_day", &mut self.index_user_day);
+        f("unindex_user_day", &mut self.unindex_user_day);
+        f("select_users_by_day", &mut self.select_users_by_day);
+        f("select_users_by_day_after", &mut self.select_users_by_day_after);
+        f("release_email", &mut self.release_email);
+        f("select_phone_owner", &mut self.select_phone_owner);
+        f("claim_phone", &mut self.claim_phone);
+        f("release_phone", &mut self.release_phone);
+        f("delete_user", &mut self.delete_user);
+        f("delete_user_if_unchanged", &mut self.delete_user_if_unchanged);
+        f("soft_delete_user", &mut self.soft_delete_user);
+        f("soft_delete_user_if_unchanged", &mut self.soft_delete_user_if_unchanged);
+        f("soft_delete_user_in_batch", &mut self.soft_delete_user_in_batch);
+        f("restore_user", &mut self.restore_user);
+        f("replace_user", &mut self.replace_user);
+        f("replace_user_if_unchanged", &mut self.replace_user_if_unchanged);
+        f("anonymize_user", &mut self.anonymize_user);
+        f("anonymize_user_if_unchanged", &mut self.anonymize_user_if_unchanged);
+        f("select_user_roles", &mut self.select_user_roles);
+        f("update_user_roles", &mut self.update_user_roles);
+        f("select_user_addresses", &mut self.select_user_addresses);
+        f("append_user_address", &mut self.append_user_address);
+        f("remove_user_address", &mut self.remove_user_address);
+        f("select_user_tags", &mut self.select_user_tags);
+        f("add_user_tag", &mut self.add_user_tag);
+        f("remove_user_tag", &mut self.remove_user_tag);
+        f("select_user_metadata", &mut self.select_user_metadata);
+        f("set_user_metadata", &mut self.set_user_metadata);
+        f("delete_user_metadata", &mut self.delete_user_metadata);
+        f("select_avatar", &mut self.select_avatar);
+        f("insert_avatar", &mut self.insert_avatar);
+        f("delete_avatar", &mut self.delete_avatar);
+        f("select_avatar_chunks", &mut self.select_avatar_chunks);
+        f("insert_avatar_chunk", &mut self.insert_avatar_chunk);
+        f("delete_avatar_chunks", &mut self.delete_avatar_chunks);
+        f("select_api_key", &mut self.select_api_key);
+        f("insert_api_key", &mut self.insert_api_key);
+        f("revoke_api_key", &mut self.revoke_api_key);
+        f("claim_idempotency_key", &mut self.claim_idempotency_key);
+        f("select_idempotency_key", &mut self.select_idempotency_key);
+        f("complete_idempotency_key", &mut self.complete_idempotency_key);
+        f("release_idempotency_key", &mut self.release_idempotency_key);
+        f("select_tenant", &mut self.select_tenant);
+        f("select_tenants", &mut self.select_tenants);
+        f("insert_tenant", &mut self.insert_tenant);
+        f("insert_audit_entry", &mut self.insert_audit_entry);
+        f("select_audit_entries", &mut self.select_audit_entries);
+        f("select_audit_entries_before", &mut self.select_audit_entries_before);
+        f("select_last_user_event", &mut self.select_last_user_event);
+        f("append_user_event", &mut self.append_user_event);
+        f("select_user_events", &mut self.select_user_events);
+        f("insert_outbox_event", &mut self.insert_outbox_event);
+        f("select_outbox_events", &mut self.select_outbox_events);
+        f("delete_outbox_event", &mut self.delete_outbox_event);
+        f("insert_email_verification", &mut self.insert_email_verification);
+        f("select_email_verification", &mut self.select_email_verification);
+        f("consume_email_verification", &mut self.consume_email_verification);
+        f("verify_user", &mut self.verify_user);
+        f("set_user_status", &mut self.set_user_status);
+        f("record_dual_write_failure", &mut self.record_dual_write_failure);
+        f("insert_password_reset", &mut self.insert_password_reset);
+        f("select_password_reset", &mut self.select_password_reset);
+        f("consume_password_reset", &mut self.consume_password_reset);
+        f("reset_password", &mut self.reset_password);
+        f("insert_session", &mut self.insert_session);
+        f("select_session", &mut self.select_session);
+        f("select_user_sessions", &mut self.select_user_sessions);
+        f("rotate_session", &mut self.rotate_session);
+        f("delete_session", &mut self.delete_session);
+        f("insert_oauth_state", &mut self.insert_oauth_state);
+        f("select_oauth_state", &mut self.select_oauth_state);
+        f("consume_oauth_state", &mut self.consume_oauth_state);
+        f("insert_oauth_identity", &mut self.insert_oauth_identity);
+        f("select_oauth_identity", &mut self.select_oauth_identity);
+        f("delete_oauth_identity", &mut self.delete_oauth_identity);
+        f("select_feature_flags", &mut self.select_feature_flags);
+        f("upsert_feature_flag", &mut self.upsert_feature_flag);
+        f("select_webhooks", &mut self.select_webhooks);
+        f("insert_webhook", &mut self.insert_webhook);
+        f("delete_webhook", &mut self.delete_webhook);
+        f("insert_webhook_delivery", &mut self.insert_webhook_delivery);
+        f("select_webhook_deliveries", &mut self.select_webhook_deliveries);
+        f("count_request", &mut self.count_request);
+        f("select_request_count", &mut self.select_request_count);
+        f("delete_request_counts", &mut self.delete_request_counts);
+        f("count_login", &mut self.count_login);
+        f("count_profile_view", &mut self.count_profile_view);
+        f("select_user_stats", &mut self.select_user_stats);
+        f("delete_user_stats", &mut self.delete_user_stats);
+        f("select_groups", &mut self.select_groups);
+        f("select_group", &mut self.select_group);
+        f("insert_group", &mut self.insert_group);
+        f("update_group", &mut self.update_group);
+        f("delete_group", &mut self.delete_group);
+        f("select_group_members", &mut self.select_group_members);
+        f("select_group_member", &mut self.select_group_member);
+        f("insert_group_member", &mut self.insert_group_member);
+        f("delete_group_member", &mut self.delete_group_member);
+        f("delete_group_members", &mut self.delete_group_members);
+        f("select_user_groups", &mut self.select_user_groups);
+        f("insert_user_group", &mut self.insert_user_group);
+        f("delete_user_group", &mut self.delete_user_group);
+        f("delete_user_groups", &mut self.delete_user_groups);
+        f("select_posts", &mut self.select_posts);
+        f("select_posts_before", &mut self.select_posts_before);
+        f("insert_post", &mut self.insert_post);
+        f("delete_post", &mut self.delete_post);
+        f("delete_posts", &mut self.delete_posts);
+    }
+
+    // Marks every SELECT idempotent, and the statements named in `named`, so
+    // the driver may retry them and send them speculatively. Returns the
+    // names that aren't statements.
+    pub fn mark_idempotent(&mut self, named: &[String]) -> Vec<String> {
+        let mut unknown: Vec<String> = named.to_vec();
+        self.each_mut(|name, statement| {
+            let listed = named.iter().any(|named| named == name);
+            statement.set_is_idempotent(listed || reads(statement.get_statement()));
+            unknown.retain(|named| named != name);
+        });
+        unknown
+    }
+}
+
+// Whether the CQL `text` is a SELECT, which may be run again without changing
+// anything.
+fn reads(text: &str) -> bool {
+    let text = text.trim_start();
+    text.get(..6).is_some_and(|verb| verb.eq_ignore_ascii_case("select"))
+}
+
+// Reads the `[applied]` column of a lightweight-transaction result.
+pub fn applied(result: QueryResult) -> Result<bool, String> {
+    let row = result
+        .into_rows_result()
+        .map_err(|e| e.to_string())?
+        .first_row::<Row>()
+        .map_err(|e| e.to_string())?;
+    match row.columns.first() {
+        Some(Some(CqlValue::Boolean(applied))) => Ok(*applied),
+        other => Err(format!("unexpected [applied] column: {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_selects_are_idempotent_by_default() {
+        assert!(reads("SELECT now() FROM system.local"));
+        assert!(reads("  select id FROM users WHERE id = ?"));
+        assert!(!reads("UPDATE users SET name = ? WHERE id = ?"));
+        assert!(!reads("DELETE FROM users WHERE id = ?"));
+        assert!(!reads("SEL"));
+    }
+}
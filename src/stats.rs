@@ -0,0 +1,137 @@
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, Problem};
+use crate::models::UserStats;
+use crate::observe;
+use crate::state::AppState;
+use crate::statements::Statements;
+use crate::users;
+use actix_web::{web, HttpResponse};
+use scylla::frame::value::Counter;
+use scylla::prepared_statement::PreparedStatement;
+use uuid::Uuid;
+
+// How often each user has logged in and had their profile read, kept in
+// counter columns of `user_stats` (migration 0026). Counters must live in a
+// table of their own, so they can't go on the user row, and can only be
+// incremented, never set or given a TTL; a hard delete drops the user's row
+// of them, a soft delete keeps it.
+//
+// Increments are not idempotent, so they are never retried: a timed-out one
+// may or may not have counted, and counting it twice would be worse than
+// missing it. They are made off the request path and a failure only logs a
+// warning, since a count is not worth failing or slowing a login or a read.
+
+fn authorize_for(subject: Option<&Subject>, user_id: Uuid) -> Result<&Subject, AuthError> {
+    auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only read their own stats unless they have the admin role",
+    )
+}
+
+// Adds one to the counter of `user_id` that `statement` increments, in the
+// background.
+fn increment(
+    data: &AppState,
+    statement_name: &'static str,
+    statement: fn(&Statements) -> &PreparedStatement,
+    user_id: Uuid,
+) {
+    let data = data.clone();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = observe::conditional(&data, statement_name, || {
+            data.session
+                .execute_unpaged(statement(&data.statements), (user_id,))
+        })
+        .await
+        {
+            tracing::warn!(%user_id, error = %e, statement = statement_name, "failed to count");
+        }
+    });
+}
+
+// Counts a successful login by `user_id`.
+pub fn record_login(data: &AppState, user_id: Uuid) {
+    increment(data, "count_login", |statements| &statements.count_login, user_id);
+}
+
+// Counts a read of the profile of `user_id`.
+pub fn record_profile_view(data: &AppState, user_id: Uuid) {
+    increment(data, "count_profile_view", |statements| &statements.count_profile_view, user_id);
+}
+
+// Drops the counters of `user_id`, for hard deletes. Best effort, like
+// `avatars::remove`.
+pub async fn remove(data: &AppState, user_id: Uuid) {
+    if let Err(e) = observe::query(data, "delete_user_stats", || {
+        data.session
+            .execute_unpaged(&data.statements.delete_user_stats, (user_id,))
+    })
+    .await
+    {
+        tracing::warn!(%user_id, error = %e, "failed to delete stats");
+    }
+}
+
+// The stats as read from a `user_stats` row. Counters never incremented are
+// null, and a user never counted has no row at all.
+fn from_row(row: Option<(Option<Counter>, Option<Counter>)>) -> UserStats {
+    let count = |counter: Option<Counter>| counter.map_or(0, |Counter(n)| n.max(0) as u64);
+    let (logins, profile_views) = row.unwrap_or_default();
+    UserStats {
+        logins: count(logins),
+        profile_views: count(profile_views),
+    }
+}
+
+/// How often the user has logged in and had their profile read.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/stats",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user's counts", body = UserStats),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn get_stats(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    users::stored_user(&data, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User with ID {} not found", user_id)))?;
+    let result = observe::query(&data, "select_user_stats", || {
+        data.session
+            .execute_unpaged(&data.statements.select_user_stats, (user_id,))
+    })
+    .await
+    .map_err(ApiError::from)?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read stats", e))?
+        .maybe_first_row::<(Option<Counter>, Option<Counter>)>()
+        .map_err(|e| ApiError::internal("Failed to read stats", e))?;
+    Ok(HttpResponse::Ok().json(from_row(row)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_rows_and_counters_count_as_zero() {
+        let none = from_row(None);
+        assert_eq!((none.logins, none.profile_views), (0, 0));
+        let some = from_row(Some((Some(Counter(3)), None)));
+        assert_eq!((some.logins, some.profile_views), (3, 0));
+        let both = from_row(Some((Some(Counter(1)), Some(Counter(42)))));
+        assert_eq!((both.logins, both.profile_views), (1, 42));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::audit::{self, Action};
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::dry_run;
+use crate::error::{ApiError, Problem};
+use crate::events::EventKind;
+use crate::history;
+use crate::links;
+use crate::models::{User, UserStatus};
+use crate::repository::ColumnWrite;
+use crate::sessions;
+use crate::state::AppState;
+use crate::users;
+use actix_web::{web, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+// A user is `active`, `suspended` or `deactivated`, stored in the `status`
+// column; users written before it existed have none and are active. Users
+// deactivate their own account with POST /users/{id}/deactivate and may
+// activate it again; only an admin suspends a user, or activates a suspended
+// one. Leaving `active` ends the user's sessions, and a suspended user can't
+// sign in again, by password, provider or refresh token, until activated.
+//
+// GET /users leaves out users that aren't active unless `status` asks for
+// them. The user is still read by id, and keeps their email and phone
+// claims. Like verification, a change of status is an event and an audit
+// entry but leaves `updated_at` and the version alone.
+
+// Whether the caller may move a user from `from` to `to`: an admin always,
+// the user themselves only between active and deactivated.
+fn permitted(from: UserStatus, to: UserStatus, admin: bool) -> Result<(), &'static str> {
+    if admin {
+        return Ok(());
+    }
+    match (from, to) {
+        (UserStatus::Suspended, _) => {
+            Err("only an admin can change the status of a suspended user")
+        }
+        (_, UserStatus::Suspended) => Err("only an admin can suspend a user"),
+        _ => Ok(()),
+    }
+}
+
+// Refuses to sign in a suspended user. Unknown users are left to the caller.
+pub async fn check_sign_in(data: &AppState, user_id: Uuid) -> Result<(), actix_web::Error> {
+    let user = users::stored_user(data, user_id).await?;
+    if user.and_then(|user| user.status) == Some(UserStatus::Suspended) {
+        tracing::info!(%user_id, "sign-in refused to a suspended user");
+        let reason = format!("user {} is suspended", user_id);
+        return Err(AuthError::Forbidden(reason).into());
+    }
+    Ok(())
+}
+
+// Moves the live user `user_id` to `to`, returning it as stored. A user
+// already there is returned unchanged.
+async fn change(
+    data: &AppState,
+    subject: Option<&Subject>,
+    user_id: Uuid,
+    to: UserStatus,
+) -> Result<User, actix_web::Error> {
+    let subject = auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only change their own status unless they have the admin role",
+    )?;
+    let before = match users::stored_row(data, user_id).await? {
+        Some((before, false)) => before,
+        _ => return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)).into()),
+    };
+    let from = before.status.unwrap_or_default();
+    permitted(from, to, subject.has_role(ADMIN_ROLE))
+        .map_err(|reason| AuthError::Forbidden(reason.to_string()))?;
+    if from == to {
+        return Ok(before);
+    }
+    let after = User {
+        status: Some(to),
+        ..before.clone()
+    };
+    if dry_run::active() {
+        return Ok(after);
+    }
+
+    let write = ColumnWrite::Status { from, to };
+    let (applied, _) = data.users.write_column(user_id, before.expires_at, write).await?;
+    if !applied {
+        let message = format!("User {} was changed or deleted concurrently; retry", user_id);
+        return Err(ApiError::Conflict(message).into());
+    }
+    if to != UserStatus::Active {
+        match sessions::revoke_all(data, user_id).await {
+            Ok(ended) => tracing::info!(%user_id, ended, "sessions ended with the status change"),
+            Err(e) => tracing::warn!(%user_id, error = %e, "failed to end sessions"),
+        }
+    }
+    users::forget_cached(data, user_id).await;
+    history::append(data, EventKind::Updated, user_id, Some(after.clone())).await;
+    audit::record(data, user_id, Action::Update, Some(&before), Some(&after)).await;
+    tracing::info!(
+        %user_id,
+        from = from.as_str(),
+        to = to.as_str(),
+        actor = %subject.id,
+        "status changed"
+    );
+    Ok(after)
+}
+
+async fn respond(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    to: UserStatus,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = change(&data, subject.as_deref(), user_id.into_inner(), to).await?;
+    let body = links::user(&req, &user, serde_json::to_value(&user).unwrap_or_default());
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Deactivates the user: they leave listings and their sessions end. The
+/// user can activate their account again.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/deactivate",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user, deactivated", body = User),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user, or the user is suspended"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "The user changed concurrently", body = Problem),
+    )
+)]
+pub async fn deactivate_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    respond(req, subject, user_id, data, UserStatus::Deactivated).await
+}
+
+/// Suspends the user: they leave listings, their sessions end and they
+/// can't sign in until an admin activates them.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/suspend",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user, suspended", body = User),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "The user changed concurrently", body = Problem),
+    )
+)]
+pub async fn suspend_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    respond(req, subject, user_id, data, UserStatus::Suspended).await
+}
+
+/// Activates a deactivated or suspended user; only an admin activates a
+/// suspended one.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/activate",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user, active", body = User),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user, or the user is suspended"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "The user changed concurrently", body = Problem),
+    )
+)]
+pub async fn activate_user(
+    req: HttpRequest,
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    respond(req, subject, user_id, data, UserStatus::Active).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::StatusFilter;
+
+    #[test]
+    fn users_move_between_active_and_deactivated_and_admins_anywhere() {
+        use UserStatus::*;
+        assert!(permitted(Active, Deactivated, false).is_ok());
+        assert!(permitted(Deactivated, Active, false).is_ok());
+        assert!(permitted(Active, Suspended, false).is_err());
+        assert!(permitted(Suspended, Active, false).is_err());
+        assert!(permitted(Suspended, Deactivated, false).is_err());
+        for (from, to) in [(Active, Suspended), (Suspended, Active), (Suspended, Deactivated)] {
+            assert!(permitted(from, to, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn statuses_read_from_the_column_and_filter_listings() {
+        assert_eq!(UserStatus::from_column(None), UserStatus::Active);
+        assert_eq!(UserStatus::from_column(Some("suspended")), UserStatus::Suspended);
+        assert_eq!(UserStatus::from_column(Some("deactivated")), UserStatus::Deactivated);
+        assert!(StatusFilter::default().admits(UserStatus::Active));
+        assert!(!StatusFilter::default().admits(UserStatus::Deactivated));
+        assert!(StatusFilter::Suspended.admits(UserStatus::Suspended));
+        assert!(StatusFilter::All.admits(UserStatus::Deactivated));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::auth::{self, AuthError, Subject, ADMIN_ROLE};
+use crate::error::{ApiError, FieldError, Problem};
+use crate::models::{NewTag, Tags};
+use crate::negotiate::Body;
+use crate::observe;
+use crate::repository::ColumnWrite;
+use crate::state::AppState;
+use crate::users;
+use crate::validation;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// A user's tags are a set on the user row, served as a sub-resource of the
+// user: GET /users/{id}/tags lists them, POST adds one and DELETE
+// /users/{id}/tags/{tag} removes one. Both writes are set mutations
+// (`tags + ?` and `tags - ?`), so concurrent ones don't undo each other,
+// and adding a tag the user already has changes nothing. GET /users?tag=
+// finds the users with a tag through the index on the set (migration 0022).
+//
+// Like addresses, tags are not part of the user's representation and leave
+// `updated_at`, the user's version and its events alone, but they do change
+// which users a `tag` listing returns, so cached listings are dropped.
+
+// Most tags one user may have. Checked against the set as read, so two
+// concurrent additions may both get in at the limit.
+const MAX_TAGS: usize = 20;
+
+fn authorize_for(subject: Option<&Subject>, user_id: Uuid) -> Result<&Subject, AuthError> {
+    auth::authorize(
+        subject,
+        |subject| subject.has_role(ADMIN_ROLE) || subject.is_user(user_id),
+        "users may only manage their own tags unless they have the admin role",
+    )
+}
+
+// The tags of the live user with `user_id`, in order, and when the user
+// expires, or 404.
+async fn stored(
+    data: &AppState,
+    user_id: Uuid,
+) -> Result<(Vec<String>, Option<DateTime<Utc>>), ApiError> {
+    let user = users::stored_user(data, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User with ID {} not found", user_id)))?;
+    let result = observe::query(data, "select_user_tags", || {
+        data.session
+            .execute_unpaged(&data.statements.select_user_tags, (user_id,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Failed to read tags", e))?
+        .maybe_first_row::<(Option<Vec<String>>,)>()
+        .map_err(|e| ApiError::internal("Failed to read tags", e))?;
+    let tags = row.and_then(|(tags,)| tags).unwrap_or_default();
+    Ok((tags, user.expires_at))
+}
+
+// Makes `write`, `AddTag` or `RemoveTag`, failing with 404 when the row has
+// gone since it was read.
+async fn change(
+    data: &AppState,
+    user_id: Uuid,
+    write: ColumnWrite<'_>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let (applied, _) = data.users.write_column(user_id, expires_at, write).await?;
+    if !applied {
+        return Err(ApiError::NotFound(format!("User with ID {} not found", user_id)));
+    }
+    users::forget_cached(data, user_id).await;
+    Ok(())
+}
+
+/// The user's tags, in alphabetical order.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/tags",
+    params(("id" = Uuid, Path, description = "User id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The tags", body = Tags),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+    )
+)]
+pub async fn list_tags(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let (tags, _) = stored(&data, user_id).await?;
+    Ok(HttpResponse::Ok().json(Tags { tags }))
+}
+
+/// Adds a tag to the user. Adding one the user already has changes nothing.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/tags",
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body = NewTag,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The user's tags", body = Tags),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user", body = Problem),
+        (status = 409, description = "The user already has 20 tags", body = Problem),
+        (status = 422, description = "Invalid tag", body = Problem),
+    )
+)]
+pub async fn add_tag(
+    subject: Option<web::ReqData<Subject>>,
+    user_id: web::Path<Uuid>,
+    Body(new_tag): Body<NewTag>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let tag = validation::tag(&new_tag.tag).map_err(|message| {
+        ApiError::Validation(vec![FieldError {
+            field: String::from("tag"),
+            message,
+        }])
+    })?;
+    let (mut tags, expires_at) = stored(&data, user_id).await?;
+    let Err(position) = tags.binary_search(&tag) else {
+        return Ok(HttpResponse::Ok().json(Tags { tags }));
+    };
+    if tags.len() >= MAX_TAGS {
+        return Err(ApiError::Conflict(format!(
+            "User {} already has {} tags",
+            user_id, MAX_TAGS
+        ))
+        .into());
+    }
+    change(&data, user_id, ColumnWrite::AddTag(&tag), expires_at).await?;
+    tracing::info!(%user_id, %tag, "tag added");
+    tags.insert(position, tag);
+    Ok(HttpResponse::Ok().json(Tags { tags }))
+}
+
+/// Removes a tag from the user.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/tags/{tag}",
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("tag" = String, Path, description = "The tag, in any case"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Tag removed; the user's tags", body = Tags),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is neither an admin nor the user"),
+        (status = 404, description = "No such user, or the user doesn't have the tag", body = Problem),
+    )
+)]
+pub async fn delete_tag(
+    subject: Option<web::ReqData<Subject>>,
+    path: web::Path<(Uuid, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, tag) = path.into_inner();
+    authorize_for(subject.as_deref(), user_id)?;
+    let tag = validation::normalize_tag(&tag);
+    let (mut tags, expires_at) = stored(&data, user_id).await?;
+    let Ok(position) = tags.binary_search(&tag) else {
+        let message = format!("User {} has no tag {:?}", user_id, tag);
+        return Err(ApiError::NotFound(message).into());
+    };
+    change(&data, user_id, ColumnWrite::RemoveTag(&tag), expires_at).await?;
+    tracing::info!(%user_id, %tag, "tag removed");
+    tags.remove(position);
+    Ok(HttpResponse::Ok().json(Tags { tags }))
+}
@@ -0,0 +1,357 @@
+use crate::config::{self, Config};
+use crate::error::{ApiError, Problem};
+use crate::migrations::{self, Ddl};
+use crate::models::{NewTenant, Tenant};
+use crate::observe;
+use crate::startup::{self, Replication};
+use crate::state::AppState;
+use crate::statements::{self, Statements};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::RwLock;
+use tokio::sync::Mutex;
+
+// Each tenant's users live in a keyspace of their own, `keyspace_prefix`
+// followed by the tenant id, with the same tables as the serving keyspace.
+// A request names its tenant with `X-Tenant-Id`, or as the subdomain of
+// `tenants.base_domain` it was sent to, and `route` serves it with a state
+// whose statements are prepared against that keyspace; requests naming no
+// tenant are served from `scylla.keyspace` as before. Tenants are registered
+// in the serving keyspace's `tenants` table by POST /admin/tenants, which
+// creates and migrates the keyspace first. Tenant states are built on first
+// use and kept for the life of the process.
+//
+// Background work (the CDC consumer, the cluster monitor) and gRPC stay on
+// the serving keyspace.
+
+pub const TENANT_HEADER: HeaderName = HeaderName::from_static("x-tenant-id");
+
+pub struct Tenants {
+    base: AppState,
+    config: Config,
+    replication: Replication,
+    ddl: Ddl,
+    serving: RwLock<HashMap<String, web::Data<AppState>>>,
+    // Held while a keyspace is created, as that changes the session's
+    // default keyspace until it is put back.
+    provisioning: Mutex<()>,
+}
+
+// The keyspace of tenant `id`. Ids are lowercase letters, digits and
+// underscores, short enough for the keyspace name to fit in 48 characters.
+fn keyspace_for(prefix: &str, id: &str) -> Result<String, ApiError> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(ApiError::BadRequest(String::from(
+            "tenant ids are lowercase letters, digits and underscores",
+        )));
+    }
+    let keyspace = format!("{}{}", prefix, id);
+    if !config::is_valid_keyspace(&keyspace) {
+        return Err(ApiError::BadRequest(format!("tenant id {} is too long", id)));
+    }
+    Ok(keyspace)
+}
+
+// The label before `base_domain` in `host`, its port aside.
+fn subdomain<'a>(host: &'a str, base_domain: &str) -> Option<&'a str> {
+    let host = host.split(':').next().unwrap_or(host);
+    host.strip_suffix(base_domain)?
+        .strip_suffix('.')
+        .filter(|label| !label.is_empty())
+}
+
+// The tenant a request names, lowercased: its `X-Tenant-Id`, or else the
+// subdomain of `base_domain` it was sent to.
+fn named(
+    header: Option<&HeaderValue>,
+    host: &str,
+    base_domain: Option<&str>,
+) -> Result<Option<String>, ApiError> {
+    if let Some(value) = header {
+        return value
+            .to_str()
+            .map(|id| Some(id.trim().to_ascii_lowercase()))
+            .map_err(|_| ApiError::BadRequest(format!("{} must be a tenant id", TENANT_HEADER)));
+    }
+    let host = host.to_ascii_lowercase();
+    Ok(base_domain.and_then(|base| subdomain(&host, &base.to_ascii_lowercase()).map(String::from)))
+}
+
+// Makes `data` the `web::Data<T>` the rest of `req` sees: data added to a
+// request is looked up before the app's own.
+fn serve_with<T: 'static>(req: &mut ServiceRequest, data: web::Data<T>) {
+    let mut extensions = Extensions::new();
+    extensions.insert(data);
+    req.add_data_container(Rc::new(extensions));
+}
+
+impl Tenants {
+    pub fn new(base: AppState, config: &Config) -> Self {
+        Tenants {
+            base,
+            config: config.clone(),
+            replication: Replication {
+                factor: config.scylla.replication_factor,
+                datacenters: config.scylla.replication_datacenters.clone(),
+            },
+            ddl: Ddl::new(&config.scylla),
+            serving: RwLock::new(HashMap::new()),
+            provisioning: Mutex::new(()),
+        }
+    }
+
+    async fn registered(&self, id: &str) -> Result<Option<Tenant>, ApiError> {
+        let state = &self.base;
+        let result = observe::query(state, "select_tenant", || {
+            state.session.execute_unpaged(&state.statements.select_tenant, (id,))
+        })
+        .await?;
+        let row = result
+            .into_rows_result()
+            .map_err(|e| ApiError::internal("Error reading tenant", e))?
+            .maybe_first_row::<(String, Option<DateTime<Utc>>)>()
+            .map_err(|e| ApiError::internal("Error reading tenant", e))?;
+        Ok(row.map(|(keyspace, created_at)| Tenant {
+            id: id.to_string(),
+            keyspace,
+            created_at,
+        }))
+    }
+
+    // The state serving tenant `id`, built on its first request.
+    async fn state(&self, id: &str) -> Result<web::Data<AppState>, ApiError> {
+        keyspace_for(&self.config.tenants.keyspace_prefix, id)?;
+        if let Some(state) = self.serving.read().unwrap().get(id) {
+            return Ok(state.clone());
+        }
+        let tenant = self
+            .registered(id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound(format!("Tenant {} not found", id)))?;
+        self.serve(tenant).await
+    }
+
+    async fn serve(&self, tenant: Tenant) -> Result<web::Data<AppState>, ApiError> {
+        let statements = Statements::prepare(&self.base.session, &tenant.keyspace).await?;
+        let state = web::Data::new(self.base.for_keyspace(&self.config, tenant.keyspace, statements));
+        Ok(self
+            .serving
+            .write()
+            .unwrap()
+            .entry(tenant.id)
+            .or_insert(state)
+            .clone())
+    }
+
+    // Creates and migrates tenant `id`'s keyspace, then registers it.
+    // Creating a keyspace that exists already is harmless, so a provisioning
+    // that failed half-way can be retried.
+    async fn provision(&self, id: String, keyspace: String) -> Result<Tenant, ApiError> {
+        let _provisioning = self.provisioning.lock().await;
+        if self.registered(&id).await?.is_some() {
+            return Err(ApiError::Conflict(format!("Tenant {} already exists", id)));
+        }
+
+        let session = &self.base.session;
+        let created = async {
+            startup::bootstrap(session, &keyspace, &self.replication, &self.ddl).await?;
+            migrations::run(session, &keyspace, &self.ddl).await
+        }
+        .await;
+        session
+            .use_keyspace(&self.base.keyspace, false)
+            .await
+            .map_err(|e| ApiError::internal("Cannot use the serving keyspace", e))?;
+        let count = created.map_err(|e| ApiError::internal("Failed to create the tenant keyspace", e))?;
+        tracing::info!(tenant = %id, keyspace = %keyspace, count, "provisioned tenant keyspace");
+
+        let tenant = Tenant {
+            id,
+            keyspace,
+            created_at: Some(Utc::now()),
+        };
+        let state = &self.base;
+        let result = observe::conditional(state, "insert_tenant", || {
+            state.session.execute_unpaged(
+                &state.statements.insert_tenant,
+                (&tenant.id, &tenant.keyspace, tenant.created_at),
+            )
+        })
+        .await?;
+        match statements::applied(result) {
+            Ok(true) => {}
+            Ok(false) => return Err(ApiError::Conflict(format!("Tenant {} already exists", tenant.id))),
+            Err(e) => return Err(ApiError::internal("Failed to register tenant", e)),
+        }
+        self.serve(tenant.clone()).await?;
+        Ok(tenant)
+    }
+}
+
+// Serves the rest of the request from the keyspace of the tenant it names.
+pub async fn route(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let tenants = req.app_data::<web::Data<Tenants>>().cloned();
+    let named = match &tenants {
+        Some(tenants) => named(
+            req.headers().get(&TENANT_HEADER),
+            req.connection_info().host(),
+            tenants.config.tenants.base_domain.as_deref(),
+        ),
+        None if req.headers().contains_key(&TENANT_HEADER) => Err(ApiError::BadRequest(format!(
+            "{} is not enabled on this server",
+            TENANT_HEADER
+        ))),
+        None => Ok(None),
+    };
+    let state = match (tenants, named) {
+        (_, Err(e)) => Err(e),
+        (Some(tenants), Ok(Some(id))) => tenants.state(&id).await.map(Some),
+        _ => Ok(None),
+    };
+    match state {
+        Ok(state) => {
+            if let Some(state) = state {
+                serve_with(&mut req, state);
+            }
+            next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+        }
+        Err(e) => Ok(req.error_response(e).map_into_boxed_body()),
+    }
+}
+
+/// Creates the tenant's keyspace with the tables of the serving one and
+/// registers it. Requests with `X-Tenant-Id` set to its id are then served
+/// from it.
+#[utoipa::path(
+    post,
+    path = "/admin/tenants",
+    request_body = NewTenant,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 201, description = "Tenant provisioned", body = Tenant),
+        (status = 400, description = "Invalid tenant id", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+        (status = 409, description = "The tenant exists already", body = Problem),
+    )
+)]
+pub async fn create_tenant(
+    new_tenant: web::Json<NewTenant>,
+    tenants: web::Data<Tenants>,
+) -> Result<HttpResponse, ApiError> {
+    let id = new_tenant.into_inner().id.trim().to_ascii_lowercase();
+    let keyspace = keyspace_for(&tenants.config.tenants.keyspace_prefix, &id)?;
+    let tenant = tenants.provision(id, keyspace).await?;
+    Ok(HttpResponse::Created().json(tenant))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/tenants",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Every registered tenant, by id", body = [Tenant]),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller is not an admin"),
+    )
+)]
+pub async fn list_tenants(tenants: web::Data<Tenants>) -> Result<HttpResponse, ApiError> {
+    let state = &tenants.base;
+    let result = observe::query(state, "select_tenants", || {
+        state.session.execute_unpaged(&state.statements.select_tenants, ())
+    })
+    .await?;
+    let mut listed = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error listing tenants", e))?
+        .rows::<(String, String, Option<DateTime<Utc>>)>()
+        .map_err(|e| ApiError::internal("Error listing tenants", e))?
+        .map(|row| {
+            row.map(|(id, keyspace, created_at)| Tenant {
+                id,
+                keyspace,
+                created_at,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Error listing tenants", e))?;
+    listed.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn tenant_ids_name_keyspaces() {
+        assert_eq!(keyspace_for("tenant_", "acme_2").unwrap(), "tenant_acme_2");
+        assert!(keyspace_for("tenant_", "").is_err());
+        assert!(keyspace_for("tenant_", "Acme").is_err());
+        assert!(keyspace_for("tenant_", "acme.corp").is_err());
+        assert!(keyspace_for("tenant_", &"a".repeat(42)).is_err());
+        assert!(keyspace_for("tenant_", &"a".repeat(41)).is_ok());
+    }
+
+    #[test]
+    fn tenants_are_named_by_header_or_subdomain() {
+        let header = HeaderValue::from_static(" Acme ");
+        let base = Some("api.example.com");
+        assert_eq!(named(Some(&header), "other.api.example.com", base).unwrap().as_deref(), Some("acme"));
+        assert_eq!(named(None, "Globex.API.example.com:8080", base).unwrap().as_deref(), Some("globex"));
+        assert_eq!(named(None, "api.example.com", base).unwrap(), None);
+        assert_eq!(named(None, "myapi.example.com", base).unwrap(), None);
+        assert_eq!(named(None, "globex.api.example.com", None).unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn added_data_is_seen_before_the_apps() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(String::from("serving")))
+                .wrap(from_fn(|mut req: ServiceRequest, next: Next<BoxBody>| async move {
+                    if req.headers().contains_key(&TENANT_HEADER) {
+                        serve_with(&mut req, web::Data::new(String::from("tenant")));
+                    }
+                    next.call(req).await
+                }))
+                .route("/", web::get().to(|data: web::Data<String>| async move { data.to_string() })),
+        )
+        .await;
+        let plain = test::call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(plain, "serving");
+        let tenant = TestRequest::get().uri("/").insert_header((TENANT_HEADER, "acme")).to_request();
+        assert_eq!(test::call_and_read_body(&app, tenant).await, "tenant");
+    }
+
+    #[actix_web::test]
+    async fn tenant_headers_are_refused_unless_enabled() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(route))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let tenant = TestRequest::get().uri("/").insert_header((TENANT_HEADER, "acme")).to_request();
+        assert_eq!(test::call_service(&app, tenant).await.status(), StatusCode::BAD_REQUEST);
+        let plain = test::call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(plain.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,48 @@
+use actix_web::http::header;
+use actix_web::http::uri::Authority;
+use actix_web::{HttpRequest, HttpResponse};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::path::Path;
+use std::sync::Arc;
+
+// rustls server config from a PEM certificate chain and private key, using
+// the ring provider.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificates from {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("cannot read private key from {}: {}", key_path.display(), e))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate/key pair: {}", e))
+}
+
+// Default service of the plain-HTTP listener: a permanent redirect to the same
+// host and path on the HTTPS port.
+pub async fn redirect_to_https(req: HttpRequest, https_port: actix_web::web::Data<u16>) -> HttpResponse {
+    let host = req.connection_info().host().to_string();
+    let host = host
+        .parse::<Authority>()
+        .map_or(host.clone(), |authority| authority.host().to_string());
+    let authority = match **https_port {
+        443 => host,
+        port => format!("{}:{}", host, port),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, format!("https://{}{}", authority, path)))
+        .finish()
+}
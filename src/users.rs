@@ -0,0 +1,2158 @@
+use crate::audit::{self, Action};
+use crate::avatars;
+use crate::config::{ConcurrencyMode, RowCapMode};
+use crate::consistency;
+use crate::coalesce::Coalescer;
+use crate::cql::{self, Condition, Op};
+use crate::days;
+use crate::dry_run;
+use crate::emails;
+use crate::error::ApiError;
+use crate::events::EventKind;
+use crate::flags::Flag;
+use crate::groups;
+use crate::history;
+use crate::login;
+use crate::models::{
+    ListUsersQuery, NewUser, Profile, ReplaceUser, SearchUsersQuery, SortField, SortOrder,
+    StatusFilter, UpdateUser, User, UserStatus, UsersPage,
+};
+use crate::observe;
+use crate::paging::{self, CursorKind, CursorScope};
+use crate::phones;
+use crate::posts;
+use crate::repository::Expect;
+use crate::search;
+use crate::search_index::SearchIndex;
+use crate::sessions;
+use crate::state::AppState;
+use crate::statements;
+use crate::stats;
+use crate::validation;
+use crate::verification;
+use crate::write_behind;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::future;
+use futures::stream::LocalBoxStream;
+use futures::{Stream, StreamExt, TryStreamExt};
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::prepared_statement::PreparedStatement;
+use scylla::statement::PagingState;
+use scylla::{DeserializeRow, SerializeRow};
+use scylla::QueryResult;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use uuid::Uuid;
+
+// The user operations behind every API surface (REST, GraphQL, gRPC). They
+// validate input, keep the email and phone claims, the name index and the
+// user cache in step with the `users` table, append each change to the
+// user's event stream (which publishes it to the live feeds), record it in
+// the audit log, and report failures as `ApiError`s; authentication, content negotiation and
+// response headers stay with the callers.
+//
+// `tracing` switches on server-side query tracing for the statements an
+// operation runs; the ids of the recorded sessions are returned alongside its
+// result.
+
+// Prepared statements are shared, so tracing and the consistency levels the
+// request asked for (see `consistency`) are set on a cheap clone.
+pub fn for_request(prepared: &PreparedStatement, tracing: bool) -> PreparedStatement {
+    let mut prepared = prepared.clone();
+    prepared.set_tracing(tracing);
+    consistency::apply(&mut prepared);
+    prepared
+}
+
+// Resolves a requested page size against `http.max_page_size` and the row
+// cap. The row cap still bounds a single response: in truncate mode an
+// oversized limit is served as a smaller page (the flag), in error mode it is
+// refused.
+pub fn page_limit(data: &AppState, limit: Option<usize>) -> Result<(usize, bool), ApiError> {
+    let requested = limit.unwrap_or(data.default_page_size);
+    if requested == 0 || requested > data.max_page_size {
+        return Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            data.max_page_size
+        )));
+    }
+    if requested <= data.max_rows_per_request {
+        return Ok((requested, false));
+    }
+    if data.row_cap_mode == RowCapMode::Error {
+        return Err(ApiError::BadRequest(format!(
+            "limit exceeds the limit of {} rows per request",
+            data.max_rows_per_request
+        )));
+    }
+    Ok((data.max_rows_per_request, true))
+}
+
+// Set on a response the row cap cut short, in truncate mode.
+pub const TRUNCATED_HEADER: HeaderName = HeaderName::from_static("x-truncated");
+
+// `response`, marked `X-Truncated: true` when `truncated`.
+pub fn with_truncated(mut response: HttpResponse, truncated: bool) -> HttpResponse {
+    if truncated {
+        response
+            .headers_mut()
+            .insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+// Reads `rows` up to the row cap, for reads that aren't bounded by a page
+// size, such as streamed listings and exports. One row past the cap is read
+// to tell whether there are more: in truncate mode the rows up to the cap are
+// returned with the flag, in error mode the read is refused. Holding the rows
+// back bounds the response before its status is sent.
+pub async fn cap_rows<T>(
+    cap: usize,
+    mode: RowCapMode,
+    mut rows: impl Stream<Item = Result<T, ApiError>> + Unpin,
+) -> Result<(Vec<T>, bool), ApiError> {
+    let mut capped = Vec::new();
+    while let Some(row) = rows.try_next().await? {
+        if capped.len() == cap {
+            if mode == RowCapMode::Error {
+                return Err(ApiError::BadRequest(format!(
+                    "the read exceeds the limit of {} rows per request",
+                    cap
+                )));
+            }
+            return Ok((capped, true));
+        }
+        capped.push(row);
+    }
+    Ok((capped, false))
+}
+
+// A `users` row as read with `statements::USER_COLUMNS`.
+#[derive(DeserializeRow)]
+pub struct UserRow {
+    id: Uuid,
+    name: String,
+    email: String,
+    phone: Option<String>,
+    profile: Option<Profile>,
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    verified: Option<bool>,
+    version: Option<i32>,
+    status: Option<String>,
+    anonymized_at: Option<DateTime<Utc>>,
+    expires_in: Option<i32>,
+}
+
+impl UserRow {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn into_user(self) -> User {
+        User {
+            id: self.id,
+            name: self.name,
+            email: self.email,
+            phone: self.phone,
+            profile: self.profile,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            expires_at: self
+                .expires_in
+                .map(|secs| Utc::now() + TimeDelta::seconds(i64::from(secs))),
+            // Users registered before verification existed have no flag.
+            verified: Some(self.verified.unwrap_or(false)),
+            version: self.version,
+            status: Some(UserStatus::from_column(self.status.as_deref())),
+            anonymized_at: self.anonymized_at,
+        }
+    }
+}
+
+// The values `insert_user` binds, matched to its columns by name.
+#[derive(SerializeRow)]
+pub struct NewUserRow<'a> {
+    id: Uuid,
+    name: &'a str,
+    email: &'a str,
+    phone: Option<&'a str>,
+    password_hash: Option<&'a str>,
+    profile: Option<CqlValue>,
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    #[scylla(rename = "[ttl]")]
+    ttl: i32,
+}
+
+impl<'a> NewUserRow<'a> {
+    pub fn new(keyspace: &str, user: &'a User, password_hash: Option<&'a str>) -> Self {
+        NewUserRow {
+            id: user.id,
+            name: &user.name,
+            email: &user.email,
+            phone: user.phone.as_deref(),
+            password_hash,
+            profile: user.profile.as_ref().map(|profile| profile_value(keyspace, profile)),
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            ttl: ttl(user.expires_at),
+        }
+    }
+}
+
+// A `users` row as read by `select_credentials_by_id` and
+// `select_credentials_by_email`.
+#[derive(DeserializeRow)]
+pub struct CredentialsRow {
+    pub id: Uuid,
+    password_hash: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl CredentialsRow {
+    // The password hash of a live user who has one.
+    pub fn live_hash(self) -> Option<String> {
+        self.password_hash.filter(|_| self.deleted_at.is_none())
+    }
+}
+
+// A `users` row as read by `select_user_timestamps`.
+#[derive(DeserializeRow)]
+pub struct TimestampsRow {
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl TimestampsRow {
+    // When a live user last changed, `Some(None)` for one that predates
+    // timestamps; `None` for a soft-deleted user.
+    pub fn modified(self) -> Option<Option<DateTime<Utc>>> {
+        self.deleted_at
+            .is_none()
+            .then(|| self.updated_at.or(self.created_at))
+    }
+}
+
+// The TTL that makes a write to a user's rows expire along with the user:
+// the seconds left until `expires_at`, rounded up, or 0 (none) for a user
+// that doesn't expire. Every write to `users` binds it, as cells written
+// without one would outlive the rest of the row.
+pub fn ttl(expires_at: Option<DateTime<Utc>>) -> i32 {
+    expires_at.map_or(0, |at| {
+        let millis = (at - Utc::now()).num_milliseconds();
+        i32::try_from((millis + 999) / 1000).unwrap_or(i32::MAX).max(1)
+    })
+}
+
+// When a user registered at `now` expires: after the `expires_in_seconds` it
+// asked for, else `http.default_user_ttl_secs`, else never.
+pub fn expiry(
+    data: &AppState,
+    expires_in_seconds: Option<u32>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    expires_in_seconds
+        .or(data.default_user_ttl)
+        .map(|secs| now + TimeDelta::seconds(i64::from(secs)))
+}
+
+// Reads user rows selecting the fields of `User`.
+fn read_users(result: QueryResult, limit: usize) -> Result<Vec<User>, ApiError> {
+    let rows_result = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading rows", e))?;
+    let rows = rows_result
+        .rows::<User>()
+        .map_err(|e| ApiError::internal("Error streaming rows", e))?;
+
+    let mut users = Vec::with_capacity(limit);
+    for row in rows {
+        users.push(row.map_err(|e| ApiError::internal("Error fetching next row", e))?);
+    }
+    Ok(users)
+}
+
+// Reads `UserRow`s into users, or rows of `columns` when the listing was
+// narrowed, skipping soft-deleted ones and those `status` leaves out; a page
+// may therefore hold fewer users than its limit.
+fn read_live_users(
+    result: QueryResult,
+    limit: usize,
+    columns: Option<&[&str]>,
+    status: StatusFilter,
+) -> Result<Vec<User>, ApiError> {
+    let rows_result = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading rows", e))?;
+    let mut users = Vec::with_capacity(limit);
+    if let Some(columns) = columns {
+        let rows = rows_result
+            .rows::<Row>()
+            .map_err(|e| ApiError::internal("Error streaming rows", e))?;
+        for row in rows {
+            let row = row.map_err(|e| ApiError::internal("Error fetching next row", e))?;
+            users.extend(sparse_user(columns, row).filter(|user| admitted(status, user)));
+        }
+        return Ok(users);
+    }
+    let rows = rows_result
+        .rows::<UserRow>()
+        .map_err(|e| ApiError::internal("Error streaming rows", e))?;
+    for row in rows {
+        let row = row.map_err(|e| ApiError::internal("Error fetching next row", e))?;
+        if !row.is_deleted() {
+            users.extend(Some(row.into_user()).filter(|user| admitted(status, user)));
+        }
+    }
+    Ok(users)
+}
+
+// Whether a listing asking for `status` shows `user`.
+pub fn admitted(status: StatusFilter, user: &User) -> bool {
+    status.admits(user.status.unwrap_or_default())
+}
+
+// The columns a listing narrowed to `fields` reads: those fields, in
+// `USER_FIELDS` order so there are few distinct statements, with `id` and
+// the sort column to order the page by, `status` to filter by and
+// `deleted_at` to skip soft-deleted users.
+fn sparse_columns(fields: &[&str], sort: Option<SortField>) -> Vec<&'static str> {
+    let sort_column = match sort {
+        Some(SortField::Name) => Some("name"),
+        Some(SortField::Email) => Some("email"),
+        Some(SortField::Id) | None => None,
+    };
+    let mut columns: Vec<&'static str> = USER_FIELDS
+        .into_iter()
+        .filter(|field| {
+            ["id", "status"].contains(field)
+                || fields.contains(field)
+                || sort_column == Some(*field)
+        })
+        .collect();
+    columns.push("deleted_at");
+    columns
+}
+
+// A user from a row of `columns`, as `sparse_columns` chose them, or `None`
+// for a soft-deleted one. Fields not read are left empty, and left out of the
+// response.
+fn sparse_user(columns: &[&str], row: Row) -> Option<User> {
+    let mut user = User {
+        id: Uuid::nil(),
+        name: String::new(),
+        email: String::new(),
+        phone: None,
+        profile: None,
+        created_at: None,
+        updated_at: None,
+        expires_at: None,
+        verified: None,
+        version: None,
+        status: None,
+        anonymized_at: None,
+    };
+    let timestamp = |value: &CqlValue| {
+        value
+            .as_cql_timestamp()
+            .and_then(|timestamp| DateTime::from_timestamp_millis(timestamp.0))
+    };
+    for (column, value) in columns.iter().zip(row.columns) {
+        let Some(value) = value else {
+            continue;
+        };
+        match *column {
+            "id" => user.id = value.as_uuid().unwrap_or_default(),
+            "name" => user.name = value.into_string().unwrap_or_default(),
+            "email" => user.email = value.into_string().unwrap_or_default(),
+            "phone" => user.phone = value.into_string(),
+            "profile" => {
+                let mut profile = Profile::default();
+                for (field, value) in value.into_udt_pair_vec().unwrap_or_default() {
+                    let value = value.and_then(CqlValue::into_string);
+                    match field.as_str() {
+                        "bio" => profile.bio = value,
+                        "avatar_url" => profile.avatar_url = value,
+                        "locale" => profile.locale = value,
+                        "timezone" => profile.timezone = value,
+                        _ => {}
+                    }
+                }
+                user.profile = Some(profile);
+            }
+            "created_at" => user.created_at = timestamp(&value),
+            "updated_at" => user.updated_at = timestamp(&value),
+            "verified" => user.verified = Some(value.as_boolean().unwrap_or(false)),
+            "version" => user.version = value.as_int(),
+            "status" => {
+                user.status = Some(UserStatus::from_column(value.as_text().map(String::as_str)))
+            }
+            "deleted_at" => return None,
+            _ => {}
+        }
+    }
+    // As `UserRow::into_user` reads it, for users registered before
+    // verification existed.
+    if columns.contains(&"verified") && user.verified.is_none() {
+        user.verified = Some(false);
+    }
+    if columns.contains(&"status") && user.status.is_none() {
+        user.status = Some(UserStatus::Active);
+    }
+    Some(user)
+}
+
+// One page of a listing or search. `truncated` is set when the row cap served
+// fewer rows than the requested limit.
+pub struct Listing {
+    pub page: UsersPage,
+    pub truncated: bool,
+    pub tracing_ids: Vec<Uuid>,
+    // The `User` fields asked for, or `None` for all of them.
+    pub fields: Option<Vec<&'static str>>,
+}
+
+// CQL text and bind values for a listing restricted to the filters present
+// in `params` and reading only `columns` when given, or `None` for the
+// listing of every column with no filters. Filtering on anything but email
+// or a tag, which have indexes, scans the table server-side, a page at a
+// time, like the unfiltered listing.
+fn list_statement(
+    keyspace: &str,
+    params: &ListUsersQuery,
+    columns: Option<&[&'static str]>,
+) -> Option<(String, Vec<Option<CqlValue>>)> {
+    let mut select = cql::Select::new(statements::USER_COLUMNS, keyspace, "users");
+    if let Some(columns) = columns {
+        select.columns(columns);
+    }
+    if let Some(name) = &params.name {
+        select.filter("name", Op::Eq, CqlValue::Text(name.trim().to_string()));
+    }
+    if let Some(email) = &params.email {
+        select.filter("email", Op::Eq, CqlValue::Text(email.trim().to_string()));
+    }
+    let ranges = [
+        ("created_at", Op::Gt, params.created_after),
+        ("created_at", Op::Lt, params.created_before),
+        ("updated_at", Op::Gt, params.updated_after),
+        ("updated_at", Op::Lt, params.updated_before),
+    ];
+    for (column, op, bound) in ranges {
+        if let Some(bound) = bound {
+            select.filter(column, op, CqlValue::Timestamp(bound.into()));
+        }
+    }
+    if let Some(verified) = params.verified {
+        select.filter("verified", Op::Eq, CqlValue::Boolean(verified));
+    }
+    if let Some(tag) = &params.tag {
+        let tag = validation::normalize_tag(tag);
+        select.filter("tags", Op::Contains, CqlValue::Text(tag));
+    }
+
+    if !select.is_filtered() {
+        return columns.map(|_| select.build());
+    }
+    select.allow_filtering();
+    Some(select.build())
+}
+
+// The name a listing's statement is observed under.
+fn listing_statement_name(params: &ListUsersQuery) -> &'static str {
+    match is_filtered(params) {
+        true => "select_users_filtered",
+        false => "select_users_sparse",
+    }
+}
+
+// Whether `params` narrows the listing to some of the users.
+pub fn is_filtered(params: &ListUsersQuery) -> bool {
+    params.name.is_some()
+        || params.email.is_some()
+        || params.created_after.is_some()
+        || params.created_before.is_some()
+        || params.updated_after.is_some()
+        || params.updated_before.is_some()
+        || params.verified.is_some()
+        || params.tag.is_some()
+}
+
+// Orders one page by id, or case-insensitively by name or email with ties
+// broken by id, so the order is stable across requests. Pages are bounded by
+// `page_limit`, so this never sorts more than `max_page_size` users.
+pub fn sort_users(users: &mut [User], field: SortField, order: SortOrder) {
+    match field {
+        SortField::Id => users.sort_unstable_by_key(|user| user.id),
+        SortField::Name | SortField::Email => users.sort_by_cached_key(|user| {
+            let key = match field {
+                SortField::Email => &user.email,
+                _ => &user.name,
+            };
+            (key.to_lowercase(), user.id)
+        }),
+    }
+    if order == SortOrder::Desc {
+        users.reverse();
+    }
+}
+
+// Fields of `User` a listing can be narrowed to with `fields`.
+pub const USER_FIELDS: [&str; 10] = [
+    "id",
+    "name",
+    "email",
+    "phone",
+    "profile",
+    "created_at",
+    "updated_at",
+    "verified",
+    "version",
+    "status",
+];
+
+// `fields=id,name` as the `User` fields it names, in the order given and
+// without repeats.
+pub fn parse_fields(fields: &str) -> Result<Vec<&'static str>, ApiError> {
+    let mut selected = Vec::new();
+    for name in fields.split(',').map(str::trim) {
+        let Some(field) = USER_FIELDS.iter().find(|field| **field == name) else {
+            return Err(ApiError::BadRequest(format!(
+                "fields: unknown field \"{}\", expected some of {}",
+                name,
+                USER_FIELDS.join(", ")
+            )));
+        };
+        if !selected.contains(field) {
+            selected.push(*field);
+        }
+    }
+    Ok(selected)
+}
+
+// `user` narrowed to `fields`.
+pub fn project_user(user: &User, fields: &[&str]) -> serde_json::Value {
+    let mut value = serde_json::to_value(user).unwrap_or_default();
+    if let Some(object) = value.as_object_mut() {
+        object.retain(|key, _| fields.contains(&key.as_str()));
+    }
+    value
+}
+
+// `page` with each user narrowed to `fields`.
+pub fn project_page(page: &UsersPage, fields: &[&str]) -> serde_json::Value {
+    let users: Vec<serde_json::Value> =
+        page.users.iter().map(|user| project_user(user, fields)).collect();
+    serde_json::json!({ "users": users, "next_cursor": page.next_cursor })
+}
+
+// GET /users parameters checked together before anything is read: the
+// statement the filters and fields call for, the cursor, which must come
+// from the same filters and sort, and the fields to return along with the
+// columns read for them.
+struct ListingPlan {
+    statement: Option<(String, Vec<Option<CqlValue>>)>,
+    scope: CursorScope,
+    paging_state: PagingState,
+    fields: Option<Vec<&'static str>>,
+    columns: Option<Vec<&'static str>>,
+}
+
+fn plan_listing(
+    keyspace: &str,
+    params: &ListUsersQuery,
+    cursor_max_age: Duration,
+) -> Result<ListingPlan, ApiError> {
+    if params.order.is_some() && params.sort.is_none() {
+        return Err(ApiError::BadRequest(String::from("order requires sort")));
+    }
+    if let Some(tag) = &params.tag {
+        validation::tag(tag).map_err(|e| ApiError::BadRequest(format!("tag {}", e)))?;
+    }
+    let fields = params.fields.as_deref().map(parse_fields).transpose()?;
+    let columns = fields.as_deref().map(|fields| sparse_columns(fields, params.sort));
+
+    let statement = list_statement(keyspace, params, columns.as_deref());
+    let kind = match is_filtered(params) {
+        true => CursorKind::FilteredUsers,
+        false => CursorKind::Users,
+    };
+    let trimmed = |value: &Option<String>| value.as_deref().map(str::trim).map(String::from);
+    let scope = CursorScope::new(
+        kind,
+        &(
+            trimmed(&params.name),
+            trimmed(&params.email),
+            [
+                params.created_after,
+                params.created_before,
+                params.updated_after,
+                params.updated_before,
+            ],
+            params.verified,
+            params.tag.as_deref().map(validation::normalize_tag),
+            params.status,
+            params.sort,
+            params.sort.map(|_| params.order.unwrap_or_default()),
+        ),
+    );
+    let paging_state = paging::decode_cursor(params.cursor.as_deref(), &scope, cursor_max_age)?;
+    Ok(ListingPlan {
+        statement,
+        scope,
+        paging_state,
+        fields,
+        columns,
+    })
+}
+
+// One page of live users in storage order, filtered and sorted as `params`
+// asks.
+pub async fn list(
+    data: &AppState,
+    params: &ListUsersQuery,
+    tracing: bool,
+) -> Result<Listing, ApiError> {
+    let session = &data.session;
+
+    let (limit, truncated) = page_limit(data, params.limit)?;
+    if days::serves(params) {
+        return days::list(data, params, limit, truncated).await;
+    }
+    let ListingPlan {
+        statement,
+        scope,
+        paging_state,
+        fields,
+        columns,
+    } = plan_listing(&data.keyspace, params, data.cursor_max_age)?;
+
+    let (statement_name, prepared, values) = match statement {
+        Some((query, values)) => {
+            let prepared = data.statements.get_or_prepare(session, query).await?;
+            (listing_statement_name(params), prepared, values)
+        }
+        None => ("select_all_users", data.statements.select_all_users.clone(), Vec::new()),
+    };
+    let cached = !tracing && data.flags.enabled(Flag::UserCache);
+    let generation = match &data.shared_cache {
+        Some(shared) if cached => shared.listing_generation().await,
+        _ => None,
+    };
+    if let (Some(shared), Some(generation)) = (&data.shared_cache, generation) {
+        let cached = shared.listing(generation, params).await;
+        data.metrics.cache_lookup("redis_listings", cached.is_some());
+        if let Some((page, truncated)) = cached {
+            return Ok(Listing {
+                page,
+                truncated,
+                tracing_ids: Vec::new(),
+                fields,
+            });
+        }
+    }
+
+    let mut query = for_request(&prepared, tracing);
+    query.set_page_size(limit as i32);
+
+    let (result, paging_response) = observe::query(
+        data,
+        statement_name,
+        || session.execute_single_page(&query, &values, paging_state.clone()),
+    )
+    .await?;
+    let tracing_ids = result.tracing_id().into_iter().collect();
+
+    let status = params.status.unwrap_or_default();
+    let mut users = read_live_users(result, limit, columns.as_deref(), status)?;
+    if let Some(field) = params.sort {
+        sort_users(&mut users, field, params.order.unwrap_or_default());
+    }
+    tracing::debug!(count = users.len(), "listed users");
+
+    let page = UsersPage {
+        users,
+        next_cursor: paging::encode_cursor(&scope, paging_response),
+    };
+    if let (Some(shared), Some(generation)) = (&data.shared_cache, generation) {
+        shared.put_listing(generation, params, &page, truncated).await;
+    }
+    Ok(Listing {
+        page,
+        truncated,
+        tracing_ids,
+        fields,
+    })
+}
+
+// Every live user matching the filters of a listing, up to the row cap.
+pub struct UserStream {
+    pub users: LocalBoxStream<'static, Result<User, ApiError>>,
+    // The `User` fields asked for, or `None` for all of them.
+    pub fields: Option<Vec<&'static str>>,
+    // Whether the row cap cut the listing short.
+    pub truncated: bool,
+}
+
+// GET /users as one stream of every matching user instead of pages. Paging
+// and sorting don't apply, so `limit`, `cursor` and `sort` are refused. The
+// users are read a page at a time, but held back up to the row cap (see
+// `cap_rows`), so a listing past it is truncated or refused before anything
+// is sent.
+pub async fn stream(data: &AppState, params: &ListUsersQuery) -> Result<UserStream, ApiError> {
+    if params.limit.is_some() || params.cursor.is_some() || params.sort.is_some() {
+        return Err(ApiError::BadRequest(String::from(
+            "limit, cursor and sort don't apply to a streamed listing",
+        )));
+    }
+    let ListingPlan {
+        statement,
+        fields,
+        columns,
+        ..
+    } = plan_listing(&data.keyspace, params, data.cursor_max_age)?;
+
+    let (statement_name, prepared, values) = match statement {
+        Some((query, values)) => {
+            let prepared = data.statements.get_or_prepare(&data.session, query).await?;
+            (listing_statement_name(params), prepared, values)
+        }
+        None => ("select_all_users", data.statements.select_all_users.clone(), Vec::new()),
+    };
+    let pager = observe::query(data, statement_name, || {
+        data.session.execute_iter(prepared.clone(), values.clone())
+    })
+    .await?;
+    let status = params.status.unwrap_or_default();
+    let users = match columns {
+        Some(columns) => pager
+            .rows_stream::<Row>()
+            .map_err(|e| ApiError::internal("Error streaming users", e))?
+            .map_err(|e| ApiError::internal("Error fetching users", e))
+            .try_filter_map(move |row| future::ready(Ok(sparse_user(&columns, row))))
+            .try_filter(move |user| future::ready(admitted(status, user)))
+            .boxed_local(),
+        None => pager
+            .rows_stream::<UserRow>()
+            .map_err(|e| ApiError::internal("Error streaming users", e))?
+            .map_err(|e| ApiError::internal("Error fetching users", e))
+            .try_filter(|row| future::ready(!row.is_deleted()))
+            .map_ok(UserRow::into_user)
+            .try_filter(move |user| future::ready(admitted(status, user)))
+            .boxed_local(),
+    };
+    let (users, truncated) = cap_rows(data.max_rows_per_request, data.row_cap_mode, users).await?;
+    Ok(UserStream {
+        users: futures::stream::iter(users.into_iter().map(Ok)).boxed_local(),
+        fields,
+        truncated,
+    })
+}
+
+// One page of users whose name starts with `params.name_prefix`, by name.
+pub async fn search(
+    data: &AppState,
+    params: &SearchUsersQuery,
+    tracing: bool,
+) -> Result<Listing, ApiError> {
+    let prefix = search::normalize(params.name_prefix.as_deref().unwrap_or_default());
+    if prefix.is_empty() {
+        return Err(ApiError::BadRequest(String::from("name_prefix must not be empty")));
+    }
+    let (limit, truncated) = page_limit(data, params.limit)?;
+
+    let scope = CursorScope::new(CursorKind::UserSearch, &prefix);
+    let paging_state =
+        paging::decode_cursor(params.cursor.as_deref(), &scope, data.cursor_max_age)?;
+
+    let mut query = for_request(&data.statements.search_users_by_name, tracing);
+    query.set_page_size(limit as i32);
+    let values = search::search_values(&prefix);
+
+    let (result, paging_response) = observe::query(
+        data,
+        "search_users_by_name",
+        || data.session.execute_single_page(&query, &values, paging_state.clone()),
+    )
+    .await?;
+    let tracing_ids = result.tracing_id().into_iter().collect();
+
+    let users = read_users(result, limit)?;
+    tracing::debug!(count = users.len(), "searched users");
+
+    Ok(Listing {
+        page: UsersPage {
+            users,
+            next_cursor: paging::encode_cursor(&scope, paging_response),
+        },
+        truncated,
+        tracing_ids,
+        fields: None,
+    })
+}
+
+// One page of users matching `q` in `index`, best match first. Cursors hold
+// the offset of the next page, so a page may repeat or skip a user whose
+// position changed as the index did.
+pub async fn full_text_search(
+    data: &AppState,
+    index: &SearchIndex,
+    q: &str,
+    params: &SearchUsersQuery,
+) -> Result<Listing, ApiError> {
+    let q = q.trim();
+    if q.is_empty() {
+        return Err(ApiError::BadRequest(String::from("q must not be empty")));
+    }
+    let (limit, truncated) = page_limit(data, params.limit)?;
+
+    let scope = CursorScope::new(CursorKind::FullTextSearch, &q);
+    let offset = match paging::decode_key(params.cursor.as_deref(), &scope, data.cursor_max_age)? {
+        Some(key) => <[u8; 8]>::try_from(key.as_slice())
+            .map(u64::from_be_bytes)
+            .map_err(|_| ApiError::BadRequest(String::from("Invalid cursor")))?,
+        None => 0,
+    };
+    let hits = index
+        .query(q, offset as usize, limit)
+        .await
+        .map_err(|e| ApiError::internal("Search index failed", e))?;
+    let next = offset + limit as u64;
+    let next_cursor = (!hits.ids.is_empty() && next < hits.total)
+        .then(|| paging::encode_key(&scope, &next.to_be_bytes()));
+
+    let reads = hits.ids.iter().map(|id| stored_user(data, *id));
+    let users: Vec<User> = future::try_join_all(reads).await?.into_iter().flatten().collect();
+    tracing::debug!(count = users.len(), "searched users by text");
+
+    Ok(Listing {
+        page: UsersPage { users, next_cursor },
+        truncated,
+        tracing_ids: Vec::new(),
+        fields: None,
+    })
+}
+
+// Reads one user row with `statement`, which must select
+// `statements::USER_COLUMNS`, along with whether it is soft-deleted.
+async fn fetch_row(
+    data: &AppState,
+    statement_name: &'static str,
+    statement: &PreparedStatement,
+    key: CqlValue,
+) -> Result<Option<(User, bool)>, ApiError> {
+    let result = observe::query(data, statement_name, || {
+        data.session.execute_unpaged(statement, (&key,))
+    })
+    .await?;
+    let row = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading rows", e))?
+        .maybe_first_row::<UserRow>()
+        .map_err(|e| ApiError::internal("Error reading rows", e))?;
+    Ok(row.map(|row| {
+        let deleted = row.is_deleted();
+        (row.into_user(), deleted)
+    }))
+}
+
+// Like `fetch_row`, but soft-deleted users are not found.
+async fn fetch_user(
+    data: &AppState,
+    statement_name: &'static str,
+    statement: &PreparedStatement,
+    key: CqlValue,
+) -> Result<Option<User>, ApiError> {
+    let row = fetch_row(data, statement_name, statement, key).await?;
+    Ok(row.and_then(|(user, deleted)| (!deleted).then_some(user)))
+}
+
+// The user as stored before a change, so the email claim and the name index
+// can be moved along with it.
+pub async fn stored_user(data: &AppState, user_id: Uuid) -> Result<Option<User>, ApiError> {
+    let row = stored_row(data, user_id).await?;
+    Ok(row.and_then(|(user, deleted)| (!deleted).then_some(user)))
+}
+
+// The user with `user_id` even when it is soft-deleted, along with whether
+// it is.
+pub async fn stored_row(data: &AppState, user_id: Uuid) -> Result<Option<(User, bool)>, ApiError> {
+    Ok(data.users.get(user_id, false).await?.0)
+}
+
+// Reads of a user by id, with whether it is soft-deleted, that concurrent
+// `get`s share.
+pub type UserReads = Coalescer<Uuid, Result<Option<(User, bool)>, ApiError>>;
+
+// Drops `user_id` from the caches after a write to it, along with every
+// listing page cached in Redis.
+pub async fn forget_cached(data: &AppState, user_id: Uuid) {
+    data.user_reads.forget(&user_id);
+    data.user_cache.invalidate(user_id);
+    if let Some(shared) = &data.shared_cache {
+        shared.invalidate(user_id).await;
+    }
+}
+
+// The live user with `user_id`.
+pub async fn get(
+    data: &AppState,
+    user_id: Uuid,
+    tracing: bool,
+) -> Result<(Option<User>, Vec<Uuid>), ApiError> {
+    let cache_version = data.user_cache.version();
+    // A traced lookup always reads the row, as the trace is what was asked for.
+    let cached = !tracing && data.flags.enabled(Flag::UserCache);
+    if cached && data.user_cache.enabled() {
+        let cached = data.user_cache.get(user_id);
+        data.metrics.cache_lookup("memory", cached.is_some());
+        if cached.is_some() {
+            return Ok((cached, Vec::new()));
+        }
+    }
+    if cached && let Some(shared) = &data.shared_cache {
+        let found = shared.user(user_id).await;
+        data.metrics.cache_lookup("redis_users", found.is_some());
+        if let Some(user) = found {
+            data.user_cache.insert(cache_version, user.clone());
+            return Ok((Some(user), Vec::new()));
+        }
+    }
+    let (row, tracing_ids) = if tracing || !data.flags.enabled(Flag::CoalesceReads) {
+        data.users.get(user_id, tracing).await?
+    } else {
+        // Concurrent misses for the same user share one read; see `coalesce`.
+        let users = data.users.clone();
+        let read = || async move { users.get(user_id, false).await.map(|(row, _)| row) };
+        let (row, joined) = data.user_reads.run(user_id, read).await;
+        data.metrics.cache_lookup("in_flight", joined);
+        (row?, Vec::new())
+    };
+    let user = row.and_then(|(user, deleted)| (!deleted).then_some(user));
+    if let Some(user) = &user
+        && data.flags.enabled(Flag::UserCache)
+    {
+        data.user_cache.insert(cache_version, user.clone());
+        if let Some(shared) = &data.shared_cache {
+            shared.put_user(user).await;
+        }
+    }
+    Ok((user, tracing_ids))
+}
+
+// What HEAD /users/{id} learns of a live user: the user itself when the
+// in-process cache holds it, and when it last changed.
+pub struct Existing {
+    pub cached: Option<User>,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+// Whether the live user with `user_id` exists. A cached user answers at once;
+// otherwise only its timestamps are read, not the whole row.
+pub async fn exists(data: &AppState, user_id: Uuid) -> Result<Option<Existing>, ApiError> {
+    if data.user_cache.enabled() && data.flags.enabled(Flag::UserCache) {
+        let cached = data.user_cache.get(user_id);
+        data.metrics.cache_lookup("memory", cached.is_some());
+        if let Some(user) = cached {
+            return Ok(Some(Existing {
+                modified: user.updated_at.or(user.created_at),
+                cached: Some(user),
+            }));
+        }
+    }
+    let (modified, _) = data.users.modified(user_id).await?;
+    Ok(modified.map(|modified| Existing { cached: None, modified }))
+}
+
+// The live user registered with `email`, matched case-insensitively.
+pub async fn by_email(data: &AppState, email: &str) -> Result<Option<User>, ApiError> {
+    let email = email.trim();
+
+    // Users registered before `users_by_email` existed have no claim row;
+    // they are still found through the secondary index, with an exact match.
+    match emails::owner(data, email).await? {
+        Some(user_id) => stored_user(data, user_id).await,
+        None => {
+            fetch_user(
+                data,
+                "select_user_by_email",
+                &data.statements.select_user_by_email,
+                CqlValue::Text(email.to_string()),
+            )
+            .await
+        }
+    }
+}
+
+// The live user registered with `phone`, in any form registration accepts.
+pub async fn by_phone(data: &AppState, phone: &str) -> Result<Option<User>, ApiError> {
+    let phone = validation::phone(phone)
+        .map_err(|message| ApiError::BadRequest(format!("phone {}", message)))?;
+    match phones::owner(data, &phone).await? {
+        Some(user_id) => stored_user(data, user_id).await,
+        None => Ok(None),
+    }
+}
+
+// Claims the email and, if it has one, the phone number of a user about to be
+// stored, releasing the email again when the number is taken.
+pub async fn claim_contacts(data: &AppState, user: &User) -> Result<(), ApiError> {
+    emails::claim(data, &user.email, user.id, user.expires_at).await?;
+    if let Some(phone) = &user.phone
+        && let Err(e) = phones::claim(data, phone, user.id, user.expires_at).await
+    {
+        emails::release(data, &user.email, user.id).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Releases what `claim_contacts` claimed for `user`.
+pub async fn release_contacts(data: &AppState, user: &User) {
+    release_claims(data, user.id, Some(&user.email), user.phone.as_deref()).await;
+}
+
+// Releases the claims `user_id` holds on `email` and `phone`, where given.
+async fn release_claims(data: &AppState, user_id: Uuid, email: Option<&str>, phone: Option<&str>) {
+    if let Some(email) = email {
+        emails::release(data, email, user_id).await;
+    }
+    if let Some(phone) = phone {
+        phones::release(data, phone, user_id).await;
+    }
+}
+
+// A new user, validated and with its password hashed, ready to store.
+pub struct Registration {
+    pub user: User,
+    pub password_hash: Option<String>,
+}
+
+async fn registration(data: &AppState, new_user: NewUser) -> Result<Registration, ApiError> {
+    let new_user = validation::new_user(new_user)?;
+    data.validation.enforce(&new_user.email)?;
+
+    let password_hash = match new_user.password {
+        Some(password) => Some(
+            login::hash_password_blocking(password)
+                .await
+                .map_err(|e| ApiError::internal("Failed to hash password", e))?,
+        ),
+        None => None,
+    };
+
+    let now = Utc::now();
+    let user = User {
+        id: Uuid::new_v4(),
+        name: new_user.name,
+        email: new_user.email,
+        phone: new_user.phone,
+        profile: new_user.profile,
+        created_at: Some(now),
+        updated_at: Some(now),
+        expires_at: expiry(data, new_user.expires_in_seconds, now),
+        verified: Some(false),
+        version: Some(1),
+        status: Some(UserStatus::Active),
+        anonymized_at: None,
+    };
+    Ok(Registration { user, password_hash })
+}
+
+// What follows storing a new user: indexing, verification, its first event
+// and its audit entry.
+pub async fn registered(data: &AppState, user: &User) {
+    search::index(data, user).await;
+    days::index(data, user).await;
+    forget_cached(data, user.id).await;
+    verification::issue(data, user).await;
+    history::append(data, EventKind::Created, user.id, Some(user.clone())).await;
+    audit::record(data, user.id, Action::Create, None, Some(user)).await;
+}
+
+// Validates, hashes and stores one new user, claiming its email and phone
+// number first, and returns it as stored. Shared by single, bulk and
+// imported registrations. A dry run returns the user it would have stored.
+pub async fn register(
+    data: &AppState,
+    new_user: NewUser,
+    tracing: bool,
+) -> Result<(User, Vec<Uuid>), ApiError> {
+    let Registration { user, password_hash } = registration(data, new_user).await?;
+    claim_contacts(data, &user).await?;
+    if dry_run::active() {
+        return Ok((user, Vec::new()));
+    }
+    match data.users.insert(&user, password_hash.as_deref(), tracing).await {
+        Ok(((), tracing_ids)) => {
+            registered(data, &user).await;
+            Ok((user, tracing_ids))
+        }
+        Err(e) => {
+            release_contacts(data, &user).await;
+            Err(e)
+        }
+    }
+}
+
+// Like `register`, but leaves storing the user to `queue`, returning its id
+// once the email and phone number are claimed. The queue is reserved first, so a full one
+// turns the registration away before anything is claimed.
+pub async fn register_behind(
+    data: &AppState,
+    queue: &write_behind::Queue,
+    new_user: NewUser,
+) -> Result<Uuid, ApiError> {
+    let registration = registration(data, new_user).await?;
+    let slot = queue.reserve()?;
+    let user = &registration.user;
+    claim_contacts(data, user).await?;
+    let id = user.id;
+    slot.send(registration);
+    Ok(id)
+}
+
+// `profile` as a value of the `profile` user-defined type in `keyspace`.
+// Fields it lacks are bound as null.
+pub fn profile_value(keyspace: &str, profile: &Profile) -> CqlValue {
+    let field = |name: &str, value: &Option<String>| {
+        (name.to_string(), value.clone().map(CqlValue::Text))
+    };
+    CqlValue::UserDefinedType {
+        keyspace: keyspace.to_string(),
+        type_name: String::from("profile"),
+        fields: vec![
+            field("bio", &profile.bio),
+            field("avatar_url", &profile.avatar_url),
+            field("locale", &profile.locale),
+            field("timezone", &profile.timezone),
+        ],
+    }
+}
+
+// CQL text and bind values for an UPDATE setting the fields present in
+// `update`, profile fields one by one, and bumping `updated_at` and
+// `version`, from `before`, under `condition` (`Condition::None` in a
+// batch). The cells keep the user's `expires_at`.
+pub fn update_statement(
+    keyspace: &str,
+    update: &UpdateUser,
+    before: &User,
+    updated_at: DateTime<Utc>,
+    condition: Condition,
+) -> (String, Vec<Option<CqlValue>>) {
+    let (user_id, expires_at) = (before.id, before.expires_at);
+    let mut statement = cql::Update::new(keyspace, "users");
+    statement.ttl(ttl(expires_at));
+    if let Some(name) = &update.name {
+        statement.set("name", CqlValue::Text(name.clone()));
+    }
+    if let Some(email) = &update.email {
+        statement.set("email", CqlValue::Text(email.clone()));
+    }
+    if let Some(phone) = &update.phone {
+        statement.set("phone", CqlValue::Text(phone.clone()));
+    }
+    if let Some(profile) = &update.profile {
+        let fields = [
+            ("profile.bio", &profile.bio),
+            ("profile.avatar_url", &profile.avatar_url),
+            ("profile.locale", &profile.locale),
+            ("profile.timezone", &profile.timezone),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                statement.set(field, CqlValue::Text(value.clone()));
+            }
+        }
+    }
+    statement
+        .set("updated_at", CqlValue::Timestamp(updated_at.into()))
+        .set("version", CqlValue::Int(next_version(before)))
+        .key("id", CqlValue::Uuid(user_id))
+        .condition(condition);
+    statement.build()
+}
+
+// `before` with `update` applied, as stored after an update at `updated_at`.
+pub fn apply_update(before: &User, update: &UpdateUser, updated_at: DateTime<Utc>) -> User {
+    User {
+        id: before.id,
+        name: update.name.clone().unwrap_or_else(|| before.name.clone()),
+        email: update.email.clone().unwrap_or_else(|| before.email.clone()),
+        phone: update.phone.clone().or_else(|| before.phone.clone()),
+        profile: match &update.profile {
+            None => before.profile.clone(),
+            Some(changes) => {
+                let before = before.profile.clone().unwrap_or_default();
+                Some(Profile {
+                    bio: changes.bio.clone().or(before.bio),
+                    avatar_url: changes.avatar_url.clone().or(before.avatar_url),
+                    locale: changes.locale.clone().or(before.locale),
+                    timezone: changes.timezone.clone().or(before.timezone),
+                })
+            }
+        },
+        created_at: before.created_at,
+        updated_at: Some(updated_at),
+        expires_at: before.expires_at,
+        verified: before.verified,
+        version: Some(next_version(before)),
+        status: before.status,
+        anonymized_at: None,
+    }
+}
+
+// The version a change moves `before` to. Users last written before
+// versions were recorded have none, and start again at 1.
+pub fn next_version(before: &User) -> i32 {
+    before.version.map_or(1, |version| version + 1)
+}
+
+// A token for the stored state of `user`, which changes whenever anything
+// about it does: the ETag of its REST representation, and what `if_match`
+// on a write is compared with. `expires_at` is left out: it is worked out
+// from the TTL left at each read, so it can differ by a second between two
+// reads of the same row.
+pub fn version(user: &User) -> String {
+    let user = User {
+        expires_at: None,
+        ..user.clone()
+    };
+    let json = serde_json::to_vec(&user).unwrap_or_default();
+    Sha256::digest(json)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn changed(user_id: Uuid) -> ApiError {
+    ApiError::PreconditionFailed(format!("User with ID {} has changed", user_id))
+}
+
+// Fails unless `before` has one of the `if_match` versions; `None` accepts
+// any.
+fn check_version(before: &User, if_match: Option<&[String]>) -> Result<(), ApiError> {
+    match if_match {
+        Some(versions) if !versions.contains(&version(before)) => Err(changed(before.id)),
+        _ => Ok(()),
+    }
+}
+
+fn moved_on(user_id: Uuid, expected: i32) -> ApiError {
+    ApiError::Conflict(format!(
+        "User with ID {} is no longer at version {}; read it again and retry",
+        user_id, expected
+    ))
+}
+
+// Fails with 409 unless `before` is at the `expected` version a client sent
+// with its write; `None` accepts any. Also used by `batch`.
+pub fn check_expected(before: &User, expected: Option<i32>) -> Result<(), ApiError> {
+    match expected {
+        Some(expected) if before.version != Some(expected) => Err(moved_on(before.id, expected)),
+        _ => Ok(()),
+    }
+}
+
+// In the strict `http.concurrency_mode`, a PUT or PATCH of an existing user
+// has to say which state it expects, with `If-Match` or a `version`.
+pub fn require_precondition(
+    mode: ConcurrencyMode,
+    user_id: Uuid,
+    if_match: Option<&[String]>,
+    expected: Option<i32>,
+) -> Result<(), ApiError> {
+    if mode == ConcurrencyMode::Strict && if_match.is_none() && expected.is_none() {
+        return Err(ApiError::PreconditionRequired(format!(
+            "Changing user {} needs If-Match or the user's version",
+            user_id
+        )));
+    }
+    Ok(())
+}
+
+// What a write that found the row changed since `before` was read fails
+// with: 409 for a `version` from the body, 412 for `If-Match`.
+fn lost_race(user_id: Uuid, expected: Option<i32>) -> ApiError {
+    match expected {
+        Some(expected) => moved_on(user_id, expected),
+        None => changed(user_id),
+    }
+}
+
+// Applies `update` to a live user, returning the user as stored afterwards.
+// With `if_match`, the user must still have one of those versions, and with
+// `update.version` be at that version.
+pub async fn update(
+    data: &AppState,
+    user_id: Uuid,
+    update: UpdateUser,
+    if_match: Option<&[String]>,
+    tracing: bool,
+) -> Result<(User, Vec<Uuid>), ApiError> {
+    let update = validation::update_user(update)?;
+    if let Some(email) = &update.email {
+        data.validation.enforce(email)?;
+    }
+
+    let not_found = || ApiError::NotFound(format!("User with ID {} not found", user_id));
+    let before = stored_user(data, user_id).await?.ok_or_else(not_found)?;
+    check_version(&before, if_match)?;
+    check_expected(&before, update.version)?;
+    let conditional = if_match.is_some() || update.version.is_some();
+    let expect = if conditional { Expect::Unchanged(&before) } else { Expect::Exists };
+
+    // A new email or phone number is claimed before the row changes and the
+    // old one released after, so at no point can another user register
+    // either of them.
+    let mut email_change = None;
+    if let Some(email) = &update.email
+        && !before.email.eq_ignore_ascii_case(email)
+    {
+        emails::claim(data, email, user_id, before.expires_at).await?;
+        email_change = Some(email.as_str());
+    }
+    let mut phone_change = None;
+    if let Some(phone) = &update.phone
+        && before.phone.as_ref() != Some(phone)
+    {
+        if let Err(e) = phones::claim(data, phone, user_id, before.expires_at).await {
+            release_claims(data, user_id, email_change, None).await;
+            return Err(e);
+        }
+        phone_change = Some(phone.as_str());
+    }
+    let now = Utc::now();
+    if dry_run::active() {
+        return Ok((apply_update(&before, &update, now), Vec::new()));
+    }
+    let written = data
+        .users
+        .update(&before, &update, now, expect, tracing)
+        .await;
+    let (applied, tracing_ids) = match written {
+        Ok(result) => result,
+        Err(e) => {
+            release_claims(data, user_id, email_change, phone_change).await;
+            return Err(e);
+        }
+    };
+    if !applied {
+        release_claims(data, user_id, email_change, phone_change).await;
+        return Err(if conditional { lost_race(user_id, update.version) } else { not_found() });
+    }
+    release_claims(
+        data,
+        user_id,
+        email_change.and(Some(before.email.as_str())),
+        phone_change.and(before.phone.as_deref()),
+    )
+    .await;
+    let after = apply_update(&before, &update, now);
+    search::reindex(data, &before, &after).await;
+    forget_cached(data, user_id).await;
+    history::append(data, EventKind::Updated, user_id, Some(after.clone())).await;
+    audit::record(data, user_id, Action::Update, Some(&before), Some(&after)).await;
+    Ok((after, tracing_ids))
+}
+
+// Replaces every field of the user with `user_id` but its creation time,
+// or, when it doesn't exist and `may_create`, creates it with that id,
+// reporting which it did. A soft-deleted user is neither: it has to be
+// restored first. With `if_match`, the user must exist and still have one of
+// those versions, and with `replacement.version` be at that version; in the
+// strict `concurrency_mode`, replacing a user needs one of them.
+pub async fn replace(
+    data: &AppState,
+    user_id: Uuid,
+    replacement: ReplaceUser,
+    if_match: Option<&[String]>,
+    may_create: bool,
+    tracing: bool,
+) -> Result<(User, bool, Vec<Uuid>), ApiError> {
+    let expected = replacement.version;
+    let replacement = validation::new_user(NewUser {
+        name: replacement.name,
+        email: replacement.email,
+        password: None,
+        phone: replacement.phone,
+        profile: replacement.profile,
+        expires_in_seconds: None,
+    })?;
+    data.validation.enforce(&replacement.email)?;
+
+    let not_found = || ApiError::NotFound(format!("User with ID {} not found", user_id));
+    let now = Utc::now();
+    let before = match stored_row(data, user_id).await? {
+        Some((_, true)) => {
+            return Err(ApiError::Conflict(format!(
+                "User with ID {} is deleted; restore it before replacing it",
+                user_id
+            )));
+        }
+        Some((before, false)) => before,
+        None if may_create && if_match.is_none() && expected.is_none() => {
+            let user = User {
+                id: user_id,
+                name: replacement.name,
+                email: replacement.email,
+                phone: replacement.phone,
+                profile: replacement.profile,
+                created_at: Some(now),
+                updated_at: Some(now),
+                expires_at: None,
+                verified: Some(false),
+                version: Some(1),
+                status: Some(UserStatus::Active),
+                anonymized_at: None,
+            };
+            claim_contacts(data, &user).await?;
+            if dry_run::active() {
+                return Ok((user, true, Vec::new()));
+            }
+            let tracing_ids = match data.users.insert(&user, None, tracing).await {
+                Ok(((), tracing_ids)) => tracing_ids,
+                Err(e) => {
+                    release_contacts(data, &user).await;
+                    return Err(e);
+                }
+            };
+            search::index(data, &user).await;
+            days::index(data, &user).await;
+            forget_cached(data, user_id).await;
+            verification::issue(data, &user).await;
+            history::append(data, EventKind::Created, user_id, Some(user.clone())).await;
+            audit::record(data, user_id, Action::Create, None, Some(&user)).await;
+            return Ok((user, true, tracing_ids));
+        }
+        None => return Err(not_found()),
+    };
+    require_precondition(data.concurrency_mode, user_id, if_match, expected)?;
+    check_version(&before, if_match)?;
+    check_expected(&before, expected)?;
+    let conditional = if_match.is_some() || expected.is_some();
+    let expect = if conditional { Expect::Unchanged(&before) } else { Expect::Exists };
+
+    let after = User {
+        id: user_id,
+        name: replacement.name,
+        email: replacement.email,
+        phone: replacement.phone,
+        profile: replacement.profile,
+        created_at: before.created_at,
+        updated_at: Some(now),
+        expires_at: before.expires_at,
+        verified: before.verified,
+        version: Some(next_version(&before)),
+        status: before.status,
+        anonymized_at: None,
+    };
+    let email_change = !before.email.eq_ignore_ascii_case(&after.email);
+    let phone_change = before.phone != after.phone;
+    let new_email = Some(after.email.as_str()).filter(|_| email_change);
+    let new_phone = after.phone.as_deref().filter(|_| phone_change);
+    if let Some(email) = new_email {
+        emails::claim(data, email, user_id, after.expires_at).await?;
+    }
+    if let Some(phone) = new_phone
+        && let Err(e) = phones::claim(data, phone, user_id, after.expires_at).await
+    {
+        release_claims(data, user_id, new_email, None).await;
+        return Err(e);
+    }
+    if dry_run::active() {
+        return Ok((after, false, Vec::new()));
+    }
+    let (applied, tracing_ids) = match data.users.replace(&after, expect, tracing).await {
+        Ok(result) => result,
+        Err(e) => {
+            release_claims(data, user_id, new_email, new_phone).await;
+            return Err(e);
+        }
+    };
+    if !applied {
+        release_claims(data, user_id, new_email, new_phone).await;
+        return Err(if conditional { lost_race(user_id, expected) } else { not_found() });
+    }
+    release_claims(
+        data,
+        user_id,
+        Some(before.email.as_str()).filter(|_| email_change),
+        before.phone.as_deref().filter(|_| phone_change),
+    )
+    .await;
+    search::reindex(data, &before, &after).await;
+    forget_cached(data, user_id).await;
+    history::append(data, EventKind::Updated, user_id, Some(after.clone())).await;
+    audit::record(data, user_id, Action::Replace, Some(&before), Some(&after)).await;
+    Ok((after, false, tracing_ids))
+}
+
+// Deletes a user. A soft delete keeps the row and its email and phone claims,
+// so a restore brings the user back as they were; only the name index row
+// goes. A
+// hard delete also purges users that were already soft-deleted. With
+// `if_match`, the user must still have one of those versions.
+pub async fn delete(
+    data: &AppState,
+    user_id: Uuid,
+    hard: bool,
+    if_match: Option<&[String]>,
+    tracing: bool,
+) -> Result<Vec<Uuid>, ApiError> {
+    let not_found = || ApiError::NotFound(format!("User with ID {} not found", user_id));
+
+    let before = stored_row(data, user_id).await?;
+    if !hard && !matches!(before, Some((_, false))) {
+        return Err(not_found());
+    }
+    let expect = match (&before, if_match) {
+        (Some((before, _)), Some(_)) => {
+            check_version(before, if_match)?;
+            Expect::Unchanged(before)
+        }
+        (None, Some(_)) => return Err(not_found()),
+        (_, None) => Expect::Exists,
+    };
+    if dry_run::active() {
+        return Ok(Vec::new());
+    }
+    let (applied, tracing_ids) = if hard {
+        data.users.delete(user_id, expect, tracing).await?
+    } else {
+        let expires_at = before.as_ref().and_then(|(before, _)| before.expires_at);
+        data.users.soft_delete(user_id, Utc::now(), expires_at, expect, tracing).await?
+    };
+    if !applied {
+        return Err(match expect {
+            Expect::Unchanged(_) => changed(user_id),
+            Expect::Exists => not_found(),
+        });
+    }
+    if let Some((before, _)) = &before {
+        if hard {
+            release_contacts(data, before).await;
+            avatars::remove(data, user_id).await;
+            stats::remove(data, user_id).await;
+            groups::remove(data, user_id).await;
+            posts::remove(data, user_id).await;
+            days::unindex(data, before).await;
+        }
+        search::unindex(data, before).await;
+    }
+    forget_cached(data, user_id).await;
+    history::append(data, EventKind::Deleted, user_id, None).await;
+    let action = if hard { Action::Purge } else { Action::Delete };
+    let before = before.as_ref().filter(|(_, deleted)| !deleted).map(|(user, _)| user);
+    audit::record(data, user_id, action, before, None).await;
+    Ok(tracing_ids)
+}
+
+// Brings back a soft-deleted user, returning it as stored.
+pub async fn restore(
+    data: &AppState,
+    user_id: Uuid,
+    tracing: bool,
+) -> Result<(User, Vec<Uuid>), ApiError> {
+    let not_found = || ApiError::NotFound(format!("No deleted user with ID {}", user_id));
+
+    let Some((mut user, true)) = stored_row(data, user_id).await? else {
+        return Err(not_found());
+    };
+    let now = Utc::now();
+    user.updated_at = Some(now);
+    if dry_run::active() {
+        return Ok((user, Vec::new()));
+    }
+    let (applied, tracing_ids) = data.users.restore(user_id, now, user.expires_at, tracing).await?;
+    if !applied {
+        return Err(not_found());
+    }
+    search::index(data, &user).await;
+    forget_cached(data, user_id).await;
+    history::append(data, EventKind::Restored, user_id, Some(user.clone())).await;
+    audit::record(data, user_id, Action::Restore, None, Some(&user)).await;
+    Ok((user, tracing_ids))
+}
+
+// The domain of the placeholder email of an anonymized user; `.invalid` is
+// reserved, so it can't reach anyone.
+const ANONYMIZED_DOMAIN: &str = "anonymized.invalid";
+
+// `before` with its personal data erased at `at`, or `None` when it already
+// has been: the name and email become `deleted-<id>` placeholders and the
+// phone and profile go, along with what `User` doesn't hold (the password
+// hash, addresses and metadata; see `UserRepository::anonymize`).
+pub fn anonymized(before: &User, at: DateTime<Utc>) -> Option<User> {
+    if before.anonymized_at.is_some() {
+        return None;
+    }
+    let placeholder = format!("deleted-{}", before.id);
+    Some(User {
+        email: format!("{}@{}", placeholder, ANONYMIZED_DOMAIN),
+        name: placeholder,
+        phone: None,
+        profile: None,
+        updated_at: Some(at),
+        version: Some(next_version(before)),
+        anonymized_at: Some(at),
+        ..before.clone()
+    })
+}
+
+// Erases the personal data of a user, live or soft-deleted, for an erasure
+// request, returning it as stored. The row stays under its id, so the user's
+// posts, groups and audit log still resolve; the email and phone claims are
+// released for others to register, the avatar goes and the sessions end.
+// Anonymizing a user again returns it unchanged. The events and audit
+// entries recorded before are left as they are.
+pub async fn anonymize(data: &AppState, user_id: Uuid) -> Result<User, ApiError> {
+    let not_found = || ApiError::NotFound(format!("User with ID {} not found", user_id));
+
+    let (before, deleted) = stored_row(data, user_id).await?.ok_or_else(not_found)?;
+    let Some(after) = anonymized(&before, Utc::now()) else {
+        return Ok(before);
+    };
+    if dry_run::active() {
+        return Ok(after);
+    }
+    let (applied, _) = data.users.anonymize(&after, Expect::Unchanged(&before)).await?;
+    if !applied {
+        // A concurrent anonymization did what this one would have.
+        return match stored_row(data, user_id).await? {
+            Some((user, _)) if user.anonymized_at.is_some() => Ok(user),
+            Some(_) => Err(changed(user_id)),
+            None => Err(not_found()),
+        };
+    }
+    release_contacts(data, &before).await;
+    avatars::remove(data, user_id).await;
+    if let Err(e) = sessions::revoke_all(data, user_id).await {
+        tracing::warn!(%user_id, error = %e, "failed to end sessions");
+    }
+    if !deleted {
+        search::reindex(data, &before, &after).await;
+    }
+    forget_cached(data, user_id).await;
+    history::append(data, EventKind::Updated, user_id, Some(after.clone())).await;
+    audit::record(data, user_id, Action::Anonymize, None, Some(&after)).await;
+    Ok(after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{MemoryUsers, UserRepository};
+    use scylla::frame::value::CqlTimestamp;
+    use scylla::statement::PagingStateResponse;
+
+    const MAX_AGE: Duration = Duration::from_secs(3_600);
+
+    fn user(name: &str, email: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            email: email.to_string(),
+            phone: None,
+            profile: Some(Profile {
+                bio: Some(String::from("Analyst")),
+                locale: Some(String::from("en-GB")),
+                ..Profile::default()
+            }),
+            created_at: Some(Utc::now()),
+            updated_at: Some(Utc::now()),
+            expires_at: None,
+            verified: None,
+            version: None,
+            status: None,
+            anonymized_at: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn anonymizing_erases_the_personal_data_once() {
+        let store = MemoryUsers::default();
+        let mut ada = user("Ada Lovelace", "ada@example.com");
+        ada.phone = Some(String::from("+14155550123"));
+        ada.version = Some(2);
+        store.insert(&ada, Some("hash"), false).await.unwrap();
+
+        let at = Utc::now();
+        let erased = anonymized(&ada, at).unwrap();
+        assert!(store.anonymize(&erased, Expect::Unchanged(&ada)).await.unwrap().0);
+        let (stored, _) = store.get(ada.id, false).await.unwrap().0.unwrap();
+        let placeholder = format!("deleted-{}", ada.id);
+        assert_eq!(stored.name, placeholder);
+        assert_eq!(stored.email, format!("{}@anonymized.invalid", placeholder));
+        assert!(stored.phone.is_none() && stored.profile.is_none());
+        assert!(store.password_hash(ada.id).await.unwrap().0.is_none());
+        assert_eq!((stored.anonymized_at, stored.version), (Some(at), Some(3)));
+        assert_eq!((stored.id, stored.created_at), (ada.id, ada.created_at));
+        let body = serde_json::to_string(&stored).unwrap();
+        for personal in ["Lovelace", "ada@example.com", "+14155550123", "Analyst"] {
+            assert!(!body.contains(personal), "{} left in {}", personal, body);
+        }
+
+        // Asking again finds nothing left to erase, and a write made from the
+        // stale read would not apply.
+        assert!(anonymized(&stored, Utc::now()).is_none());
+        assert!(!store.anonymize(&erased, Expect::Unchanged(&ada)).await.unwrap().0);
+        let (again, _) = store.get(ada.id, false).await.unwrap().0.unwrap();
+        assert_eq!(version(&again), version(&stored));
+    }
+
+    #[actix_web::test]
+    async fn the_row_cap_truncates_or_refuses_longer_reads() {
+        let rows = || futures::stream::iter((0..5).map(Ok::<_, ApiError>));
+        let (capped, truncated) = cap_rows(3, RowCapMode::Truncate, rows()).await.unwrap();
+        assert_eq!((capped, truncated), (vec![0, 1, 2], true));
+        let (capped, truncated) = cap_rows(5, RowCapMode::Truncate, rows()).await.unwrap();
+        assert_eq!((capped.len(), truncated), (5, false));
+
+        let refused = cap_rows(3, RowCapMode::Error, rows()).await.unwrap_err();
+        assert_eq!(refused.code(), "bad_request");
+        assert!(cap_rows(5, RowCapMode::Error, rows()).await.is_ok());
+
+        let response = with_truncated(HttpResponse::Ok().finish(), true);
+        assert_eq!(response.headers().get(TRUNCATED_HEADER).unwrap(), "true");
+        let response = with_truncated(HttpResponse::Ok().finish(), false);
+        assert!(!response.headers().contains_key(TRUNCATED_HEADER));
+    }
+
+    fn rename(name: &str) -> UpdateUser {
+        UpdateUser {
+            name: Some(name.to_string()),
+            email: None,
+            phone: None,
+            profile: None,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn writes_keep_the_time_left_to_expiring_users() {
+        assert_eq!(ttl(None), 0);
+        assert_eq!(ttl(Some(Utc::now() + TimeDelta::milliseconds(90_500))), 91);
+        // Expired but not yet gone: the write goes soon after it.
+        assert_eq!(ttl(Some(Utc::now() - TimeDelta::seconds(5))), 1);
+
+        let ada = User {
+            expires_at: Some(Utc::now() + TimeDelta::seconds(60)),
+            ..user("Ada", "ada@example.com")
+        };
+        let reread = User {
+            expires_at: ada.expires_at.map(|at| at - TimeDelta::seconds(1)),
+            ..ada.clone()
+        };
+        assert_eq!(version(&ada), version(&reread));
+        let renamed = apply_update(&ada, &rename("Ada L"), Utc::now());
+        assert_eq!(renamed.expires_at, ada.expires_at);
+    }
+
+    #[test]
+    fn versions_follow_every_change() {
+        let ada = user("Ada", "ada@example.com");
+        assert_eq!(version(&ada), version(&ada.clone()));
+        assert_eq!(version(&ada).len(), 16);
+        let renamed = apply_update(&ada, &rename("Ada L"), ada.updated_at.unwrap());
+        assert_ne!(version(&ada), version(&renamed));
+        let touched = User {
+            updated_at: Some(Utc::now() + chrono::Duration::seconds(1)),
+            ..ada.clone()
+        };
+        assert_ne!(version(&ada), version(&touched));
+    }
+
+    #[test]
+    fn if_match_accepts_only_the_current_version() {
+        let ada = user("Ada", "ada@example.com");
+        assert!(check_version(&ada, None).is_ok());
+        assert!(check_version(&ada, Some(&[String::from("stale"), version(&ada)])).is_ok());
+        let error = check_version(&ada, Some(&[String::from("stale")])).unwrap_err();
+        assert!(matches!(error, ApiError::PreconditionFailed(_)));
+        assert!(check_version(&ada, Some(&[])).is_err());
+    }
+
+    #[test]
+    fn expected_versions_conflict_once_the_user_moves_on() {
+        let legacy = user("Ada", "ada@example.com");
+        assert_eq!((legacy.version, next_version(&legacy)), (None, 1));
+        assert!(check_expected(&legacy, None).is_ok());
+        assert!(matches!(check_expected(&legacy, Some(1)), Err(ApiError::Conflict(_))));
+
+        let renamed = apply_update(&legacy, &rename("Ada L"), Utc::now());
+        assert_eq!(renamed.version, Some(1));
+        let renamed = apply_update(&renamed, &rename("Ada K"), Utc::now());
+        assert_eq!(renamed.version, Some(2));
+        assert!(check_expected(&renamed, Some(2)).is_ok());
+        assert!(matches!(check_expected(&renamed, Some(1)), Err(ApiError::Conflict(_))));
+        assert!(matches!(lost_race(renamed.id, None), ApiError::PreconditionFailed(_)));
+    }
+
+    #[test]
+    fn strict_mode_refuses_unconditional_writes() {
+        let id = Uuid::new_v4();
+        let etags = [String::from("abc")];
+        let strict = ConcurrencyMode::Strict;
+        let refused = require_precondition(strict, id, None, None).unwrap_err();
+        assert_eq!(refused.to_problem().status, 428);
+        assert!(require_precondition(strict, id, Some(&etags), None).is_ok());
+        assert!(require_precondition(strict, id, None, Some(3)).is_ok());
+        assert!(require_precondition(ConcurrencyMode::Lenient, id, None, None).is_ok());
+    }
+
+    #[test]
+    fn updates_keep_the_fields_they_leave_out() {
+        let ada = User {
+            phone: Some(String::from("+442079460958")),
+            ..user("Ada", "ada@example.com")
+        };
+        let at = Utc::now();
+        let update = UpdateUser {
+            name: None,
+            email: Some(String::from("ada@lovelace.org")),
+            phone: None,
+            profile: Some(Profile {
+                bio: Some(String::from("Mathematician")),
+                ..Profile::default()
+            }),
+            version: None,
+        };
+        let after = apply_update(&ada, &update, at);
+        assert_eq!(after.name, "Ada");
+        assert_eq!(after.email, "ada@lovelace.org");
+        assert_eq!(after.phone, ada.phone);
+        let profile = after.profile.unwrap();
+        assert_eq!(profile.bio.as_deref(), Some("Mathematician"));
+        assert_eq!(profile.locale.as_deref(), Some("en-GB"));
+        assert_eq!(after.created_at, ada.created_at);
+        assert_eq!(after.updated_at, Some(at));
+    }
+
+    #[test]
+    fn update_statements_set_only_the_fields_present() {
+        let ada = User {
+            version: Some(4),
+            ..user("Ada", "ada@example.com")
+        };
+        let at = Utc::now();
+        let update = UpdateUser {
+            name: Some(String::from("Ada")),
+            email: None,
+            phone: Some(String::from("+442079460958")),
+            profile: Some(Profile {
+                timezone: Some(String::from("Europe/London")),
+                ..Profile::default()
+            }),
+            version: None,
+        };
+        let (query, values) = update_statement("app", &update, &ada, at, Condition::Exists);
+        assert_eq!(
+            query,
+            "UPDATE app.users USING TTL ? SET name = ?, phone = ?, profile.timezone = ?, \
+             updated_at = ?, version = ? WHERE id = ? IF EXISTS"
+        );
+        assert_eq!(
+            values,
+            [
+                Some(CqlValue::Int(0)),
+                Some(CqlValue::Text(String::from("Ada"))),
+                Some(CqlValue::Text(String::from("+442079460958"))),
+                Some(CqlValue::Text(String::from("Europe/London"))),
+                Some(CqlValue::Timestamp(at.into())),
+                Some(CqlValue::Int(5)),
+                Some(CqlValue::Uuid(ada.id)),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_filters_become_conditions() {
+        assert!(list_statement("app", &ListUsersQuery::default(), None).is_none());
+        let after = Utc::now();
+        let params = ListUsersQuery {
+            email: Some(String::from(" ada@example.com ")),
+            created_after: Some(after),
+            verified: Some(true),
+            tag: Some(String::from("Beta")),
+            ..ListUsersQuery::default()
+        };
+        let (query, values) = list_statement("app", &params, None).unwrap();
+        assert!(query.ends_with(
+            "FROM app.users WHERE email = ? AND created_at > ? AND verified = ? \
+             AND tags CONTAINS ? ALLOW FILTERING"
+        ));
+        assert_eq!(
+            values,
+            [
+                Some(CqlValue::Text(String::from("ada@example.com"))),
+                Some(CqlValue::Timestamp(after.into())),
+                Some(CqlValue::Boolean(true)),
+                Some(CqlValue::Text(String::from("beta"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn pages_sort_case_insensitively_with_ties_broken_by_id() {
+        let mut users = vec![
+            user("bob", "b@example.com"),
+            user("Ada", "z@example.com"),
+            user("ada", "y@example.com"),
+        ];
+        sort_users(&mut users, SortField::Name, SortOrder::Asc);
+        let names: Vec<_> = users.iter().map(|user| user.name.to_lowercase()).collect();
+        assert_eq!(names, ["ada", "ada", "bob"]);
+        assert!(users[0].id < users[1].id);
+        let ascending: Vec<_> = users.iter().map(|user| user.id).collect();
+        sort_users(&mut users, SortField::Name, SortOrder::Desc);
+        let descending: Vec<_> = users.iter().rev().map(|user| user.id).collect();
+        assert_eq!(ascending, descending);
+        sort_users(&mut users, SortField::Email, SortOrder::Asc);
+        assert_eq!(users[0].email, "b@example.com");
+    }
+
+    #[test]
+    fn sorting_by_id_repeats_across_identical_pages() {
+        let page: Vec<_> = (0..20)
+            .map(|i| user(&format!("User {}", i % 3), "u@example.com"))
+            .collect();
+        let mut first = page.clone();
+        let mut second: Vec<_> = page.into_iter().rev().collect();
+        sort_users(&mut first, SortField::Id, SortOrder::Asc);
+        sort_users(&mut second, SortField::Id, SortOrder::Asc);
+        let ids = |users: &[User]| users.iter().map(|user| user.id).collect::<Vec<_>>();
+        assert_eq!(ids(&first), ids(&second));
+        assert!(first.windows(2).all(|pair| pair[0].id < pair[1].id));
+    }
+
+    fn bad_request(result: Result<ListingPlan, ApiError>) -> String {
+        match result {
+            Err(ApiError::BadRequest(message)) => message,
+            Err(other) => panic!("unexpected {:?}", other),
+            Ok(_) => panic!("plan accepted"),
+        }
+    }
+
+    // A cursor as `list` would hand out next for `params`.
+    fn next_cursor(params: &ListUsersQuery) -> String {
+        let plan = plan_listing("app", params, MAX_AGE).unwrap();
+        let state = PagingState::new_from_raw_bytes(&b"page"[..]);
+        paging::encode_cursor(&plan.scope, PagingStateResponse::HasMorePages { state }).unwrap()
+    }
+
+    fn filtered_and_sorted() -> ListUsersQuery {
+        ListUsersQuery {
+            name: Some(String::from("Ada")),
+            created_after: DateTime::from_timestamp(1_700_000_000, 0),
+            sort: Some(SortField::Email),
+            order: Some(SortOrder::Desc),
+            fields: Some(String::from("id, email")),
+            ..ListUsersQuery::default()
+        }
+    }
+
+    #[test]
+    fn listing_plans_combine_filters_sort_fields_and_cursor() {
+        let first = filtered_and_sorted();
+        let plan = plan_listing("app", &first, MAX_AGE).unwrap();
+        assert_eq!(plan.fields, Some(vec!["id", "email"]));
+        assert_eq!(plan.columns, Some(vec!["id", "email", "status", "deleted_at"]));
+        let (query, values) = plan.statement.unwrap();
+        let select = "SELECT id, email, status, deleted_at FROM app.users WHERE name = ?";
+        assert!(query.starts_with(select));
+        assert_eq!(values.len(), 2);
+        assert!(plan.paging_state.as_bytes_slice().is_none());
+
+        // Page size, fields and whitespace around filters may change.
+        let second = ListUsersQuery {
+            cursor: Some(next_cursor(&first)),
+            name: Some(String::from(" Ada ")),
+            limit: Some(5),
+            fields: None,
+            ..filtered_and_sorted()
+        };
+        let plan = plan_listing("app", &second, MAX_AGE).unwrap();
+        assert_eq!(plan.paging_state.as_bytes_slice().map(|bytes| bytes.to_vec()), Some(b"page".to_vec()));
+        assert_eq!(plan.fields, None);
+
+        let unfiltered = plan_listing("app", &ListUsersQuery::default(), MAX_AGE).unwrap();
+        assert!(unfiltered.statement.is_none());
+    }
+
+    #[test]
+    fn cursors_only_continue_the_filters_and_sort_they_came_from() {
+        let cursor = next_cursor(&filtered_and_sorted());
+        let changed = [
+            ListUsersQuery {
+                name: Some(String::from("Bob")),
+                ..filtered_and_sorted()
+            },
+            ListUsersQuery {
+                sort: Some(SortField::Name),
+                ..filtered_and_sorted()
+            },
+            ListUsersQuery {
+                order: Some(SortOrder::Asc),
+                ..filtered_and_sorted()
+            },
+            ListUsersQuery {
+                created_after: None,
+                ..filtered_and_sorted()
+            },
+            ListUsersQuery {
+                tag: Some(String::from("beta")),
+                ..filtered_and_sorted()
+            },
+        ];
+        for params in changed {
+            let params = ListUsersQuery {
+                cursor: Some(cursor.clone()),
+                ..params
+            };
+            assert_eq!(
+                bad_request(plan_listing("app", &params, MAX_AGE)),
+                "Cursor was issued for different query parameters; restart from the first page"
+            );
+        }
+
+        let unfiltered = ListUsersQuery {
+            cursor: Some(cursor),
+            ..ListUsersQuery::default()
+        };
+        assert_eq!(
+            bad_request(plan_listing("app", &unfiltered, MAX_AGE)),
+            "Cursor belongs to a different listing"
+        );
+    }
+
+    #[test]
+    fn the_default_order_matches_an_explicit_asc() {
+        let implicit = ListUsersQuery {
+            sort: Some(SortField::Id),
+            ..ListUsersQuery::default()
+        };
+        let explicit = ListUsersQuery {
+            cursor: Some(next_cursor(&implicit)),
+            sort: Some(SortField::Id),
+            order: Some(SortOrder::Asc),
+            ..ListUsersQuery::default()
+        };
+        assert!(plan_listing("app", &explicit, MAX_AGE).is_ok());
+    }
+
+    #[test]
+    fn incompatible_listing_parameters_are_refused() {
+        let order_alone = ListUsersQuery {
+            order: Some(SortOrder::Desc),
+            ..ListUsersQuery::default()
+        };
+        assert_eq!(bad_request(plan_listing("app", &order_alone, MAX_AGE)), "order requires sort");
+
+        for fields in ["", "id,,name", "id,password"] {
+            let params = ListUsersQuery {
+                fields: Some(fields.to_string()),
+                ..ListUsersQuery::default()
+            };
+            assert!(bad_request(plan_listing("app", &params, MAX_AGE)).starts_with("fields: unknown field"));
+        }
+    }
+
+    #[test]
+    fn narrowed_listings_read_only_the_columns_they_need() {
+        assert_eq!(sparse_columns(&["email"], None), ["id", "email", "status", "deleted_at"]);
+        assert_eq!(
+            sparse_columns(&["verified", "id"], Some(SortField::Name)),
+            ["id", "name", "verified", "status", "deleted_at"]
+        );
+
+        let columns = ["id", "profile", "created_at", "verified", "status", "deleted_at"];
+        let id = Uuid::new_v4();
+        let row = |deleted_at: Option<CqlValue>| Row {
+            columns: vec![
+                Some(CqlValue::Uuid(id)),
+                Some(CqlValue::UserDefinedType {
+                    keyspace: String::from("app"),
+                    type_name: String::from("profile"),
+                    fields: vec![(
+                        String::from("locale"),
+                        Some(CqlValue::Text(String::from("en-GB"))),
+                    )],
+                }),
+                Some(CqlValue::Timestamp(CqlTimestamp(1_700_000_000_000))),
+                None,
+                None,
+                deleted_at,
+            ],
+        };
+        let user = sparse_user(&columns, row(None)).unwrap();
+        assert_eq!(user.id, id);
+        assert_eq!(user.profile.as_ref().unwrap().locale.as_deref(), Some("en-GB"));
+        assert_eq!(user.created_at, DateTime::from_timestamp(1_700_000_000, 0));
+        assert_eq!(user.verified, Some(false));
+        assert_eq!(user.status, Some(UserStatus::Active));
+        assert!(admitted(StatusFilter::Active, &user));
+        assert!(!admitted(StatusFilter::Suspended, &user));
+        let deleted = Some(CqlValue::Timestamp(CqlTimestamp(1_700_000_000_000)));
+        assert!(sparse_user(&columns, row(deleted)).is_none());
+    }
+
+    #[test]
+    fn projected_pages_keep_only_the_fields_asked_for() {
+        let page = UsersPage {
+            users: vec![user("Ada", "ada@example.com")],
+            next_cursor: Some(String::from("next")),
+        };
+        let projected = project_page(&page, &parse_fields("name,id,name").unwrap());
+        let first = projected["users"][0].as_object().unwrap();
+        let mut keys: Vec<_> = first.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["id", "name"]);
+        assert_eq!(first["name"], "Ada");
+        assert_eq!(projected["next_cursor"], "next");
+    }
+
+    #[test]
+    fn typed_rows_bind_and_read_users() {
+        let mut user = user("Ada", "ada@example.com");
+        user.expires_at = Some(Utc::now() + TimeDelta::seconds(60));
+        let row = NewUserRow::new("hireme", &user, Some("hash"));
+        assert_eq!((row.id, row.name, row.email), (user.id, "Ada", "ada@example.com"));
+        assert_eq!(row.password_hash, Some("hash"));
+        assert!(row.profile.is_some());
+        assert!(row.ttl > 0 && row.ttl <= 60);
+
+        let at = Some(Utc::now());
+        let live = CredentialsRow {
+            id: user.id,
+            password_hash: Some(String::from("hash")),
+            deleted_at: None,
+        };
+        let deleted = CredentialsRow {
+            id: user.id,
+            password_hash: Some(String::from("hash")),
+            deleted_at: at,
+        };
+        assert_eq!(live.live_hash().as_deref(), Some("hash"));
+        assert_eq!(deleted.live_hash(), None);
+
+        let legacy = TimestampsRow {
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+        };
+        assert_eq!(legacy.modified(), Some(None));
+        let changed = TimestampsRow {
+            created_at: None,
+            updated_at: at,
+            deleted_at: None,
+        };
+        assert_eq!(changed.modified(), Some(at));
+    }
+}
@@ -0,0 +1,381 @@
+use crate::{
+    addresses, api_keys, audit, auth, avatars, batch, bulk_delete, cluster, count, export, flags,
+    groups, handlers, history, import, indexes, latency, login, maintenance,
+    maintenance_mode, metadata, monitor, oauth, password_reset, posts, raw_cql, reload, sessions,
+    sse, stats, status, tags, tenants, verification, webhooks, ws,
+};
+#[cfg(feature = "graphql")]
+use crate::graphql;
+use actix_web::middleware::from_fn;
+use actix_web::web;
+
+// Version 1 of the API: every route but the probes, metrics and API docs. It
+// is served under `PREFIX` and, for clients written before the API was
+// versioned, at the top level as well. A new version gets a module of its
+// own beside this one, registered under its own prefix, with handlers that
+// return its response shapes; routes it leaves unchanged can reuse these
+// handlers. The unprefixed routes stay on version 1.
+
+pub const PREFIX: &str = "/api/v1";
+
+// `path` without the version prefix, for middleware that picks out routes
+// by their path.
+pub fn unversioned(path: &str) -> &str {
+    path.strip_prefix(PREFIX)
+        .filter(|rest| rest.starts_with('/'))
+        .unwrap_or(path)
+}
+
+// The routes of version 1; `/admin/tenants` only with `tenants.enabled`, and
+// `/graphql` only with the `graphql` feature.
+pub fn configure(cfg: &mut web::ServiceConfig, tenants: bool) {
+    if tenants {
+        cfg.service(
+            web::resource("/admin/tenants")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(tenants::list_tenants))
+                .route(web::post().to(tenants::create_tenant)),
+        );
+    }
+    #[cfg(feature = "graphql")]
+    cfg.service(
+        web::resource("/graphql")
+            .route(web::get().to(graphql::get_schema))
+            .route(web::post().to(graphql::execute)),
+    );
+    cfg.route("/users", web::get().to(handlers::get_all_users))
+        .route("/register", web::post().to(handlers::register_user))
+        .service(
+            web::resource("/batch")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(batch::apply_batch)),
+        )
+        .service(
+            web::resource("/register/bulk")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(handlers::register_users_bulk)),
+        )
+        .route("/login", web::post().to(login::login))
+        .route("/token/refresh", web::post().to(sessions::refresh))
+        .route("/oauth/{provider}/authorize", web::get().to(oauth::authorize))
+        .route("/oauth/{provider}/callback", web::get().to(oauth::callback))
+        .service(
+            web::resource("/logout")
+                .wrap(from_fn(auth::require_jwt))
+                .route(web::post().to(sessions::logout)),
+        )
+        .route("/verify/{token}", web::post().to(verification::verify_email))
+        .route("/password/forgot", web::post().to(password_reset::forgot_password))
+        .route("/password/reset", web::post().to(password_reset::reset_password))
+        .service(
+            web::resource("/update/{id}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::patch().to(handlers::update_user)),
+        )
+        .service(
+            web::resource("/delete/{id}")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::delete().to(handlers::delete_user)),
+        )
+        .service(
+            web::scope("/admin/api-keys")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt))
+                .route("", web::post().to(api_keys::create_api_key))
+                .route("/{id}", web::delete().to(api_keys::revoke_api_key)),
+        )
+        .service(
+            web::resource("/admin/users/{id}/roles")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::put().to(handlers::set_user_roles)),
+        )
+        .service(
+            web::resource("/users/{id}/restore")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(handlers::restore_user)),
+        )
+        .service(
+            web::resource("/users/{id}/anonymize")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(handlers::anonymize_user)),
+        )
+        .service(
+            web::resource("/users/{id}/audit")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(audit::get_audit_log)),
+        )
+        .service(
+            web::resource("/users/{id}/events")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(history::get_user_events)),
+        )
+        .service(
+            web::resource("/users/{id}/sessions")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(sessions::list_sessions))
+                .route(web::delete().to(sessions::revoke_sessions)),
+        )
+        .service(
+            web::resource("/users/{id}/sessions/{session_id}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::delete().to(sessions::revoke_session)),
+        )
+        .service(
+            web::resource("/users/{id}/addresses")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(addresses::list_addresses))
+                .route(web::post().to(addresses::add_address)),
+        )
+        .service(
+            web::resource("/users/{id}/addresses/{index}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::delete().to(addresses::delete_address)),
+        )
+        .service(
+            web::resource("/users/{id}/tags")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(tags::list_tags))
+                .route(web::post().to(tags::add_tag)),
+        )
+        .service(
+            web::resource("/users/{id}/tags/{tag}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::delete().to(tags::delete_tag)),
+        )
+        .service(
+            web::resource("/users/{id}/metadata")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(metadata::get_metadata)),
+        )
+        .service(
+            web::resource("/users/{id}/metadata/{key}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::put().to(metadata::set_metadata))
+                .route(web::delete().to(metadata::delete_metadata)),
+        )
+        .service(
+            web::resource("/users/{id}/stats")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(stats::get_stats)),
+        )
+        .service(
+            web::resource("/users/{id}/deactivate")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(status::deactivate_user)),
+        )
+        .service(
+            web::resource("/users/{id}/suspend")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(status::suspend_user)),
+        )
+        .service(
+            web::resource("/users/{id}/activate")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(status::activate_user)),
+        )
+        .service(
+            web::resource("/users/{id}/groups")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(groups::list_user_groups)),
+        )
+        .service(
+            web::resource("/users/{id}/posts")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(posts::list_posts))
+                .route(web::post().to(posts::create_post)),
+        )
+        .service(
+            web::resource("/users/{id}/posts/{post_id}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::delete().to(posts::delete_post)),
+        )
+        .service(
+            web::resource("/groups")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(groups::list_groups))
+                .route(
+                    web::post()
+                        .to(groups::create_group)
+                        .wrap(from_fn(auth::require_admin)),
+                ),
+        )
+        .service(
+            web::resource("/groups/{id}")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(groups::get_group))
+                .route(
+                    web::patch()
+                        .to(groups::update_group)
+                        .wrap(from_fn(auth::require_admin)),
+                )
+                .route(
+                    web::delete()
+                        .to(groups::delete_group)
+                        .wrap(from_fn(auth::require_admin)),
+                ),
+        )
+        .service(
+            web::resource("/groups/{id}/members")
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(groups::list_members)),
+        )
+        .service(
+            web::resource("/groups/{id}/members/{user_id}")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(groups::add_member))
+                .route(web::delete().to(groups::remove_member)),
+        )
+        .service(
+            web::resource("/users/{id}/avatar")
+                .route(web::get().to(avatars::get_avatar))
+                .route(
+                    web::put()
+                        .to(avatars::upload_avatar)
+                        .wrap(from_fn(auth::require_jwt_or_api_key)),
+                ),
+        )
+        .route("/events", web::get().to(sse::events))
+        .route("/ws/users", web::get().to(ws::user_events))
+        .service(
+            web::resource("/users/import")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(import::import_users)),
+        )
+        .service(
+            web::resource("/users/export.csv")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(export::export_users)),
+        )
+        .service(
+            web::resource("/users/delete")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(bulk_delete::delete_users)),
+        )
+        .route("/users/count", web::get().to(count::get_user_count))
+        .route("/users/search", web::get().to(handlers::search_users))
+        .route("/users/check-email", web::get().to(handlers::check_email))
+        .route("/users/by-email/{email}", web::get().to(handlers::get_user_by_email))
+        .route("/users/by-phone/{phone}", web::get().to(handlers::get_user_by_phone))
+        .service(
+            web::resource("/users/{id}")
+                .route(web::get().to(handlers::get_user_by_id))
+                .route(web::head().to(handlers::user_exists))
+                .route(
+                    web::put()
+                        .to(handlers::replace_user)
+                        .wrap(from_fn(auth::require_jwt_or_api_key)),
+                ),
+        )
+        .service(
+            web::resource("/admin/status")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(monitor::get_status)),
+        )
+        .service(
+            web::resource("/admin/cluster")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(cluster::get_cluster)),
+        )
+        .service(
+            web::resource("/admin/schema")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(cluster::get_schema)),
+        )
+        .service(
+            web::resource("/admin/indexes")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(indexes::get_indexes))
+                .route(web::post().to(indexes::create_indexes)),
+        )
+        .service(
+            web::resource("/admin/latency")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(latency::get_latency)),
+        )
+        .service(
+            web::resource("/admin/jobs/{name}")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(maintenance::run_job)),
+        )
+        .service(
+            web::resource("/admin/flags")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(flags::list_flags)),
+        )
+        .service(
+            web::resource("/admin/flags/{name}")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::put().to(flags::set_flag)),
+        )
+        .service(
+            web::resource("/admin/maintenance")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(maintenance_mode::get_maintenance))
+                .route(web::put().to(maintenance_mode::set_maintenance)),
+        )
+        .service(
+            web::resource("/admin/reload")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(reload::reload_config)),
+        )
+        .service(
+            web::resource("/admin/cql")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::post().to(raw_cql::run_cql)),
+        )
+        .service(
+            web::resource("/admin/webhooks")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(webhooks::list_webhooks))
+                .route(web::post().to(webhooks::create_webhook)),
+        )
+        .service(
+            web::resource("/admin/webhooks/{id}")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::delete().to(webhooks::delete_webhook)),
+        )
+        .service(
+            web::resource("/admin/webhooks/{id}/deliveries")
+                .wrap(from_fn(auth::require_admin))
+                .wrap(from_fn(auth::require_jwt_or_api_key))
+                .route(web::get().to(webhooks::list_deliveries)),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versioned_paths_match_their_unversioned_routes() {
+        assert_eq!(unversioned("/api/v1/users/import"), "/users/import");
+        assert_eq!(unversioned("/users/import"), "/users/import");
+        assert_eq!(unversioned("/api/v10/users"), "/api/v10/users");
+    }
+}
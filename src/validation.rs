@@ -0,0 +1,762 @@
+use crate::config::{Severity, ValidationConfig};
+use crate::error::{ApiError, FieldError};
+use crate::models::{Address, NewGroup, NewUser, Profile, UpdateGroup, UpdateUser};
+use actix_web::http::header::HeaderName;
+
+// Request bodies are checked and normalized (trimmed) before anything is
+// written, and all problems are reported at once as a 422 with one entry per
+// field. The soft checks of `[validation]` run on top: those set to `reject`
+// fail the write the same way, those set to `warn` are reported back in
+// `X-Validation-Warnings` once it has gone through.
+
+// One `field: message` line per warning.
+pub const WARNINGS_HEADER: HeaderName = HeaderName::from_static("x-validation-warnings");
+
+const MAX_NAME_CHARS: usize = 100;
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_EMAIL_LOCAL_LEN: usize = 64;
+const MIN_PASSWORD_CHARS: usize = 8;
+const MAX_PASSWORD_CHARS: usize = 128;
+// Digits of an E.164 number, country code included.
+const MIN_PHONE_DIGITS: usize = 7;
+const MAX_PHONE_DIGITS: usize = 15;
+const MAX_BIO_CHARS: usize = 500;
+const MAX_AVATAR_URL_LEN: usize = 2048;
+const MAX_LOCALE_LEN: usize = 35;
+const MAX_TIMEZONE_LEN: usize = 64;
+const MAX_ADDRESS_LINE_CHARS: usize = 100;
+const MAX_POSTAL_CODE_LEN: usize = 16;
+const MAX_TAG_LEN: usize = 32;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 1024;
+const MAX_GROUP_NAME_CHARS: usize = 100;
+const MAX_GROUP_DESCRIPTION_CHARS: usize = 500;
+const MAX_POST_CHARS: usize = 5000;
+// The longest TTL Scylla accepts, 20 years.
+pub const MAX_TTL_SECS: u32 = 630_720_000;
+
+#[derive(Default)]
+struct Errors(Vec<FieldError>);
+
+impl Errors {
+    fn add(&mut self, field: &'static str, message: &str) {
+        self.0.push(FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn finish<T>(self, value: T) -> Result<T, ApiError> {
+        if self.0.is_empty() {
+            Ok(value)
+        } else {
+            Err(ApiError::Validation(self.0))
+        }
+    }
+}
+
+// Letters (any script), spaces and the punctuation found in real names.
+fn check_name(name: &str, errors: &mut Errors) {
+    if name.is_empty() {
+        errors.add("name", "must not be empty");
+    } else if name.chars().count() > MAX_NAME_CHARS {
+        errors.add("name", &format!("must be at most {} characters", MAX_NAME_CHARS));
+    } else if !name
+        .chars()
+        .all(|c| c.is_alphabetic() || matches!(c, ' ' | '\'' | '-' | '.'))
+    {
+        errors.add("name", "may only contain letters, spaces, apostrophes, hyphens and periods");
+    }
+}
+
+// A deliberately plain check: one `@`, a non-empty local part and a dotted
+// domain of letters, digits and hyphens. Deliverability is not our problem.
+fn check_email(email: &str, errors: &mut Errors) {
+    let valid = email.len() <= MAX_EMAIL_LEN
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && local.len() <= MAX_EMAIL_LOCAL_LEN
+                && local.chars().all(|c| c.is_ascii_graphic() && c != '@')
+                && domain.contains('.')
+                && domain.split('.').all(|label| {
+                    !label.is_empty()
+                        && !label.starts_with('-')
+                        && !label.ends_with('-')
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                })
+        });
+    if !valid {
+        errors.add("email", "must be a valid email address");
+    }
+}
+
+// `phone` without the spaces, hyphens, dots and parentheses people write
+// numbers with, and with an international `00` prefix as `+`. What's left
+// is only E.164 if `check_phone` says so.
+fn compact_phone(phone: &str) -> String {
+    let compact: String = phone
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+    match compact.strip_prefix("00") {
+        Some(rest) => format!("+{}", rest),
+        None => compact,
+    }
+}
+
+// E.164: a `+` and the digits, starting with a country code. Numbers without
+// one are refused rather than guessed at; whether the number is assigned is
+// not checked.
+fn check_phone(phone: &str, errors: &mut Errors) {
+    let valid = phone.strip_prefix('+').is_some_and(|digits| {
+        (MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits.len())
+            && digits.chars().all(|c| c.is_ascii_digit())
+            && !digits.starts_with('0')
+    });
+    if !valid {
+        errors.add("phone", "must be an international number such as +14155550123");
+    }
+}
+
+// A phone number as sent: normalized, or `None` when left blank.
+fn normalize_phone(phone: Option<String>) -> Option<String> {
+    phone.map(|phone| compact_phone(&phone)).filter(|phone| !phone.is_empty())
+}
+
+fn check_password(password: &str, errors: &mut Errors) {
+    let chars = password.chars().count();
+    if !(MIN_PASSWORD_CHARS..=MAX_PASSWORD_CHARS).contains(&chars) {
+        errors.add(
+            "password",
+            &format!(
+                "must be between {} and {} characters",
+                MIN_PASSWORD_CHARS, MAX_PASSWORD_CHARS
+            ),
+        );
+    }
+}
+
+// Shape checks only: the locale and time zone must look like a language tag
+// and a zone name, but are not looked up in any registry.
+fn check_profile(profile: &Profile, errors: &mut Errors) {
+    if let Some(bio) = &profile.bio
+        && bio.chars().count() > MAX_BIO_CHARS
+    {
+        errors.add("profile.bio", &format!("must be at most {} characters", MAX_BIO_CHARS));
+    }
+    if let Some(url) = &profile.avatar_url
+        && (url.len() > MAX_AVATAR_URL_LEN
+            || !(url.starts_with("https://") || url.starts_with("http://"))
+            || url.chars().any(char::is_whitespace))
+    {
+        errors.add("profile.avatar_url", "must be an http or https URL");
+    }
+    if let Some(locale) = &profile.locale
+        && (locale.len() > MAX_LOCALE_LEN
+            || !locale
+                .split('-')
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())))
+    {
+        errors.add("profile.locale", "must be a language tag such as en-GB");
+    }
+    if let Some(timezone) = &profile.timezone
+        && (timezone.is_empty()
+            || timezone.len() > MAX_TIMEZONE_LEN
+            || !timezone
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+')))
+    {
+        errors.add("profile.timezone", "must be a time zone name such as Europe/Berlin");
+    }
+}
+
+// Trims every field, dropping the ones left empty.
+fn trim_profile(profile: Profile) -> Profile {
+    let trim = |field: Option<String>| {
+        field
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    Profile {
+        bio: trim(profile.bio),
+        avatar_url: trim(profile.avatar_url),
+        locale: trim(profile.locale),
+        timezone: trim(profile.timezone),
+    }
+}
+
+fn is_empty(profile: &Profile) -> bool {
+    profile.bio.is_none()
+        && profile.avatar_url.is_none()
+        && profile.locale.is_none()
+        && profile.timezone.is_none()
+}
+
+pub fn new_user(user: NewUser) -> Result<NewUser, ApiError> {
+    let user = NewUser {
+        name: user.name.trim().to_string(),
+        email: user.email.trim().to_string(),
+        password: user.password,
+        phone: normalize_phone(user.phone),
+        profile: user.profile.map(trim_profile).filter(|profile| !is_empty(profile)),
+        expires_in_seconds: user.expires_in_seconds,
+    };
+    let mut errors = Errors::default();
+    check_name(&user.name, &mut errors);
+    check_email(&user.email, &mut errors);
+    if let Some(password) = &user.password {
+        check_password(password, &mut errors);
+    }
+    if let Some(phone) = &user.phone {
+        check_phone(phone, &mut errors);
+    }
+    if let Some(profile) = &user.profile {
+        check_profile(profile, &mut errors);
+    }
+    if let Some(secs) = user.expires_in_seconds
+        && !(1..=MAX_TTL_SECS).contains(&secs)
+    {
+        errors.add("expires_in_seconds", &format!("must be between 1 and {}", MAX_TTL_SECS));
+    }
+    errors.finish(user)
+}
+
+// `password` if it would do for a user, as on registration.
+pub fn password(password: String) -> Result<String, ApiError> {
+    let mut errors = Errors::default();
+    check_password(&password, &mut errors);
+    errors.finish(password)
+}
+
+// The trimmed `email`, or why it isn't a valid address.
+pub fn email(email: &str) -> Result<String, String> {
+    let email = email.trim().to_string();
+    let mut errors = Errors::default();
+    check_email(&email, &mut errors);
+    match errors.0.pop() {
+        Some(error) => Err(error.message),
+        None => Ok(email),
+    }
+}
+
+// Trims every field, dropping the optional ones left empty, and upper-cases
+// the country code. Like the profile, only the shape is checked: postal
+// codes aren't matched against the country's format.
+pub fn address(address: Address) -> Result<Address, ApiError> {
+    let optional = |field: Option<String>| {
+        field
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let address = Address {
+        label: optional(address.label),
+        line1: address.line1.trim().to_string(),
+        line2: optional(address.line2),
+        city: address.city.trim().to_string(),
+        region: optional(address.region),
+        postal_code: optional(address.postal_code),
+        country: address.country.trim().to_ascii_uppercase(),
+    };
+    let mut errors = Errors::default();
+    let required = [("line1", &address.line1), ("city", &address.city)];
+    for (field, value) in required {
+        if value.is_empty() {
+            errors.add(field, "must not be empty");
+        }
+    }
+    let lines = [
+        ("label", address.label.as_ref()),
+        ("line1", Some(&address.line1)),
+        ("line2", address.line2.as_ref()),
+        ("city", Some(&address.city)),
+        ("region", address.region.as_ref()),
+    ];
+    for (field, value) in lines {
+        if value.is_some_and(|value| value.chars().count() > MAX_ADDRESS_LINE_CHARS) {
+            errors.add(field, &format!("must be at most {} characters", MAX_ADDRESS_LINE_CHARS));
+        }
+    }
+    if let Some(postal_code) = &address.postal_code
+        && (postal_code.len() > MAX_POSTAL_CODE_LEN
+            || !postal_code
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '-')))
+    {
+        errors.add("postal_code", "must be letters, digits, spaces and hyphens");
+    }
+    if address.country.len() != 2 || !address.country.chars().all(|c| c.is_ascii_alphabetic()) {
+        errors.add("country", "must be a two-letter country code such as GB");
+    }
+    errors.finish(address)
+}
+
+// `phone` in E.164 form, or why it isn't an international number.
+pub fn phone(phone: &str) -> Result<String, String> {
+    let phone = compact_phone(phone.trim());
+    let mut errors = Errors::default();
+    check_phone(&phone, &mut errors);
+    match errors.0.pop() {
+        Some(error) => Err(error.message),
+        None => Ok(phone),
+    }
+}
+
+// `tag` trimmed and lower-cased, as it is stored and matched.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+// `tag` as it is stored, or why it can't be one: up to 32 lower-case ASCII
+// letters, digits, `-`, `_` and `:`, so tags read the same in a URL path.
+pub fn tag(tag: &str) -> Result<String, String> {
+    let tag = normalize_tag(tag);
+    if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+        return Err(format!("must be 1 to {} characters", MAX_TAG_LEN));
+    }
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_:".contains(c);
+    if !tag.chars().all(allowed) {
+        return Err(String::from("must be letters, digits, '-', '_' and ':'"));
+    }
+    Ok(tag)
+}
+
+// `key` as a metadata key, or why it can't be one: up to 64 ASCII letters,
+// digits, `-`, `_`, `.` and `:`. Keys are kept as given, case included, so
+// they can carry another system's names.
+pub fn metadata_key(key: &str) -> Result<&str, String> {
+    if key.is_empty() || key.len() > MAX_METADATA_KEY_LEN {
+        return Err(format!("must be 1 to {} characters", MAX_METADATA_KEY_LEN));
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+    if !key.chars().all(allowed) {
+        return Err(String::from("must be letters, digits, '-', '_', '.' and ':'"));
+    }
+    Ok(key)
+}
+
+// A metadata value is stored as given, up to 1 KiB of UTF-8.
+pub fn metadata_value(value: &str) -> Result<&str, String> {
+    match value.len() > MAX_METADATA_VALUE_LEN {
+        true => Err(format!("must be at most {} bytes", MAX_METADATA_VALUE_LEN)),
+        false => Ok(value),
+    }
+}
+
+// Group names are free text, unlike user names, but for control characters.
+fn check_group_name(name: &str, errors: &mut Errors) {
+    if name.is_empty() {
+        errors.add("name", "must not be empty");
+    } else if name.chars().count() > MAX_GROUP_NAME_CHARS {
+        errors.add("name", &format!("must be at most {} characters", MAX_GROUP_NAME_CHARS));
+    } else if name.chars().any(char::is_control) {
+        errors.add("name", "must not contain control characters");
+    }
+}
+
+fn check_group_description(description: &str, errors: &mut Errors) {
+    if description.chars().count() > MAX_GROUP_DESCRIPTION_CHARS {
+        let message = format!("must be at most {} characters", MAX_GROUP_DESCRIPTION_CHARS);
+        errors.add("description", &message);
+    }
+}
+
+pub fn new_group(group: NewGroup) -> Result<NewGroup, ApiError> {
+    let group = NewGroup {
+        name: group.name.trim().to_string(),
+        description: group
+            .description
+            .map(|description| description.trim().to_string())
+            .filter(|description| !description.is_empty()),
+    };
+    let mut errors = Errors::default();
+    check_group_name(&group.name, &mut errors);
+    if let Some(description) = &group.description {
+        check_group_description(description, &mut errors);
+    }
+    errors.finish(group)
+}
+
+// An empty description is kept as such: it removes the group's.
+pub fn update_group(update: UpdateGroup) -> Result<UpdateGroup, ApiError> {
+    let update = UpdateGroup {
+        name: update.name.map(|name| name.trim().to_string()),
+        description: update.description.map(|description| description.trim().to_string()),
+    };
+    let mut errors = Errors::default();
+    if let Some(name) = &update.name {
+        check_group_name(name, &mut errors);
+    }
+    if let Some(description) = &update.description {
+        check_group_description(description, &mut errors);
+    }
+    errors.finish(update)
+}
+
+// `body` trimmed, as a post stores it, or why it can't be one.
+pub fn post_body(body: &str) -> Result<String, String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(String::from("must not be empty"));
+    }
+    if body.chars().count() > MAX_POST_CHARS {
+        return Err(format!("must be at most {} characters", MAX_POST_CHARS));
+    }
+    Ok(body.to_string())
+}
+
+pub fn update_user(update: UpdateUser) -> Result<UpdateUser, ApiError> {
+    let update = UpdateUser {
+        name: update.name.map(|name| name.trim().to_string()),
+        email: update.email.map(|email| email.trim().to_string()),
+        phone: normalize_phone(update.phone),
+        profile: update.profile.map(trim_profile).filter(|profile| !is_empty(profile)),
+        version: update.version,
+    };
+    let mut errors = Errors::default();
+    if update.name.is_none()
+        && update.email.is_none()
+        && update.phone.is_none()
+        && update.profile.is_none()
+    {
+        errors.add("body", "must set at least one field");
+    }
+    if let Some(name) = &update.name {
+        check_name(name, &mut errors);
+    }
+    if let Some(email) = &update.email {
+        check_email(email, &mut errors);
+    }
+    if let Some(phone) = &update.phone {
+        check_phone(phone, &mut errors);
+    }
+    if let Some(profile) = &update.profile {
+        check_profile(profile, &mut errors);
+    }
+    errors.finish(update)
+}
+
+// The soft checks and what each does with a failing value.
+pub struct Policy {
+    disposable_email: Severity,
+    disposable_domains: Vec<String>,
+}
+
+impl Policy {
+    pub fn new(config: &ValidationConfig) -> Self {
+        Policy {
+            disposable_email: config.disposable_email,
+            disposable_domains: config
+                .disposable_domains
+                .iter()
+                .map(|domain| domain.trim().to_lowercase())
+                .collect(),
+        }
+    }
+
+    fn is_disposable(&self, email: &str) -> bool {
+        let Some((_, domain)) = email.trim().rsplit_once('@') else {
+            return false;
+        };
+        let domain = domain.to_lowercase();
+        self.disposable_domains.iter().any(|disposable| {
+            domain == *disposable
+                || domain
+                    .strip_suffix(disposable.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+
+    // Soft-check failures of an email about to be stored, with the severity
+    // configured for each.
+    fn findings(&self, email: &str) -> Vec<(Severity, FieldError)> {
+        let mut findings = Vec::new();
+        if self.disposable_email != Severity::Off && self.is_disposable(email) {
+            findings.push((
+                self.disposable_email,
+                FieldError {
+                    field: String::from("email"),
+                    message: String::from("uses a disposable email domain"),
+                },
+            ));
+        }
+        findings
+    }
+
+    // Fails with 422 on the soft checks set to `reject`.
+    pub fn enforce(&self, email: &str) -> Result<(), ApiError> {
+        let rejected: Vec<FieldError> = self
+            .findings(email)
+            .into_iter()
+            .filter(|(severity, _)| *severity == Severity::Reject)
+            .map(|(_, error)| error)
+            .collect();
+        if rejected.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Validation(rejected))
+        }
+    }
+
+    // The soft checks set to `warn` that `email` fails.
+    pub fn warnings(&self, email: &str) -> Vec<FieldError> {
+        self.findings(email)
+            .into_iter()
+            .filter(|(severity, _)| *severity == Severity::Warn)
+            .map(|(_, error)| error)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(name: &str, email: &str) -> NewUser {
+        NewUser {
+            name: name.to_string(),
+            email: email.to_string(),
+            password: None,
+            phone: None,
+            profile: None,
+            expires_in_seconds: None,
+        }
+    }
+
+    fn fields(result: Result<impl Sized, ApiError>) -> Vec<String> {
+        match result {
+            Err(ApiError::Validation(errors)) => errors.into_iter().map(|error| error.field).collect(),
+            Err(other) => panic!("unexpected error {}", other),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn new_users_are_trimmed() {
+        let user = candidate("  Ada Lovelace ", " ada@example.com ");
+        let user = NewUser {
+            profile: Some(Profile {
+                bio: Some(String::from("  ")),
+                ..Profile::default()
+            }),
+            ..user
+        };
+        let user = super::new_user(user).unwrap();
+        assert_eq!(user.name, "Ada Lovelace");
+        assert_eq!(user.email, "ada@example.com");
+        assert!(user.profile.is_none());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let user = NewUser {
+            password: Some(String::from("short")),
+            profile: Some(Profile {
+                avatar_url: Some(String::from("ftp://example.com/a.png")),
+                locale: Some(String::from("en_GB")),
+                timezone: Some(String::from("Europe/London")),
+                ..Profile::default()
+            }),
+            ..candidate("R2-D2", "not-an-email")
+        };
+        assert_eq!(
+            fields(super::new_user(user)),
+            ["name", "email", "password", "profile.avatar_url", "profile.locale"]
+        );
+    }
+
+    #[test]
+    fn emails_need_a_local_part_and_a_dotted_domain() {
+        let email = |email: &str| fields(super::new_user(candidate("Ada", email)));
+        assert!(email("ada.lovelace+tag@mail.example.co.uk").is_empty());
+        let invalid = [
+            "ada",
+            "@example.com",
+            "ada@example",
+            "ada@-example.com",
+            "a@b@example.com",
+            "ada@exa mple.com",
+        ];
+        for invalid in invalid {
+            assert_eq!(email(invalid), ["email"], "{}", invalid);
+        }
+        let long_local = format!("{}@example.com", "a".repeat(MAX_EMAIL_LOCAL_LEN + 1));
+        assert_eq!(email(&long_local), ["email"]);
+    }
+
+    #[test]
+    fn lone_emails_are_trimmed_or_explained() {
+        assert_eq!(email(" ada@example.com "), Ok(String::from("ada@example.com")));
+        assert_eq!(email("ada@"), Err(String::from("must be a valid email address")));
+    }
+
+    #[test]
+    fn lone_passwords_follow_the_registration_rules() {
+        assert_eq!(password(String::from("correct horse")).unwrap(), "correct horse");
+        assert_eq!(fields(password(String::from("short"))), ["password"]);
+        assert_eq!(fields(password("x".repeat(MAX_PASSWORD_CHARS + 1))), ["password"]);
+    }
+
+    #[test]
+    fn phones_are_normalized_to_e164() {
+        let phone = |phone: &str| {
+            super::new_user(NewUser {
+                phone: Some(phone.to_string()),
+                ..candidate("Ada", "ada@example.com")
+            })
+            .map(|user| user.phone.unwrap_or_default())
+        };
+        assert_eq!(phone(" +44 20 7946 0958 ").unwrap(), "+442079460958");
+        assert_eq!(phone("+1 (415) 555-0123").unwrap(), "+14155550123");
+        assert_eq!(phone("0049.30.1234567").unwrap(), "+49301234567");
+        let invalid = ["020 7946 0958", "+0442079460958", "+1 415 CALL", "+123"];
+        for invalid in invalid.into_iter().chain(["+1234567890123456"]) {
+            assert_eq!(fields(phone(invalid)), ["phone"], "{}", invalid);
+        }
+        assert_eq!(super::phone("+1-415-555-0123").unwrap(), "+14155550123");
+        assert!(super::phone("555-0123").is_err());
+    }
+
+    #[test]
+    fn addresses_are_trimmed_and_need_a_street_city_and_country() {
+        let address = Address {
+            label: Some(String::from(" ")),
+            line1: String::from(" 12 Baker Street "),
+            line2: None,
+            city: String::from("London"),
+            region: None,
+            postal_code: Some(String::from("NW1 6XE")),
+            country: String::from("gb"),
+        };
+        let address = super::address(address).unwrap();
+        assert_eq!(address.line1, "12 Baker Street");
+        assert_eq!(address.label, None);
+        assert_eq!(address.country, "GB");
+
+        let invalid = Address {
+            line1: String::new(),
+            postal_code: Some(String::from("NW1 6XE!")),
+            country: String::from("GBR"),
+            ..address
+        };
+        assert_eq!(fields(super::address(invalid)), ["line1", "postal_code", "country"]);
+    }
+
+    #[test]
+    fn tags_are_lower_cased_and_url_safe() {
+        assert_eq!(super::tag(" Beta-Tester ").unwrap(), "beta-tester");
+        assert_eq!(super::tag("plan:pro").unwrap(), "plan:pro");
+        assert!(super::tag("  ").is_err());
+        assert!(super::tag("two words").is_err());
+        assert!(super::tag("a/b").is_err());
+        assert!(super::tag(&"x".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn metadata_keys_are_kept_as_given_and_values_are_bounded() {
+        assert_eq!(super::metadata_key("crm.AccountId"), Ok("crm.AccountId"));
+        assert!(super::metadata_key("").is_err());
+        assert!(super::metadata_key("crm id").is_err());
+        assert!(super::metadata_key(&"k".repeat(65)).is_err());
+        assert_eq!(super::metadata_value(" 0012 "), Ok(" 0012 "));
+        assert!(super::metadata_value(&"v".repeat(1025)).is_err());
+    }
+
+    #[test]
+    fn groups_are_trimmed_and_bounded() {
+        let group = |name: &str, description: Option<&str>| {
+            super::new_group(NewGroup {
+                name: name.to_string(),
+                description: description.map(str::to_string),
+            })
+        };
+        let created = group(" Platform team ", Some("  ")).unwrap();
+        assert_eq!(created.name, "Platform team");
+        assert_eq!(created.description, None);
+        assert_eq!(fields(group("", None)), ["name"]);
+        assert_eq!(fields(group("a\tb", Some(&"d".repeat(501)))), ["name", "description"]);
+        let update = super::update_group(UpdateGroup {
+            name: None,
+            description: Some(String::from(" ")),
+        });
+        assert_eq!(update.unwrap().description.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn names_allow_letters_of_any_script() {
+        let name = |name: &str| fields(super::new_user(candidate(name, "ada@example.com")));
+        assert!(name("Zoë O'Brien-Smith Jr.").is_empty());
+        assert!(name("李小龍").is_empty());
+        assert_eq!(name(""), ["name"]);
+        assert_eq!(name(&"a".repeat(MAX_NAME_CHARS + 1)), ["name"]);
+    }
+
+    #[test]
+    fn expiries_must_fit_a_ttl() {
+        let expiring = |secs| {
+            let user = NewUser {
+                expires_in_seconds: Some(secs),
+                ..candidate("Ada", "ada@example.com")
+            };
+            fields(super::new_user(user))
+        };
+        assert!(expiring(3600).is_empty());
+        assert!(expiring(MAX_TTL_SECS).is_empty());
+        assert_eq!(expiring(0), ["expires_in_seconds"]);
+        assert_eq!(expiring(MAX_TTL_SECS + 1), ["expires_in_seconds"]);
+    }
+
+    #[test]
+    fn updates_must_change_something() {
+        let empty = UpdateUser {
+            name: None,
+            email: None,
+            phone: Some(String::from(" ")),
+            profile: Some(Profile::default()),
+            version: None,
+        };
+        assert_eq!(fields(update_user(empty)), ["body"]);
+        let rename = UpdateUser {
+            name: Some(String::from(" Ada ")),
+            email: None,
+            phone: None,
+            profile: None,
+            version: None,
+        };
+        assert_eq!(update_user(rename).unwrap().name.as_deref(), Some("Ada"));
+    }
+
+    fn policy(disposable_email: Severity) -> Policy {
+        Policy::new(&ValidationConfig {
+            disposable_email,
+            disposable_domains: vec![String::from(" Mailinator.com ")],
+        })
+    }
+
+    #[test]
+    fn disposable_domains_match_exactly_or_as_a_parent() {
+        let policy = policy(Severity::Warn);
+        assert!(policy.is_disposable("ada@mailinator.com"));
+        assert!(policy.is_disposable("ada@EU.MAILINATOR.COM "));
+        assert!(!policy.is_disposable("ada@notmailinator.com"));
+        assert!(!policy.is_disposable("ada@example.com"));
+        assert!(!policy.is_disposable("not an address"));
+    }
+
+    #[test]
+    fn soft_checks_warn_reject_or_stay_off_as_configured() {
+        let warn = policy(Severity::Warn);
+        assert!(warn.enforce("ada@mailinator.com").is_ok());
+        let warnings = warn.warnings("ada@mailinator.com");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].field, "email");
+        assert!(warn.warnings("ada@example.com").is_empty());
+
+        let reject = policy(Severity::Reject);
+        assert_eq!(fields(reject.enforce("ada@mailinator.com")), ["email"]);
+        assert!(reject.warnings("ada@mailinator.com").is_empty());
+
+        let off = policy(Severity::Off);
+        assert!(off.enforce("ada@mailinator.com").is_ok());
+        assert!(off.warnings("ada@mailinator.com").is_empty());
+    }
+}
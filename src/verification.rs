@@ -0,0 +1,146 @@
+use crate::api_keys::{hash_secret, new_secret};
+use crate::audit::{self, Action};
+use crate::error::{ApiError, Problem};
+use crate::events::EventKind;
+use crate::history;
+use crate::models::User;
+use crate::observe;
+use crate::repository::ColumnWrite;
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use uuid::Uuid;
+
+// Every user created through the API starts unverified, and a token for its
+// email is stored in `email_verifications` by the SHA-256 of the token, as
+// API keys are. POST /verify/{token} consumes the token with a lightweight
+// transaction, so it verifies once, and sets `verified` on the user if it
+// still has the email the token was issued for.
+//
+// With `smtp.enabled` the token is sent to the new user in a welcome email;
+// see `mailer`. Tokens are also logged, at debug level.
+//
+// Tokens expire after `http.verification_token_ttl_secs`, or with their user
+// if it expires first. Issuing one follows the registration, and a failure
+// is logged rather than failing a user that has already been created.
+
+// The TTL of a token for `user`: the configured one, unless the user expires
+// sooner.
+fn token_ttl(state: &AppState, user: &User) -> i32 {
+    let ttl = i32::try_from(state.verification_token_ttl.as_secs()).unwrap_or(i32::MAX);
+    match users::ttl(user.expires_at) {
+        0 => ttl,
+        left => ttl.min(left),
+    }
+}
+
+async fn try_issue(state: &AppState, user: &User) -> Result<String, ApiError> {
+    let token = new_secret();
+    let values = (
+        hash_secret(&token),
+        user.id,
+        &user.email,
+        Utc::now(),
+        token_ttl(state, user),
+    );
+    observe::query(state, "insert_email_verification", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.insert_email_verification, &values)
+    })
+    .await?;
+    Ok(token)
+}
+
+// Issues a verification token for the email of a user just created.
+pub async fn issue(state: &AppState, user: &User) {
+    match try_issue(state, user).await {
+        Ok(token) => {
+            tracing::debug!(user_id = %user.id, %token, "email verification token issued");
+            if let Some(mailer) = &state.mailer {
+                mailer.welcome(user, &token);
+            }
+        }
+        Err(e) => {
+            tracing::warn!(user_id = %user.id, error = %e, "failed to issue verification token");
+        }
+    }
+}
+
+// The user and email a live token was issued for.
+async fn lookup(state: &AppState, token_hash: &str) -> Result<Option<(Uuid, String)>, ApiError> {
+    let result = observe::query(state, "select_email_verification", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.select_email_verification, (token_hash,))
+    })
+    .await?;
+    result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading verification token", e))?
+        .maybe_first_row::<(Uuid, String)>()
+        .map_err(|e| ApiError::internal("Error reading verification token", e))
+}
+
+// Deletes the token, returning whether this call was the one that did.
+async fn consume(state: &AppState, token_hash: &str) -> Result<bool, ApiError> {
+    let result = observe::conditional(state, "consume_email_verification", || {
+        state
+            .session
+            .execute_unpaged(&state.statements.consume_email_verification, (token_hash,))
+    })
+    .await?;
+    statements::applied(result).map_err(|e| ApiError::internal("Failed to consume token", e))
+}
+
+/// Confirms the email of the user a verification token was issued for. A
+/// token works once, and not after the user has changed its email.
+#[utoipa::path(
+    post,
+    path = "/verify/{token}",
+    params(("token" = String, Path, description = "Verification token")),
+    responses(
+        (status = 200, description = "Email verified", body = String),
+        (status = 404, description = "Unknown, used or expired token", body = Problem),
+        (status = 409, description = "The user's email changed after the token was issued", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn verify_email(
+    token: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let not_found = || ApiError::NotFound(String::from("Verification token not found or expired"));
+    let token_hash = hash_secret(&token);
+    let (user_id, email) = lookup(&data, &token_hash).await?.ok_or_else(not_found)?;
+    if !consume(&data, &token_hash).await? {
+        return Err(not_found());
+    }
+    let changed = || {
+        ApiError::Conflict(format!("User {} no longer has the email {}", user_id, email))
+    };
+    let before = match users::stored_row(&data, user_id).await? {
+        Some((before, false)) => before,
+        _ => return Err(not_found()),
+    };
+    if before.email != email {
+        return Err(changed());
+    }
+
+    let write = ColumnWrite::Verify { email: &email };
+    let (applied, _) = data.users.write_column(user_id, before.expires_at, write).await?;
+    if !applied {
+        return Err(changed());
+    }
+    let after = User {
+        verified: Some(true),
+        ..before.clone()
+    };
+    users::forget_cached(&data, user_id).await;
+    history::append(&data, EventKind::Updated, user_id, Some(after.clone())).await;
+    audit::record(&data, user_id, Action::Update, Some(&before), Some(&after)).await;
+    tracing::info!(%user_id, "email verified");
+    Ok(HttpResponse::Ok().json(format!("Email of user {} verified", user_id)))
+}
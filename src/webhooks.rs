@@ -0,0 +1,752 @@
+use crate::api_keys;
+use crate::config::WebhooksConfig;
+use crate::error::{ApiError, Problem};
+use crate::events::EventKind;
+use crate::http_client;
+use crate::metrics::Metrics;
+use crate::models::User;
+use crate::observe;
+use crate::outbox::{OutboxEvent, Sink};
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use crate::statements;
+use crate::users;
+use actix_web::rt::time::sleep;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+// With `webhooks.enabled`, changes to users made through the API are POSTed
+// to the URLs subscribed with POST /admin/webhooks, so other systems can
+// react to them without polling. A subscription names the event types it
+// wants and holds a secret: each delivery carries
+// `X-Webhook-Signature: sha256=<hex>`, the HMAC-SHA256 under that secret of
+// `<X-Webhook-Timestamp>.<body>`, so a receiver can check where it came from
+// and refuse old ones replayed.
+//
+// `history::append` queues each event once it is on the user's stream
+// (through the outbox relay when it is on), and a dispatcher hands it to
+// every subscription that wants it, `webhooks.concurrency` deliveries at a
+// time. A delivery is tried again with exponential backoff, up to
+// `webhooks.max_attempts`, until it gets a 2xx; a 4xx other than 408 and 429
+// is given up on at once. Every attempt is logged in `webhook_deliveries` for
+// GET /admin/webhooks/{id}/deliveries. A delivery keeps its slot while it
+// backs off, so a subscription that keeps failing slows the others rather
+// than piling up retries.
+//
+// Events that find the queue full are dropped and counted, unless they come
+// through the outbox, whose relay tries them again. Deliveries queued or
+// backing off at shutdown are lost. Each instance reads the subscriptions
+// every `webhooks.refresh_interval_secs`, so one made elsewhere gets events
+// from it after that long at most.
+
+// The event types a subscription can ask for.
+const EVENT_TYPES: [&str; 4] = ["user.created", "user.updated", "user.deleted", "user.restored"];
+
+pub fn event_type(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Created => "user.created",
+        EventKind::Updated => "user.updated",
+        EventKind::Deleted => "user.deleted",
+        EventKind::Restored => "user.restored",
+    }
+}
+
+/// A subscription to user events.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// The event types delivered, such as `user.created`.
+    pub events: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    secret: String,
+}
+
+/// A subscription to make. Without a `secret`, one is generated.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewWebhook {
+    pub url: String,
+    /// Any of `user.created`, `user.updated`, `user.deleted` and
+    /// `user.restored`.
+    pub events: Vec<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedWebhook {
+    pub id: Uuid,
+    pub url: String,
+    pub events: Vec<String>,
+    /// Signs the deliveries. Shown only once; store it safely.
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// One attempt to deliver an event to a subscription.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookDelivery {
+    pub attempted_at: DateTime<Utc>,
+    pub event_id: Uuid,
+    /// 1 for the first try.
+    pub attempt: i32,
+    pub event: String,
+    pub user_id: Uuid,
+    /// The receiver's status code; absent when it sent no response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<i32>,
+    /// Why the attempt failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: i64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveriesQuery {
+    /// How many of the latest attempts; defaults to `http.default_page_size`.
+    pub limit: Option<usize>,
+}
+
+// The body of a delivery.
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: Uuid,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    user_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a User>,
+    occurred_at: DateTime<Utc>,
+}
+
+// An event on its way to one subscription.
+struct Delivery {
+    webhook: Webhook,
+    event_id: Uuid,
+    kind: EventKind,
+    user_id: Uuid,
+    body: Arc<Vec<u8>>,
+}
+
+// What an attempt came to.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Delivered,
+    Retry,
+    GiveUp,
+}
+
+fn outcome(status: Option<u16>) -> Outcome {
+    match status {
+        Some(200..=299) => Outcome::Delivered,
+        Some(408 | 429) | Some(500..) | None => Outcome::Retry,
+        Some(_) => Outcome::GiveUp,
+    }
+}
+
+// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>` under `secret`.
+fn signature(secret: &str, timestamp: &str, body: &[u8]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(timestamp.as_bytes())?;
+    signer.update(b".")?;
+    signer.update(body)?;
+    let mac = signer.sign_to_vec()?;
+    Ok(format!(
+        "sha256={}",
+        mac.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    ))
+}
+
+type WebhookRow = (
+    Uuid,
+    Option<String>,
+    Option<String>,
+    Option<Vec<String>>,
+    Option<DateTime<Utc>>,
+);
+
+// Every subscription in the `webhooks` table.
+async fn read_webhooks(state: &AppState) -> Result<Vec<Webhook>, ApiError> {
+    let result = observe::query(state, "select_webhooks", || {
+        state.session.execute_unpaged(&state.statements.select_webhooks, ())
+    })
+    .await?;
+    let rows = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading webhooks", e))?
+        .rows::<WebhookRow>()
+        .map_err(|e| ApiError::internal("Error reading webhooks", e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Error reading webhooks", e))?;
+    Ok(rows
+        .into_iter()
+        .map(|(id, url, secret, events, created_at)| Webhook {
+            id,
+            url: url.unwrap_or_default(),
+            events: events.unwrap_or_default(),
+            created_at,
+            secret: secret.unwrap_or_default(),
+        })
+        .collect())
+}
+
+pub struct Webhooks {
+    sender: mpsc::Sender<OutboxEvent>,
+    // Taken by the dispatcher when it starts.
+    receiver: Mutex<Option<mpsc::Receiver<OutboxEvent>>>,
+    // The subscriptions read last, with those made or deleted here since.
+    subscriptions: RwLock<Vec<Webhook>>,
+    concurrency: usize,
+    timeout: Duration,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    refresh_interval: Duration,
+    log_ttl_secs: i32,
+    metrics: Arc<Metrics>,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhooksConfig, metrics: Arc<Metrics>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity);
+        Webhooks {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            subscriptions: RwLock::new(Vec::new()),
+            concurrency: config.concurrency,
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_attempts: config.max_attempts,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            refresh_interval: Duration::from_secs(config.refresh_interval_secs),
+            // Checked against the largest TTL when the configuration was loaded.
+            log_ttl_secs: config.delivery_log_ttl_secs as i32,
+            metrics,
+        }
+    }
+
+    fn queue(&self, event: &OutboxEvent) -> Result<(), String> {
+        self.sender
+            .try_send(event.clone())
+            .map_err(|_| String::from("the webhook queue is full"))
+    }
+
+    // Queues `event` for the subscriptions that want it, dropping it if the
+    // queue is full.
+    pub fn notify(&self, event: &OutboxEvent) {
+        if let Err(e) = self.queue(event) {
+            self.metrics.webhook_delivery("dropped");
+            tracing::warn!(
+                user_id = %event.user_id,
+                kind = event.kind.name(),
+                error = %e,
+                "webhook event dropped"
+            );
+        }
+    }
+
+    // The subscriptions that want `kind`.
+    fn subscribed(&self, kind: EventKind) -> Vec<Webhook> {
+        self.subscriptions
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|webhook| webhook.events.iter().any(|event| event == event_type(kind)))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn refresh(&self, state: &AppState) -> Result<(), ApiError> {
+        let webhooks = read_webhooks(state).await?;
+        *self.subscriptions.write().unwrap() = webhooks;
+        Ok(())
+    }
+
+    // Exponential backoff with full jitter, as `retry` does for queries.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    // Starts reading the subscriptions and the dispatcher; a `Webhooks` has
+    // one of each.
+    pub fn start(self: &Arc<Self>, state: AppState, tasks: &mut Tasks) {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("the webhook dispatcher is started once");
+        let webhooks = self.clone();
+        let refreshing = state.clone();
+        tasks.spawn("webhook subscriptions", move |stopping| {
+            webhooks.refresh_every(refreshing, stopping)
+        });
+        let webhooks = self.clone();
+        tasks.spawn("webhook dispatcher", move |stopping| {
+            webhooks.dispatch(state, receiver, stopping)
+        });
+    }
+
+    async fn refresh_every(self: Arc<Self>, state: AppState, mut stopping: Stopping) {
+        loop {
+            if let Err(e) = self.refresh(&state).await {
+                tracing::warn!(error = %e, "failed to refresh webhook subscriptions");
+            }
+            if !stopping.pause(self.refresh_interval).await {
+                break;
+            }
+        }
+    }
+
+    async fn dispatch(
+        self: Arc<Self>,
+        state: AppState,
+        mut receiver: mpsc::Receiver<OutboxEvent>,
+        mut stopping: Stopping,
+    ) {
+        let slots = Arc::new(Semaphore::new(self.concurrency));
+        loop {
+            let received = {
+                let received = std::pin::pin!(receiver.recv());
+                let stopped = std::pin::pin!(stopping.stopped());
+                match future::select(received, stopped).await {
+                    Either::Left((event, _)) => event,
+                    Either::Right(_) => None,
+                }
+            };
+            let Some(event) = received else {
+                break;
+            };
+            let webhooks = self.subscribed(event.kind);
+            if webhooks.is_empty() {
+                continue;
+            }
+            let payload = Payload {
+                id: event.id,
+                kind: event_type(event.kind),
+                user_id: event.user_id,
+                version: event.version,
+                user: event.user.as_ref(),
+                occurred_at: event.created_at,
+            };
+            let body = match serde_json::to_vec(&payload) {
+                Ok(body) => Arc::new(body),
+                Err(e) => {
+                    tracing::error!(event_id = %event.id, error = %e, "cannot serialize event");
+                    continue;
+                }
+            };
+            for webhook in webhooks {
+                let slot = {
+                    let acquired = std::pin::pin!(slots.clone().acquire_owned());
+                    let stopped = std::pin::pin!(stopping.stopped());
+                    match future::select(acquired, stopped).await {
+                        Either::Left((Ok(slot), _)) => slot,
+                        _ => return,
+                    }
+                };
+                let delivery = Delivery {
+                    webhook,
+                    event_id: event.id,
+                    kind: event.kind,
+                    user_id: event.user_id,
+                    body: body.clone(),
+                };
+                let webhooks = self.clone();
+                let state = state.clone();
+                actix_web::rt::spawn(async move { webhooks.deliver(&state, delivery, slot).await });
+            }
+        }
+    }
+
+    async fn deliver(&self, state: &AppState, delivery: Delivery, _slot: OwnedSemaphorePermit) {
+        let event_id = delivery.event_id.to_string();
+        let kind = event_type(delivery.kind);
+        for attempt in 1..=self.max_attempts {
+            let attempted_at = Utc::now();
+            let started = Instant::now();
+            let timestamp = attempted_at.timestamp().to_string();
+            let sent = match signature(&delivery.webhook.secret, &timestamp, &delivery.body) {
+                Ok(signature) => {
+                    let headers = [
+                        ("X-Webhook-Id", event_id.as_str()),
+                        ("X-Webhook-Event", kind),
+                        ("X-Webhook-Timestamp", timestamp.as_str()),
+                        ("X-Webhook-Signature", signature.as_str()),
+                    ];
+                    http_client::send(
+                        "POST",
+                        &delivery.webhook.url,
+                        &headers,
+                        Some(("application/json", &delivery.body)),
+                        self.timeout,
+                    )
+                    .await
+                    .map_err(|e| e.to_string())
+                }
+                Err(e) => Err(format!("cannot sign the delivery: {}", e)),
+            };
+            let (status, error) = match &sent {
+                Ok(response) if response.is_success() => (Some(response.status), None),
+                Ok(response) => (
+                    Some(response.status),
+                    Some(format!("answered {}", response.status)),
+                ),
+                Err(e) => (None, Some(e.clone())),
+            };
+            self.log(state, &delivery, attempt, attempted_at, status, error.as_deref(), started)
+                .await;
+            match outcome(status) {
+                Outcome::Delivered => {
+                    self.metrics.webhook_delivery("delivered");
+                    return;
+                }
+                Outcome::Retry if attempt < self.max_attempts => {
+                    self.metrics.webhook_delivery("retried");
+                    sleep(self.backoff(attempt)).await;
+                }
+                _ => {
+                    self.metrics.webhook_delivery("failed");
+                    tracing::warn!(
+                        webhook_id = %delivery.webhook.id,
+                        event_id = %delivery.event_id,
+                        attempts = attempt,
+                        error = error.as_deref().unwrap_or_default(),
+                        "webhook delivery given up"
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    // Records an attempt in `webhook_deliveries`; a failure to is only logged.
+    #[allow(clippy::too_many_arguments)]
+    async fn log(
+        &self,
+        state: &AppState,
+        delivery: &Delivery,
+        attempt: u32,
+        attempted_at: DateTime<Utc>,
+        status: Option<u16>,
+        error: Option<&str>,
+        started: Instant,
+    ) {
+        let duration_ms = i64::try_from(started.elapsed().as_millis()).unwrap_or(i64::MAX);
+        let logged = observe::query(state, "insert_webhook_delivery", || {
+            state.session.execute_unpaged(
+                &state.statements.insert_webhook_delivery,
+                (
+                    delivery.webhook.id,
+                    attempted_at,
+                    delivery.event_id,
+                    attempt as i32,
+                    event_type(delivery.kind),
+                    delivery.user_id,
+                    status.map(i32::from),
+                    error,
+                    duration_ms,
+                    self.log_ttl_secs,
+                ),
+            )
+        })
+        .await;
+        if let Err(e) = logged {
+            tracing::warn!(
+                webhook_id = %delivery.webhook.id,
+                error = %e,
+                "failed to log webhook delivery"
+            );
+        }
+    }
+}
+
+// Relayed outbox events. A full queue fails the event, so the relay tries
+// it again on its next run rather than losing it.
+impl Sink for Webhooks {
+    fn publish<'a>(&'a self, event: &'a OutboxEvent) -> BoxFuture<'a, Result<(), String>> {
+        future::ready(self.queue(event)).boxed()
+    }
+}
+
+// The state's webhooks, unless they are off.
+fn enabled(data: &AppState) -> Result<&Arc<Webhooks>, ApiError> {
+    data.webhooks.as_ref().ok_or_else(|| {
+        ApiError::Conflict(String::from("Webhooks are off; set webhooks.enabled"))
+    })
+}
+
+/// Subscribes a URL to user events. Deliveries are signed with the returned
+/// secret; other instances start sending them within
+/// `webhooks.refresh_interval_secs`.
+#[utoipa::path(
+    post,
+    path = "/admin/webhooks",
+    request_body = NewWebhook,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 201, description = "The subscription, with its secret", body = CreatedWebhook),
+        (status = 400, description = "Invalid URL, event type or secret", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 409, description = "`webhooks.enabled` is off", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn create_webhook(
+    body: web::Json<NewWebhook>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let webhooks = enabled(&data)?;
+    let new_webhook = body.into_inner();
+    if http_client::check_url(&new_webhook.url).is_err() {
+        return Err(ApiError::BadRequest(String::from("url must be an http or https URL")));
+    }
+    if new_webhook.events.is_empty() {
+        return Err(ApiError::BadRequest(String::from("events must not be empty")));
+    }
+    let unknown = new_webhook
+        .events
+        .iter()
+        .find(|event| !EVENT_TYPES.contains(&event.as_str()));
+    if let Some(unknown) = unknown {
+        return Err(ApiError::BadRequest(format!("unknown event type {}", unknown)));
+    }
+    if new_webhook.secret.as_deref().is_some_and(|secret| secret.trim().is_empty()) {
+        return Err(ApiError::BadRequest(String::from("secret must not be blank")));
+    }
+    let mut events = new_webhook.events;
+    events.sort();
+    events.dedup();
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: new_webhook.url,
+        events,
+        created_at: Some(Utc::now()),
+        secret: new_webhook.secret.unwrap_or_else(api_keys::new_secret),
+    };
+    observe::query(&data, "insert_webhook", || {
+        data.session.execute_unpaged(
+            &data.statements.insert_webhook,
+            (webhook.id, &webhook.url, &webhook.secret, &webhook.events, webhook.created_at),
+        )
+    })
+    .await?;
+    webhooks.subscriptions.write().unwrap().push(webhook.clone());
+    tracing::info!(webhook_id = %webhook.id, url = %webhook.url, "webhook created");
+    Ok(HttpResponse::Created().json(CreatedWebhook {
+        id: webhook.id,
+        url: webhook.url,
+        events: webhook.events,
+        secret: webhook.secret,
+        created_at: webhook.created_at.unwrap_or_default(),
+    }))
+}
+
+/// Lists the subscriptions to user events, without their secrets.
+#[utoipa::path(
+    get,
+    path = "/admin/webhooks",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Every subscription", body = [Webhook]),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 409, description = "`webhooks.enabled` is off", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn list_webhooks(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    enabled(&data)?;
+    let mut webhooks = read_webhooks(&data).await?;
+    webhooks.sort_by_key(|webhook| webhook.created_at);
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+/// Deletes a subscription. Deliveries already under way still finish, and
+/// its delivery log stays until it expires.
+#[utoipa::path(
+    delete,
+    path = "/admin/webhooks/{id}",
+    params(("id" = Uuid, Path, description = "Webhook id")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 204, description = "The subscription was deleted"),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 404, description = "No such webhook", body = Problem),
+        (status = 409, description = "`webhooks.enabled` is off", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn delete_webhook(
+    webhook_id: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let webhooks = enabled(&data)?;
+    let webhook_id = webhook_id.into_inner();
+    let result = observe::conditional(&data, "delete_webhook", || {
+        data.session.execute_unpaged(&data.statements.delete_webhook, (webhook_id,))
+    })
+    .await?;
+    match statements::applied(result) {
+        Ok(true) => {}
+        Ok(false) => return Err(ApiError::NotFound(format!("Webhook {} not found", webhook_id))),
+        Err(e) => return Err(ApiError::internal("Failed to delete webhook", e)),
+    }
+    webhooks.subscriptions.write().unwrap().retain(|webhook| webhook.id != webhook_id);
+    tracing::info!(%webhook_id, "webhook deleted");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+type DeliveryRow = (
+    DateTime<Utc>,
+    Uuid,
+    i32,
+    Option<String>,
+    Option<Uuid>,
+    Option<i32>,
+    Option<String>,
+    Option<i64>,
+);
+
+/// The latest attempts to deliver events to a subscription, newest first,
+/// kept for `webhooks.delivery_log_ttl_secs`.
+#[utoipa::path(
+    get,
+    path = "/admin/webhooks/{id}/deliveries",
+    params(("id" = Uuid, Path, description = "Webhook id"), DeliveriesQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The latest delivery attempts", body = [WebhookDelivery]),
+        (status = 400, description = "Invalid limit", body = Problem),
+        (status = 401, description = "Missing or invalid credentials"),
+        (status = 403, description = "Caller lacks the admin role"),
+        (status = 409, description = "`webhooks.enabled` is off", body = Problem),
+        (status = 503, description = "The cluster is unavailable", body = Problem),
+    )
+)]
+pub async fn list_deliveries(
+    webhook_id: web::Path<Uuid>,
+    params: web::Query<DeliveriesQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    enabled(&data)?;
+    let webhook_id = webhook_id.into_inner();
+    let (limit, truncated) = users::page_limit(&data, params.limit)?;
+    let fetch = i32::try_from(limit).unwrap_or(i32::MAX);
+    let result = observe::query(&data, "select_webhook_deliveries", || {
+        data.session
+            .execute_unpaged(&data.statements.select_webhook_deliveries, (webhook_id, fetch))
+    })
+    .await?;
+    let deliveries = result
+        .into_rows_result()
+        .map_err(|e| ApiError::internal("Error reading webhook deliveries", e))?
+        .rows::<DeliveryRow>()
+        .map_err(|e| ApiError::internal("Error reading webhook deliveries", e))?
+        .map(|row| {
+            row.map(|(attempted_at, event_id, attempt, event, user_id, status, error, ms)| {
+                WebhookDelivery {
+                    attempted_at,
+                    event_id,
+                    attempt,
+                    event: event.unwrap_or_default(),
+                    user_id: user_id.unwrap_or_default(),
+                    status,
+                    error,
+                    duration_ms: ms.unwrap_or_default(),
+                }
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::internal("Error reading webhook deliveries", e))?;
+    Ok(users::with_truncated(HttpResponse::Ok().json(deliveries), truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhooks(queue_capacity: usize) -> Webhooks {
+        Webhooks::new(
+            &WebhooksConfig {
+                queue_capacity,
+                ..WebhooksConfig::default()
+            },
+            Arc::new(Metrics::new()),
+        )
+    }
+
+    fn webhook(events: &[&str]) -> Webhook {
+        Webhook {
+            id: Uuid::new_v4(),
+            url: String::from("https://hooks.example.com/users"),
+            events: events.iter().map(|event| event.to_string()).collect(),
+            created_at: None,
+            secret: String::from("s3cret"),
+        }
+    }
+
+    #[test]
+    fn signatures_are_the_hmac_of_the_timestamp_and_body() {
+        // echo -n '1700000000.{"id":1}' | openssl dgst -sha256 -hmac s3cret
+        assert_eq!(
+            signature("s3cret", "1700000000", br#"{"id":1}"#).unwrap(),
+            "sha256=ee0658aa4e37018df69c24227df01e0f680eb3b87c7f1f9bd936e283cfe01d9b"
+        );
+        assert_ne!(
+            signature("s3cret", "1700000001", br#"{"id":1}"#).unwrap(),
+            signature("s3cret", "1700000000", br#"{"id":1}"#).unwrap()
+        );
+    }
+
+    #[test]
+    fn only_subscriptions_wanting_the_event_get_it() {
+        let webhooks = webhooks(10);
+        let created = webhook(&["user.created"]);
+        let all = webhook(&EVENT_TYPES);
+        *webhooks.subscriptions.write().unwrap() = vec![created.clone(), all.clone()];
+        let ids = |kind| webhooks.subscribed(kind).iter().map(|w| w.id).collect::<Vec<_>>();
+        assert_eq!(ids(EventKind::Created), vec![created.id, all.id]);
+        assert_eq!(ids(EventKind::Deleted), vec![all.id]);
+    }
+
+    #[test]
+    fn server_errors_and_throttling_are_retried() {
+        assert_eq!(outcome(Some(204)), Outcome::Delivered);
+        assert_eq!(outcome(None), Outcome::Retry);
+        assert_eq!(outcome(Some(503)), Outcome::Retry);
+        assert_eq!(outcome(Some(429)), Outcome::Retry);
+        assert_eq!(outcome(Some(410)), Outcome::GiveUp);
+    }
+
+    #[test]
+    fn a_full_queue_fails_relayed_events() {
+        let webhooks = webhooks(1);
+        let event = OutboxEvent::new(EventKind::Created, Uuid::new_v4(), None, Some(1));
+        assert!(webhooks.queue(&event).is_ok());
+        assert!(webhooks.queue(&event).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use crate::config::WriteBehindConfig;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::shutdown::{Stopping, Tasks};
+use crate::state::AppState;
+use crate::users::{self, Registration};
+use actix_web::rt::time;
+use futures::future::{self, Either};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// With `write_behind.enabled`, POST /register answers 202 Accepted once the
+// new user is validated, its password hashed and its email claimed, and
+// leaves the `users` insert to a background writer. The writer takes queued
+// users in unlogged batches of up to `write_behind.batch_size`, waiting at
+// most `flush_interval_ms` for a batch to fill, so many registrations share
+// one round trip; the indexing, verification email, event and audit entry of
+// each follow once its batch is stored. The email and phone claims stay
+// synchronous, so a taken email or number is still a 409.
+//
+// The queue holds `queue_capacity` users; when it is full registrations are
+// turned away with a 503 and a Retry-After rather than queued without bound.
+// A batch that fails after the retry policy's attempts is logged, counted
+// and its claims released: those users are lost, which is the trade made for
+// the throughput. On shutdown the writer stores what is queued before it
+// stops. Bulk, imported and GraphQL/gRPC registrations are stored as before.
+
+pub struct Queue {
+    sender: mpsc::Sender<Registration>,
+    // Taken by the writer when it starts.
+    receiver: Mutex<Option<mpsc::Receiver<Registration>>>,
+    batch_size: usize,
+    flush_interval: Duration,
+    metrics: Arc<Metrics>,
+}
+
+// Room for one user in the queue, held while its email is claimed.
+pub struct Slot<'a> {
+    permit: mpsc::Permit<'a, Registration>,
+    metrics: &'a Metrics,
+}
+
+impl Slot<'_> {
+    pub fn send(self, registration: Registration) {
+        self.permit.send(registration);
+        self.metrics.write_behind("accepted", 1);
+    }
+}
+
+impl Queue {
+    pub fn new(config: &WriteBehindConfig, metrics: Arc<Metrics>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity);
+        Queue {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            batch_size: config.batch_size,
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            metrics,
+        }
+    }
+
+    pub fn reserve(&self) -> Result<Slot<'_>, ApiError> {
+        match self.sender.try_reserve() {
+            Ok(permit) => Ok(Slot {
+                permit,
+                metrics: &self.metrics,
+            }),
+            Err(_) => {
+                self.metrics.write_behind("rejected", 1);
+                Err(ApiError::Overloaded(String::from("the registration queue is full")))
+            }
+        }
+    }
+
+    // Starts the writer; a queue has one.
+    pub fn start(&self, state: AppState, tasks: &mut Tasks) {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("the write-behind writer is started once");
+        let writer = Writer {
+            state,
+            receiver,
+            batch_size: self.batch_size,
+            flush_interval: self.flush_interval,
+        };
+        tasks.spawn("write-behind writer", |stopping| writer.run(stopping));
+    }
+}
+
+struct Writer {
+    state: AppState,
+    receiver: mpsc::Receiver<Registration>,
+    batch_size: usize,
+    flush_interval: Duration,
+}
+
+impl Writer {
+    async fn run(mut self, mut stopping: Stopping) {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        loop {
+            // Wait for a first user, or for shutdown.
+            let received = {
+                let first = std::pin::pin!(self.receiver.recv_many(&mut batch, self.batch_size));
+                let stopped = std::pin::pin!(stopping.stopped());
+                matches!(future::select(first, stopped).await, Either::Left((n, _)) if n > 0)
+            };
+            if !received {
+                break;
+            }
+            // Then for the batch to fill, up to the flush interval.
+            let deadline = Instant::now() + self.flush_interval;
+            while batch.len() < self.batch_size {
+                let left = deadline.saturating_duration_since(Instant::now());
+                let room = self.batch_size - batch.len();
+                match time::timeout(left, self.receiver.recv_many(&mut batch, room)).await {
+                    Ok(n) if n > 0 => {}
+                    _ => break,
+                }
+            }
+            self.flush(std::mem::take(&mut batch)).await;
+        }
+
+        // Shutdown: the listeners have drained, so what is queued is all
+        // there will be.
+        self.receiver.close();
+        tracing::info!(queued = self.receiver.len(), "storing queued registrations");
+        while self.receiver.recv_many(&mut batch, self.batch_size).await > 0 {
+            self.flush(std::mem::take(&mut batch)).await;
+        }
+    }
+
+    async fn flush(&self, batch: Vec<Registration>) {
+        let state = &self.state;
+        let count = batch.len() as u64;
+        match state.users.insert_batch(&batch).await {
+            Ok(_) => {
+                state.metrics.write_behind("stored", count);
+                future::join_all(batch.iter().map(|r| users::registered(state, &r.user))).await;
+            }
+            Err(e) => {
+                state.metrics.write_behind("failed", count);
+                tracing::error!(users = count, error = %e, "write-behind batch failed, users lost");
+                future::join_all(batch.iter().map(|r| users::release_contacts(state, &r.user)))
+                    .await;
+            }
+        }
+        state.metrics.write_behind_queued(self.receiver.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use uuid::Uuid;
+
+    fn registration() -> Registration {
+        Registration {
+            user: User {
+                id: Uuid::new_v4(),
+                name: String::from("Ada"),
+                email: String::from("ada@example.com"),
+                phone: None,
+                profile: None,
+                created_at: None,
+                updated_at: None,
+                expires_at: None,
+                verified: Some(false),
+                version: None,
+                status: None,
+                anonymized_at: None,
+            },
+            password_hash: None,
+        }
+    }
+
+    #[test]
+    fn a_full_queue_turns_registrations_away() {
+        let config = WriteBehindConfig {
+            queue_capacity: 1,
+            ..WriteBehindConfig::default()
+        };
+        let queue = Queue::new(&config, Arc::new(Metrics::new()));
+        queue.reserve().unwrap().send(registration());
+        assert!(matches!(queue.reserve(), Err(ApiError::Overloaded(_))));
+
+        let mut receiver = queue.receiver.lock().unwrap().take().unwrap();
+        assert!(receiver.try_recv().is_ok());
+        assert!(queue.reserve().is_ok());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::events::UserEvent;
+use crate::state::AppState;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::body::{BodyStream, MessageBody};
+use actix_web::web::BytesMut;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::channel::mpsc;
+use futures::{stream, SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// GET /ws/users upgrades to a WebSocket that pushes every user event as a
+// JSON text message. Messages from the client are not commands: pings are
+// answered, a close is echoed, and anything else is ignored. A client that
+// falls behind gets `{"type":"lagged","missed":N}` and should refetch.
+
+// Frames queued for one client before event forwarding waits for it.
+const OUTGOING_FRAMES: usize = 16;
+
+fn event_message(event: &UserEvent) -> Option<Message> {
+    match serde_json::to_string(event) {
+        Ok(text) => Some(Message::Text(text.into())),
+        Err(e) => {
+            tracing::warn!(event_id = event.id, error = %e, "failed to serialize user event");
+            None
+        }
+    }
+}
+
+// Answers the client's control frames until it closes the connection.
+async fn read_client(mut payload: web::Payload, mut outgoing: mpsc::Sender<Message>) {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let Ok(chunk) = chunk else {
+            return;
+        };
+        buf.extend_from_slice(&chunk);
+        loop {
+            let reply = match codec.decode(&mut buf) {
+                Ok(Some(Frame::Ping(data))) => Message::Pong(data),
+                Ok(Some(Frame::Close(reason))) => {
+                    let _ = outgoing.send(Message::Close(reason)).await;
+                    return;
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::debug!(error = %e, "websocket protocol error");
+                    let reason = CloseReason::from((CloseCode::Protocol, e.to_string()));
+                    let _ = outgoing.send(Message::Close(Some(reason))).await;
+                    return;
+                }
+            };
+            if outgoing.send(reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn forward_events(
+    mut events: broadcast::Receiver<Arc<UserEvent>>,
+    mut outgoing: mpsc::Sender<Message>,
+) {
+    loop {
+        let message = match events.recv().await {
+            Ok(event) => match event_message(&event) {
+                Some(message) => message,
+                None => continue,
+            },
+            Err(RecvError::Lagged(missed)) => {
+                Message::Text(format!("{{\"type\":\"lagged\",\"missed\":{}}}", missed).into())
+            }
+            Err(RecvError::Closed) => return,
+        };
+        // Fails once the connection is gone and the frame stream dropped.
+        if outgoing.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+pub async fn user_events(
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut response = ws::handshake(req.head())?;
+
+    let (outgoing, messages) = mpsc::channel(OUTGOING_FRAMES);
+    actix_web::rt::spawn(read_client(payload, outgoing.clone()));
+    actix_web::rt::spawn(forward_events(data.events.subscribe(), outgoing));
+
+    // Encodes queued messages into frames; the body ends after a close frame.
+    let frames = stream::unfold(
+        (messages, Codec::new(), false),
+        |(mut messages, mut codec, closed)| async move {
+            if closed {
+                return None;
+            }
+            let message = messages.next().await?;
+            let closing = matches!(message, Message::Close(_));
+            let mut frame = BytesMut::new();
+            let encoded = codec
+                .encode(message, &mut frame)
+                .map(|()| frame.freeze())
+                .map_err(actix_web::Error::from);
+            Some((encoded, (messages, codec, closing)))
+        },
+    );
+    let response = response.message_body(BodyStream::new(frames).boxed())?;
+    Ok(HttpResponse::from(response))
+}